@@ -0,0 +1,11 @@
+// Generates the gRPC service from proto/rete.proto when the grpc feature is on, with a
+// vendored protoc so nothing has to be installed
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/rete.proto");
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("vendored protoc");
+        std::env::set_var("PROTOC", protoc);
+        tonic_build::compile_protos("proto/rete.proto").expect("proto/rete.proto compiles");
+    }
+}
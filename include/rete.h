@@ -0,0 +1,51 @@
+/* C interface to the rete rule engine, built with the ffi feature, see src/ffi.rs */
+#ifndef RETE_H
+#define RETE_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct RetWorld RetWorld;
+
+typedef enum RetKind {
+    RET_INT,
+    RET_FLOAT,
+    RET_BOOL,
+    RET_STR,
+    RET_ENTITY,
+} RetKind;
+
+/* Only the field for the kind is read, entity ids go in int and can't be negative */
+typedef struct RetValue {
+    RetKind kind;
+    int64_t int_;
+    double float_;
+    const char *string;
+} RetValue;
+
+typedef void (*RetRow)(void *user, const char *const *names, const RetValue *values,
+                       size_t count);
+
+RetWorld *rete_world_new(void);
+void rete_world_free(RetWorld *world);
+const char *rete_last_error(const RetWorld *world);
+
+int32_t rete_register(RetWorld *world, const char *name, const char *const *fields,
+                      size_t count);
+int32_t rete_load_rules(RetWorld *world, const char *source);
+int64_t rete_spawn(RetWorld *world);
+int32_t rete_assert(RetWorld *world, const char *component, uint64_t entity,
+                    const RetValue *values, size_t count);
+int32_t rete_retract(RetWorld *world, const char *component, uint64_t entity);
+int64_t rete_run(RetWorld *world);
+int64_t rete_query(RetWorld *world, const char *conditions, RetRow row, void *user);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
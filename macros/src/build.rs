@@ -0,0 +1,302 @@
+// Building the parts of a Rule from tokens, the way the rule parser in rete's dsl.rs
+// reads the same text
+//
+// Rust splits `<=` and `&&` into single punctuation marks, and `60s` is a literal with a
+// suffix, so both are put back together as the rule parser would see them. What's built
+// is an expression constructing the value, so the Rule costs nothing to parse at run
+// time and can't fail to.
+use crate::{is_ident, paren_contents, span_of, Error};
+use proc_macro2::{Literal, Spacing, Span, TokenStream, TokenTree};
+use quote::{format_ident, quote};
+use std::time::Duration;
+
+// Longest first, so `<=` wins over `<`
+const OPERATORS: &[&str] = &[
+    "==", "!=", "<=", ">=", "&&", "||", "+", "-", "*", "/", "%", "<", ">", "!",
+];
+
+// Loosest first: || && comparison additive multiplicative, with BinaryOp's variants
+const LEVELS: &[&[(&str, &str)]] = &[
+    &[("||", "Or")],
+    &[("&&", "And")],
+    &[
+        ("==", "Eq"),
+        ("!=", "Ne"),
+        ("<=", "Le"),
+        (">=", "Ge"),
+        ("<", "Lt"),
+        (">", "Gt"),
+    ],
+    &[("+", "Add"), ("-", "Sub")],
+    &[("*", "Mul"), ("/", "Div"), ("%", "Rem")],
+];
+
+enum Lexeme {
+    Op(&'static str, Span),
+    Tree(TokenTree),
+}
+
+fn lex(tokens: &[TokenTree]) -> Result<Vec<Lexeme>, Error> {
+    let mut lexemes = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        let TokenTree::Punct(punct) = &tokens[i] else {
+            lexemes.push(Lexeme::Tree(tokens[i].clone()));
+            i += 1;
+            continue;
+        };
+        let mut text = punct.as_char().to_string();
+        if let (Spacing::Joint, Some(TokenTree::Punct(next))) = (punct.spacing(), tokens.get(i + 1))
+        {
+            let pair = format!("{}{}", text, next.as_char());
+            if OPERATORS.contains(&pair.as_str()) {
+                text = pair;
+                i += 1;
+            }
+        }
+        let op = OPERATORS
+            .iter()
+            .find(|op| **op == text)
+            .ok_or_else(|| (punct.span(), format!("unexpected `{}`", text)))?;
+        lexemes.push(Lexeme::Op(op, punct.span()));
+        i += 1;
+    }
+    Ok(lexemes)
+}
+
+struct Parser {
+    lexemes: Vec<Lexeme>,
+    position: usize,
+}
+
+impl Parser {
+    fn span(&self) -> Span {
+        match self.lexemes.get(self.position) {
+            Some(Lexeme::Op(_, span)) => *span,
+            Some(Lexeme::Tree(tree)) => tree.span(),
+            None => Span::call_site(),
+        }
+    }
+
+    fn eat_op(&mut self, op: &str) -> bool {
+        let found = matches!(self.lexemes.get(self.position), Some(Lexeme::Op(o, _)) if *o == op);
+        self.position += found as usize;
+        found
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let found = matches!(
+            self.lexemes.get(self.position),
+            Some(Lexeme::Tree(tree)) if is_ident(Some(tree), keyword)
+        );
+        self.position += found as usize;
+        found
+    }
+
+    fn binary_level(&mut self, level: usize) -> Result<TokenStream, Error> {
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary_level(level + 1)?;
+        'outer: loop {
+            // `x between a and b` sits with the comparisons, meaning x >= a && x <= b
+            if LEVELS[level][0].1 == "Eq" && self.eat_keyword("between") {
+                let low = self.binary_level(level + 1)?;
+                if !self.eat_keyword("and") {
+                    return Err((self.span(), "expected `and`".into()));
+                }
+                let high = self.binary_level(level + 1)?;
+                lhs = binary(
+                    binary(lhs.clone(), "Ge", low),
+                    "And",
+                    binary(lhs, "Le", high),
+                );
+                continue;
+            }
+            for (op, name) in LEVELS[level] {
+                if self.eat_op(op) {
+                    let rhs = self.binary_level(level + 1)?;
+                    lhs = binary(lhs, name, rhs);
+                    continue 'outer;
+                }
+            }
+            return Ok(lhs);
+        }
+    }
+
+    fn unary(&mut self) -> Result<TokenStream, Error> {
+        for (op, name) in [("-", "Neg"), ("!", "Not")] {
+            if self.eat_op(op) {
+                let name = format_ident!("{}", name);
+                let operand = self.unary()?;
+                return Ok(quote! {
+                    ::rete::rule::Expr::Unary(
+                        ::rete::rule::UnaryOp::#name,
+                        ::std::boxed::Box::new(#operand),
+                    )
+                });
+            }
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<TokenStream, Error> {
+        let span = self.span();
+        let Some(Lexeme::Tree(tree)) = self.lexemes.get(self.position) else {
+            return Err((span, "expected an expression".into()));
+        };
+        let tree = tree.clone();
+        self.position += 1;
+        match &tree {
+            TokenTree::Literal(literal) => {
+                let value = value(literal, false)?;
+                Ok(quote! { ::rete::rule::Expr::Const(#value) })
+            }
+            TokenTree::Ident(ident) if ident == "true" || ident == "false" => {
+                Ok(quote! { ::rete::rule::Expr::Const(::rete::Value::Bool(#ident)) })
+            }
+            TokenTree::Ident(ident) => {
+                let name = string(&ident.to_string());
+                Ok(quote! { ::rete::rule::Expr::Var(#name) })
+            }
+            _ => match paren_contents(&tree) {
+                Some(contents) => expr(&contents),
+                None => Err((span, "expected an expression".into())),
+            },
+        }
+    }
+}
+
+fn binary(lhs: TokenStream, op: &str, rhs: TokenStream) -> TokenStream {
+    let op = format_ident!("{}", op);
+    quote! {
+        ::rete::rule::Expr::Binary(
+            ::std::boxed::Box::new(#lhs),
+            ::rete::rule::BinaryOp::#op,
+            ::std::boxed::Box::new(#rhs),
+        )
+    }
+}
+
+// An Expr
+pub(crate) fn expr(tokens: &[TokenTree]) -> Result<TokenStream, Error> {
+    if tokens.is_empty() {
+        return Err((Span::call_site(), "expected an expression".into()));
+    }
+    let mut parser = Parser {
+        lexemes: lex(tokens)?,
+        position: 0,
+    };
+    let expr = parser.binary_level(0)?;
+    if parser.position < parser.lexemes.len() {
+        return Err((parser.span(), "expected an operator or `,`".into()));
+    }
+    Ok(expr)
+}
+
+// A Value from a number or string literal
+pub(crate) fn value(literal: &Literal, negative: bool) -> Result<TokenStream, Error> {
+    let text = literal.to_string();
+    let sign = negative.then(|| quote!(-));
+    if text.starts_with('"') || text.starts_with('r') {
+        if negative {
+            return Err((literal.span(), "a string can't be negative".into()));
+        }
+        return Ok(quote! { ::rete::Value::Str(::std::string::String::from(#literal)) });
+    }
+    if !text.starts_with(|c: char| c.is_ascii_digit()) {
+        return Err((literal.span(), "expected a number or a string".into()));
+    }
+    Ok(match text.contains('.') {
+        true => quote! { ::rete::Value::Float(#sign #literal) },
+        false => quote! { ::rete::Value::Int(#sign #literal) },
+    })
+}
+
+// A Value from a constant pattern argument: a literal, a negative number, true or false
+pub(crate) fn constant(tokens: &[TokenTree]) -> Result<TokenStream, Error> {
+    match tokens {
+        [TokenTree::Ident(ident)] if ident == "true" || ident == "false" => {
+            Ok(quote! { ::rete::Value::Bool(#ident) })
+        }
+        [TokenTree::Literal(literal)] => value(literal, false),
+        [TokenTree::Punct(minus), TokenTree::Literal(literal)] if minus.as_char() == '-' => {
+            value(literal, true)
+        }
+        _ => Err((span_of(tokens), "expected a variable or literal".into())),
+    }
+}
+
+// A number literal at the front, possibly negative, with how many tokens it took
+fn signed(tokens: &[TokenTree]) -> Option<(String, usize)> {
+    match tokens {
+        [TokenTree::Punct(minus), TokenTree::Literal(literal), ..] if minus.as_char() == '-' => {
+            Some((format!("-{}", literal), 2))
+        }
+        [TokenTree::Literal(literal), ..] => Some((literal.to_string(), 1)),
+        _ => None,
+    }
+}
+
+// An integer or decimal without a suffix
+pub(crate) fn number(tokens: &[TokenTree]) -> Option<(f64, usize)> {
+    let (text, used) = signed(tokens)?;
+    Some((text.parse().ok()?, used))
+}
+
+pub(crate) fn integer(tokens: &[TokenTree]) -> Option<(i64, usize)> {
+    let (text, used) = signed(tokens)?;
+    Some((text.parse().ok()?, used))
+}
+
+// A Span, `5 ticks`, `60s`, `1.5s` or `250ms`
+pub(crate) fn span(tokens: &[TokenTree]) -> Result<TokenStream, Error> {
+    let at = span_of(tokens);
+    let (amount, unit) = match tokens {
+        [TokenTree::Literal(literal)] => {
+            let text = literal.to_string();
+            let split = text.find(|c: char| c.is_alphabetic()).unwrap_or(text.len());
+            (text[..split].to_string(), text[split..].to_string())
+        }
+        [TokenTree::Literal(literal), TokenTree::Ident(unit)] => {
+            (literal.to_string(), unit.to_string())
+        }
+        _ => return Err((at, "expected a length of time".into())),
+    };
+    let Ok(amount) = amount.parse::<f64>() else {
+        return Err((at, "expected a length of time".into()));
+    };
+    let seconds = match unit.as_str() {
+        "tick" | "ticks" if amount.fract() == 0.0 => {
+            let ticks = amount as u64;
+            return Ok(quote! { ::rete::Span::Ticks(#ticks) });
+        }
+        "s" => amount,
+        "ms" => amount / 1000.0,
+        _ => {
+            return Err((
+                at,
+                format!("expected `ticks`, `s` or `ms`, found `{}`", unit),
+            ))
+        }
+    };
+    let Ok(time) = Duration::try_from_secs_f64(seconds) else {
+        return Err((
+            at,
+            format!("`{}{}` is longer than a span can be", amount, unit),
+        ));
+    };
+    let (secs, nanos) = (time.as_secs(), time.subsec_nanos());
+    Ok(quote! { ::rete::Span::Time(::std::time::Duration::new(#secs, #nanos)) })
+}
+
+pub(crate) fn string(text: &str) -> TokenStream {
+    quote! { ::std::string::String::from(#text) }
+}
+
+pub(crate) fn option(built: Option<TokenStream>) -> TokenStream {
+    match built {
+        Some(built) => quote! { ::core::option::Option::Some(#built) },
+        None => quote! { ::core::option::Option::None },
+    }
+}
@@ -0,0 +1,741 @@
+// Procedural macros for rete
+//
+// rule! takes a single rule in the text DSL and checks it against the real component types:
+//
+// rule!("flee" when Health(e, h), h < 10, not Fleeing(e) then insert Fleeing(e))
+//
+// The Rule is built from the tokens at compile time, see build.rs, rather than parsed at
+// run time. Alongside it goes a closure that is never called, mirroring the rule with
+// typed values from TypedFact. An `on insert` or `on remove` trigger is checked as a
+// pattern. Unknown components, wrong argument counts and ill typed
+// guards or inserts then fail to compile instead of failing in RuleEngine::load_str.
+use proc_macro2::{Delimiter, Ident, Literal, Span, TokenStream, TokenTree};
+use quote::{format_ident, quote, quote_spanned};
+use std::collections::HashSet;
+
+mod build;
+
+#[proc_macro]
+pub fn rule(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = TokenStream::from(input);
+    match expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err((span, message)) => quote_spanned!(span=> compile_error!(#message)).into(),
+    }
+}
+
+type Error = (Span, String);
+
+enum Arg {
+    Var(Ident),
+    Wildcard,
+    Const(TokenStream),
+}
+
+struct Pattern {
+    negated: bool,
+    component: Ident,
+    args: Vec<Arg>,
+    // `since t` binds t to the tick the pattern began to hold
+    since: Option<Ident>,
+    // The built Temporal and `where` Expr, if any
+    temporal: Option<TokenStream>,
+    guard: Option<TokenStream>,
+}
+
+// `where` clauses can name the component's fields, which aren't known here, so they are
+// left to RuleEngine::add_rule to check
+
+enum Condition {
+    Pattern(Pattern),
+    // The test as written and as a built Expr
+    Test(TokenStream, TokenStream),
+    // `near(a, b, 5)`, `within(a, "town")`, `adjacent(a, b)` or `rcc8(a, b, "tpp")`
+    // Extra is the radius, region or relations
+    Spatial {
+        name: Ident,
+        entities: Vec<Arg>,
+        extra: Option<TokenStream>,
+        built: Option<TokenStream>,
+    },
+}
+
+// Each argument is kept as written, for the check, and as a built Expr
+enum Action {
+    Insert {
+        component: Ident,
+        args: Vec<TokenStream>,
+        exprs: Vec<TokenStream>,
+        ttl: Option<TokenStream>,
+    },
+    Remove {
+        component: Ident,
+        entity: TokenStream,
+        expr: TokenStream,
+    },
+}
+
+// What comes between the rule's name and its trigger or conditions
+#[derive(Default)]
+struct Header {
+    salience: i32,
+    weight: Option<f64>,
+    certainty: Option<f64>,
+    overrides: Vec<Literal>,
+    no_loop: bool,
+    lock_on_active: bool,
+}
+
+// `on insert` or `on remove`, its pattern is the first of the conditions
+struct Trigger {
+    kind: Ident,
+    window: Option<TokenStream>,
+}
+
+fn is_ident(token: Option<&TokenTree>, name: &str) -> bool {
+    matches!(token, Some(TokenTree::Ident(ident)) if ident == name)
+}
+
+fn is_punct(token: Option<&TokenTree>, c: char) -> bool {
+    matches!(token, Some(TokenTree::Punct(punct)) if punct.as_char() == c)
+}
+
+fn split_commas(tokens: Vec<TokenTree>) -> Vec<Vec<TokenTree>> {
+    let mut parts = vec![Vec::new()];
+    for token in tokens {
+        match &token {
+            TokenTree::Punct(punct) if punct.as_char() == ',' => parts.push(Vec::new()),
+            _ => parts.last_mut().unwrap().push(token),
+        }
+    }
+    parts
+}
+
+fn paren_contents(token: &TokenTree) -> Option<Vec<TokenTree>> {
+    match token {
+        TokenTree::Group(group) if group.delimiter() == Delimiter::Parenthesis => {
+            Some(group.stream().into_iter().collect())
+        }
+        _ => None,
+    }
+}
+
+fn span_of(tokens: &[TokenTree]) -> Span {
+    tokens.first().map_or_else(Span::call_site, TokenTree::span)
+}
+
+fn parse_arg(tokens: Vec<TokenTree>) -> Result<Arg, Error> {
+    match tokens.as_slice() {
+        [] => Err((Span::call_site(), "expected a pattern argument".into())),
+        [TokenTree::Ident(ident)] if ident == "_" => Ok(Arg::Wildcard),
+        [TokenTree::Ident(ident)] if ident != "true" && ident != "false" => {
+            Ok(Arg::Var(ident.clone()))
+        }
+        _ => Ok(Arg::Const(tokens.into_iter().collect())),
+    }
+}
+
+// `for at least 5 ticks`, `within 10s` or `since t` after a pattern, as a built Temporal
+fn parse_temporal(tokens: &[TokenTree]) -> Result<Option<(TokenStream, Option<Ident>)>, Error> {
+    let temporal = match tokens {
+        [] => return Ok(None),
+        [TokenTree::Ident(keyword), rest @ ..] if keyword == "for" => match rest {
+            [at, least, span @ ..]
+                if is_ident(Some(at), "at") && is_ident(Some(least), "least") =>
+            {
+                let span = build::span(span)?;
+                quote! { ::rete::rule::Temporal::AtLeast(#span) }
+            }
+            _ => return Err((keyword.span(), "expected `for at least`".into())),
+        },
+        [TokenTree::Ident(keyword), span @ ..] if keyword == "within" => {
+            let span = build::span(span)?;
+            quote! { ::rete::rule::Temporal::Within(#span) }
+        }
+        [TokenTree::Ident(keyword), TokenTree::Ident(var)] if keyword == "since" => {
+            let name = build::string(&var.to_string());
+            let since = quote! { ::rete::rule::Temporal::Since(#name) };
+            return Ok(Some((since, Some(var.clone()))));
+        }
+        _ => {
+            return Err((
+                span_of(tokens),
+                "expected `for at least`, `within` or `since` after the pattern".into(),
+            ))
+        }
+    };
+    Ok(Some((temporal, None)))
+}
+
+fn parse_condition(tokens: Vec<TokenTree>) -> Result<Condition, Error> {
+    let negated = is_ident(tokens.first(), "not");
+    let pattern = if negated { &tokens[1..] } else { &tokens[..] };
+    let (pattern, guard) = match pattern
+        .iter()
+        .position(|token| is_ident(Some(token), "where"))
+    {
+        Some(at) if at + 1 == pattern.len() => {
+            return Err((pattern[at].span(), "expected a test after `where`".into()))
+        }
+        Some(at) => (&pattern[..at], Some(build::expr(&pattern[at + 1..])?)),
+        None => (pattern, None),
+    };
+
+    if let [TokenTree::Ident(component), group, qualifiers @ ..] = pattern {
+        let is_component = component.to_string().starts_with(char::is_uppercase);
+        if let (true, Some(contents)) = (is_component, paren_contents(group)) {
+            let args = split_commas(contents)
+                .into_iter()
+                .map(parse_arg)
+                .collect::<Result<_, _>>()?;
+            let (temporal, since) = parse_temporal(qualifiers)?.unzip();
+            return Ok(Condition::Pattern(Pattern {
+                negated,
+                component: component.clone(),
+                args,
+                since: since.flatten(),
+                temporal,
+                guard,
+            }));
+        }
+    }
+    if guard.is_some() {
+        return Err((span_of(pattern), "`where` must follow a pattern".into()));
+    }
+    if let [TokenTree::Ident(name), group] = &tokens[..] {
+        let arity = match name.to_string().as_str() {
+            "near" | "rcc8" => Some(3),
+            "within" | "adjacent" => Some(2),
+            _ => None,
+        };
+        if let (Some(arity), Some(contents)) = (arity, paren_contents(group)) {
+            let mut args = split_commas(contents);
+            if args.len() != arity {
+                let message = format!("`{}` takes {} arguments", name, arity);
+                return Err((group.span(), message));
+            }
+            let extra = (name != "adjacent").then(|| args.pop().unwrap());
+            let built = extra.as_deref().map(build::expr).transpose()?;
+            return Ok(Condition::Spatial {
+                name: name.clone(),
+                entities: args.into_iter().map(parse_arg).collect::<Result<_, _>>()?,
+                extra: extra.map(|extra| extra.into_iter().collect()),
+                built,
+            });
+        }
+    }
+    if negated {
+        return Err((span_of(&tokens), "expected a pattern after `not`".into()));
+    }
+    if tokens.is_empty() {
+        return Err((Span::call_site(), "expected a condition".into()));
+    }
+    let expr = build::expr(&tokens)?;
+    Ok(Condition::Test(tokens.into_iter().collect(), expr))
+}
+
+fn parse_action(tokens: Vec<TokenTree>) -> Result<Action, Error> {
+    let span = span_of(&tokens);
+    // `insert Tag(e) for 5 ticks`, with a time to live
+    let (tokens, ttl) = match tokens.iter().position(|token| is_ident(Some(token), "for")) {
+        Some(3) if is_ident(tokens.first(), "insert") => {
+            (tokens[..3].to_vec(), Some(build::span(&tokens[4..])?))
+        }
+        _ => (tokens, None),
+    };
+    let (verb, component, contents) = match tokens.as_slice() {
+        [TokenTree::Ident(verb), TokenTree::Ident(component), group] => {
+            match paren_contents(group) {
+                Some(contents) => (verb.to_string(), component.clone(), contents),
+                None => return Err((group.span(), "expected `(`".into())),
+            }
+        }
+        _ => {
+            return Err((
+                span,
+                "expected `insert Component(..)` or `remove Component(..)`".into(),
+            ))
+        }
+    };
+    let args: Vec<Vec<TokenTree>> = split_commas(contents)
+        .into_iter()
+        .filter(|arg| !arg.is_empty())
+        .collect();
+    let exprs = args
+        .iter()
+        .map(|arg| build::expr(arg))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut args: Vec<TokenStream> = args
+        .into_iter()
+        .map(|arg| arg.into_iter().collect())
+        .collect();
+
+    match verb.as_str() {
+        "insert" => Ok(Action::Insert {
+            component,
+            args,
+            exprs,
+            ttl,
+        }),
+        "remove" if args.len() == 1 => Ok(Action::Remove {
+            component,
+            entity: args.pop().unwrap(),
+            expr: exprs.into_iter().next().unwrap(),
+        }),
+        "remove" => Err((span, "`remove` takes just the entity".into())),
+        _ => Err((
+            span,
+            format!("expected `insert` or `remove`, found `{}`", verb),
+        )),
+    }
+}
+
+// `salience 2 weight 1.5 certainty 0.8 overrides "a", "b" no-loop lock-on-active`, each
+// optional but in that order, as the rule parser takes them
+fn parse_header(tokens: &[TokenTree]) -> Result<Header, Error> {
+    let mut header = Header::default();
+    let mut rest = tokens;
+    if is_ident(rest.first(), "salience") {
+        let Some((salience, used)) = build::integer(&rest[1..]) else {
+            return Err((rest[0].span(), "expected an integer salience".into()));
+        };
+        header.salience = i32::try_from(salience).map_err(|_| {
+            (
+                rest[1].span(),
+                format!("salience `{}` is out of range", salience),
+            )
+        })?;
+        rest = &rest[1 + used..];
+    }
+    for (keyword, field) in [
+        ("weight", &mut header.weight),
+        ("certainty", &mut header.certainty),
+    ] {
+        if is_ident(rest.first(), keyword) {
+            let Some((number, used)) = build::number(&rest[1..]) else {
+                return Err((
+                    rest[0].span(),
+                    format!("expected a number for the {}", keyword),
+                ));
+            };
+            *field = Some(number);
+            rest = &rest[1 + used..];
+        }
+    }
+    if is_ident(rest.first(), "overrides") {
+        rest = &rest[1..];
+        loop {
+            match rest.first() {
+                Some(TokenTree::Literal(name)) if name.to_string().starts_with('"') => {
+                    header.overrides.push(name.clone());
+                }
+                _ => return Err((span_of(rest), "expected a rule name in quotes".into())),
+            }
+            rest = &rest[1..];
+            if !is_punct(rest.first(), ',') {
+                break;
+            }
+            rest = &rest[1..];
+        }
+    }
+    // Rust reads `no-loop` as `no`, `-` and `loop`
+    loop {
+        let (flag, words): (_, &[&str]) = if is_ident(rest.first(), "no") {
+            (&mut header.no_loop, &["no", "loop"])
+        } else if is_ident(rest.first(), "lock") {
+            (&mut header.lock_on_active, &["lock", "on", "active"])
+        } else {
+            break;
+        };
+        let len = 2 * words.len() - 1;
+        let spelled = (0..len).all(|i| match i % 2 {
+            0 => is_ident(rest.get(i), words[i / 2]),
+            _ => is_punct(rest.get(i), '-'),
+        });
+        if !spelled {
+            return Err((span_of(rest), format!("expected `{}`", words.join("-"))));
+        }
+        *flag = true;
+        rest = &rest[len..];
+    }
+    match rest.first() {
+        Some(token) => Err((token.span(), "expected `when` or `on`".into())),
+        None => Ok(header),
+    }
+}
+
+// `count 3 within 60s` after the trigger, as a built Window
+fn parse_window(tokens: &[TokenTree]) -> Result<Option<TokenStream>, Error> {
+    let [count, rest @ ..] = tokens else {
+        return Ok(None);
+    };
+    if !is_ident(Some(count), "count") {
+        return Err((count.span(), "expected `count` or `when`".into()));
+    }
+    let n = match build::integer(rest) {
+        Some((n, 1)) if n > 0 => n as usize,
+        _ => return Err((span_of(rest), "expected a positive count".into())),
+    };
+    let within = match &rest[1..] {
+        [] => None,
+        [within, span @ ..] if is_ident(Some(within), "within") => Some(build::span(span)?),
+        other => return Err((span_of(other), "expected `within` or `when`".into())),
+    };
+    let within = build::option(within);
+    Ok(Some(quote! {
+        ::rete::rule::Window { count: #n, within: #within }
+    }))
+}
+
+// Finds a keyword at the top level of the token list
+fn position_of(tokens: &[TokenTree], keyword: &str) -> Result<usize, Error> {
+    tokens
+        .iter()
+        .position(|token| is_ident(Some(token), keyword))
+        .ok_or_else(|| (Span::call_site(), format!("expected `{}`", keyword)))
+}
+
+fn expand(input: TokenStream) -> Result<TokenStream, Error> {
+    let tokens: Vec<TokenTree> = input.into_iter().collect();
+
+    let name = match tokens.first() {
+        Some(TokenTree::Literal(literal)) if literal.to_string().starts_with('"') => literal,
+        _ => {
+            return Err((
+                span_of(&tokens),
+                "expected the rule name as a string".into(),
+            ))
+        }
+    };
+
+    let then = position_of(&tokens, "then")?;
+    // Not the `on` of `lock-on-active`
+    let on =
+        (1..then).find(|&at| is_ident(tokens.get(at), "on") && !is_punct(tokens.get(at - 1), '-'));
+    // Reactive rules can leave out `when`
+    let when = match (position_of(&tokens, "when"), on) {
+        (Ok(when), _) => when,
+        (Err(_), Some(_)) => then,
+        (Err(error), None) => return Err(error),
+    };
+    if then < when {
+        return Err((tokens[then].span(), "`then` must come after `when`".into()));
+    }
+
+    let header = parse_header(&tokens[1..on.unwrap_or(when)])?;
+
+    // The trigger binds like a pattern at the front of the conditions
+    let mut conditions = Vec::new();
+    let mut trigger = None;
+    if let Some(on) = on {
+        let kind = match tokens.get(on + 1) {
+            Some(TokenTree::Ident(verb)) if verb == "insert" => format_ident!("Insert"),
+            Some(TokenTree::Ident(verb)) if verb == "remove" => format_ident!("Remove"),
+            verb => {
+                let span = verb.map_or_else(|| tokens[on].span(), TokenTree::span);
+                return Err((span, "expected `insert` or `remove` after `on`".into()));
+            }
+        };
+        let end = tokens[on..when]
+            .iter()
+            .position(|token| is_ident(Some(token), "count"))
+            .map_or(when, |count| on + count);
+        match parse_condition(tokens[on + 2..end].to_vec())? {
+            Condition::Pattern(pattern) if !pattern.negated => {
+                conditions.push(Condition::Pattern(pattern))
+            }
+            _ => return Err((span_of(&tokens[on + 2..]), "expected a pattern".into())),
+        }
+        let window = parse_window(&tokens[end..when])?;
+        trigger = Some(Trigger { kind, window });
+    }
+    if when < then {
+        for condition in split_commas(tokens[when + 1..then].to_vec()) {
+            conditions.push(parse_condition(condition)?);
+        }
+    }
+    let actions = split_commas(tokens[then + 1..].to_vec())
+        .into_iter()
+        .map(parse_action)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let check = check_closure(&conditions, &actions);
+    let rule = build_rule(name, header, trigger, conditions, actions)?;
+    Ok(quote! {
+        {
+            #check
+            #rule
+        }
+    })
+}
+
+fn build_term(arg: &Arg) -> Result<TokenStream, Error> {
+    Ok(match arg {
+        Arg::Var(var) => {
+            let name = build::string(&var.to_string());
+            quote! { ::rete::rule::Term::Var(#name) }
+        }
+        Arg::Wildcard => quote! { ::rete::rule::Term::Wildcard },
+        Arg::Const(constant) => {
+            let tokens: Vec<TokenTree> = constant.clone().into_iter().collect();
+            let value = build::constant(&tokens)?;
+            quote! { ::rete::rule::Term::Const(#value) }
+        }
+    })
+}
+
+fn build_pattern(pattern: Pattern) -> Result<TokenStream, Error> {
+    let component = build::string(&pattern.component.to_string());
+    let args = pattern
+        .args
+        .iter()
+        .map(build_term)
+        .collect::<Result<Vec<_>, _>>()?;
+    let guard = build::option(pattern.guard);
+    let temporal = build::option(pattern.temporal);
+    Ok(quote! {
+        ::rete::rule::Pattern {
+            component: #component,
+            args: ::std::vec![#(#args),*],
+            guard: #guard,
+            temporal: #temporal,
+        }
+    })
+}
+
+// The Rule the rule parser would make of the same text
+fn build_rule(
+    name: &Literal,
+    header: Header,
+    trigger: Option<Trigger>,
+    conditions: Vec<Condition>,
+    actions: Vec<Action>,
+) -> Result<TokenStream, Error> {
+    let mut conditions = conditions.into_iter();
+    let trigger = match trigger {
+        Some(Trigger { kind, window }) => {
+            let Some(Condition::Pattern(pattern)) = conditions.next() else {
+                unreachable!("the trigger's pattern comes first");
+            };
+            let pattern = build_pattern(pattern)?;
+            let window = build::option(window);
+            Some(quote! {
+                ::rete::rule::Trigger {
+                    kind: ::rete::rule::TriggerKind::#kind,
+                    pattern: #pattern,
+                    window: #window,
+                }
+            })
+        }
+        None => None,
+    };
+
+    let mut built = Vec::new();
+    for condition in conditions {
+        built.push(match condition {
+            Condition::Pattern(pattern) if pattern.negated => {
+                let pattern = build_pattern(pattern)?;
+                quote! { ::rete::rule::Condition::Not(#pattern) }
+            }
+            Condition::Pattern(pattern) => {
+                let pattern = build_pattern(pattern)?;
+                quote! { ::rete::rule::Condition::Pattern(#pattern) }
+            }
+            Condition::Test(_, expr) => quote! { ::rete::rule::Condition::Test(#expr) },
+            Condition::Spatial {
+                name,
+                entities,
+                built,
+                ..
+            } => {
+                let terms = entities
+                    .iter()
+                    .map(build_term)
+                    .collect::<Result<Vec<_>, _>>()?;
+                let variant = match name.to_string().as_str() {
+                    "near" => format_ident!("Near"),
+                    "within" => format_ident!("Within"),
+                    "adjacent" => format_ident!("Adjacent"),
+                    _ => format_ident!("Rcc8"),
+                };
+                let args = terms.into_iter().chain(built);
+                quote! {
+                    ::rete::rule::Condition::Spatial(::rete::rule::Spatial::#variant(#(#args),*))
+                }
+            }
+        });
+    }
+
+    let actions = actions.into_iter().map(|action| match action {
+        Action::Insert {
+            component,
+            exprs,
+            ttl,
+            ..
+        } => {
+            let component = build::string(&component.to_string());
+            let ttl = build::option(ttl);
+            quote! {
+                ::rete::rule::Action::Insert {
+                    component: #component,
+                    args: ::std::vec![#(#exprs),*],
+                    ttl: #ttl,
+                }
+            }
+        }
+        Action::Remove {
+            component, expr, ..
+        } => {
+            let component = build::string(&component.to_string());
+            quote! {
+                ::rete::rule::Action::Remove {
+                    component: #component,
+                    entity: #expr,
+                }
+            }
+        }
+    });
+
+    let Header {
+        salience,
+        weight,
+        certainty,
+        overrides,
+        no_loop,
+        lock_on_active,
+    } = header;
+    let weight = build::option(weight.map(|weight| quote!(#weight)));
+    let certainty = build::option(certainty.map(|certainty| quote!(#certainty)));
+    let trigger = build::option(trigger);
+    Ok(quote! {
+        ::rete::rule::Rule {
+            name: ::std::string::String::from(#name),
+            salience: #salience,
+            weight: #weight,
+            certainty: #certainty,
+            overrides: ::std::vec![#(::std::string::String::from(#overrides)),*],
+            no_loop: #no_loop,
+            lock_on_active: #lock_on_active,
+            module: ::core::option::Option::None,
+            trigger: #trigger,
+            conditions: ::std::vec![#(#built),*],
+            actions: ::std::vec![#(#actions),*],
+        }
+    })
+}
+
+// Binds or compares each pattern argument against the typed entity and fields
+fn check_pattern(index: usize, pattern: &Pattern, bound: &mut HashSet<String>) -> TokenStream {
+    let component = &pattern.component;
+    let entity = format_ident!("__entity_{}", index);
+    let fact = format_ident!("__fact_{}", index);
+    let fields: Vec<Ident> = (1..pattern.args.len())
+        .map(|field| format_ident!("__field_{}_{}", index, field))
+        .collect();
+
+    let mut statements = vec![quote_spanned! {component.span()=>
+        let (#(#fields,)*) = <#component as ::rete::registry::TypedFact>::fields(#fact);
+    }];
+    let sources = std::iter::once(&entity).chain(fields.iter());
+
+    for (arg, source) in pattern.args.iter().zip(sources) {
+        statements.push(match arg {
+            Arg::Wildcard => continue,
+            Arg::Const(constant) => quote! { let _: bool = #source == #constant; },
+            Arg::Var(var) if bound.contains(&var.to_string()) => {
+                quote_spanned! {var.span()=> let _: bool = #var == #source; }
+            }
+            Arg::Var(var) => {
+                bound.insert(var.to_string());
+                quote! { let #var = #source; }
+            }
+        });
+    }
+    if let Some(var) = &pattern.since {
+        statements.push(if bound.insert(var.to_string()) {
+            quote! { let #var: i64 = 0; }
+        } else {
+            quote_spanned! {var.span()=> let _: bool = #var == 0i64; }
+        });
+    }
+    quote! { #(#statements)* }
+}
+
+fn check_closure(conditions: &[Condition], actions: &[Action]) -> TokenStream {
+    let mut params = Vec::new();
+    let mut statements = Vec::new();
+    let mut bound = HashSet::new();
+
+    for condition in conditions {
+        match condition {
+            Condition::Pattern(pattern) => {
+                let index = params.len();
+                let component = &pattern.component;
+                let entity = format_ident!("__entity_{}", index);
+                let fact = format_ident!("__fact_{}", index);
+                params.push(quote! { #entity: ::rete::EntityId, #fact: &#component });
+
+                if pattern.negated {
+                    // Variables first seen inside `not` are local to it
+                    let checks = check_pattern(index, pattern, &mut bound.clone());
+                    statements.push(quote! { { #checks } });
+                } else {
+                    statements.push(check_pattern(index, pattern, &mut bound));
+                }
+            }
+            Condition::Test(expr, _) => statements.push(quote! { let _: bool = #expr; }),
+            Condition::Spatial {
+                name,
+                entities,
+                extra,
+                ..
+            } => {
+                statements.extend(extra.iter().map(|extra| match name.to_string().as_str() {
+                    "near" => quote! { let _: i64 = #extra; },
+                    _ => quote! { let _: &str = #extra; },
+                }));
+                for arg in entities {
+                    statements.push(match arg {
+                        Arg::Wildcard => continue,
+                        Arg::Const(constant) => quote! { let _: ::rete::EntityId = #constant; },
+                        Arg::Var(var) if bound.contains(&var.to_string()) => {
+                            quote_spanned! {var.span()=> let _: ::rete::EntityId = #var; }
+                        }
+                        Arg::Var(var) => {
+                            bound.insert(var.to_string());
+                            quote! { let #var: ::rete::EntityId = 0; }
+                        }
+                    });
+                }
+            }
+        }
+    }
+
+    for action in actions {
+        statements.push(match action {
+            Action::Insert {
+                component, args, ..
+            } => {
+                let (entity, fields) = match args.split_first() {
+                    Some((entity, fields)) => (entity.clone(), fields),
+                    None => (quote_spanned!(component.span()=> ()), &[][..]),
+                };
+                quote_spanned! {component.span()=>
+                    let _: ::rete::EntityId = #entity;
+                    let _: #component = <#component as ::rete::registry::TypedFact>::from_fields((#(#fields,)*));
+                }
+            }
+            Action::Remove {
+                component, entity, ..
+            } => quote! {
+                let _: ::rete::EntityId = #entity;
+                let _ = ::core::marker::PhantomData::<#component>;
+            },
+        });
+    }
+
+    quote! {
+        #[allow(unused_variables, unused_parens, clippy::all)]
+        let _check = |#(#params),*| { #(#statements)* };
+    }
+}
@@ -0,0 +1,222 @@
+// Borrowing pools without borrowing the store
+//
+// Every pool sits in its own AtomicRefCell, see cell.rs, so pools can be borrowed one
+// at a time through a shared reference to the store. Two systems, on one thread or on
+// two, can then hold &mut Pool<A> and &mut Pool<B> at once, with only a conflicting
+// borrow of the same pool refused. pool and pool_mut panic on a conflict as RefCell
+// does, try_pool and try_pool_mut return Error::Borrowed instead. Changes made through
+// a borrowed pool skip hooks, as with get, but they move the pool's change ticks, so
+// refresh_indexes still catches indexes up.
+//
+// pools_mut borrows several pools for writing in one call, given their types as a
+// tuple, so a system touching positions, velocities and health takes all three at once
+// and gets back a tuple of borrows. Naming a type twice is a conflicting borrow like any
+// other, panicking in pools_mut and an error from try_pools_mut.
+use crate::cell::{AtomicRef, AtomicRefCell, AtomicRefMut};
+use crate::error::Error;
+use crate::relation::short_type_name;
+use crate::store::{Component, EntityStore, Pool, PoolRef};
+use std::any::TypeId;
+
+// Tuples of up to eight component types, whose pools pools_mut borrows together
+pub trait PoolSet {
+    type Borrowed<'a>;
+
+    fn type_ids() -> Vec<TypeId>;
+
+    fn borrow_all(store: &EntityStore) -> Result<Self::Borrowed<'_>, Error>;
+}
+
+macro_rules! pool_set {
+    ($($name:ident),+) => {
+        impl<$($name: Component + Eq + 'static),+> PoolSet for ($($name,)+) {
+            type Borrowed<'a> = ($(AtomicRefMut<'a, Pool<$name>>,)+);
+
+            fn type_ids() -> Vec<TypeId> {
+                vec![$(TypeId::of::<$name>()),+]
+            }
+
+            // A borrow already taken is dropped again if a later one fails
+            fn borrow_all(store: &EntityStore) -> Result<Self::Borrowed<'_>, Error> {
+                Ok(($(store.try_pool_mut::<$name>()?,)+))
+            }
+        }
+    };
+}
+
+pool_set!(A);
+pool_set!(A, B);
+pool_set!(A, B, C);
+pool_set!(A, B, C, D);
+pool_set!(A, B, C, D, E);
+pool_set!(A, B, C, D, E, F);
+pool_set!(A, B, C, D, E, F, G);
+pool_set!(A, B, C, D, E, F, G, H);
+
+impl EntityStore {
+    // T's pool, borrowed for reading, None if it has none
+    pub fn pool<T: Component + Eq + 'static>(&self) -> Option<AtomicRef<'_, Pool<T>>> {
+        Some(self.get::<T>()?.borrow())
+    }
+
+    // T's pool, borrowed for writing, None if it has none
+    pub fn pool_mut<T: Component + Eq + 'static>(&self) -> Option<AtomicRefMut<'_, Pool<T>>> {
+        Some(self.get::<T>()?.borrow_mut())
+    }
+
+    pub fn try_pool<T: Component + Eq + 'static>(&self) -> Result<AtomicRef<'_, Pool<T>>, Error> {
+        self.pool_cell::<T>()?
+            .try_borrow()
+            .ok_or_else(|| Error::Borrowed(short_type_name::<T>().to_string()))
+    }
+
+    pub fn try_pool_mut<T: Component + Eq + 'static>(
+        &self,
+    ) -> Result<AtomicRefMut<'_, Pool<T>>, Error> {
+        self.pool_cell::<T>()?
+            .try_borrow_mut()
+            .ok_or_else(|| Error::Borrowed(short_type_name::<T>().to_string()))
+    }
+
+    // Every pool in the tuple, borrowed for writing, None if any has no pool
+    pub fn pools_mut<P: PoolSet>(&self) -> Option<P::Borrowed<'_>> {
+        match P::borrow_all(self) {
+            Ok(pools) => Some(pools),
+            Err(Error::UnknownComponent(_)) => None,
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    pub fn try_pools_mut<P: PoolSet>(&self) -> Result<P::Borrowed<'_>, Error> {
+        P::borrow_all(self)
+    }
+
+    // A pool by TypeId, type erased, for callers without the type, see reflect.rs
+    pub fn pool_ref(&self, type_id: TypeId) -> Result<AtomicRef<'_, dyn PoolRef>, Error> {
+        self.pool_ref_cell(type_id)?
+            .try_borrow()
+            .ok_or_else(|| Error::Borrowed(format!("{:?}", type_id)))
+    }
+
+    pub fn pool_ref_mut(&self, type_id: TypeId) -> Result<AtomicRefMut<'_, dyn PoolRef>, Error> {
+        self.pool_ref_cell(type_id)?
+            .try_borrow_mut()
+            .ok_or_else(|| Error::Borrowed(format!("{:?}", type_id)))
+    }
+
+    // A pool borrowed for writing can't say its type, so may be the one wanted
+    fn pool_ref_cell(&self, type_id: TypeId) -> Result<&AtomicRefCell<dyn PoolRef>, Error> {
+        let mut borrowed = false;
+        let found = self
+            .pool_refs
+            .0
+            .iter()
+            .find(|pool| match pool.try_borrow() {
+                Some(pool) => pool.component_type() == type_id,
+                None => {
+                    borrowed = true;
+                    false
+                }
+            });
+        match found {
+            Some(pool) => Ok(&**pool),
+            None if borrowed => Err(Error::Borrowed(format!("{:?}", type_id))),
+            None => Err(Error::UnknownComponent(format!("{:?}", type_id))),
+        }
+    }
+
+    fn pool_cell<T: Component + Eq + 'static>(&self) -> Result<&AtomicRefCell<Pool<T>>, Error> {
+        self.get::<T>()
+            .map(|pool| &**pool)
+            .ok_or_else(|| Error::UnknownComponent(short_type_name::<T>().to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Position(i64);
+    impl Component for Position {}
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Velocity(i64);
+    impl Component for Velocity {}
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Frozen;
+    impl Component for Frozen {}
+
+    #[test]
+    fn disjoint_pools_borrow_mutably_at_once() {
+        let mut store = EntityStore::new();
+        store.new_component::<Position>();
+        store.new_component::<Velocity>();
+        for entity_id in 0..1000 {
+            store.add_component(entity_id, Position(0));
+            store.add_component(entity_id, Velocity(1));
+        }
+
+        let store = &store;
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut positions = store.pool_mut::<Position>().unwrap();
+                positions.components_iter_mut().for_each(|(_, p)| p.0 += 5);
+            });
+            scope.spawn(|| {
+                let mut velocities = store.pool_mut::<Velocity>().unwrap();
+                velocities.components_iter_mut().for_each(|(_, v)| v.0 *= 2);
+            });
+        });
+        assert_eq!(
+            store.pool::<Position>().unwrap().get(999),
+            Some(&Position(5))
+        );
+        assert_eq!(store.pool::<Velocity>().unwrap().get(0), Some(&Velocity(2)));
+
+        let positions = store.pool_mut::<Position>().unwrap();
+        let velocities = store.pool::<Velocity>().unwrap();
+        assert_eq!(
+            store.try_pool::<Position>().unwrap_err(),
+            Error::Borrowed("Position".to_string())
+        );
+        assert!(store.try_pool::<Velocity>().is_ok());
+        assert!(store.try_pool_mut::<Velocity>().is_err());
+        assert_eq!(
+            store.try_pool::<Frozen>().unwrap_err(),
+            Error::UnknownComponent("Frozen".to_string())
+        );
+        drop((positions, velocities));
+        assert!(store.try_pool_mut::<Position>().is_ok());
+    }
+
+    #[test]
+    fn pools_borrow_together() {
+        let mut store = EntityStore::new();
+        store.new_component::<Position>();
+        store.new_component::<Velocity>();
+        store.add_component(1, Position(10));
+        store.add_component(1, Velocity(3));
+
+        {
+            let (mut positions, velocities) = store.pools_mut::<(Position, Velocity)>().unwrap();
+            for (entity_id, position) in positions.components_iter_mut() {
+                position.0 += velocities.get(*entity_id).map_or(0, |v| v.0);
+            }
+        }
+        assert_eq!(
+            store.pool::<Position>().unwrap().get(1),
+            Some(&Position(13))
+        );
+        assert!(store.pools_mut::<(Position, Frozen)>().is_none());
+        assert_eq!(
+            store
+                .try_pools_mut::<(Position, Velocity, Position)>()
+                .err(),
+            Some(Error::Borrowed("Position".to_string()))
+        );
+        // The borrows taken before the duplicate were let go
+        assert!(store.try_pools_mut::<(Velocity, Position)>().is_ok());
+    }
+}
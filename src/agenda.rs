@@ -0,0 +1,132 @@
+// Inspecting and steering the agenda
+//
+// pending lists every activation waiting to fire, in the order they will: first the
+// queue of reactive and injected activations, then those whose conditions hold, by
+// salience and rule order. Reactive matches whose other conditions haven't been
+// matched yet, see reactive.rs, are listed with the trigger's bindings only. Activations
+// that have fired and still match, or are held back, see looping.rs, aren't pending.
+//
+// cancel takes one off the agenda. A queued activation is dropped from the queue, and
+// one whose conditions hold is treated as having fired, so it waits until it stops
+// matching and matches again, as refraction would have it. inject queues an activation
+// of a rule by hand with whatever bindings its actions need, firing on the next run or
+// step whether or not its conditions hold.
+use crate::engine::{Activation, RuleEngine};
+use crate::error::Error;
+use crate::store::EntityStore;
+use crate::value::Bindings;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pending {
+    pub activation: Activation,
+    pub rule: String,
+    pub salience: i32,
+    // Reactive or injected, firing ahead of the rest
+    pub queued: bool,
+}
+
+impl RuleEngine {
+    // See above
+    pub fn pending(&self, store: &EntityStore) -> Vec<Pending> {
+        let queued = self
+            .reactions
+            .iter()
+            .chain(&self.triggered)
+            .cloned()
+            .map(|activation| (activation, true));
+        let ready = self
+            .activations(store)
+            .into_iter()
+            .filter(|activation| !self.fired.contains(activation) && !self.held_back(activation))
+            .map(|activation| (activation, false));
+        queued
+            .chain(ready)
+            .map(|(activation, queued)| {
+                let rule = &self.rules[activation.rule];
+                Pending {
+                    rule: rule.name.clone(),
+                    salience: rule.salience,
+                    activation,
+                    queued,
+                }
+            })
+            .collect()
+    }
+
+    // Returns false if the activation wasn't queued and had already been cancelled or fired
+    pub fn cancel(&mut self, activation: &Activation) -> bool {
+        for queue in [&mut self.reactions, &mut self.triggered] {
+            if let Some(position) = queue.iter().position(|queued| queued == activation) {
+                return queue.remove(position).is_some();
+            }
+        }
+        self.fired.insert(activation.clone())
+    }
+
+    // Queues an activation of the first rule with this name, see above
+    pub fn inject(&mut self, rule: &str, bindings: Bindings) -> Result<(), Error> {
+        let index = self
+            .rules
+            .iter()
+            .position(|candidate| candidate.name == rule)
+            .ok_or_else(|| Error::UnknownRule(rule.to_string()))?;
+        self.reactions.push_back(Activation {
+            rule: index,
+            bindings,
+            premises: Vec::new(),
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::Value;
+
+    #[test]
+    fn agenda_can_be_steered() {
+        let mut engine = RuleEngine::new();
+        engine
+            .load_str(
+                r#"
+                fact Health(points)
+                fact Fleeing()
+                fact Healed()
+                rule "flee" salience 5 when Health(e, h), h < 10 then insert Fleeing(e)
+                rule "heal" when Health(e, h), h < 10 then insert Healed(e)
+                "#,
+            )
+            .unwrap();
+        let registry = engine.registry().clone();
+        let mut store = EntityStore::new();
+        for entity_id in [1, 2] {
+            store
+                .insert_fact(&registry, "Health", entity_id, &[Value::Int(5)])
+                .unwrap();
+        }
+
+        let pending = engine.pending(&store);
+        let listed: Vec<(&str, i32)> = pending
+            .iter()
+            .map(|pending| (pending.rule.as_str(), pending.salience))
+            .collect();
+        assert_eq!(listed, [("flee", 5), ("flee", 5), ("heal", 0), ("heal", 0)]);
+
+        // Nobody flees, and entity 3 is healed though it has no Health
+        for pending in pending.iter().filter(|pending| pending.rule == "flee") {
+            assert!(engine.cancel(&pending.activation));
+        }
+        let bindings = Bindings::from([("e".to_string(), Value::Entity(3))]);
+        engine.inject("heal", bindings).unwrap();
+        assert!(engine.pending(&store)[0].queued);
+        assert_eq!(engine.run(&mut store), 3);
+        assert!(registry.get("Fleeing").unwrap().facts(&store).is_empty());
+        assert_eq!(registry.get("Healed").unwrap().facts(&store).len(), 3);
+
+        assert_eq!(
+            engine.inject("rest", Bindings::new()),
+            Err(Error::UnknownRule("rest".to_string()))
+        );
+    }
+}
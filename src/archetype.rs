@@ -0,0 +1,316 @@
+// Archetype storage, an alternative to EntityStore's sparse sets
+//
+// Entities with the same set of component types share a table, which holds one column
+// per type with a row per entity. Iterating several components together walks the
+// tables having all of them and zips their columns, touching nothing else, which suits
+// worlds mostly read in wide passes. The cost is paid on change: adding or removing a
+// component moves the entity's whole row to another table. A world picks one or the
+// other by which store it creates. Rules still match against an EntityStore, so an
+// ArchetypeStore is for host side systems that don't need the engine.
+use crate::store::{Component, EntityId};
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+
+// A table column, a Vec<T> seen without knowing T
+trait Column {
+    // Removes the row, moving the last one into its place
+    fn swap_remove(&mut self, row: usize);
+    // Moves the row to the end of another column of the same type, as swap_remove does
+    fn move_row(&mut self, row: usize, to: &mut dyn Column);
+    fn empty(&self) -> Box<dyn Column>;
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: 'static> Column for Vec<T> {
+    fn swap_remove(&mut self, row: usize) {
+        Vec::swap_remove(self, row);
+    }
+
+    fn move_row(&mut self, row: usize, to: &mut dyn Column) {
+        let value = Vec::swap_remove(self, row);
+        to.as_any_mut()
+            .downcast_mut::<Vec<T>>()
+            .expect("columns of a type hold that type")
+            .push(value);
+    }
+
+    fn empty(&self) -> Box<dyn Column> {
+        Box::new(Vec::<T>::new())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+struct Table {
+    // Sorted, identifies the archetype
+    types: Vec<TypeId>,
+    columns: HashMap<TypeId, Box<dyn Column>>,
+    entities: Vec<EntityId>,
+}
+
+impl Table {
+    fn column<T: 'static>(&self) -> Option<&Vec<T>> {
+        self.columns
+            .get(&TypeId::of::<T>())?
+            .as_any()
+            .downcast_ref()
+    }
+
+    fn column_mut<T: 'static>(&mut self) -> Option<&mut Vec<T>> {
+        self.columns
+            .get_mut(&TypeId::of::<T>())?
+            .as_any_mut()
+            .downcast_mut()
+    }
+}
+
+#[derive(Default)]
+pub struct ArchetypeStore {
+    tables: Vec<Table>,
+    by_types: HashMap<Vec<TypeId>, usize>,
+    // The table and row of every entity with at least one component
+    locations: HashMap<EntityId, (usize, usize)>,
+}
+
+impl fmt::Debug for ArchetypeStore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ArchetypeStore")
+            .field("tables", &self.tables.len())
+            .field("entities", &self.locations.len())
+            .finish()
+    }
+}
+
+impl ArchetypeStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Number of distinct component sets in use
+    pub fn archetypes(&self) -> usize {
+        self.tables
+            .iter()
+            .filter(|table| !table.entities.is_empty())
+            .count()
+    }
+
+    // The table for a set of types, creating it with empty columns like those of another
+    // table, plus one for T if add is set
+    fn table_for<T: 'static>(
+        &mut self,
+        types: Vec<TypeId>,
+        like: Option<usize>,
+        add: bool,
+    ) -> usize {
+        if let Some(&index) = self.by_types.get(&types) {
+            return index;
+        }
+        let mut columns: HashMap<TypeId, Box<dyn Column>> = like
+            .into_iter()
+            .flat_map(|like| &self.tables[like].columns)
+            .filter(|(type_id, _)| types.contains(type_id))
+            .map(|(&type_id, column)| (type_id, column.empty()))
+            .collect();
+        if add {
+            columns.insert(TypeId::of::<T>(), Box::new(Vec::<T>::new()));
+        }
+        self.tables.push(Table {
+            types: types.clone(),
+            columns,
+            entities: Vec::new(),
+        });
+        self.by_types.insert(types, self.tables.len() - 1);
+        self.tables.len() - 1
+    }
+
+    // Moves the entity's row from one table to another, dropping what the target has no
+    // column for, except the taken column the caller has already removed the row from
+    fn move_entity(
+        &mut self,
+        entity_id: EntityId,
+        (from, row): (usize, usize),
+        to: usize,
+        taken: Option<TypeId>,
+    ) {
+        let (source, target) = if from < to {
+            let (left, right) = self.tables.split_at_mut(to);
+            (&mut left[from], &mut right[0])
+        } else {
+            let (left, right) = self.tables.split_at_mut(from);
+            (&mut right[0], &mut left[to])
+        };
+        for (type_id, column) in &mut source.columns {
+            match target.columns.get_mut(type_id) {
+                Some(to) => column.move_row(row, to.as_mut()),
+                None if Some(*type_id) == taken => {}
+                None => column.swap_remove(row),
+            }
+        }
+        source.entities.swap_remove(row);
+        if let Some(&moved) = source.entities.get(row) {
+            self.locations.insert(moved, (from, row));
+        }
+        target.entities.push(entity_id);
+        self.locations
+            .insert(entity_id, (to, target.entities.len() - 1));
+    }
+
+    // Adds a component, or overrides it if there already is one
+    // Returns the component that was overridden
+    pub fn add_component<T: Component + 'static>(
+        &mut self,
+        entity_id: EntityId,
+        component: T,
+    ) -> Option<T> {
+        let Some(&(from, row)) = self.locations.get(&entity_id) else {
+            let table = self.table_for::<T>(vec![TypeId::of::<T>()], None, true);
+            let table_ref = &mut self.tables[table];
+            table_ref.column_mut::<T>().unwrap().push(component);
+            table_ref.entities.push(entity_id);
+            self.locations
+                .insert(entity_id, (table, table_ref.entities.len() - 1));
+            return None;
+        };
+        if let Some(column) = self.tables[from].column_mut::<T>() {
+            return Some(std::mem::replace(&mut column[row], component));
+        }
+        let mut types = self.tables[from].types.clone();
+        types.push(TypeId::of::<T>());
+        types.sort();
+        let to = self.table_for::<T>(types, Some(from), true);
+        self.move_entity(entity_id, (from, row), to, None);
+        self.tables[to].column_mut::<T>().unwrap().push(component);
+        None
+    }
+
+    // Removes the entity's component and returns it
+    pub fn remove_component<T: Component + 'static>(&mut self, entity_id: EntityId) -> Option<T> {
+        let &(from, row) = self.locations.get(&entity_id)?;
+        let removed = self.tables[from].column_mut::<T>()?.swap_remove(row);
+        let types: Vec<TypeId> = self.tables[from]
+            .types
+            .iter()
+            .copied()
+            .filter(|&type_id| type_id != TypeId::of::<T>())
+            .collect();
+        if types.is_empty() {
+            // T was its only component, so there is no row left to move
+            let table = &mut self.tables[from];
+            table.entities.swap_remove(row);
+            if let Some(&moved) = table.entities.get(row) {
+                self.locations.insert(moved, (from, row));
+            }
+            self.locations.remove(&entity_id);
+        } else {
+            let to = self.table_for::<T>(types, Some(from), false);
+            self.move_entity(entity_id, (from, row), to, Some(TypeId::of::<T>()));
+        }
+        Some(removed)
+    }
+
+    pub fn remove_entity(&mut self, entity_id: EntityId) {
+        let Some((table, row)) = self.locations.remove(&entity_id) else {
+            return;
+        };
+        let table_ref = &mut self.tables[table];
+        for column in table_ref.columns.values_mut() {
+            column.swap_remove(row);
+        }
+        table_ref.entities.swap_remove(row);
+        if let Some(&moved) = table_ref.entities.get(row) {
+            self.locations.insert(moved, (table, row));
+        }
+    }
+
+    pub fn get<T: Component + 'static>(&self, entity_id: EntityId) -> Option<&T> {
+        let &(table, row) = self.locations.get(&entity_id)?;
+        self.tables[table].column::<T>()?.get(row)
+    }
+
+    pub fn get_mut<T: Component + 'static>(&mut self, entity_id: EntityId) -> Option<&mut T> {
+        let &(table, row) = self.locations.get(&entity_id)?;
+        self.tables[table].column_mut::<T>()?.get_mut(row)
+    }
+
+    pub fn has_component<T: Component + 'static>(&self, entity_id: EntityId) -> bool {
+        self.get::<T>(entity_id).is_some()
+    }
+
+    // Every entity with both A and B, table by table
+    pub fn query<A: Component + 'static, B: Component + 'static>(
+        &self,
+    ) -> impl Iterator<Item = (EntityId, &A, &B)> {
+        self.tables.iter().flat_map(|table| {
+            let columns = table.column::<A>().zip(table.column::<B>());
+            columns.into_iter().flat_map(move |(a, b)| {
+                table
+                    .entities
+                    .iter()
+                    .zip(a.iter().zip(b))
+                    .map(|(&entity_id, (a, b))| (entity_id, a, b))
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Position(i64);
+    impl Component for Position {}
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Velocity(i64);
+    impl Component for Velocity {}
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Frozen;
+    impl Component for Frozen {}
+
+    fn moving(store: &ArchetypeStore) -> Vec<(EntityId, i64, i64)> {
+        let mut moving: Vec<_> = store
+            .query::<Position, Velocity>()
+            .map(|(entity_id, p, v)| (entity_id, p.0, v.0))
+            .collect();
+        moving.sort();
+        moving
+    }
+
+    #[test]
+    fn rows_move_between_tables() {
+        let mut store = ArchetypeStore::new();
+        for entity_id in 0..4 {
+            store.add_component(entity_id, Position(entity_id as i64));
+            store.add_component(entity_id, Velocity(1));
+        }
+        store.add_component(2, Frozen);
+        store.add_component(9, Velocity(5));
+        assert_eq!(store.archetypes(), 3);
+        assert_eq!(
+            moving(&store),
+            vec![(0, 0, 1), (1, 1, 1), (2, 2, 1), (3, 3, 1)]
+        );
+
+        assert_eq!(store.add_component(1, Velocity(2)), Some(Velocity(1)));
+        assert_eq!(store.remove_component::<Velocity>(0), Some(Velocity(1)));
+        assert_eq!(store.remove_component::<Velocity>(0), None);
+        assert_eq!(store.remove_component::<Frozen>(2), Some(Frozen));
+        store.get_mut::<Position>(3).unwrap().0 = 30;
+        store.remove_entity(9);
+        assert_eq!(moving(&store), vec![(1, 1, 2), (2, 2, 1), (3, 30, 1)]);
+        assert_eq!(store.get::<Position>(0), Some(&Position(0)));
+        assert_eq!(store.remove_component::<Position>(0), Some(Position(0)));
+        assert!(!store.has_component::<Position>(0) && !store.has_component::<Velocity>(9));
+        assert_eq!(store.archetypes(), 1);
+    }
+}
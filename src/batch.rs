@@ -0,0 +1,293 @@
+// Spawning and despawning many entities at once
+//
+// spawn_batch gives each bundle of components a new entity, numbered on from the
+// highest id the store has seen, and returns the range of ids it used. A bundle is a
+// tuple of up to eight components, each of a type with a pool. When nothing is
+// listening to those types, no hooks, groups, journal or change tracking, the bundles
+// are split into one column per type and each pool takes its column in a single
+// Pool::extend, reserving room once instead of growing entity by entity, and emits
+// PoolGrew once if it had to. Otherwise each component goes through add_component as
+// usual, so everything listening hears about it. Loading a large map is the case this
+// is for.
+//
+// despawn_batch removes a list of entities, and despawn_where every entity with all of
+// a tuple of component types. With nothing listening to any pool, each pool drops the
+// lot in one pass with Pool::remove_batch, compacting its packed arrays as retain does
+// rather than swapping each removed entity out on its own. Otherwise it is
+// remove_entity for each, as above.
+//
+// clear despawns everything the same way and starts ids over from 0, for restarting a
+// level or a test without building the store again. Pools, hooks, indexes, groups and
+// event queues stay, just empty of entities, and an undo history is emptied since it
+// refers to entities that are gone. An engine that ran over the store should be reset
+// too, or it may take new entities for old ones it has already fired for.
+use crate::access::PoolSet;
+use crate::journal::Journal;
+use crate::relation::short_type_name;
+use crate::stats::StoreEvent;
+use crate::store::{Component, EntityId, EntityStore, MAX_ENTITY};
+use std::any::TypeId;
+use std::collections::BTreeSet;
+use std::ops::Range;
+
+// Components added to an entity together, not to be confused with the saved Bundle
+pub trait ComponentBundle: Sized {
+    // Adds the components to consecutive entities from first, returning how many
+    fn spawn_all(store: &mut EntityStore, first: EntityId, bundles: Vec<Self>) -> usize;
+}
+
+macro_rules! component_bundle {
+    ($($index:tt $name:ident),+) => {
+        impl<$($name: Component + Eq + 'static),+> ComponentBundle for ($($name,)+) {
+            fn spawn_all(store: &mut EntityStore, first: EntityId, bundles: Vec<Self>) -> usize {
+                let count = bundles.len();
+                // Past MAX_ENTITY add_component turns them away one by one
+                let unheard = [$(TypeId::of::<$name>()),+]
+                    .into_iter()
+                    .all(|type_id| store.unheard(type_id))
+                    && first + count <= MAX_ENTITY + 1;
+                if !unheard {
+                    for (entity_id, bundle) in (first..).zip(bundles) {
+                        $(store.add_component(entity_id, bundle.$index);)+
+                    }
+                    return count;
+                }
+                let mut columns = ($(Vec::<(EntityId, $name)>::with_capacity(count),)+);
+                for (entity_id, bundle) in (first..).zip(bundles) {
+                    $(columns.$index.push((entity_id, bundle.$index));)+
+                }
+                $(extend(store, columns.$index);)+
+                count
+            }
+        }
+    };
+}
+
+component_bundle!(0 A);
+component_bundle!(0 A, 1 B);
+component_bundle!(0 A, 1 B, 2 C);
+component_bundle!(0 A, 1 B, 2 C, 3 D);
+component_bundle!(0 A, 1 B, 2 C, 3 D, 4 E);
+component_bundle!(0 A, 1 B, 2 C, 3 D, 4 E, 5 F);
+component_bundle!(0 A, 1 B, 2 C, 3 D, 4 E, 5 F, 6 G);
+component_bundle!(0 A, 1 B, 2 C, 3 D, 4 E, 5 F, 6 G, 7 H);
+
+// Adds a column of components to its pool in one go, telling subscribers if it grew
+fn extend<T: Component + Eq + 'static>(store: &mut EntityStore, column: Vec<(EntityId, T)>) {
+    let grew = {
+        let mut pool = store.get::<T>().unwrap().borrow_mut();
+        let before = pool.capacity();
+        pool.extend(column);
+        (pool.capacity() > before).then(|| StoreEvent::PoolGrew {
+            component: short_type_name::<T>(),
+            len: pool.len(),
+            capacity: pool.capacity(),
+        })
+    };
+    if let Some(event) = grew {
+        store.emit(event);
+    }
+}
+
+impl EntityStore {
+    // Gives every bundle a new entity, see above
+    pub fn spawn_batch<B: ComponentBundle>(
+        &mut self,
+        bundles: impl IntoIterator<Item = B>,
+    ) -> Range<EntityId> {
+        let first = self.next_entity();
+        let count = B::spawn_all(self, first, bundles.into_iter().collect());
+        if count > 0 {
+            self.reserve_up_to(first + count - 1);
+        }
+        first..first + count
+    }
+
+    // Removes every component of the listed entities, see above
+    pub fn despawn_batch(&mut self, entity_ids: &[EntityId]) {
+        let unheard = self
+            .pool_refs
+            .0
+            .iter()
+            .all(|pool| self.unheard(pool.borrow().component_type()));
+        if !unheard {
+            for &entity_id in entity_ids {
+                self.remove_entity(entity_id);
+            }
+            return;
+        }
+        for pool in &self.pool_refs.0 {
+            pool.borrow_mut().remove_batch(entity_ids);
+        }
+    }
+
+    // Despawns every entity and resets ids, see above
+    pub fn clear(&mut self) {
+        let live: BTreeSet<EntityId> = self
+            .pool_refs
+            .0
+            .iter()
+            .flat_map(|pool| pool.borrow().presence().entities())
+            .collect();
+        self.despawn_batch(&live.into_iter().collect::<Vec<_>>());
+        self.tiles.clear();
+        self.set_next_entity(0);
+        if self.journal.is_some() {
+            self.journal = Some(Journal::default());
+        }
+    }
+
+    // Despawns every entity with all of Q's components, returning them
+    pub fn despawn_where<Q: PoolSet>(&mut self) -> Vec<EntityId> {
+        let entities = self.entities_with(&Q::type_ids());
+        self.despawn_batch(&entities);
+        entities
+    }
+
+    // The lowest id above every entity spawned, reserved or with a component, 0 in a new
+    // store. It isn't taken until something is added to it, see spawn
+    pub fn next_entity(&self) -> EntityId {
+        let last = self
+            .pool_refs
+            .0
+            .iter()
+            .filter_map(|pool| pool.borrow().presence().last())
+            .max();
+        self.allocated().max(last.map_or(0, |last| last + 1))
+    }
+
+    // A new entity with nothing on it yet, never the same id twice
+    pub fn spawn(&mut self) -> EntityId {
+        let entity_id = self.next_entity();
+        self.reserve_up_to(entity_id);
+        entity_id
+    }
+
+    // Whether adding a T can skip add_component, with its pool there, nothing outside
+    // the pool to tell and no singleton or requirement to keep
+    fn unheard(&self, type_id: TypeId) -> bool {
+        self.pool_refs
+            .0
+            .iter()
+            .any(|pool| pool.borrow().component_type() == type_id)
+            && !self.hooks.contains(type_id)
+            && !self.grouped(type_id)
+            && !self.singletons.contains_key(&type_id)
+            && !self.constrained(type_id)
+            && self.journal.is_none()
+            && !self.tracking_changes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Position(i64, i64);
+    impl Component for Position {}
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Terrain(&'static str);
+    impl Component for Terrain {}
+
+    #[test]
+    fn batches_spawn_on_fresh_ids() {
+        let mut store = EntityStore::new();
+        store.new_component::<Position>();
+        store.new_component::<Terrain>();
+        let grew = Arc::new(AtomicUsize::new(0));
+        let counter = grew.clone();
+        store.subscribe(move |event| {
+            if let StoreEvent::PoolGrew { len: 10_000, .. } = event {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        let map = (0..10_000).map(|i| (Position(i % 100, i / 100), Terrain("grass")));
+        assert_eq!(store.spawn_batch(map), 0..10_000);
+        // Once for each pool, not entity by entity
+        assert_eq!(grew.load(Ordering::Relaxed), 2);
+        assert_eq!(store.max_entity(), 9_999);
+        let positions = store.get::<Position>().unwrap();
+        assert_eq!(positions.borrow().get(250), Some(&Position(50, 2)));
+        assert_eq!(positions.borrow().len(), 10_000);
+        assert_eq!(positions.borrow().changed_since(0).count(), 10_000);
+
+        let added = Arc::new(AtomicUsize::new(0));
+        let counter = added.clone();
+        store.on_add::<Terrain>(move |_, _| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        let spawned = store.spawn_batch([(Terrain("water"),), (Terrain("sand"),)]);
+        assert_eq!(spawned, 10_000..10_002);
+        assert_eq!(added.load(Ordering::Relaxed), 2);
+        assert!(store.has_component::<Terrain>(10_001));
+        assert!(!store.has_component::<Position>(10_001));
+        assert_eq!(store.spawn_batch(Vec::<(Terrain,)>::new()), 10_002..10_002);
+        // Entities given components directly count too
+        store.add_component(20_000, Terrain("rock"));
+        assert_eq!(store.next_entity(), 20_001);
+    }
+
+    #[test]
+    fn despawning_compacts_pools() {
+        let mut store = EntityStore::new();
+        store.new_component::<Position>();
+        store.new_component::<Terrain>();
+        store.spawn_batch((0..100).map(|i| (Position(i, 0),)));
+        for entity_id in (0..100).step_by(3) {
+            store.add_component(entity_id, Terrain("lava"));
+        }
+
+        let despawned = store.despawn_where::<(Position, Terrain)>();
+        assert_eq!(despawned.len(), 34);
+        let positions = store.get::<Position>().unwrap();
+        let left: Vec<i64> = positions
+            .borrow()
+            .components_iter()
+            .map(|(_, p)| p.0)
+            .collect();
+        assert_eq!(left.len(), 66);
+        assert!(left.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(positions.borrow().get(98), Some(&Position(98, 0)));
+        assert!(store.get::<Terrain>().unwrap().borrow().is_empty());
+
+        let removed = Arc::new(AtomicUsize::new(0));
+        let counter = removed.clone();
+        store.on_remove::<Position>(move |_, _| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        store.despawn_batch(&[1, 2, 3]);
+        assert_eq!(removed.load(Ordering::Relaxed), 2);
+        assert!(!store.has_component::<Position>(2));
+    }
+
+    #[test]
+    fn clearing_keeps_pools_and_hooks() {
+        let mut store = EntityStore::new();
+        store.new_component::<Position>();
+        store.new_component::<Terrain>();
+        let removed = Arc::new(AtomicUsize::new(0));
+        let counter = removed.clone();
+        store.on_remove::<Terrain>(move |_, _| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        store.spawn_batch((0..10).map(|i| (Position(i, i), Terrain("grass"))));
+        store.start_journal();
+
+        store.clear();
+        assert_eq!(removed.load(Ordering::Relaxed), 10);
+        assert!(store.get::<Position>().unwrap().borrow().is_empty());
+        assert!(!store.can_undo());
+        assert_eq!(store.next_entity(), 0);
+        assert_eq!(store.spawn_batch([(Terrain("sand"),)]), 0..1);
+        assert!(store.has_component::<Terrain>(0));
+
+        // Spawned entities are taken even with nothing on them
+        store.clear();
+        assert_eq!([store.spawn(), store.spawn(), store.spawn()], [0, 1, 2]);
+        assert_eq!(store.spawn_batch([(Terrain("sand"),)]), 3..4);
+    }
+}
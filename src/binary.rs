@@ -0,0 +1,358 @@
+// Compact binary encoding of a store's registered components
+//
+// Layout, with every integer a LEB128 varint unless noted:
+//
+//   "RETE" version:u8 max_entity sections
+//   section = name_len name schema_version body_len body
+//   body    = rows (entity_delta field_count value*)*
+//   value   = tag:u8 payload
+//
+// Rows in a section are sorted by entity and each stores the gap from the one before,
+// so dense ids take a byte each. Ints are zigzag encoded, floats are 8 little endian
+// bytes. Sections carry their length, so a reader skips the ones whose name it has no
+// registration for and reports them rather than failing, which lets older builds load
+// worlds saved by newer ones. Each section's schema version lets rows saved before a
+// component changed be migrated, see migrate.rs. Version 1 files have none and are
+// read as schema 0. Like serialize_store this goes through the registry, and
+// decoding inserts rows the way rules do, so derived state is rebuilt.
+use crate::error::Error;
+use crate::registry::{FactRow, Registry};
+use crate::store::{check_entity, EntityId, EntityStore};
+use crate::value::Value;
+
+const MAGIC: &[u8; 4] = b"RETE";
+pub const VERSION: u8 = 2;
+
+const INT: u8 = 0;
+const FLOAT: u8 = 1;
+const BOOL: u8 = 2;
+const STR: u8 = 3;
+const ENTITY: u8 = 4;
+
+// A decoded store, with the names of sections that were skipped as unregistered
+#[derive(Debug)]
+pub struct Decoded {
+    pub store: EntityStore,
+    pub skipped: Vec<String>,
+}
+
+pub(crate) fn write_varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+pub(crate) fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+pub(crate) fn write_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Int(i) => {
+            out.push(INT);
+            write_varint(out, ((i << 1) ^ (i >> 63)) as u64);
+        }
+        Value::Float(f) => {
+            out.push(FLOAT);
+            out.extend_from_slice(&f.to_le_bytes());
+        }
+        Value::Bool(b) => out.extend_from_slice(&[BOOL, *b as u8]),
+        Value::Str(s) => {
+            out.push(STR);
+            write_bytes(out, s.as_bytes());
+        }
+        Value::Entity(e) => {
+            out.push(ENTITY);
+            write_varint(out, *e as u64);
+        }
+    }
+}
+
+fn write_rows(rows: &[FactRow]) -> Vec<u8> {
+    let mut body = Vec::new();
+    let mut previous = 0;
+    for (entity_id, values) in rows {
+        write_varint(&mut body, (entity_id - previous) as u64);
+        previous = *entity_id;
+        write_varint(&mut body, values.len() as u64);
+        for value in values {
+            write_value(&mut body, value);
+        }
+    }
+    body
+}
+
+pub(crate) struct Reader<'a> {
+    pub(crate) bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.bytes.len() < len {
+            return Err(Error::Decode("unexpected end of input".to_string()));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    pub(crate) fn byte(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn varint(&mut self) -> Result<u64, Error> {
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            n |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(Error::Decode("varint too long".to_string()))
+    }
+
+    pub(crate) fn len(&mut self) -> Result<usize, Error> {
+        usize::try_from(self.varint()?).map_err(|_| Error::Decode("length too large".to_string()))
+    }
+
+    // An entity id, failing past MAX_ENTITY rather than have the store allocate up to it
+    pub(crate) fn entity(&mut self) -> Result<EntityId, Error> {
+        entity_in_range(self.len()?)
+    }
+
+    pub(crate) fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.len()?;
+        self.take(len)
+    }
+
+    pub(crate) fn string(&mut self) -> Result<String, Error> {
+        String::from_utf8(self.bytes()?.to_vec())
+            .map_err(|_| Error::Decode("string is not utf-8".to_string()))
+    }
+
+    pub(crate) fn value(&mut self) -> Result<Value, Error> {
+        Ok(match self.byte()? {
+            INT => {
+                let n = self.varint()?;
+                Value::Int((n >> 1) as i64 ^ -((n & 1) as i64))
+            }
+            FLOAT => Value::Float(f64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+            BOOL => Value::Bool(self.byte()? != 0),
+            STR => Value::Str(self.string()?),
+            ENTITY => Value::Entity(self.len()?),
+            tag => return Err(Error::Decode(format!("unknown value tag {}", tag))),
+        })
+    }
+
+    fn rows(&mut self) -> Result<Vec<FactRow>, Error> {
+        let mut rows = Vec::new();
+        let mut entity_id: EntityId = 0;
+        while !self.is_empty() {
+            entity_id = entity_in_range(
+                entity_id
+                    .checked_add(self.len()?)
+                    .ok_or_else(|| Error::Decode("entity id too large".to_string()))?,
+            )?;
+            let count = self.len()?;
+            let values = (0..count)
+                .map(|_| self.value())
+                .collect::<Result<Vec<_>, _>>()?;
+            rows.push((entity_id, values));
+        }
+        Ok(rows)
+    }
+}
+
+fn entity_in_range(entity_id: EntityId) -> Result<EntityId, Error> {
+    check_entity(entity_id).map_err(|error| Error::Decode(error.to_string()))
+}
+
+impl Registry {
+    // Encodes the store's registered components, skipping derived ones
+    pub fn encode_store(&self, store: &EntityStore) -> Vec<u8> {
+        let sections: Vec<(&str, u32, Vec<u8>)> = self
+            .iter()
+            .filter(|info| !info.is_derived())
+            .filter_map(|info| {
+                let mut rows = info.facts(store);
+                if rows.is_empty() {
+                    return None;
+                }
+                rows.sort_by_key(|(entity_id, _)| *entity_id);
+                let version = self.schema_version(&info.name);
+                Some((info.name.as_str(), version, write_rows(&rows)))
+            })
+            .collect();
+
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        write_varint(&mut out, store.max_entity() as u64);
+        write_varint(&mut out, sections.len() as u64);
+        for (name, version, body) in sections {
+            write_bytes(&mut out, name.as_bytes());
+            write_varint(&mut out, version as u64);
+            write_bytes(&mut out, &body);
+        }
+        out
+    }
+
+    // Decodes a store written by encode_store, skipping sections it has no registration for
+    // Sections from older schemas are migrated, failing with every one that can't be
+    pub fn decode_store(&self, bytes: &[u8]) -> Result<Decoded, Error> {
+        let mut reader = Reader { bytes };
+        if reader.take(4).ok() != Some(&MAGIC[..]) {
+            return Err(Error::Decode("not a snapshot".to_string()));
+        }
+        let version = reader.byte()?;
+        if version > VERSION {
+            return Err(Error::Decode(format!(
+                "version {} is newer than {}",
+                version, VERSION
+            )));
+        }
+        let mut store = EntityStore::new();
+        store.reserve_up_to(reader.entity()?);
+
+        let mut sections = Vec::new();
+        let mut skipped = Vec::new();
+        let mut unmigratable = Vec::new();
+        for _ in 0..reader.len()? {
+            let name = reader.string()?;
+            let schema = match version {
+                1 => 0,
+                _ => u32::try_from(reader.varint()?)
+                    .map_err(|_| Error::Decode("schema version too large".to_string()))?,
+            };
+            let body = reader.bytes()?;
+            let Some(info) = self.get(&name).filter(|info| !info.is_derived()) else {
+                skipped.push(name);
+                continue;
+            };
+            let rows = Reader { bytes: body }.rows()?;
+            match self.migrate_rows(info, schema, rows) {
+                Some(rows) => sections.push((info, rows)),
+                None => unmigratable.push(name),
+            }
+        }
+        if !unmigratable.is_empty() {
+            return Err(Error::Unmigratable(unmigratable));
+        }
+        Registry::insert_rows(&mut store, sections).map_err(Error::Decode)?;
+        Ok(Decoded { store, skipped })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hierarchy::Parent;
+    use crate::registry::Fact;
+    use crate::store::{Component, MAX_ENTITY};
+    use crate::tiles::Tile;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Label(String, f64);
+
+    impl Eq for Label {}
+    impl Component for Label {}
+
+    impl Fact for Label {
+        const FIELDS: &'static [&'static str] = &["text", "weight"];
+
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Str(self.0.clone()), Value::Float(self.1)]
+        }
+
+        fn from_values(values: &[Value]) -> Option<Self> {
+            match values {
+                [text, weight] => Some(Label(text.as_str()?.to_string(), weight.as_float()?)),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn binary_round_trip_skips_unknown_sections() {
+        let mut store = EntityStore::new();
+        for entity_id in 0..100 {
+            let index = entity_id as i64;
+            store.place_tile(entity_id, Tile::new(index % 10 - 5, index / 10));
+        }
+        store.set_parent(7, 3).unwrap();
+        store.new_component::<Label>();
+        store.add_component(3, Label("keep".to_string(), -1.5));
+
+        let mut registry = Registry::new();
+        registry.register_tiles("Tile", "AdjacentTo");
+        registry.register_hierarchy("Parent");
+        registry.register::<Label>("Label");
+        let bytes = registry.encode_store(&store);
+        // Six bytes a tile: the entity gap, the field count and two tagged ints
+        assert!(bytes.len() < 700, "{} bytes", bytes.len());
+
+        let decoded = registry.decode_store(&bytes).unwrap();
+        assert!(decoded.skipped.is_empty());
+        let restored = decoded.store;
+        assert_eq!(restored.max_entity(), 99);
+        assert_eq!(restored.tile_at(Tile::new(-5, 9)), Some(90));
+        assert_eq!(restored.neighbours(0), store.neighbours(0));
+        assert_eq!(restored.children(3), vec![7]);
+        assert_eq!(
+            restored.get::<Label>().unwrap().borrow().get(3),
+            Some(&Label("keep".to_string(), -1.5))
+        );
+
+        // An older reader without Label still loads the rest
+        let mut older = Registry::new();
+        older.register_tiles("Tile", "AdjacentTo");
+        older.register_hierarchy("Parent");
+        let decoded = older.decode_store(&bytes).unwrap();
+        assert_eq!(decoded.skipped, vec!["Label".to_string()]);
+        assert!(decoded.store.has_component::<Parent>(7));
+
+        let mut newer = bytes.clone();
+        newer[4] = VERSION + 1;
+        assert!(registry.decode_store(&newer).is_err());
+        assert!(registry.decode_store(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn entity_ids_out_of_range_fail_to_decode() {
+        let mut registry = Registry::new();
+        registry.register::<Label>("Label");
+        let header = |max_entity: u64| {
+            let mut out = MAGIC.to_vec();
+            out.push(VERSION);
+            write_varint(&mut out, max_entity);
+            out
+        };
+        let decode = |bytes: &[u8]| match registry.decode_store(bytes) {
+            Err(Error::Decode(message)) => message,
+            other => panic!("decoded {:?}", other.map(|decoded| decoded.skipped)),
+        };
+        assert!(decode(&header(u64::MAX)).contains("past the highest id"));
+
+        // A row's gap from the last one overflowing, then one landing past the maximum
+        for gaps in [&[MAX_ENTITY as u64, u64::MAX][..], &[MAX_ENTITY as u64 + 1]] {
+            let mut body = Vec::new();
+            for &gap in gaps {
+                write_varint(&mut body, gap);
+                write_varint(&mut body, 0);
+            }
+            let mut bytes = header(0);
+            write_varint(&mut bytes, 1);
+            write_bytes(&mut bytes, b"Label");
+            write_varint(&mut bytes, 0);
+            write_bytes(&mut bytes, &body);
+            decode(&bytes);
+        }
+    }
+}
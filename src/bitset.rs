@@ -0,0 +1,203 @@
+// Which entities have a component, as a hierarchical bitset
+//
+// The bottom layer has a bit per entity. Each layer above has a bit per word of the one
+// below, set while that word has any bit set, so an intersection only descends into
+// words every set has something in and skips empty stretches of ids 64 or 4096 at a
+// time. Every pool keeps one up to date, and joins over several components intersect
+// their pools' sets to find the entities worth looking up, instead of scanning one
+// pool and probing the rest.
+use crate::cell::AtomicRef;
+use crate::store::{EntityId, EntityStore};
+use std::any::TypeId;
+
+const LAYERS: usize = 4;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BitSet {
+    // Bottom layer first
+    layers: [Vec<u64>; LAYERS],
+}
+
+impl BitSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, entity_id: EntityId) {
+        let mut index = entity_id;
+        for layer in &mut self.layers {
+            let word = index / 64;
+            if word >= layer.len() {
+                layer.resize(word + 1, 0);
+            }
+            let was_empty = layer[word] == 0;
+            layer[word] |= 1 << (index % 64);
+            if !was_empty {
+                break;
+            }
+            index = word;
+        }
+    }
+
+    pub fn remove(&mut self, entity_id: EntityId) {
+        let mut index = entity_id;
+        for layer in &mut self.layers {
+            let word = index / 64;
+            let Some(bits) = layer.get_mut(word) else {
+                return;
+            };
+            *bits &= !(1 << (index % 64));
+            if *bits != 0 {
+                break;
+            }
+            index = word;
+        }
+    }
+
+    pub fn contains(&self, entity_id: EntityId) -> bool {
+        self.layers[0]
+            .get(entity_id / 64)
+            .is_some_and(|bits| bits & (1 << (entity_id % 64)) != 0)
+    }
+
+    // The highest entity in the set, found from the top layer down
+    pub fn last(&self) -> Option<EntityId> {
+        let top = &self.layers[LAYERS - 1];
+        let mut index = top.iter().rposition(|&bits| bits != 0)?;
+        for layer in self.layers.iter().rev() {
+            let bits = layer[index];
+            index = index * 64 + 63 - bits.leading_zeros() as usize;
+        }
+        Some(index)
+    }
+
+    // Drops trailing empty words
+    pub fn shrink_to_fit(&mut self) {
+        for layer in &mut self.layers {
+            while layer.last() == Some(&0) {
+                layer.pop();
+            }
+            layer.shrink_to_fit();
+        }
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.layers
+            .iter()
+            .map(|layer| layer.capacity() * std::mem::size_of::<u64>())
+            .sum()
+    }
+
+    // Every entity in the set, in id order
+    pub fn entities(&self) -> Vec<EntityId> {
+        BitSet::intersection(&[self])
+    }
+
+    // Entities in all of the sets, in id order
+    pub fn intersection(sets: &[&BitSet]) -> Vec<EntityId> {
+        let mut entities = Vec::new();
+        if sets.is_empty() {
+            return entities;
+        }
+        let top = sets
+            .iter()
+            .map(|set| set.layers[LAYERS - 1].len())
+            .min()
+            .unwrap_or(0);
+        for word in 0..top {
+            Self::descend(sets, LAYERS - 1, word, &mut entities);
+        }
+        entities
+    }
+
+    fn descend(sets: &[&BitSet], layer: usize, word: usize, entities: &mut Vec<EntityId>) {
+        let mut bits = sets
+            .iter()
+            .map(|set| set.layers[layer].get(word).copied().unwrap_or(0))
+            .fold(u64::MAX, |all, bits| all & bits);
+        while bits != 0 {
+            let index = word * 64 + bits.trailing_zeros() as usize;
+            bits &= bits - 1;
+            match layer {
+                0 => entities.push(index),
+                _ => Self::descend(sets, layer - 1, index, entities),
+            }
+        }
+    }
+}
+
+impl EntityStore {
+    // Which entities have the component, None if it has no pool
+    pub fn presence(&self, type_id: TypeId) -> Option<AtomicRef<'_, BitSet>> {
+        let pool = self
+            .pool_refs
+            .0
+            .iter()
+            .find(|pool| pool.borrow().component_type() == type_id)?;
+        Some(AtomicRef::map(pool.borrow(), |pool| pool.presence()))
+    }
+
+    // How many entities have the component, None if it has no pool
+    pub fn pool_len(&self, type_id: TypeId) -> Option<usize> {
+        let pool = self
+            .pool_refs
+            .0
+            .iter()
+            .find(|pool| pool.borrow().component_type() == type_id)?;
+        let len = pool.borrow().usage().len;
+        Some(len)
+    }
+
+    // Entities with every one of the components, in id order
+    pub fn entities_with(&self, types: &[TypeId]) -> Vec<EntityId> {
+        let Some(masks) = types
+            .iter()
+            .map(|&type_id| self.presence(type_id))
+            .collect::<Option<Vec<_>>>()
+        else {
+            return Vec::new();
+        };
+        let sets: Vec<&BitSet> = masks.iter().map(|mask| &**mask).collect();
+        BitSet::intersection(&sets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Component;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Position(i64);
+    impl Component for Position {}
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Velocity(i64);
+    impl Component for Velocity {}
+
+    #[test]
+    fn intersections_follow_the_pools() {
+        let mut store = EntityStore::new();
+        store.new_component::<Position>();
+        store.new_component::<Velocity>();
+        for entity_id in 0..10_000 {
+            store.add_component(entity_id, Position(0));
+        }
+        for entity_id in [9_999, 70, 5, 300_000] {
+            store.add_component(entity_id, Velocity(1));
+        }
+        store.remove_component::<Position>(70);
+
+        let both = [TypeId::of::<Position>(), TypeId::of::<Velocity>()];
+        assert_eq!(store.entities_with(&both), vec![5, 9_999]);
+        let velocity = store.presence(TypeId::of::<Velocity>()).unwrap();
+        assert_eq!(velocity.entities(), vec![5, 70, 9_999, 300_000]);
+        assert!(!velocity.contains(6) && velocity.contains(300_000));
+        assert_eq!(velocity.last(), Some(300_000));
+        drop(velocity);
+
+        store.remove_entity(5);
+        assert_eq!(store.entities_with(&both), vec![9_999]);
+        assert!(store.entities_with(&[TypeId::of::<u8>()]).is_empty());
+    }
+}
@@ -0,0 +1,227 @@
+// Breakpoints
+//
+// A debugger can have the engine pause when a rule is about to fire, or once a firing
+// has changed a component, on one entity or any. When one is hit the handler set with
+// on_break is called with what happened and the store as it is, and says whether to
+// pause. Without a handler every hit pauses.
+//
+// Pausing stops run, or step, where it is: before the activation fires for a rule, after
+// the firing for a change. The tick doesn't move on, paused says where things stand, and
+// the next run or step carries on from there, firing the activation paused before
+// without hitting its breakpoint again. The REPL sets breakpoints with `break`.
+use crate::engine::{Activation, RuleEngine, Step};
+use crate::store::{EntityId, EntityStore};
+use crate::trace::Mutation;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Breakpoint {
+    // Before an activation of the rule fires
+    Rule(String),
+    // After a firing inserts or removes the component, on the entity if there is one
+    Change {
+        component: String,
+        entity: Option<EntityId>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hit {
+    pub breakpoint: Breakpoint,
+    pub rule: String,
+    pub activation: Activation,
+    // What the firing changed, empty when paused before it
+    pub mutations: Vec<Mutation>,
+    pub fired: bool,
+}
+
+type Handler = Box<dyn FnMut(&Hit, &EntityStore) -> bool + Send + Sync>;
+
+#[derive(Default)]
+pub(crate) struct Breakpoints {
+    points: Vec<Breakpoint>,
+    handler: Option<Handler>,
+    paused: Option<Hit>,
+    // The activation paused before, to fire without hitting again on resuming
+    passed: Option<Activation>,
+}
+
+impl fmt::Debug for Breakpoints {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Breakpoints")
+            .field("points", &self.points)
+            .field("paused", &self.paused)
+            .finish()
+    }
+}
+
+impl Breakpoint {
+    fn changed(&self, mutation: &Mutation) -> bool {
+        let Breakpoint::Change { component, entity } = self else {
+            return false;
+        };
+        let (Mutation::Inserted {
+            component: changed,
+            entity: on,
+            ..
+        }
+        | Mutation::Removed {
+            component: changed,
+            entity: on,
+            ..
+        }) = mutation;
+        changed == component && entity.is_none_or(|entity| entity == *on)
+    }
+}
+
+impl RuleEngine {
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        if !self.breakpoints.points.contains(&breakpoint) {
+            self.breakpoints.points.push(breakpoint);
+        }
+    }
+
+    pub fn remove_breakpoint(&mut self, breakpoint: &Breakpoint) -> bool {
+        let points = &mut self.breakpoints.points;
+        let before = points.len();
+        points.retain(|point| point != breakpoint);
+        points.len() != before
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints.points
+    }
+
+    // Called on every hit, returning whether to pause, see above
+    pub fn on_break(
+        &mut self,
+        handler: impl FnMut(&Hit, &EntityStore) -> bool + Send + Sync + 'static,
+    ) {
+        self.breakpoints.handler = Some(Box::new(handler));
+    }
+
+    // The hit the engine is paused at, if it is
+    pub fn paused(&self) -> Option<&Hit> {
+        self.breakpoints.paused.as_ref()
+    }
+
+    // Carries on from a pause, see above
+    pub(crate) fn resume(&mut self) {
+        if let Some(hit) = self.breakpoints.paused.take() {
+            if !hit.fired {
+                self.breakpoints.passed = Some(hit.activation);
+            }
+        }
+    }
+
+    // Whether to pause before the activation fires
+    pub(crate) fn break_before(&mut self, activation: &Activation, store: &EntityStore) -> bool {
+        if self.breakpoints.passed.take().as_ref() == Some(activation) {
+            return false;
+        }
+        let rule = &self.rules[activation.rule].name;
+        let Some(breakpoint) = self
+            .breakpoints
+            .points
+            .iter()
+            .find(|point| matches!(point, Breakpoint::Rule(name) if name == rule))
+        else {
+            return false;
+        };
+        let hit = Hit {
+            breakpoint: breakpoint.clone(),
+            rule: rule.clone(),
+            activation: activation.clone(),
+            mutations: Vec::new(),
+            fired: false,
+        };
+        self.hit(hit, store)
+    }
+
+    // Whether to pause now that the step has fired
+    pub(crate) fn break_after(&mut self, step: &Step, store: &EntityStore) -> bool {
+        let Some(breakpoint) = self.breakpoints.points.iter().find(|point| {
+            step.mutations
+                .iter()
+                .any(|mutation| point.changed(mutation))
+        }) else {
+            return false;
+        };
+        let hit = Hit {
+            breakpoint: breakpoint.clone(),
+            rule: step.rule.clone(),
+            activation: step.activation.clone(),
+            mutations: step.mutations.clone(),
+            fired: true,
+        };
+        self.hit(hit, store)
+    }
+
+    fn hit(&mut self, hit: Hit, store: &EntityStore) -> bool {
+        let pause = match &mut self.breakpoints.handler {
+            Some(handler) => handler(&hit, store),
+            None => true,
+        };
+        if pause {
+            self.breakpoints.paused = Some(hit);
+        }
+        pause
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::Value;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn breakpoints_pause_and_resume() {
+        let mut engine = RuleEngine::new();
+        engine
+            .load_str(
+                r#"
+                fact Health(points)
+                fact Fleeing()
+                rule "hurt" when Health(e, h), h > 5 then insert Health(e, h - 5)
+                rule "flee" when Health(e, h), h <= 5, not Fleeing(e) then insert Fleeing(e)
+                "#,
+            )
+            .unwrap();
+        let registry = engine.registry().clone();
+        let mut store = EntityStore::new();
+        store
+            .insert_fact(&registry, "Health", 1, &[Value::Int(10)])
+            .unwrap();
+        engine.add_breakpoint(Breakpoint::Rule("flee".to_string()));
+
+        // Hurt fires, then the run pauses before flee
+        assert_eq!(engine.run(&mut store), 1);
+        let hit = engine.paused().unwrap();
+        assert_eq!(hit.rule, "flee");
+        assert!(!hit.fired);
+        assert!(registry.get("Fleeing").unwrap().facts(&store).is_empty());
+        assert_eq!(engine.run(&mut store), 1);
+        assert!(engine.paused().is_none());
+        assert_eq!(registry.get("Fleeing").unwrap().facts(&store).len(), 1);
+
+        // A handler sees every change to entity 2's health and lets the run carry on
+        assert!(engine.remove_breakpoint(&Breakpoint::Rule("flee".to_string())));
+        engine.add_breakpoint(Breakpoint::Change {
+            component: "Health".to_string(),
+            entity: Some(2),
+        });
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = seen.clone();
+        engine.on_break(move |hit, _| {
+            log.lock().unwrap().push(hit.mutations.clone());
+            false
+        });
+        store
+            .insert_fact(&registry, "Health", 2, &[Value::Int(15)])
+            .unwrap();
+        assert_eq!(engine.run(&mut store), 3);
+        assert!(engine.paused().is_none());
+        assert_eq!(seen.lock().unwrap().len(), 2);
+    }
+}
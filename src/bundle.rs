@@ -0,0 +1,114 @@
+// Saving rules together with the world they run over
+//
+// A Bundle holds an engine's rules as they stand after parsing, salience, module and
+// trigger included, which modules are switched off, and the store encoded as in
+// binary.rs. With the serde feature the whole thing serializes, so a saved game or an
+// audit of what the rules did carries everything needed to pick it up again. Component
+// types can't be saved, so the engine loading a bundle must have them registered.
+use crate::engine::RuleEngine;
+use crate::error::Error;
+use crate::rule::Rule;
+use crate::store::EntityStore;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Bundle {
+    pub rules: Vec<Rule>,
+    // Disabled modules, sorted
+    pub disabled: Vec<String>,
+    pub world: Vec<u8>,
+}
+
+impl RuleEngine {
+    pub fn bundle(&self, store: &EntityStore) -> Bundle {
+        let mut disabled: Vec<String> = self.disabled.iter().cloned().collect();
+        disabled.sort();
+        Bundle {
+            rules: self.rules().to_vec(),
+            disabled,
+            world: self.registry().encode_store(store),
+        }
+    }
+
+    // Swaps in the bundle's rules and disabled modules, giving back its world
+    // Fails without changing the engine if a rule or the world doesn't fit the registry
+    pub fn load_bundle(&mut self, bundle: &Bundle) -> Result<EntityStore, Error> {
+        let decoded = self.registry().decode_store(&bundle.world)?;
+        if let Some(name) = decoded.skipped.into_iter().next() {
+            return Err(Error::UnknownComponent(name));
+        }
+        self.replace_rules(bundle.rules.clone())?;
+        self.disabled = bundle.disabled.iter().cloned().collect();
+        self.reorder();
+        Ok(decoded.store)
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use crate::engine::RuleEngine;
+    use crate::registry::Fact;
+    use crate::store::{Component, EntityStore};
+    use crate::value::Value;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Gold(i64);
+
+    impl Component for Gold {}
+
+    impl Fact for Gold {
+        const FIELDS: &'static [&'static str] = &["amount"];
+
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.0)]
+        }
+
+        fn from_values(values: &[Value]) -> Option<Self> {
+            match values {
+                [amount] => Some(Gold(amount.as_int()?)),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn bundles_carry_rules_and_world() {
+        let mut engine = RuleEngine::new();
+        engine.register::<Gold>("Gold");
+        engine
+            .load_str(
+                r#"
+                module "economy"
+                rule "interest" salience 2 when Gold(e, g), g < 100 then insert Gold(e, g * 2)
+                module "tax"
+                rule "tax" on insert Gold(e, g) when Gold(e, g), g > 50 then insert Gold(e, g - 10) for 3 ticks
+                "#,
+            )
+            .unwrap();
+        engine.disable_module("tax");
+        let mut store = EntityStore::new();
+        store.new_component::<Gold>();
+        store.add_component(1, Gold(30));
+
+        let json = serde_json::to_string(&engine.bundle(&store)).unwrap();
+        let bundle = serde_json::from_str(&json).unwrap();
+        assert_eq!(engine.bundle(&store), bundle);
+
+        let mut loaded = RuleEngine::new();
+        loaded.register::<Gold>("Gold");
+        let mut world = loaded.load_bundle(&bundle).unwrap();
+        assert_eq!(loaded.rules(), engine.rules());
+        assert_eq!(loaded.rules()[0].salience, 2);
+        assert!(!loaded.is_module_enabled("tax"));
+        loaded.run(&mut world);
+        assert_eq!(
+            world.get::<Gold>().unwrap().borrow().get(1),
+            Some(&Gold(120))
+        );
+
+        // Without the component registered neither the rules nor the world can load
+        let mut bare = RuleEngine::new();
+        assert!(bare.load_bundle(&bundle).is_err());
+        assert!(bare.rules().is_empty());
+    }
+}
@@ -0,0 +1,47 @@
+// A synchronous event bus
+//
+// Subscribers are called in the order they subscribed, as soon as an event is emitted,
+// so embedding applications can react to what the store and engine are doing without
+// polling them.
+use std::fmt;
+
+type Subscriber<E> = Box<dyn FnMut(&E) + Send + Sync>;
+
+pub struct EventBus<E> {
+    subscribers: Vec<Subscriber<E>>,
+}
+
+impl<E> Default for EventBus<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<E> fmt::Debug for EventBus<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EventBus({} subscribers)", self.subscribers.len())
+    }
+}
+
+impl<E> EventBus<E> {
+    pub fn new() -> Self {
+        EventBus {
+            subscribers: Vec::new(),
+        }
+    }
+
+    pub fn subscribe(&mut self, subscriber: impl FnMut(&E) + Send + Sync + 'static) {
+        self.subscribers.push(Box::new(subscriber));
+    }
+
+    // Nobody is listening, so emitters can skip building the event
+    pub fn is_empty(&self) -> bool {
+        self.subscribers.is_empty()
+    }
+
+    pub fn emit(&mut self, event: &E) {
+        for subscriber in &mut self.subscribers {
+            subscriber(event);
+        }
+    }
+}
@@ -0,0 +1,187 @@
+// Cached queries
+//
+// A UI panel, or host code reading the same query many times a tick, can hold it in a
+// CachedQuery. It keeps the last answer along with the change tick and size of every
+// pool the query reads, and asking again only recomputes once one of those has moved,
+// see ComponentInfo::version. Anything added, replaced, borrowed mutably or removed
+// counts, so a cached answer is never stale, just sometimes recomputed to come out the
+// same. Each time an answer is thrown away for that, the store emits
+// StoreEvent::CacheInvalidated naming the components that changed, so a host can see
+// how often its caches are paying off.
+//
+// Derived components have no pool to follow and spatial conditions read the engine's
+// indexes, so queries using either are answered afresh every time. A handle assumes it
+// is always asked against the same engine and store, invalidate drops the answer when
+// that isn't so.
+use crate::engine::RuleEngine;
+use crate::error::Error;
+use crate::query::Query;
+use crate::rule::Condition;
+use crate::stats::StoreEvent;
+use crate::store::EntityStore;
+use crate::value::Bindings;
+
+// A query whose answer depends only on the facts of the components it reads
+pub trait Cacheable {
+    type Answer;
+
+    // The components read, by registered name, None if they can't all be followed
+    fn reads(&self) -> Option<Vec<String>>;
+
+    fn answer(&self, engine: &RuleEngine, store: &EntityStore) -> Result<Self::Answer, Error>;
+}
+
+impl Cacheable for Query {
+    type Answer = Vec<Bindings>;
+
+    fn reads(&self) -> Option<Vec<String>> {
+        self.conditions
+            .iter()
+            .filter_map(|condition| match condition {
+                Condition::Pattern(pattern) | Condition::Not(pattern) => {
+                    Some(Some(pattern.component.clone()))
+                }
+                Condition::Test(_) => None,
+                Condition::Spatial(_) => Some(None),
+            })
+            .collect()
+    }
+
+    fn answer(&self, engine: &RuleEngine, store: &EntityStore) -> Result<Vec<Bindings>, Error> {
+        Ok(engine.solve(self, store)?.collect())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CachedQuery<Q: Cacheable> {
+    query: Q,
+    answer: Option<Q::Answer>,
+    // The version of each pool read when the answer was worked out, in reads order, None
+    // if they can't all be followed
+    seen: Option<Vec<(u64, usize)>>,
+    // Times the answer has been worked out rather than reused
+    recomputed: usize,
+}
+
+impl<Q: Cacheable> CachedQuery<Q> {
+    pub fn new(query: Q) -> Self {
+        CachedQuery {
+            query,
+            answer: None,
+            seen: None,
+            recomputed: 0,
+        }
+    }
+
+    pub fn query(&self) -> &Q {
+        &self.query
+    }
+
+    pub fn recomputed(&self) -> usize {
+        self.recomputed
+    }
+
+    // The answer last worked out, without checking it's still current
+    pub fn answer(&self) -> Option<&Q::Answer> {
+        self.answer.as_ref()
+    }
+
+    pub fn invalidate(&mut self) {
+        self.answer = None;
+    }
+
+    // The answer, reused unless a pool the query reads has changed since it was worked out
+    pub fn get(
+        &mut self,
+        engine: &RuleEngine,
+        store: &mut EntityStore,
+    ) -> Result<&Q::Answer, Error> {
+        let versions = self.versions(engine, store);
+        if let (Some(_), Some(seen), Some(now)) = (&self.answer, &self.seen, &versions) {
+            let changed: Vec<String> = (self.query.reads().into_iter().flatten())
+                .zip(seen.iter().zip(now))
+                .filter(|(_, (seen, now))| seen != now)
+                .map(|(component, _)| component)
+                .collect();
+            if !changed.is_empty() {
+                store.emit(StoreEvent::CacheInvalidated { changed });
+            }
+        }
+        if self.answer.is_none() || versions.is_none() || versions != self.seen {
+            self.answer = Some(self.query.answer(engine, store)?);
+            self.seen = versions;
+            self.recomputed += 1;
+        }
+        Ok(self.answer.as_ref().expect("answered above"))
+    }
+
+    fn versions(&self, engine: &RuleEngine, store: &EntityStore) -> Option<Vec<(u64, usize)>> {
+        self.query
+            .reads()?
+            .iter()
+            .map(|component| engine.registry().get(component)?.version(store))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::Value;
+
+    #[test]
+    fn answers_are_reused_until_their_pools_change() {
+        let mut engine = RuleEngine::new();
+        engine
+            .load_str(
+                r#"
+                fact Health(points)
+                fact Fleeing()
+                fact Name(name)
+                "#,
+            )
+            .unwrap();
+        let registry = engine.registry().clone();
+        let mut store = EntityStore::new();
+        for (entity_id, health) in [(1, 5), (2, 50)] {
+            store
+                .insert_fact(&registry, "Health", entity_id, &[Value::Int(health)])
+                .unwrap();
+        }
+        let invalidated = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = invalidated.clone();
+        store.subscribe(move |event| {
+            if let StoreEvent::CacheInvalidated { changed } = event {
+                log.lock().unwrap().push(changed.join(","));
+            }
+        });
+        let mut weak =
+            CachedQuery::new(Query::parse("Health(e, h), h < 10, not Fleeing(e)").unwrap());
+        assert_eq!(weak.get(&engine, &mut store).unwrap().len(), 1);
+        assert_eq!(weak.get(&engine, &mut store).unwrap().len(), 1);
+        assert_eq!(weak.recomputed(), 1);
+
+        // Names aren't read, health and fleeing are
+        store
+            .insert_fact(&registry, "Name", 1, &[Value::from("hero")])
+            .unwrap();
+        weak.get(&engine, &mut store).unwrap();
+        assert_eq!(weak.recomputed(), 1);
+        store
+            .insert_fact(&registry, "Health", 2, &[Value::Int(3)])
+            .unwrap();
+        assert_eq!(weak.get(&engine, &mut store).unwrap().len(), 2);
+        registry.get("Fleeing").unwrap().insert(&mut store, 1, &[]);
+        assert_eq!(weak.get(&engine, &mut store).unwrap().len(), 1);
+        registry.get("Fleeing").unwrap().remove(&mut store, 1);
+        assert_eq!(weak.get(&engine, &mut store).unwrap().len(), 2);
+        assert_eq!(weak.recomputed(), 4);
+        weak.invalidate();
+        weak.get(&engine, &mut store).unwrap();
+        assert_eq!(weak.recomputed(), 5);
+        assert_eq!(
+            *invalidated.lock().unwrap(),
+            ["Health", "Fleeing", "Fleeing"]
+        );
+    }
+}
@@ -0,0 +1,258 @@
+// A RefCell that can be shared between threads
+//
+// Pools, the indexes following them and the event queues used to sit in Rc<RefCell>,
+// which kept the store on the thread that made it. AtomicRefCell keeps RefCell's rules,
+// any number of shared borrows or one mutable one, but counts them in an atomic, so it
+// can go in an Arc and the store can be Send and Sync. Borrowing against the rules
+// panics, as with RefCell, rather than waiting for the other borrow to end; try_borrow
+// and try_borrow_mut say None instead.
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// The count while mutably borrowed, shared borrows stop one short of it
+const WRITING: usize = usize::MAX;
+
+pub struct AtomicRefCell<T: ?Sized> {
+    borrows: AtomicUsize,
+    value: UnsafeCell<T>,
+}
+
+// Borrows hand out &T to whichever thread holds the cell and &mut T to one at a time,
+// the same as RwLock
+unsafe impl<T: ?Sized + Send> Send for AtomicRefCell<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for AtomicRefCell<T> {}
+
+impl<T> AtomicRefCell<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            borrows: AtomicUsize::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> AtomicRefCell<T> {
+    // None if it is mutably borrowed
+    pub fn try_borrow(&self) -> Option<AtomicRef<'_, T>> {
+        let mut current = self.borrows.load(Ordering::Relaxed);
+        loop {
+            if current >= WRITING - 1 {
+                return None;
+            }
+            match self.borrows.compare_exchange_weak(
+                current,
+                current + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(actual) => current = actual,
+            }
+        }
+        Some(AtomicRef {
+            // Safety: the count now holds off mutable borrows until this one drops
+            value: unsafe { &*self.value.get() },
+            borrows: &self.borrows,
+        })
+    }
+
+    // None if it is borrowed at all
+    pub fn try_borrow_mut(&self) -> Option<AtomicRefMut<'_, T>> {
+        self.borrows
+            .compare_exchange(0, WRITING, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        Some(AtomicRefMut {
+            // Safety: the count now holds off every other borrow until this one drops
+            value: unsafe { &mut *self.value.get() },
+            borrows: &self.borrows,
+        })
+    }
+
+    #[track_caller]
+    pub fn borrow(&self) -> AtomicRef<'_, T> {
+        self.try_borrow().expect("already mutably borrowed")
+    }
+
+    #[track_caller]
+    pub fn borrow_mut(&self) -> AtomicRefMut<'_, T> {
+        self.try_borrow_mut().expect("already borrowed")
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<T: Default> Default for AtomicRefCell<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for AtomicRefCell<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_borrow() {
+            Some(value) => f.debug_tuple("AtomicRefCell").field(&&*value).finish(),
+            None => f.write_str("AtomicRefCell(<borrowed>)"),
+        }
+    }
+}
+
+pub struct AtomicRef<'a, T: ?Sized> {
+    value: &'a T,
+    borrows: &'a AtomicUsize,
+}
+
+impl<'a, T: ?Sized> AtomicRef<'a, T> {
+    // A borrow of part of the value, as Ref::map
+    pub fn map<U: ?Sized>(this: Self, f: impl FnOnce(&T) -> &U) -> AtomicRef<'a, U> {
+        let this = ManuallyDrop::new(this);
+        AtomicRef {
+            value: f(this.value),
+            borrows: this.borrows,
+        }
+    }
+
+    // A borrow of part of the value if there is one, as Ref::filter_map
+    pub fn filter_map<U: ?Sized>(
+        this: Self,
+        f: impl FnOnce(&T) -> Option<&U>,
+    ) -> Result<AtomicRef<'a, U>, Self> {
+        let value: &'a T = this.value;
+        match f(value) {
+            Some(value) => {
+                let this = ManuallyDrop::new(this);
+                Ok(AtomicRef {
+                    value,
+                    borrows: this.borrows,
+                })
+            }
+            None => Err(this),
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for AtomicRef<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T: ?Sized> Drop for AtomicRef<'_, T> {
+    fn drop(&mut self) {
+        self.borrows.fetch_sub(1, Ordering::Release);
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for AtomicRef<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
+pub struct AtomicRefMut<'a, T: ?Sized> {
+    value: &'a mut T,
+    borrows: &'a AtomicUsize,
+}
+
+impl<'a, T: ?Sized> AtomicRefMut<'a, T> {
+    // A mutable borrow of part of the value, as RefMut::map
+    pub fn map<U: ?Sized>(this: Self, f: impl FnOnce(&mut T) -> &mut U) -> AtomicRefMut<'a, U> {
+        let this = ManuallyDrop::new(this);
+        let borrows = this.borrows;
+        // Safety: this is never dropped or used again, so the borrow moves to the new guard
+        let value: &'a mut T = unsafe { std::ptr::read(&this.value) };
+        AtomicRefMut {
+            value: f(value),
+            borrows,
+        }
+    }
+
+    // A mutable borrow of part of the value if there is one, as RefMut::filter_map
+    pub fn filter_map<U: ?Sized>(
+        this: Self,
+        f: impl FnOnce(&mut T) -> Option<&mut U>,
+    ) -> Result<AtomicRefMut<'a, U>, Self> {
+        let this = ManuallyDrop::new(this);
+        let borrows = this.borrows;
+        // Safety: as in map, and when f finds nothing it keeps no borrow, so the value
+        // goes back into a guard of its own
+        let value: *mut T = unsafe { std::ptr::read(&this.value) as *mut T };
+        match f(unsafe { &mut *value }) {
+            Some(part) => Ok(AtomicRefMut {
+                value: part,
+                borrows,
+            }),
+            None => Err(AtomicRefMut {
+                value: unsafe { &mut *value },
+                borrows,
+            }),
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for AtomicRefMut<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T: ?Sized> DerefMut for AtomicRefMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value
+    }
+}
+
+impl<T: ?Sized> Drop for AtomicRefMut<'_, T> {
+    fn drop(&mut self) {
+        self.borrows.store(0, Ordering::Release);
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for AtomicRefMut<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn borrows_follow_refcell_rules_across_threads() {
+        let cell = Arc::new(AtomicRefCell::new(vec![1, 2, 3]));
+        let first = cell.borrow();
+        let second = AtomicRef::map(cell.borrow(), |values| &values[1]);
+        assert_eq!((first.len(), *second), (3, 2));
+        assert!(cell.try_borrow_mut().is_none());
+        drop((first, second));
+
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..1000 {
+                        assert_eq!(cell.borrow().iter().sum::<i32>(), 6);
+                    }
+                });
+            }
+        });
+        let mut values = AtomicRefMut::map(cell.borrow_mut(), |values| &mut values[1..]);
+        values[0] = 7;
+        assert!(cell.try_borrow().is_none());
+        drop(values);
+        assert_eq!(*cell.borrow(), [1, 7, 3]);
+    }
+}
@@ -0,0 +1,183 @@
+// Complex event processing, reactive rules that wait for several changes
+//
+// A trigger with `count 3 within 60s` keeps, for each set of values its variables
+// take, when the changes it matched were made. The rule fires when the window holds
+// enough matches, and the window starts again empty. Matches that fall out of the span
+// are evicted as they are seen and at the start of every run.
+use crate::engine::RuleEngine;
+use crate::rule::Window;
+use crate::time::Stamp;
+use crate::value::Bindings;
+
+impl RuleEngine {
+    // Records a trigger match on a change made at, returning true when the window is full
+    pub(crate) fn fill_window(
+        &mut self,
+        rule: usize,
+        key: &Bindings,
+        window: Window,
+        at: Stamp,
+    ) -> bool {
+        let seen = self.windows.entry((rule, key.clone())).or_default();
+        seen.push_back(at);
+        if let Some(within) = window.within {
+            while seen.front().is_some_and(|&since| !within.covers(since, at)) {
+                seen.pop_front();
+            }
+        }
+        if seen.len() < window.count {
+            return false;
+        }
+        self.windows.remove(&(rule, key.clone()));
+        true
+    }
+
+    pub(crate) fn evict_windows(&mut self) {
+        let now = self.now();
+        let rules = &self.rules;
+        self.windows.retain(|(rule, _), seen| {
+            let within = rules[*rule]
+                .trigger
+                .as_ref()
+                .and_then(|trigger| trigger.window)
+                .and_then(|window| window.within);
+            if let Some(within) = within {
+                seen.retain(|&since| within.covers(since, now));
+            }
+            !seen.is_empty()
+        });
+    }
+
+    // Matches waiting in windows, for inspecting how close rules are to firing
+    pub fn window_len(&self, rule: &str, key: &Bindings) -> usize {
+        let Some(index) = self
+            .rules()
+            .iter()
+            .position(|candidate| candidate.name == rule)
+        else {
+            return 0;
+        };
+        self.windows
+            .get(&(index, key.clone()))
+            .map_or(0, |seen| seen.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::RuleEngine;
+    use crate::registry::{Fact, TypedFact};
+    use crate::store::{Component, EntityStore};
+    use crate::value::{Bindings, Value};
+    use std::time::Duration;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct FailedLogin(i64);
+    impl Component for FailedLogin {}
+    impl Fact for FailedLogin {
+        const FIELDS: &'static [&'static str] = &["attempt"];
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.0)]
+        }
+        fn from_values(values: &[Value]) -> Option<Self> {
+            match values {
+                [value] => Some(FailedLogin(value.as_int()?)),
+                _ => None,
+            }
+        }
+    }
+
+    impl TypedFact for FailedLogin {
+        type Fields = (i64,);
+        fn fields(&self) -> Self::Fields {
+            (self.0,)
+        }
+        fn from_fields((attempt,): Self::Fields) -> Self {
+            FailedLogin(attempt)
+        }
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Locked;
+    impl Component for Locked {}
+    impl Fact for Locked {
+        const FIELDS: &'static [&'static str] = &[];
+        fn to_values(&self) -> Vec<Value> {
+            Vec::new()
+        }
+        fn from_values(values: &[Value]) -> Option<Self> {
+            values.is_empty().then_some(Locked)
+        }
+    }
+    impl TypedFact for Locked {
+        type Fields = ();
+        fn fields(&self) -> Self::Fields {}
+        fn from_fields(_: Self::Fields) -> Self {
+            Locked
+        }
+    }
+
+    #[test]
+    fn three_failures_within_a_minute_lock() {
+        let mut engine = RuleEngine::new();
+        engine.register::<FailedLogin>("FailedLogin");
+        engine.register::<Locked>("Locked");
+        engine
+            .add_rule(crate::rule!(
+                "lockout" on insert FailedLogin(e, _) count 3 within 60s then insert Locked(e)
+            ))
+            .unwrap();
+        engine.set_time(Duration::ZERO);
+
+        let mut store = EntityStore::new();
+        store.new_component::<FailedLogin>();
+        store.track_changes();
+        let mut fail = |engine: &mut RuleEngine, entity, after: u64| {
+            engine.advance(Duration::from_secs(after));
+            store.add_component(entity, FailedLogin(0));
+            engine.run(&mut store);
+            store.update_events();
+            store.has_component::<Locked>(entity)
+        };
+
+        // Spread out, the first has expired by the third
+        assert!(!fail(&mut engine, 1, 0));
+        assert!(!fail(&mut engine, 1, 50));
+        assert!(!fail(&mut engine, 1, 50));
+        let key = Bindings::from([("e".to_string(), Value::Entity(1))]);
+        assert_eq!(engine.window_len("lockout", &key), 2);
+
+        // Another entity's failures are counted separately
+        assert!(!fail(&mut engine, 2, 1));
+        assert!(fail(&mut engine, 1, 1));
+        assert_eq!(engine.window_len("lockout", &key), 0);
+
+        engine.advance(Duration::from_secs(120));
+        engine.run(&mut EntityStore::new());
+        let other = Bindings::from([("e".to_string(), Value::Entity(2))]);
+        assert_eq!(engine.window_len("lockout", &other), 0);
+    }
+
+    #[test]
+    fn windows_count_from_when_changes_were_made() {
+        let mut engine = RuleEngine::new();
+        engine.register::<FailedLogin>("FailedLogin");
+        engine.register::<Locked>("Locked");
+        engine
+            .add_rule(crate::rule!(
+                "lockout" on insert FailedLogin(e, _) count 2 within 60s then insert Locked(e)
+            ))
+            .unwrap();
+        engine.set_time(Duration::ZERO);
+        let mut store = EntityStore::new();
+        store.new_component::<FailedLogin>();
+        engine.run(&mut store);
+
+        // Both are read by the same run, but were made too far apart
+        store.add_component(1, FailedLogin(0));
+        engine.advance(Duration::from_secs(90));
+        store.add_component(1, FailedLogin(1));
+        engine.run(&mut store);
+        assert!(!store.has_component::<Locked>(1));
+    }
+}
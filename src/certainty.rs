@@ -0,0 +1,206 @@
+// Certainty factors
+//
+// With certainty enabled every fact carries a certainty factor, from 1 for certainly so
+// to -1 for certainly not. Facts are certain unless inserted with insert_uncertain. A
+// rule can be given its own factor, `rule "flu" certainty 0.7 when ...`, for how far its
+// conclusions follow from its conditions, certain if left out.
+//
+// When a rule fires, the factors of the facts its patterns matched are combined by the
+// conjunction and scaled by the rule's factor. A fact concluded by several activations
+// combines what each concluded, by default as MYCIN did, so agreeing evidence adds up
+// without passing 1 and conflicting evidence cancels out. An activation firing again
+// replaces what it concluded before rather than adding to it. A fact whose combined
+// factor falls short of the threshold isn't inserted, and is left as it was if it's
+// already there.
+use crate::engine::{Activation, RuleEngine};
+use crate::error::Error;
+use crate::provenance::FactKey;
+use crate::store::{EntityId, EntityStore};
+use crate::value::{Bindings, Value};
+use std::collections::HashMap;
+
+// How the factors of the facts a rule matched combine
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Conjunction {
+    #[default]
+    Minimum,
+    Product,
+}
+
+// How the factors several activations concluded for one fact combine
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Combination {
+    #[default]
+    Mycin,
+    Maximum,
+}
+
+impl Combination {
+    pub fn combine(self, a: f64, b: f64) -> f64 {
+        match self {
+            Combination::Mycin if a >= 0.0 && b >= 0.0 => a + b * (1.0 - a),
+            Combination::Mycin if a < 0.0 && b < 0.0 => a + b * (1.0 + a),
+            // One certainly so and the other certainly not cancel out
+            Combination::Mycin if a.abs().min(b.abs()) >= 1.0 => 0.0,
+            Combination::Mycin => (a + b) / (1.0 - a.abs().min(b.abs())),
+            Combination::Maximum => a.max(b),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Certainty {
+    pub conjunction: Conjunction,
+    pub combination: Combination,
+    // Concluded facts less certain than this aren't inserted
+    pub threshold: f64,
+    // Facts inserted uncertain, and ones concluded by rules
+    factors: HashMap<FactKey, f64>,
+    // What each activation concluded for each fact, by rule and bindings
+    concluded: HashMap<FactKey, Vec<(usize, Bindings, f64)>>,
+}
+
+impl Default for Certainty {
+    fn default() -> Self {
+        Self {
+            conjunction: Conjunction::default(),
+            combination: Combination::default(),
+            threshold: 0.2,
+            factors: HashMap::new(),
+            concluded: HashMap::new(),
+        }
+    }
+}
+
+impl Certainty {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The fact's factor, 1 unless it was inserted uncertain or concluded by a rule
+    pub fn factor(&self, key: &FactKey) -> f64 {
+        self.factors.get(key).copied().unwrap_or(1.0)
+    }
+
+    // Records what the activation concludes about the fact, true if it's certain enough
+    // to insert
+    pub(crate) fn conclude(
+        &mut self,
+        activation: &Activation,
+        rule: Option<f64>,
+        key: FactKey,
+    ) -> bool {
+        let premises = activation.premises.iter().map(|premise| {
+            self.factor(&(
+                premise.component.clone(),
+                premise.entity,
+                premise.values.clone(),
+            ))
+        });
+        let matched = match self.conjunction {
+            Conjunction::Minimum => premises.fold(1.0, f64::min),
+            Conjunction::Product => premises.product(),
+        };
+        let factor = matched.max(0.0) * rule.unwrap_or(1.0);
+        let concluded = self.concluded.entry(key.clone()).or_default();
+        match concluded.iter_mut().find(|(rule, bindings, _)| {
+            *rule == activation.rule && *bindings == activation.bindings
+        }) {
+            Some((_, _, before)) => *before = factor,
+            None => concluded.push((activation.rule, activation.bindings.clone(), factor)),
+        }
+        let combination = self.combination;
+        let combined = concluded
+            .iter()
+            .map(|&(_, _, factor)| factor)
+            .reduce(|a, b| combination.combine(a, b))
+            .unwrap_or(factor);
+        if combined < self.threshold || combined <= 0.0 {
+            return false;
+        }
+        self.factors.insert(key, combined);
+        true
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.factors.clear();
+        self.concluded.clear();
+    }
+}
+
+impl RuleEngine {
+    // Starts tracking certainty, keeping the settings if it's already enabled
+    pub fn enable_certainty(&mut self) -> &mut Certainty {
+        self.certainty.get_or_insert_with(Certainty::new)
+    }
+
+    pub fn certainty(&self) -> Option<&Certainty> {
+        self.certainty.as_ref()
+    }
+
+    // Inserts a registered fact with the given factor, enabling certainty if it isn't
+    pub fn insert_uncertain(
+        &mut self,
+        store: &mut EntityStore,
+        component: &str,
+        entity_id: EntityId,
+        values: &[Value],
+        factor: f64,
+    ) -> Result<(), Error> {
+        store.insert_fact(self.registry(), component, entity_id, values)?;
+        let key = (component.to_string(), entity_id, values.to_vec());
+        self.enable_certainty().factors.insert(key, factor);
+        Ok(())
+    }
+
+    // The factor of the entity's component as it is now, None if it has none
+    pub fn factor(&self, store: &EntityStore, component: &str, entity_id: EntityId) -> Option<f64> {
+        let values = self.registry().get(component)?.get(store, entity_id)?;
+        let key = (component.to_string(), entity_id, values);
+        Some(
+            self.certainty
+                .as_ref()
+                .map_or(1.0, |certainty| certainty.factor(&key)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn factors_propagate_and_combine() {
+        let mut engine = RuleEngine::new();
+        engine
+            .load_str(
+                r#"
+                fact Fever()
+                fact Cough()
+                fact Flu()
+                fact Cold()
+                rule "flu" certainty 0.7 when Fever(p), Cough(p) then insert Flu(p)
+                rule "feverish" certainty 0.5 when Fever(p) then insert Flu(p)
+                rule "cold" certainty 0.3 when Cough(p) then insert Cold(p)
+                "#,
+            )
+            .unwrap();
+        let mut store = EntityStore::new();
+        engine
+            .insert_uncertain(&mut store, "Fever", 1, &[], 0.8)
+            .unwrap();
+        engine
+            .insert_uncertain(&mut store, "Cough", 1, &[], 0.6)
+            .unwrap();
+        engine.run(&mut store);
+
+        // min(0.8, 0.6) * 0.7 and 0.8 * 0.5, combined as 0.42 + 0.4 * (1 - 0.42)
+        let flu = engine.factor(&store, "Flu", 1).unwrap();
+        assert!((flu - 0.652).abs() < 1e-9);
+        // 0.6 * 0.3 falls short of the threshold
+        assert_eq!(engine.factor(&store, "Cold", 1), None);
+
+        assert!((Combination::Mycin.combine(0.6, -0.4) - 1.0 / 3.0).abs() < 1e-9);
+        assert_eq!(Combination::Maximum.combine(0.6, 0.4), 0.6);
+    }
+}
@@ -0,0 +1,103 @@
+// Copying an entity onto a new one
+//
+// clone_entity gives a new entity a copy of each of an entity's components whose type
+// has been marked with clone_component, or clone_component_with for a type that isn't
+// Clone or needs copying some other way, like resetting a counter or leaving out a
+// handle. Components of types not marked are skipped, so an entity holding a Parent or
+// a Tile doesn't end up with a copy that means something else on the new entity unless
+// asked for. Copies go in through add_component, so hooks, indexes and relations
+// follow, and the whole clone is one undo step.
+use crate::store::{Component, EntityId, EntityStore};
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+// Copies the component from one entity to another, if the first has one
+type Cloner = Arc<dyn Fn(&mut EntityStore, EntityId, EntityId) + Send + Sync>;
+
+#[derive(Default)]
+pub(crate) struct Cloners(HashMap<TypeId, Cloner>);
+
+impl fmt::Debug for Cloners {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Cloners({})", self.0.len())
+    }
+}
+
+impl EntityStore {
+    // Copies T onto cloned entities with Clone::clone
+    pub fn clone_component<T: Component + Eq + Clone + 'static>(&mut self) {
+        self.clone_component_with::<T>(T::clone);
+    }
+
+    // Copies T onto cloned entities with copy
+    pub fn clone_component_with<T: Component + Eq + 'static>(
+        &mut self,
+        copy: impl Fn(&T) -> T + Send + Sync + 'static,
+    ) {
+        let cloner = move |store: &mut EntityStore, from: EntityId, to: EntityId| {
+            let component = store
+                .get::<T>()
+                .and_then(|pool| pool.borrow().get(from).map(&copy));
+            if let Some(component) = component {
+                store.add_component(to, component);
+            }
+        };
+        self.cloners.0.insert(TypeId::of::<T>(), Arc::new(cloner));
+    }
+
+    // A new entity with copies of the marked components of entity_id, see above
+    pub fn clone_entity(&mut self, entity_id: EntityId) -> EntityId {
+        let clone = self.next_entity();
+        self.reserve_up_to(clone);
+        let cloners: Vec<Cloner> = self.cloners.0.values().cloned().collect();
+        self.begin_step();
+        for cloner in cloners {
+            cloner(self, entity_id, clone);
+        }
+        self.end_step();
+        clone
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Health(i64);
+    impl Component for Health {}
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Kills(u32);
+    impl Component for Kills {}
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Handle(u8);
+    impl Component for Handle {}
+
+    #[test]
+    fn clones_copy_marked_components() {
+        let mut store = EntityStore::new();
+        store.new_component::<Health>();
+        store.new_component::<Kills>();
+        store.new_component::<Handle>();
+        store.clone_component::<Health>();
+        store.clone_component_with::<Kills>(|_| Kills(0));
+        store.add_component(4, Health(30));
+        store.add_component(4, Kills(12));
+        store.add_component(4, Handle(1));
+
+        let clone = store.clone_entity(4);
+        assert_eq!(clone, 5);
+        let health = store.get::<Health>().unwrap();
+        assert_eq!(health.borrow().get(clone), Some(&Health(30)));
+        assert_eq!(
+            store.get::<Kills>().unwrap().borrow().get(clone),
+            Some(&Kills(0))
+        );
+        assert!(!store.has_component::<Handle>(clone));
+        assert_eq!(store.clone_entity(99), 6);
+        assert!(!store.has_component::<Health>(6));
+    }
+}
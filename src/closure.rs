@@ -0,0 +1,144 @@
+// Transitive closure of relations
+//
+// Registering a closure over a relation, say `Reachable` over ConnectedTo, gives rules
+// a pattern `Reachable(a, b)` that holds when b can be got to from a by following one
+// or more ConnectedTo links, with no recursive rules needed. An entity holds at most
+// one relation of each type, so the closure from a is the walk along its links, and
+// the closure into b is a walk back through the relation's reverse index. Both are
+// kept up to date by the store as links change, so nothing is recomputed up front.
+use crate::relation::Relation;
+use crate::store::{EntityId, EntityStore};
+use crate::value::Value;
+use std::collections::HashSet;
+use std::marker::PhantomData;
+
+// Stands in for the closure's type in the registry, it is never stored
+pub(crate) struct Closure<R>(PhantomData<R>);
+
+impl EntityStore {
+    // Every entity reachable from the given one along R links, nearest first
+    // The entity itself is included only if the links lead back round to it
+    pub fn reachable<R: Relation>(&self, from: EntityId) -> Vec<EntityId> {
+        let mut reached = Vec::new();
+        let mut seen = HashSet::new();
+        let mut current = from;
+        while let Some(next) = self.related::<R>(current) {
+            if !seen.insert(next) {
+                break;
+            }
+            reached.push(next);
+            current = next;
+        }
+        reached
+    }
+
+    // Every entity the given one is reachable from, nearest first
+    pub fn reaching<R: Relation>(&mut self, to: EntityId) -> Vec<EntityId> {
+        self.index_relation::<R>();
+        self.reaching_indexed::<R>(to)
+    }
+
+    fn reaching_indexed<R: Relation>(&self, to: EntityId) -> Vec<EntityId> {
+        let mut reaching = Vec::new();
+        let mut seen = HashSet::new();
+        let mut frontier = vec![to];
+        while !frontier.is_empty() {
+            let mut next = Vec::new();
+            for target in frontier {
+                for source in self.sources::<R>(target) {
+                    if seen.insert(source) {
+                        reaching.push(source);
+                        next.push(source);
+                    }
+                }
+            }
+            frontier = next;
+        }
+        reaching
+    }
+}
+
+// Registry hooks for the closure's pattern, the target is its only field
+pub(crate) fn closure_rows<R: Relation>(store: &EntityStore, from: EntityId) -> Vec<Vec<Value>> {
+    store
+        .reachable::<R>(from)
+        .into_iter()
+        .map(|to| vec![Value::Entity(to)])
+        .collect()
+}
+
+pub(crate) fn closure_facts<R: Relation>(store: &EntityStore) -> Vec<(EntityId, Vec<Value>)> {
+    let Some(pool) = store.get::<R>() else {
+        return Vec::new();
+    };
+    let sources: Vec<EntityId> = pool.borrow().entities().into_iter().copied().collect();
+    sources
+        .into_iter()
+        .flat_map(|from| {
+            closure_rows::<R>(store, from)
+                .into_iter()
+                .map(move |row| (from, row))
+        })
+        .collect()
+}
+
+pub(crate) fn closure_sources<R: Relation>(store: &EntityStore, to: EntityId) -> Vec<EntityId> {
+    store.reaching_indexed::<R>(to)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::RuleEngine;
+    use crate::store::Component;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Room;
+    impl Component for Room {}
+
+    crate::relation!(ConnectedTo: Room -> Room);
+
+    #[test]
+    fn closure_follows_links_both_ways() {
+        let mut store = EntityStore::new();
+        store.new_component::<Room>();
+        for room in 1..=5 {
+            store.add_component(room, Room);
+        }
+        // 1 → 2 → 3, 4 → 2
+        store.relate::<ConnectedTo>(1, 2).unwrap();
+        store.relate::<ConnectedTo>(2, 3).unwrap();
+        store.relate::<ConnectedTo>(4, 2).unwrap();
+        assert_eq!(store.reachable::<ConnectedTo>(1), vec![2, 3]);
+        assert_eq!(store.reaching::<ConnectedTo>(3), vec![2, 1, 4]);
+
+        let mut engine = RuleEngine::new();
+        engine.register_relation::<ConnectedTo>("ConnectedTo");
+        engine.register_closure::<ConnectedTo>("Reachable");
+        let pairs = |engine: &RuleEngine, store: &EntityStore, source: &str| {
+            let mut pairs: Vec<(EntityId, EntityId)> = engine
+                .query(source, store)
+                .unwrap()
+                .iter()
+                .map(|b| (b["a"].as_entity().unwrap(), b["b"].as_entity().unwrap()))
+                .collect();
+            pairs.sort();
+            pairs
+        };
+        assert_eq!(
+            pairs(&engine, &store, "Reachable(a, b)"),
+            vec![(1, 2), (1, 3), (2, 3), (4, 2), (4, 3)]
+        );
+
+        // Closing the loop, every room on it now reaches itself
+        store.relate::<ConnectedTo>(3, 1).unwrap();
+        assert_eq!(
+            pairs(
+                &engine,
+                &store,
+                "ConnectedTo(b, _), Reachable(a, b), a == b"
+            ),
+            vec![(1, 1), (2, 2), (3, 3)]
+        );
+    }
+}
@@ -0,0 +1,209 @@
+// Reclaiming space after entity churn
+//
+// compact renumbers the live entities 0, 1, 2... keeping their order, so sparse arrays
+// and bitsets only cover ids in use. Registered components are taken out and put back
+// under their new ids through the registry, with entity fields rewritten too, so
+// parents, tiles and relations come back with their children, neighbours and reverse
+// indexes rebuilt. Pools of unregistered types are renumbered in place, but any entity
+// ids held inside their components can't be seen and are left as they were, as are
+// fields pointing at entities that no longer exist. Requirements, see require.rs, are
+// set aside while components are put back, so the order they go in doesn't matter, and
+// a component that still won't go back is an error rather than lost without a word. The
+// undo history refers to the old ids, so it is cleared. memory_usage says what each pool
+// holds, to judge when compacting and shrinking are worth it.
+use crate::error::Error;
+use crate::journal::Journal;
+use crate::registry::{ComponentInfo, FactRow, Registry};
+use crate::store::{EntityId, EntityStore};
+use crate::value::Value;
+use std::collections::{BTreeSet, HashMap};
+
+// Old id to new id, for every entity that was live
+pub type Remap = HashMap<EntityId, EntityId>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolUsage {
+    pub component: &'static str,
+    pub len: usize,
+    // Allocated, spare capacity included
+    pub bytes: usize,
+}
+
+impl EntityStore {
+    pub fn memory_usage(&self) -> Vec<PoolUsage> {
+        self.pool_refs
+            .0
+            .iter()
+            .map(|pool| pool.borrow().usage())
+            .collect()
+    }
+
+    // Renumbers live entities without gaps and shrinks every pool, see above
+    pub fn compact(&mut self, registry: &Registry) -> Result<Remap, Error> {
+        let live: BTreeSet<EntityId> = self
+            .pool_refs
+            .0
+            .iter()
+            .flat_map(|pool| pool.borrow().presence().entities())
+            .collect();
+        let remap: Remap = live
+            .iter()
+            .enumerate()
+            .map(|(new, &old)| (old, new))
+            .collect();
+
+        let requirements = std::mem::take(&mut self.requirements);
+        let sections: Vec<(&ComponentInfo, Vec<FactRow>)> = registry
+            .iter()
+            .filter(|info| !info.is_derived())
+            .map(|info| (info, info.facts(self)))
+            .collect();
+        for (info, rows) in &sections {
+            for (entity_id, _) in rows {
+                info.remove(self, *entity_id);
+            }
+        }
+        for pool in &self.pool_refs.0 {
+            pool.borrow_mut().remap(&remap);
+        }
+        self.reindex_tiles();
+        self.set_next_entity(live.len());
+
+        let renumber = |entity_id: &EntityId| remap.get(entity_id).copied().unwrap_or(*entity_id);
+        let sections = sections
+            .into_iter()
+            .map(|(info, rows)| {
+                let rows = rows
+                    .into_iter()
+                    .map(|(entity_id, values)| {
+                        let values = values
+                            .into_iter()
+                            .map(|value| match value {
+                                Value::Entity(target) => Value::Entity(renumber(&target)),
+                                value => value,
+                            })
+                            .collect();
+                        (renumber(&entity_id), values)
+                    })
+                    .collect();
+                (info, rows)
+            })
+            .collect();
+        let inserted = Registry::insert_rows(self, sections);
+        self.requirements = requirements;
+
+        if self.journal.is_some() {
+            self.journal = Some(Journal::default());
+        }
+        inserted.map_err(Error::Compact)?;
+        self.shrink_to_fit();
+        Ok(remap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hierarchy::Parent;
+    use crate::registry::Fact;
+    use crate::store::Component;
+    use crate::value::Value;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Health(i64);
+    impl Component for Health {}
+
+    impl Fact for Health {
+        const FIELDS: &'static [&'static str] = &["hp"];
+
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.0)]
+        }
+
+        fn from_values(values: &[Value]) -> Option<Self> {
+            match values {
+                [hp] => Some(Health(hp.as_int()?)),
+                _ => None,
+            }
+        }
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Shield;
+    impl Component for Shield {}
+
+    impl Fact for Shield {
+        const FIELDS: &'static [&'static str] = &[];
+
+        fn to_values(&self) -> Vec<Value> {
+            Vec::new()
+        }
+
+        fn from_values(values: &[Value]) -> Option<Self> {
+            values.is_empty().then_some(Shield)
+        }
+    }
+
+    fn bytes(store: &EntityStore) -> usize {
+        store.memory_usage().iter().map(|usage| usage.bytes).sum()
+    }
+
+    #[test]
+    fn compaction_renumbers_and_reclaims() {
+        let mut store = EntityStore::new();
+        store.new_component::<Health>();
+        for entity_id in 0..20_000 {
+            store.add_component(entity_id, Health(entity_id as i64));
+        }
+        for entity_id in 0..19_998 {
+            store.remove_entity(entity_id);
+        }
+        store.add_component(50_000, Health(-1));
+        store.set_parent(19_999, 50_000).unwrap();
+        let before = bytes(&store);
+
+        let mut registry = Registry::new();
+        registry.register_hierarchy("Parent");
+        let remap = store.compact(&registry).unwrap();
+        assert_eq!(remap.len(), 3);
+        assert_eq!((remap[&19_998], remap[&19_999], remap[&50_000]), (0, 1, 2));
+        assert_eq!(store.max_entity(), 2);
+        // A page per pool is all that's left
+        assert!(
+            bytes(&store) * 4 < before,
+            "{} of {}",
+            bytes(&store),
+            before
+        );
+
+        let health = store.get::<Health>().unwrap();
+        assert_eq!(health.borrow().get(1), Some(&Health(19_999)));
+        assert_eq!(health.borrow().get(2), Some(&Health(-1)));
+        assert_eq!(store.parent(1), Some(2));
+        assert_eq!(store.children(2), vec![1]);
+        assert!(!store.has_component::<Parent>(19_999));
+        let usage = store.memory_usage();
+        assert!(usage
+            .iter()
+            .any(|pool| pool.component == "Health" && pool.len == 3));
+    }
+
+    #[test]
+    fn required_components_survive_compaction() {
+        let mut store = EntityStore::new();
+        store.new_component::<Health>();
+        store.new_component::<Shield>();
+        store.require::<Shield, Health>();
+        store.add_component(5, Health(10));
+        store.add_component(5, Shield);
+
+        // Shield goes back in before what it requires
+        let mut registry = Registry::new();
+        registry.register::<Shield>("Shield");
+        registry.register::<Health>("Health");
+        store.compact(&registry).unwrap();
+        assert!(store.has_component::<Shield>(0));
+        assert!(store.has_component::<Health>(0));
+        assert!(store.try_add_component(1, Shield).is_err());
+    }
+}
@@ -0,0 +1,272 @@
+// Finite domain constraints
+//
+// An entity with a Domain component is a constraint variable, holding the integers it
+// may still take. Rules post constraints over them with `then post x + y <= 10`: in a
+// posted constraint, a variable bound to an entity with a Domain stands for that
+// entity's value, and any other variable for the value it was bound to when posted.
+//
+// The engine keeps domains arc consistent, dropping every value that no choice of values
+// from the other domains in some constraint can satisfy, and again for the constraints
+// over any domain that shrank, until nothing more can be dropped. This runs at the start
+// of every run and after every firing that posts a constraint, so rules matching on
+// domains see them pruned. A domain left empty means the constraints can't all hold.
+//
+// Supports are found by trying every combination of the other domains' values, so a
+// constraint is best kept to a few variables over small domains. Registered as a fact,
+// say `engine.register::<Domain>("Domain")`, rules see a domain as its bounds, `min` and
+// `max`, with min above max once it's empty. A domain a rule inserts is every value
+// between its bounds.
+use crate::engine::RuleEngine;
+use crate::registry::Fact;
+use crate::rule::Expr;
+use crate::store::{Component, EntityId, EntityStore};
+use crate::value::{Bindings, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Domain(pub BTreeSet<i64>);
+
+impl Component for Domain {}
+
+impl Domain {
+    // Every value from min to max, both included
+    pub fn range(min: i64, max: i64) -> Self {
+        Domain((min..=max).collect())
+    }
+
+    // The value once only one is left
+    pub fn value(&self) -> Option<i64> {
+        match self.0.len() {
+            1 => self.0.first().copied(),
+            _ => None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl Fact for Domain {
+    const FIELDS: &'static [&'static str] = &["min", "max"];
+
+    fn to_values(&self) -> Vec<Value> {
+        match (self.0.first(), self.0.last()) {
+            (Some(&min), Some(&max)) => vec![Value::Int(min), Value::Int(max)],
+            _ => vec![Value::Int(0), Value::Int(-1)],
+        }
+    }
+
+    fn from_values(values: &[Value]) -> Option<Self> {
+        match values {
+            [min, max] => Some(Domain::range(min.as_int()?, max.as_int()?)),
+            _ => None,
+        }
+    }
+}
+
+// What each entity with a Domain may still take
+pub(crate) type Domains = HashMap<EntityId, BTreeSet<i64>>;
+
+// The entities a constraint is over, each with the variables naming it
+pub(crate) type Scope<'a> = Vec<(EntityId, Vec<&'a str>)>;
+
+pub(crate) fn domains(store: &EntityStore) -> Option<Domains> {
+    let pool = store.get::<Domain>()?.borrow();
+    Some(
+        pool.components_iter()
+            .map(|(&entity_id, domain)| (entity_id, domain.0.clone()))
+            .collect(),
+    )
+}
+
+// A posted constraint or cost, with the bindings of the activation that posted it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Constraint {
+    pub expr: Expr,
+    pub bindings: Bindings,
+}
+
+impl Constraint {
+    // The entities with domains the constraint is over, each with the variables naming it
+    pub(crate) fn variables(&self, domains: &Domains) -> Scope<'_> {
+        let mut variables: BTreeMap<EntityId, Vec<&str>> = BTreeMap::new();
+        for name in self.expr.vars() {
+            let entity = self.bindings.get(name).and_then(Value::as_entity);
+            if let Some(entity_id) = entity.filter(|entity_id| domains.contains_key(entity_id)) {
+                let names = variables.entry(entity_id).or_default();
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        variables.into_iter().collect()
+    }
+
+    // Whether some choice of values for the variables from the next on satisfies it
+    fn satisfiable(
+        &self,
+        variables: &[(EntityId, Vec<&str>)],
+        domains: &Domains,
+        bindings: &mut Bindings,
+        next: usize,
+    ) -> bool {
+        let Some((entity_id, names)) = variables.get(next) else {
+            return self.expr.eval(bindings) == Some(Value::Bool(true));
+        };
+        domains[entity_id].iter().any(|&value| {
+            for name in names {
+                bindings.insert(name.to_string(), Value::Int(value));
+            }
+            self.satisfiable(variables, domains, bindings, next + 1)
+        })
+    }
+}
+
+impl RuleEngine {
+    // Adds a constraint over the domains of the entities in bindings, see above
+    // Domains are pruned on the next run, or by calling propagate
+    pub fn post(&mut self, expr: Expr, bindings: Bindings) {
+        let constraint = Constraint { expr, bindings };
+        if !self.constraints.contains(&constraint) {
+            self.constraints.push(constraint);
+        }
+    }
+
+    pub fn constraints(&self) -> &[Constraint] {
+        &self.constraints
+    }
+
+    // Drops every unsupported value from the domains, see above
+    // Returns false if some domain is left empty
+    pub fn propagate(&self, store: &mut EntityStore) -> bool {
+        let Some(mut domains) = domains(store) else {
+            return true;
+        };
+        let scopes = self.scopes(&domains);
+        let (shrunk, consistent) =
+            self.prune(&mut domains, &scopes, (0..self.constraints.len()).collect());
+        for entity_id in shrunk {
+            let domain = domains.remove(&entity_id).unwrap_or_default();
+            store.add_component(entity_id, Domain(domain));
+        }
+        consistent
+    }
+
+    // Each constraint's variables, see Constraint::variables
+    pub(crate) fn scopes(&self, domains: &Domains) -> Vec<Scope<'_>> {
+        self.constraints
+            .iter()
+            .map(|constraint| constraint.variables(domains))
+            .collect()
+    }
+
+    // Prunes the domains until the constraints are arc consistent, starting from the
+    // ones queued. Returns the entities whose domains shrank, and false if one emptied
+    pub(crate) fn prune(
+        &self,
+        domains: &mut Domains,
+        scopes: &[Scope],
+        mut queue: VecDeque<usize>,
+    ) -> (BTreeSet<EntityId>, bool) {
+        let mut queued = vec![false; self.constraints.len()];
+        for &index in &queue {
+            queued[index] = true;
+        }
+        let mut shrunk = BTreeSet::new();
+        while let Some(index) = queue.pop_front() {
+            queued[index] = false;
+            let constraint = &self.constraints[index];
+            let over = &scopes[index];
+            for (position, (entity_id, names)) in over.iter().enumerate() {
+                // Try each value for this variable with the others chosen after it
+                let mut order = over.clone();
+                order.swap(0, position);
+                let supported: BTreeSet<i64> = domains[entity_id]
+                    .iter()
+                    .copied()
+                    .filter(|&value| {
+                        let mut bindings = constraint.bindings.clone();
+                        for name in names {
+                            bindings.insert(name.to_string(), Value::Int(value));
+                        }
+                        constraint.satisfiable(&order, domains, &mut bindings, 1)
+                    })
+                    .collect();
+                if supported.len() == domains[entity_id].len() {
+                    continue;
+                }
+                let empty = supported.is_empty();
+                domains.insert(*entity_id, supported);
+                shrunk.insert(*entity_id);
+                if empty {
+                    return (shrunk, false);
+                }
+                for (other, over) in scopes.iter().enumerate() {
+                    let involved = over.iter().any(|(variable, _)| variable == entity_id);
+                    if involved && !queued[other] {
+                        queued[other] = true;
+                        queue.push_back(other);
+                    }
+                }
+            }
+        }
+        (shrunk, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn posted_constraints_prune_domains() {
+        let mut engine = RuleEngine::new();
+        engine.register::<Domain>("Domain");
+        engine
+            .load_str(
+                r#"
+                fact Pair(other)
+                fact Fixed()
+                rule "sum" when Pair(x, y) then post x + y <= 10
+                rule "apart" when Pair(x, y) then post x - y >= 6
+                rule "fixed" when Domain(e, v, v) then insert Fixed(e)
+                "#,
+            )
+            .unwrap();
+        let registry = engine.registry().clone();
+        let mut store = EntityStore::new();
+        store.new_component::<Domain>();
+        store.add_component(1, Domain::range(0, 9));
+        store.add_component(2, Domain::range(0, 9));
+        store
+            .insert_fact(&registry, "Pair", 1, &[Value::Entity(2)])
+            .unwrap();
+
+        engine.run(&mut store);
+        assert_eq!(engine.constraints().len(), 2);
+        let domain = |store: &EntityStore, entity_id| {
+            store
+                .get::<Domain>()
+                .unwrap()
+                .borrow()
+                .get(entity_id)
+                .cloned()
+                .unwrap()
+        };
+        // Each constraint alone, x - y >= 6 leaves x at least 6 and y at most 3
+        assert_eq!(domain(&store, 1), Domain::range(6, 9));
+        assert_eq!(domain(&store, 2), Domain::range(0, 3));
+        assert!(registry.get("Fixed").unwrap().facts(&store).is_empty());
+
+        // Fixing y to 2 leaves only x = 8
+        store.add_component(2, Domain::range(2, 2));
+        assert!(engine.propagate(&mut store));
+        assert_eq!(domain(&store, 1).value(), Some(8));
+        engine.run(&mut store);
+        assert_eq!(registry.get("Fixed").unwrap().facts(&store).len(), 2);
+
+        store.add_component(1, Domain::range(0, 1));
+        assert!(!engine.propagate(&mut store));
+    }
+}
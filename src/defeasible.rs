@@ -0,0 +1,162 @@
+// Defeasible rules
+//
+// A rule can be an exception to others, `rule "penguins" overrides "birds fly" when ...`,
+// so "birds fly, unless penguin" needs no `not Penguin(b)` written into the default.
+// Activations of an overridden rule are defeated by the activations of its exceptions
+// they agree with: they don't fire, and don't count as matches anywhere activations are
+// used. Two activations agree when every variable the rules share by name is bound to
+// the same value, so an exception speaks about the default's variables by their names;
+// one sharing none with it defeats every activation of the default.
+//
+// Exceptions can have exceptions of their own, and a defeated exception defeats nothing,
+// so the most specific rule that applies wins. Rules can't override each other in a
+// circle. Rules in disabled modules and reactive rules defeat nothing, and what a default
+// concluded before its exception came to match stays in the store.
+use crate::engine::{Activation, RuleEngine};
+use crate::error::Error;
+use crate::rule::Rule;
+use crate::value::Bindings;
+use std::collections::{HashMap, HashSet};
+
+// Fails if, following what each rule overrides, some rule leads back to itself
+pub(crate) fn check_overrides<'a>(rules: impl IntoIterator<Item = &'a Rule>) -> Result<(), Error> {
+    let mut overrides: HashMap<&str, Vec<&str>> = HashMap::new();
+    for rule in rules {
+        overrides
+            .entry(&rule.name)
+            .or_default()
+            .extend(rule.overrides.iter().map(String::as_str));
+    }
+
+    fn visit<'a>(
+        name: &'a str,
+        overrides: &HashMap<&'a str, Vec<&'a str>>,
+        path: &mut Vec<&'a str>,
+        done: &mut HashSet<&'a str>,
+    ) -> Result<(), Error> {
+        if done.contains(name) {
+            return Ok(());
+        }
+        if let Some(start) = path.iter().position(|&other| other == name) {
+            let circle: Vec<String> = path[start..]
+                .iter()
+                .chain([&name])
+                .map(|name| format!("\"{}\"", name))
+                .collect();
+            return Err(Error::Overrides(circle.join(" overrides ")));
+        }
+        path.push(name);
+        for &overridden in overrides.get(name).into_iter().flatten() {
+            visit(overridden, overrides, path, done)?;
+        }
+        path.pop();
+        done.insert(name);
+        Ok(())
+    }
+
+    let mut done = HashSet::new();
+    for &name in overrides.keys() {
+        visit(name, &overrides, &mut Vec::new(), &mut done)?;
+    }
+    Ok(())
+}
+
+// Whether the bindings agree on every variable both have
+fn agree(a: &Bindings, b: &Bindings) -> bool {
+    a.iter()
+        .all(|(name, value)| b.get(name).is_none_or(|other| other == value))
+}
+
+impl RuleEngine {
+    // The activations no undefeated exception agrees with, in the same order
+    pub(crate) fn undefeated(&self, activations: Vec<Activation>) -> Vec<Activation> {
+        if self.rules.iter().all(|rule| rule.overrides.is_empty()) {
+            return activations;
+        }
+        let mut known = vec![None; activations.len()];
+        let defeated: Vec<bool> = (0..activations.len())
+            .map(|index| self.defeated(&activations, index, &mut known))
+            .collect();
+        activations
+            .into_iter()
+            .zip(defeated)
+            .filter_map(|(activation, defeated)| (!defeated).then_some(activation))
+            .collect()
+    }
+
+    // Rules can't override in a circle, so this always bottoms out
+    fn defeated(
+        &self,
+        activations: &[Activation],
+        index: usize,
+        known: &mut Vec<Option<bool>>,
+    ) -> bool {
+        if let Some(defeated) = known[index] {
+            return defeated;
+        }
+        let activation = &activations[index];
+        let name = &self.rules[activation.rule].name;
+        let defeated = (0..activations.len()).any(|other| {
+            let exception = &activations[other];
+            self.rules[exception.rule].overrides.contains(name)
+                && agree(&activation.bindings, &exception.bindings)
+                && !self.defeated(activations, other, known)
+        });
+        known[index] = Some(defeated);
+        defeated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::EntityStore;
+
+    #[test]
+    fn exceptions_defeat_defaults() {
+        let mut engine = RuleEngine::new();
+        engine
+            .load_str(
+                r#"
+                fact Bird()
+                fact Penguin()
+                fact Rocket()
+                fact Flies(how)
+                rule "birds fly" when Bird(b) then insert Flies(b, "wings")
+                rule "penguins don't" overrides "birds fly" when Penguin(b) then insert Flies(b, "no")
+                rule "unless strapped to a rocket" overrides "penguins don't"
+                when Penguin(b), Rocket(b) then insert Flies(b, "rocket")
+                "#,
+            )
+            .unwrap();
+        let registry = engine.registry().clone();
+        let mut store = EntityStore::new();
+        for (entity_id, component) in [(1, "Bird"), (2, "Bird"), (2, "Penguin"), (3, "Bird")] {
+            store
+                .insert_fact(&registry, component, entity_id, &[])
+                .unwrap();
+        }
+        for component in ["Penguin", "Rocket"] {
+            store.insert_fact(&registry, component, 3, &[]).unwrap();
+        }
+        engine.run(&mut store);
+
+        let flies = registry.get("Flies").unwrap();
+        assert_eq!(flies.get(&store, 1), Some(vec!["wings".into()]));
+        assert_eq!(flies.get(&store, 2), Some(vec!["no".into()]));
+        // The rocket defeats the penguin rule, which then can't defeat the default, so
+        // both fire and the rocket, added later, has the last word
+        assert_eq!(flies.get(&store, 3), Some(vec!["rocket".into()]));
+
+        let mut circular = RuleEngine::new();
+        let result = circular.load_str(
+            r#"
+            fact Bird()
+            rule "a" overrides "b" when Bird(x) then remove Bird(x)
+            rule "b" overrides "a" when Bird(x) then remove Bird(x)
+            "#,
+        );
+        assert!(matches!(result, Err(Error::Overrides(_))));
+        assert!(circular.rules().is_empty());
+    }
+}
@@ -0,0 +1,189 @@
+// Differences between snapshots, and applying them as patches
+//
+// Diffing two snapshots compares their marked pools type by type. For each type that
+// changed, the delta keeps the components that were added or replaced and the
+// entities that lost theirs, and it lists the entities that appeared or disappeared
+// altogether. Applying a delta to a store in the first snapshot's state brings it to
+// the second's through add_component and remove_component, so hooks and change
+// tracking see the patch, and the tile map is rebuilt if tiles moved.
+use crate::snapshot::{PoolCopier, WorldSnapshot};
+use crate::store::{Component, EntityId, EntityStore, Pool};
+use crate::tiles::Tile;
+use std::any::{Any, TypeId};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+// The changes to one component type, in entity order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolDelta<T> {
+    // Components added or replaced
+    pub set: Vec<(EntityId, T)>,
+    pub removed: Vec<EntityId>,
+}
+
+pub(crate) fn entities<T: Component + Eq + 'static>(pool: &dyn Any) -> Vec<EntityId> {
+    pool.downcast_ref::<Pool<T>>()
+        .map(|pool| pool.entities().into_iter().copied().collect())
+        .unwrap_or_default()
+}
+
+pub(crate) fn diff<T: Component + Eq + Clone + 'static>(
+    before: Option<&dyn Any>,
+    after: Option<&dyn Any>,
+) -> Option<Box<dyn Any>> {
+    let empty = Pool::<T>::new();
+    let before = before
+        .and_then(|pool| pool.downcast_ref())
+        .unwrap_or(&empty);
+    let after = after.and_then(|pool| pool.downcast_ref()).unwrap_or(&empty);
+    let mut set: Vec<(EntityId, T)> = after
+        .components_iter()
+        .filter(|&(&entity_id, component)| before.get(entity_id) != Some(component))
+        .map(|(&entity_id, component)| (entity_id, component.clone()))
+        .collect();
+    let mut removed: Vec<EntityId> = before
+        .entities()
+        .into_iter()
+        .copied()
+        .filter(|&entity_id| !after.has_component(entity_id))
+        .collect();
+    if set.is_empty() && removed.is_empty() {
+        return None;
+    }
+    set.sort_by_key(|(entity_id, _)| *entity_id);
+    removed.sort_unstable();
+    Some(Box::new(PoolDelta { set, removed }))
+}
+
+pub(crate) fn apply<T: Component + Eq + Clone + 'static>(store: &mut EntityStore, delta: &dyn Any) {
+    let Some(delta) = delta.downcast_ref::<PoolDelta<T>>() else {
+        return;
+    };
+    if store.get::<T>().is_none() {
+        store.new_component::<T>();
+    }
+    for &entity_id in &delta.removed {
+        store.remove_component::<T>(entity_id);
+    }
+    for (entity_id, component) in &delta.set {
+        store.reserve_up_to(*entity_id);
+        store.add_component(*entity_id, component.clone());
+    }
+}
+
+pub struct WorldDelta {
+    // Entities with no marked component before that have one after, and the reverse
+    pub spawned: Vec<EntityId>,
+    pub despawned: Vec<EntityId>,
+    pub max_entity: EntityId,
+    pools: HashMap<TypeId, (PoolCopier, Box<dyn Any>)>,
+}
+
+impl WorldDelta {
+    pub fn is_empty(&self) -> bool {
+        self.pools.is_empty()
+    }
+
+    // The changes to T's pool, None if it didn't change
+    pub fn changes<T: Component + Eq + 'static>(&self) -> Option<&PoolDelta<T>> {
+        self.pools.get(&TypeId::of::<T>())?.1.downcast_ref()
+    }
+
+    // Brings a store from the diff's first snapshot to its second
+    pub fn apply(&self, store: &mut EntityStore) {
+        for (copier, delta) in self.pools.values() {
+            (copier.apply)(store, delta.as_ref());
+        }
+        if self.pools.contains_key(&TypeId::of::<Tile>()) {
+            store.reindex_tiles();
+        }
+        store.reserve_up_to(self.max_entity);
+    }
+}
+
+impl fmt::Debug for WorldDelta {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WorldDelta")
+            .field("spawned", &self.spawned)
+            .field("despawned", &self.despawned)
+            .field("max_entity", &self.max_entity)
+            .field("pools", &self.pools.len())
+            .finish()
+    }
+}
+
+impl WorldSnapshot {
+    // What changed going from this snapshot to other
+    // Only types marked when either snapshot was taken are compared
+    pub fn diff(&self, other: &WorldSnapshot) -> WorldDelta {
+        let mut copiers = self.copiers.clone();
+        copiers.extend(other.copiers.iter().map(|(&k, &v)| (k, v)));
+
+        let mut before = BTreeSet::new();
+        let mut after = BTreeSet::new();
+        let mut pools = HashMap::new();
+        for (type_id, copier) in copiers {
+            let old = self.pools.get(&type_id).map(|pool| pool.as_ref());
+            let new = other.pools.get(&type_id).map(|pool| pool.as_ref());
+            before.extend(old.map(copier.entities).unwrap_or_default());
+            after.extend(new.map(copier.entities).unwrap_or_default());
+            if let Some(delta) = (copier.diff)(old, new) {
+                pools.insert(type_id, (copier, delta));
+            }
+        }
+        WorldDelta {
+            spawned: after.difference(&before).copied().collect(),
+            despawned: before.difference(&after).copied().collect(),
+            max_entity: other.max_entity,
+            pools,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Health(i64);
+
+    impl Component for Health {}
+
+    #[test]
+    fn deltas_patch_one_snapshot_into_the_next() {
+        let mut store = EntityStore::new();
+        store.new_component::<Health>();
+        store.snapshot_component::<Health>();
+        store.add_component(1, Health(10));
+        store.add_component(2, Health(20));
+        store.place_tile(1, Tile::new(0, 0));
+        let before = store.snapshot();
+
+        store.add_component(1, Health(5));
+        store.remove_component::<Health>(2);
+        store.add_component(3, Health(30));
+        store.place_tile(3, Tile::new(1, 0));
+        let after = store.snapshot();
+
+        let delta = before.diff(&after);
+        assert_eq!(delta.spawned, vec![3]);
+        assert_eq!(delta.despawned, vec![2]);
+        assert_eq!(
+            delta.changes::<Health>(),
+            Some(&PoolDelta {
+                set: vec![(1, Health(5)), (3, Health(30))],
+                removed: vec![2],
+            })
+        );
+        assert!(before.diff(&before).is_empty());
+
+        // A replica at the earlier state catches up by applying the delta
+        let mut replica = EntityStore::new();
+        replica.snapshot_component::<Health>();
+        replica.restore(&before);
+        delta.apply(&mut replica);
+        assert!(replica.snapshot().diff(&after).is_empty());
+        assert_eq!(replica.tile_at(Tile::new(1, 0)), Some(3));
+        assert_eq!(replica.neighbours(1), vec![3]);
+    }
+}
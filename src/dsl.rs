@@ -0,0 +1,961 @@
+// Text rule language
+//
+// rule "flee" salience 10
+// when Health(e, h), h < 10, not Fleeing(e)
+// then insert Fleeing(e)
+//
+// After the salience a rule can be given a weight, `weight 1.5`, see probability.rs, and
+// then a certainty factor, `certainty 0.8`, see certainty.rs. Last comes what it's an
+// exception to, `overrides "birds fly", "birds nest"`, see defeasible.rs, and then
+// `no-loop` and `lock-on-active` in either order, see looping.rs.
+//
+// A rule can instead react to changes, firing once for each one:
+//
+// rule "hurt" on insert Damage(e, amt) when Health(e, h) then insert Health(e, h - amt)
+//
+// and can wait for several changes, here three logins for the same entity within a minute:
+//
+// rule "lockout" on insert FailedLogin(e) count 3 within 60s then insert Locked(e)
+//
+// Patterns can say how long they must have held: `Overheated(e) for at least 5 ticks`,
+// `Alarm(e) within 10s`, or `Alarm(e) since t` to bind the tick it began at.
+//
+// `near(a, b, 5)`, `within(a, "town")` and `adjacent(a, b)` ask the engine's spatial
+// index about entity positions, see spatial.rs. `rcc8(a, b, "tpp|ntpp")` asks how two
+// regions relate, see rcc8.rs.
+//
+// Inserted facts can be given a time to live, `insert Stunned(e) for 3 ticks`.
+// Spans of time are written `5 ticks`, `60s` or `250ms`.
+//
+// `module "combat"` puts the rules after it, up to the next module line, in that module.
+//
+// `then run "reward"` runs a script, see script.rs.
+//
+// `then post x + y <= 10` posts a constraint over the domains of x and y, see
+// constraint.rs, and `then minimize 3 * x` a cost for the solver, see solver.rs.
+//
+// `fact Quest(title, stage)` declares a component the host doesn't have, see dynamic.rs.
+//
+// Patterns start with an uppercase component name, variables are lowercase,
+// `_` is a wildcard. A pattern can be followed by `where` and a test on its fields,
+// `Position(e, _, _) where x > 0 && y < 10`. `t between a and b` is short for
+// `t >= a && t <= b`. Comments run from `//` to the end of the line.
+use crate::error::Error;
+use crate::rule::{
+    Action, BinaryOp, Condition, Expr, Pattern, Rule, Spatial, Temporal, Term, Trigger,
+    TriggerKind, UnaryOp, Window,
+};
+use crate::time::Span;
+use crate::value::Value;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Punct(&'static str),
+    Eof,
+}
+
+#[derive(Debug, Clone)]
+struct Spanned {
+    token: Token,
+    line: usize,
+    column: usize,
+}
+
+// Longest first, so `<=` wins over `<`
+const PUNCTUATION: &[&str] = &[
+    "==", "!=", "<=", ">=", "&&", "||", "(", ")", ",", "+", "-", "*", "/", "%", "<", ">", "!",
+];
+
+fn tokenize(source: &str) -> Result<Vec<Spanned>, Error> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let (mut i, mut line, mut column) = (0, 1, 1);
+
+    let error = |line, column, message: String| Error::Parse {
+        line,
+        column,
+        message,
+    };
+
+    while i < chars.len() {
+        let c = chars[i];
+        let (start, start_line, start_column) = (i, line, column);
+
+        if c == '\n' {
+            i += 1;
+            line += 1;
+            column = 1;
+            continue;
+        }
+        if c.is_whitespace() {
+            i += 1;
+            column += 1;
+            continue;
+        }
+        if c == '/' && chars.get(i + 1) == Some(&'/') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+            continue;
+        }
+
+        let token = if c.is_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            Token::Ident(chars[start..i].iter().collect())
+        } else if c.is_ascii_digit() {
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                i += 1;
+            }
+            let is_float =
+                chars.get(i) == Some(&'.') && chars.get(i + 1).is_some_and(|c| c.is_ascii_digit());
+            if is_float {
+                i += 1;
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    i += 1;
+                }
+            }
+            let text: String = chars[start..i].iter().collect();
+            if is_float {
+                Token::Float(text.parse().unwrap())
+            } else {
+                Token::Int(
+                    text.parse().map_err(|_| {
+                        error(line, column, format!("integer `{}` is too large", text))
+                    })?,
+                )
+            }
+        } else if c == '"' {
+            i += 1;
+            let mut text = String::new();
+            loop {
+                match chars.get(i) {
+                    None => return Err(error(line, column, "unterminated string".into())),
+                    Some('"') => break,
+                    Some('\\') => {
+                        text.push(match chars.get(i + 1) {
+                            Some('n') => '\n',
+                            Some('t') => '\t',
+                            Some(&other) => other,
+                            None => return Err(error(line, column, "unterminated string".into())),
+                        });
+                        i += 2;
+                    }
+                    Some(&other) => {
+                        text.push(other);
+                        i += 1;
+                    }
+                }
+            }
+            i += 1;
+            Token::Str(text)
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            match PUNCTUATION.iter().find(|p| rest.starts_with(**p)) {
+                Some(punct) => {
+                    i += punct.len();
+                    Token::Punct(punct)
+                }
+                None => return Err(error(line, column, format!("unexpected character `{}`", c))),
+            }
+        };
+
+        // Strings may span lines, so walk what was consumed to keep the position right
+        for &consumed in &chars[start..i] {
+            if consumed == '\n' {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
+        }
+        tokens.push(Spanned {
+            token,
+            line: start_line,
+            column: start_column,
+        });
+    }
+
+    tokens.push(Spanned {
+        token: Token::Eof,
+        line,
+        column,
+    });
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Spanned>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.position].token
+    }
+
+    fn peek_at(&self, offset: usize) -> &Token {
+        let index = (self.position + offset).min(self.tokens.len() - 1);
+        &self.tokens[index].token
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.position].token.clone();
+        if self.position < self.tokens.len() - 1 {
+            self.position += 1;
+        }
+        token
+    }
+
+    fn error(&self, message: String) -> Error {
+        let spanned = &self.tokens[self.position];
+        Error::Parse {
+            line: spanned.line,
+            column: spanned.column,
+            message,
+        }
+    }
+
+    fn describe(token: &Token) -> String {
+        match token {
+            Token::Ident(name) => format!("`{}`", name),
+            Token::Str(text) => format!("{:?}", text),
+            Token::Int(i) => format!("`{}`", i),
+            Token::Float(f) => format!("`{}`", f),
+            Token::Punct(p) => format!("`{}`", p),
+            Token::Eof => "end of input".to_string(),
+        }
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Token::Ident(name) if name == keyword)
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if self.is_keyword(keyword) {
+            self.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), Error> {
+        if self.eat_keyword(keyword) {
+            Ok(())
+        } else {
+            Err(self.error(format!(
+                "expected `{}`, found {}",
+                keyword,
+                Self::describe(self.peek())
+            )))
+        }
+    }
+
+    fn eat_punct(&mut self, punct: &str) -> bool {
+        if matches!(self.peek(), Token::Punct(p) if *p == punct) {
+            self.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_punct(&mut self, punct: &str) -> Result<(), Error> {
+        if self.eat_punct(punct) {
+            Ok(())
+        } else {
+            Err(self.error(format!(
+                "expected `{}`, found {}",
+                punct,
+                Self::describe(self.peek())
+            )))
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, Error> {
+        match self.peek().clone() {
+            Token::Ident(name) => {
+                self.next();
+                Ok(name)
+            }
+            other => Err(self.error(format!("expected a name, found {}", Self::describe(&other)))),
+        }
+    }
+
+    fn rules(&mut self) -> Result<(Vec<Declaration>, Vec<Rule>), Error> {
+        let mut facts = Vec::new();
+        let mut rules = Vec::new();
+        let mut module = None;
+        while *self.peek() != Token::Eof {
+            if self.eat_keyword("module") {
+                module = Some(self.string("a module name")?);
+                continue;
+            }
+            if self.eat_keyword("fact") {
+                facts.push(self.declaration()?);
+                continue;
+            }
+            let mut rule = self.rule()?;
+            rule.module = module.clone();
+            rules.push(rule);
+        }
+        Ok((facts, rules))
+    }
+
+    // `Quest(title, stage)`, after `fact`
+    fn declaration(&mut self) -> Result<Declaration, Error> {
+        let name = self.ident()?;
+        let mut fields = Vec::new();
+        self.expect_punct("(")?;
+        if !self.eat_punct(")") {
+            loop {
+                let field = self.ident()?;
+                if fields.contains(&field) {
+                    return Err(self.error(format!("`{}` is declared twice", field)));
+                }
+                fields.push(field);
+                if !self.eat_punct(",") {
+                    break;
+                }
+            }
+            self.expect_punct(")")?;
+        }
+        Ok((name, fields))
+    }
+
+    fn count(&mut self) -> Result<usize, Error> {
+        match self.peek().clone() {
+            Token::Int(i) if i > 0 => {
+                self.next();
+                Ok(i as usize)
+            }
+            other => Err(self.error(format!(
+                "expected a positive count, found {}",
+                Self::describe(&other)
+            ))),
+        }
+    }
+
+    // `5 ticks`, `60s`, `1.5s`, `250ms`
+    fn span(&mut self) -> Result<Span, Error> {
+        let at = self.position;
+        let amount = match self.peek().clone() {
+            Token::Int(i) if i >= 0 => i as f64,
+            Token::Float(f) if f >= 0.0 => f,
+            other => {
+                return Err(self.error(format!(
+                    "expected a length of time, found {}",
+                    Self::describe(&other)
+                )));
+            }
+        };
+        self.next();
+        let unit = self.ident()?;
+        let seconds = match unit.as_str() {
+            "tick" | "ticks" if amount.fract() == 0.0 => return Ok(Span::Ticks(amount as u64)),
+            "s" => amount,
+            "ms" => amount / 1000.0,
+            _ => {
+                self.position -= 1;
+                return Err(self.error(format!(
+                    "expected `ticks`, `s` or `ms` after `{}`, found `{}`",
+                    amount, unit
+                )));
+            }
+        };
+        Duration::try_from_secs_f64(seconds)
+            .map(Span::Time)
+            .map_err(|_| {
+                self.position = at;
+                self.error(format!("`{}{}` is longer than a span can be", amount, unit))
+            })
+    }
+
+    fn string(&mut self, what: &str) -> Result<String, Error> {
+        match self.peek().clone() {
+            Token::Str(text) => {
+                self.next();
+                Ok(text)
+            }
+            other => Err(self.error(format!(
+                "expected {} in quotes, found {}",
+                what,
+                Self::describe(&other)
+            ))),
+        }
+    }
+
+    // A number, possibly negative
+    fn number(&mut self, what: &str) -> Result<f64, Error> {
+        let negative = self.eat_punct("-");
+        let number = match self.peek().clone() {
+            Token::Int(i) => i as f64,
+            Token::Float(f) => f,
+            other => {
+                return Err(self.error(format!(
+                    "expected a number for {}, found {}",
+                    what,
+                    Self::describe(&other)
+                )));
+            }
+        };
+        self.next();
+        Ok(if negative { -number } else { number })
+    }
+
+    fn rule(&mut self) -> Result<Rule, Error> {
+        self.expect_keyword("rule")?;
+        let name = self.string("a rule name")?;
+        let mut rule = Rule::new(&name);
+
+        if self.eat_keyword("salience") {
+            let negative = self.eat_punct("-");
+            match self.peek().clone() {
+                Token::Int(i) => {
+                    self.next();
+                    let i = if negative { -i } else { i };
+                    rule.salience = i32::try_from(i)
+                        .map_err(|_| self.error(format!("salience `{}` is out of range", i)))?;
+                }
+                other => {
+                    return Err(self.error(format!(
+                        "expected an integer salience, found {}",
+                        Self::describe(&other)
+                    )));
+                }
+            }
+        }
+
+        if self.eat_keyword("weight") {
+            rule.weight = Some(self.number("the weight")?);
+        }
+        if self.eat_keyword("certainty") {
+            rule.certainty = Some(self.number("the certainty")?);
+        }
+        if self.eat_keyword("overrides") {
+            loop {
+                rule.overrides.push(self.string("a rule name")?);
+                if !self.eat_punct(",") {
+                    break;
+                }
+            }
+        }
+        loop {
+            if self.eat_keyword("no") {
+                self.expect_punct("-")?;
+                self.expect_keyword("loop")?;
+                rule.no_loop = true;
+            } else if self.eat_keyword("lock") {
+                for keyword in ["on", "active"] {
+                    self.expect_punct("-")?;
+                    self.expect_keyword(keyword)?;
+                }
+                rule.lock_on_active = true;
+            } else {
+                break;
+            }
+        }
+
+        if self.eat_keyword("on") {
+            let kind = if self.eat_keyword("insert") {
+                TriggerKind::Insert
+            } else if self.eat_keyword("remove") {
+                TriggerKind::Remove
+            } else {
+                return Err(self.error(format!(
+                    "expected `insert` or `remove`, found {}",
+                    Self::describe(self.peek())
+                )));
+            };
+            let pattern = self.pattern()?;
+            let window = match self.eat_keyword("count") {
+                true => Some(Window {
+                    count: self.count()?,
+                    within: match self.eat_keyword("within") {
+                        true => Some(self.span()?),
+                        false => None,
+                    },
+                }),
+                false => None,
+            };
+            rule.trigger = Some(Trigger {
+                kind,
+                pattern,
+                window,
+            });
+        }
+
+        // Reactive rules don't need any further conditions
+        if rule.trigger.is_none() || self.is_keyword("when") {
+            self.expect_keyword("when")?;
+            loop {
+                rule.conditions.push(self.condition()?);
+                if !self.eat_punct(",") {
+                    break;
+                }
+            }
+        }
+
+        self.expect_keyword("then")?;
+        loop {
+            rule.actions.push(self.action()?);
+            if !self.eat_punct(",") {
+                break;
+            }
+        }
+        Ok(rule)
+    }
+
+    fn is_pattern_start(&self) -> bool {
+        matches!(self.peek(), Token::Ident(name) if name.starts_with(|c: char| c.is_uppercase()))
+            && *self.peek_at(1) == Token::Punct("(")
+    }
+
+    fn condition(&mut self) -> Result<Condition, Error> {
+        if self.is_keyword("not") && matches!(self.peek_at(1), Token::Ident(_)) {
+            self.next();
+            return Ok(Condition::Not(self.pattern()?));
+        }
+        if self.is_pattern_start() {
+            return Ok(Condition::Pattern(self.pattern()?));
+        }
+        if let Token::Ident(name) = self.peek() {
+            let spatial = ["near", "within", "adjacent", "rcc8"].contains(&name.as_str());
+            if spatial && *self.peek_at(1) == Token::Punct("(") {
+                return Ok(Condition::Spatial(self.spatial()?));
+            }
+        }
+        Ok(Condition::Test(self.expr()?))
+    }
+
+    fn spatial(&mut self) -> Result<Spatial, Error> {
+        let name = self.ident()?;
+        self.expect_punct("(")?;
+        let a = self.term()?;
+        self.expect_punct(",")?;
+        let spatial = match name.as_str() {
+            "near" | "rcc8" => {
+                let b = self.term()?;
+                self.expect_punct(",")?;
+                match name.as_str() {
+                    "near" => Spatial::Near(a, b, self.expr()?),
+                    _ => Spatial::Rcc8(a, b, self.expr()?),
+                }
+            }
+            "within" => Spatial::Within(a, self.expr()?),
+            _ => Spatial::Adjacent(a, self.term()?),
+        };
+        self.expect_punct(")")?;
+        Ok(spatial)
+    }
+
+    fn pattern(&mut self) -> Result<Pattern, Error> {
+        let component = self.ident()?;
+        self.expect_punct("(")?;
+        let mut args = Vec::new();
+        if !self.eat_punct(")") {
+            loop {
+                args.push(self.term()?);
+                if !self.eat_punct(",") {
+                    break;
+                }
+            }
+            self.expect_punct(")")?;
+        }
+        let mut pattern = Pattern::new(&component, args);
+        if self.eat_keyword("for") {
+            self.expect_keyword("at")?;
+            self.expect_keyword("least")?;
+            pattern.temporal = Some(Temporal::AtLeast(self.span()?));
+        } else if self.eat_keyword("within") {
+            pattern.temporal = Some(Temporal::Within(self.span()?));
+        } else if self.eat_keyword("since") {
+            pattern.temporal = Some(Temporal::Since(self.ident()?));
+        }
+        if self.eat_keyword("where") {
+            pattern.guard = Some(self.expr()?);
+        }
+        Ok(pattern)
+    }
+
+    fn term(&mut self) -> Result<Term, Error> {
+        if let Token::Ident(name) = self.peek().clone() {
+            self.next();
+            return Ok(match name.as_str() {
+                "_" => Term::Wildcard,
+                "true" => Term::Const(Value::Bool(true)),
+                "false" => Term::Const(Value::Bool(false)),
+                _ => Term::Var(name),
+            });
+        }
+        let negative = self.eat_punct("-");
+        let term = match self.peek().clone() {
+            Token::Int(i) => Term::Const(Value::Int(if negative { -i } else { i })),
+            Token::Float(f) => Term::Const(Value::Float(if negative { -f } else { f })),
+            Token::Str(s) if !negative => Term::Const(Value::Str(s)),
+            other => {
+                return Err(self.error(format!(
+                    "expected a variable or literal, found {}",
+                    Self::describe(&other)
+                )))
+            }
+        };
+        self.next();
+        Ok(term)
+    }
+
+    fn action(&mut self) -> Result<Action, Error> {
+        let verb = self.ident()?;
+        match verb.as_str() {
+            "insert" => {
+                let component = self.ident()?;
+                self.expect_punct("(")?;
+                let mut args = Vec::new();
+                if !self.eat_punct(")") {
+                    loop {
+                        args.push(self.expr()?);
+                        if !self.eat_punct(",") {
+                            break;
+                        }
+                    }
+                    self.expect_punct(")")?;
+                }
+                let ttl = match self.eat_keyword("for") {
+                    true => Some(self.span()?),
+                    false => None,
+                };
+                Ok(Action::Insert {
+                    component,
+                    args,
+                    ttl,
+                })
+            }
+            "remove" => {
+                let component = self.ident()?;
+                self.expect_punct("(")?;
+                let entity = self.expr()?;
+                self.expect_punct(")")?;
+                Ok(Action::Remove { component, entity })
+            }
+            "run" => Ok(Action::Run {
+                script: self.string("a script name")?,
+            }),
+            "post" => Ok(Action::Post {
+                constraint: self.expr()?,
+            }),
+            "minimize" => Ok(Action::Minimize { cost: self.expr()? }),
+            _ => {
+                self.position -= 1;
+                Err(self.error(format!(
+                    "expected `insert`, `remove`, `run`, `post` or `minimize`, found `{}`",
+                    verb
+                )))
+            }
+        }
+    }
+
+    // Precedence climbing, loosest first: || && comparison additive multiplicative unary
+    fn expr(&mut self) -> Result<Expr, Error> {
+        self.binary_level(0)
+    }
+
+    fn binary_level(&mut self, level: usize) -> Result<Expr, Error> {
+        const LEVELS: &[&[(&str, BinaryOp)]] = &[
+            &[("||", BinaryOp::Or)],
+            &[("&&", BinaryOp::And)],
+            &[
+                ("==", BinaryOp::Eq),
+                ("!=", BinaryOp::Ne),
+                ("<=", BinaryOp::Le),
+                (">=", BinaryOp::Ge),
+                ("<", BinaryOp::Lt),
+                (">", BinaryOp::Gt),
+            ],
+            &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+            &[
+                ("*", BinaryOp::Mul),
+                ("/", BinaryOp::Div),
+                ("%", BinaryOp::Rem),
+            ],
+        ];
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+
+        let mut lhs = self.binary_level(level + 1)?;
+        'outer: loop {
+            // `x between a and b` sits with the comparisons, meaning x >= a && x <= b
+            if LEVELS[level][0].1 == BinaryOp::Eq && self.eat_keyword("between") {
+                let low = self.binary_level(level + 1)?;
+                self.expect_keyword("and")?;
+                let high = self.binary_level(level + 1)?;
+                lhs = Expr::binary(
+                    Expr::binary(lhs.clone(), BinaryOp::Ge, low),
+                    BinaryOp::And,
+                    Expr::binary(lhs, BinaryOp::Le, high),
+                );
+                continue;
+            }
+            for (punct, op) in LEVELS[level] {
+                if self.eat_punct(punct) {
+                    let rhs = self.binary_level(level + 1)?;
+                    lhs = Expr::binary(lhs, *op, rhs);
+                    continue 'outer;
+                }
+            }
+            return Ok(lhs);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, Error> {
+        if self.eat_punct("-") {
+            return Ok(Expr::Unary(UnaryOp::Neg, Box::new(self.unary()?)));
+        }
+        if self.eat_punct("!") {
+            return Ok(Expr::Unary(UnaryOp::Not, Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, Error> {
+        let expr = match self.peek().clone() {
+            Token::Int(i) => Expr::Const(Value::Int(i)),
+            Token::Float(f) => Expr::Const(Value::Float(f)),
+            Token::Str(s) => Expr::Const(Value::Str(s)),
+            Token::Ident(name) if name == "true" => Expr::Const(Value::Bool(true)),
+            Token::Ident(name) if name == "false" => Expr::Const(Value::Bool(false)),
+            Token::Ident(name) => Expr::Var(name),
+            Token::Punct("(") => {
+                self.next();
+                let expr = self.expr()?;
+                self.expect_punct(")")?;
+                return Ok(expr);
+            }
+            other => {
+                return Err(self.error(format!(
+                    "expected an expression, found {}",
+                    Self::describe(&other)
+                )))
+            }
+        };
+        self.next();
+        Ok(expr)
+    }
+}
+
+// A component declared in the source, its name and field names
+pub type Declaration = (String, Vec<String>);
+
+// Parses every rule in the source, without checking them against a registry
+// Fact declarations are skipped, see parse_source
+pub fn parse(source: &str) -> Result<Vec<Rule>, Error> {
+    Ok(parse_source(source)?.1)
+}
+
+// Parses every fact declaration and rule in the source
+pub fn parse_source(source: &str) -> Result<(Vec<Declaration>, Vec<Rule>), Error> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        position: 0,
+    };
+    parser.rules()
+}
+
+// Parses source containing exactly one rule
+pub fn parse_rule(source: &str) -> Result<Rule, Error> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        position: 0,
+    };
+    let rule = parser.rule()?;
+    if *parser.peek() != Token::Eof {
+        return Err(parser.error(format!(
+            "expected end of input, found {}",
+            Parser::describe(parser.peek())
+        )));
+    }
+    Ok(rule)
+}
+
+// Parses a single action, as found after `then`
+pub fn parse_action(source: &str) -> Result<Action, Error> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        position: 0,
+    };
+    let action = parser.action()?;
+    if *parser.peek() != Token::Eof {
+        return Err(parser.error(format!(
+            "expected end of input, found {}",
+            Parser::describe(parser.peek())
+        )));
+    }
+    Ok(action)
+}
+
+// Parses a comma separated list of conditions, as found after `when`
+pub fn parse_conditions(source: &str) -> Result<Vec<Condition>, Error> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        position: 0,
+    };
+    let mut conditions = Vec::new();
+    loop {
+        conditions.push(parser.condition()?);
+        if !parser.eat_punct(",") {
+            break;
+        }
+    }
+    if *parser.peek() != Token::Eof {
+        return Err(parser.error(format!(
+            "expected `,` or end of input, found {}",
+            Parser::describe(parser.peek())
+        )));
+    }
+    Ok(conditions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_flee_rule() {
+        let rules = parse(
+            r#"rule "flee" when Health(e, h), h < 10, not Fleeing(e) then insert Fleeing(e)"#,
+        )
+        .unwrap();
+        assert_eq!(rules.len(), 1);
+
+        let rule = &rules[0];
+        assert_eq!(rule.name, "flee");
+        assert_eq!(rule.salience, 0);
+        assert_eq!(
+            rule.conditions,
+            vec![
+                Condition::Pattern(Pattern::new("Health", vec![Term::var("e"), Term::var("h")])),
+                Condition::Test(Expr::binary(
+                    Expr::var("h"),
+                    BinaryOp::Lt,
+                    Expr::Const(Value::Int(10))
+                )),
+                Condition::Not(Pattern::new("Fleeing", vec![Term::var("e")])),
+            ]
+        );
+        assert_eq!(
+            rule.actions,
+            vec![Action::Insert {
+                component: "Fleeing".into(),
+                args: vec![Expr::var("e")],
+                ttl: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn parses_several_rules_with_comments() {
+        let source = r#"
+            // heal slowly
+            rule "regen" salience -2
+            when Health(e, h), h < 100
+            then insert Health(e, h + 1)
+
+            rule "cleanup" when Dead(e), Health(e, _) then remove Health(e)
+        "#;
+        let rules = parse(source).unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].salience, -2);
+        assert_eq!(
+            rules[1].conditions[1],
+            Condition::Pattern(Pattern::new("Health", vec![Term::var("e"), Term::Wildcard]))
+        );
+    }
+
+    #[test]
+    fn module_lines_group_rules() {
+        let source = r#"
+            rule "a" when A(e) then remove A(e)
+            module "combat"
+            rule "b" when A(e) then remove A(e)
+            rule "c" when A(e) then remove A(e)
+        "#;
+        let rules = parse(source).unwrap();
+        let modules: Vec<Option<&str>> = rules.iter().map(|rule| rule.module.as_deref()).collect();
+        assert_eq!(modules, [None, Some("combat"), Some("combat")]);
+    }
+
+    #[test]
+    fn operator_precedence() {
+        let rules =
+            parse(r#"rule "p" when A(e, x), x + 1 * 2 > 3 || !true then remove A(e)"#).unwrap();
+        let expected = Expr::binary(
+            Expr::binary(
+                Expr::binary(
+                    Expr::var("x"),
+                    BinaryOp::Add,
+                    Expr::binary(
+                        Expr::Const(Value::Int(1)),
+                        BinaryOp::Mul,
+                        Expr::Const(Value::Int(2)),
+                    ),
+                ),
+                BinaryOp::Gt,
+                Expr::Const(Value::Int(3)),
+            ),
+            BinaryOp::Or,
+            Expr::Unary(UnaryOp::Not, Box::new(Expr::Const(Value::Bool(true)))),
+        );
+        assert_eq!(rules[0].conditions[1], Condition::Test(expected));
+    }
+
+    #[test]
+    fn reports_error_position() {
+        let error = parse("rule \"broken\"\nwhen Health(e h)").unwrap_err();
+        assert_eq!(
+            error,
+            Error::Parse {
+                line: 2,
+                column: 15,
+                message: "expected `)`, found `h`".into()
+            }
+        );
+
+        // Running out where a name, number or expression is due points at the end
+        for (source, column) in [
+            ("rule", 5),
+            ("rule \"r\" salience", 18),
+            ("rule \"r\" when Health(e, h) where h >", 37),
+        ] {
+            match parse(source).unwrap_err() {
+                Error::Parse {
+                    line: 1,
+                    column: found,
+                    message,
+                } => {
+                    assert_eq!(found, column, "{}", source);
+                    assert!(message.ends_with("found end of input"), "{}", message);
+                }
+                error => panic!("{:?}", error),
+            }
+        }
+
+        // Too long a time for a Duration is an error at the number, not a panic
+        let source =
+            r#"rule "r" on insert A(e) count 3 within 100000000000000000000.5s then insert B(e)"#;
+        match parse(source).unwrap_err() {
+            Error::Parse {
+                line: 1,
+                column: 40,
+                message,
+            } => assert!(message.contains("longer than a span can be"), "{}", message),
+            error => panic!("{:?}", error),
+        }
+    }
+}
@@ -0,0 +1,192 @@
+// Components defined at runtime
+//
+// A rule file or host can bring in a fact type the host was never compiled with, by
+// name and field names, `fact Quest(title, stage)` in the rule language or
+// register_dynamic from code. Its facts are stored as rows of values. Each name is
+// given one of the Dynamic<SLOT> types, the first time any registry sees it, and keeps
+// it for the life of the process, so stores shared between engines agree on which pool
+// holds what. A slot is an ordinary component type, so dynamic facts have pools,
+// indexes, hooks and undo like any other, but only DYNAMIC_SLOTS names can be given
+// one. insert_fact and fact read and write any registered component by name, which
+// dynamic ones need since the host has no type to name them by.
+use crate::error::Error;
+use crate::registry::{ComponentInfo, Fact, Registry};
+use crate::store::{check_entity, Component, EntityId, EntityStore};
+use crate::value::Value;
+use std::sync::{Mutex, OnceLock};
+
+pub const DYNAMIC_SLOTS: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dynamic<const SLOT: usize>(pub Vec<Value>);
+
+impl<const SLOT: usize> Component for Dynamic<SLOT> {}
+
+// Fields come from the registration rather than the type, see register_dynamic
+impl<const SLOT: usize> Fact for Dynamic<SLOT> {
+    const FIELDS: &'static [&'static str] = &[];
+
+    fn to_values(&self) -> Vec<Value> {
+        self.0.clone()
+    }
+
+    fn from_values(values: &[Value]) -> Option<Self> {
+        Some(Dynamic(values.to_vec()))
+    }
+}
+
+macro_rules! slots {
+    ($($slot:literal)*) => {
+        [$(ComponentInfo::new::<Dynamic<$slot>> as fn(&str) -> ComponentInfo,)*]
+    };
+}
+
+// A registration for each slot, by slot
+const SLOTS: [fn(&str) -> ComponentInfo; DYNAMIC_SLOTS] = slots!(
+    0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31
+    32 33 34 35 36 37 38 39 40 41 42 43 44 45 46 47 48 49 50 51 52 53 54 55 56 57 58 59 60
+    61 62 63
+);
+
+// Names given slots so far, by slot
+fn taken() -> &'static Mutex<Vec<String>> {
+    static TAKEN: OnceLock<Mutex<Vec<String>>> = OnceLock::new();
+    TAKEN.get_or_init(Default::default)
+}
+
+fn slot(name: &str) -> Option<usize> {
+    let mut taken = taken()
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(slot) = taken.iter().position(|other| other == name) {
+        return Some(slot);
+    }
+    (taken.len() < DYNAMIC_SLOTS).then(|| {
+        taken.push(name.to_string());
+        taken.len() - 1
+    })
+}
+
+impl Registry {
+    // Registers a component with the given fields under the name, see above
+    // Declaring it again with the same fields does nothing, but it can't replace a
+    // compiled component or change its fields
+    pub fn register_dynamic(&mut self, name: &str, fields: &[String]) -> Result<(), Error> {
+        if let Some(info) = self.get(name) {
+            if self.is_dynamic(name) && info.fields == fields {
+                return Ok(());
+            }
+            return Err(Error::Dynamic(format!(
+                "`{}` is already registered with fields {:?}",
+                name, info.fields
+            )));
+        }
+        let slot = slot(name).ok_or_else(|| {
+            Error::Dynamic(format!(
+                "no slot left for `{}`, {} are taken",
+                name, DYNAMIC_SLOTS
+            ))
+        })?;
+        let mut info = SLOTS[slot](name);
+        // Kept for the life of the process, as the names of compiled fields are
+        let fields: Vec<&'static str> = fields
+            .iter()
+            .map(|field| &*Box::leak(field.clone().into_boxed_str()))
+            .collect();
+        info.fields = Box::leak(fields.into_boxed_slice());
+        self.insert_info(info);
+        Ok(())
+    }
+
+    pub fn is_dynamic(&self, name: &str) -> bool {
+        let Some(info) = self.get(name) else {
+            return false;
+        };
+        let taken = taken()
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        taken
+            .iter()
+            .position(|other| other == name)
+            .is_some_and(|slot| SLOTS[slot](name).type_id == info.type_id)
+    }
+}
+
+impl EntityStore {
+    // Gives the entity the named component, from one value per field
+    pub fn insert_fact(
+        &mut self,
+        registry: &Registry,
+        component: &str,
+        entity_id: EntityId,
+        values: &[Value],
+    ) -> Result<(), Error> {
+        let info = registry
+            .get(component)
+            .filter(|info| !info.is_derived())
+            .ok_or_else(|| Error::UnknownComponent(component.to_string()))?;
+        check_entity(entity_id)?;
+        if values.len() != info.fields.len() {
+            return Err(Error::Arity {
+                component: component.to_string(),
+                expected: info.fields.len(),
+                found: values.len(),
+            });
+        }
+        match info.insert(self, entity_id, values) {
+            true => Ok(()),
+            false => Err(Error::Unbuildable {
+                component: component.to_string(),
+                values: values.to_vec(),
+            }),
+        }
+    }
+
+    // The entity's named component, one value per field
+    pub fn fact(
+        &self,
+        registry: &Registry,
+        component: &str,
+        entity_id: EntityId,
+    ) -> Option<Vec<Value>> {
+        registry.get(component)?.get(self, entity_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::RuleEngine;
+
+    #[test]
+    fn rule_files_declare_their_own_facts() {
+        let mut engine = RuleEngine::new();
+        engine
+            .load_str(
+                r#"
+                fact Quest(title, stage)
+                fact Finished(title)
+                rule "finish" when Quest(e, t, 3) then insert Finished(e, t)
+                "#,
+            )
+            .unwrap();
+        assert!(engine.registry().is_dynamic("Quest"));
+        assert!(engine.load_str("fact Quest(title)").is_err());
+        assert!(engine.load_str("fact Quest(title, stage)").is_ok());
+
+        let mut store = EntityStore::new();
+        let quest = [Value::Str("dragon".to_string()), Value::Int(3)];
+        store
+            .insert_fact(engine.registry(), "Quest", 4, &quest)
+            .unwrap();
+        assert!(matches!(
+            store.insert_fact(engine.registry(), "Quest", 5, &quest[..1]),
+            Err(Error::Arity { expected: 2, .. })
+        ));
+        engine.run(&mut store);
+        assert_eq!(
+            store.fact(engine.registry(), "Finished", 4),
+            Some(vec![Value::Str("dragon".to_string())])
+        );
+    }
+}
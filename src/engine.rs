@@ -0,0 +1,1430 @@
+// Forward chaining rule engine over an EntityStore
+use crate::breakpoint::Breakpoints;
+use crate::cell::AtomicRefCell;
+use crate::certainty::Certainty;
+use crate::constraint::Constraint;
+use crate::defeasible::check_overrides;
+use crate::dsl;
+use crate::error::Error;
+use crate::events::EventReader;
+use crate::fuzzy::FuzzySystem;
+use crate::index::{self, Key};
+#[cfg(feature = "metrics")]
+use crate::metrics::Metrics;
+use crate::path::Graph;
+#[cfg(feature = "plugins")]
+use crate::plugin::Plugins;
+use crate::profile::Profile;
+use crate::provenance::{FactKey, Premise, Provenance};
+use crate::query::Query;
+use crate::rcc8::Rcc8Network;
+use crate::reactive::Change;
+use crate::reflect::Reflect;
+use crate::registry::{ComponentInfo, Fact, FactRow, Registry};
+use crate::relation::Relation;
+use crate::replay::{bindings_order, pinned_order};
+use crate::rule::{Action, Condition, Expr, Pattern, Rule, Term};
+#[cfg(feature = "scripting")]
+use crate::script::Scripts;
+use crate::spatial::{Rect, SpatialIndex};
+use crate::stats::StoreEvent;
+use crate::store::{EntityId, EntityStore};
+use crate::tabling::Tables;
+#[cfg(feature = "tokio")]
+use crate::task::Tasks;
+use crate::time::{Clock, SharedTime, Stamp};
+use crate::trace::{Mutation, Trace};
+use crate::ttl::Expiry;
+use crate::value::{Bindings, Value};
+use crate::view::View;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+// A rule whose conditions hold for a particular set of bindings
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Activation {
+    pub rule: usize,
+    pub bindings: Bindings,
+    // The facts matched by the rule's positive patterns, in condition order
+    pub premises: Vec<Premise>,
+}
+
+// What one firing did, see RuleEngine::step
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    pub rule: String,
+    pub activation: Activation,
+    // What its actions changed in the store, as a trace would record them
+    pub mutations: Vec<Mutation>,
+    // Reactive or injected rather than ready by its conditions, see agenda.rs
+    pub queued: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct RuleEngine {
+    pub(crate) registry: Registry,
+    pub(crate) rules: Vec<Rule>,
+
+    // Activations that have already fired and still match
+    // An activation only fires again once it has stopped matching in between (refraction)
+    pub(crate) fired: HashSet<Activation>,
+    // The tick and what lock-on-active rules were ready for when it began, see looping.rs
+    pub(crate) locked: Option<(u64, HashSet<Activation>)>,
+
+    // How each fact inserted by a rule came to be, see explain
+    // Keyed by value too, as a derivation may rest on an older version of a fact
+    pub(crate) provenance: HashMap<FactKey, Provenance>,
+
+    // Only kept once tracing is enabled
+    pub(crate) trace: Option<Trace>,
+    // Only kept once profiling is enabled, locked as matching only borrows the engine,
+    // see profile.rs
+    pub(crate) profile: Option<Mutex<Profile>>,
+    // Only kept once metrics are enabled, see metrics.rs
+    #[cfg(feature = "metrics")]
+    pub(crate) metrics: Option<Metrics>,
+    // Whether to open spans, see instrument.rs
+    #[cfg(feature = "tracing")]
+    pub(crate) spans: bool,
+    // Where to pause, and whether paused, see breakpoint.rs
+    pub(crate) breakpoints: Breakpoints,
+    // Only kept once certainty is enabled, see certainty.rs
+    pub(crate) certainty: Option<Certainty>,
+
+    // Modules whose rules are skipped, see module.rs
+    pub(crate) disabled: HashSet<String>,
+    // Enabled rules in firing order, rebuilt whenever the rules or modules change
+    agenda: Vec<usize>,
+    // Whether rules of equal salience fire most specific first, see specificity.rs
+    pub(crate) specific: bool,
+
+    // Where reactive rules have read the store's changes up to, see reactive.rs
+    pub(crate) changes: Option<EventReader<Change>>,
+    // Reactive activations waiting to fire, in the order their changes happened, and
+    // injected ones, see agenda.rs
+    pub(crate) reactions: VecDeque<Activation>,
+    // Trigger matches whose other conditions are matched once they come up to fire
+    pub(crate) triggered: VecDeque<Activation>,
+    // When each windowed trigger matched, by rule and trigger bindings, see cep.rs
+    pub(crate) windows: HashMap<(usize, Bindings), VecDeque<Stamp>>,
+
+    // Facts inserted with a time to live, see ttl.rs
+    pub(crate) expiries: HashMap<(String, EntityId), Expiry>,
+
+    // When each temporal pattern began to hold, by rule, condition and entity
+    pub(crate) held: HashMap<(usize, usize, EntityId), Stamp>,
+
+    // What spatial predicates in conditions are answered from, see spatial.rs
+    pub(crate) spatial: Option<Arc<AtomicRefCell<dyn SpatialIndex>>>,
+    pub(crate) regions: HashMap<String, Rect>,
+    // Relations asserted between regions, see rcc8.rs
+    pub(crate) rcc8: Rcc8Network,
+
+    // Constraints rules have posted over domains, see constraint.rs
+    pub(crate) constraints: Vec<Constraint>,
+    // Costs rules have posted for the solver, see solver.rs
+    pub(crate) costs: Vec<Constraint>,
+
+    // Fuzzy systems run before rules are matched, see fuzzy.rs
+    pub(crate) fuzzy: Vec<FuzzySystem>,
+    // Kept up to date before each match, see view.rs
+    pub(crate) views: Vec<View>,
+
+    // Sub-goal answers kept between proofs, see tabling.rs
+    pub(crate) tables: Tables,
+
+    pub(crate) tick: u64,
+    pub(crate) clock: Clock,
+    // The two of them as stores stamp changes with, see time.rs
+    pub(crate) time: SharedTime,
+
+    // Whether activations of a rule are sorted rather than left in match order, see replay.rs
+    pub(crate) pinned: bool,
+
+    // Scripts rules can run, see script.rs
+    #[cfg(feature = "scripting")]
+    scripts: Scripts,
+    // WASM modules whose exports rules can run, see plugin.rs
+    #[cfg(feature = "plugins")]
+    plugins: Plugins,
+    // Async actions rules can run and the commands they've sent back, see task.rs
+    #[cfg(feature = "tokio")]
+    pub(crate) tasks: Tasks,
+}
+
+impl RuleEngine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // An engine sharing component registrations with another, e.g. to load a second rule set
+    pub fn with_registry(registry: Registry) -> Self {
+        RuleEngine {
+            registry,
+            ..Self::default()
+        }
+    }
+
+    // Make a component type available to rules under the given name
+    pub fn register<T: Fact>(&mut self, name: &str) {
+        self.registry.register::<T>(name);
+    }
+
+    // Like register, but inserts made by rules must respect the relation's endpoints
+    pub fn register_relation<R: Relation + Fact>(&mut self, name: &str) {
+        self.registry.register_relation::<R>(name);
+    }
+
+    // See closure.rs
+    pub fn register_closure<R: Relation>(&mut self, name: &str) {
+        self.registry.register_closure::<R>(name);
+    }
+
+    // See path.rs
+    pub fn register_paths<G: Graph>(&mut self, name: &str) {
+        self.registry.register_paths::<G>(name);
+    }
+
+    // See hierarchy.rs
+    pub fn register_hierarchy(&mut self, parent: &str) {
+        self.registry.register_hierarchy(parent);
+    }
+
+    // See tiles.rs
+    pub fn register_tiles(&mut self, tile: &str, adjacency: &str) {
+        self.registry.register_tiles(tile, adjacency);
+    }
+
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    pub fn add_rule(&mut self, rule: Rule) -> Result<(), Error> {
+        self.check(&rule)?;
+        check_overrides(self.rules.iter().chain([&rule]))?;
+        self.rules.push(rule);
+        self.reorder();
+        Ok(())
+    }
+
+    // Parse and add every rule in the source, registering the facts it declares
+    // Nothing is added unless all of the rules and declarations are valid
+    pub fn load_str(&mut self, source: &str) -> Result<(), Error> {
+        let (facts, rules) = dsl::parse_source(source)?;
+        let registry = self.registry.clone();
+        let checked = self
+            .declare(&facts)
+            .and_then(|()| rules.iter().try_for_each(|rule| self.check(rule)))
+            .and_then(|()| check_overrides(self.rules.iter().chain(&rules)));
+        if let Err(error) = checked {
+            self.registry = registry;
+            return Err(error);
+        }
+        self.rules.extend(rules);
+        self.reorder();
+        Ok(())
+    }
+
+    // Compiles a script for rules to run by name, replacing any of the same name
+    #[cfg(feature = "scripting")]
+    pub fn define_script(&mut self, name: &str, source: &str) -> Result<(), Error> {
+        self.scripts.define(name, source)
+    }
+
+    // Loads a WASM module for rules to run the exports of, replacing any of the same name
+    #[cfg(feature = "plugins")]
+    pub fn load_plugin(&mut self, name: &str, wasm: &[u8]) -> Result<(), Error> {
+        self.plugins.load(name, wasm)
+    }
+
+    // See reflect.rs
+    pub fn reflect<T: Reflect>(&mut self) {
+        self.registry.reflect::<T>();
+    }
+
+    // See dynamic.rs
+    pub fn register_dynamic(&mut self, name: &str, fields: &[String]) -> Result<(), Error> {
+        self.registry.register_dynamic(name, fields)
+    }
+
+    fn declare(&mut self, facts: &[dsl::Declaration]) -> Result<(), Error> {
+        facts
+            .iter()
+            .try_for_each(|(name, fields)| self.registry.register_dynamic(name, fields))
+    }
+
+    // Swap the whole rule set for the rules in the source, keeping the store untouched
+    // Either every rule is valid and the swap happens, or the old rules stay in place
+    // Refraction is carried over for rules that are unchanged, so they don't refire
+    pub fn replace_str(&mut self, source: &str) -> Result<(), Error> {
+        let (facts, rules) = dsl::parse_source(source)?;
+        let registry = self.registry.clone();
+        let replaced = self
+            .declare(&facts)
+            .and_then(|()| self.replace_rules(rules));
+        if replaced.is_err() {
+            self.registry = registry;
+        }
+        replaced
+    }
+
+    pub fn replace_rules(&mut self, rules: Vec<Rule>) -> Result<(), Error> {
+        for rule in &rules {
+            self.check(rule)?;
+        }
+        check_overrides(&rules)?;
+        let remap: Vec<Option<usize>> = self
+            .rules
+            .iter()
+            .map(|old| rules.iter().position(|new| new == old))
+            .collect();
+        self.fired = std::mem::take(&mut self.fired)
+            .into_iter()
+            .filter_map(|activation| {
+                Some(Activation {
+                    rule: remap[activation.rule]?,
+                    ..activation
+                })
+            })
+            .collect();
+        self.windows = std::mem::take(&mut self.windows)
+            .into_iter()
+            .filter_map(|((rule, key), seen)| Some(((remap[rule]?, key), seen)))
+            .collect();
+        self.held = std::mem::take(&mut self.held)
+            .into_iter()
+            .filter_map(|((rule, position, entity), since)| {
+                Some(((remap[rule]?, position, entity), since))
+            })
+            .collect();
+        // Rule indices move, so what lock-on-active rules were ready for is noted afresh
+        self.locked = None;
+        self.rules = rules;
+        self.reorder();
+        Ok(())
+    }
+
+    // Drop all recorded provenance, explain will report every fact as asserted
+    pub fn clear_provenance(&mut self) {
+        self.provenance.clear();
+    }
+
+    // Forget which activations have fired, so everything currently matching fires again
+    pub fn forget_fired(&mut self) {
+        self.fired.clear();
+    }
+
+    // Forget everything learned from running over a world, keeping the rules, modules
+    // and regions, for after EntityStore::clear
+    pub fn reset(&mut self) {
+        self.fired.clear();
+        self.locked = None;
+        self.provenance.clear();
+        self.reactions.clear();
+        self.triggered.clear();
+        self.windows.clear();
+        self.expiries.clear();
+        self.held.clear();
+        self.constraints.clear();
+        self.costs.clear();
+        if let Some(certainty) = &mut self.certainty {
+            certainty.clear();
+        }
+        self.forget_tables();
+    }
+
+    // Check a rule against the registry, so mistakes show up at load time rather than silently never matching
+    fn check(&self, rule: &Rule) -> Result<(), Error> {
+        self.check_from(rule, HashSet::new())
+    }
+
+    // As check, with some variables bound before the rule starts
+    pub(crate) fn check_from<'a>(
+        &self,
+        rule: &'a Rule,
+        mut bound: HashSet<&'a str>,
+    ) -> Result<(), Error> {
+        let unbound = |variable: &str| Error::UnboundVariable {
+            rule: rule.name.clone(),
+            variable: variable.to_string(),
+        };
+
+        if let Some(trigger) = &rule.trigger {
+            let pattern = &trigger.pattern;
+            self.check_arity(&pattern.component, pattern.args.len())?;
+            bound.extend(pattern.vars());
+            self.check_guard(pattern, &bound).map_err(unbound)?;
+        }
+
+        for condition in &rule.conditions {
+            match condition {
+                Condition::Pattern(pattern) => {
+                    self.check_arity(&pattern.component, pattern.args.len())?;
+                    bound.extend(pattern.vars());
+                    self.check_guard(pattern, &bound).map_err(unbound)?;
+                }
+                Condition::Not(pattern) => {
+                    self.check_arity(&pattern.component, pattern.args.len())?;
+                    let mut local = bound.clone();
+                    local.extend(pattern.vars());
+                    self.check_guard(pattern, &local).map_err(unbound)?;
+                }
+                Condition::Test(expr) => {
+                    if let Some(variable) = expr.vars().into_iter().find(|v| !bound.contains(v)) {
+                        return Err(unbound(variable));
+                    }
+                }
+                Condition::Spatial(predicate) => {
+                    let exprs = predicate.exprs().into_iter().flat_map(Expr::vars);
+                    if let Some(variable) = exprs.into_iter().find(|v| !bound.contains(v)) {
+                        return Err(unbound(variable));
+                    }
+                    // Pairs are searched outwards from an entity we already have
+                    let entities = predicate.entities();
+                    let known = |term: &&Term| match term {
+                        Term::Var(name) => bound.contains(name.as_str()),
+                        Term::Const(_) => true,
+                        Term::Wildcard => false,
+                    };
+                    if entities.len() == 2 && !entities.iter().any(known) {
+                        let variable = match entities[0] {
+                            Term::Var(name) => name.as_str(),
+                            _ => "_",
+                        };
+                        return Err(unbound(variable));
+                    }
+                    bound.extend(entities.into_iter().filter_map(|term| match term {
+                        Term::Var(name) => Some(name.as_str()),
+                        _ => None,
+                    }));
+                }
+            }
+        }
+
+        for action in &rule.actions {
+            let exprs: Vec<&Expr> = match action {
+                Action::Insert {
+                    component, args, ..
+                } => {
+                    self.check_arity(component, args.len())?;
+                    args.iter().collect()
+                }
+                Action::Remove { component, entity } => {
+                    self.info(component)?;
+                    vec![entity]
+                }
+                Action::Run { script } => {
+                    self.check_run(script)?;
+                    Vec::new()
+                }
+                Action::Post { constraint } => vec![constraint],
+                Action::Minimize { cost } => vec![cost],
+            };
+            for expr in exprs {
+                if let Some(variable) = expr.vars().into_iter().find(|v| !bound.contains(v)) {
+                    return Err(unbound(variable));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // A run action needs a script, plugin or async action by that name
+    fn check_run(&self, name: &str) -> Result<(), Error> {
+        #[cfg(feature = "plugins")]
+        if self.plugins.contains(name) {
+            return Ok(());
+        }
+        #[cfg(feature = "scripting")]
+        if self.scripts.contains(name) {
+            return Ok(());
+        }
+        #[cfg(feature = "tokio")]
+        if self.tasks.contains(name) {
+            return Ok(());
+        }
+        Err(Error::Script(format!(
+            "`{}` isn't a defined script, async action or loaded plugin's action",
+            name
+        )))
+    }
+
+    // A where clause may only use bound variables and the component's own fields
+    // Returns the first name that is neither
+    fn check_guard<'a>(&self, pattern: &'a Pattern, bound: &HashSet<&str>) -> Result<(), &'a str> {
+        let Some(guard) = &pattern.guard else {
+            return Ok(());
+        };
+        let fields = self
+            .registry
+            .get(&pattern.component)
+            .map_or(&[][..], |info| info.fields);
+        match guard
+            .vars()
+            .into_iter()
+            .find(|v| !bound.contains(v) && !fields.contains(v))
+        {
+            Some(variable) => Err(variable),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn info(&self, component: &str) -> Result<&ComponentInfo, Error> {
+        self.registry
+            .get(component)
+            .ok_or_else(|| Error::UnknownComponent(component.to_string()))
+    }
+
+    fn check_arity(&self, component: &str, found: usize) -> Result<(), Error> {
+        let expected = self.info(component)?.arity();
+        if expected != found {
+            return Err(Error::Arity {
+                component: component.to_string(),
+                expected,
+                found,
+            });
+        }
+        Ok(())
+    }
+
+    // The facts a pattern could match, given what is already bound
+    // A bound entity argument turns the pool scan into a single lookup, as does a bound
+    // target for a relation
+    pub(crate) fn candidates(
+        info: &ComponentInfo,
+        pattern: &Pattern,
+        bindings: &Bindings,
+        store: &EntityStore,
+    ) -> Vec<FactRow> {
+        Self::lookup(info, pattern, bindings, store).unwrap_or_else(|| info.facts(store))
+    }
+
+    // As candidates, None when the whole pool has to be scanned
+    fn lookup(
+        info: &ComponentInfo,
+        pattern: &Pattern,
+        bindings: &Bindings,
+        store: &EntityStore,
+    ) -> Option<Vec<FactRow>> {
+        let entity = match pattern.args.first() {
+            Some(Term::Var(name)) => bindings.get(name).and_then(Value::as_entity),
+            Some(Term::Const(value)) => value.as_entity(),
+            _ => None,
+        };
+        match entity {
+            Some(entity_id) => Some(
+                info.rows(store, entity_id)
+                    .into_iter()
+                    .map(|fields| (entity_id, fields))
+                    .collect(),
+            ),
+            None => {
+                let target = match pattern.args.get(1) {
+                    Some(Term::Var(name)) => bindings.get(name).and_then(Value::as_entity),
+                    Some(Term::Const(value)) => value.as_entity(),
+                    _ => None,
+                };
+                let sources = target.and_then(|target| info.sources(store, target))?;
+                Some(
+                    sources
+                        .into_iter()
+                        .flat_map(|source| {
+                            info.rows(store, source)
+                                .into_iter()
+                                .map(move |fields| (source, fields))
+                        })
+                        .collect(),
+                )
+            }
+        }
+    }
+
+    // When the pattern's entity is unbound but a field with a hash index is known, or a
+    // field with an ordered index is bounded, the facts of just the entities that could
+    // match, see index.rs. rest is the conditions from this pattern on, for its tests
+    fn indexed(
+        info: &ComponentInfo,
+        pattern: &Pattern,
+        bindings: &Bindings,
+        rest: &[Condition],
+        store: &EntityStore,
+    ) -> Option<Vec<FactRow>> {
+        let known = |term: &Term| match term {
+            Term::Var(name) => bindings.get(name).cloned(),
+            Term::Const(value) => Some(value.clone()),
+            Term::Wildcard => None,
+        };
+        if pattern.args.first().and_then(known).is_some() {
+            return None;
+        }
+        let hashed = pattern
+            .args
+            .iter()
+            .skip(1)
+            .enumerate()
+            .find_map(|(position, term)| store.index_lookup(info.type_id, position, &known(term)?));
+        let entities = hashed.or_else(|| {
+            let mut bounds: Vec<_> = index::field_bounds(pattern, info.fields, bindings, rest)
+                .into_iter()
+                .collect();
+            bounds.sort_by_key(|&(position, _)| position);
+            bounds.into_iter().find_map(|(position, (low, high))| {
+                let bound = |key: Option<Key>| key.map_or(Bound::Unbounded, Bound::Included);
+                store.range_lookup(info.type_id, position, (bound(low), bound(high)))
+            })
+        })?;
+        Some(
+            entities
+                .into_iter()
+                .flat_map(|entity_id| {
+                    info.rows(store, entity_id)
+                        .into_iter()
+                        .map(move |fields| (entity_id, fields))
+                })
+                .collect(),
+        )
+    }
+
+    // When the pattern's entity is unbound and later positive patterns share it, the facts
+    // of just the entities having all of their components, in id order. The entities come
+    // from intersecting the pools' presence bitsets, see bitset.rs, so only they are looked
+    // up. None if there is no such join, or a component involved is derived
+    fn joined(
+        &self,
+        info: &ComponentInfo,
+        pattern: &Pattern,
+        bindings: &Bindings,
+        rest: &[Condition],
+        store: &EntityStore,
+    ) -> Option<Vec<FactRow>> {
+        let entity = match pattern.args.first() {
+            Some(Term::Var(name)) if !bindings.contains_key(name) => &pattern.args[0],
+            _ => return None,
+        };
+        let mut types = Vec::new();
+        for condition in rest {
+            let Condition::Pattern(other) = condition else {
+                continue;
+            };
+            if other.args.first() != Some(entity) {
+                continue;
+            }
+            let other = self.registry.get(&other.component)?;
+            if other.is_derived() {
+                return None;
+            }
+            if !types.contains(&other.type_id) {
+                types.push(other.type_id);
+            }
+        }
+        if types.len() < 2 {
+            return None;
+        }
+        Some(
+            store
+                .entities_with(&types)
+                .into_iter()
+                .flat_map(|entity_id| {
+                    info.rows(store, entity_id)
+                        .into_iter()
+                        .map(move |fields| (entity_id, fields))
+                })
+                .collect(),
+        )
+    }
+
+    // Every way the rule's conditions can be satisfied, in match order
+    // Each match comes with the facts its positive patterns matched
+    pub(crate) fn match_rule(
+        &self,
+        rule: &Rule,
+        store: &EntityStore,
+    ) -> Vec<(Bindings, Vec<Premise>)> {
+        // Temporal patterns need to know which of our rules this is, if any
+        let index = self
+            .rules
+            .iter()
+            .position(|candidate| std::ptr::eq(candidate, rule));
+        self.match_from(index, &rule.conditions, store, Default::default())
+    }
+
+    // Conditions are joined in the order planned for them, see plan.rs, each extending
+    // the binding sets so far
+    // A variable shared between patterns must take the same value in both
+    pub(crate) fn match_from(
+        &self,
+        rule: Option<usize>,
+        conditions: &[Condition],
+        store: &EntityStore,
+        start: (Bindings, Vec<Premise>),
+    ) -> Vec<(Bindings, Vec<Premise>)> {
+        let order = self.plan(conditions, &start.0, store);
+        let planned: Cow<[Condition]> = if order.iter().enumerate().all(|(i, &j)| i == j) {
+            Cow::Borrowed(conditions)
+        } else {
+            Cow::Owned(order.iter().map(|&i| conditions[i].clone()).collect())
+        };
+        let given = start.1.len();
+        let mut partial = vec![start];
+        let now = self.now();
+
+        for (step, condition) in planned.iter().enumerate() {
+            // Where the condition is written, which is what temporal patterns are kept by
+            let position = order[step];
+            let mut next = Vec::new();
+            for (bindings, premises) in &partial {
+                match condition {
+                    Condition::Pattern(pattern) | Condition::Not(pattern) => {
+                        let Some(info) = self.registry.get(&pattern.component) else {
+                            return Vec::new();
+                        };
+                        let rest = match condition {
+                            Condition::Pattern(_) => &planned[step..],
+                            _ => &[],
+                        };
+                        let looked_up = Self::indexed(info, pattern, bindings, rest, store)
+                            .or_else(|| match condition {
+                                Condition::Pattern(_) => {
+                                    self.joined(info, pattern, bindings, rest, store)
+                                }
+                                _ => None,
+                            })
+                            .or_else(|| Self::lookup(info, pattern, bindings, store));
+                        let scanned = looked_up.is_none();
+                        let candidates = looked_up.unwrap_or_else(|| info.facts(store));
+                        self.count_read(&pattern.component, scanned, candidates.len());
+                        let mut unified =
+                            candidates.into_iter().filter_map(|(entity_id, fields)| {
+                                let mut values = Vec::with_capacity(fields.len() + 1);
+                                values.push(Value::Entity(entity_id));
+                                values.extend(fields.iter().cloned());
+                                let mut bindings = pattern.unify(&values, bindings)?;
+                                if !pattern.guard_holds(&bindings, info.fields, &fields) {
+                                    return None;
+                                }
+                                if let Some(temporal) = &pattern.temporal {
+                                    let held = (rule?, position, entity_id);
+                                    let since = self.held.get(&held).copied().unwrap_or(now);
+                                    if !temporal.holds(since, now, &mut bindings) {
+                                        return None;
+                                    }
+                                }
+                                let mut premises = premises.clone();
+                                premises.push(Premise {
+                                    component: pattern.component.clone(),
+                                    entity: entity_id,
+                                    values: fields,
+                                });
+                                Some((bindings, premises))
+                            });
+                        if let Condition::Pattern(_) = condition {
+                            next.extend(unified);
+                        } else if unified.next().is_none() {
+                            next.push((bindings.clone(), premises.clone()));
+                        }
+                    }
+                    Condition::Test(expr) => {
+                        if expr.eval(bindings) == Some(Value::Bool(true)) {
+                            next.push((bindings.clone(), premises.clone()));
+                        }
+                    }
+                    Condition::Spatial(predicate) => next.extend(
+                        self.spatial_matches(predicate, bindings, store)
+                            .into_iter()
+                            .map(|bindings| (bindings, premises.clone())),
+                    ),
+                }
+            }
+            partial = next;
+            if partial.is_empty() {
+                break;
+            }
+        }
+
+        if let Cow::Owned(_) = planned {
+            // Put the premises back in the order their patterns are written
+            let written: Vec<usize> = order
+                .iter()
+                .copied()
+                .filter(|&position| matches!(conditions[position], Condition::Pattern(_)))
+                .collect();
+            for (_, premises) in &mut partial {
+                let mut matched: Vec<(usize, Premise)> = written
+                    .iter()
+                    .copied()
+                    .zip(premises.drain(given..))
+                    .collect();
+                matched.sort_by_key(|&(position, _)| position);
+                premises.extend(matched.into_iter().map(|(_, premise)| premise));
+            }
+        }
+        partial
+    }
+
+    // Every set of bindings that satisfies the conditions, e.g. "Parent(x, y), Parent(y, z)"
+    // The conditions are checked like a rule's, so unknown components are an error
+    pub fn query(&self, source: &str, store: &EntityStore) -> Result<Vec<Bindings>, Error> {
+        Ok(self.solve(&Query::parse(source)?, store)?.collect())
+    }
+
+    // Every set of bindings that satisfies the rule's conditions, in match order
+    pub fn matches(&self, rule: &Rule, store: &EntityStore) -> Vec<Bindings> {
+        self.match_rule(rule, store)
+            .into_iter()
+            .map(|(bindings, _)| bindings)
+            .collect()
+    }
+
+    // Higher salience first, then more specific if ordering by specificity, then rules in
+    // the order they were added
+    // Rules in disabled modules are left out entirely, so they cost nothing to match
+    pub(crate) fn reorder(&mut self) {
+        let rules = &self.rules;
+        let disabled = &self.disabled;
+        self.agenda = (0..rules.len())
+            .filter(|&index| match &rules[index].module {
+                Some(module) => !disabled.contains(module),
+                None => true,
+            })
+            .collect();
+        let specific = self.specific;
+        self.agenda.sort_by_key(|&index| {
+            let rule = &rules[index];
+            let specificity = if specific { rule.specificity() } else { 0 };
+            (-(rule.salience as i64), std::cmp::Reverse(specificity))
+        });
+        self.forget_tables();
+    }
+
+    pub(crate) fn agenda(&self) -> &[usize] {
+        &self.agenda
+    }
+
+    // All current activations, in the order they would fire
+    // Reactive rules only fire on changes, so they never appear here, and defeated
+    // activations don't fire at all, see defeasible.rs
+    pub fn activations(&self, store: &EntityStore) -> Vec<Activation> {
+        let activations = self
+            .agenda
+            .iter()
+            .filter(|&&rule| self.rules[rule].trigger.is_none())
+            .flat_map(|&rule| {
+                let started = self.profile.as_ref().map(|_| Instant::now());
+                let mut matches = self.match_rule(&self.rules[rule], store);
+                if let Some(started) = started {
+                    self.count_match(rule, matches.len(), started.elapsed());
+                }
+                if self.pinned {
+                    matches
+                        .sort_by(|a, b| pinned_order(&a.1, &b.1).then(bindings_order(&a.0, &b.0)));
+                }
+                matches
+                    .into_iter()
+                    .map(move |(bindings, premises)| Activation {
+                        rule,
+                        bindings,
+                        premises,
+                    })
+            })
+            .collect();
+        self.undefeated(activations)
+    }
+
+    // Apply the rule's actions for one activation, returning what it changed
+    fn fire(&mut self, activation: &Activation, store: &mut EntityStore) -> Vec<Mutation> {
+        let rule = &self.rules[activation.rule];
+        let bindings = &activation.bindings;
+        let now = self.now();
+        let mut mutations = Vec::new();
+        let mut posted = false;
+
+        for action in &rule.actions {
+            match action {
+                Action::Insert {
+                    component,
+                    args,
+                    ttl,
+                } => {
+                    let Some(info) = self.registry.get(component) else {
+                        continue;
+                    };
+                    let Some(values) = args
+                        .iter()
+                        .map(|arg| arg.eval(bindings))
+                        .collect::<Option<Vec<_>>>()
+                    else {
+                        continue;
+                    };
+                    if let Some(entity_id) = values.first().and_then(Value::as_entity) {
+                        if let Some(certainty) = &mut self.certainty {
+                            let key = (component.clone(), entity_id, values[1..].to_vec());
+                            if !certainty.conclude(activation, rule.certainty, key) {
+                                continue;
+                            }
+                        }
+                        if info.insert(store, entity_id, &values[1..]) {
+                            self.provenance.insert(
+                                (component.clone(), entity_id, values[1..].to_vec()),
+                                Provenance {
+                                    rule: rule.name.clone(),
+                                    bindings: bindings.clone(),
+                                    premises: activation.premises.clone(),
+                                },
+                            );
+                            let key = (component.clone(), entity_id);
+                            match ttl {
+                                Some(ttl) => self.expiries.insert(
+                                    key,
+                                    Expiry {
+                                        inserted: now,
+                                        ttl: *ttl,
+                                        values: values[1..].to_vec(),
+                                    },
+                                ),
+                                None => self.expiries.remove(&key),
+                            };
+                            mutations.push(Mutation::Inserted {
+                                component: component.clone(),
+                                entity: entity_id,
+                                values: values[1..].to_vec(),
+                            });
+                        }
+                    }
+                }
+                Action::Remove { component, entity } => {
+                    let Some(info) = self.registry.get(component) else {
+                        continue;
+                    };
+                    if let Some(entity_id) =
+                        entity.eval(bindings).as_ref().and_then(Value::as_entity)
+                    {
+                        self.expiries.remove(&(component.clone(), entity_id));
+                        if let Some(values) = info.get(store, entity_id) {
+                            self.provenance
+                                .remove(&(component.clone(), entity_id, values.clone()));
+                            mutations.push(Mutation::Removed {
+                                component: component.clone(),
+                                entity: entity_id,
+                                values,
+                            });
+                        }
+                        info.remove(store, entity_id);
+                    }
+                }
+                Action::Run { script } => self.run_action(&rule.name, script, bindings, store),
+                Action::Post { constraint } => {
+                    let constraint = Constraint {
+                        expr: constraint.clone(),
+                        bindings: bindings.clone(),
+                    };
+                    if !self.constraints.contains(&constraint) {
+                        self.constraints.push(constraint);
+                        posted = true;
+                    }
+                }
+                Action::Minimize { cost } => {
+                    let cost = Constraint {
+                        expr: cost.clone(),
+                        bindings: bindings.clone(),
+                    };
+                    if !self.costs.contains(&cost) {
+                        self.costs.push(cost);
+                    }
+                }
+            }
+        }
+        if posted {
+            self.propagate(store);
+        }
+        mutations
+    }
+
+    // Plugin actions are named "plugin.export", so they're tried before scripts
+    // Async actions are tried next, see task.rs
+    // Which of these runs depends on the features enabled, with none there's nothing to run
+    fn run_action(&self, rule: &str, name: &str, bindings: &Bindings, store: &mut EntityStore) {
+        #[cfg(feature = "plugins")]
+        if self.plugins.contains(name) {
+            self.plugins
+                .run(rule, name, bindings, &self.registry, store);
+            #[cfg(any(feature = "tokio", feature = "scripting"))]
+            return;
+        }
+        #[cfg(feature = "tokio")]
+        if self.tasks.contains(name) {
+            self.tasks.spawn(rule, name, bindings, store);
+            #[cfg(feature = "scripting")]
+            return;
+        }
+        #[cfg(feature = "scripting")]
+        self.scripts
+            .run(rule, name, bindings, &self.registry, store);
+        #[cfg(not(any(feature = "plugins", feature = "tokio", feature = "scripting")))]
+        let _ = (rule, name, bindings, store);
+    }
+
+    // Fire activations one at a time until nothing new is ready
+    // Matches are recomputed after every firing, so rules see each other's changes
+    // Reactive rules fire first, in the order their changes happened
+    // Returns the number of rules fired
+    pub fn run(&mut self, store: &mut EntityStore) -> usize {
+        #[cfg(feature = "tracing")]
+        let _tick = self.tick_span();
+        self.prepare(store);
+        let mut fired = 0;
+        while self.fire_next(store, fired == 0).is_some() {
+            fired += 1;
+            if self.paused().is_some() {
+                break;
+            }
+        }
+        if self.paused().is_none() {
+            self.next_tick();
+        }
+        fired
+    }
+
+    // Fire the one activation run would fire next, None if nothing is ready, so inference
+    // can be walked through a firing at a time
+    // The tick only moves on once nothing is
+    // Pausing at a breakpoint before firing also gives None, see breakpoint.rs
+    pub fn step(&mut self, store: &mut EntityStore) -> Option<Step> {
+        #[cfg(feature = "tracing")]
+        let _tick = self.tick_span();
+        self.prepare(store);
+        let fired = self.fire_next(store, true);
+        if fired.is_none() && self.paused().is_none() {
+            self.next_tick();
+        }
+        fired
+    }
+
+    // Whether a run could change something with no change to the facts, as time
+    // passing expires facts, ends holds, closes windows and brings in async answers
+    pub fn has_pending(&self) -> bool {
+        #[cfg(feature = "tokio")]
+        if self.tasks.pending() {
+            return true;
+        }
+        !self.expiries.is_empty() || !self.held.is_empty() || !self.windows.is_empty()
+    }
+
+    fn next_tick(&mut self) {
+        self.tick += 1;
+        self.share_time();
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &mut self.metrics {
+            metrics.end_tick();
+        }
+    }
+
+    fn prepare(&mut self, store: &mut EntityStore) {
+        self.resume();
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &mut self.metrics {
+            metrics.start_tick();
+        }
+        store.refresh_indexes();
+        self.evict_windows();
+        #[cfg(feature = "tokio")]
+        self.system("actions", |engine| engine.apply_actions(store));
+        self.system("expire", |engine| engine.expire(store));
+        self.system("fuzzy", |engine| engine.infer_fuzzy(store));
+        self.system("propagate", |engine| engine.propagate(store));
+        self.system("observe", |engine| engine.observe(store));
+        if self.has_reactive_rules() {
+            store.track_changes();
+            store.read_fields_with(&self.registry);
+            // A wall clock the engine started with was never shared
+            self.share_time();
+            store.time = Some(self.time.clone());
+            if self.changes.is_none() {
+                self.changes = store
+                    .events::<Change>()
+                    .map(|queue| queue.borrow().reader_from_oldest());
+            }
+        }
+    }
+
+    // Runs one of the stages of a tick, in its own span with the tracing feature
+    fn system<T>(&mut self, name: &'static str, run: impl FnOnce(&mut Self) -> T) -> T {
+        #[cfg(feature = "tracing")]
+        let _system = self.system_span(name);
+        #[cfg(not(feature = "tracing"))]
+        let _ = name;
+        run(self)
+    }
+
+    fn fire_next(&mut self, store: &mut EntityStore, first: bool) -> Option<Step> {
+        self.system("react", |engine| engine.react(store));
+        self.system("views", |engine| engine.refresh_views(store));
+        let activations = self.system("match", |engine| engine.activations(store));
+        let current: HashSet<&Activation> = activations.iter().collect();
+        self.fired.retain(|activation| current.contains(activation));
+        self.lock(&activations);
+
+        let mut ready = activations
+            .iter()
+            .filter(|activation| !self.fired.contains(*activation) && !self.held_back(activation));
+        if first {
+            let pending = self.reactions.len() + self.triggered.len() + ready.clone().count();
+            store.emit(StoreEvent::AgendaBacklog { pending });
+        }
+        let ready = ready.next().cloned();
+        self.match_triggered(store);
+
+        let (next, queued) = match self.reactions.front() {
+            Some(reaction) => (reaction.clone(), true),
+            None => (ready?, false),
+        };
+        if self.break_before(&next, store) {
+            return None;
+        }
+        if queued {
+            self.reactions.pop_front();
+        }
+
+        let started = self.profile.as_ref().map(|_| Instant::now());
+        #[cfg(feature = "tracing")]
+        let firing = self.fire_span(&next);
+        let mutations = self.fire(&next, store);
+        #[cfg(feature = "tracing")]
+        if let Some(span) = firing {
+            crate::instrument::record_fired(&span, &mutations);
+        }
+        if let Some(started) = started {
+            self.count_fire(next.rule, started.elapsed());
+        }
+        let rule = self.rules[next.rule].name.clone();
+        if let Some(trace) = &mut self.trace {
+            trace.record(self.tick, &rule, &next.bindings, mutations.clone());
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &mut self.metrics {
+            metrics.fired(&rule, &mutations);
+        }
+        if !queued {
+            self.fired.insert(next.clone());
+        }
+        let step = Step {
+            rule,
+            activation: next,
+            mutations,
+            queued,
+        };
+        self.break_after(&step, store);
+        Some(step)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::TypedFact;
+    use crate::store::Component;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Health(i64);
+    impl Component for Health {}
+    impl Fact for Health {
+        const FIELDS: &'static [&'static str] = &["value"];
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.0)]
+        }
+        fn from_values(values: &[Value]) -> Option<Self> {
+            match values {
+                [value] => Some(Health(value.as_int()?)),
+                _ => None,
+            }
+        }
+    }
+    impl TypedFact for Health {
+        type Fields = (i64,);
+        fn fields(&self) -> Self::Fields {
+            (self.0,)
+        }
+        fn from_fields((value,): Self::Fields) -> Self {
+            Health(value)
+        }
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Fleeing;
+    impl Component for Fleeing {}
+    impl Fact for Fleeing {
+        const FIELDS: &'static [&'static str] = &[];
+        fn to_values(&self) -> Vec<Value> {
+            Vec::new()
+        }
+        fn from_values(values: &[Value]) -> Option<Self> {
+            values.is_empty().then_some(Fleeing)
+        }
+    }
+    impl TypedFact for Fleeing {
+        type Fields = ();
+        fn fields(&self) -> Self::Fields {}
+        fn from_fields(_: Self::Fields) -> Self {
+            Fleeing
+        }
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Parent(EntityId);
+    impl Component for Parent {}
+    impl Fact for Parent {
+        const FIELDS: &'static [&'static str] = &["parent"];
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Entity(self.0)]
+        }
+        fn from_values(values: &[Value]) -> Option<Self> {
+            match values {
+                [value] => Some(Parent(value.as_entity()?)),
+                _ => None,
+            }
+        }
+    }
+
+    fn engine() -> RuleEngine {
+        let mut engine = RuleEngine::new();
+        engine.register::<Health>("Health");
+        engine.register::<Fleeing>("Fleeing");
+        engine.register::<Parent>("Parent");
+        engine
+    }
+
+    #[test]
+    fn flee_rule_fires_once_per_entity() {
+        let mut engine = engine();
+        engine
+            .load_str(
+                r#"rule "flee" when Health(e, h), h < 10, not Fleeing(e) then insert Fleeing(e)"#,
+            )
+            .unwrap();
+
+        let mut store = EntityStore::new();
+        store.new_component::<Health>();
+        store.add_component(1, Health(5));
+        store.add_component(2, Health(50));
+        store.add_component(3, Health(9));
+
+        assert_eq!(engine.run(&mut store), 2);
+        assert!(store.has_component::<Fleeing>(1));
+        assert!(!store.has_component::<Fleeing>(2));
+        assert!(store.has_component::<Fleeing>(3));
+        assert_eq!(engine.run(&mut store), 0);
+    }
+
+    #[test]
+    fn shared_variables_join_patterns() {
+        let mut engine = engine();
+        let mut store = EntityStore::new();
+        store.new_component::<Parent>();
+        store.add_component(2, Parent(1));
+        store.add_component(3, Parent(2));
+        store.add_component(4, Parent(9));
+
+        engine
+            .load_str(r#"rule "grandparent" when Parent(x, y), Parent(y, z) then remove Parent(x)"#)
+            .unwrap();
+        let matches = engine.matches(&engine.rules()[0], &store);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].get("x"), Some(&Value::Entity(3)));
+        assert_eq!(matches[0].get("z"), Some(&Value::Entity(1)));
+        let chains = engine.query("Parent(x, y), Parent(y, z)", &store).unwrap();
+        assert_eq!(chains, matches);
+        let orphans = engine
+            .query("Parent(x, y), not Parent(y, _)", &store)
+            .unwrap();
+        let orphans: Vec<&Value> = orphans.iter().map(|found| &found["x"]).collect();
+        assert_eq!(orphans, [&Value::Entity(2), &Value::Entity(4)]);
+        assert_eq!(
+            engine.query("Parent(x, y), Parent(y", &store),
+            Err(Error::Parse {
+                line: 1,
+                column: 23,
+                message: "expected `)`, found end of input".into()
+            })
+        );
+    }
+
+    #[test]
+    fn where_clauses_filter_on_fields() {
+        let mut engine = engine();
+        engine
+            .load_str(
+                r#"rule "weak" when Health(e, _) where value < 10, not Fleeing(e) then insert Fleeing(e)"#,
+            )
+            .unwrap();
+
+        let mut store = EntityStore::new();
+        store.new_component::<Health>();
+        store.add_component(1, Health(5));
+        store.add_component(2, Health(50));
+        engine.run(&mut store);
+        assert!(store.has_component::<Fleeing>(1));
+        assert!(!store.has_component::<Fleeing>(2));
+
+        let rule = crate::rule!("w" when Health(e, h) where h < value then remove Health(e));
+        assert!(matches!(&rule.conditions[0], Condition::Pattern(p) if p.guard.is_some()));
+
+        assert_eq!(
+            engine.load_str(r#"rule "x" when Health(e, h) where hp < 1 then remove Health(e)"#),
+            Err(Error::UnboundVariable {
+                rule: "x".into(),
+                variable: "hp".into()
+            })
+        );
+    }
+
+    #[test]
+    fn salience_orders_firing() {
+        let mut engine = engine();
+        engine
+            .load_str(
+                r#"
+                rule "low" when Health(e, h), not Fleeing(e) then insert Health(e, 1)
+                rule "high" salience 5 when Health(e, h), not Fleeing(e) then insert Fleeing(e)
+                "#,
+            )
+            .unwrap();
+
+        let mut store = EntityStore::new();
+        store.new_component::<Health>();
+        store.add_component(1, Health(50));
+
+        assert_eq!(engine.run(&mut store), 1);
+        assert_eq!(
+            store.get::<Health>().unwrap().borrow().get(1),
+            Some(&Health(50))
+        );
+    }
+
+    #[test]
+    fn steps_report_each_firing() {
+        let mut engine = engine();
+        engine
+            .load_str(r#"rule "flee" when Health(e, h), h < 10 then insert Fleeing(e)"#)
+            .unwrap();
+
+        let mut store = EntityStore::new();
+        store.new_component::<Health>();
+        store.new_component::<Fleeing>();
+        store.add_component(1, Health(5));
+
+        let step = engine.step(&mut store).unwrap();
+        assert_eq!(step.rule, "flee");
+        assert_eq!(step.activation.bindings["h"], Value::Int(5));
+        assert_eq!(
+            step.mutations,
+            [Mutation::Inserted {
+                component: "Fleeing".to_string(),
+                entity: 1,
+                values: Vec::new(),
+            }]
+        );
+        assert!(!step.queued);
+        assert_eq!(engine.step(&mut store), None);
+    }
+
+    #[test]
+    fn rule_macro_builds_checked_rule() {
+        let rule = crate::rule!("heal" salience 2 when Health(e, h), h < 10, not Fleeing(e) then insert Health(e, h + 5));
+        assert_eq!(rule.name, "heal");
+        assert_eq!(rule.salience, 2);
+
+        let mut engine = engine();
+        engine.add_rule(rule).unwrap();
+
+        let mut store = EntityStore::new();
+        store.new_component::<Health>();
+        store.add_component(1, Health(3));
+        assert_eq!(engine.run(&mut store), 2);
+        assert_eq!(
+            store.get::<Health>().unwrap().borrow().get(1),
+            Some(&Health(13))
+        );
+    }
+
+    #[test]
+    fn rule_macro_builds_what_the_parser_does() {
+        let built = [
+            crate::rule!(
+                "a" salience -3 weight 1.5 certainty 0.8 overrides "b", "c" no-loop lock-on-active
+                when Health(e, h) for at least 5 ticks where h between 1 and 10 || !(h >= 100),
+                    not Fleeing(e), h - 1 >= -5 * 2, Health(e, -4), adjacent(e, _)
+                then insert Fleeing(e) for 250ms, remove Health(e)
+            ),
+            crate::rule!(
+                "b" lock-on-active on insert Health(e, amt) count 3 within 1.5s
+                when Health(e, _) since t, within(e, "camp")
+                then insert Health(e, (t + amt) % 7)
+            ),
+        ];
+        let parsed = crate::dsl::parse(
+            r#"
+            rule "a" salience -3 weight 1.5 certainty 0.8 overrides "b", "c" no-loop lock-on-active
+            when Health(e, h) for at least 5 ticks where h between 1 and 10 || !(h >= 100),
+                not Fleeing(e), h - 1 >= -5 * 2, Health(e, -4), adjacent(e, _)
+            then insert Fleeing(e) for 250ms, remove Health(e)
+            rule "b" lock-on-active on insert Health(e, amt) count 3 within 1.5s
+            when Health(e, _) since t, within(e, "camp")
+            then insert Health(e, (t + amt) % 7)
+            "#,
+        )
+        .unwrap();
+        assert_eq!(built.to_vec(), parsed);
+    }
+
+    #[test]
+    fn replacing_rules_keeps_refraction_for_unchanged_rules() {
+        let flee = r#"rule "flee" when Health(e, h), h < 10 then insert Fleeing(e)"#;
+        let mut engine = engine();
+        engine.load_str(flee).unwrap();
+
+        let mut store = EntityStore::new();
+        store.new_component::<Health>();
+        store.add_component(1, Health(5));
+        assert_eq!(engine.run(&mut store), 1);
+
+        engine
+            .replace_str(&format!(
+                r#"rule "new" when Fleeing(e) then remove Health(e) {}"#,
+                flee
+            ))
+            .unwrap();
+        assert_eq!(engine.run(&mut store), 1);
+        assert!(!store.has_component::<Health>(1));
+    }
+
+    #[test]
+    fn load_errors() {
+        let mut engine = engine();
+        assert_eq!(
+            engine.load_str(r#"rule "a" when Mana(e, m) then remove Mana(e)"#),
+            Err(Error::UnknownComponent("Mana".into()))
+        );
+        assert_eq!(
+            engine.load_str(r#"rule "b" when Health(e) then remove Health(e)"#),
+            Err(Error::Arity {
+                component: "Health".into(),
+                expected: 2,
+                found: 1
+            })
+        );
+        assert_eq!(
+            engine.load_str(r#"rule "c" when h < 10, Health(e, h) then remove Health(e)"#),
+            Err(Error::UnboundVariable {
+                rule: "c".into(),
+                variable: "h".into()
+            })
+        );
+        assert!(engine.rules().is_empty());
+    }
+}
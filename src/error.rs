@@ -0,0 +1,186 @@
+use crate::store::{EntityId, MAX_ENTITY};
+use crate::value::Value;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    // Malformed rule text, line and column are 1 based
+    Parse {
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    UnknownComponent(String),
+    Arity {
+        component: String,
+        expected: usize,
+        found: usize,
+    },
+    // A variable used before any positive pattern binds it
+    UnboundVariable {
+        rule: String,
+        variable: String,
+    },
+    // A relation endpoint is missing the component the relation requires
+    Endpoint {
+        relation: String,
+        entity: EntityId,
+        component: String,
+    },
+    // Asserting a spatial relation left two regions with no possible relation, see rcc8.rs
+    Inconsistent {
+        a: EntityId,
+        b: EntityId,
+    },
+    // Parenting would make an entity its own ancestor
+    Cycle {
+        child: EntityId,
+        parent: EntityId,
+    },
+    // Saved world state that couldn't be read back, see binary.rs and json.rs
+    Decode(String),
+    // Saved components whose rows couldn't be brought up to their current schema, see migrate.rs
+    Unmigratable(Vec<String>),
+    // A rule file couldn't be read
+    Io {
+        path: String,
+        message: String,
+    },
+    // A component from another store wouldn't go in, see merge.rs
+    Merge(String),
+    // No world by this name, see worlds.rs
+    UnknownWorld(String),
+    // No prefab by this name, see prefab.rs
+    UnknownPrefab(String),
+    // No rule by this name, see agenda.rs
+    UnknownRule(String),
+    // Values that don't make the component, like a string for a number field
+    Unbuildable {
+        component: String,
+        values: Vec<Value>,
+    },
+    // A second entity was given a component only one may have, see singleton.rs
+    Singleton(String),
+    // A field that can't be reached or set through reflection, see reflect.rs
+    Reflect(String),
+    // A script that doesn't compile, or a rule running one that isn't there, see script.rs
+    Script(String),
+    // A plugin that isn't a valid WASM module, see plugin.rs
+    Plugin(String),
+    // A runtime component that can't be registered, see dynamic.rs
+    Dynamic(String),
+    // A component added without, or a component taken from, one needing the other,
+    // see require.rs
+    Required {
+        component: String,
+        requires: String,
+    },
+    // A pool was already borrowed in a way that rules out this borrow, see access.rs
+    Borrowed(String),
+    // A command the REPL can't carry out, see repl.rs
+    Command(String),
+    // A request the server can't make sense of, see server.rs
+    Request(String),
+    // A goal whose rules can't be rewritten for it, see magic.rs
+    Magic(String),
+    // A goal that can't be proved backwards, see tabling.rs
+    Tabling(String),
+    // A fuzzy rule that doesn't parse or names a term that isn't there, see fuzzy.rs
+    Fuzzy(String),
+    // Rules that are exceptions to each other in a circle, see defeasible.rs
+    Overrides(String),
+    // A view that can't be defined, see view.rs
+    View(String),
+    // A component that wouldn't go back in under its new id, see compact.rs
+    Compact(String),
+    // An entity id past the highest a store takes, see store.rs
+    EntityRange(EntityId),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Parse {
+                line,
+                column,
+                message,
+            } => write!(f, "{}:{}: {}", line, column, message),
+            Error::UnknownComponent(name) => write!(f, "unknown component `{}`", name),
+            Error::Arity {
+                component,
+                expected,
+                found,
+            } => write!(
+                f,
+                "`{}` takes {} arguments but {} were given",
+                component, expected, found
+            ),
+            Error::UnboundVariable { rule, variable } => write!(
+                f,
+                "variable `{}` is used before it is bound in rule \"{}\"",
+                variable, rule
+            ),
+            Error::Endpoint {
+                relation,
+                entity,
+                component,
+            } => write!(
+                f,
+                "`{}` requires entity {} to have `{}`",
+                relation, entity, component
+            ),
+            Error::Inconsistent { a, b } => {
+                write!(f, "entities {} and {} can't be related consistently", a, b)
+            }
+            Error::Cycle { child, parent } => write!(
+                f,
+                "entity {} can't be parented to its descendant {}",
+                child, parent
+            ),
+            Error::Decode(message) => write!(f, "can't load saved world: {}", message),
+            Error::Unmigratable(names) => {
+                let names: Vec<String> = names.iter().map(|name| format!("`{}`", name)).collect();
+                write!(f, "can't migrate saved components {}", names.join(", "))
+            }
+            Error::Io { path, message } => write!(f, "{}: {}", path, message),
+            Error::Merge(message) => write!(f, "can't merge stores: {}", message),
+            Error::UnknownWorld(name) => write!(f, "no world named `{}`", name),
+            Error::UnknownPrefab(name) => write!(f, "no prefab named `{}`", name),
+            Error::UnknownRule(name) => write!(f, "no rule named \"{}\"", name),
+            Error::Unbuildable { component, values } => {
+                write!(f, "can't build `{}` from {:?}", component, values)
+            }
+            Error::Singleton(component) => {
+                write!(
+                    f,
+                    "another entity already has the `{}` singleton",
+                    component
+                )
+            }
+            Error::Reflect(message) => write!(f, "{}", message),
+            Error::Script(message) => write!(f, "script {}", message),
+            Error::Plugin(message) => write!(f, "plugin {}", message),
+            Error::Dynamic(message) => write!(f, "{}", message),
+            Error::Required {
+                component,
+                requires,
+            } => write!(f, "`{}` requires `{}`", component, requires),
+            Error::Borrowed(component) => write!(f, "the `{}` pool is already borrowed", component),
+            Error::Command(message) => write!(f, "{}", message),
+            Error::Request(message) => write!(f, "bad request: {}", message),
+            Error::Magic(message) => write!(f, "can't evaluate goal: {}", message),
+            Error::Tabling(message) => write!(f, "can't prove goal: {}", message),
+            Error::Fuzzy(message) => write!(f, "bad fuzzy rule: {}", message),
+            Error::Overrides(circle) => write!(f, "rules override each other: {}", circle),
+            Error::View(message) => write!(f, "can't define view: {}", message),
+            Error::Compact(message) => write!(f, "can't compact: {}", message),
+            Error::EntityRange(entity_id) => write!(
+                f,
+                "entity {} is past the highest id a store takes, {}",
+                entity_id, MAX_ENTITY
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,240 @@
+// Typed event queues
+//
+// Events<T> holds the events sent this tick and last tick. Each tick the store calls
+// update_events, dropping the older buffer, so an event lives for two ticks and every
+// reader that runs once a tick sees it exactly once, whichever order they run in.
+// Events are for things that happen (DamageDealt, DoorOpened) rather than marker
+// components that someone has to remember to remove.
+use crate::cell::AtomicRefCell;
+use crate::store::EntityStore;
+use anymap::Map;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub struct Events<T> {
+    previous: Vec<T>,
+    current: Vec<T>,
+    // How many events have ever been sent, readers remember where they got up to in this
+    sent: usize,
+}
+
+impl<T> Default for Events<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Events<T> {
+    pub fn new() -> Self {
+        Events {
+            previous: Vec::new(),
+            current: Vec::new(),
+            sent: 0,
+        }
+    }
+
+    pub fn send(&mut self, event: T) {
+        self.current.push(event);
+        self.sent += 1;
+    }
+
+    pub fn writer(&mut self) -> EventWriter<'_, T> {
+        EventWriter { events: self }
+    }
+
+    // A reader that will see every event sent from now on
+    pub fn reader(&self) -> EventReader<T> {
+        EventReader {
+            read: self.sent,
+            marker: PhantomData,
+        }
+    }
+
+    // A reader that will also see the events still buffered
+    pub fn reader_from_oldest(&self) -> EventReader<T> {
+        EventReader {
+            read: self.oldest(),
+            marker: PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.previous.len() + self.current.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Swap buffers, events sent before the previous update are dropped
+    pub fn update(&mut self) {
+        std::mem::swap(&mut self.previous, &mut self.current);
+        self.current.clear();
+    }
+
+    pub fn clear(&mut self) {
+        self.previous.clear();
+        self.current.clear();
+    }
+
+    // Number of the oldest event still buffered
+    fn oldest(&self) -> usize {
+        self.sent - self.len()
+    }
+
+    fn since(&self, read: usize) -> impl Iterator<Item = &T> {
+        let skip = read.saturating_sub(self.oldest());
+        self.previous.iter().chain(self.current.iter()).skip(skip)
+    }
+}
+
+pub struct EventWriter<'a, T> {
+    events: &'a mut Events<T>,
+}
+
+impl<T> EventWriter<'_, T> {
+    pub fn send(&mut self, event: T) {
+        self.events.send(event);
+    }
+
+    pub fn send_batch(&mut self, events: impl IntoIterator<Item = T>) {
+        for event in events {
+            self.events.send(event);
+        }
+    }
+}
+
+// Remembers how far it has read, so each event is returned once
+// Events that were dropped before the reader got to them are skipped
+pub struct EventReader<T> {
+    read: usize,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T> fmt::Debug for EventReader<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EventReader({})", self.read)
+    }
+}
+
+impl<T> EventReader<T> {
+    pub fn read<'a>(&mut self, events: &'a Events<T>) -> impl Iterator<Item = &'a T> {
+        let unread = events.since(self.read);
+        self.read = events.sent;
+        unread
+    }
+
+    // How many events read would return
+    pub fn len(&self, events: &Events<T>) -> usize {
+        events.since(self.read).count()
+    }
+
+    pub fn is_empty(&self, events: &Events<T>) -> bool {
+        self.len(events) == 0
+    }
+}
+
+// Type erased so the store can update every queue at once
+pub(crate) trait EventQueue: Send + Sync {
+    fn update(&mut self);
+}
+
+impl<T: Send + Sync> EventQueue for Events<T> {
+    fn update(&mut self) {
+        Events::update(self)
+    }
+}
+
+pub(crate) struct EventQueues {
+    // Stores Arc<AtomicRefCell<Events<T>>>, like the pools
+    by_type: Map<dyn anymap::any::Any + Send + Sync>,
+    all: Vec<Arc<AtomicRefCell<dyn EventQueue>>>,
+}
+
+impl Default for EventQueues {
+    fn default() -> Self {
+        EventQueues {
+            by_type: Map::new(),
+            all: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Debug for EventQueues {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EventQueues({})", self.all.len())
+    }
+}
+
+impl EntityStore {
+    // Define a new event type, does nothing if it already exists
+    pub fn new_events<T: Send + Sync + 'static>(&mut self) {
+        if self
+            .queues
+            .by_type
+            .contains::<Arc<AtomicRefCell<Events<T>>>>()
+        {
+            return;
+        }
+        let queue = Arc::new(AtomicRefCell::new(Events::<T>::new()));
+        self.queues.by_type.insert(queue.clone());
+        self.queues.all.push(queue);
+    }
+
+    pub fn events<T: Send + Sync + 'static>(&self) -> Option<&Arc<AtomicRefCell<Events<T>>>> {
+        self.queues.by_type.get::<Arc<AtomicRefCell<Events<T>>>>()
+    }
+
+    // Sends an event, defining the event type if needed
+    pub fn send_event<T: Send + Sync + 'static>(&mut self, event: T) {
+        self.new_events::<T>();
+        if let Some(queue) = self.events::<T>() {
+            queue.borrow_mut().send(event);
+        }
+    }
+
+    // Call once a tick, see Events::update
+    pub fn update_events(&mut self) {
+        for queue in &self.queues.all {
+            queue.borrow_mut().update();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct DamageDealt(u32);
+
+    #[test]
+    fn events_live_for_two_ticks() {
+        let mut store = EntityStore::new();
+        store.new_events::<DamageDealt>();
+        let queue = store.events::<DamageDealt>().unwrap().clone();
+        let mut early = queue.borrow().reader();
+
+        store.send_event(DamageDealt(1));
+        let mut late = queue.borrow().reader_from_oldest();
+        queue
+            .borrow_mut()
+            .writer()
+            .send_batch([DamageDealt(2), DamageDealt(3)]);
+
+        let read: Vec<_> = early.read(&queue.borrow()).cloned().collect();
+        assert_eq!(read, [DamageDealt(1), DamageDealt(2), DamageDealt(3)]);
+        assert!(early.is_empty(&queue.borrow()));
+
+        store.update_events();
+        store.send_event(DamageDealt(4));
+        assert_eq!(late.len(&queue.borrow()), 4);
+        store.update_events();
+        store.update_events();
+        assert_eq!(queue.borrow().len(), 0);
+        assert_eq!(late.read(&queue.borrow()).count(), 0);
+        assert_eq!(early.read(&queue.borrow()).count(), 0);
+    }
+}
@@ -0,0 +1,365 @@
+// A C interface for embedding
+//
+// With the ffi feature the crate exports a small extern "C" surface, declared for C
+// and C++ in include/rete.h, so game engines and services in other languages can run
+// rules over a world. A RetWorld is an engine and a store together, made with
+// rete_world_new and released with rete_world_free. Components come from rule files'
+// fact declarations or rete_register, both dynamic, see dynamic.rs, since C has no Rust
+// types to register. Values cross as RetValue, a tag and one field per kind.
+//
+// Calls that can fail return a negative number, or null, and leave a message for
+// rete_last_error until the next call on the same world. Every pointer given must be
+// valid for the call, strings NUL terminated UTF-8, and a world used by one thread at a
+// time. Strings handed to query callbacks live only until the callback returns. A panic
+// is caught before it reaches the caller, failing the call with the panic's message.
+// Entity ids past MAX_ENTITY, see store.rs, or negative in a RetValue, fail the call.
+#![allow(clippy::missing_safety_doc)]
+use crate::engine::RuleEngine;
+use crate::error::Error;
+use crate::store::{check_entity, EntityId, EntityStore};
+use crate::value::Value;
+use std::any::Any;
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+
+pub struct RetWorld {
+    engine: RuleEngine,
+    store: EntityStore,
+    error: Option<CString>,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetKind {
+    Int,
+    Float,
+    Bool,
+    Str,
+    Entity,
+}
+
+// Only the field for the kind is read, entity ids go in int
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct RetValue {
+    pub kind: RetKind,
+    pub int: i64,
+    pub float: f64,
+    pub string: *const c_char,
+}
+
+pub type RetRow = extern "C" fn(
+    user: *mut c_void,
+    names: *const *const c_char,
+    values: *const RetValue,
+    count: usize,
+);
+
+unsafe fn text<'a>(ptr: *const c_char) -> Result<&'a str, Error> {
+    if ptr.is_null() {
+        return Err(Error::Decode("null string".to_string()));
+    }
+    CStr::from_ptr(ptr)
+        .to_str()
+        .map_err(|_| Error::Decode("string isn't UTF-8".to_string()))
+}
+
+unsafe fn value(value: &RetValue) -> Result<Value, Error> {
+    Ok(match value.kind {
+        RetKind::Int => Value::Int(value.int),
+        RetKind::Float => Value::Float(value.float),
+        RetKind::Bool => Value::Bool(value.int != 0),
+        RetKind::Str => Value::Str(text(value.string)?.to_string()),
+        RetKind::Entity => match u64::try_from(value.int) {
+            Ok(id) => Value::Entity(entity_id(id)?),
+            Err(_) => return Err(Error::Decode(format!("entity {} is negative", value.int))),
+        },
+    })
+}
+
+fn entity_id(entity_id: u64) -> Result<EntityId, Error> {
+    check_entity(EntityId::try_from(entity_id).unwrap_or(EntityId::MAX))
+}
+
+// Unwinding into C is undefined, so every export runs inside this
+fn guard<T>(failed: T, f: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(failed)
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    let message = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic");
+    format!("panicked: {}", message)
+}
+
+// Runs the call, keeping its error for rete_last_error
+unsafe fn call<T>(
+    world: *mut RetWorld,
+    failed: T,
+    f: impl FnOnce(&mut RetWorld) -> Result<T, Error>,
+) -> T {
+    let Some(world) = world.as_mut() else {
+        return failed;
+    };
+    world.error = None;
+    let message = match panic::catch_unwind(AssertUnwindSafe(|| f(world))) {
+        Ok(Ok(result)) => return result,
+        Ok(Err(error)) => error.to_string(),
+        Err(payload) => panic_message(payload.as_ref()),
+    };
+    world.error = CString::new(message.replace('\0', " ")).ok();
+    failed
+}
+
+#[no_mangle]
+pub extern "C" fn rete_world_new() -> *mut RetWorld {
+    guard(std::ptr::null_mut(), || {
+        Box::into_raw(Box::new(RetWorld {
+            engine: RuleEngine::new(),
+            store: EntityStore::new(),
+            error: None,
+        }))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rete_world_free(world: *mut RetWorld) {
+    if !world.is_null() {
+        guard((), || drop(Box::from_raw(world)));
+    }
+}
+
+// The last call's error, null if it succeeded
+#[no_mangle]
+pub unsafe extern "C" fn rete_last_error(world: *const RetWorld) -> *const c_char {
+    guard(std::ptr::null(), || {
+        match world.as_ref().and_then(|world| world.error.as_ref()) {
+            Some(error) => error.as_ptr(),
+            None => std::ptr::null(),
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rete_register(
+    world: *mut RetWorld,
+    name: *const c_char,
+    fields: *const *const c_char,
+    count: usize,
+) -> i32 {
+    call(world, -1, |world| {
+        let fields = (0..count)
+            .map(|i| Ok(text(*fields.add(i))?.to_string()))
+            .collect::<Result<Vec<_>, Error>>()?;
+        world.engine.register_dynamic(text(name)?, &fields)?;
+        Ok(0)
+    })
+}
+
+// Adds every rule and fact declaration in the source
+#[no_mangle]
+pub unsafe extern "C" fn rete_load_rules(world: *mut RetWorld, source: *const c_char) -> i32 {
+    call(world, -1, |world| {
+        world.engine.load_str(text(source)?)?;
+        Ok(0)
+    })
+}
+
+// A new entity with nothing on it
+#[no_mangle]
+pub unsafe extern "C" fn rete_spawn(world: *mut RetWorld) -> i64 {
+    call(world, -1, |world| Ok(world.store.spawn() as i64))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rete_assert(
+    world: *mut RetWorld,
+    component: *const c_char,
+    entity: u64,
+    values: *const RetValue,
+    count: usize,
+) -> i32 {
+    call(world, -1, |world| {
+        let values = (0..count)
+            .map(|i| value(&*values.add(i)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        let entity_id = entity_id(entity)?;
+        let registry = world.engine.registry();
+        world
+            .store
+            .insert_fact(registry, text(component)?, entity_id, &values)?;
+        Ok(0)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rete_retract(
+    world: *mut RetWorld,
+    component: *const c_char,
+    entity: u64,
+) -> i32 {
+    call(world, -1, |world| {
+        let component = text(component)?;
+        let info = world
+            .engine
+            .registry()
+            .get(component)
+            .ok_or_else(|| Error::UnknownComponent(component.to_string()))?;
+        info.remove(&mut world.store, entity_id(entity)?);
+        Ok(0)
+    })
+}
+
+// Runs the rules to quiescence, giving how many fired
+#[no_mangle]
+pub unsafe extern "C" fn rete_run(world: *mut RetWorld) -> i64 {
+    call(world, -1, |world| {
+        Ok(world.engine.run(&mut world.store) as i64)
+    })
+}
+
+// Calls row once for each match of the conditions, with the variables sorted by name,
+// giving how many there were
+#[no_mangle]
+pub unsafe extern "C" fn rete_query(
+    world: *mut RetWorld,
+    conditions: *const c_char,
+    row: RetRow,
+    user: *mut c_void,
+) -> i64 {
+    call(world, -1, |world| {
+        let matches = world.engine.query(text(conditions)?, &world.store)?;
+        for bindings in &matches {
+            let names: Vec<CString> = bindings
+                .keys()
+                .map(|name| CString::new(name.as_str()).unwrap_or_default())
+                .collect();
+            let strings: Vec<Option<CString>> = bindings
+                .values()
+                .map(|value| match value {
+                    Value::Str(s) => CString::new(s.replace('\0', " ")).ok(),
+                    _ => None,
+                })
+                .collect();
+            let values: Vec<RetValue> = bindings
+                .values()
+                .zip(&strings)
+                .map(|(value, string)| {
+                    let (kind, int, float) = match *value {
+                        Value::Int(i) => (RetKind::Int, i, 0.0),
+                        Value::Float(f) => (RetKind::Float, 0, f),
+                        Value::Bool(b) => (RetKind::Bool, b as i64, 0.0),
+                        Value::Str(_) => (RetKind::Str, 0, 0.0),
+                        Value::Entity(e) => (RetKind::Entity, e as i64, 0.0),
+                    };
+                    let string = string.as_ref().map_or(std::ptr::null(), |s| s.as_ptr());
+                    RetValue {
+                        kind,
+                        int,
+                        float,
+                        string,
+                    }
+                })
+                .collect();
+            let name_ptrs: Vec<*const c_char> = names.iter().map(|name| name.as_ptr()).collect();
+            row(user, name_ptrs.as_ptr(), values.as_ptr(), values.len());
+        }
+        Ok(matches.len() as i64)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern "C" fn collect(
+        user: *mut c_void,
+        names: *const *const c_char,
+        values: *const RetValue,
+        count: usize,
+    ) {
+        let rows = unsafe { &mut *(user as *mut Vec<(String, i64)>) };
+        for i in 0..count {
+            let (name, value) = unsafe { (CStr::from_ptr(*names.add(i)), *values.add(i)) };
+            rows.push((name.to_str().unwrap().to_string(), value.int));
+        }
+    }
+
+    #[test]
+    fn worlds_run_through_the_c_interface() {
+        let rules = CString::new(
+            r#"fact Heat(level)
+               rule "alarm" when Heat(e, h), h > 90 then insert Alarm(e)"#,
+        )
+        .unwrap();
+        let alarm = CString::new("Alarm").unwrap();
+        let heat = CString::new("Heat").unwrap();
+        let query = CString::new("Alarm(e)").unwrap();
+        unsafe {
+            let world = rete_world_new();
+            assert_eq!(rete_load_rules(world, rules.as_ptr()), -1);
+            let error = CStr::from_ptr(rete_last_error(world)).to_str().unwrap();
+            assert!(error.contains("Alarm"), "{}", error);
+            assert_eq!(rete_register(world, alarm.as_ptr(), std::ptr::null(), 0), 0);
+            assert_eq!(rete_load_rules(world, rules.as_ptr()), 0);
+            assert!(rete_last_error(world).is_null());
+
+            for level in [50, 95] {
+                let e = rete_spawn(world) as u64;
+                let value = RetValue {
+                    kind: RetKind::Int,
+                    int: level,
+                    float: 0.0,
+                    string: std::ptr::null(),
+                };
+                assert_eq!(rete_assert(world, heat.as_ptr(), e, &value, 1), 0);
+            }
+            assert_eq!(rete_run(world), 1);
+            let mut rows: Vec<(String, i64)> = Vec::new();
+            let user = &mut rows as *mut _ as *mut c_void;
+            assert_eq!(rete_query(world, query.as_ptr(), collect, user), 1);
+            assert_eq!(rows, [("e".to_string(), 1)]);
+
+            // -1 from C, as an entity and as an entity value
+            let level = RetValue {
+                kind: RetKind::Int,
+                int: 99,
+                float: 0.0,
+                string: std::ptr::null(),
+            };
+            assert_eq!(rete_assert(world, heat.as_ptr(), u64::MAX, &level, 1), -1);
+            let error = CStr::from_ptr(rete_last_error(world)).to_str().unwrap();
+            assert!(error.contains("past the highest id"), "{}", error);
+            assert_eq!(rete_retract(world, heat.as_ptr(), 1 << 50), -1);
+            let negative = RetValue {
+                kind: RetKind::Entity,
+                int: -1,
+                ..level
+            };
+            assert_eq!(rete_assert(world, heat.as_ptr(), 0, &negative, 1), -1);
+            let error = CStr::from_ptr(rete_last_error(world)).to_str().unwrap();
+            assert!(error.contains("negative"), "{}", error);
+            rete_world_free(world);
+        }
+    }
+
+    #[test]
+    fn spawns_are_fresh_and_panics_stay_on_the_rust_side() {
+        unsafe {
+            let world = rete_world_new();
+            let spawned: Vec<i64> = (0..3).map(|_| rete_spawn(world)).collect();
+            assert_eq!(spawned, [0, 1, 2]);
+
+            assert_eq!(
+                call(world, -1, |_| -> Result<i32, Error> { panic!("boom") }),
+                -1
+            );
+            let error = CStr::from_ptr(rete_last_error(world)).to_str().unwrap();
+            assert_eq!(error, "panicked: boom");
+            rete_world_free(world);
+        }
+    }
+}
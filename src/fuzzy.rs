@@ -0,0 +1,587 @@
+// Fuzzy rules
+//
+// A FuzzySystem maps numeric fields to numeric fields through rules over linguistic
+// terms, for control style logic where crisp thresholds would jump:
+//
+// if temperature is somewhat high and pressure is not low then throttle is medium
+//
+// Each input variable reads one field of a component and each output variable writes
+// one, and both name terms with membership functions giving how true the term is of a
+// value, from 0 to 1. A condition's degree of truth is its term's membership, after any
+// hedges: `very` squares it, `somewhat` takes its square root and `not` takes it from 1.
+// `and` and `or` combine degrees with the system's t-norm and its dual, and a rule
+// clips each term it concludes to the degree its conditions hold, also with the t-norm.
+// The clipped terms for an output are merged by taking the greatest at each point, and
+// defuzzified to one value by sampling its range.
+//
+// Systems added to an engine run at the start of every run, before any rule is matched,
+// for every entity with at least one input. An output is written only when some rule
+// concluding it held at all, and an Int field gets the value rounded. Rules reading an
+// input the entity lacks are skipped.
+use crate::engine::RuleEngine;
+use crate::error::Error;
+use crate::registry::Registry;
+use crate::store::{EntityId, EntityStore};
+use crate::value::Value;
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Membership {
+    // Rising from the first point to 1 at the second, falling to 0 at the third
+    Triangle(f64, f64, f64),
+    // As a triangle with a flat top between the middle two points
+    Trapezoid(f64, f64, f64, f64),
+    // A bell around the mean, with the spread given
+    Gaussian(f64, f64),
+}
+
+impl Membership {
+    pub fn degree(&self, x: f64) -> f64 {
+        // A side with no width is a step, so shoulders can be written as trapezoids
+        let rising = |a: f64, b: f64| match b > a {
+            true => ((x - a) / (b - a)).clamp(0.0, 1.0),
+            false => (x >= a) as u8 as f64,
+        };
+        let falling = |c: f64, d: f64| match d > c {
+            true => ((d - x) / (d - c)).clamp(0.0, 1.0),
+            false => (x <= d) as u8 as f64,
+        };
+        match *self {
+            Membership::Triangle(a, b, c) => rising(a, b).min(falling(b, c)),
+            Membership::Trapezoid(a, b, c, d) => rising(a, b).min(falling(c, d)),
+            Membership::Gaussian(mean, spread) => {
+                (-((x - mean) * (x - mean)) / (2.0 * spread * spread)).exp()
+            }
+        }
+    }
+}
+
+// How `and` combines degrees, with `or` using the matching t-conorm
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TNorm {
+    // The lesser, or for `or` the greater
+    #[default]
+    Minimum,
+    // a * b, or a + b - a * b
+    Product,
+    // a + b - 1 floored at 0, or a + b capped at 1
+    Lukasiewicz,
+}
+
+impl TNorm {
+    pub fn and(self, a: f64, b: f64) -> f64 {
+        match self {
+            TNorm::Minimum => a.min(b),
+            TNorm::Product => a * b,
+            TNorm::Lukasiewicz => (a + b - 1.0).max(0.0),
+        }
+    }
+
+    pub fn or(self, a: f64, b: f64) -> f64 {
+        match self {
+            TNorm::Minimum => a.max(b),
+            TNorm::Product => a + b - a * b,
+            TNorm::Lukasiewicz => (a + b).min(1.0),
+        }
+    }
+}
+
+// How an output's merged terms become one value
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Defuzzify {
+    // The centre of the area under them
+    #[default]
+    Centroid,
+    // The point splitting that area in half
+    Bisector,
+    // The middle of the points where they're highest
+    MeanOfMaximum,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variable {
+    pub component: String,
+    pub field: String,
+    // Where outputs are sampled when defuzzifying
+    pub range: (f64, f64),
+    pub terms: Vec<(String, Membership)>,
+}
+
+impl Variable {
+    fn term(&self, name: &str) -> Option<&Membership> {
+        self.terms
+            .iter()
+            .find(|(term, _)| term == name)
+            .map(|(_, membership)| membership)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Hedge {
+    Very,
+    Somewhat,
+    Not,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Antecedent {
+    Is {
+        variable: String,
+        hedges: Vec<Hedge>,
+        term: String,
+    },
+    And(Box<Antecedent>, Box<Antecedent>),
+    Or(Box<Antecedent>, Box<Antecedent>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct FuzzyRule {
+    antecedent: Antecedent,
+    // Each output variable and term concluded
+    consequents: Vec<(String, String)>,
+}
+
+// Input variables, output variables and the rules between them, see above
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuzzySystem {
+    inputs: BTreeMap<String, Variable>,
+    outputs: BTreeMap<String, Variable>,
+    rules: Vec<FuzzyRule>,
+    pub tnorm: TNorm,
+    pub defuzzify: Defuzzify,
+    // Points an output's range is sampled at
+    pub resolution: usize,
+}
+
+impl Default for FuzzySystem {
+    fn default() -> Self {
+        Self {
+            inputs: BTreeMap::new(),
+            outputs: BTreeMap::new(),
+            rules: Vec::new(),
+            tnorm: TNorm::default(),
+            defuzzify: Defuzzify::default(),
+            resolution: 201,
+        }
+    }
+}
+
+fn variable(
+    component: &str,
+    field: &str,
+    range: (f64, f64),
+    terms: impl IntoIterator<Item = (&'static str, Membership)>,
+) -> Variable {
+    Variable {
+        component: component.to_string(),
+        field: field.to_string(),
+        range,
+        terms: terms
+            .into_iter()
+            .map(|(name, membership)| (name.to_string(), membership))
+            .collect(),
+    }
+}
+
+impl FuzzySystem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // An input read from the component's field, range is unused for inputs
+    pub fn input(
+        mut self,
+        name: &str,
+        component: &str,
+        field: &str,
+        terms: impl IntoIterator<Item = (&'static str, Membership)>,
+    ) -> Self {
+        let input = variable(component, field, (0.0, 0.0), terms);
+        self.inputs.insert(name.to_string(), input);
+        self
+    }
+
+    pub fn output(
+        mut self,
+        name: &str,
+        component: &str,
+        field: &str,
+        range: (f64, f64),
+        terms: impl IntoIterator<Item = (&'static str, Membership)>,
+    ) -> Self {
+        let output = variable(component, field, range, terms);
+        self.outputs.insert(name.to_string(), output);
+        self
+    }
+
+    // Adds a rule written as above, over variables and terms already added
+    pub fn rule(mut self, source: &str) -> Result<Self, Error> {
+        let rule = Parser::new(source, &self).rule()?;
+        self.rules.push(rule);
+        Ok(self)
+    }
+
+    pub fn tnorm(mut self, tnorm: TNorm) -> Self {
+        self.tnorm = tnorm;
+        self
+    }
+
+    pub fn defuzzify(mut self, defuzzify: Defuzzify) -> Self {
+        self.defuzzify = defuzzify;
+        self
+    }
+
+    fn degree(&self, antecedent: &Antecedent, inputs: &HashMap<&str, f64>) -> Option<f64> {
+        match antecedent {
+            Antecedent::Is {
+                variable,
+                hedges,
+                term,
+            } => {
+                let x = *inputs.get(variable.as_str())?;
+                let membership = self.inputs[variable].term(term)?;
+                let degree = hedges
+                    .iter()
+                    .rev()
+                    .fold(membership.degree(x), |degree, hedge| match hedge {
+                        Hedge::Very => degree * degree,
+                        Hedge::Somewhat => degree.sqrt(),
+                        Hedge::Not => 1.0 - degree,
+                    });
+                Some(degree)
+            }
+            Antecedent::And(a, b) => Some(
+                self.tnorm
+                    .and(self.degree(a, inputs)?, self.degree(b, inputs)?),
+            ),
+            Antecedent::Or(a, b) => Some(
+                self.tnorm
+                    .or(self.degree(a, inputs)?, self.degree(b, inputs)?),
+            ),
+        }
+    }
+
+    // The crisp value of each output some rule concluded, given the inputs by name
+    pub fn evaluate(&self, inputs: &HashMap<&str, f64>) -> BTreeMap<String, f64> {
+        let mut concluded: HashMap<&str, Vec<(&Membership, f64)>> = HashMap::new();
+        for rule in &self.rules {
+            let Some(degree) = self.degree(&rule.antecedent, inputs) else {
+                continue;
+            };
+            if degree <= 0.0 {
+                continue;
+            }
+            for (output, term) in &rule.consequents {
+                let membership = self.outputs[output].term(term).expect("checked on parse");
+                concluded
+                    .entry(output.as_str())
+                    .or_default()
+                    .push((membership, degree));
+            }
+        }
+        concluded
+            .into_iter()
+            .filter_map(|(output, clipped)| {
+                let value = self.crisp(&self.outputs[output], &clipped)?;
+                Some((output.to_string(), value))
+            })
+            .collect()
+    }
+
+    fn crisp(&self, output: &Variable, clipped: &[(&Membership, f64)]) -> Option<f64> {
+        let (low, high) = output.range;
+        let samples = self.resolution.max(2);
+        let step = (high - low) / (samples - 1) as f64;
+        let points: Vec<(f64, f64)> = (0..samples)
+            .map(|i| {
+                let x = low + step * i as f64;
+                let height = clipped
+                    .iter()
+                    .map(|(membership, degree)| self.tnorm.and(*degree, membership.degree(x)))
+                    .fold(0.0, f64::max);
+                (x, height)
+            })
+            .collect();
+        let area: f64 = points.iter().map(|(_, height)| height).sum();
+        if area <= 0.0 {
+            return None;
+        }
+        match self.defuzzify {
+            Defuzzify::Centroid => {
+                Some(points.iter().map(|(x, height)| x * height).sum::<f64>() / area)
+            }
+            Defuzzify::Bisector => {
+                let mut below = 0.0;
+                points
+                    .iter()
+                    .find(|(_, height)| {
+                        below += height;
+                        below >= area / 2.0
+                    })
+                    .map(|(x, _)| *x)
+            }
+            Defuzzify::MeanOfMaximum => {
+                let highest = points.iter().map(|(_, height)| *height).fold(0.0, f64::max);
+                let top: Vec<f64> = points
+                    .iter()
+                    .filter(|(_, height)| highest - height < 1e-9)
+                    .map(|(x, _)| *x)
+                    .collect();
+                Some(top.iter().sum::<f64>() / top.len() as f64)
+            }
+        }
+    }
+
+    // Evaluates the system for every entity with an input, see above
+    // Returns how many outputs were written
+    pub(crate) fn apply(&self, registry: &Registry, store: &mut EntityStore) -> usize {
+        let mut inputs: BTreeMap<EntityId, HashMap<&str, f64>> = BTreeMap::new();
+        for (name, input) in &self.inputs {
+            let Some(info) = registry.get(&input.component) else {
+                continue;
+            };
+            let Some(field) = info.fields.iter().position(|field| *field == input.field) else {
+                continue;
+            };
+            for (entity_id, fields) in info.facts(store) {
+                if let Some(x) = fields.get(field).and_then(Value::as_float) {
+                    inputs.entry(entity_id).or_default().insert(name, x);
+                }
+            }
+        }
+
+        let mut written = 0;
+        for (entity_id, inputs) in inputs {
+            for (name, value) in self.evaluate(&inputs) {
+                let output = &self.outputs[&name];
+                let Some(info) = registry.get(&output.component) else {
+                    continue;
+                };
+                let Some(field) = info.fields.iter().position(|field| *field == output.field)
+                else {
+                    continue;
+                };
+                let mut values = match info.get(store, entity_id) {
+                    Some(values) => values,
+                    None if info.fields.len() == 1 => vec![Value::Float(value)],
+                    None => continue,
+                };
+                values[field] = match values[field] {
+                    Value::Int(_) => Value::Int(value.round() as i64),
+                    _ => Value::Float(value),
+                };
+                if info.insert(store, entity_id, &values) {
+                    written += 1;
+                }
+            }
+        }
+        written
+    }
+}
+
+struct Parser<'a> {
+    words: Vec<String>,
+    position: usize,
+    system: &'a FuzzySystem,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &str, system: &'a FuzzySystem) -> Self {
+        let words = source
+            .replace('(', " ( ")
+            .replace(')', " ) ")
+            .replace(',', " and ")
+            .split_whitespace()
+            .map(str::to_lowercase)
+            .collect();
+        Self {
+            words,
+            position: 0,
+            system,
+        }
+    }
+
+    fn error(&self, expected: &str) -> Error {
+        let found = match self.words.get(self.position) {
+            Some(word) => format!("`{}`", word),
+            None => "the end".to_string(),
+        };
+        Error::Fuzzy(format!("expected {}, found {}", expected, found))
+    }
+
+    fn next(&mut self) -> Option<String> {
+        let word = self.words.get(self.position)?.clone();
+        self.position += 1;
+        Some(word)
+    }
+
+    fn eat(&mut self, word: &str) -> bool {
+        let found = self
+            .words
+            .get(self.position)
+            .is_some_and(|next| next == word);
+        self.position += found as usize;
+        found
+    }
+
+    fn expect(&mut self, word: &str) -> Result<(), Error> {
+        match self.eat(word) {
+            true => Ok(()),
+            false => Err(self.error(&format!("`{}`", word))),
+        }
+    }
+
+    fn rule(&mut self) -> Result<FuzzyRule, Error> {
+        self.expect("if")?;
+        let antecedent = self.or()?;
+        self.expect("then")?;
+        let mut consequents = Vec::new();
+        loop {
+            let (output, term) = self.is()?;
+            let variable = self.system.outputs.get(&output);
+            if variable.and_then(|variable| variable.term(&term)).is_none() {
+                return Err(Error::Fuzzy(format!(
+                    "no output term `{} is {}`",
+                    output, term
+                )));
+            }
+            consequents.push((output, term));
+            if !self.eat("and") {
+                break;
+            }
+        }
+        match self.position < self.words.len() {
+            true => Err(self.error("the end of the rule")),
+            false => Ok(FuzzyRule {
+                antecedent,
+                consequents,
+            }),
+        }
+    }
+
+    fn or(&mut self) -> Result<Antecedent, Error> {
+        let mut antecedent = self.and()?;
+        while self.eat("or") {
+            antecedent = Antecedent::Or(Box::new(antecedent), Box::new(self.and()?));
+        }
+        Ok(antecedent)
+    }
+
+    fn and(&mut self) -> Result<Antecedent, Error> {
+        let mut antecedent = self.condition()?;
+        while self.eat("and") {
+            antecedent = Antecedent::And(Box::new(antecedent), Box::new(self.condition()?));
+        }
+        Ok(antecedent)
+    }
+
+    fn condition(&mut self) -> Result<Antecedent, Error> {
+        if self.eat("(") {
+            let antecedent = self.or()?;
+            self.expect(")")?;
+            return Ok(antecedent);
+        }
+        let variable = self.next().ok_or_else(|| self.error("an input"))?;
+        self.expect("is")?;
+        let mut hedges = Vec::new();
+        loop {
+            let hedge = match self.words.get(self.position).map(String::as_str) {
+                Some("very") => Hedge::Very,
+                Some("somewhat") => Hedge::Somewhat,
+                Some("not") => Hedge::Not,
+                _ => break,
+            };
+            hedges.push(hedge);
+            self.position += 1;
+        }
+        let term = self.next().ok_or_else(|| self.error("a term"))?;
+        let input = self.system.inputs.get(&variable);
+        if input.and_then(|input| input.term(&term)).is_none() {
+            return Err(Error::Fuzzy(format!(
+                "no input term `{} is {}`",
+                variable, term
+            )));
+        }
+        Ok(Antecedent::Is {
+            variable,
+            hedges,
+            term,
+        })
+    }
+
+    // `variable is term` in a conclusion
+    fn is(&mut self) -> Result<(String, String), Error> {
+        let variable = self.next().ok_or_else(|| self.error("an output"))?;
+        self.expect("is")?;
+        let term = self.next().ok_or_else(|| self.error("a term"))?;
+        Ok((variable, term))
+    }
+}
+
+impl RuleEngine {
+    // Runs the system at the start of every run, see above
+    pub fn add_fuzzy(&mut self, system: FuzzySystem) {
+        self.fuzzy.push(system);
+    }
+
+    pub(crate) fn infer_fuzzy(&self, store: &mut EntityStore) {
+        for system in &self.fuzzy {
+            system.apply(self.registry(), store);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fuzzy_rules_blend_outputs() {
+        let levels = || {
+            [
+                ("low", Membership::Trapezoid(0.0, 0.0, 20.0, 50.0)),
+                ("medium", Membership::Triangle(25.0, 50.0, 75.0)),
+                ("high", Membership::Trapezoid(50.0, 80.0, 100.0, 100.0)),
+            ]
+        };
+        let system = FuzzySystem::new()
+            .input("temperature", "Temperature", "celsius", levels())
+            .output("throttle", "Throttle", "level", (0.0, 100.0), levels())
+            .rule("if temperature is low then throttle is low")
+            .and_then(|system| {
+                system.rule("if temperature is somewhat medium then throttle is medium")
+            })
+            .and_then(|system| system.rule("if temperature is very high then throttle is high"))
+            .unwrap();
+        assert!(matches!(
+            system
+                .clone()
+                .rule("if temperature is scorching then throttle is high"),
+            Err(Error::Fuzzy(_))
+        ));
+
+        // Only medium holds at 50, and its triangle is centred there
+        let at = |celsius| system.evaluate(&HashMap::from([("temperature", celsius)]));
+        assert!((at(50.0)["throttle"] - 50.0).abs() < 1e-6);
+        // At 70 medium holds to 0.2, somewhat to 0.45, and high to 2/3, very to 0.44
+        let warm = at(70.0)["throttle"];
+        assert!(warm > 55.0 && warm < 80.0);
+        let mean = system.clone().defuzzify(Defuzzify::MeanOfMaximum);
+        let warm = mean.evaluate(&HashMap::from([("temperature", 70.0)]))["throttle"];
+        assert!(warm > 45.0 && warm < 55.0);
+
+        let mut engine = RuleEngine::new();
+        engine
+            .load_str("fact Temperature(celsius)\nfact Throttle(level)")
+            .unwrap();
+        engine.add_fuzzy(system);
+        let registry = engine.registry().clone();
+        let mut store = EntityStore::new();
+        store
+            .insert_fact(&registry, "Temperature", 1, &[Value::Float(50.0)])
+            .unwrap();
+        engine.run(&mut store);
+        let throttle = registry.get("Throttle").unwrap().get(&store, 1).unwrap();
+        assert!((throttle[0].as_float().unwrap() - 50.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,219 @@
+// Owning groups, after EnTT
+//
+// A group owns the pools of a set of component types. Entities with every one of them
+// are kept at the front of each owned pool, in the same order in all of them, so
+// iterating the group is a zip over the front of the packed arrays with no sparse
+// lookups. add_component and remove_component keep it sorted with a couple of swaps
+// per pool. A pool can only be owned by one group, and like hooks, changes made
+// directly through a pool go unseen and leave the group stale.
+use crate::cell::{AtomicRef, AtomicRefCell};
+use crate::store::{Component, EntityId, EntityStore, Pool, PoolRef};
+use std::any::TypeId;
+use std::fmt;
+use std::sync::Arc;
+
+pub(crate) struct Group {
+    types: Vec<TypeId>,
+    pools: Vec<Arc<AtomicRefCell<dyn PoolRef>>>,
+    // How many entities at the front of each pool are in the group
+    len: usize,
+}
+
+impl fmt::Debug for Group {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Group")
+            .field("types", &self.types.len())
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+impl Group {
+    fn has_all(&self, entity_id: EntityId) -> bool {
+        self.pools
+            .iter()
+            .all(|pool| pool.borrow().index_of(entity_id).is_some())
+    }
+
+    fn contains(&self, entity_id: EntityId) -> bool {
+        let index = self.pools[0].borrow().index_of(entity_id);
+        index.is_some_and(|index| index < self.len)
+    }
+
+    // Moves the entity into the group, if it now has every type and isn't in yet
+    fn join(&mut self, entity_id: EntityId) {
+        if self.contains(entity_id) || !self.has_all(entity_id) {
+            return;
+        }
+        for pool in &self.pools {
+            let mut pool = pool.borrow_mut();
+            let index = pool.index_of(entity_id).unwrap();
+            pool.swap_packed(index, self.len);
+        }
+        self.len += 1;
+    }
+
+    // Moves the entity to just past the end of the group, if it is in it
+    fn leave(&mut self, entity_id: EntityId) {
+        if !self.contains(entity_id) {
+            return;
+        }
+        self.len -= 1;
+        for pool in &self.pools {
+            let mut pool = pool.borrow_mut();
+            let index = pool.index_of(entity_id).unwrap();
+            pool.swap_packed(index, self.len);
+        }
+    }
+}
+
+// The members of a group seen through two of its types
+pub struct GroupRef<'a, A: Component + Eq, B: Component + Eq> {
+    a: AtomicRef<'a, Pool<A>>,
+    b: AtomicRef<'a, Pool<B>>,
+    len: usize,
+}
+
+impl<A: Component + Eq, B: Component + Eq> GroupRef<'_, A, B> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (EntityId, &A, &B)> {
+        self.a
+            .components_iter()
+            .zip(self.b.components_iter())
+            .take(self.len)
+            .map(|((&entity_id, a), (_, b))| (entity_id, a, b))
+    }
+}
+
+impl EntityStore {
+    // Groups the pools of the given types, sorting the entities already in them
+    // Returns false, grouping nothing, if a type has no pool or is already in a group
+    pub fn add_group(&mut self, types: &[TypeId]) -> bool {
+        let owned = |type_id: &TypeId| {
+            self.groups
+                .iter()
+                .any(|group| group.types.contains(type_id))
+        };
+        if types.is_empty() || types.iter().any(owned) {
+            return false;
+        }
+        let Some(pools) = types
+            .iter()
+            .map(|&type_id| {
+                self.pool_refs
+                    .0
+                    .iter()
+                    .find(|pool| pool.borrow().component_type() == type_id)
+                    .cloned()
+            })
+            .collect::<Option<Vec<_>>>()
+        else {
+            return false;
+        };
+        let mut group = Group {
+            types: types.to_vec(),
+            pools,
+            len: 0,
+        };
+        for entity_id in self.entities_with(types) {
+            group.join(entity_id);
+        }
+        self.groups.push(group);
+        true
+    }
+
+    // The group holding both A and B, if there is one
+    pub fn group<A: Component + Eq + 'static, B: Component + Eq + 'static>(
+        &self,
+    ) -> Option<GroupRef<'_, A, B>> {
+        let (a, b) = (TypeId::of::<A>(), TypeId::of::<B>());
+        let group = self
+            .groups
+            .iter()
+            .find(|group| group.types.contains(&a) && group.types.contains(&b))?;
+        Some(GroupRef {
+            a: self.get::<A>()?.borrow(),
+            b: self.get::<B>()?.borrow(),
+            len: group.len,
+        })
+    }
+
+    // Called once the entity has gained a component of the type
+    pub(crate) fn grouped(&self, type_id: TypeId) -> bool {
+        self.groups
+            .iter()
+            .any(|group| group.types.contains(&type_id))
+    }
+
+    pub(crate) fn join_groups(&mut self, type_id: TypeId, entity_id: EntityId) {
+        for group in &mut self.groups {
+            if group.types.contains(&type_id) {
+                group.join(entity_id);
+            }
+        }
+    }
+
+    // Called before the entity loses a component of the type, or every one if None
+    pub(crate) fn leave_groups(&mut self, type_id: Option<TypeId>, entity_id: EntityId) {
+        for group in &mut self.groups {
+            if type_id.is_none_or(|type_id| group.types.contains(&type_id)) {
+                group.leave(entity_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Position(i64);
+    impl Component for Position {}
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Velocity(i64);
+    impl Component for Velocity {}
+
+    fn members(store: &EntityStore) -> Vec<(EntityId, i64, i64)> {
+        let group = store.group::<Velocity, Position>().unwrap();
+        let mut members: Vec<_> = group.iter().map(|(e, v, p)| (e, p.0, v.0)).collect();
+        members.sort();
+        members
+    }
+
+    #[test]
+    fn groups_stay_sorted_through_changes() {
+        let mut store = EntityStore::new();
+        store.new_component::<Position>();
+        store.new_component::<Velocity>();
+        for entity_id in 0..6 {
+            store.add_component(entity_id, Position(entity_id as i64));
+        }
+        store.add_component(4, Velocity(40));
+        store.add_component(1, Velocity(10));
+        let types = [TypeId::of::<Position>(), TypeId::of::<Velocity>()];
+        assert!(store.add_group(&types));
+        assert!(!store.add_group(&types[..1]));
+        assert_eq!(members(&store), vec![(1, 1, 10), (4, 4, 40)]);
+
+        store.add_component(5, Velocity(50));
+        store.add_component(4, Velocity(41));
+        store.remove_component::<Position>(1);
+        store.add_component(7, Velocity(70));
+        assert_eq!(members(&store), vec![(4, 4, 41), (5, 5, 50)]);
+
+        store.remove_entity(4);
+        store.add_component(1, Position(100));
+        assert_eq!(members(&store), vec![(1, 100, 10), (5, 5, 50)]);
+        assert_eq!(store.group::<Position, Velocity>().unwrap().len(), 2);
+        assert!(store.group::<Position, Position>().is_some());
+    }
+}
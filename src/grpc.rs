@@ -0,0 +1,378 @@
+// A gRPC service for evaluating facts against a rule set
+//
+// With the grpc feature the engine can be served with tonic to backends in other
+// languages, through the service in proto/rete.proto. LoadRuleSet replaces the rule set
+// and its fact declarations. EvaluateFacts runs the rules over a fresh store holding just
+// the facts in the request, so requests never see each other's facts, and answers what
+// the rules derived, and optionally the rows of a query over the result.
+// StreamDerivations does the same, sending every insert and removal as the rule making
+// it fires. Evaluations run on tokio's blocking threads, the rule set is shared behind
+// a lock and only held long enough to copy it.
+//
+// GrpcService::new starts from an engine's registry and rules, so components registered
+// from Rust can be used alongside those declared in rule sets. A new rule set starts
+// from that registry again, dropping the previous set's declarations. The engine's
+// scripts, plugins and async actions don't come along, so new fails on rules that run
+// one, as loading a rule set running one does. Entity ids past MAX_ENTITY, as a fact's
+// entity or a value, are invalid arguments.
+use crate::engine::RuleEngine;
+use crate::error::Error;
+use crate::registry::Registry;
+use crate::rule::Rule;
+use crate::store::{check_entity, EntityId, EntityStore};
+use crate::trace::{Firing, Mutation};
+use crate::value::Value;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tonic::{Request, Response, Status};
+
+// Generated from proto/rete.proto by build.rs
+#[allow(clippy::all)]
+pub mod proto {
+    tonic::include_proto!("rete");
+}
+
+use proto::rule_service_server::{RuleService, RuleServiceServer};
+use proto::value::Kind;
+use proto::{Derivation, Evaluated, Evaluation, Fact, Row, RuleSet, RuleSetLoaded};
+
+fn to_proto(value: &Value) -> proto::Value {
+    let kind = match value {
+        Value::Int(i) => Kind::Int(*i),
+        Value::Float(f) => Kind::Float(*f),
+        Value::Bool(b) => Kind::Bool(*b),
+        Value::Str(s) => Kind::Str(s.clone()),
+        Value::Entity(e) => Kind::Entity(*e as u64),
+    };
+    proto::Value { kind: Some(kind) }
+}
+
+fn from_proto(value: &proto::Value) -> Result<Value, Error> {
+    Ok(match &value.kind {
+        Some(Kind::Int(i)) => Value::Int(*i),
+        Some(Kind::Float(f)) => Value::Float(*f),
+        Some(Kind::Bool(b)) => Value::Bool(*b),
+        Some(Kind::Str(s)) => Value::Str(s.clone()),
+        Some(Kind::Entity(e)) => Value::Entity(entity(*e)?),
+        None => return Err(Error::Request("a value has no kind".to_string())),
+    })
+}
+
+fn entity(entity_id: u64) -> Result<EntityId, Error> {
+    check_entity(EntityId::try_from(entity_id).unwrap_or(EntityId::MAX))
+}
+
+fn fact(component: &str, entity_id: EntityId, values: &[Value]) -> Fact {
+    Fact {
+        component: component.to_string(),
+        entity: entity_id as u64,
+        values: values.iter().map(to_proto).collect(),
+    }
+}
+
+fn invalid(error: Error) -> Status {
+    Status::invalid_argument(error.to_string())
+}
+
+// The rule set no longer builds an engine, not the request's doing
+fn internal(error: Error) -> Status {
+    Status::internal(error.to_string())
+}
+
+struct Rules {
+    registry: Registry,
+    rules: Vec<Rule>,
+}
+
+pub struct GrpcService {
+    // The registry rule sets start from
+    base: Registry,
+    rules: Arc<RwLock<Rules>>,
+}
+
+impl GrpcService {
+    // Fails on rules the service can't run, see above
+    pub fn new(engine: &RuleEngine) -> Result<Self, Error> {
+        let service = GrpcService {
+            base: engine.registry().clone(),
+            rules: Arc::new(RwLock::new(Rules {
+                registry: engine.registry().clone(),
+                rules: engine.rules().to_vec(),
+            })),
+        };
+        service.engine()?;
+        Ok(service)
+    }
+
+    pub fn into_server(self) -> RuleServiceServer<Self> {
+        RuleServiceServer::new(self)
+    }
+
+    // An engine with the current rule set
+    fn engine(&self) -> Result<RuleEngine, Error> {
+        let rules = self
+            .rules
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut engine = RuleEngine::with_registry(rules.registry.clone());
+        engine.replace_rules(rules.rules.clone())?;
+        Ok(engine)
+    }
+}
+
+// Runs the rules over the evaluation's facts, handing each firing to sink as it happens
+fn evaluate(
+    mut engine: RuleEngine,
+    evaluation: Evaluation,
+    sink: impl FnMut(&Firing) + Send + Sync + 'static,
+) -> Result<Evaluated, Error> {
+    let mut store = EntityStore::new();
+    for fact in &evaluation.facts {
+        let values = fact
+            .values
+            .iter()
+            .map(from_proto)
+            .collect::<Result<Vec<_>, _>>()?;
+        let entity_id = entity(fact.entity)?;
+        store.insert_fact(engine.registry(), &fact.component, entity_id, &values)?;
+    }
+    engine.enable_trace().subscribe(sink);
+    let fired = engine.run(&mut store);
+
+    let mut derived: BTreeMap<(String, EntityId), Vec<Value>> = BTreeMap::new();
+    for firing in engine.trace().map_or(&[][..], |trace| trace.log()) {
+        for mutation in &firing.mutations {
+            match mutation {
+                Mutation::Inserted {
+                    component,
+                    entity,
+                    values,
+                } => derived.insert((component.clone(), *entity), values.clone()),
+                Mutation::Removed {
+                    component, entity, ..
+                } => derived.remove(&(component.clone(), *entity)),
+            };
+        }
+    }
+    let rows = match evaluation.query.trim() {
+        "" => Vec::new(),
+        query => engine
+            .query(query, &store)?
+            .iter()
+            .map(|bindings| Row {
+                bindings: bindings
+                    .iter()
+                    .map(|(name, value)| (name.clone(), to_proto(value)))
+                    .collect(),
+            })
+            .collect(),
+    };
+    Ok(Evaluated {
+        fired: fired as u64,
+        derived: derived
+            .iter()
+            .map(|((component, entity_id), values)| fact(component, *entity_id, values))
+            .collect(),
+        rows,
+    })
+}
+
+#[tonic::async_trait]
+impl RuleService for GrpcService {
+    async fn load_rule_set(
+        &self,
+        request: Request<RuleSet>,
+    ) -> Result<Response<RuleSetLoaded>, Status> {
+        let mut engine = RuleEngine::with_registry(self.base.clone());
+        engine
+            .load_str(&request.get_ref().source)
+            .map_err(invalid)?;
+        let loaded = RuleSetLoaded {
+            rules: engine.rules().len() as u32,
+        };
+        *self
+            .rules
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Rules {
+            registry: engine.registry().clone(),
+            rules: engine.rules().to_vec(),
+        };
+        Ok(Response::new(loaded))
+    }
+
+    async fn evaluate_facts(
+        &self,
+        request: Request<Evaluation>,
+    ) -> Result<Response<Evaluated>, Status> {
+        let engine = self.engine().map_err(internal)?;
+        let evaluation = request.into_inner();
+        let evaluated = tokio::task::spawn_blocking(move || evaluate(engine, evaluation, |_| {}))
+            .await
+            .map_err(|error| Status::internal(error.to_string()))?
+            .map_err(invalid)?;
+        Ok(Response::new(evaluated))
+    }
+
+    type StreamDerivationsStream = UnboundedReceiverStream<Result<Derivation, Status>>;
+
+    async fn stream_derivations(
+        &self,
+        request: Request<Evaluation>,
+    ) -> Result<Response<Self::StreamDerivationsStream>, Status> {
+        let engine = self.engine().map_err(internal)?;
+        let evaluation = request.into_inner();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let sink = sender.clone();
+        tokio::task::spawn_blocking(move || {
+            let sent = evaluate(engine, evaluation, move |firing| {
+                for mutation in &firing.mutations {
+                    let (component, entity, values, removed) = match mutation {
+                        Mutation::Inserted {
+                            component,
+                            entity,
+                            values,
+                        } => (component, entity, values, false),
+                        Mutation::Removed {
+                            component,
+                            entity,
+                            values,
+                        } => (component, entity, values, true),
+                    };
+                    // A client that hung up no longer cares
+                    let _ = sink.send(Ok(Derivation {
+                        rule: firing.rule.clone(),
+                        fact: Some(fact(component, *entity, values)),
+                        removed,
+                    }));
+                }
+            });
+            if let Err(error) = sent {
+                let _ = sender.send(Err(invalid(error)));
+            }
+        });
+        Ok(Response::new(UnboundedReceiverStream::new(receiver)))
+    }
+}
+
+// Serves the engine's rules on the address until the server fails
+pub async fn serve_grpc(
+    address: SocketAddr,
+    engine: &RuleEngine,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    tonic::transport::Server::builder()
+        .add_service(GrpcService::new(engine)?.into_server())
+        .serve(address)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_stream::StreamExt;
+
+    fn int(i: i64) -> proto::Value {
+        to_proto(&Value::Int(i))
+    }
+
+    #[test]
+    fn rule_sets_evaluate_and_stream() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let service = GrpcService::new(&RuleEngine::new()).unwrap();
+        let source = r#"fact Order(total)
+            fact Discount(percent)
+            rule "big" when Order(o, t), t >= 100 then insert Discount(o, 10)"#;
+        let evaluation = Evaluation {
+            facts: vec![
+                fact("Order", 1, &[Value::Int(150)]),
+                fact("Order", 2, &[Value::Int(20)]),
+            ],
+            query: "Discount(o, p)".to_string(),
+        };
+        runtime.block_on(async {
+            let loaded = service
+                .load_rule_set(Request::new(RuleSet {
+                    source: source.to_string(),
+                }))
+                .await
+                .unwrap();
+            assert_eq!(loaded.get_ref().rules, 1);
+
+            let evaluated = service
+                .evaluate_facts(Request::new(evaluation.clone()))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(evaluated.fired, 1);
+            assert_eq!(
+                evaluated.derived,
+                vec![fact("Discount", 1, &[Value::Int(10)])]
+            );
+            assert_eq!(evaluated.rows.len(), 1);
+            assert_eq!(evaluated.rows[0].bindings["p"], int(10));
+
+            let stream = service
+                .stream_derivations(Request::new(evaluation))
+                .await
+                .unwrap()
+                .into_inner();
+            let derivations: Vec<_> = stream.collect().await;
+            assert_eq!(derivations.len(), 1);
+            let derivation = derivations[0].as_ref().unwrap();
+            assert_eq!(
+                (derivation.rule.as_str(), derivation.removed),
+                ("big", false)
+            );
+
+            let unknown = Evaluation {
+                facts: vec![fact("Invoice", 1, &[])],
+                query: String::new(),
+            };
+            let status = service
+                .evaluate_facts(Request::new(unknown))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument);
+            let broken = RuleSet {
+                source: "rule".to_string(),
+            };
+            assert!(service.load_rule_set(Request::new(broken)).await.is_err());
+
+            // Ids past the maximum, for a fact or in a value, are turned away
+            for far in [
+                fact("Order", 1 << 50, &[Value::Int(1)]),
+                Fact {
+                    values: vec![proto::Value {
+                        kind: Some(Kind::Entity(u64::MAX)),
+                    }],
+                    ..fact("Order", 1, &[])
+                },
+            ] {
+                let evaluation = Evaluation {
+                    facts: vec![far],
+                    query: String::new(),
+                };
+                let status = service
+                    .evaluate_facts(Request::new(evaluation))
+                    .await
+                    .unwrap_err();
+                assert_eq!(status.code(), tonic::Code::InvalidArgument);
+            }
+        });
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn rules_running_the_engines_actions_are_refused() {
+        let mut engine = RuleEngine::new();
+        engine.load_str("fact Order(total)").unwrap();
+        engine.define_async("notify", |_| async { Ok(Vec::new()) });
+        engine
+            .load_str(r#"rule "ping" when Order(o, t) then run "notify""#)
+            .unwrap();
+        assert!(matches!(GrpcService::new(&engine), Err(Error::Script(_))));
+    }
+}
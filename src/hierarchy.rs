@@ -0,0 +1,204 @@
+// Parent and child links between entities
+//
+// set_parent keeps a Parent component on the child and a Children component on the
+// parent in step, moving the child out of its old parent's list when reparenting.
+// despawn takes an entity out of the hierarchy along with everything below it, so no
+// child is left pointing at a parent that is gone. Changing Parent or Children with
+// add_component, or despawning with remove_entity, bypasses this.
+use crate::error::Error;
+use crate::registry::{Fact, TypedFact};
+use crate::store::{Component, EntityId, EntityStore};
+use crate::value::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Parent(pub EntityId);
+
+impl Component for Parent {}
+
+impl Fact for Parent {
+    const FIELDS: &'static [&'static str] = &["parent"];
+
+    fn to_values(&self) -> Vec<Value> {
+        vec![Value::Entity(self.0)]
+    }
+
+    fn from_values(values: &[Value]) -> Option<Self> {
+        match values {
+            [parent] => Some(Parent(parent.as_entity()?)),
+            _ => None,
+        }
+    }
+}
+
+impl TypedFact for Parent {
+    type Fields = (EntityId,);
+
+    fn fields(&self) -> Self::Fields {
+        (self.0,)
+    }
+
+    fn from_fields((parent,): Self::Fields) -> Self {
+        Parent(parent)
+    }
+}
+
+// An entity's children, in the order they were added
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Children(pub Vec<EntityId>);
+
+impl Component for Children {}
+
+impl EntityStore {
+    pub fn parent(&self, entity_id: EntityId) -> Option<EntityId> {
+        let pool = self.get::<Parent>()?;
+        let parent = pool.borrow().get(entity_id)?.0;
+        Some(parent)
+    }
+
+    pub fn children(&self, entity_id: EntityId) -> Vec<EntityId> {
+        self.get::<Children>()
+            .and_then(|pool| {
+                pool.borrow()
+                    .get(entity_id)
+                    .map(|children| children.0.clone())
+            })
+            .unwrap_or_default()
+    }
+
+    // Makes child a child of parent, detaching it from any earlier parent
+    // Fails without changing anything if parent is child or one of its descendants
+    pub fn set_parent(&mut self, child: EntityId, parent: EntityId) -> Result<(), Error> {
+        if child == parent || self.ancestors(parent).contains(&child) {
+            return Err(Error::Cycle { child, parent });
+        }
+        if self.parent(child) == Some(parent) {
+            return Ok(());
+        }
+        self.begin_step();
+        self.remove_parent(child);
+        if self.get::<Parent>().is_none() {
+            self.new_component::<Parent>();
+            self.snapshot_component::<Parent>();
+        }
+        if self.get::<Children>().is_none() {
+            self.new_component::<Children>();
+            self.snapshot_component::<Children>();
+        }
+        let mut children = self.children(parent);
+        children.push(child);
+        self.reserve_up_to(child.max(parent));
+        self.add_component(parent, Children(children));
+        self.add_component(child, Parent(parent));
+        self.end_step();
+        Ok(())
+    }
+
+    // Makes the entity a root, taking it out of its parent's children
+    pub fn remove_parent(&mut self, child: EntityId) {
+        let Some(parent) = self.parent(child) else {
+            return;
+        };
+        self.begin_step();
+        let mut children = self.children(parent);
+        children.retain(|&other| other != child);
+        if children.is_empty() {
+            self.remove_component::<Children>(parent);
+        } else {
+            self.add_component(parent, Children(children));
+        }
+        self.remove_component::<Parent>(child);
+        self.end_step();
+    }
+
+    // Parent first, then its parent and so on up to the root
+    pub fn ancestors(&self, entity_id: EntityId) -> Vec<EntityId> {
+        let mut ancestors = Vec::new();
+        let mut current = entity_id;
+        while let Some(parent) = self.parent(current) {
+            ancestors.push(parent);
+            current = parent;
+        }
+        ancestors
+    }
+
+    // Everything below the entity, depth first with each child before its own children
+    pub fn descendants(&self, entity_id: EntityId) -> Vec<EntityId> {
+        let mut descendants = Vec::new();
+        let mut stack: Vec<EntityId> = self.children(entity_id).into_iter().rev().collect();
+        while let Some(child) = stack.pop() {
+            descendants.push(child);
+            stack.extend(self.children(child).into_iter().rev());
+        }
+        descendants
+    }
+
+    // Removes the entity and all its descendants, and takes it out of its parent's children
+    pub fn despawn(&mut self, entity_id: EntityId) {
+        self.begin_step();
+        self.remove_parent(entity_id);
+        for descendant in self.descendants(entity_id) {
+            self.remove_entity(descendant);
+        }
+        self.remove_entity(entity_id);
+        self.end_step();
+    }
+}
+
+// Lets rules insert and remove Parent with the children kept in step
+pub(crate) fn insert_parent(
+    store: &mut EntityStore,
+    entity_id: EntityId,
+    values: &[Value],
+) -> bool {
+    match Parent::from_values(values) {
+        Some(Parent(parent)) => store.set_parent(entity_id, parent).is_ok(),
+        None => false,
+    }
+}
+
+pub(crate) fn remove_parent(store: &mut EntityStore, entity_id: EntityId) {
+    store.remove_parent(entity_id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::RuleEngine;
+
+    #[test]
+    fn reparenting_and_despawn_keep_the_tree_consistent() {
+        let mut store = EntityStore::new();
+        // 1 ─ 2 ─ 4
+        //   └ 3
+        store.set_parent(2, 1).unwrap();
+        store.set_parent(3, 1).unwrap();
+        store.set_parent(4, 2).unwrap();
+        assert_eq!(store.descendants(1), vec![2, 4, 3]);
+        assert_eq!(store.ancestors(4), vec![2, 1]);
+        assert_eq!(
+            store.set_parent(1, 4),
+            Err(Error::Cycle {
+                child: 1,
+                parent: 4
+            })
+        );
+
+        store.set_parent(4, 3).unwrap();
+        assert_eq!(store.children(2), Vec::<EntityId>::new());
+        assert_eq!(store.children(3), vec![4]);
+
+        let mut engine = RuleEngine::new();
+        engine.register_hierarchy("Parent");
+        engine
+            .load_str(r#"rule "flatten" when Parent(c, p), Parent(p, g) then insert Parent(c, g)"#)
+            .unwrap();
+        engine.run(&mut store);
+        assert_eq!(store.children(1), vec![2, 3, 4]);
+        assert!(store.children(3).is_empty());
+
+        store.set_parent(5, 4).unwrap();
+        store.despawn(4);
+        assert_eq!(store.children(1), vec![2, 3]);
+        assert!(!store.has_component::<Parent>(5));
+    }
+}
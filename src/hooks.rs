@@ -0,0 +1,168 @@
+// Component lifecycle hooks
+//
+// Callbacks run whenever a component of a given type is added to, replaced on or
+// removed from any entity through the store, so indexes and caches kept outside the
+// store can follow along. Components changed in place through a pool aren't seen.
+use crate::store::{Component, EntityId, EntityStore};
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+
+type Added<T> = Box<dyn FnMut(EntityId, &T) + Send + Sync>;
+type Replaced<T> = Box<dyn FnMut(EntityId, &T, &T) + Send + Sync>;
+type Removed<T> = Box<dyn FnMut(EntityId, &T) + Send + Sync>;
+
+pub(crate) struct Hooks<T> {
+    added: Vec<Added<T>>,
+    replaced: Vec<Replaced<T>>,
+    removed: Vec<Removed<T>>,
+}
+
+impl<T> Hooks<T> {
+    fn new() -> Self {
+        Hooks {
+            added: Vec::new(),
+            replaced: Vec::new(),
+            removed: Vec::new(),
+        }
+    }
+
+    pub(crate) fn added(&mut self, entity_id: EntityId, component: &T) {
+        for hook in &mut self.added {
+            hook(entity_id, component);
+        }
+    }
+
+    pub(crate) fn replaced(&mut self, entity_id: EntityId, old: &T, new: &T) {
+        for hook in &mut self.replaced {
+            hook(entity_id, old, new);
+        }
+    }
+
+    pub(crate) fn removed(&mut self, entity_id: EntityId, component: &T) {
+        for hook in &mut self.removed {
+            hook(entity_id, component);
+        }
+    }
+}
+
+// Lets remove_entity run hooks for pools it only knows type erased
+pub(crate) trait AnyHooks: Send + Sync {
+    fn removed_any(&mut self, entity_id: EntityId, component: &dyn Any);
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: 'static> AnyHooks for Hooks<T> {
+    fn removed_any(&mut self, entity_id: EntityId, component: &dyn Any) {
+        if let Some(component) = component.downcast_ref::<T>() {
+            self.removed(entity_id, component);
+        }
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct HookStore(HashMap<TypeId, Box<dyn AnyHooks>>);
+
+impl fmt::Debug for HookStore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HookStore")
+    }
+}
+
+impl HookStore {
+    pub(crate) fn get_mut<T: 'static>(&mut self) -> Option<&mut Hooks<T>> {
+        self.0
+            .get_mut(&TypeId::of::<T>())?
+            .as_any_mut()
+            .downcast_mut()
+    }
+
+    pub(crate) fn contains(&self, type_id: TypeId) -> bool {
+        self.0.contains_key(&type_id)
+    }
+
+    pub(crate) fn get_erased(&mut self, type_id: TypeId) -> Option<&mut dyn AnyHooks> {
+        Some(self.0.get_mut(&type_id)?.as_mut())
+    }
+
+    fn entry<T: 'static>(&mut self) -> &mut Hooks<T> {
+        self.0
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(Hooks::<T>::new()))
+            .as_any_mut()
+            .downcast_mut()
+            .expect("hooks are keyed by their component's type")
+    }
+}
+
+impl EntityStore {
+    // Called when an entity without a T gains one
+    pub fn on_add<T: Component + 'static>(
+        &mut self,
+        hook: impl FnMut(EntityId, &T) + Send + Sync + 'static,
+    ) {
+        self.hooks.entry::<T>().added.push(Box::new(hook));
+    }
+
+    // Called with the old and new component when an entity's T is overwritten
+    pub fn on_replace<T: Component + 'static>(
+        &mut self,
+        hook: impl FnMut(EntityId, &T, &T) + Send + Sync + 'static,
+    ) {
+        self.hooks.entry::<T>().replaced.push(Box::new(hook));
+    }
+
+    // Called with the component as it is removed, including by remove_entity
+    pub fn on_remove<T: Component + 'static>(
+        &mut self,
+        hook: impl FnMut(EntityId, &T) + Send + Sync + 'static,
+    ) {
+        self.hooks.entry::<T>().removed.push(Box::new(hook));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cell::AtomicRefCell;
+    use std::sync::Arc;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Name(&'static str);
+    impl Component for Name {}
+
+    #[test]
+    fn hooks_keep_an_index_in_sync() {
+        let index: Arc<AtomicRefCell<HashMap<&'static str, EntityId>>> = Arc::default();
+        let mut store = EntityStore::new();
+        store.new_component::<Name>();
+
+        let added = index.clone();
+        store.on_add(move |entity, name: &Name| {
+            added.borrow_mut().insert(name.0, entity);
+        });
+        let replaced = index.clone();
+        store.on_replace(move |entity, old: &Name, new: &Name| {
+            let mut index = replaced.borrow_mut();
+            index.remove(old.0);
+            index.insert(new.0, entity);
+        });
+        let removed = index.clone();
+        store.on_remove(move |_, name: &Name| {
+            removed.borrow_mut().remove(name.0);
+        });
+
+        store.add_component(1, Name("ada"));
+        store.add_component(2, Name("bob"));
+        store.add_component(1, Name("eve"));
+        store.remove_component::<Name>(2);
+        assert_eq!(*index.borrow(), HashMap::from([("eve", 1)]));
+
+        store.remove_entity(1);
+        assert!(index.borrow().is_empty());
+    }
+}
@@ -0,0 +1,384 @@
+// Incremental maintenance of derived relations
+//
+// A view, see view.rs, is kept by counting. Every way its conditions match, a
+// derivation, is remembered by the facts its patterns matched, and every row by how many
+// derivations give it. When the components it reads change only the difference is
+// worked through. The facts each component had when last looked at are kept, so its
+// inserted and removed facts are found by comparing, which is skipped outright while
+// the pool's version hasn't moved, see cache.rs. A removed fact drops the derivations
+// that used it. An inserted fact is pinned in place of each pattern it unifies with and
+// only the rest of the conditions are matched around it, so the work follows the size
+// of the change rather than of the join. A row whose count falls to nothing goes, one
+// whose count rises from nothing appears.
+//
+// Negation doesn't count this way, since a change to a component under `not` can make
+// or break derivations using none of its facts. Such changes are matched from scratch,
+// as is the first refresh, and so is every change to conditions whose meaning depends on
+// the order patterns are matched in: spatial ones, a `not` or `where` using a variable
+// a pattern written after it binds, and temporal patterns. Either way only the rows that
+// came or went are reported.
+use crate::engine::RuleEngine;
+use crate::provenance::Premise;
+use crate::registry::FactRow;
+use crate::replay::values_order;
+use crate::rule::{Condition, Pattern};
+use crate::store::{EntityId, EntityStore};
+use crate::value::{Bindings, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+pub(crate) type Row = (EntityId, Vec<Value>);
+
+// The facts matched by a derivation's patterns, in the order they're written
+type Derivation = Vec<Premise>;
+
+// Rows whose counts a refresh changed, in the order first changed, and whether each had
+// one before
+type Touched = HashMap<Row, (usize, bool)>;
+
+// A component's version and facts when last looked at
+type Seen = (Option<(u64, usize)>, HashSet<FactRow>);
+
+// The facts inserted and removed in each component changed
+type Changes = BTreeMap<String, (Vec<FactRow>, Vec<FactRow>)>;
+
+// Rows that came and went in one refresh
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Delta {
+    pub(crate) added: Vec<Row>,
+    pub(crate) removed: Vec<Row>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Maintained {
+    conditions: Vec<Condition>,
+    // The entity's variable, then one per field of the rows
+    head: Vec<String>,
+    // Whether changes can be worked through as differences, see above
+    incremental: bool,
+    primed: bool,
+    // Each component read, see Seen
+    seen: HashMap<String, Seen>,
+    // The row each derivation gives, None if its first variable isn't an entity
+    derivations: HashMap<Derivation, Option<Row>>,
+    // The derivations using each fact
+    uses: HashMap<Premise, Vec<Derivation>>,
+    counts: HashMap<Row, usize>,
+}
+
+// Variables the pattern's where clause uses
+fn guard_vars(pattern: &Pattern) -> Vec<&str> {
+    pattern
+        .guard
+        .as_ref()
+        .map(|guard| guard.vars())
+        .unwrap_or_default()
+}
+
+// Whether matching each pattern first, before the conditions written ahead of it, gives
+// the same answers, see above
+fn pinnable(conditions: &[Condition]) -> bool {
+    let patterns: Vec<&Pattern> = conditions
+        .iter()
+        .filter_map(|condition| match condition {
+            Condition::Pattern(pattern) => Some(pattern),
+            _ => None,
+        })
+        .collect();
+    let mut bound = HashSet::new();
+    for condition in conditions {
+        let (pattern, negated) = match condition {
+            Condition::Pattern(pattern) => (pattern, false),
+            Condition::Not(pattern) => (pattern, true),
+            Condition::Test(_) => continue,
+            Condition::Spatial(_) => return false,
+        };
+        if pattern.temporal.is_some() {
+            return false;
+        }
+        let own: HashSet<&str> = pattern.vars().collect();
+        let mut used = guard_vars(pattern);
+        if negated {
+            used.extend(pattern.vars());
+        }
+        let elsewhere = |variable: &&str| {
+            patterns
+                .iter()
+                .any(|other| !std::ptr::eq(*other, pattern) && other.vars().any(|v| v == *variable))
+        };
+        // Pinned first, a pattern's where clause sees only its own variables, and a
+        // `not` sees those of every pattern written after it too
+        let leaked = used
+            .iter()
+            .filter(|variable| match negated {
+                true => !bound.contains(**variable),
+                false => !own.contains(**variable),
+            })
+            .any(elsewhere);
+        if leaked {
+            return false;
+        }
+        if !negated {
+            bound.extend(own);
+        }
+    }
+    true
+}
+
+impl Maintained {
+    pub(crate) fn new(conditions: Vec<Condition>, head: Vec<String>) -> Self {
+        Maintained {
+            incremental: pinnable(&conditions),
+            conditions,
+            head,
+            ..Default::default()
+        }
+    }
+
+    // Brings the derivations up to date with the store, giving the rows that changed
+    pub(crate) fn refresh(&mut self, engine: &RuleEngine, store: &EntityStore) -> Delta {
+        let changes = self.changes(engine, store);
+        let negated = changes.keys().any(|component| {
+            self.conditions.iter().any(|condition| {
+                matches!(condition, Condition::Not(pattern) if pattern.component == *component)
+            })
+        });
+        let mut touched = HashMap::new();
+        if !self.primed || !self.incremental || negated {
+            let all: Vec<Derivation> = self.derivations.keys().cloned().collect();
+            for derivation in all {
+                self.retract(&derivation, &mut touched);
+            }
+            let start = (Bindings::new(), Vec::new());
+            for (bindings, premises) in engine.match_from(None, &self.conditions, store, start) {
+                self.derive(premises, &bindings, &mut touched);
+            }
+            self.primed = true;
+        } else {
+            for (component, (_, removed)) in &changes {
+                for (entity, values) in removed {
+                    let premise = Premise {
+                        component: component.clone(),
+                        entity: *entity,
+                        values: values.clone(),
+                    };
+                    for derivation in self.uses.get(&premise).cloned().unwrap_or_default() {
+                        self.retract(&derivation, &mut touched);
+                    }
+                }
+            }
+            for (component, (added, _)) in &changes {
+                for position in 0..self.conditions.len() {
+                    let Condition::Pattern(pattern) = &self.conditions[position] else {
+                        continue;
+                    };
+                    if pattern.component != *component {
+                        continue;
+                    }
+                    for fact in added {
+                        for (bindings, premises) in self.pinned(engine, store, position, fact) {
+                            self.derive(premises, &bindings, &mut touched);
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut touched: Vec<(Row, (usize, bool))> = touched.into_iter().collect();
+        touched.sort_by_key(|(_, (order, _))| *order);
+        let mut delta = Delta::default();
+        for (row, (_, was)) in touched {
+            match (was, self.counts.contains_key(&row)) {
+                (false, true) => delta.added.push(row),
+                (true, false) => delta.removed.push(row),
+                _ => {}
+            }
+        }
+        delta
+    }
+
+    // The facts inserted and removed in each component read since the last refresh
+    fn changes(&mut self, engine: &RuleEngine, store: &EntityStore) -> Changes {
+        let mut changes = BTreeMap::new();
+        let components: HashSet<String> = self
+            .conditions
+            .iter()
+            .filter_map(|condition| match condition {
+                Condition::Pattern(pattern) | Condition::Not(pattern) => {
+                    Some(pattern.component.clone())
+                }
+                _ => None,
+            })
+            .collect();
+        for component in components {
+            let Some(info) = engine.registry().get(&component) else {
+                continue;
+            };
+            let version = info.version(store);
+            let seen = self.seen.entry(component.clone()).or_default();
+            if self.primed && version.is_some() && version == seen.0 {
+                continue;
+            }
+            let facts: HashSet<FactRow> = info.facts(store).into_iter().collect();
+            let mut added: Vec<FactRow> = facts.difference(&seen.1).cloned().collect();
+            let removed: Vec<FactRow> = seen.1.difference(&facts).cloned().collect();
+            // In a set order, so rows derived from them come out the same way every run
+            added.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| values_order(&a.1, &b.1)));
+            *seen = (version, facts);
+            if !added.is_empty() || !removed.is_empty() {
+                changes.insert(component, (added, removed));
+            }
+        }
+        changes
+    }
+
+    // Every derivation in which the pattern at position matches the fact
+    fn pinned(
+        &self,
+        engine: &RuleEngine,
+        store: &EntityStore,
+        position: usize,
+        (entity_id, fields): &FactRow,
+    ) -> Vec<(Bindings, Derivation)> {
+        let Condition::Pattern(pattern) = &self.conditions[position] else {
+            return Vec::new();
+        };
+        let Some(info) = engine.registry().get(&pattern.component) else {
+            return Vec::new();
+        };
+        let mut values = vec![Value::Entity(*entity_id)];
+        values.extend(fields.iter().cloned());
+        let Some(bindings) = pattern.unify(&values, &Bindings::new()) else {
+            return Vec::new();
+        };
+        if !pattern.guard_holds(&bindings, info.fields, fields) {
+            return Vec::new();
+        }
+        let premise = Premise {
+            component: pattern.component.clone(),
+            entity: *entity_id,
+            values: fields.clone(),
+        };
+        let mut rest = self.conditions.clone();
+        rest.remove(position);
+        // Where the pinned fact goes among the premises, as the patterns are written
+        let at = self.conditions[..position]
+            .iter()
+            .filter(|condition| matches!(condition, Condition::Pattern(_)))
+            .count();
+        engine
+            .match_from(None, &rest, store, (bindings, vec![premise]))
+            .into_iter()
+            .map(|(bindings, mut premises)| {
+                let pinned = premises.remove(0);
+                premises.insert(at, pinned);
+                (bindings, premises)
+            })
+            .collect()
+    }
+
+    fn derive(&mut self, derivation: Derivation, bindings: &Bindings, touched: &mut Touched) {
+        if self.derivations.contains_key(&derivation) {
+            return;
+        }
+        let row = match bindings.get(&self.head[0]) {
+            Some(Value::Entity(entity_id)) => {
+                let fields = self.head[1..]
+                    .iter()
+                    .map(|variable| bindings[variable].clone())
+                    .collect();
+                Some((*entity_id, fields))
+            }
+            _ => None,
+        };
+        if let Some(row) = &row {
+            let order = touched.len();
+            touched
+                .entry(row.clone())
+                .or_insert_with(|| (order, self.counts.contains_key(row)));
+            *self.counts.entry(row.clone()).or_default() += 1;
+        }
+        for premise in &derivation {
+            self.uses
+                .entry(premise.clone())
+                .or_default()
+                .push(derivation.clone());
+        }
+        self.derivations.insert(derivation, row);
+    }
+
+    fn retract(&mut self, derivation: &Derivation, touched: &mut Touched) {
+        let Some(row) = self.derivations.remove(derivation) else {
+            return;
+        };
+        for premise in derivation {
+            if let Some(uses) = self.uses.get_mut(premise) {
+                uses.retain(|other| other != derivation);
+                if uses.is_empty() {
+                    self.uses.remove(premise);
+                }
+            }
+        }
+        let Some(row) = row else {
+            return;
+        };
+        let order = touched.len();
+        touched.entry(row.clone()).or_insert((order, true));
+        if let Some(count) = self.counts.get_mut(&row) {
+            *count -= 1;
+            if *count == 0 {
+                self.counts.remove(&row);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::Query;
+
+    fn maintained(conditions: &str, head: &[&str]) -> Maintained {
+        let conditions = Query::parse(conditions).unwrap().conditions;
+        Maintained::new(conditions, head.iter().map(|v| v.to_string()).collect())
+    }
+
+    #[test]
+    fn deltas_match_recomputing() {
+        assert!(!maintained("Edge(a, b), not Edge(b, c), Edge(c, a)", &["a"]).incremental);
+        assert!(!maintained("Edge(a, b) where b > c, Weight(c, c)", &["a"]).incremental);
+        let mut grandparents = maintained("Parent(x, y), Parent(y, z)", &["x", "z"]);
+        assert!(grandparents.incremental);
+
+        let mut engine = RuleEngine::new();
+        engine.load_str("fact Parent(parent)").unwrap();
+        let registry = engine.registry().clone();
+        let parent = registry.get("Parent").unwrap();
+        let mut store = EntityStore::new();
+        for (child, of) in [(1, 3), (2, 3), (3, 4)] {
+            parent.insert(&mut store, child, &[Value::Entity(of)]);
+        }
+        let delta = grandparents.refresh(&engine, &store);
+        assert_eq!(
+            delta.added,
+            [(1, vec![Value::Entity(4)]), (2, vec![Value::Entity(4)])]
+        );
+
+        // 4 gets a parent, 2 moves to 5 whose parent is 4 too, and 1 goes
+        parent.insert(&mut store, 4, &[Value::Entity(6)]);
+        parent.insert(&mut store, 2, &[Value::Entity(5)]);
+        parent.insert(&mut store, 5, &[Value::Entity(4)]);
+        parent.remove(&mut store, 1);
+        let mut delta = grandparents.refresh(&engine, &store);
+        delta.added.sort_by_key(|(entity_id, _)| *entity_id);
+        assert_eq!(
+            delta.added,
+            [(3, vec![Value::Entity(6)]), (5, vec![Value::Entity(6)])]
+        );
+        assert_eq!(delta.removed, [(1, vec![Value::Entity(4)])]);
+        assert_eq!(grandparents.refresh(&engine, &store), Delta::default());
+
+        let mut recomputed = maintained("Parent(x, y), Parent(y, z)", &["x", "z"]);
+        recomputed.refresh(&engine, &store);
+        assert_eq!(recomputed.counts, grandparents.counts);
+    }
+}
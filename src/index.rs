@@ -0,0 +1,557 @@
+// Hash and ordered indexes on component fields
+//
+// index_field keeps a map from each value of one field of a component to the entities
+// holding it. Rules use it for patterns whose entity is unbound but one of whose
+// indexed fields is a constant or already bound, so `Faction(a, f), Faction(b, f)`
+// looks b up by f instead of scanning every Faction for each a.
+//
+// index_range keeps a numeric field in order instead, for comparisons. A pattern whose
+// field is bounded by `<`, `<=`, `>`, `>=`, `==` or `between`, either in its where
+// clause or in a test later in the rule, only visits the entities in that range. The
+// bounds just pick candidates, the comparisons are still tested on each, so Ints are
+// keyed as floats without worrying about rounding at the edges. Values that aren't
+// numbers, or are NaN, can't satisfy a numeric comparison and aren't indexed.
+//
+// These and the spatial indexes follow their pool the same way. Hooks keep them up to
+// date as components are added, replaced and removed through the store. Components
+// changed in place through a pool are caught by refresh_indexes, which RuleEngine::run
+// calls before matching: each index remembers the pool's change tick when it last
+// looked and only re-reads the components whose tick has moved past it, see
+// Pool::changed_since. Components taken straight out of a pool are still missed.
+use crate::cell::AtomicRefCell;
+use crate::registry::Fact;
+use crate::rule::{BinaryOp, Condition, Expr, Pattern, Term};
+use crate::stats::Histogram;
+use crate::store::{Component, EntityId, EntityStore};
+use crate::value::{Bindings, Value};
+use std::any::TypeId;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+
+// Catches an index up with its pool
+type Refresher = Box<dyn FnMut(&EntityStore) + Send + Sync>;
+
+#[derive(Default)]
+pub(crate) struct Refreshers(Vec<Refresher>);
+
+impl fmt::Debug for Refreshers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Refreshers({})", self.0.len())
+    }
+}
+
+// The entities holding each value of one field
+#[derive(Debug, Default)]
+pub(crate) struct FieldIndex {
+    entities: HashMap<Value, Vec<EntityId>>,
+    // What each entity is filed under
+    values: HashMap<EntityId, Value>,
+}
+
+impl FieldIndex {
+    // Files the entity under its new value, or takes it out for None
+    fn set(&mut self, entity_id: EntityId, value: Option<Value>) {
+        if self.values.get(&entity_id) == value.as_ref() {
+            return;
+        }
+        if let Some(old) = self.values.remove(&entity_id) {
+            if let Some(entities) = self.entities.get_mut(&old) {
+                entities.retain(|&other| other != entity_id);
+                if entities.is_empty() {
+                    self.entities.remove(&old);
+                }
+            }
+        }
+        if let Some(value) = value {
+            self.entities
+                .entry(value.clone())
+                .or_default()
+                .push(entity_id);
+            self.values.insert(entity_id, value);
+        }
+    }
+}
+
+// An index key, ordered by f64::total_cmp
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Key(f64);
+
+impl From<Key> for f64 {
+    fn from(key: Key) -> f64 {
+        key.0
+    }
+}
+
+impl Key {
+    fn of(value: &Value) -> Option<Key> {
+        let float = value.as_float()?;
+        // -0.0 and 0.0 compare equal, so they share a key
+        (!float.is_nan()).then_some(Key(float + 0.0))
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Key) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Key {}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Key) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Key {
+    fn cmp(&self, other: &Key) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+// The entities holding each numeric value of one field, in order of value
+#[derive(Debug, Default)]
+pub(crate) struct OrderedIndex {
+    entities: BTreeMap<Key, Vec<EntityId>>,
+    keys: HashMap<EntityId, Key>,
+}
+
+impl OrderedIndex {
+    // As FieldIndex::set, values that aren't numbers take the entity out
+    fn set(&mut self, entity_id: EntityId, value: Option<&Value>) {
+        let key = value.and_then(Key::of);
+        if self.keys.get(&entity_id) == key.as_ref() {
+            return;
+        }
+        if let Some(old) = self.keys.remove(&entity_id) {
+            if let Some(entities) = self.entities.get_mut(&old) {
+                entities.retain(|&other| other != entity_id);
+                if entities.is_empty() {
+                    self.entities.remove(&old);
+                }
+            }
+        }
+        if let Some(key) = key {
+            self.entities.entry(key).or_default().push(entity_id);
+            self.keys.insert(entity_id, key);
+        }
+    }
+}
+
+impl EntityStore {
+    // Fills an index from T's pool and keeps it following the pool, see above
+    // update is given each component added or changed, or None once it is removed
+    pub(crate) fn follow<T, I>(
+        &mut self,
+        index: &Arc<AtomicRefCell<I>>,
+        update: impl Fn(&mut I, EntityId, Option<&T>) + Clone + Send + Sync + 'static,
+    ) where
+        T: Component + Eq + 'static,
+        I: Send + Sync + 'static,
+    {
+        let mut seen = 0;
+        if let Some(pool) = self.get::<T>() {
+            let pool = pool.borrow();
+            let mut index = index.borrow_mut();
+            for (&entity_id, component) in pool.components_iter() {
+                update(&mut index, entity_id, Some(component));
+            }
+            seen = pool.tick();
+        }
+
+        let (added, on_add) = (index.clone(), update.clone());
+        self.on_add(move |entity_id, component: &T| {
+            on_add(&mut added.borrow_mut(), entity_id, Some(component));
+        });
+        let (replaced, on_replace) = (index.clone(), update.clone());
+        self.on_replace(move |entity_id, _, component: &T| {
+            on_replace(&mut replaced.borrow_mut(), entity_id, Some(component));
+        });
+        let (removed, on_remove) = (index.clone(), update.clone());
+        self.on_remove(move |entity_id, _: &T| {
+            on_remove(&mut removed.borrow_mut(), entity_id, None);
+        });
+
+        let refreshed = index.clone();
+        self.refreshers.0.push(Box::new(move |store: &EntityStore| {
+            let Some(pool) = store.get::<T>() else {
+                return;
+            };
+            let pool = pool.borrow();
+            // A pool made anew counts its ticks from the start again
+            if pool.tick() < seen {
+                seen = 0;
+            }
+            let mut index = refreshed.borrow_mut();
+            for (&entity_id, component) in pool.changed_since(seen) {
+                update(&mut index, entity_id, Some(component));
+            }
+            seen = pool.tick();
+        }));
+    }
+
+    // Catches every index up on components changed in place since it last looked
+    pub fn refresh_indexes(&mut self) {
+        let mut refreshers = std::mem::take(&mut self.refreshers);
+        for refresh in &mut refreshers.0 {
+            refresh(self);
+        }
+        self.refreshers = refreshers;
+    }
+
+    // Starts keeping a hash index on one of T's fields
+    // Returns false if T has no such field
+    pub fn index_field<T: Fact>(&mut self, field: &str) -> bool {
+        let Some(position) = T::FIELDS.iter().position(|&name| name == field) else {
+            return false;
+        };
+        let key = (TypeId::of::<T>(), position);
+        if self.field_indexes.contains_key(&key) {
+            return true;
+        }
+        let index = Arc::new(AtomicRefCell::new(FieldIndex::default()));
+        self.follow(&index, move |index, entity_id, component: Option<&T>| {
+            let value = component.map(|component| component.to_values().swap_remove(position));
+            index.set(entity_id, value);
+        });
+        self.field_indexes.insert(key, index);
+        true
+    }
+
+    // The entities whose T has the value in the field, None if the field isn't indexed
+    pub fn indexed<T: Fact>(&self, field: &str, value: &Value) -> Option<Vec<EntityId>> {
+        let position = T::FIELDS.iter().position(|&name| name == field)?;
+        self.index_lookup(TypeId::of::<T>(), position, value)
+    }
+
+    // As indexed, by type and field position, for the engine
+    pub(crate) fn index_lookup(
+        &self,
+        type_id: TypeId,
+        position: usize,
+        value: &Value,
+    ) -> Option<Vec<EntityId>> {
+        let index = self.field_indexes.get(&(type_id, position))?;
+        let index = index.borrow();
+        Some(index.entities.get(value).cloned().unwrap_or_default())
+    }
+
+    // How many distinct values a field with a hash index holds, for planning joins
+    pub(crate) fn distinct_values(&self, type_id: TypeId, position: usize) -> Option<usize> {
+        let index = self.field_indexes.get(&(type_id, position))?;
+        let distinct = index.borrow().entities.len();
+        Some(distinct)
+    }
+
+    // The spread of a field with an ordered index, see stats.rs
+    pub(crate) fn histogram(&self, type_id: TypeId, position: usize) -> Option<Histogram> {
+        let index = self.ordered_indexes.get(&(type_id, position))?;
+        let values: Vec<(f64, usize)> = index
+            .borrow()
+            .entities
+            .iter()
+            .map(|(key, entities)| (key.0, entities.len()))
+            .collect();
+        Histogram::build(&values)
+    }
+
+    // Starts keeping an ordered index on one of T's numeric fields
+    // Returns false if T has no such field
+    pub fn index_range<T: Fact>(&mut self, field: &str) -> bool {
+        let Some(position) = T::FIELDS.iter().position(|&name| name == field) else {
+            return false;
+        };
+        let key = (TypeId::of::<T>(), position);
+        if self.ordered_indexes.contains_key(&key) {
+            return true;
+        }
+        let index = Arc::new(AtomicRefCell::new(OrderedIndex::default()));
+        self.follow(&index, move |index, entity_id, component: Option<&T>| {
+            let value = component.map(|component| component.to_values().swap_remove(position));
+            index.set(entity_id, value.as_ref());
+        });
+        self.ordered_indexes.insert(key, index);
+        true
+    }
+
+    // The entities whose T has a value in the range in the field, in order of value
+    // None if the field has no ordered index
+    pub fn in_range<T: Fact>(
+        &self,
+        field: &str,
+        range: impl RangeBounds<f64>,
+    ) -> Option<Vec<EntityId>> {
+        let position = T::FIELDS.iter().position(|&name| name == field)?;
+        let bound = |bound: Bound<&f64>| match bound {
+            Bound::Included(&float) => Bound::Included(Key(float + 0.0)),
+            Bound::Excluded(&float) => Bound::Excluded(Key(float + 0.0)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let bounds = (bound(range.start_bound()), bound(range.end_bound()));
+        self.range_lookup(TypeId::of::<T>(), position, bounds)
+    }
+
+    // As in_range, by type and field position, for the engine
+    pub(crate) fn range_lookup(
+        &self,
+        type_id: TypeId,
+        position: usize,
+        (low, high): (Bound<Key>, Bound<Key>),
+    ) -> Option<Vec<EntityId>> {
+        let index = self.ordered_indexes.get(&(type_id, position))?;
+        // BTreeMap::range panics on a backwards range, which just matches nothing
+        let empty = match (low, high) {
+            (Bound::Included(low), Bound::Included(high)) => low > high,
+            (Bound::Included(low) | Bound::Excluded(low), Bound::Excluded(high))
+            | (Bound::Excluded(low), Bound::Included(high)) => low >= high,
+            _ => false,
+        };
+        if empty {
+            return Some(Vec::new());
+        }
+        let index = index.borrow();
+        Some(
+            index
+                .entities
+                .range((low, high))
+                .flat_map(|(_, entities)| entities.iter().copied())
+                .collect(),
+        )
+    }
+}
+
+// Inclusive bounds on each of a pattern's fields, from comparisons in its where clause
+// and in the tests that follow it against values already known, see above
+pub(crate) fn field_bounds(
+    pattern: &Pattern,
+    fields: &[&str],
+    bindings: &Bindings,
+    rest: &[Condition],
+) -> HashMap<usize, (Option<Key>, Option<Key>)> {
+    // Which field an unbound variable reads, as a pattern argument or in the where
+    // clause by the field's name
+    let position = |name: &str, guard: bool| {
+        if bindings.contains_key(name) {
+            return None;
+        }
+        let argument = pattern.args.iter().skip(1).position(|term| match term {
+            Term::Var(var) => var == name,
+            _ => false,
+        });
+        match argument {
+            Some(position) => Some(position),
+            None if guard => fields.iter().position(|&field| field == name),
+            None => None,
+        }
+    };
+
+    let mut bounds: HashMap<usize, (Option<Key>, Option<Key>)> = HashMap::new();
+    let tests = rest.iter().filter_map(|condition| match condition {
+        Condition::Test(expr) => Some((expr, false)),
+        _ => None,
+    });
+    for (expr, guard) in pattern.guard.iter().map(|guard| (guard, true)).chain(tests) {
+        for conjunct in conjuncts(expr) {
+            let Expr::Binary(lhs, op, rhs) = conjunct else {
+                continue;
+            };
+            let (name, op, other) = match (&**lhs, &**rhs) {
+                (Expr::Var(name), other) => (name, *op, other),
+                (other, Expr::Var(name)) => (name, flip(*op), other),
+                _ => continue,
+            };
+            let Some(position) = position(name, guard) else {
+                continue;
+            };
+            let Some(key) = other.eval(bindings).as_ref().and_then(Key::of) else {
+                continue;
+            };
+            let (low, high) = bounds.entry(position).or_default();
+            if matches!(op, BinaryOp::Gt | BinaryOp::Ge | BinaryOp::Eq) {
+                *low = Some(low.map_or(key, |low| low.max(key)));
+            }
+            if matches!(op, BinaryOp::Lt | BinaryOp::Le | BinaryOp::Eq) {
+                *high = Some(high.map_or(key, |high| high.min(key)));
+            }
+        }
+    }
+    bounds
+}
+
+fn conjuncts(expr: &Expr) -> Vec<&Expr> {
+    match expr {
+        Expr::Binary(lhs, BinaryOp::And, rhs) => {
+            let mut all = conjuncts(lhs);
+            all.extend(conjuncts(rhs));
+            all
+        }
+        expr => vec![expr],
+    }
+}
+
+// The same comparison with its sides swapped, `3 < x` is `x > 3`
+fn flip(op: BinaryOp) -> BinaryOp {
+    match op {
+        BinaryOp::Lt => BinaryOp::Gt,
+        BinaryOp::Le => BinaryOp::Ge,
+        BinaryOp::Gt => BinaryOp::Lt,
+        BinaryOp::Ge => BinaryOp::Le,
+        op => op,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::RuleEngine;
+    use crate::store::Component;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Faction {
+        id: i64,
+    }
+
+    impl Component for Faction {}
+
+    impl Fact for Faction {
+        const FIELDS: &'static [&'static str] = &["id"];
+
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.id)]
+        }
+
+        fn from_values(values: &[Value]) -> Option<Self> {
+            match values {
+                [id] => Some(Faction { id: id.as_int()? }),
+                _ => None,
+            }
+        }
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Health {
+        hp: i64,
+    }
+
+    impl Component for Health {}
+
+    impl Fact for Health {
+        const FIELDS: &'static [&'static str] = &["hp"];
+
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.hp)]
+        }
+
+        fn from_values(values: &[Value]) -> Option<Self> {
+            match values {
+                [hp] => Some(Health { hp: hp.as_int()? }),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn indexes_follow_the_pool_and_serve_joins() {
+        let mut store = EntityStore::new();
+        store.new_component::<Faction>();
+        store.add_component(1, Faction { id: 7 });
+        assert!(store.index_field::<Faction>("id"));
+        assert!(!store.index_field::<Faction>("name"));
+        for entity_id in 2..5 {
+            store.add_component(entity_id, Faction { id: 7 });
+        }
+        store.add_component(5, Faction { id: 8 });
+        store.add_component(3, Faction { id: 8 });
+        store.remove_entity(4);
+        assert_eq!(
+            store.indexed::<Faction>("id", &Value::Int(7)),
+            Some(vec![1, 2])
+        );
+        assert_eq!(
+            store.indexed::<Faction>("id", &Value::Int(8)),
+            Some(vec![5, 3])
+        );
+        assert_eq!(store.indexed::<Faction>("id", &Value::Int(9)), Some(vec![]));
+
+        let mut engine = RuleEngine::new();
+        engine.register::<Faction>("Faction");
+        let allies = engine
+            .query("Faction(a, f), Faction(b, f), a != b", &store)
+            .unwrap();
+        assert_eq!(allies.len(), 4);
+        assert_eq!(engine.query("Faction(a, 8)", &store).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn ordered_indexes_serve_ranges() {
+        let mut store = EntityStore::new();
+        store.new_component::<Health>();
+        for (entity_id, hp) in [(1, 30), (2, 5), (3, 12), (4, 9)] {
+            store.add_component(entity_id, Health { hp });
+        }
+        assert!(store.index_range::<Health>("hp"));
+        store.add_component(5, Health { hp: 0 });
+        store.add_component(3, Health { hp: 10 });
+        store.remove_entity(2);
+        assert_eq!(store.in_range::<Health>("hp", ..10.0), Some(vec![5, 4]));
+        assert_eq!(store.in_range::<Health>("hp", 9.0..=10.0), Some(vec![4, 3]));
+        assert_eq!(store.in_range::<Health>("hp", 20.0..1.0), Some(vec![]));
+
+        let mut engine = RuleEngine::new();
+        engine.register::<Health>("Health");
+        let count = |engine: &RuleEngine, query| engine.query(query, &store).unwrap().len();
+        assert_eq!(count(&engine, "Health(e, h), h < 10"), 2);
+        assert_eq!(count(&engine, "Health(e, h), 10 >= h, h > 0"), 2);
+        assert_eq!(count(&engine, "Health(e, _) where hp between 9 and 30"), 3);
+        assert_eq!(count(&engine, "Health(a, 10), Health(b, h), h > 10.5"), 1);
+    }
+
+    #[test]
+    fn refreshing_catches_changes_made_in_place() {
+        use crate::spatial::{Position, Rect, SpatialGrid, SpatialIndex};
+
+        let mut store = EntityStore::new();
+        store.new_component::<Health>();
+        store.new_component::<Position>();
+        for entity_id in 0..4 {
+            store.add_component(entity_id, Health { hp: 50 });
+            store.add_component(entity_id, Position::new(0, 0));
+        }
+        store.index_field::<Health>("hp");
+        store.index_range::<Health>("hp");
+        let grid = store.index_positions(SpatialGrid::new(10));
+
+        let health = store.get::<Health>().unwrap().clone();
+        let tick = health.borrow().tick();
+        health.borrow_mut().get_mut(2).unwrap().hp = 5;
+        let changed: Vec<_> = health
+            .borrow()
+            .changed_since(tick)
+            .map(|(&e, _)| e)
+            .collect();
+        assert_eq!(changed, vec![2]);
+        store
+            .get::<Position>()
+            .unwrap()
+            .borrow_mut()
+            .get_mut(3)
+            .unwrap()
+            .x = 95;
+        assert_eq!(store.in_range::<Health>("hp", ..10.0), Some(vec![]));
+
+        store.refresh_indexes();
+        assert_eq!(store.in_range::<Health>("hp", ..10.0), Some(vec![2]));
+        assert_eq!(
+            store.indexed::<Health>("hp", &Value::Int(50)),
+            Some(vec![0, 1, 3])
+        );
+        assert_eq!(
+            grid.borrow().in_rect(Rect::around(Position::new(90, 0), 5)),
+            vec![3]
+        );
+    }
+}
@@ -0,0 +1,259 @@
+// A debugging panel for a world, drawn with egui
+//
+// With the inspector feature, Inspector draws the live entities, the components on
+// the selected one, the agenda and the latest firings into any egui Ui, so a game can
+// put it in whatever window or side panel it likes, or open it as a window of its own.
+// Components are read through the registry, field names and all. Fields of reflected
+// components, see reflect.rs, holding numbers, bools or strings can be edited in place,
+// the rest are shown as the registry's values. Firings come from the engine's trace and
+// only show once one is enabled, see trace.rs.
+use crate::engine::RuleEngine;
+use crate::registry::ComponentInfo;
+use crate::store::{EntityId, EntityStore};
+use crate::value::Bindings;
+use egui::{CollapsingHeader, Context, DragValue, Grid, ScrollArea, Ui, Window};
+use std::any::Any;
+use std::collections::BTreeSet;
+
+// Firings listed, newest first
+const RECENT: usize = 20;
+
+#[derive(Debug, Default)]
+pub struct Inspector {
+    pub selected: Option<EntityId>,
+    // Only entities whose id or components contain this are listed
+    pub filter: String,
+}
+
+fn format_bindings(bindings: &Bindings) -> String {
+    let pairs: Vec<String> = bindings
+        .iter()
+        .map(|(name, value)| format!("{} = {}", name, value))
+        .collect();
+    pairs.join(", ")
+}
+
+// Edits the field if it's a type with a widget, false if it isn't
+fn edit(ui: &mut Ui, value: &mut dyn Any) -> bool {
+    if let Some(value) = value.downcast_mut::<i64>() {
+        ui.add(DragValue::new(value));
+    } else if let Some(value) = value.downcast_mut::<i32>() {
+        ui.add(DragValue::new(value));
+    } else if let Some(value) = value.downcast_mut::<u32>() {
+        ui.add(DragValue::new(value));
+    } else if let Some(value) = value.downcast_mut::<usize>() {
+        ui.add(DragValue::new(value));
+    } else if let Some(value) = value.downcast_mut::<f64>() {
+        ui.add(DragValue::new(value).speed(0.1));
+    } else if let Some(value) = value.downcast_mut::<f32>() {
+        ui.add(DragValue::new(value).speed(0.1));
+    } else if let Some(value) = value.downcast_mut::<bool>() {
+        ui.checkbox(value, "");
+    } else if let Some(value) = value.downcast_mut::<String>() {
+        ui.text_edit_singleline(value);
+    } else {
+        return false;
+    }
+    true
+}
+
+impl Inspector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Every entity with a component, in id order
+    pub fn entities(store: &EntityStore) -> Vec<EntityId> {
+        let entities: BTreeSet<EntityId> = store
+            .pool_refs
+            .0
+            .iter()
+            .flat_map(|pool| pool.borrow().presence().entities())
+            .collect();
+        entities.into_iter().collect()
+    }
+
+    // Opens the inspector as a window of its own
+    pub fn window(&mut self, ctx: &Context, engine: &RuleEngine, store: &EntityStore) {
+        Window::new("World")
+            .default_width(420.0)
+            .show(ctx, |ui| self.ui(ui, engine, store));
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui, engine: &RuleEngine, store: &EntityStore) {
+        CollapsingHeader::new("Entities")
+            .default_open(true)
+            .show(ui, |ui| self.entities_ui(ui, engine, store));
+        if let Some(entity_id) = self.selected {
+            CollapsingHeader::new(format!("Entity #{}", entity_id))
+                .default_open(true)
+                .show(ui, |ui| self.components_ui(ui, engine, store, entity_id));
+        }
+        CollapsingHeader::new("Agenda")
+            .default_open(true)
+            .show(ui, |ui| Self::agenda_ui(ui, engine, store));
+        CollapsingHeader::new("Recent firings").show(ui, |ui| Self::firings_ui(ui, engine));
+    }
+
+    fn names(engine: &RuleEngine, store: &EntityStore, entity_id: EntityId) -> Vec<String> {
+        engine
+            .registry()
+            .iter()
+            .filter(|info| !info.is_derived() && info.get(store, entity_id).is_some())
+            .map(|info| info.name.to_string())
+            .collect()
+    }
+
+    fn entities_ui(&mut self, ui: &mut Ui, engine: &RuleEngine, store: &EntityStore) {
+        ui.horizontal(|ui| {
+            ui.label("Filter");
+            ui.text_edit_singleline(&mut self.filter);
+        });
+        ScrollArea::vertical()
+            .id_salt("entities")
+            .max_height(200.0)
+            .show(ui, |ui| {
+                for entity_id in Self::entities(store) {
+                    let label = format!(
+                        "#{} {}",
+                        entity_id,
+                        Self::names(engine, store, entity_id).join(" ")
+                    );
+                    if !label.contains(self.filter.trim()) {
+                        continue;
+                    }
+                    let selected = self.selected == Some(entity_id);
+                    if ui.selectable_label(selected, label).clicked() {
+                        self.selected = (!selected).then_some(entity_id);
+                    }
+                }
+            });
+    }
+
+    fn components_ui(
+        &mut self,
+        ui: &mut Ui,
+        engine: &RuleEngine,
+        store: &EntityStore,
+        entity_id: EntityId,
+    ) {
+        let registry = engine.registry();
+        let infos: Vec<&ComponentInfo> =
+            registry.iter().filter(|info| !info.is_derived()).collect();
+        for info in infos {
+            let Some(values) = info.get(store, entity_id) else {
+                continue;
+            };
+            ui.strong(info.name.as_str());
+            Grid::new(("fields", info.name.as_str()))
+                .num_columns(2)
+                .show(ui, |ui| match registry.fields_of(info.type_id) {
+                    Some(fields) => {
+                        for field in fields {
+                            ui.label(field.name);
+                            match store.field_mut(registry, info.type_id, entity_id, field.name) {
+                                Ok(mut value) => {
+                                    if !edit(ui, &mut *value) {
+                                        ui.label(field.type_name);
+                                    }
+                                }
+                                Err(error) => {
+                                    ui.label(error.to_string());
+                                }
+                            }
+                            ui.end_row();
+                        }
+                    }
+                    None => {
+                        for (field, value) in info.fields.iter().zip(&values) {
+                            ui.label(*field);
+                            ui.label(value.to_string());
+                            ui.end_row();
+                        }
+                    }
+                });
+        }
+    }
+
+    fn agenda_ui(ui: &mut Ui, engine: &RuleEngine, store: &EntityStore) {
+        let activations = engine.activations(store);
+        if activations.is_empty() {
+            ui.label("Nothing to fire");
+        }
+        for activation in activations {
+            let rule = &engine.rules()[activation.rule].name;
+            ui.label(format!(
+                "\"{}\" {}",
+                rule,
+                format_bindings(&activation.bindings)
+            ));
+        }
+    }
+
+    fn firings_ui(ui: &mut Ui, engine: &RuleEngine) {
+        let Some(trace) = engine.trace() else {
+            ui.label("Enable the engine's trace to see firings");
+            return;
+        };
+        for firing in trace.log().iter().rev().take(RECENT) {
+            ui.label(firing.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reflect;
+    use crate::registry::Fact;
+    use crate::store::Component;
+    use crate::value::Value;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Health(i64);
+    impl Component for Health {}
+    reflect!(Health { 0: i64 });
+
+    impl Fact for Health {
+        const FIELDS: &'static [&'static str] = &["hp"];
+
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.0)]
+        }
+
+        fn from_values(values: &[Value]) -> Option<Self> {
+            Some(Health(values.first()?.as_int()?))
+        }
+    }
+
+    #[test]
+    fn panel_draws_the_selected_entity() {
+        let mut engine = RuleEngine::new();
+        engine.register::<Health>("Health");
+        engine.reflect::<Health>();
+        engine
+            .load_str(r#"rule "low" when Health(e, h), h < 10 then remove Health(e)"#)
+            .unwrap();
+        engine.enable_trace();
+        let mut store = EntityStore::new();
+        store.new_component::<Health>();
+        store.add_component(3, Health(5));
+        store.add_component(8, Health(50));
+        assert_eq!(Inspector::entities(&store), vec![3, 8]);
+
+        let mut inspector = Inspector::new();
+        inspector.selected = Some(8);
+        let ctx = Context::default();
+        let draw = |inspector: &mut Inspector, engine: &RuleEngine, store: &EntityStore| {
+            let output = ctx.run(Default::default(), |ctx| {
+                inspector.window(ctx, engine, store)
+            });
+            output.shapes.len()
+        };
+        assert!(draw(&mut inspector, &engine, &store) > 0);
+        engine.run(&mut store);
+        assert_eq!(Inspector::entities(&store), vec![8]);
+        assert_eq!(engine.trace().unwrap().log().len(), 1);
+        assert!(draw(&mut inspector, &engine, &store) > 0);
+    }
+}
@@ -0,0 +1,171 @@
+// Spans for the tracing crate, behind the tracing feature
+//
+// Once enabled, the engine opens spans for whatever subscriber the host has set up: one
+// per run or step, `tick`, with the tick number; one per stage a tick goes through,
+// `system`, named actions, expire, fuzzy, propagate, observe, react, views or match;
+// and one per firing, `fire`, with the rule, the entities bound and the bindings written
+// out, recording what the firing inserted and removed once it's done. Firings sit
+// inside their tick's span, so a subscriber's tree shows a tick the way the trace log
+// does, see trace.rs.
+//
+// Spans are created with the rete target, at info for ticks and debug for the rest, so
+// the usual filters pick how much to see. Disabling spans again leaves the subscriber
+// alone and costs the engine nothing per firing.
+use crate::engine::{Activation, RuleEngine};
+use crate::trace::Mutation;
+use crate::value::Value;
+use tracing::field::Empty;
+use tracing::span::EnteredSpan;
+
+impl RuleEngine {
+    pub fn enable_spans(&mut self) {
+        self.spans = true;
+    }
+
+    pub fn disable_spans(&mut self) {
+        self.spans = false;
+    }
+
+    pub fn spans(&self) -> bool {
+        self.spans
+    }
+
+    pub(crate) fn tick_span(&self) -> Option<EnteredSpan> {
+        self.spans
+            .then(|| tracing::info_span!(target: "rete", "tick", tick = self.tick).entered())
+    }
+
+    pub(crate) fn system_span(&self, system: &'static str) -> Option<EnteredSpan> {
+        self.spans
+            .then(|| tracing::debug_span!(target: "rete", "system", system).entered())
+    }
+
+    pub(crate) fn fire_span(&self, activation: &Activation) -> Option<EnteredSpan> {
+        if !self.spans {
+            return None;
+        }
+        let entities: Vec<String> = activation
+            .bindings
+            .values()
+            .filter_map(|value| match value {
+                Value::Entity(entity_id) => Some(entity_id.to_string()),
+                _ => None,
+            })
+            .collect();
+        let bindings: Vec<String> = activation
+            .bindings
+            .iter()
+            .map(|(name, value)| format!("{} = {}", name, value))
+            .collect();
+        let span = tracing::debug_span!(
+            target: "rete",
+            "fire",
+            rule = self.rules[activation.rule].name.as_str(),
+            entities = entities.join(", "),
+            bindings = bindings.join(", "),
+            inserted = Empty,
+            removed = Empty,
+        );
+        Some(span.entered())
+    }
+}
+
+// Records what the firing changed on its span
+pub(crate) fn record_fired(span: &EnteredSpan, mutations: &[Mutation]) {
+    let inserted = mutations
+        .iter()
+        .filter(|mutation| matches!(mutation, Mutation::Inserted { .. }))
+        .count();
+    span.record("inserted", inserted);
+    span.record("removed", mutations.len() - inserted);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::EntityStore;
+    use std::fmt;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    // Writes each span out as its name and fields, in the order they're created
+    #[derive(Clone, Default)]
+    struct Spans(Arc<Mutex<Vec<String>>>);
+
+    struct Line<'a>(&'a mut String);
+
+    impl Visit for Line<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.push_str(&format!(" {}={:?}", field.name(), value));
+        }
+    }
+
+    impl Subscriber for Spans {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut spans = self.0.lock().unwrap();
+            let mut line = span.metadata().name().to_string();
+            span.record(&mut Line(&mut line));
+            spans.push(line);
+            Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, span: &Id, values: &Record<'_>) {
+            let mut spans = self.0.lock().unwrap();
+            values.record(&mut Line(&mut spans[span.into_u64() as usize - 1]));
+        }
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, _: &Event<'_>) {}
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    #[test]
+    fn spans_cover_ticks_systems_and_firings() {
+        let mut engine = RuleEngine::new();
+        engine
+            .load_str(
+                r#"
+                fact Health(points)
+                fact Fleeing()
+                rule "flee" when Health(e, h), h < 10, not Fleeing(e) then insert Fleeing(e)
+                "#,
+            )
+            .unwrap();
+        let registry = engine.registry().clone();
+        let mut store = EntityStore::new();
+        store
+            .insert_fact(&registry, "Health", 1, &[Value::Int(5)])
+            .unwrap();
+        let spans = Spans::default();
+
+        tracing::subscriber::with_default(spans.clone(), || engine.run(&mut store));
+        assert!(spans.0.lock().unwrap().is_empty());
+
+        engine.enable_spans();
+        store
+            .insert_fact(&registry, "Health", 2, &[Value::Int(7)])
+            .unwrap();
+        tracing::subscriber::with_default(spans.clone(), || engine.run(&mut store));
+        let spans = spans.0.lock().unwrap();
+        assert_eq!(spans[0], "tick tick=1");
+        assert!(spans.contains(&"system system=\"expire\"".to_string()));
+        let fired: Vec<&String> = spans
+            .iter()
+            .filter(|span| span.starts_with("fire"))
+            .collect();
+        assert_eq!(
+            fired,
+            ["fire rule=\"flee\" entities=\"2\" bindings=\"e = #2, h = 7\" inserted=1 removed=0"]
+        );
+    }
+}
@@ -0,0 +1,337 @@
+// Sending each client only the part of the world it should see
+//
+// Clients sits on a Replicator, see replicate.rs, and gives every connected client a
+// frame of its own each tick. A client's Interest can limit it to some components, and
+// to entities whose Position is inside a rectangle. An entity spawns for a client when
+// it first has something the client is interested in, in view, and despawns when it
+// no longer has or leaves, so a client never holds more than it can see. Its frames are
+// grouped by entity, in the order the entities were first changed that tick.
+//
+// Entities come into and out of view as their Position changes, which only shows in
+// the stream when Position is registered, so a region needs it registered.
+use crate::error::Error;
+use crate::registry::Registry;
+use crate::replicate::{Delta, Frame, Replicator};
+use crate::spatial::{Position, Rect};
+use crate::store::{EntityId, EntityStore};
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+pub type ClientId = u64;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Interest {
+    // None for every registered component
+    pub components: Option<HashSet<String>>,
+    // None for everywhere, entities without a Position included
+    pub region: Option<Rect>,
+}
+
+impl Interest {
+    // Everything
+    pub fn all() -> Self {
+        Self::default()
+    }
+
+    pub fn only(mut self, components: &[&str]) -> Self {
+        self.components = Some(components.iter().map(|name| name.to_string()).collect());
+        self
+    }
+
+    pub fn within(mut self, region: Rect) -> Self {
+        self.region = Some(region);
+        self
+    }
+
+    fn wants(&self, component: &str) -> bool {
+        self.components
+            .as_ref()
+            .is_none_or(|components| components.contains(component))
+    }
+
+    fn sees(&self, store: &EntityStore, entity_id: EntityId) -> bool {
+        let Some(region) = self.region else {
+            return true;
+        };
+        let Some(positions) = store.get::<Position>() else {
+            return false;
+        };
+        let seen = positions
+            .borrow()
+            .get(entity_id)
+            .is_some_and(|position| region.contains(position));
+        seen
+    }
+}
+
+struct Client {
+    interest: Interest,
+    // The components the client has of each entity it can see
+    holds: HashMap<EntityId, BTreeSet<String>>,
+}
+
+impl Client {
+    // What the client needs to catch up on the entity, changed holding the components
+    // whose fields changed since it was last told
+    fn reconcile(
+        &mut self,
+        registry: &Registry,
+        store: &EntityStore,
+        entity_id: EntityId,
+        changed: &HashSet<&str>,
+        deltas: &mut Vec<Delta>,
+    ) {
+        let wanted: BTreeSet<String> = match self.interest.sees(store, entity_id) {
+            true => registry
+                .iter()
+                .filter(|info| !info.is_derived() && self.interest.wants(&info.name))
+                .filter(|info| info.get(store, entity_id).is_some())
+                .map(|info| info.name.to_string())
+                .collect(),
+            false => BTreeSet::new(),
+        };
+        let held = self.holds.remove(&entity_id).unwrap_or_default();
+        if held.is_empty() && !wanted.is_empty() {
+            deltas.push(Delta::Spawn(entity_id));
+        }
+        for component in held.difference(&wanted) {
+            deltas.push(Delta::Remove {
+                component: component.clone(),
+                entity: entity_id,
+            });
+        }
+        for component in &wanted {
+            let fresh = !held.contains(component);
+            if !fresh && !changed.contains(component.as_str()) {
+                continue;
+            }
+            let values = registry
+                .get(component)
+                .and_then(|info| info.get(store, entity_id))
+                .unwrap_or_default();
+            deltas.push(match fresh {
+                true => Delta::Insert {
+                    component: component.clone(),
+                    entity: entity_id,
+                    values,
+                },
+                false => Delta::Change {
+                    component: component.clone(),
+                    entity: entity_id,
+                    values,
+                },
+            });
+        }
+        match wanted.is_empty() {
+            true if !held.is_empty() => deltas.push(Delta::Despawn(entity_id)),
+            true => {}
+            false => {
+                self.holds.insert(entity_id, wanted);
+            }
+        }
+    }
+
+    // Catches the client up on every entity, for a new client or a new interest
+    fn reconcile_all(&mut self, registry: &Registry, store: &EntityStore) -> Vec<Delta> {
+        let mut entities: BTreeSet<EntityId> = self.holds.keys().copied().collect();
+        for info in registry.iter().filter(|info| !info.is_derived()) {
+            entities.extend(
+                info.facts(store)
+                    .into_iter()
+                    .map(|(entity_id, _)| entity_id),
+            );
+        }
+        let mut deltas = Vec::new();
+        for entity_id in entities {
+            self.reconcile(registry, store, entity_id, &HashSet::new(), &mut deltas);
+        }
+        deltas
+    }
+}
+
+pub struct Clients {
+    replicator: Replicator,
+    clients: HashMap<ClientId, Client>,
+    next: ClientId,
+}
+
+impl Clients {
+    // Turns on change tracking, as Replicator::new
+    pub fn new(registry: &Registry, store: &mut EntityStore) -> Self {
+        Clients {
+            replicator: Replicator::new(registry, store),
+            clients: HashMap::new(),
+            next: 0,
+        }
+    }
+
+    // Adds a client, with the frame that brings it up to date
+    pub fn connect(
+        &mut self,
+        interest: Interest,
+        registry: &Registry,
+        store: &EntityStore,
+    ) -> (ClientId, Frame) {
+        let id = self.next;
+        self.next += 1;
+        let mut client = Client {
+            interest,
+            holds: HashMap::new(),
+        };
+        let frame = Frame {
+            tick: self.replicator.tick(),
+            deltas: client.reconcile_all(registry, store),
+        };
+        self.clients.insert(id, client);
+        (id, frame)
+    }
+
+    pub fn disconnect(&mut self, client: ClientId) {
+        self.clients.remove(&client);
+    }
+
+    // Changes what the client sees, with the frame that brings it in line
+    pub fn set_interest(
+        &mut self,
+        client: ClientId,
+        interest: Interest,
+        registry: &Registry,
+        store: &EntityStore,
+    ) -> Result<Frame, Error> {
+        let client = self
+            .clients
+            .get_mut(&client)
+            .ok_or_else(|| Error::Request(format!("no client {}", client)))?;
+        client.interest = interest;
+        Ok(Frame {
+            tick: self.replicator.tick(),
+            deltas: client.reconcile_all(registry, store),
+        })
+    }
+
+    // The changes since the last frame, as each client should see them
+    pub fn frames(&mut self, registry: &Registry, store: &EntityStore) -> HashMap<ClientId, Frame> {
+        let frame = self.replicator.frame(registry, store);
+        let mut touched: Vec<EntityId> = Vec::new();
+        let mut changed: HashMap<EntityId, HashSet<&str>> = HashMap::new();
+        for delta in &frame.deltas {
+            let (entity_id, component) = match delta {
+                Delta::Spawn(entity_id) | Delta::Despawn(entity_id) => (*entity_id, None),
+                Delta::Insert {
+                    component, entity, ..
+                }
+                | Delta::Change {
+                    component, entity, ..
+                } => (*entity, Some(component.as_str())),
+                Delta::Remove { entity, .. } => (*entity, None),
+            };
+            let components = changed.entry(entity_id).or_insert_with(|| {
+                touched.push(entity_id);
+                HashSet::new()
+            });
+            components.extend(component);
+        }
+        self.clients
+            .iter_mut()
+            .map(|(&id, client)| {
+                let mut deltas = Vec::new();
+                for entity_id in &touched {
+                    let changed = &changed[entity_id];
+                    client.reconcile(registry, store, *entity_id, changed, &mut deltas);
+                }
+                let frame = Frame {
+                    tick: frame.tick,
+                    deltas,
+                };
+                (id, frame)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::WorkingMemory;
+    use crate::registry::Fact;
+    use crate::store::Component;
+    use crate::value::Value;
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Health(i64);
+    impl Component for Health {}
+
+    impl Fact for Health {
+        const FIELDS: &'static [&'static str] = &["hp"];
+
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.0)]
+        }
+
+        fn from_values(values: &[Value]) -> Option<Self> {
+            Some(Health(values.first()?.as_int()?))
+        }
+    }
+
+    #[test]
+    fn clients_see_their_own_slice() {
+        let mut engine = crate::engine::RuleEngine::new();
+        engine.register::<Health>("Health");
+        engine.register::<Position>("Position");
+        let registry = engine.registry().clone();
+        let mut memory = WorkingMemory::new(engine);
+        memory.assert_fact(1, Health(10));
+        memory.assert_fact(1, Position::new(0, 0));
+        memory.assert_fact(2, Health(5));
+        memory.assert_fact(2, Position::new(50, 50));
+        let mut clients = Clients::new(&registry, memory.store_mut());
+
+        let near = Interest::all()
+            .only(&["Health"])
+            .within(Rect::around(Position::new(0, 0), 10));
+        let (nearby, joined) = clients.connect(near, &registry, memory.store());
+        let health = |entity, hp| Delta::Insert {
+            component: "Health".to_string(),
+            entity,
+            values: vec![Value::Int(hp)],
+        };
+        assert_eq!(joined.deltas, vec![Delta::Spawn(1), health(1, 10)]);
+        let mut peer = EntityStore::new();
+        peer.new_component::<Health>();
+        peer.new_component::<Position>();
+        registry.apply_frame(&mut peer, &joined).unwrap();
+        let (everyone, joined) = clients.connect(Interest::all(), &registry, memory.store());
+        assert_eq!(joined.deltas.len(), 6);
+
+        memory.assert_fact(2, Position::new(3, 3));
+        memory.assert_fact(1, Position::new(40, 0));
+        let frames = clients.frames(&registry, memory.store());
+        assert_eq!(
+            frames[&nearby].deltas,
+            vec![
+                Delta::Spawn(2),
+                health(2, 5),
+                Delta::Remove {
+                    component: "Health".to_string(),
+                    entity: 1
+                },
+                Delta::Despawn(1),
+            ]
+        );
+        assert_eq!(frames[&everyone].deltas.len(), 2);
+        registry.apply_frame(&mut peer, &frames[&nearby]).unwrap();
+
+        let wide = Interest::all().within(Rect::around(Position::new(0, 0), 100));
+        let frame = clients
+            .set_interest(nearby, wide, &registry, memory.store())
+            .unwrap();
+        registry.apply_frame(&mut peer, &frame).unwrap();
+        assert_eq!(
+            registry.encode_store(&peer),
+            registry.encode_store(memory.store())
+        );
+        clients.disconnect(everyone);
+        assert!(clients
+            .set_interest(everyone, Interest::all(), &registry, memory.store())
+            .is_err());
+    }
+}
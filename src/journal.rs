@@ -0,0 +1,287 @@
+// Undo and redo of store changes
+//
+// While the journal is on, every component added, replaced or removed through the
+// store is recorded along with what was there before, so undo can put it back. Like
+// snapshots, only types marked with snapshot_component are recorded, since undoing a
+// removal needs a copy of the removed component. Each call into the store is one step,
+// and the ones built from several changes, like remove_entity, place_tile or
+// set_parent, are a single step too. begin_step and end_step group any run of calls
+// into one step. Undoing and redoing go through add_component and remove_component,
+// so hooks and change tracking follow along, and making a new change drops whatever
+// could have been redone.
+use crate::snapshot::PoolCopier;
+use crate::store::{Component, EntityId, EntityStore};
+use crate::tiles::Tile;
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+
+// Setting one entity's component, None if it had none
+struct Swap {
+    component: TypeId,
+    entity: EntityId,
+    value: Option<Box<dyn Any + Send + Sync>>,
+}
+
+#[derive(Default)]
+pub(crate) struct Journal {
+    undo: Vec<Vec<Swap>>,
+    redo: Vec<Vec<Swap>>,
+    // How many begin_steps are open
+    depth: usize,
+}
+
+impl fmt::Debug for Journal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Journal")
+            .field("undo", &self.undo.len())
+            .field("redo", &self.redo.len())
+            .finish()
+    }
+}
+
+impl Journal {
+    fn push(&mut self, swap: Swap) {
+        self.redo.clear();
+        match self.undo.last_mut() {
+            Some(step) if self.depth > 0 => step.push(swap),
+            _ => self.undo.push(vec![swap]),
+        }
+    }
+
+    // Records what was in a slot before a change, if its type is marked
+    pub(crate) fn record(
+        &mut self,
+        copiers: &HashMap<TypeId, PoolCopier>,
+        component: TypeId,
+        entity: EntityId,
+        previous: Option<Box<dyn Any + Send + Sync>>,
+    ) {
+        if copiers.contains_key(&component) {
+            self.push(Swap {
+                component,
+                entity,
+                value: previous,
+            });
+        }
+    }
+
+    // Records a removal, copying the removed component since the caller keeps it
+    pub(crate) fn record_removed(
+        &mut self,
+        copiers: &HashMap<TypeId, PoolCopier>,
+        component: TypeId,
+        entity: EntityId,
+        removed: &dyn Any,
+    ) {
+        if let Some(copier) = copiers.get(&component) {
+            self.push(Swap {
+                component,
+                entity,
+                value: (copier.clone_one)(removed),
+            });
+        }
+    }
+
+    pub(crate) fn begin_step(&mut self) {
+        if self.depth == 0 {
+            self.undo.push(Vec::new());
+        }
+        self.depth += 1;
+    }
+
+    pub(crate) fn end_step(&mut self) {
+        self.depth = self.depth.saturating_sub(1);
+        if self.depth == 0 && self.undo.last().is_some_and(Vec::is_empty) {
+            self.undo.pop();
+        }
+    }
+}
+
+pub(crate) fn clone_one<T: Component + Clone + 'static>(
+    component: &dyn Any,
+) -> Option<Box<dyn Any + Send + Sync>> {
+    let component: T = component.downcast_ref::<T>()?.clone();
+    Some(Box::new(component))
+}
+
+// Sets an entity's T to value, returning what was there
+pub(crate) fn put<T: Component + Eq + Clone + 'static>(
+    store: &mut EntityStore,
+    entity_id: EntityId,
+    value: Option<Box<dyn Any + Send + Sync>>,
+) -> Option<Box<dyn Any + Send + Sync>> {
+    let current = store
+        .get::<T>()
+        .and_then(|pool| pool.borrow().get(entity_id).cloned());
+    match value.and_then(|value| value.downcast::<T>().ok()) {
+        Some(value) => {
+            if store.get::<T>().is_none() {
+                store.new_component::<T>();
+            }
+            store.add_component(entity_id, *value);
+        }
+        None => store.remove_component::<T>(entity_id),
+    }
+    current.map(|current| Box::new(current) as Box<dyn Any + Send + Sync>)
+}
+
+impl EntityStore {
+    // Starts recording changes to marked types, with nothing to undo yet
+    pub fn start_journal(&mut self) {
+        self.journal = Some(Journal::default());
+    }
+
+    // Stops recording and forgets the history
+    pub fn stop_journal(&mut self) {
+        self.journal = None;
+    }
+
+    // Groups the changes until the matching end_step into one step
+    pub fn begin_step(&mut self) {
+        if let Some(journal) = &mut self.journal {
+            journal.begin_step();
+        }
+    }
+
+    pub fn end_step(&mut self) {
+        if let Some(journal) = &mut self.journal {
+            journal.end_step();
+        }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        self.journal
+            .as_ref()
+            .is_some_and(|journal| !journal.undo.is_empty())
+    }
+
+    pub fn can_redo(&self) -> bool {
+        self.journal
+            .as_ref()
+            .is_some_and(|journal| !journal.redo.is_empty())
+    }
+
+    // Undoes up to n steps, returning how many were undone
+    pub fn undo(&mut self, n: usize) -> usize {
+        self.replay_steps(n, false)
+    }
+
+    // Redoes up to n undone steps, returning how many were redone
+    pub fn redo(&mut self, n: usize) -> usize {
+        self.replay_steps(n, true)
+    }
+
+    // Pops steps off one stack, applies them, and pushes their inverses onto the other
+    fn replay_steps(&mut self, n: usize, redo: bool) -> usize {
+        // Taken out so the changes made here aren't recorded
+        let Some(mut journal) = self.journal.take() else {
+            return 0;
+        };
+        let mut done = 0;
+        let mut tiles_moved = false;
+        while done < n {
+            let step = if redo {
+                journal.redo.pop()
+            } else {
+                journal.undo.pop()
+            };
+            let Some(step) = step else {
+                break;
+            };
+            let mut inverse = Vec::with_capacity(step.len());
+            for swap in step.into_iter().rev() {
+                let Some(copier) = self.copiers.get(&swap.component).copied() else {
+                    continue;
+                };
+                tiles_moved |= swap.component == TypeId::of::<Tile>();
+                inverse.push(Swap {
+                    value: (copier.put)(self, swap.entity, swap.value),
+                    ..swap
+                });
+            }
+            if redo {
+                journal.undo.push(inverse);
+            } else {
+                journal.redo.push(inverse);
+            }
+            done += 1;
+        }
+        if tiles_moved {
+            self.reindex_tiles();
+        }
+        self.journal = Some(journal);
+        done
+    }
+
+    // Records the component an entity had before add_component replaced it, if journaling
+    pub(crate) fn journal_insert<T: Component + 'static>(
+        &mut self,
+        entity_id: EntityId,
+        previous: Option<T>,
+    ) {
+        if let Some(journal) = &mut self.journal {
+            let previous =
+                previous.map(|previous| Box::new(previous) as Box<dyn Any + Send + Sync>);
+            journal.record(&self.copiers, TypeId::of::<T>(), entity_id, previous);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Health(i64);
+
+    impl Component for Health {}
+
+    #[test]
+    fn undo_and_redo_step_through_changes() {
+        let mut store = EntityStore::new();
+        store.new_component::<Health>();
+        store.snapshot_component::<Health>();
+        store.start_journal();
+        let health = |store: &EntityStore, entity_id| {
+            store
+                .get::<Health>()
+                .unwrap()
+                .borrow()
+                .get(entity_id)
+                .cloned()
+        };
+
+        store.add_component(1, Health(10));
+        store.add_component(1, Health(5));
+        store.place_tile(1, Tile::new(0, 0));
+        store.place_tile(2, Tile::new(1, 0));
+        store.begin_step();
+        store.remove_tile(1);
+        store.remove_entity(1);
+        store.end_step();
+        assert!(store.neighbours(2).is_empty());
+
+        // The whole step comes back at once, neighbours included
+        assert_eq!(store.undo(1), 1);
+        assert_eq!(health(&store, 1), Some(Health(5)));
+        assert_eq!(store.tile_at(Tile::new(0, 0)), Some(1));
+        assert_eq!(store.neighbours(2), vec![1]);
+
+        assert_eq!(store.undo(3), 3);
+        assert_eq!(health(&store, 1), Some(Health(10)));
+        assert_eq!(store.tile_at(Tile::new(0, 0)), None);
+        assert_eq!(store.undo(5), 1);
+        assert_eq!(health(&store, 1), None);
+        assert!(!store.can_undo());
+
+        assert_eq!(store.redo(2), 2);
+        assert_eq!(health(&store, 1), Some(Health(5)));
+
+        // A new change drops the rest of the redo history
+        store.add_component(3, Health(30));
+        assert!(!store.can_redo());
+        assert_eq!(store.undo(1), 1);
+        assert_eq!(health(&store, 3), None);
+    }
+}
@@ -0,0 +1,183 @@
+// Human readable JSON dump of a store, for authoring worlds by hand and reading diffs
+//
+// Each entity is an object holding its id and one entry per registered component it
+// has, keyed by registered name, with the fields keyed by field name:
+//
+//   { "max_entity": 2, "entities": [
+//     { "id": 1, "Health": { "hp": 10 }, "Parent": { "parent": { "entity": 2 } } } ] }
+//
+// Entity fields are written as { "entity": id } so they can't be mistaken for ints.
+// Unlike the binary format, importing is strict: an unregistered component name, or a
+// field missing or left over, is an error, since the file was likely written by hand.
+use crate::error::Error;
+use crate::registry::{FactRow, Registry};
+use crate::store::{EntityId, EntityStore};
+use crate::value::Value;
+use serde_json::{json, Map, Number};
+use std::collections::BTreeMap;
+
+type Json = serde_json::Value;
+
+pub(crate) fn to_json(value: &Value) -> Json {
+    match value {
+        Value::Int(i) => json!(i),
+        Value::Float(f) => Number::from_f64(*f).map_or(Json::Null, Json::Number),
+        Value::Bool(b) => json!(b),
+        Value::Str(s) => json!(s),
+        Value::Entity(e) => json!({ "entity": e }),
+    }
+}
+
+pub(crate) fn from_json(json: &Json) -> Option<Value> {
+    Some(match json {
+        Json::Number(n) if n.is_f64() => Value::Float(n.as_f64()?),
+        Json::Number(n) => Value::Int(n.as_i64()?),
+        Json::Bool(b) => Value::Bool(*b),
+        Json::String(s) => Value::Str(s.clone()),
+        Json::Object(object) if object.len() == 1 => {
+            Value::Entity(object.get("entity")?.as_u64()?.try_into().ok()?)
+        }
+        _ => return None,
+    })
+}
+
+fn invalid(message: String) -> Error {
+    Error::Decode(message)
+}
+
+impl Registry {
+    // Every entity with a registered component, in id order, pretty printed
+    pub fn export_json(&self, store: &EntityStore) -> String {
+        let mut entities: BTreeMap<EntityId, Map<String, Json>> = BTreeMap::new();
+        for info in self.iter().filter(|info| !info.is_derived()) {
+            for (entity_id, values) in info.facts(store) {
+                let fields = info
+                    .fields
+                    .iter()
+                    .zip(&values)
+                    .map(|(field, value)| (field.to_string(), to_json(value)))
+                    .collect();
+                entities
+                    .entry(entity_id)
+                    .or_default()
+                    .insert(info.name.clone(), Json::Object(fields));
+            }
+        }
+        let entities: Vec<Json> = entities
+            .into_iter()
+            .map(|(entity_id, components)| {
+                let mut object = Map::new();
+                object.insert("id".to_string(), json!(entity_id));
+                object.extend(components);
+                Json::Object(object)
+            })
+            .collect();
+        let world = json!({ "max_entity": store.max_entity(), "entities": entities });
+        serde_json::to_string_pretty(&world).unwrap()
+    }
+
+    // Builds a store from export_json's format
+    pub fn import_json(&self, text: &str) -> Result<EntityStore, Error> {
+        let world: Json = serde_json::from_str(text).map_err(|e| invalid(e.to_string()))?;
+        let entities = world["entities"]
+            .as_array()
+            .ok_or_else(|| invalid("`entities` should be a list".to_string()))?;
+
+        let mut sections: BTreeMap<&str, Vec<FactRow>> = BTreeMap::new();
+        let mut max_entity = world["max_entity"].as_u64().unwrap_or(0) as EntityId;
+        for entity in entities {
+            let object = entity
+                .as_object()
+                .ok_or_else(|| invalid("entities should be objects".to_string()))?;
+            let entity_id = object
+                .get("id")
+                .and_then(Json::as_u64)
+                .ok_or_else(|| invalid(format!("entity has no id: {}", entity)))?
+                as EntityId;
+            max_entity = max_entity.max(entity_id);
+            for (name, fields) in object.iter().filter(|(key, _)| *key != "id") {
+                let info = self
+                    .get(name)
+                    .filter(|info| !info.is_derived())
+                    .ok_or_else(|| Error::UnknownComponent(name.clone()))?;
+                let fields = fields.as_object().ok_or_else(|| {
+                    invalid(format!(
+                        "`{}` of entity {} isn't an object",
+                        name, entity_id
+                    ))
+                })?;
+                if fields.len() != info.fields.len() {
+                    return Err(invalid(format!(
+                        "`{}` of entity {} should have fields {:?}",
+                        name, entity_id, info.fields
+                    )));
+                }
+                let values = info
+                    .fields
+                    .iter()
+                    .map(|field| fields.get(*field).and_then(from_json))
+                    .collect::<Option<Vec<Value>>>()
+                    .ok_or_else(|| {
+                        invalid(format!(
+                            "`{}` of entity {} should have fields {:?}",
+                            name, entity_id, info.fields
+                        ))
+                    })?;
+                sections
+                    .entry(info.name.as_str())
+                    .or_default()
+                    .push((entity_id, values));
+            }
+        }
+
+        let mut store = EntityStore::new();
+        store.reserve_up_to(max_entity);
+        let sections = sections
+            .into_iter()
+            .map(|(name, rows)| (self.get(name).unwrap(), rows))
+            .collect();
+        Registry::insert_rows(&mut store, sections).map_err(invalid)?;
+        Ok(store)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tiles::Tile;
+
+    #[test]
+    fn json_round_trip_and_hand_written_worlds() {
+        let mut store = EntityStore::new();
+        store.place_tile(1, Tile::new(0, 0));
+        store.place_tile(2, Tile::new(1, 0));
+        store.set_parent(2, 1).unwrap();
+
+        let mut registry = Registry::new();
+        registry.register_tiles("Tile", "AdjacentTo");
+        registry.register_hierarchy("Parent");
+        let text = registry.export_json(&store);
+        assert!(text.contains(r#""parent": {"#) && !text.contains("AdjacentTo"));
+
+        let restored = registry.import_json(&text).unwrap();
+        assert_eq!(registry.export_json(&restored), text);
+        assert_eq!(restored.neighbours(1), vec![2]);
+
+        let written = r#"{ "entities": [
+            { "id": 4, "Tile": { "x": 0, "y": 1 } },
+            { "id": 5, "Tile": { "x": 0, "y": 2 }, "Parent": { "parent": { "entity": 4 } } }
+        ] }"#;
+        let world = registry.import_json(written).unwrap();
+        assert_eq!(world.max_entity(), 5);
+        assert_eq!(world.neighbours(4), vec![5]);
+        assert_eq!(world.children(4), vec![5]);
+
+        let typo = written.replace("Parent", "Parnet");
+        assert_eq!(
+            registry.import_json(&typo).unwrap_err(),
+            Error::UnknownComponent("Parnet".to_string())
+        );
+        let missing = written.replace(r#""y": 2"#, r#""z": 2"#);
+        assert!(registry.import_json(&missing).is_err());
+    }
+}
@@ -0,0 +1,155 @@
+// A rule engine built on top of a sparse set entity-component store.
+// Components are facts, rules match patterns over them and insert or remove components in response.
+
+// Extractor Pattern, semi-simply explained
+// https://blog.logrocket.com/rust-bevy-entity-component-system/
+
+// Spatial stuff using logic programming:
+// https://cgi.cse.unsw.edu.au/~eptcs/paper.cgi?ICLP2021.34.pdf
+
+// Lets rule! expansions refer to ::rete from inside this crate too
+extern crate self as rete;
+
+pub mod access;
+pub mod agenda;
+pub mod archetype;
+pub mod batch;
+pub mod binary;
+pub mod bitset;
+pub mod breakpoint;
+pub mod bundle;
+pub mod bus;
+pub mod cache;
+pub mod cell;
+pub mod cep;
+pub mod certainty;
+pub mod clone;
+pub mod closure;
+pub mod compact;
+pub mod constraint;
+pub mod defeasible;
+pub mod diff;
+pub mod dsl;
+pub mod dynamic;
+pub mod engine;
+pub mod error;
+pub mod events;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod fuzzy;
+pub mod group;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod hierarchy;
+pub mod hooks;
+pub mod incremental;
+pub mod index;
+#[cfg(feature = "inspector")]
+pub mod inspector;
+#[cfg(feature = "tracing")]
+pub mod instrument;
+pub mod interest;
+pub mod journal;
+#[cfg(feature = "json")]
+pub mod json;
+pub mod looping;
+pub mod magic;
+pub mod memory;
+pub mod merge;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod migrate;
+pub mod module;
+pub mod names;
+#[cfg(feature = "parallel")]
+pub mod parallel;
+pub mod path;
+#[cfg(feature = "serde")]
+pub mod persist;
+pub mod plan;
+#[cfg(feature = "plugins")]
+pub mod plugin;
+pub mod prefab;
+pub mod preview;
+pub mod probability;
+pub mod profile;
+pub mod provenance;
+pub mod quadtree;
+pub mod query;
+pub mod rcc8;
+pub mod reactive;
+pub mod reflect;
+pub mod registry;
+pub mod relation;
+pub mod reload;
+pub mod repl;
+pub mod replay;
+pub mod replicate;
+pub mod require;
+pub mod rule;
+#[cfg(feature = "scripting")]
+pub mod script;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod shadow;
+pub mod singleton;
+pub mod snapshot;
+pub mod solver;
+pub mod spatial;
+pub mod specificity;
+pub mod stats;
+pub mod store;
+pub mod subscribe;
+pub mod tabling;
+pub mod tags;
+#[cfg(feature = "tokio")]
+pub mod task;
+pub mod temporal;
+pub mod testkit;
+pub mod tiles;
+pub mod time;
+pub mod trace;
+pub mod ttl;
+pub mod value;
+pub mod view;
+pub mod wal;
+#[cfg(feature = "tokio")]
+pub mod watch;
+pub mod worlds;
+
+pub use access::PoolSet;
+pub use archetype::ArchetypeStore;
+pub use batch::ComponentBundle;
+pub use bitset::BitSet;
+pub use bundle::Bundle;
+pub use bus::EventBus;
+pub use cell::AtomicRefCell;
+pub use diff::WorldDelta;
+pub use dynamic::Dynamic;
+pub use engine::{Activation, RuleEngine};
+pub use error::Error;
+pub use events::{EventReader, EventWriter, Events};
+pub use memory::WorkingMemory;
+pub use names::Name;
+pub use path::{Graph, Path};
+pub use prefab::Prefab;
+pub use quadtree::QuadTree;
+pub use query::{Query, Solutions};
+pub use reflect::{Field, Reflect};
+pub use registry::{Fact, Registry, TypedFact};
+pub use relation::Relation;
+pub use reload::RuleWatcher;
+pub use repl::Repl;
+pub use replay::{Input, Recorder, Recording};
+pub use replicate::Replicator;
+pub use rete_macros::rule;
+pub use rule::Rule;
+pub use singleton::Uniqueness;
+pub use snapshot::WorldSnapshot;
+pub use spatial::{Position, SpatialGrid, SpatialIndex};
+pub use stats::{Stats, StoreEvent};
+pub use store::{Component, EntityId, EntityStore, Pool};
+pub use time::{Clock, Span};
+pub use trace::{Firing, Trace};
+pub use value::{Bindings, Value};
+pub use worlds::Worlds;
@@ -0,0 +1,101 @@
+// Keeping rules from looping
+//
+// A rule that updates a fact it matched, `rule "heal" when Health(e, h), h < 100 then
+// insert Health(e, h + 1)`, matches the new fact afresh and fires again. Two rule
+// attributes stop that, written after the rest of the header:
+//
+// `no-loop` keeps a rule from firing on a match that rests on a fact it inserted itself.
+// Once another rule, or the host, changes the fact, the rule can fire on it again.
+//
+// `lock-on-active` keeps a rule from firing on any match that wasn't ready when the
+// run began, so rules updating each other's facts take turns across runs instead of
+// firing each other forever within one. With step, the run lasts until nothing is ready.
+//
+// Both apply to rules matching on conditions, a reactive rule fires on every change.
+use crate::engine::{Activation, RuleEngine};
+use crate::provenance::Premise;
+use std::collections::HashSet;
+
+impl RuleEngine {
+    // Whether no-loop or lock-on-active hold the activation back, see above
+    pub(crate) fn held_back(&self, activation: &Activation) -> bool {
+        let rule = &self.rules[activation.rule];
+        let own = |premise: &Premise| {
+            let key = (
+                premise.component.clone(),
+                premise.entity,
+                premise.values.clone(),
+            );
+            self.provenance
+                .get(&key)
+                .is_some_and(|provenance| provenance.rule == rule.name)
+        };
+        let looped = rule.no_loop && activation.premises.iter().any(own);
+        let locked = rule.lock_on_active
+            && self
+                .locked
+                .as_ref()
+                .is_some_and(|(tick, ready)| *tick == self.tick && !ready.contains(activation));
+        looped || locked
+    }
+
+    // Notes which activations of lock-on-active rules are ready, once per run
+    pub(crate) fn lock(&mut self, activations: &[Activation]) {
+        if self
+            .locked
+            .as_ref()
+            .is_some_and(|(tick, _)| *tick == self.tick)
+        {
+            return;
+        }
+        let ready: HashSet<Activation> = activations
+            .iter()
+            .filter(|activation| self.rules[activation.rule].lock_on_active)
+            .cloned()
+            .collect();
+        self.locked = Some((self.tick, ready));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::RuleEngine;
+    use crate::store::EntityStore;
+    use crate::value::Value;
+
+    #[test]
+    fn loops_are_held_back() {
+        let mut engine = RuleEngine::new();
+        engine
+            .load_str(
+                r#"
+                fact Health(points)
+                fact Order(total)
+                rule "heal" no-loop when Health(e, h), h < 100 then insert Health(e, h + 10)
+                rule "discount" lock-on-active when Order(e, t), t > 100 then insert Order(e, t - 10)
+                rule "shipping" lock-on-active when Order(e, t) then insert Order(e, t + 5)
+                "#,
+            )
+            .unwrap();
+        let registry = engine.registry().clone();
+        let mut store = EntityStore::new();
+        store
+            .insert_fact(&registry, "Health", 1, &[Value::Int(50)])
+            .unwrap();
+        store
+            .insert_fact(&registry, "Order", 2, &[Value::Int(120)])
+            .unwrap();
+
+        // Healing once, and discounting, which leaves shipping nothing it was ready for
+        assert_eq!(engine.run(&mut store), 2);
+        let value = |store: &EntityStore, component: &str, entity_id| {
+            registry.get(component).unwrap().get(store, entity_id)
+        };
+        assert_eq!(value(&store, "Health", 1), Some(vec![Value::Int(60)]));
+        assert_eq!(value(&store, "Order", 2), Some(vec![Value::Int(110)]));
+
+        // Healing rests on its own fact, the discount comes round again
+        assert_eq!(engine.run(&mut store), 1);
+        assert_eq!(value(&store, "Order", 2), Some(vec![Value::Int(100)]));
+    }
+}
@@ -0,0 +1,388 @@
+// Goal directed evaluation by the magic sets rewrite
+//
+// Running every rule derives everything the rules can, when a query about one entity
+// may only need a sliver of it. magic_sets rewrites the rules for a goal so that a rule
+// deriving a component for an entity only runs once that entity is known to matter. Each
+// component derived for a known entity gets a magic component, a tag named
+// `magic:Component` on the entities it's wanted for. The rules deriving it are guarded
+// by its tag on the entity they insert on, and for every derived pattern in their
+// conditions whose entity is known by the time it's reached, a magic rule tags that
+// entity as wanted too. The goal's own patterns seed the tags from the entities it
+// names, as constants or bound variables.
+//
+// Only the entity argument is used to narrow, since components are held one per
+// entity. A derived component that's used without its entity known, under `not`, or by
+// a rule that can't be guarded, one inserting on a computed entity, removing anything
+// or inserting more than one component, is derived in full by its rules as written,
+// as is everything those rules use. Rules deriving nothing the goal needs are dropped.
+// Rules whose meaning depends on history or hands off elsewhere, reactive ones, ones
+// with temporal patterns, ones running something or ones posting to the solver, can't be
+// evaluated out of order, so a goal needing them is an error.
+//
+// solve_goal runs the rewritten rules over a copy of the store's registered facts,
+// leaving the store untouched, and answers the goal from the copy.
+use crate::engine::RuleEngine;
+use crate::error::Error;
+use crate::query::{Query, Solutions};
+use crate::registry::Registry;
+use crate::rule::{Action, Condition, Expr, Pattern, Rule, Term};
+use crate::store::EntityStore;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    // Wanted only for entities the tags name
+    Bound,
+    // Wanted for every entity
+    Free,
+}
+
+fn magic(component: &str) -> String {
+    format!("magic:{}", component)
+}
+
+fn term(entity: &Expr) -> Term {
+    match entity {
+        Expr::Const(value) => Term::Const(value.clone()),
+        Expr::Var(name) => Term::Var(name.clone()),
+        _ => Term::Wildcard,
+    }
+}
+
+fn tag(component: &str, entity: Term) -> Condition {
+    Condition::Pattern(Pattern::new(&magic(component), vec![entity]))
+}
+
+fn insert_tag(component: &str, entity: Expr) -> Action {
+    Action::Insert {
+        component: magic(component),
+        args: vec![entity],
+        ttl: None,
+    }
+}
+
+// The one component a rule inserts and the entity it inserts on, if that's all it does
+// and the entity is a variable or a constant
+fn head(rule: &Rule) -> Option<(&str, &Expr)> {
+    let [Action::Insert {
+        component, args, ..
+    }] = rule.actions.as_slice()
+    else {
+        return None;
+    };
+    let entity = args.first()?;
+    matches!(entity, Expr::Var(_) | Expr::Const(_)).then_some((component.as_str(), entity))
+}
+
+fn touched(rule: &Rule) -> impl Iterator<Item = &str> {
+    rule.actions.iter().filter_map(|action| match action {
+        Action::Insert { component, .. } | Action::Remove { component, .. } => {
+            Some(component.as_str())
+        }
+        Action::Run { .. } | Action::Post { .. } | Action::Minimize { .. } => None,
+    })
+}
+
+// Whether the rule needs more than the facts to mean the same thing
+fn historical(rule: &Rule) -> bool {
+    rule.trigger.is_some()
+        || rule
+            .actions
+            .iter()
+            .any(|action| !matches!(action, Action::Insert { .. } | Action::Remove { .. }))
+        || rule.conditions.iter().any(|condition| match condition {
+            Condition::Pattern(pattern) | Condition::Not(pattern) => pattern.temporal.is_some(),
+            _ => false,
+        })
+}
+
+fn is_known(term: &Term, bound: &HashSet<&str>) -> bool {
+    match term {
+        Term::Var(name) => bound.contains(name.as_str()),
+        Term::Const(_) => true,
+        Term::Wildcard => false,
+    }
+}
+
+// The variables bound once the condition has run
+fn binds(condition: &Condition) -> Vec<&str> {
+    match condition {
+        Condition::Pattern(pattern) => pattern.vars().collect(),
+        Condition::Spatial(predicate) => predicate
+            .entities()
+            .into_iter()
+            .filter_map(|term| match term {
+                Term::Var(name) => Some(name.as_str()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+// Each positive pattern in the conditions, with its position and whether its entity
+// is known by the time it's reached, starting from the variables bound
+fn patterns<'a>(
+    conditions: &'a [Condition],
+    mut bound: HashSet<&'a str>,
+) -> Vec<(usize, &'a Pattern, bool)> {
+    let mut patterns = Vec::new();
+    for (position, condition) in conditions.iter().enumerate() {
+        if let Condition::Pattern(pattern) = condition {
+            let known = pattern
+                .args
+                .first()
+                .is_some_and(|term| is_known(term, &bound));
+            patterns.push((position, pattern, known));
+        }
+        bound.extend(binds(condition));
+    }
+    patterns
+}
+
+// What a rule's head entity binds before its conditions run, when guarded
+fn head_bound(rule: &Rule) -> HashSet<&str> {
+    match head(rule) {
+        Some((_, Expr::Var(name))) => HashSet::from([name.as_str()]),
+        _ => HashSet::new(),
+    }
+}
+
+struct Rewrite<'a> {
+    rules: Vec<&'a Rule>,
+    derived: HashSet<&'a str>,
+    modes: HashMap<&'a str, Mode>,
+}
+
+impl<'a> Rewrite<'a> {
+    // Records a demand, true if it wanted more than before
+    fn want(&mut self, component: &'a str, mode: Mode) -> bool {
+        if !self.derived.contains(component) {
+            return false;
+        }
+        match (self.modes.get(component), mode) {
+            (Some(Mode::Free), _) | (Some(Mode::Bound), Mode::Bound) => false,
+            _ => {
+                self.modes.insert(component, mode);
+                true
+            }
+        }
+    }
+
+    fn guarded(&self, rule: &Rule) -> bool {
+        head(rule).is_some_and(|(component, _)| self.modes.get(component) == Some(&Mode::Bound))
+    }
+
+    // Spreads what is wanted through the rules until nothing more is
+    fn settle(&mut self) -> Result<(), Error> {
+        loop {
+            let mut wanted: Vec<(&'a str, Mode)> = Vec::new();
+            for &rule in &self.rules {
+                if !touched(rule).any(|component| self.modes.contains_key(component)) {
+                    continue;
+                }
+                if historical(rule) {
+                    return Err(Error::Magic(format!(
+                        "rule \"{}\" depends on more than the facts",
+                        rule.name
+                    )));
+                }
+                let guarded = self.guarded(rule);
+                if head(rule).is_none() {
+                    wanted.extend(touched(rule).map(|component| (component, Mode::Free)));
+                }
+                for condition in &rule.conditions {
+                    if let Condition::Not(pattern) = condition {
+                        wanted.push((&pattern.component, Mode::Free));
+                    }
+                }
+                let bound = match guarded {
+                    true => head_bound(rule),
+                    false => HashSet::new(),
+                };
+                for (_, pattern, known) in patterns(&rule.conditions, bound) {
+                    let mode = match guarded && known {
+                        true => Mode::Bound,
+                        false => Mode::Free,
+                    };
+                    wanted.push((&pattern.component, mode));
+                }
+            }
+            let mut changed = false;
+            for (component, mode) in wanted {
+                changed |= self.want(component, mode);
+            }
+            if !changed {
+                return Ok(());
+            }
+        }
+    }
+
+    // The rule as it runs in the rewritten program, guarded and with its magic rules
+    fn rewrite(&self, rule: &Rule) -> Vec<Rule> {
+        if !self.guarded(rule) {
+            return vec![rule.clone()];
+        }
+        let (component, entity) = head(rule).expect("guarded rules have a head");
+        let guard = tag(component, term(entity));
+        let mut rules = Vec::new();
+        for (position, pattern, known) in patterns(&rule.conditions, head_bound(rule)) {
+            if !known || self.modes.get(pattern.component.as_str()) != Some(&Mode::Bound) {
+                continue;
+            }
+            let mut wants = Rule::new(&format!("{}:{}", magic(&rule.name), position));
+            wants.module = rule.module.clone();
+            wants.conditions = std::iter::once(guard.clone())
+                .chain(rule.conditions[..position].iter().cloned())
+                .collect();
+            let entity = match &pattern.args[0] {
+                Term::Var(name) => Expr::Var(name.clone()),
+                Term::Const(value) => Expr::Const(value.clone()),
+                Term::Wildcard => unreachable!("known entities aren't wildcards"),
+            };
+            wants.actions = vec![insert_tag(&pattern.component, entity)];
+            rules.push(wants);
+        }
+        let mut guarded = rule.clone();
+        guarded.conditions.insert(0, guard);
+        rules.push(guarded);
+        rules
+    }
+}
+
+impl RuleEngine {
+    // An engine running just the rules the goal needs, rewritten as above
+    pub fn magic_sets(&self, goal: &Query) -> Result<RuleEngine, Error> {
+        let rules: Vec<&Rule> = self
+            .agenda()
+            .iter()
+            .map(|&index| &self.rules[index])
+            .collect();
+        let mut rewrite = Rewrite {
+            derived: rules.iter().flat_map(|rule| touched(rule)).collect(),
+            rules,
+            modes: HashMap::new(),
+        };
+
+        // The goal's patterns are only narrowed by entities it names itself
+        let mut seeds = Vec::new();
+        for condition in &goal.conditions {
+            match condition {
+                Condition::Pattern(pattern) => {
+                    let entity = match pattern.args.first() {
+                        Some(Term::Const(value)) => Some(value.clone()),
+                        Some(Term::Var(name)) => goal.bindings.get(name).cloned(),
+                        _ => None,
+                    };
+                    let mode = match entity {
+                        Some(_) => Mode::Bound,
+                        None => Mode::Free,
+                    };
+                    rewrite.want(&pattern.component, mode);
+                    seeds.extend(entity.map(|entity| (pattern.component.as_str(), entity)));
+                }
+                Condition::Not(pattern) => {
+                    rewrite.want(&pattern.component, Mode::Free);
+                }
+                _ => {}
+            }
+        }
+        rewrite.settle()?;
+
+        let mut registry: Registry = self.registry().clone();
+        let mut program = Vec::new();
+        for (component, entity) in seeds {
+            if rewrite.modes.get(component) == Some(&Mode::Bound) {
+                let mut seed = Rule::new(&format!("{}:goal:{}", magic(component), program.len()));
+                seed.actions = vec![insert_tag(component, Expr::Const(entity))];
+                program.push(seed);
+            }
+        }
+        for (component, mode) in &rewrite.modes {
+            if *mode == Mode::Bound {
+                registry.register_dynamic(&magic(component), &[])?;
+            }
+        }
+        for rule in &rewrite.rules {
+            if touched(rule).any(|component| rewrite.modes.contains_key(component)) {
+                program.extend(rewrite.rewrite(rule));
+            }
+        }
+
+        let mut engine = RuleEngine::with_registry(registry);
+        engine.spatial = self.spatial.clone();
+        engine.regions = self.regions.clone();
+        engine.rcc8 = self.rcc8.clone();
+        engine.replace_rules(program)?;
+        Ok(engine)
+    }
+
+    // Answers the goal deriving only what it needs, see above
+    pub fn solve_goal(&self, goal: &Query, store: &EntityStore) -> Result<Solutions, Error> {
+        let mut engine = self.magic_sets(goal)?;
+        let mut scratch = EntityStore::new();
+        self.registry().copy_facts(store, &mut scratch);
+        engine.run(&mut scratch);
+        engine.solve(goal, &scratch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::Value;
+
+    #[test]
+    fn goals_derive_only_what_they_need() {
+        let mut engine = RuleEngine::new();
+        engine
+            .load_str(
+                r#"
+                fact Next(node)
+                fact Last()
+                fact Distance(steps)
+                rule "end" when Last(e) then insert Distance(e, 0)
+                rule "step" when Next(e, n), Distance(n, d) then insert Distance(e, d + 1)
+                "#,
+            )
+            .unwrap();
+        let registry = engine.registry().clone();
+        let mut store = EntityStore::new();
+        // Two chains, 0 -> 1 -> ... -> 9 and 100 -> ... -> 149
+        for (first, last) in [(0, 9), (100, 149)] {
+            for node in first..last {
+                store
+                    .insert_fact(&registry, "Next", node, &[Value::Entity(node + 1)])
+                    .unwrap();
+            }
+            store.insert_fact(&registry, "Last", last, &[]).unwrap();
+        }
+
+        let goal = Query::parse("Distance(e, d)")
+            .unwrap()
+            .bind("e", Value::Entity(3));
+        let found: Vec<Value> = engine
+            .solve_goal(&goal, &store)
+            .unwrap()
+            .values("d")
+            .collect();
+        assert_eq!(found, [Value::Int(6)]);
+
+        let mut magic = engine.magic_sets(&goal).unwrap();
+        let mut scratch = EntityStore::new();
+        registry.copy_facts(&store, &mut scratch);
+        magic.run(&mut scratch);
+        let derived = registry.get("Distance").unwrap().facts(&scratch);
+        assert_eq!(derived.len(), 7);
+
+        let everything = Query::parse("Distance(e, d)").unwrap();
+        assert_eq!(engine.solve_goal(&everything, &store).unwrap().len(), 60);
+        engine.run(&mut store);
+        let expected = engine
+            .solve(&goal, &store)
+            .unwrap()
+            .values("d")
+            .collect::<Vec<_>>();
+        assert_eq!(expected, [Value::Int(6)]);
+    }
+}
@@ -0,0 +1,201 @@
+// Working memory, the store and engine together behind production system verbs
+//
+// assert_fact, retract_fact and modify_fact change the store and note that the rules
+// need matching again. run only does the work when something has changed since the
+// last run, or the engine has work waiting on time, see RuleEngine::has_pending, so
+// callers can run every tick without paying for idle ticks.
+use crate::cell::AtomicRef;
+use crate::engine::RuleEngine;
+use crate::registry::Fact;
+use crate::store::{EntityId, EntityStore};
+
+#[derive(Debug, Default)]
+pub struct WorkingMemory {
+    store: EntityStore,
+    engine: RuleEngine,
+    // Facts changed since the rules last ran to quiescence
+    dirty: bool,
+}
+
+impl WorkingMemory {
+    pub fn new(engine: RuleEngine) -> Self {
+        WorkingMemory {
+            store: EntityStore::new(),
+            engine,
+            dirty: false,
+        }
+    }
+
+    // Wraps an existing store, its facts are matched on the first run
+    pub fn with_store(engine: RuleEngine, store: EntityStore) -> Self {
+        WorkingMemory {
+            store,
+            engine,
+            dirty: true,
+        }
+    }
+
+    pub fn store(&self) -> &EntityStore {
+        &self.store
+    }
+
+    // Changes made through the store directly aren't seen until mark_changed
+    pub fn store_mut(&mut self) -> &mut EntityStore {
+        &mut self.store
+    }
+
+    pub fn engine(&self) -> &RuleEngine {
+        &self.engine
+    }
+
+    // Changing the rules marks memory changed, new rules may match existing facts
+    pub fn engine_mut(&mut self) -> &mut RuleEngine {
+        self.dirty = true;
+        &mut self.engine
+    }
+
+    pub fn mark_changed(&mut self) {
+        self.dirty = true;
+    }
+
+    pub fn into_parts(self) -> (RuleEngine, EntityStore) {
+        (self.engine, self.store)
+    }
+
+    // Adds the fact to the entity, replacing any fact of the same type
+    pub fn assert_fact<T: Fact>(&mut self, entity_id: EntityId, fact: T) {
+        if self.store.get::<T>().is_none() {
+            self.store.new_component::<T>();
+        }
+        self.store.reserve_up_to(entity_id);
+        self.store.add_component(entity_id, fact);
+        self.dirty = true;
+    }
+
+    // Returns whether the entity had the fact
+    pub fn retract_fact<T: Fact>(&mut self, entity_id: EntityId) -> bool {
+        if !self.store.has_component::<T>(entity_id) {
+            return false;
+        }
+        self.store.remove_component::<T>(entity_id);
+        self.dirty = true;
+        true
+    }
+
+    // Changes the fact in place, returns false if the entity doesn't have one
+    pub fn modify_fact<T: Fact>(
+        &mut self,
+        entity_id: EntityId,
+        modify: impl FnOnce(&mut T),
+    ) -> bool {
+        let Some(pool) = self.store.get::<T>() else {
+            return false;
+        };
+        let mut pool = pool.borrow_mut();
+        let Some(fact) = pool.get_mut(entity_id) else {
+            return false;
+        };
+        modify(fact);
+        drop(pool);
+        // Seen by change tracking like a replace, the pool couldn't tell it apart
+        self.store.record_inserted::<T>(entity_id);
+        self.dirty = true;
+        true
+    }
+
+    pub fn fact<T: Fact>(&self, entity_id: EntityId) -> Option<AtomicRef<'_, T>> {
+        let pool = self.store.get::<T>()?.borrow();
+        AtomicRef::filter_map(pool, |pool| pool.get(entity_id)).ok()
+    }
+
+    // Fires rules until nothing new is ready, returning how many fired
+    // Does nothing if no facts have changed since the last run and nothing is pending
+    pub fn run(&mut self) -> usize {
+        if !self.dirty && !self.engine.has_pending() {
+            return 0;
+        }
+        self.dirty = false;
+        self.engine.run(&mut self.store)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Component;
+    use crate::time::Span;
+    use crate::value::Value;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Health(i64);
+    impl Component for Health {}
+    impl Fact for Health {
+        const FIELDS: &'static [&'static str] = &["value"];
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.0)]
+        }
+        fn from_values(values: &[Value]) -> Option<Self> {
+            match values {
+                [value] => Some(Health(value.as_int()?)),
+                _ => None,
+            }
+        }
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Fleeing;
+    impl Component for Fleeing {}
+    impl Fact for Fleeing {
+        const FIELDS: &'static [&'static str] = &[];
+        fn to_values(&self) -> Vec<Value> {
+            Vec::new()
+        }
+        fn from_values(values: &[Value]) -> Option<Self> {
+            values.is_empty().then_some(Fleeing)
+        }
+    }
+
+    #[test]
+    fn verbs_feed_the_rules() {
+        let mut engine = RuleEngine::new();
+        engine.register::<Health>("Health");
+        engine.register::<Fleeing>("Fleeing");
+        engine
+            .load_str(
+                r#"
+                rule "flee" when Health(e, h), h < 10, not Fleeing(e) then insert Fleeing(e)
+                rule "calm" when Fleeing(e), not Health(e, _) then remove Fleeing(e)
+                "#,
+            )
+            .unwrap();
+        let mut memory = WorkingMemory::new(engine);
+
+        memory.assert_fact(1, Health(50));
+        assert_eq!(memory.run(), 0);
+        assert!(memory.modify_fact(1, |health: &mut Health| health.0 -= 45));
+        assert_eq!(memory.run(), 1);
+        assert!(memory.fact::<Fleeing>(1).is_some());
+        assert_eq!(memory.run(), 0);
+
+        assert!(memory.retract_fact::<Health>(1));
+        assert!(!memory.retract_fact::<Health>(1));
+        assert_eq!(memory.run(), 1);
+        assert!(memory.fact::<Fleeing>(1).is_none());
+        assert!(!memory.modify_fact(1, |health: &mut Health| health.0 = 0));
+    }
+
+    #[test]
+    fn facts_expire_with_nothing_else_changing() {
+        let mut engine = RuleEngine::new();
+        engine.register::<Health>("Health");
+        let mut store = EntityStore::new();
+        assert!(engine.insert_for(&mut store, 1, Health(5), Span::Ticks(3)));
+        let mut memory = WorkingMemory::with_store(engine, store);
+
+        for _ in 0..4 {
+            memory.run();
+        }
+        assert!(memory.fact::<Health>(1).is_none());
+        assert!(!memory.engine().has_pending());
+    }
+}
@@ -0,0 +1,121 @@
+// Merging one store into another
+//
+// merge copies every entity of another store into this one under new ids, numbered on
+// from next_entity in the order of their old ids, and returns the old id to new id map,
+// so a level chunk authored on its own can be streamed into the running world. As in
+// compact.rs, components go across through the registry, with every entity field
+// rewritten to the new ids, so relations, parents and tiles arrive pointing at the
+// right entities and their reverse indexes, children and neighbours are built as they
+// go in. Fields pointing at entities the other store doesn't have are left as they
+// were, and components of unregistered types aren't copied at all.
+use crate::compact::Remap;
+use crate::error::Error;
+use crate::registry::{ComponentInfo, FactRow, Registry};
+use crate::store::{EntityId, EntityStore};
+use crate::value::Value;
+use std::collections::BTreeSet;
+
+impl EntityStore {
+    // Copies the other store's entities in under new ids, see above
+    // Fails, part way through, if a component won't go in, like a relation whose
+    // endpoint was outside the other store and lacks what it requires here
+    pub fn merge(&mut self, other: &EntityStore, registry: &Registry) -> Result<Remap, Error> {
+        let live: BTreeSet<EntityId> = other
+            .pool_refs
+            .0
+            .iter()
+            .flat_map(|pool| pool.borrow().presence().entities())
+            .collect();
+        let first = self.next_entity();
+        let remap: Remap = live
+            .iter()
+            .enumerate()
+            .map(|(offset, &old)| (old, first + offset))
+            .collect();
+        if let Some(&last) = remap.values().max() {
+            self.reserve_up_to(last);
+        }
+
+        let renumber = |entity_id: EntityId| remap.get(&entity_id).copied().unwrap_or(entity_id);
+        let sections: Vec<(&ComponentInfo, Vec<FactRow>)> = registry
+            .iter()
+            .filter(|info| !info.is_derived())
+            .map(|info| {
+                let rows = info
+                    .facts(other)
+                    .into_iter()
+                    .map(|(entity_id, values)| {
+                        let values = values
+                            .into_iter()
+                            .map(|value| match value {
+                                Value::Entity(target) => Value::Entity(renumber(target)),
+                                value => value,
+                            })
+                            .collect();
+                        (renumber(entity_id), values)
+                    })
+                    .collect();
+                (info, rows)
+            })
+            .collect();
+        self.begin_step();
+        let inserted = Registry::insert_rows(self, sections);
+        self.end_step();
+        inserted.map_err(Error::Merge)?;
+        self.reindex_tiles();
+        Ok(remap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::Fact;
+    use crate::store::Component;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Door(EntityId);
+    impl Component for Door {}
+
+    impl Fact for Door {
+        const FIELDS: &'static [&'static str] = &["leads_to"];
+
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Entity(self.0)]
+        }
+
+        fn from_values(values: &[Value]) -> Option<Self> {
+            Some(Door(values.first()?.as_entity()?))
+        }
+    }
+
+    #[test]
+    fn merged_entities_keep_their_references() {
+        let mut registry = Registry::new();
+        registry.register::<Door>("Door");
+        registry.register_hierarchy("Parent");
+
+        let mut world = EntityStore::new();
+        world.new_component::<Door>();
+        world.add_component(0, Door(1));
+        world.add_component(1, Door(0));
+
+        let mut chunk = EntityStore::new();
+        chunk.new_component::<Door>();
+        chunk.add_component(10, Door(20));
+        chunk.add_component(20, Door(10));
+        chunk.add_component(30, Door(99));
+        chunk.set_parent(30, 20).unwrap();
+
+        let remap = world.merge(&chunk, &registry).unwrap();
+        assert_eq!((remap[&10], remap[&20], remap[&30]), (2, 3, 4));
+        let doors = world.get::<Door>().unwrap();
+        assert_eq!(doors.borrow().get(2), Some(&Door(3)));
+        assert_eq!(doors.borrow().get(3), Some(&Door(2)));
+        assert_eq!(doors.borrow().get(4), Some(&Door(99)));
+        assert_eq!(doors.borrow().get(0), Some(&Door(1)));
+        assert_eq!(world.parent(4), Some(3));
+        assert_eq!(world.children(3), vec![4]);
+        assert_eq!(world.next_entity(), 5);
+    }
+}
@@ -0,0 +1,260 @@
+// Prometheus metrics, behind the metrics feature
+//
+// Once enabled, the engine counts rule firings by rule, the facts rules insert and
+// remove, overall and in the last tick, and how long each tick took from its first run
+// or step to the one finding nothing left to fire. metrics_text writes those, along
+// with how many entities have registered facts and how many facts each component has,
+// in Prometheus' text format, to be scraped from wherever the host serves it. With the
+// server feature too, the server serves it at GET /metrics, see server.rs.
+use crate::engine::RuleEngine;
+use crate::store::EntityStore;
+use crate::trace::Mutation;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+// Upper bounds of the tick latency buckets, in seconds
+const BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    firings: BTreeMap<String, u64>,
+    asserted: u64,
+    retracted: u64,
+    // In the tick under way, moved to the last tick's once it ends
+    tick_asserted: u64,
+    tick_retracted: u64,
+    last_asserted: u64,
+    last_retracted: u64,
+    ticks: u64,
+    started: Option<Instant>,
+    // Ticks with a latency up to each bucket's bound, not cumulative
+    latency: [u64; BUCKETS.len()],
+    latency_sum: Duration,
+}
+
+impl Metrics {
+    pub(crate) fn start_tick(&mut self) {
+        self.started.get_or_insert_with(Instant::now);
+    }
+
+    pub(crate) fn end_tick(&mut self) {
+        let took = self
+            .started
+            .take()
+            .map_or(Duration::ZERO, |started| started.elapsed());
+        self.ticks += 1;
+        self.latency_sum += took;
+        if let Some(bucket) = BUCKETS
+            .iter()
+            .position(|&bound| took.as_secs_f64() <= bound)
+        {
+            self.latency[bucket] += 1;
+        }
+        self.last_asserted = std::mem::take(&mut self.tick_asserted);
+        self.last_retracted = std::mem::take(&mut self.tick_retracted);
+    }
+
+    pub(crate) fn fired(&mut self, rule: &str, mutations: &[Mutation]) {
+        *self.firings.entry(rule.to_string()).or_default() += 1;
+        for mutation in mutations {
+            match mutation {
+                Mutation::Inserted { .. } => {
+                    self.asserted += 1;
+                    self.tick_asserted += 1;
+                }
+                Mutation::Removed { .. } => {
+                    self.retracted += 1;
+                    self.tick_retracted += 1;
+                }
+            }
+        }
+    }
+}
+
+// Escapes a label value, see the text format's rules
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn header(text: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} {}", name, kind);
+}
+
+impl RuleEngine {
+    pub fn enable_metrics(&mut self) {
+        if self.metrics.is_none() {
+            self.metrics = Some(Metrics::default());
+        }
+    }
+
+    pub fn disable_metrics(&mut self) {
+        self.metrics = None;
+    }
+
+    // See above, None unless metrics are enabled
+    pub fn metrics_text(&self, store: &EntityStore) -> Option<String> {
+        let metrics = self.metrics.as_ref()?;
+        let mut text = String::new();
+
+        let mut entities = HashSet::new();
+        let mut facts = BTreeMap::new();
+        for info in self.registry().iter() {
+            let rows = info.facts(store);
+            entities.extend(rows.iter().map(|(entity_id, _)| *entity_id));
+            facts.insert(info.name.clone(), rows.len());
+        }
+        header(
+            &mut text,
+            "rete_entities",
+            "gauge",
+            "Entities with registered facts",
+        );
+        let _ = writeln!(text, "rete_entities {}", entities.len());
+        header(
+            &mut text,
+            "rete_facts",
+            "gauge",
+            "Registered facts by component",
+        );
+        for (component, count) in &facts {
+            let _ = writeln!(
+                text,
+                "rete_facts{{component=\"{}\"}} {}",
+                label(component),
+                count
+            );
+        }
+
+        header(
+            &mut text,
+            "rete_rule_firings_total",
+            "counter",
+            "Rule firings by rule",
+        );
+        for (rule, count) in &metrics.firings {
+            let _ = writeln!(
+                text,
+                "rete_rule_firings_total{{rule=\"{}\"}} {}",
+                label(rule),
+                count
+            );
+        }
+        let counters = [
+            (
+                "rete_facts_asserted_total",
+                "counter",
+                "Facts inserted by rules",
+                metrics.asserted,
+            ),
+            (
+                "rete_facts_retracted_total",
+                "counter",
+                "Facts removed by rules",
+                metrics.retracted,
+            ),
+            (
+                "rete_tick_facts_asserted",
+                "gauge",
+                "Facts inserted by rules in the last tick",
+                metrics.last_asserted,
+            ),
+            (
+                "rete_tick_facts_retracted",
+                "gauge",
+                "Facts removed by rules in the last tick",
+                metrics.last_retracted,
+            ),
+        ];
+        for (name, kind, help, value) in counters {
+            header(&mut text, name, kind, help);
+            let _ = writeln!(text, "{} {}", name, value);
+        }
+
+        header(
+            &mut text,
+            "rete_tick_seconds",
+            "histogram",
+            "Time taken by each tick",
+        );
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(metrics.latency) {
+            cumulative += count;
+            let _ = writeln!(
+                text,
+                "rete_tick_seconds_bucket{{le=\"{}\"}} {}",
+                bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            text,
+            "rete_tick_seconds_bucket{{le=\"+Inf\"}} {}",
+            metrics.ticks
+        );
+        let _ = writeln!(
+            text,
+            "rete_tick_seconds_sum {}",
+            metrics.latency_sum.as_secs_f64()
+        );
+        let _ = writeln!(text, "rete_tick_seconds_count {}", metrics.ticks);
+        Some(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::Value;
+
+    #[test]
+    fn metrics_export_as_text() {
+        let mut engine = RuleEngine::new();
+        engine
+            .load_str(
+                r#"
+                fact Health(points)
+                fact Fleeing()
+                rule "flee" when Health(e, h), h < 10 then insert Fleeing(e), remove Health(e)
+                "#,
+            )
+            .unwrap();
+        let registry = engine.registry().clone();
+        let mut store = EntityStore::new();
+        for (entity_id, health) in [(1, 5), (2, 50), (3, 7)] {
+            store
+                .insert_fact(&registry, "Health", entity_id, &[Value::Int(health)])
+                .unwrap();
+        }
+        assert_eq!(engine.metrics_text(&store), None);
+        engine.enable_metrics();
+        engine.run(&mut store);
+        engine.run(&mut store);
+
+        let text = engine.metrics_text(&store).unwrap();
+        for line in [
+            "rete_entities 3",
+            "rete_facts{component=\"Fleeing\"} 2",
+            "rete_facts{component=\"Health\"} 1",
+            "rete_rule_firings_total{rule=\"flee\"} 2",
+            "rete_facts_asserted_total 2",
+            "rete_facts_retracted_total 2",
+            // The second run found nothing to do
+            "rete_tick_facts_asserted 0",
+            "rete_tick_seconds_count 2",
+            "rete_tick_seconds_bucket{le=\"+Inf\"} 2",
+            "# TYPE rete_tick_seconds histogram",
+        ] {
+            assert!(
+                text.lines().any(|found| found == line),
+                "{}\n{}",
+                line,
+                text
+            );
+        }
+        assert_eq!(label("say \"hi\"\n"), "say \\\"hi\\\"\\n");
+    }
+}
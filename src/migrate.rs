@@ -0,0 +1,153 @@
+// Loading worlds saved before a component's fields changed
+//
+// Each registered component has a schema version, 0 until migrations are added for it.
+// The nth migration added for a name turns a row saved at version n into one for
+// version n + 1, so adding one bumps the version. Binary snapshots record the version
+// of every section, and decode_store runs older rows through the migrations they missed
+// before inserting them. A section that can't be brought up to date, because it was
+// saved by a newer build, a migration gave up on a row, or its rows still don't have
+// the fields the component has now, fails the whole load, and the error lists every
+// such component rather than just the first.
+use crate::registry::{ComponentInfo, FactRow, Registry};
+use crate::value::Value;
+
+// Rewrites one row's fields to the next version, None if the row can't be migrated
+pub type Migration = fn(Vec<Value>) -> Option<Vec<Value>>;
+
+impl Registry {
+    // Adds the migration from the component's current schema version to the next
+    pub fn add_migration(&mut self, name: &str, migration: Migration) {
+        self.migrations
+            .entry(name.to_string())
+            .or_default()
+            .push(migration);
+    }
+
+    pub fn schema_version(&self, name: &str) -> u32 {
+        self.migrations.get(name).map_or(0, Vec::len) as u32
+    }
+
+    // Brings rows saved at a schema version up to the current one
+    // None if that isn't possible, see above
+    pub(crate) fn migrate_rows(
+        &self,
+        info: &ComponentInfo,
+        version: u32,
+        rows: Vec<FactRow>,
+    ) -> Option<Vec<FactRow>> {
+        let migrations = self
+            .migrations
+            .get(&info.name)
+            .map_or(&[][..], Vec::as_slice);
+        let pending = migrations.get(version as usize..)?;
+        rows.into_iter()
+            .map(|(entity_id, values)| {
+                let values = pending
+                    .iter()
+                    .try_fold(values, |values, migration| migration(values))?;
+                (values.len() == info.fields.len()).then_some((entity_id, values))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::registry::{Fact, Registry};
+    use crate::store::{Component, EntityStore};
+    use crate::value::Value;
+
+    // Version 0 of a component, before it gained a maximum
+    #[derive(Debug, PartialEq, Eq)]
+    struct OldHealth(i64);
+
+    impl Component for OldHealth {}
+
+    impl Fact for OldHealth {
+        const FIELDS: &'static [&'static str] = &["hp"];
+
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.0)]
+        }
+
+        fn from_values(values: &[Value]) -> Option<Self> {
+            match values {
+                [hp] => Some(OldHealth(hp.as_int()?)),
+                _ => None,
+            }
+        }
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Health {
+        hp: i64,
+        max: i64,
+    }
+
+    impl Component for Health {}
+
+    impl Fact for Health {
+        const FIELDS: &'static [&'static str] = &["hp", "max"];
+
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.hp), Value::Int(self.max)]
+        }
+
+        fn from_values(values: &[Value]) -> Option<Self> {
+            match values {
+                [hp, max] => Some(Health {
+                    hp: hp.as_int()?,
+                    max: max.as_int()?,
+                }),
+                _ => None,
+            }
+        }
+    }
+
+    fn add_max(mut values: Vec<Value>) -> Option<Vec<Value>> {
+        let hp = values.first()?.as_int()?;
+        values.push(Value::Int(hp.max(100)));
+        Some(values)
+    }
+
+    #[test]
+    fn old_snapshots_are_migrated_or_listed() {
+        let mut store = EntityStore::new();
+        store.new_component::<OldHealth>();
+        store.add_component(1, OldHealth(30));
+        let mut old = Registry::new();
+        old.register::<OldHealth>("Health");
+        old.register::<OldHealth>("Shield");
+        let bytes = old.encode_store(&store);
+
+        let mut registry = Registry::new();
+        registry.register::<Health>("Health");
+        registry.register::<Health>("Shield");
+        assert_eq!(
+            registry.decode_store(&bytes).unwrap_err(),
+            Error::Unmigratable(vec!["Health".to_string(), "Shield".to_string()])
+        );
+
+        registry.add_migration("Health", add_max);
+        assert_eq!(registry.schema_version("Health"), 1);
+        assert_eq!(
+            registry.decode_store(&bytes).unwrap_err(),
+            Error::Unmigratable(vec!["Shield".to_string()])
+        );
+
+        registry.add_migration("Shield", add_max);
+        let decoded = registry.decode_store(&bytes).unwrap();
+        assert_eq!(
+            decoded.store.get::<Health>().unwrap().borrow().get(1),
+            Some(&Health { hp: 30, max: 100 })
+        );
+
+        // A build that only knows the old schema can't read the newer save
+        let newer = registry.encode_store(&decoded.store);
+        assert_eq!(
+            old.decode_store(&newer).unwrap_err(),
+            Error::Unmigratable(vec!["Health".to_string(), "Shield".to_string()])
+        );
+    }
+}
@@ -0,0 +1,132 @@
+// Rule modules
+//
+// Rules can be grouped into named modules ("combat", "economy") with a `module` line in
+// the rule text. A module can be switched off and on, or have its rules swapped out,
+// without touching the rest of the rule set.
+use crate::dsl;
+use crate::engine::RuleEngine;
+use crate::error::Error;
+use crate::rule::Rule;
+
+impl RuleEngine {
+    // Every module with at least one rule, in the order they first appear
+    pub fn modules(&self) -> Vec<&str> {
+        let mut modules: Vec<&str> = Vec::new();
+        for module in self
+            .rules()
+            .iter()
+            .filter_map(|rule| rule.module.as_deref())
+        {
+            if !modules.contains(&module) {
+                modules.push(module);
+            }
+        }
+        modules
+    }
+
+    pub fn is_module_enabled(&self, module: &str) -> bool {
+        !self.disabled.contains(module)
+    }
+
+    // Disabled modules stay disabled when their rules are replaced or reloaded
+    // Their activations lapse while disabled, so on re-enabling anything that
+    // matches fires again
+    pub fn disable_module(&mut self, module: &str) {
+        if self.disabled.insert(module.to_string()) {
+            self.reorder();
+        }
+    }
+
+    pub fn enable_module(&mut self, module: &str) {
+        if self.disabled.remove(module) {
+            self.reorder();
+        }
+    }
+
+    // Swaps the module's rules for the rules in the source, which all join the module
+    // The new rules take the place of the first old one, or go last if the module is new
+    pub fn replace_module(&mut self, module: &str, source: &str) -> Result<(), Error> {
+        let mut incoming = dsl::parse(source)?;
+        for rule in &mut incoming {
+            rule.module = Some(module.to_string());
+        }
+
+        let in_module = |rule: &Rule| rule.module.as_deref() == Some(module);
+        let position = self.rules().iter().position(in_module);
+        let mut rules: Vec<_> = self
+            .rules()
+            .iter()
+            .filter(|rule| !in_module(rule))
+            .cloned()
+            .collect();
+        let at = position.unwrap_or(rules.len());
+        rules.splice(at..at, incoming);
+        self.replace_rules(rules)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::RuleEngine;
+    use crate::registry::Fact;
+    use crate::store::{Component, EntityStore};
+    use crate::value::Value;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Gold(i64);
+    impl Component for Gold {}
+    impl Fact for Gold {
+        const FIELDS: &'static [&'static str] = &["amount"];
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.0)]
+        }
+        fn from_values(values: &[Value]) -> Option<Self> {
+            match values {
+                [value] => Some(Gold(value.as_int()?)),
+                _ => None,
+            }
+        }
+    }
+
+    fn gold(store: &EntityStore) -> i64 {
+        store.get::<Gold>().unwrap().borrow().get(1).unwrap().0
+    }
+
+    #[test]
+    fn disabled_modules_do_not_fire() {
+        let mut engine = RuleEngine::new();
+        engine.register::<Gold>("Gold");
+        engine
+            .load_str(
+                r#"
+                module "economy"
+                rule "interest" when Gold(e, g), g < 100 then insert Gold(e, g * 2)
+                "#,
+            )
+            .unwrap();
+        assert_eq!(engine.modules(), ["economy"]);
+
+        let mut store = EntityStore::new();
+        store.new_component::<Gold>();
+        store.add_component(1, Gold(10));
+
+        engine.disable_module("economy");
+        assert!(!engine.is_module_enabled("economy"));
+        assert!(engine.activations(&store).is_empty());
+        assert_eq!(engine.run(&mut store), 0);
+
+        engine.enable_module("economy");
+        engine.run(&mut store);
+        assert_eq!(gold(&store), 160);
+
+        engine
+            .replace_module(
+                "economy",
+                r#"rule "tax" when Gold(e, g), g > 100 then insert Gold(e, 100)"#,
+            )
+            .unwrap();
+        assert_eq!(engine.rules()[0].module.as_deref(), Some("economy"));
+        engine.run(&mut store);
+        assert_eq!(gold(&store), 100);
+    }
+}
@@ -0,0 +1,109 @@
+// Entities known by name
+//
+// set_name gives an entity a Name, taking it off whichever entity had it before, so a
+// name picks out at most one entity. The Name pool gets a hash index on its field the
+// first time, which named answers from, and which rules matching `Name(e, "player")`
+// use too once Name is registered with the engine. Names given with add_component, or
+// inserted by rules, are found the same way but aren't kept unique.
+use crate::registry::{Fact, TypedFact};
+use crate::store::{Component, EntityId, EntityStore};
+use crate::value::Value;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Name(pub String);
+
+impl Component for Name {}
+
+impl Fact for Name {
+    const FIELDS: &'static [&'static str] = &["name"];
+
+    fn to_values(&self) -> Vec<Value> {
+        vec![Value::Str(self.0.clone())]
+    }
+
+    fn from_values(values: &[Value]) -> Option<Self> {
+        match values {
+            [name] => Some(Name(name.as_str()?.to_string())),
+            _ => None,
+        }
+    }
+}
+
+impl TypedFact for Name {
+    type Fields = (String,);
+
+    fn fields(&self) -> Self::Fields {
+        (self.0.clone(),)
+    }
+
+    fn from_fields((name,): Self::Fields) -> Self {
+        Name(name)
+    }
+}
+
+impl EntityStore {
+    // Names the entity, see above
+    pub fn set_name(&mut self, entity_id: EntityId, name: &str) {
+        if self.get::<Name>().is_none() {
+            self.new_component::<Name>();
+        }
+        self.index_field::<Name>("name");
+        self.begin_step();
+        if let Some(previous) = self.named(name).filter(|&other| other != entity_id) {
+            self.remove_component::<Name>(previous);
+        }
+        self.reserve_up_to(entity_id);
+        self.add_component(entity_id, Name(name.to_string()));
+        self.end_step();
+    }
+
+    // The entity with the name, the lowest if several were given it directly
+    pub fn named(&self, name: &str) -> Option<EntityId> {
+        let value = Value::Str(name.to_string());
+        match self.indexed::<Name>("name", &value) {
+            Some(entities) => entities.into_iter().min(),
+            None => {
+                let pool = self.get::<Name>()?.borrow();
+                let found = pool
+                    .components_iter()
+                    .filter(|(_, other)| other.0 == name)
+                    .map(|(&entity_id, _)| entity_id)
+                    .min();
+                found
+            }
+        }
+    }
+
+    pub fn name_of(&self, entity_id: EntityId) -> Option<String> {
+        let pool = self.get::<Name>()?;
+        let name = pool.borrow().get(entity_id)?.0.clone();
+        Some(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::RuleEngine;
+
+    #[test]
+    fn names_pick_out_one_entity() {
+        let mut store = EntityStore::new();
+        store.set_name(4, "player");
+        store.set_name(9, "world_clock");
+        assert_eq!(store.named("player"), Some(4));
+        assert_eq!(store.name_of(9).as_deref(), Some("world_clock"));
+
+        store.set_name(5, "player");
+        assert_eq!(store.named("player"), Some(5));
+        assert_eq!(store.name_of(4), None);
+        store.remove_entity(5);
+        assert_eq!(store.named("player"), None);
+
+        let mut engine = RuleEngine::new();
+        engine.register::<Name>("Name");
+        let found = engine.query(r#"Name(e, "world_clock")"#, &store).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0]["e"], Value::Entity(9));
+    }
+}
@@ -0,0 +1,123 @@
+// Parallel iteration on rayon's thread pool, behind the parallel feature
+//
+// par_iter and par_iter_mut split a pool's packed arrays into chunks and hand them to
+// worker threads. par_join and par_join_mut do the same for every entity with two
+// components, walking the first pool's packed arrays and looking each entity up in the
+// second. Components read from several threads at once must be Sync and ones written
+// must be Send, which every Component is, since the store itself can be shared between
+// threads. The pools are borrowed for the length of the call, so the closures get
+// the components but can't reach back into the store.
+use crate::store::{Component, EntityId, EntityStore, Pool};
+use rayon::prelude::*;
+use std::any::TypeId;
+
+// Fewest entities handed to a thread at once, so small pools aren't split into tasks
+// that cost more to schedule than to run
+const CHUNK: usize = 1024;
+
+impl<T: Component + Eq + Sync> Pool<T> {
+    pub fn par_iter(&self) -> impl IndexedParallelIterator<Item = (&EntityId, &T)> {
+        let (entities, components) = self.packed();
+        entities
+            .par_iter()
+            .zip(components.par_iter())
+            .with_min_len(CHUNK)
+    }
+}
+
+impl<T: Component + Eq + Send> Pool<T> {
+    // Counts as a change to every component, like components_mut
+    pub fn par_iter_mut(&mut self) -> impl IndexedParallelIterator<Item = (&EntityId, &mut T)> {
+        let (entities, components) = self.packed_mut();
+        entities
+            .par_iter()
+            .zip(components.par_iter_mut())
+            .with_min_len(CHUNK)
+    }
+}
+
+impl EntityStore {
+    // Calls f for every entity with both an A and a B, in parallel
+    pub fn par_join<A, B>(&self, f: impl Fn(EntityId, &A, &B) + Sync)
+    where
+        A: Component + Eq + Sync + 'static,
+        B: Component + Eq + Sync + 'static,
+    {
+        let (Some(a), Some(b)) = (self.get::<A>(), self.get::<B>()) else {
+            return;
+        };
+        let (a, b) = (a.borrow(), b.borrow());
+        let b = &*b;
+        a.par_iter().for_each(|(&entity_id, a)| {
+            if let Some(b) = b.get(entity_id) {
+                f(entity_id, a, b);
+            }
+        });
+    }
+
+    // Calls f for every entity with both an A and a B, in parallel, letting it change
+    // the A. Every A counts as changed, and A and B must be different types
+    pub fn par_join_mut<A, B>(&mut self, f: impl Fn(EntityId, &mut A, &B) + Sync)
+    where
+        A: Component + Eq + Send + 'static,
+        B: Component + Eq + Sync + 'static,
+    {
+        assert_ne!(
+            TypeId::of::<A>(),
+            TypeId::of::<B>(),
+            "par_join_mut can't write and read the same pool"
+        );
+        let (Some(a), Some(b)) = (self.get::<A>(), self.get::<B>()) else {
+            return;
+        };
+        let (mut a, b) = (a.borrow_mut(), b.borrow());
+        let b = &*b;
+        a.par_iter_mut().for_each(|(&entity_id, a)| {
+            if let Some(b) = b.get(entity_id) {
+                f(entity_id, a, b);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicI64, Ordering};
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Position(i64);
+    impl Component for Position {}
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Velocity(i64);
+    impl Component for Velocity {}
+
+    #[test]
+    fn joins_run_over_every_match() {
+        let mut store = EntityStore::new();
+        store.new_component::<Position>();
+        store.new_component::<Velocity>();
+        for entity_id in 0..10_000 {
+            store.add_component(entity_id, Position(entity_id as i64));
+            if entity_id % 2 == 0 {
+                store.add_component(entity_id, Velocity(1));
+            }
+        }
+
+        store.par_join_mut(|_, position: &mut Position, velocity: &Velocity| {
+            position.0 += velocity.0;
+        });
+        let pool = store.get::<Position>().unwrap().borrow();
+        let sum: i64 = pool.par_iter().map(|(_, position)| position.0).sum();
+        assert_eq!(sum, (0..10_000).sum::<i64>() + 5_000);
+        drop(pool);
+
+        let moving = AtomicI64::new(0);
+        store.par_join(|entity_id, _: &Position, _: &Velocity| {
+            assert_eq!(entity_id % 2, 0);
+            moving.fetch_add(1, Ordering::Relaxed);
+        });
+        assert_eq!(moving.into_inner(), 5_000);
+    }
+}
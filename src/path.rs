@@ -0,0 +1,224 @@
+// Shortest paths over entity graphs
+//
+// A Graph says which entities each entity leads to and what each step costs, so the
+// same searches work over relation links, tile adjacency or anything a caller builds
+// from components. Paths come back as the sequence of entities walked. Registering a
+// graph with an engine gives rules a pattern `Route(a, goal, next, cost)`, holding for
+// every goal reachable from a with next the first step towards it, which is what an
+// action moving a along the path needs. The start should be bound by an earlier
+// condition, as an unbound one searches from every node of the graph.
+use crate::relation::Relation;
+use crate::store::{EntityId, EntityStore};
+use crate::tiles::Tile;
+use crate::value::Value;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::marker::PhantomData;
+
+pub trait Graph: 'static {
+    // The entities one step on from the given one, each with the step's cost
+    fn edges(store: &EntityStore, from: EntityId) -> Vec<(EntityId, u64)>;
+
+    // The entities paths can start from, for patterns whose start is unbound
+    fn nodes(store: &EntityStore) -> Vec<EntityId>;
+}
+
+// Follows R links from source to target, each costing 1
+pub struct Links<R>(PhantomData<R>);
+
+impl<R: Relation> Graph for Links<R> {
+    fn edges(store: &EntityStore, from: EntityId) -> Vec<(EntityId, u64)> {
+        store
+            .related::<R>(from)
+            .map(|to| (to, 1))
+            .into_iter()
+            .collect()
+    }
+
+    fn nodes(store: &EntityStore) -> Vec<EntityId> {
+        store
+            .get::<R>()
+            .map(|pool| pool.borrow().entities().into_iter().copied().collect())
+            .unwrap_or_default()
+    }
+}
+
+// Steps between adjacent tiles, each costing 1
+pub struct Tiles;
+
+impl Graph for Tiles {
+    fn edges(store: &EntityStore, from: EntityId) -> Vec<(EntityId, u64)> {
+        store
+            .neighbours(from)
+            .into_iter()
+            .map(|to| (to, 1))
+            .collect()
+    }
+
+    fn nodes(store: &EntityStore) -> Vec<EntityId> {
+        store
+            .get::<Tile>()
+            .map(|pool| pool.borrow().entities().into_iter().copied().collect())
+            .unwrap_or_default()
+    }
+}
+
+// The entities walked from start to goal, both included, and the total cost
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Path {
+    pub entities: Vec<EntityId>,
+    pub cost: u64,
+}
+
+impl Path {
+    // The first step, None if the path starts at its goal
+    pub fn next(&self) -> Option<EntityId> {
+        self.entities.get(1).copied()
+    }
+}
+
+// Cheapest known cost and the entity it was reached from, per entity reached
+type Search = HashMap<EntityId, (u64, Option<EntityId>)>;
+
+// Dijkstra from a start, stopping early once goal is settled if there is one
+// Ties go to the lower entity id, so results don't depend on edge order
+fn search<G: Graph>(store: &EntityStore, from: EntityId, goal: Option<EntityId>) -> Search {
+    let mut reached: Search = HashMap::from([(from, (0, None))]);
+    let mut queue = BinaryHeap::from([Reverse((0, from))]);
+    while let Some(Reverse((cost, entity_id))) = queue.pop() {
+        if cost > reached[&entity_id].0 {
+            continue;
+        }
+        if Some(entity_id) == goal {
+            break;
+        }
+        for (to, step) in G::edges(store, entity_id) {
+            let cost = cost.saturating_add(step);
+            if reached.get(&to).is_none_or(|&(known, _)| cost < known) {
+                reached.insert(to, (cost, Some(entity_id)));
+                queue.push(Reverse((cost, to)));
+            }
+        }
+    }
+    reached
+}
+
+fn walk_back(reached: &Search, goal: EntityId) -> Option<Path> {
+    let &(cost, _) = reached.get(&goal)?;
+    let mut entities = vec![goal];
+    while let Some(&(_, Some(previous))) = reached.get(entities.last().unwrap()) {
+        entities.push(previous);
+    }
+    entities.reverse();
+    Some(Path { entities, cost })
+}
+
+impl EntityStore {
+    // The cheapest path from one entity to another, None if goal can't be reached
+    pub fn shortest_path<G: Graph>(&self, from: EntityId, to: EntityId) -> Option<Path> {
+        walk_back(&search::<G>(self, from, Some(to)), to)
+    }
+
+    // The cheapest path to every entity reachable from the given one, itself included
+    pub fn paths_from<G: Graph>(&self, from: EntityId) -> HashMap<EntityId, Path> {
+        let reached = search::<G>(self, from, None);
+        reached
+            .keys()
+            .filter_map(|&goal| Some((goal, walk_back(&reached, goal)?)))
+            .collect()
+    }
+}
+
+// Registry hooks for the route pattern, one row per reachable goal other than the start
+pub(crate) fn route_rows<G: Graph>(store: &EntityStore, from: EntityId) -> Vec<Vec<Value>> {
+    let mut rows: Vec<Vec<Value>> = store
+        .paths_from::<G>(from)
+        .into_iter()
+        .filter_map(|(goal, path)| {
+            Some(vec![
+                Value::Entity(goal),
+                Value::Entity(path.next()?),
+                Value::Int(path.cost.try_into().unwrap_or(i64::MAX)),
+            ])
+        })
+        .collect();
+    rows.sort_by_key(|row| row[0].as_entity());
+    rows
+}
+
+pub(crate) fn route_facts<G: Graph>(store: &EntityStore) -> Vec<(EntityId, Vec<Value>)> {
+    G::nodes(store)
+        .into_iter()
+        .flat_map(|from| {
+            route_rows::<G>(store, from)
+                .into_iter()
+                .map(move |row| (from, row))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::RuleEngine;
+
+    // Diagonal steps cost 3 and straight ones 2
+    struct Walk;
+
+    impl Graph for Walk {
+        fn edges(store: &EntityStore, from: EntityId) -> Vec<(EntityId, u64)> {
+            let tiles = store.get::<Tile>().unwrap();
+            let tiles = tiles.borrow();
+            let here = tiles.get(from).unwrap();
+            store
+                .neighbours(from)
+                .into_iter()
+                .map(|to| {
+                    let there = tiles.get(to).unwrap();
+                    let diagonal = here.x != there.x && here.y != there.y;
+                    (to, if diagonal { 3 } else { 2 })
+                })
+                .collect()
+        }
+
+        fn nodes(store: &EntityStore) -> Vec<EntityId> {
+            Tiles::nodes(store)
+        }
+    }
+
+    #[test]
+    fn paths_follow_edge_costs() {
+        // 1 2 3
+        // 4 5 6
+        // 7 8 9
+        let mut store = EntityStore::new();
+        for entity_id in 1..=9 {
+            let index = entity_id as i64 - 1;
+            store.place_tile(entity_id, Tile::new(index % 3, index / 3));
+        }
+        let path = store.shortest_path::<Walk>(1, 9).unwrap();
+        assert_eq!(path.entities, vec![1, 5, 9]);
+        assert_eq!(path.cost, 6);
+        assert_eq!(store.shortest_path::<Tiles>(1, 9).unwrap().cost, 2);
+
+        // With the centre gone the path goes round
+        store.remove_tile(5);
+        let path = store.shortest_path::<Walk>(1, 9).unwrap();
+        assert_eq!(path.cost, 7);
+        assert_eq!(path.entities.len(), 4);
+        assert_eq!(store.shortest_path::<Walk>(1, 5), None);
+
+        let mut engine = RuleEngine::new();
+        engine.register_tiles("Tile", "AdjacentTo");
+        engine.register_paths::<Walk>("Route");
+        let routes = engine
+            .query(
+                "Tile(a, 0, 0), Tile(g, 1, 2), Route(a, g, next, cost)",
+                &store,
+            )
+            .unwrap();
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0]["next"], Value::Entity(4));
+        assert_eq!(routes[0]["cost"], Value::Int(5));
+    }
+}
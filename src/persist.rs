@@ -0,0 +1,203 @@
+// Serde support for pools and whole stores
+//
+// A Pool<T> of serializable components serializes as its (entity, component) pairs in
+// packed order, and comes back with the same iteration order. A store can't be done the
+// same way since its pools are type erased, so it goes through a Registry instead: each
+// registered component is written under its registered name as (entity, fields) rows,
+// and read back by inserting the rows through the registry. Inserts take the same path
+// rules do, so tiles, parents and relations come back with their neighbour lists,
+// children and reverse indexes rebuilt. Unregistered components, and derived ones like
+// AdjacentTo, are not saved.
+use crate::registry::{FactRow, Registry};
+use crate::store::{check_entity, Component, EntityId, EntityStore, Pool};
+use serde::de::{DeserializeSeed, Error as _};
+use serde::ser::{SerializeMap, SerializeStruct};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+
+impl<T: Component + Eq + Serialize> Serialize for Pool<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.components_iter())
+    }
+}
+
+impl<'de, T: Component + Eq + Deserialize<'de>> Deserialize<'de> for Pool<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut pool = Pool::new();
+        for (entity_id, component) in Vec::<(EntityId, T)>::deserialize(deserializer)? {
+            check_entity(entity_id).map_err(D::Error::custom)?;
+            pool.add_component(entity_id, component);
+        }
+        Ok(pool)
+    }
+}
+
+// A store seen through a registry, see Registry::serialize_store
+pub struct StoreRef<'a> {
+    registry: &'a Registry,
+    store: &'a EntityStore,
+}
+
+impl Serialize for StoreRef<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut world = serializer.serialize_struct("World", 2)?;
+        world.serialize_field("max_entity", &self.store.max_entity())?;
+        world.serialize_field("components", &Components(self))?;
+        world.end()
+    }
+}
+
+struct Components<'a>(&'a StoreRef<'a>);
+
+impl Serialize for Components<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let StoreRef { registry, store } = self.0;
+        let mut map = serializer.serialize_map(None)?;
+        for info in registry.iter().filter(|info| !info.is_derived()) {
+            let mut rows = info.facts(store);
+            if rows.is_empty() {
+                continue;
+            }
+            rows.sort_by_key(|(entity_id, _)| *entity_id);
+            map.serialize_entry(&info.name, &rows)?;
+        }
+        map.end()
+    }
+}
+
+// What a serialized store holds, before its rows are turned back into components
+#[derive(Deserialize)]
+struct World {
+    max_entity: EntityId,
+    components: BTreeMap<String, Vec<FactRow>>,
+}
+
+// Deserializes a store through a registry, see Registry::deserialize_store
+pub struct StoreSeed<'a>(pub &'a Registry);
+
+impl<'de> DeserializeSeed<'de> for StoreSeed<'_> {
+    type Value = EntityStore;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<EntityStore, D::Error> {
+        let world = World::deserialize(deserializer)?;
+        let mut store = EntityStore::new();
+        store.reserve_up_to(world.max_entity);
+
+        let mut sections = Vec::new();
+        for (name, rows) in world.components {
+            let info = self
+                .0
+                .get(&name)
+                .filter(|info| !info.is_derived())
+                .ok_or_else(|| D::Error::custom(crate::Error::UnknownComponent(name)))?;
+            sections.push((info, rows));
+        }
+        Registry::insert_rows(&mut store, sections).map_err(D::Error::custom)?;
+        Ok(store)
+    }
+}
+
+impl Registry {
+    // Something serde can write the store's registered components out through
+    pub fn serialize_store<'a>(&'a self, store: &'a EntityStore) -> StoreRef<'a> {
+        StoreRef {
+            registry: self,
+            store,
+        }
+    }
+
+    // Reads back a store written with serialize_store, failing on unregistered names
+    pub fn deserialize_store<'de, D: Deserializer<'de>>(
+        &self,
+        deserializer: D,
+    ) -> Result<EntityStore, D::Error> {
+        StoreSeed(self).deserialize(deserializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hierarchy::Parent;
+    use crate::registry::{Fact, TypedFact};
+    use crate::tiles::Tile;
+    use crate::value::Value;
+
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    struct Health(i64);
+
+    impl Component for Health {}
+
+    impl Fact for Health {
+        const FIELDS: &'static [&'static str] = &["hp"];
+
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.0)]
+        }
+
+        fn from_values(values: &[Value]) -> Option<Self> {
+            match values {
+                [hp] => Some(Health(hp.as_int()?)),
+                _ => None,
+            }
+        }
+    }
+
+    impl TypedFact for Health {
+        type Fields = (i64,);
+
+        fn fields(&self) -> Self::Fields {
+            (self.0,)
+        }
+
+        fn from_fields((hp,): Self::Fields) -> Self {
+            Health(hp)
+        }
+    }
+
+    #[test]
+    fn pools_and_stores_round_trip() {
+        let mut pool = Pool::new();
+        pool.add_component(3, Health(30));
+        pool.add_component(1, Health(10));
+        let json = serde_json::to_string(&pool).unwrap();
+        assert_eq!(json, "[[3,30],[1,10]]");
+        assert_eq!(serde_json::from_str::<Pool<Health>>(&json).unwrap(), pool);
+
+        let mut store = EntityStore::new();
+        store.new_component::<Health>();
+        store.add_component(1, Health(10));
+        store.place_tile(1, Tile::new(0, 0));
+        store.place_tile(2, Tile::new(1, 0));
+        store.set_parent(2, 1).unwrap();
+        store.reserve_up_to(9);
+
+        let mut registry = Registry::new();
+        registry.register::<Health>("Health");
+        registry.register_tiles("Tile", "AdjacentTo");
+        registry.register_hierarchy("Parent");
+        let json = serde_json::to_string(&registry.serialize_store(&store)).unwrap();
+        assert!(!json.contains("AdjacentTo"));
+
+        let restored = registry
+            .deserialize_store(&mut serde_json::Deserializer::from_str(&json))
+            .unwrap();
+        assert_eq!(restored.max_entity(), 9);
+        assert_eq!(
+            restored.get::<Health>().unwrap().borrow().get(1),
+            Some(&Health(10))
+        );
+        // Derived state is rebuilt rather than saved
+        assert_eq!(restored.neighbours(1), vec![2]);
+        assert_eq!(restored.children(1), vec![2]);
+        assert_eq!(
+            restored.get::<Parent>().unwrap().borrow().get(2),
+            Some(&Parent(1))
+        );
+
+        let unknown = Registry::new()
+            .deserialize_store(&mut serde_json::Deserializer::from_str(&json))
+            .unwrap_err();
+        assert!(unknown.to_string().contains("unknown component"));
+    }
+}
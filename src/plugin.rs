@@ -0,0 +1,401 @@
+// Rule actions from WASM plugins
+//
+// With the plugins feature, a rule pack can ship its actions as a WebAssembly module
+// rather than native code. load_plugin takes the module's bytes under a name, and
+// `then run "pack.reward"` calls its export `reward`, which takes nothing and returns
+// nothing. Each call gets a fresh instance and a budget of fuel, so a plugin keeps no
+// state between firings and one that loops forever is stopped. Everything it can do to
+// the world goes through the host interface, imported from the module `rete`:
+//
+//   binding(name, len) -> i64           an Int, Bool or Entity the rule bound
+//   binding_float(name, len) -> f64
+//   read_fact(component, len, e) -> i32 loads e's fact, giving its field count, or -1
+//   field(i) -> i64, field_float(i) -> f64
+//   push_int(i64), push_float(f64), push_bool(i32), push_entity(i64), push_str(ptr, len)
+//   assert_fact(component, len, e) -> i32   adds the pushed fields as e's fact, 1 if it fit
+//   retract_fact(component, len, e)
+//   emit_event(name, len)               sends a PluginEvent carrying the pushed fields
+//
+// Names are UTF-8 in the plugin's exported memory. Pushed values are used up by the
+// next assert_fact or emit_event. Failures are reported as for scripts, see script.rs.
+use crate::error::Error;
+use crate::registry::Registry;
+use crate::stats::StoreEvent;
+use crate::store::{EntityId, EntityStore};
+use crate::value::{Bindings, Value};
+use std::collections::HashMap;
+use std::fmt;
+use wasmi::{Caller, Config, Engine, Extern, Linker, Module, Store};
+
+// Instructions a plugin action may run before it's stopped, roughly
+const FUEL: u64 = 10_000_000;
+
+// Sent on the store by a plugin's emit_event
+#[derive(Debug, Clone, PartialEq)]
+pub struct PluginEvent {
+    pub plugin: String,
+    pub name: String,
+    pub values: Vec<Value>,
+}
+
+// What a plugin instance works on while one of its actions runs
+struct Host {
+    store: EntityStore,
+    registry: Registry,
+    bindings: Bindings,
+    plugin: String,
+    // The fields read_fact loaded
+    fact: Vec<Value>,
+    // Values pushed for the next assert_fact or emit_event
+    pushed: Vec<Value>,
+}
+
+pub(crate) struct Plugins {
+    engine: Engine,
+    linker: Linker<Host>,
+    modules: HashMap<String, Module>,
+}
+
+impl fmt::Debug for Plugins {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Plugins")
+            .field("modules", &self.modules.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+fn trap(message: &str) -> wasmi::Error {
+    wasmi::Error::new(message.to_string())
+}
+
+// A UTF-8 string out of the plugin's memory
+fn string(caller: &Caller<'_, Host>, ptr: i32, len: i32) -> Result<String, wasmi::Error> {
+    let memory = caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| trap("the plugin exports no memory"))?;
+    let mut bytes = vec![0; len.max(0) as usize];
+    memory
+        .read(caller, ptr.max(0) as usize, &mut bytes)
+        .map_err(|_| trap("string out of bounds"))?;
+    String::from_utf8(bytes).map_err(|_| trap("string isn't UTF-8"))
+}
+
+fn entity(e: i64) -> Result<EntityId, wasmi::Error> {
+    EntityId::try_from(e).map_err(|_| trap("negative entity"))
+}
+
+fn link(linker: &mut Linker<Host>) -> Result<(), wasmi::Error> {
+    linker.func_wrap(
+        "rete",
+        "binding",
+        |caller: Caller<'_, Host>, ptr: i32, len: i32| {
+            let name = string(&caller, ptr, len)?;
+            match caller.data().bindings.get(&name) {
+                Some(Value::Int(i)) => Ok(*i),
+                Some(Value::Bool(b)) => Ok(*b as i64),
+                Some(Value::Entity(e)) => Ok(*e as i64),
+                _ => Err(trap("no such integer binding")),
+            }
+        },
+    )?;
+    linker.func_wrap(
+        "rete",
+        "binding_float",
+        |caller: Caller<'_, Host>, ptr: i32, len: i32| {
+            let name = string(&caller, ptr, len)?;
+            match caller.data().bindings.get(&name) {
+                Some(value) => value
+                    .as_float()
+                    .ok_or_else(|| trap("binding isn't a number")),
+                None => Err(trap("no such binding")),
+            }
+        },
+    )?;
+    linker.func_wrap(
+        "rete",
+        "read_fact",
+        |mut caller: Caller<'_, Host>, ptr: i32, len: i32, e: i64| {
+            let component = string(&caller, ptr, len)?;
+            let host = caller.data_mut();
+            let info = host
+                .registry
+                .get(&component)
+                .ok_or_else(|| trap("unknown component"))?;
+            let fact = info.get(&host.store, entity(e)?);
+            let found = fact.as_ref().map_or(-1, |fact| fact.len() as i32);
+            host.fact = fact.unwrap_or_default();
+            Ok(found)
+        },
+    )?;
+    linker.func_wrap(
+        "rete",
+        "field",
+        |caller: Caller<'_, Host>, i: i32| match caller.data().fact.get(i.max(0) as usize) {
+            Some(Value::Int(i)) => Ok(*i),
+            Some(Value::Bool(b)) => Ok(*b as i64),
+            Some(Value::Entity(e)) => Ok(*e as i64),
+            _ => Err(trap("no such integer field")),
+        },
+    )?;
+    linker.func_wrap("rete", "field_float", |caller: Caller<'_, Host>, i: i32| {
+        let field = caller.data().fact.get(i.max(0) as usize);
+        field
+            .and_then(Value::as_float)
+            .ok_or_else(|| trap("no such number field"))
+    })?;
+    linker.func_wrap(
+        "rete",
+        "push_int",
+        |mut caller: Caller<'_, Host>, i: i64| {
+            caller.data_mut().pushed.push(Value::Int(i));
+        },
+    )?;
+    linker.func_wrap(
+        "rete",
+        "push_float",
+        |mut caller: Caller<'_, Host>, f: f64| {
+            caller.data_mut().pushed.push(Value::Float(f));
+        },
+    )?;
+    linker.func_wrap(
+        "rete",
+        "push_bool",
+        |mut caller: Caller<'_, Host>, b: i32| {
+            caller.data_mut().pushed.push(Value::Bool(b != 0));
+        },
+    )?;
+    linker.func_wrap(
+        "rete",
+        "push_entity",
+        |mut caller: Caller<'_, Host>, e: i64| {
+            let value = Value::Entity(entity(e)?);
+            caller.data_mut().pushed.push(value);
+            Ok(())
+        },
+    )?;
+    linker.func_wrap(
+        "rete",
+        "push_str",
+        |mut caller: Caller<'_, Host>, ptr: i32, len: i32| {
+            let value = Value::Str(string(&caller, ptr, len)?);
+            caller.data_mut().pushed.push(value);
+            Ok(())
+        },
+    )?;
+    linker.func_wrap(
+        "rete",
+        "assert_fact",
+        |mut caller: Caller<'_, Host>, ptr: i32, len: i32, e: i64| {
+            let component = string(&caller, ptr, len)?;
+            let host = caller.data_mut();
+            let values = std::mem::take(&mut host.pushed);
+            let info = host
+                .registry
+                .get(&component)
+                .ok_or_else(|| trap("unknown component"))?;
+            let fits = values.len() == info.fields.len()
+                && info.insert(&mut host.store, entity(e)?, &values);
+            Ok(fits as i32)
+        },
+    )?;
+    linker.func_wrap(
+        "rete",
+        "retract_fact",
+        |mut caller: Caller<'_, Host>, ptr: i32, len: i32, e: i64| {
+            let component = string(&caller, ptr, len)?;
+            let host = caller.data_mut();
+            let info = host
+                .registry
+                .get(&component)
+                .ok_or_else(|| trap("unknown component"))?;
+            info.remove(&mut host.store, entity(e)?);
+            Ok(())
+        },
+    )?;
+    linker.func_wrap(
+        "rete",
+        "emit_event",
+        |mut caller: Caller<'_, Host>, ptr: i32, len: i32| {
+            let name = string(&caller, ptr, len)?;
+            let host = caller.data_mut();
+            let event = PluginEvent {
+                plugin: host.plugin.clone(),
+                name,
+                values: std::mem::take(&mut host.pushed),
+            };
+            host.store.send_event(event);
+            Ok(())
+        },
+    )?;
+    Ok(())
+}
+
+impl Default for Plugins {
+    fn default() -> Self {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let mut linker = Linker::new(&engine);
+        link(&mut linker).expect("host functions are defined once each");
+        Plugins {
+            engine,
+            linker,
+            modules: HashMap::new(),
+        }
+    }
+}
+
+impl Plugins {
+    pub(crate) fn load(&mut self, name: &str, wasm: &[u8]) -> Result<(), Error> {
+        let module = Module::new(&self.engine, wasm)
+            .map_err(|error| Error::Plugin(format!("`{}`: {}", name, error)))?;
+        self.modules.insert(name.to_string(), module);
+        Ok(())
+    }
+
+    // Whether "plugin.export" names an action a loaded plugin has
+    pub(crate) fn contains(&self, action: &str) -> bool {
+        action.split_once('.').is_some_and(|(plugin, export)| {
+            self.modules
+                .get(plugin)
+                .is_some_and(|module| module.get_export(export).is_some())
+        })
+    }
+
+    // Runs the action over the store, see above
+    pub(crate) fn run(
+        &self,
+        rule: &str,
+        action: &str,
+        bindings: &Bindings,
+        registry: &Registry,
+        store: &mut EntityStore,
+    ) {
+        let Some((plugin, export)) = action.split_once('.') else {
+            return;
+        };
+        let Some(module) = self.modules.get(plugin) else {
+            return;
+        };
+        let host = Host {
+            store: std::mem::take(store),
+            registry: registry.clone(),
+            bindings: bindings.clone(),
+            plugin: plugin.to_string(),
+            fact: Vec::new(),
+            pushed: Vec::new(),
+        };
+        let mut instance_store = Store::new(&self.engine, host);
+        let result = instance_store
+            .set_fuel(FUEL)
+            .map_err(wasmi::Error::from)
+            .and_then(|()| self.linker.instantiate(&mut instance_store, module))
+            .and_then(|pre| pre.start(&mut instance_store))
+            .and_then(|instance| {
+                instance
+                    .get_typed_func::<(), ()>(&instance_store, export)?
+                    .call(&mut instance_store, ())
+            });
+        *store = instance_store.into_data().store;
+        if let Err(error) = result {
+            store.emit(StoreEvent::ActionFailed {
+                rule: rule.to_string(),
+                message: error.to_string(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::RuleEngine;
+    use crate::registry::Fact;
+    use crate::store::Component;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Gold(i64);
+    impl Component for Gold {}
+
+    impl Fact for Gold {
+        const FIELDS: &'static [&'static str] = &["amount"];
+
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.0)]
+        }
+
+        fn from_values(values: &[Value]) -> Option<Self> {
+            Some(Gold(values.first()?.as_int()?))
+        }
+    }
+
+    const PACK: &str = r#"
+        (module
+          (import "rete" "binding" (func $binding (param i32 i32) (result i64)))
+          (import "rete" "read_fact" (func $read (param i32 i32 i64) (result i32)))
+          (import "rete" "field" (func $field (param i32) (result i64)))
+          (import "rete" "push_int" (func $push_int (param i64)))
+          (import "rete" "assert_fact" (func $assert (param i32 i32 i64) (result i32)))
+          (import "rete" "emit_event" (func $emit (param i32 i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 0) "eGoldtaxed")
+          (func (export "tax") (local $e i64)
+            (local.set $e (call $binding (i32.const 0) (i32.const 1)))
+            (drop (call $read (i32.const 1) (i32.const 4) (local.get $e)))
+            (call $push_int (i64.div_s (call $field (i32.const 0)) (i64.const 2)))
+            (drop (call $assert (i32.const 1) (i32.const 4) (local.get $e)))
+            (call $push_int (local.get $e))
+            (call $emit (i32.const 5) (i32.const 5)))
+          (func (export "spin") (loop $forever (br $forever))))
+    "#;
+
+    #[test]
+    fn plugins_act_through_the_host_interface() {
+        let mut engine = RuleEngine::new();
+        engine.register::<Gold>("Gold");
+        engine
+            .load_plugin("pack", &wat::parse_str(PACK).unwrap())
+            .unwrap();
+        assert!(engine.load_plugin("junk", b"not wasm").is_err());
+        engine
+            .load_str(r#"rule "tax" when Gold(e, g), g > 100 then run "pack.tax""#)
+            .unwrap();
+        assert!(engine
+            .load_str(r#"rule "x" when Gold(e, _) then run "pack.missing""#)
+            .is_err());
+
+        let mut store = EntityStore::new();
+        store.new_component::<Gold>();
+        store.add_component(1, Gold(400));
+        store.add_component(2, Gold(50));
+        engine.run(&mut store);
+        let gold = store.get::<Gold>().unwrap().borrow();
+        assert_eq!(
+            (gold.get(1), gold.get(2)),
+            (Some(&Gold(100)), Some(&Gold(50)))
+        );
+        drop(gold);
+        let events = store.events::<PluginEvent>().unwrap().borrow();
+        let sent: Vec<_> = events.reader_from_oldest().read(&events).cloned().collect();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(
+            (sent[0].name.as_str(), &sent[0].values),
+            ("taxed", &vec![Value::Int(1)])
+        );
+        drop(events);
+
+        let failures = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = failures.clone();
+        store.subscribe(move |event: &StoreEvent| {
+            if let StoreEvent::ActionFailed { message, .. } = event {
+                seen.lock().unwrap().push(message.clone());
+            }
+        });
+        engine
+            .load_str(r#"rule "spin" when Gold(e, 50) then run "pack.spin""#)
+            .unwrap();
+        engine.run(&mut store);
+        assert_eq!(failures.lock().unwrap().len(), 1);
+    }
+}
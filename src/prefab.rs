@@ -0,0 +1,236 @@
+// Entity templates
+//
+// A prefab lists the components an entity starts with, written as rule patterns are
+// but without the entity, like `Health(hp), Team("orc"), Position(x, y)`. Constants are
+// the same for every instance, variables are parameters, each filled from the
+// overrides given to spawn_prefab or else from the prefab's defaults. Prefabs are
+// checked against the registry when parsed and defined on the store by name, then
+// spawn_prefab gives a new entity the components, through the registry as rules insert
+// them, in one undo step. with_component adds a type's default as well, for markers and
+// other components with nothing to fill in, registered or not.
+use crate::dsl;
+use crate::error::Error;
+use crate::registry::{ComponentInfo, Registry};
+use crate::rule::{Condition, Term};
+use crate::store::{Component, EntityId, EntityStore};
+use crate::value::{Bindings, Value};
+
+#[derive(Debug, Clone)]
+pub struct Prefab {
+    components: Vec<(ComponentInfo, Vec<Term>)>,
+    defaults: Bindings,
+    // Components added as their defaults, see insert_default
+    inserts: Vec<fn(&mut EntityStore, EntityId)>,
+}
+
+impl Prefab {
+    // Fails if a component isn't registered, has the wrong number of fields, or comes
+    // with anything other than plain fields
+    pub fn parse(source: &str, registry: &Registry) -> Result<Prefab, Error> {
+        let mut components = Vec::new();
+        for condition in dsl::parse_conditions(source)? {
+            let pattern = match condition {
+                Condition::Pattern(pattern)
+                    if pattern.guard.is_none() && pattern.temporal.is_none() =>
+                {
+                    pattern
+                }
+                _ => {
+                    return Err(Error::Parse {
+                        line: 1,
+                        column: 1,
+                        message: "prefabs only list components and their fields".to_string(),
+                    })
+                }
+            };
+            let info = registry
+                .get(&pattern.component)
+                .filter(|info| !info.is_derived())
+                .ok_or_else(|| Error::UnknownComponent(pattern.component.clone()))?;
+            if pattern.args.len() != info.fields.len() {
+                return Err(Error::Arity {
+                    component: pattern.component,
+                    expected: info.fields.len(),
+                    found: pattern.args.len(),
+                });
+            }
+            components.push((info.clone(), pattern.args));
+        }
+        Ok(Prefab {
+            components,
+            defaults: Bindings::new(),
+            inserts: Vec::new(),
+        })
+    }
+
+    // The value a parameter takes when spawn_prefab isn't given one
+    pub fn with_default(mut self, parameter: &str, value: impl Into<Value>) -> Self {
+        self.defaults.insert(parameter.to_string(), value.into());
+        self
+    }
+
+    // Instances also get T's default
+    pub fn with_component<T: Component + Eq + Default + 'static>(mut self) -> Self {
+        self.inserts.push(EntityStore::insert_default::<T>);
+        self
+    }
+
+    // Every parameter, in the order first used
+    pub fn parameters(&self) -> Vec<&str> {
+        let mut parameters = Vec::new();
+        for (_, args) in &self.components {
+            for arg in args {
+                if let Term::Var(name) = arg {
+                    if !parameters.contains(&name.as_str()) {
+                        parameters.push(name.as_str());
+                    }
+                }
+            }
+        }
+        parameters
+    }
+}
+
+impl EntityStore {
+    // Replaces any prefab of the same name
+    pub fn define_prefab(&mut self, name: &str, prefab: Prefab) {
+        self.prefabs.insert(name.to_string(), prefab);
+    }
+
+    // A new entity laid out by the named prefab, see above
+    // Fails, leaving the store as it was, if the prefab isn't defined, a parameter has
+    // no value, or a value doesn't fit its field
+    pub fn spawn_prefab(&mut self, name: &str, overrides: &Bindings) -> Result<EntityId, Error> {
+        let prefab = self
+            .prefabs
+            .get(name)
+            .ok_or_else(|| Error::UnknownPrefab(name.to_string()))?;
+        let mut rows = Vec::with_capacity(prefab.components.len());
+        for (info, args) in &prefab.components {
+            let values = args
+                .iter()
+                .map(|arg| match arg {
+                    Term::Const(value) => Ok(value.clone()),
+                    Term::Var(parameter) => overrides
+                        .get(parameter)
+                        .or_else(|| prefab.defaults.get(parameter))
+                        .cloned()
+                        .ok_or_else(|| Error::UnboundVariable {
+                            rule: name.to_string(),
+                            variable: parameter.clone(),
+                        }),
+                    Term::Wildcard => Err(Error::UnboundVariable {
+                        rule: name.to_string(),
+                        variable: "_".to_string(),
+                    }),
+                })
+                .collect::<Result<Vec<Value>, Error>>()?;
+            rows.push((info.clone(), values));
+        }
+
+        let entity_id = self.next_entity();
+        self.begin_step();
+        let unbuilt = rows
+            .iter()
+            .find(|(info, values)| !info.insert(self, entity_id, values));
+        let result = match unbuilt {
+            Some((info, values)) => {
+                self.remove_entity(entity_id);
+                Err(Error::Unbuildable {
+                    component: info.name.clone(),
+                    values: values.clone(),
+                })
+            }
+            None => {
+                for insert in self.prefabs[name].inserts.clone() {
+                    insert(self, entity_id);
+                }
+                self.reserve_up_to(entity_id);
+                Ok(entity_id)
+            }
+        };
+        self.end_step();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::Fact;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Health(i64);
+    impl Component for Health {}
+
+    impl Fact for Health {
+        const FIELDS: &'static [&'static str] = &["hp"];
+
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.0)]
+        }
+
+        fn from_values(values: &[Value]) -> Option<Self> {
+            Some(Health(values.first()?.as_int()?))
+        }
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Team(String);
+    impl Component for Team {}
+
+    impl Fact for Team {
+        const FIELDS: &'static [&'static str] = &["name"];
+
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Str(self.0.clone())]
+        }
+
+        fn from_values(values: &[Value]) -> Option<Self> {
+            Some(Team(values.first()?.as_str()?.to_string()))
+        }
+    }
+
+    #[derive(Debug, Default, PartialEq, Eq)]
+    struct Hostile;
+    impl Component for Hostile {}
+
+    #[test]
+    fn prefabs_spawn_with_overrides() {
+        let mut registry = Registry::new();
+        registry.register::<Health>("Health");
+        registry.register::<Team>("Team");
+        let goblin = Prefab::parse(r#"Health(hp), Team("orc")"#, &registry)
+            .unwrap()
+            .with_default("hp", 30)
+            .with_component::<Hostile>();
+        assert_eq!(goblin.parameters(), ["hp"]);
+        let mut store = EntityStore::new();
+        store.define_prefab("goblin", goblin);
+
+        let first = store.spawn_prefab("goblin", &Bindings::new()).unwrap();
+        let overrides = Bindings::from([("hp".to_string(), Value::Int(80))]);
+        let chief = store.spawn_prefab("goblin", &overrides).unwrap();
+        assert_eq!((first, chief), (0, 1));
+        let health = store.get::<Health>().unwrap();
+        assert_eq!(health.borrow().get(1), Some(&Health(80)));
+        let teams = store.get::<Team>().unwrap();
+        assert_eq!(teams.borrow().get(0), Some(&Team("orc".to_string())));
+        assert!(store.has_component::<Hostile>(1));
+
+        let wrong = Bindings::from([("hp".to_string(), Value::Str("lots".to_string()))]);
+        assert!(matches!(
+            store.spawn_prefab("goblin", &wrong),
+            Err(Error::Unbuildable { .. })
+        ));
+        assert!(!store.has_component::<Team>(2));
+        assert_eq!(
+            store.spawn_prefab("troll", &Bindings::new()),
+            Err(Error::UnknownPrefab("troll".to_string()))
+        );
+        assert!(matches!(
+            Prefab::parse("Health(1, 2)", &registry),
+            Err(Error::Arity { expected: 1, .. })
+        ));
+    }
+}
@@ -0,0 +1,159 @@
+// Dry runs
+//
+// preview lists the activations ready to fire, in the order they would, with what each
+// one's actions would do, without touching the store or the engine. Each is worked out
+// against the store as it is now, as if it fired first: in a real run an earlier firing
+// can change what a later one matches or does, or cancel it, and rules only matching
+// once others have fired don't show up at all. Reactive rules wait on changes, so
+// they're left out, as are activations that have fired or are held back, see
+// looping.rs. Inserts that wouldn't change the store, and removes of components that
+// aren't there, are left out like they are from the trace.
+use crate::constraint::Constraint;
+use crate::engine::RuleEngine;
+use crate::rule::Action;
+use crate::store::EntityStore;
+use crate::trace::Mutation;
+use crate::value::{Bindings, Value};
+
+// What one action of a previewed activation would do
+#[derive(Debug, Clone, PartialEq)]
+pub enum Effect {
+    Mutation(Mutation),
+    Run { script: String },
+    Post { constraint: Constraint },
+    Minimize { cost: Constraint },
+}
+
+// An activation that's ready, with what firing it would do
+#[derive(Debug, Clone, PartialEq)]
+pub struct Preview {
+    pub rule: String,
+    pub bindings: Bindings,
+    pub effects: Vec<Effect>,
+}
+
+impl RuleEngine {
+    // See above
+    pub fn preview(&self, store: &EntityStore) -> Vec<Preview> {
+        self.activations(store)
+            .into_iter()
+            .filter(|activation| !self.fired.contains(activation) && !self.held_back(activation))
+            .map(|activation| {
+                let rule = &self.rules[activation.rule];
+                let bindings = activation.bindings;
+                let effects = rule
+                    .actions
+                    .iter()
+                    .filter_map(|action| self.effect(action, &bindings, store))
+                    .collect();
+                Preview {
+                    rule: rule.name.clone(),
+                    bindings,
+                    effects,
+                }
+            })
+            .collect()
+    }
+
+    // What the action would do, mirroring fire
+    fn effect(&self, action: &Action, bindings: &Bindings, store: &EntityStore) -> Option<Effect> {
+        match action {
+            Action::Insert {
+                component, args, ..
+            } => {
+                let info = self.registry().get(component)?;
+                let values = args
+                    .iter()
+                    .map(|arg| arg.eval(bindings))
+                    .collect::<Option<Vec<_>>>()?;
+                let entity = values.first().and_then(Value::as_entity)?;
+                let values = values[1..].to_vec();
+                if info.rows(store, entity).contains(&values) {
+                    return None;
+                }
+                Some(Effect::Mutation(Mutation::Inserted {
+                    component: component.clone(),
+                    entity,
+                    values,
+                }))
+            }
+            Action::Remove { component, entity } => {
+                let info = self.registry().get(component)?;
+                let entity = entity.eval(bindings)?.as_entity()?;
+                Some(Effect::Mutation(Mutation::Removed {
+                    component: component.clone(),
+                    entity,
+                    values: info.get(store, entity)?,
+                }))
+            }
+            Action::Run { script } => Some(Effect::Run {
+                script: script.clone(),
+            }),
+            Action::Post { constraint } => Some(Effect::Post {
+                constraint: Constraint {
+                    expr: constraint.clone(),
+                    bindings: bindings.clone(),
+                },
+            }),
+            Action::Minimize { cost } => Some(Effect::Minimize {
+                cost: Constraint {
+                    expr: cost.clone(),
+                    bindings: bindings.clone(),
+                },
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn previews_leave_the_world_alone() {
+        let mut engine = RuleEngine::new();
+        engine
+            .load_str(
+                r#"
+                fact Health(points)
+                fact Fleeing()
+                rule "flee" salience 1 when Health(e, h), h < 10 then insert Fleeing(e), remove Health(e)
+                rule "calm" when Fleeing(e) then remove Fleeing(e)
+                "#,
+            )
+            .unwrap();
+        let registry = engine.registry().clone();
+        let mut store = EntityStore::new();
+        store
+            .insert_fact(&registry, "Health", 1, &[Value::Int(5)])
+            .unwrap();
+        store
+            .insert_fact(&registry, "Health", 2, &[Value::Int(50)])
+            .unwrap();
+        let before = registry.encode_store(&store);
+
+        let preview = engine.preview(&store);
+        assert_eq!(preview.len(), 1);
+        assert_eq!(preview[0].rule, "flee");
+        assert_eq!(
+            preview[0].effects,
+            [
+                Effect::Mutation(Mutation::Inserted {
+                    component: "Fleeing".to_string(),
+                    entity: 1,
+                    values: Vec::new(),
+                }),
+                Effect::Mutation(Mutation::Removed {
+                    component: "Health".to_string(),
+                    entity: 1,
+                    values: vec![Value::Int(5)],
+                }),
+            ]
+        );
+        assert_eq!(registry.encode_store(&store), before);
+
+        // Previewing again, or running afterwards, goes as if it never happened
+        assert_eq!(engine.preview(&store), preview);
+        assert_eq!(engine.run(&mut store), 2);
+    }
+}
@@ -0,0 +1,181 @@
+// Weighted rules and the probabilities of what they derive
+//
+// A rule can be given a weight, `rule "flu" weight 1.5 when ...`, saying how far to
+// trust its conclusions: the log odds that they hold when its conditions do, so 0 is a
+// coin flip and the higher the surer. Unweighted rules are certain. Running treats
+// weighted rules like any other; marginals works out afterwards how probable each fact
+// the rules conclude from the current store is, weighted rules making hypotheses to rank
+// rather than plain yes or no derivations.
+//
+// Each current match of a rule is one way for the facts it inserts to hold, as likely as
+// the rule times every fact its patterns matched, and a fact holds if any of its ways
+// do, taking them as independent. Facts no match concludes, and ones asserted from
+// outside, are certain, as are the facts `not` rules out. Rules concluding from each
+// other's conclusions are worked round until the probabilities settle. A fact is
+// concluded whether or not it's in the store, so rules inserting competing values for
+// one component on one entity each get their own probability to rank by.
+use crate::engine::RuleEngine;
+use crate::provenance::FactKey;
+use crate::rule::Action;
+use crate::store::{EntityId, EntityStore};
+use crate::value::Value;
+use std::collections::HashMap;
+
+// Each fact the rules conclude, with how probable it is
+pub type Marginals = HashMap<FactKey, f64>;
+
+// Settled once no probability moves more than this between rounds
+const SETTLED: f64 = 1e-9;
+const ROUNDS: usize = 1000;
+
+// The probability of a weight in log odds
+pub fn logistic(weight: f64) -> f64 {
+    1.0 / (1.0 + (-weight).exp())
+}
+
+impl RuleEngine {
+    // How probable each fact the rules conclude is, see above
+    pub fn marginals(&self, store: &EntityStore) -> Marginals {
+        // Each conclusion, with the ways it's reached: the rule's probability and premises
+        let mut ways: HashMap<FactKey, Vec<(f64, Vec<FactKey>)>> = HashMap::new();
+        for activation in self.activations(store) {
+            let rule = &self.rules[activation.rule];
+            let likely = rule.weight.map_or(1.0, logistic);
+            let premises: Vec<FactKey> = activation
+                .premises
+                .iter()
+                .map(|premise| {
+                    (
+                        premise.component.clone(),
+                        premise.entity,
+                        premise.values.clone(),
+                    )
+                })
+                .collect();
+            for action in &rule.actions {
+                let Action::Insert {
+                    component, args, ..
+                } = action
+                else {
+                    continue;
+                };
+                let Some(values) = args
+                    .iter()
+                    .map(|arg| arg.eval(&activation.bindings))
+                    .collect::<Option<Vec<Value>>>()
+                else {
+                    continue;
+                };
+                let Some(entity_id) = values.first().and_then(Value::as_entity) else {
+                    continue;
+                };
+                let key = (component.clone(), entity_id, values[1..].to_vec());
+                ways.entry(key)
+                    .or_default()
+                    .push((likely, premises.clone()));
+            }
+        }
+
+        // Asserted facts are certain however else they could be concluded
+        let asserted = |(component, entity_id, values): &FactKey| {
+            let held = self
+                .registry()
+                .get(component)
+                .is_some_and(|info| info.rows(store, *entity_id).contains(values));
+            held && !self
+                .provenance
+                .contains_key(&(component.clone(), *entity_id, values.clone()))
+        };
+        let mut marginals: Marginals = ways
+            .keys()
+            .map(|key| (key.clone(), asserted(key) as u8 as f64))
+            .collect();
+        for _ in 0..ROUNDS {
+            let mut moved: f64 = 0.0;
+            for (key, reached) in &ways {
+                if asserted(key) {
+                    continue;
+                }
+                let unlikely: f64 = reached
+                    .iter()
+                    .map(|(likely, premises)| {
+                        let held: f64 = premises
+                            .iter()
+                            .map(|premise| marginals.get(premise).copied().unwrap_or(1.0))
+                            .product();
+                        1.0 - likely * held
+                    })
+                    .product();
+                let probability = 1.0 - unlikely;
+                moved = moved.max((probability - marginals[key]).abs());
+                marginals.insert(key.clone(), probability);
+            }
+            if moved <= SETTLED {
+                break;
+            }
+        }
+        marginals
+    }
+
+    // The component's concluded facts, most probable first
+    pub fn ranked(&self, component: &str, store: &EntityStore) -> Vec<(EntityId, Vec<Value>, f64)> {
+        let mut ranked: Vec<(EntityId, Vec<Value>, f64)> = self
+            .marginals(store)
+            .into_iter()
+            .filter(|((name, _, _), _)| name == component)
+            .map(|((_, entity_id, values), probability)| (entity_id, values, probability))
+            .collect();
+        ranked.sort_by(|a, b| {
+            b.2.total_cmp(&a.2).then(a.0.cmp(&b.0)).then_with(|| {
+                let text = |values: &[Value]| -> Vec<String> {
+                    values.iter().map(Value::to_string).collect()
+                };
+                text(&a.1).cmp(&text(&b.1))
+            })
+        });
+        ranked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weighted_rules_rank_hypotheses() {
+        let mut engine = RuleEngine::new();
+        engine
+            .load_str(
+                r#"
+                fact Fever()
+                fact Cough()
+                fact Diagnosis(illness)
+                fact Flu()
+                fact Contagious()
+                rule "flu?" weight 1.5 when Fever(p), Cough(p) then insert Diagnosis(p, "flu")
+                rule "cold?" weight 0 when Cough(p) then insert Diagnosis(p, "cold")
+                rule "flu" weight 1.5 when Fever(p), Cough(p) then insert Flu(p)
+                rule "spread" when Flu(p) then insert Contagious(p)
+                rule "fever" weight -1 when Fever(p) then insert Contagious(p)
+                "#,
+            )
+            .unwrap();
+        let registry = engine.registry().clone();
+        let mut store = EntityStore::new();
+        store.insert_fact(&registry, "Fever", 1, &[]).unwrap();
+        store.insert_fact(&registry, "Cough", 1, &[]).unwrap();
+        engine.run(&mut store);
+
+        let ranked = engine.ranked("Diagnosis", &store);
+        let flu = logistic(1.5);
+        assert_eq!(ranked.len(), 2);
+        assert_eq!(ranked[0].1, [Value::from("flu")]);
+        assert!((ranked[0].2 - flu).abs() < 1e-9);
+        assert!((ranked[1].2 - 0.5).abs() < 1e-9);
+
+        // Contagious through flu or through fever alone
+        let contagious = engine.marginals(&store)[&("Contagious".to_string(), 1, Vec::new())];
+        let expected = 1.0 - (1.0 - flu) * (1.0 - logistic(-1.0));
+        assert!((contagious - expected).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,219 @@
+// Profiling rules and pools
+//
+// Once enabled, the engine records for each rule how long matching and firing it took
+// and how often, and for each pool how its facts were reached while matching: looked up
+// by entity, through an index or a join, or by scanning the whole pool. A rule taking
+// most of the time is the one to rewrite, and a pool scanned over and over, with many
+// rows read, is one a pattern would like an index on, see index.rs.
+//
+// profile_report gives the numbers so far, rules by time taken and pools by rows read,
+// and prints as a table. Counting stops when profiling is disabled, and enabling it
+// again starts from nothing.
+use crate::engine::RuleEngine;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RuleProfile {
+    pub rule: String,
+    // Times the rule's conditions were matched, and the matches found
+    pub matched: usize,
+    pub matches: usize,
+    pub matching: Duration,
+    pub fired: usize,
+    pub firing: Duration,
+}
+
+impl RuleProfile {
+    pub fn total(&self) -> Duration {
+        self.matching + self.firing
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PoolProfile {
+    pub component: String,
+    // Reads of one entity's facts, or of the entities an index or join picked out
+    pub lookups: usize,
+    // Reads of every fact in the pool
+    pub scans: usize,
+    // Facts read either way
+    pub rows: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProfileReport {
+    // Most time taken first
+    pub rules: Vec<RuleProfile>,
+    // Most rows read first
+    pub pools: Vec<PoolProfile>,
+}
+
+impl ProfileReport {
+    pub fn rule(&self, name: &str) -> Option<&RuleProfile> {
+        self.rules.iter().find(|rule| rule.rule == name)
+    }
+
+    pub fn pool(&self, component: &str) -> Option<&PoolProfile> {
+        self.pools.iter().find(|pool| pool.component == component)
+    }
+}
+
+// What's been counted so far, rules by name so replacing rules keeps their counts
+#[derive(Debug, Default)]
+pub(crate) struct Profile {
+    rules: HashMap<String, RuleProfile>,
+    pools: HashMap<String, PoolProfile>,
+}
+
+impl RuleEngine {
+    pub fn enable_profiling(&mut self) {
+        if self.profile.is_none() {
+            self.profile = Some(Mutex::new(Profile::default()));
+        }
+    }
+
+    pub fn disable_profiling(&mut self) {
+        self.profile = None;
+    }
+
+    // None unless profiling is enabled, see above
+    pub fn profile_report(&self) -> Option<ProfileReport> {
+        let profile = self.profiling()?;
+        let mut rules: Vec<RuleProfile> = profile.rules.values().cloned().collect();
+        rules.sort_by(|a, b| b.total().cmp(&a.total()).then(a.rule.cmp(&b.rule)));
+        let mut pools: Vec<PoolProfile> = profile.pools.values().cloned().collect();
+        pools.sort_by(|a, b| b.rows.cmp(&a.rows).then(a.component.cmp(&b.component)));
+        Some(ProfileReport { rules, pools })
+    }
+
+    fn profiling(&self) -> Option<MutexGuard<'_, Profile>> {
+        let profile = self.profile.as_ref()?;
+        Some(
+            profile
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()),
+        )
+    }
+
+    fn rule_profile<'a>(&self, profile: &'a mut Profile, rule: usize) -> &'a mut RuleProfile {
+        let name = &self.rules[rule].name;
+        profile
+            .rules
+            .entry(name.clone())
+            .or_insert_with(|| RuleProfile {
+                rule: name.clone(),
+                ..Default::default()
+            })
+    }
+
+    pub(crate) fn count_match(&self, rule: usize, matches: usize, took: Duration) {
+        if let Some(mut profile) = self.profiling() {
+            let counted = self.rule_profile(&mut profile, rule);
+            counted.matched += 1;
+            counted.matches += matches;
+            counted.matching += took;
+        }
+    }
+
+    pub(crate) fn count_fire(&self, rule: usize, took: Duration) {
+        if let Some(mut profile) = self.profiling() {
+            let counted = self.rule_profile(&mut profile, rule);
+            counted.fired += 1;
+            counted.firing += took;
+        }
+    }
+
+    pub(crate) fn count_read(&self, component: &str, scanned: bool, rows: usize) {
+        if let Some(mut profile) = self.profiling() {
+            let counted = profile
+                .pools
+                .entry(component.to_string())
+                .or_insert_with(|| PoolProfile {
+                    component: component.to_string(),
+                    ..Default::default()
+                });
+            match scanned {
+                true => counted.scans += 1,
+                false => counted.lookups += 1,
+            }
+            counted.rows += rows;
+        }
+    }
+}
+
+// One line per rule and per pool
+impl fmt::Display for ProfileReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "rule                   matched  matches   matching  fired     firing"
+        )?;
+        for rule in &self.rules {
+            writeln!(
+                f,
+                "{:<22} {:>7} {:>8} {:>10.3?} {:>6} {:>10.3?}",
+                format!("{:?}", rule.rule),
+                rule.matched,
+                rule.matches,
+                rule.matching,
+                rule.fired,
+                rule.firing
+            )?;
+        }
+        writeln!(f, "pool                   lookups    scans       rows")?;
+        for pool in &self.pools {
+            writeln!(
+                f,
+                "{:<22} {:>7} {:>8} {:>10}",
+                pool.component, pool.lookups, pool.scans, pool.rows
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::EntityStore;
+    use crate::value::Value;
+
+    #[test]
+    fn profiles_rules_and_pools() {
+        let mut engine = RuleEngine::new();
+        engine
+            .load_str(
+                r#"
+                fact Health(points)
+                fact Fleeing()
+                rule "flee" when Health(e, h), h < 10, not Fleeing(e) then insert Fleeing(e)
+                "#,
+            )
+            .unwrap();
+        let registry = engine.registry().clone();
+        let mut store = EntityStore::new();
+        for (entity_id, health) in [(1, 5), (2, 50), (3, 7)] {
+            store
+                .insert_fact(&registry, "Health", entity_id, &[Value::Int(health)])
+                .unwrap();
+        }
+        assert_eq!(engine.profile_report(), None);
+        engine.enable_profiling();
+        engine.run(&mut store);
+
+        let report = engine.profile_report().unwrap();
+        let flee = report.rule("flee").unwrap();
+        // Matched before each of the two firings and once more to find nothing left
+        assert_eq!((flee.matched, flee.fired), (3, 2));
+        assert_eq!(flee.matches, 2 + 1);
+        // Health is scanned each time, Fleeing looked up for each entity under 10
+        let health = report.pool("Health").unwrap();
+        assert_eq!((health.scans, health.lookups, health.rows), (3, 0, 9));
+        let fleeing = report.pool("Fleeing").unwrap();
+        assert_eq!((fleeing.scans, fleeing.lookups), (0, 6));
+        assert!(report.to_string().contains("\"flee\""));
+    }
+}
@@ -0,0 +1,220 @@
+// Provenance of derived facts
+//
+// Whenever a rule inserts a component the engine remembers the rule, its bindings and
+// the facts its patterns matched. explain walks that back into a derivation tree, e.g.
+//
+// Fleeing(#1) by "flee" with e = #1, h = 5
+//   Health(#1, 5) asserted
+use crate::engine::RuleEngine;
+use crate::store::{EntityId, EntityStore};
+use crate::value::{Bindings, Value};
+use std::collections::HashSet;
+use std::fmt;
+
+// A component, the entity it is on, and its field values
+pub type FactKey = (String, EntityId, Vec<Value>);
+
+// A fact matched by a rule, with its field values at the time
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Premise {
+    pub component: String,
+    pub entity: EntityId,
+    pub values: Vec<Value>,
+}
+
+// Recorded by the engine when a rule inserts a fact
+#[derive(Debug, Clone, PartialEq)]
+pub struct Provenance {
+    pub rule: String,
+    pub bindings: Bindings,
+    pub premises: Vec<Premise>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Derivation {
+    pub component: String,
+    pub entity: EntityId,
+    pub values: Vec<Value>,
+    // None for facts that were asserted from outside rather than derived by a rule
+    pub rule: Option<String>,
+    pub bindings: Bindings,
+    pub premises: Vec<Derivation>,
+}
+
+impl RuleEngine {
+    // Why does the entity have this component?
+    // None if it doesn't have it, or T isn't registered
+    pub fn explain<T: 'static>(&self, store: &EntityStore, entity: EntityId) -> Option<Derivation> {
+        let name = self.registry().get_type::<T>()?.name.clone();
+        self.explain_fact(store, &name, entity)
+    }
+
+    // explain by registered component name
+    pub fn explain_fact(
+        &self,
+        store: &EntityStore,
+        component: &str,
+        entity: EntityId,
+    ) -> Option<Derivation> {
+        let values = self.registry().get(component)?.get(store, entity)?;
+        Some(self.derivation(component, entity, values, &mut HashSet::new()))
+    }
+
+    // Facts can be rederived from themselves (Health(e, h) then insert Health(e, h - 1)),
+    // so anything already on the path is treated as a leaf to keep the tree finite
+    fn derivation(
+        &self,
+        component: &str,
+        entity: EntityId,
+        values: Vec<Value>,
+        path: &mut HashSet<FactKey>,
+    ) -> Derivation {
+        let key = (component.to_string(), entity, values);
+        let provenance = match self.provenance.get(&key) {
+            Some(provenance) if !path.contains(&key) => provenance,
+            _ => {
+                return Derivation {
+                    component: component.to_string(),
+                    entity,
+                    values: key.2,
+                    rule: None,
+                    bindings: Bindings::new(),
+                    premises: Vec::new(),
+                }
+            }
+        };
+
+        path.insert(key.clone());
+        let premises = provenance
+            .premises
+            .iter()
+            .map(|premise| {
+                self.derivation(
+                    &premise.component,
+                    premise.entity,
+                    premise.values.clone(),
+                    path,
+                )
+            })
+            .collect();
+        path.remove(&key);
+
+        Derivation {
+            component: component.to_string(),
+            entity,
+            values: key.2,
+            rule: Some(provenance.rule.clone()),
+            bindings: provenance.bindings.clone(),
+            premises,
+        }
+    }
+}
+
+impl Derivation {
+    fn write(&self, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
+        write!(
+            f,
+            "{:indent$}{}(#{}",
+            "",
+            self.component,
+            self.entity,
+            indent = depth * 2
+        )?;
+        for value in &self.values {
+            write!(f, ", {}", value)?;
+        }
+        match &self.rule {
+            Some(rule) => {
+                write!(f, ") by {:?} with ", rule)?;
+                let bindings: Vec<String> = self
+                    .bindings
+                    .iter()
+                    .map(|(name, value)| format!("{} = {}", name, value))
+                    .collect();
+                writeln!(f, "{}", bindings.join(", "))?;
+            }
+            None => writeln!(f, ") asserted")?,
+        }
+        for premise in &self.premises {
+            premise.write(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Derivation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::Fact;
+    use crate::store::Component;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Health(i64);
+    impl Component for Health {}
+    impl Fact for Health {
+        const FIELDS: &'static [&'static str] = &["value"];
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.0)]
+        }
+        fn from_values(values: &[Value]) -> Option<Self> {
+            match values {
+                [value] => Some(Health(value.as_int()?)),
+                _ => None,
+            }
+        }
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Tag(i64);
+    impl Component for Tag {}
+    impl Fact for Tag {
+        const FIELDS: &'static [&'static str] = &["level"];
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.0)]
+        }
+        fn from_values(values: &[Value]) -> Option<Self> {
+            match values {
+                [value] => Some(Tag(value.as_int()?)),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn explains_chain_of_rules() {
+        let mut engine = RuleEngine::new();
+        engine.register::<Health>("Health");
+        engine.register::<Tag>("Tag");
+        engine
+            .load_str(
+                r#"
+                rule "hurt" when Health(e, h), h < 10, not Tag(e, _) then insert Tag(e, 1)
+                rule "danger" when Tag(e, 1), Health(e, h), h < 5 then insert Tag(e, 2)
+                "#,
+            )
+            .unwrap();
+
+        let mut store = EntityStore::new();
+        store.new_component::<Health>();
+        store.add_component(1, Health(3));
+        engine.run(&mut store);
+
+        let derivation = engine.explain::<Tag>(&store, 1).unwrap();
+        assert_eq!(
+            derivation.to_string(),
+            "Tag(#1, 2) by \"danger\" with e = #1, h = 3\n\
+             \x20 Tag(#1, 1) by \"hurt\" with e = #1, h = 3\n\
+             \x20   Health(#1, 3) asserted\n\
+             \x20 Health(#1, 3) asserted\n"
+        );
+
+        assert_eq!(engine.explain::<Health>(&store, 1).unwrap().rule, None);
+        assert!(engine.explain::<Tag>(&store, 2).is_none());
+    }
+}
@@ -0,0 +1,201 @@
+// Quadtree spatial index, for worlds where entities bunch together
+//
+// Each node covers a quarter of its parent and splits once it holds too many entries.
+// Entries are bounding boxes, kept in the deepest node that wholly contains them, so a
+// box straddling a split stays with the parent. Anything outside the tree's bounds is
+// kept at the root, which makes the bounds a hint for balance rather than a limit.
+use crate::spatial::{Position, Rect, SpatialIndex};
+use crate::store::EntityId;
+use std::collections::HashMap;
+
+// Entries a node holds before splitting, and how deep splitting may go
+const CAPACITY: usize = 8;
+const MAX_DEPTH: usize = 16;
+
+#[derive(Debug, Clone)]
+struct Node {
+    bounds: Rect,
+    entries: Vec<EntityId>,
+    // Indices of the four quarters once split
+    children: Option<[usize; 4]>,
+}
+
+impl Node {
+    fn new(bounds: Rect) -> Self {
+        Node {
+            bounds,
+            entries: Vec::new(),
+            children: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QuadTree {
+    // Nodes are never freed, the root is always the first
+    nodes: Vec<Node>,
+    boxes: HashMap<EntityId, Rect>,
+}
+
+impl QuadTree {
+    pub fn new(bounds: Rect) -> Self {
+        QuadTree {
+            nodes: vec![Node::new(bounds)],
+            boxes: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.boxes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.boxes.is_empty()
+    }
+
+    // Indexes the entity as covering a whole rectangle, replacing any earlier entry
+    pub fn insert_bounds(&mut self, entity_id: EntityId, bounds: Rect) {
+        self.remove(entity_id);
+        self.boxes.insert(entity_id, bounds);
+
+        let path = self.path(&bounds);
+        let (&node, depth) = (path.last().unwrap(), path.len());
+        self.nodes[node].entries.push(entity_id);
+        if self.nodes[node].entries.len() > CAPACITY && depth < MAX_DEPTH {
+            self.split(node);
+        }
+    }
+
+    // The nodes from the root down to the deepest one wholly containing bounds
+    fn path(&self, bounds: &Rect) -> Vec<usize> {
+        let mut path = vec![0];
+        while let Some(children) = self.nodes[*path.last().unwrap()].children {
+            match children
+                .into_iter()
+                .find(|&child| self.nodes[child].bounds.contains_rect(bounds))
+            {
+                Some(child) => path.push(child),
+                None => break,
+            }
+        }
+        path
+    }
+
+    fn split(&mut self, node: usize) {
+        let Rect { min, max } = self.nodes[node].bounds;
+        // A single row or column can't be quartered
+        if min.x == max.x || min.y == max.y {
+            return;
+        }
+        let mid = Position::new(min.x + (max.x - min.x) / 2, min.y + (max.y - min.y) / 2);
+        let quarters = [
+            Rect::new(min, mid),
+            Rect::new(Position::new(mid.x + 1, min.y), Position::new(max.x, mid.y)),
+            Rect::new(Position::new(min.x, mid.y + 1), Position::new(mid.x, max.y)),
+            Rect::new(Position::new(mid.x + 1, mid.y + 1), max),
+        ];
+        let first = self.nodes.len();
+        self.nodes.extend(quarters.map(Node::new));
+        self.nodes[node].children = Some([first, first + 1, first + 2, first + 3]);
+
+        // Move down whatever fits in a quarter, splitting again only on later inserts
+        let entries = std::mem::take(&mut self.nodes[node].entries);
+        for entity_id in entries {
+            let bounds = self.boxes[&entity_id];
+            let home = (first..first + 4)
+                .find(|&child| self.nodes[child].bounds.contains_rect(&bounds))
+                .unwrap_or(node);
+            self.nodes[home].entries.push(entity_id);
+        }
+    }
+}
+
+impl SpatialIndex for QuadTree {
+    fn insert(&mut self, entity_id: EntityId, position: Position) {
+        self.insert_bounds(entity_id, Rect::point(position));
+    }
+
+    fn remove(&mut self, entity_id: EntityId) {
+        let Some(bounds) = self.boxes.remove(&entity_id) else {
+            return;
+        };
+        let node = *self.path(&bounds).last().unwrap();
+        self.nodes[node].entries.retain(|&other| other != entity_id);
+    }
+
+    fn bounds(&self, entity_id: EntityId) -> Option<Rect> {
+        self.boxes.get(&entity_id).copied()
+    }
+
+    fn in_rect(&self, rect: Rect) -> Vec<EntityId> {
+        let mut found = Vec::new();
+        // The root is always searched, it holds entries outside the tree's bounds
+        let mut stack = vec![0];
+        while let Some(node) = stack.pop() {
+            let node = &self.nodes[node];
+            found.extend(
+                node.entries
+                    .iter()
+                    .filter(|entity_id| self.boxes[entity_id].intersects(&rect)),
+            );
+            if let Some(children) = node.children {
+                stack.extend(
+                    children
+                        .into_iter()
+                        .filter(|&child| self.nodes[child].bounds.intersects(&rect)),
+                );
+            }
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::EntityStore;
+
+    #[test]
+    fn quadtree_follows_clustered_positions() {
+        let world = Rect::new(Position::new(0, 0), Position::new(1023, 1023));
+        let mut store = EntityStore::new();
+        store.new_component::<Position>();
+        let tree = store.index_positions(QuadTree::new(world));
+
+        // A tight cluster forces several splits, plus a few stragglers
+        for entity_id in 0..40 {
+            let offset = entity_id as i64;
+            store.add_component(entity_id, Position::new(500 + offset % 7, 500 + offset / 7));
+        }
+        store.add_component(100, Position::new(10, 10));
+        store.add_component(101, Position::new(-50, 2000));
+        assert!(tree.borrow().nodes.len() > 1);
+
+        let mut near = tree.borrow().near(Position::new(500, 500), 1);
+        near.sort();
+        assert_eq!(near, vec![0, 1, 7]);
+        assert_eq!(tree.borrow().near(Position::new(-50, 1990), 10), vec![101]);
+
+        store.add_component(0, Position::new(12, 12));
+        store.remove_component::<Position>(1);
+        let mut near = tree.borrow().near(Position::new(500, 500), 1);
+        near.sort();
+        assert_eq!(near, vec![7]);
+        let mut corner = tree
+            .borrow()
+            .in_rect(Rect::new(Position::new(0, 0), Position::new(20, 20)));
+        corner.sort();
+        assert_eq!(corner, vec![0, 100]);
+
+        // Boxes straddling a split are still found
+        tree.borrow_mut().insert_bounds(
+            200,
+            Rect::new(Position::new(400, 400), Position::new(600, 600)),
+        );
+        assert!(tree
+            .borrow()
+            .near(Position::new(390, 390), 15)
+            .contains(&200));
+        assert_eq!(tree.borrow().len(), 42);
+    }
+}
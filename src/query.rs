@@ -0,0 +1,174 @@
+// Ad hoc questions about the world, outside of rule firing
+//
+// RuleEngine::query answers conditions written as after `when`. A Query holds the same
+// conditions, parsed or built up one at a time from Rust, and can start with variables
+// already bound, like a Prolog goal with some arguments given. RuleEngine::solve answers
+// it with Solutions, an iterator over every binding set that satisfies it, in match
+// order. Variables can be written in either case, so `Parent(X, Y), Parent(Y, Z)` asks
+// the same as `Parent(x, y), Parent(y, z)`.
+use crate::dsl;
+use crate::engine::RuleEngine;
+use crate::error::Error;
+use crate::rule::{Condition, Expr, Pattern, Rule, Term};
+use crate::store::EntityStore;
+use crate::value::{Bindings, Value};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Query {
+    pub conditions: Vec<Condition>,
+    // Bound before the conditions are matched
+    pub bindings: Bindings,
+}
+
+impl Query {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn parse(source: &str) -> Result<Self, Error> {
+        Self::new().and(source)
+    }
+
+    // Adds the conditions in the source, written as after `when`
+    pub fn and(mut self, source: &str) -> Result<Self, Error> {
+        self.conditions.extend(dsl::parse_conditions(source)?);
+        Ok(self)
+    }
+
+    pub fn pattern(mut self, component: &str, args: impl IntoIterator<Item = Term>) -> Self {
+        let pattern = Pattern::new(component, args.into_iter().collect());
+        self.conditions.push(Condition::Pattern(pattern));
+        self
+    }
+
+    pub fn not(mut self, component: &str, args: impl IntoIterator<Item = Term>) -> Self {
+        let pattern = Pattern::new(component, args.into_iter().collect());
+        self.conditions.push(Condition::Not(pattern));
+        self
+    }
+
+    pub fn test(mut self, expr: Expr) -> Self {
+        self.conditions.push(Condition::Test(expr));
+        self
+    }
+
+    pub fn bind(mut self, variable: &str, value: impl Into<Value>) -> Self {
+        self.bindings.insert(variable.to_string(), value.into());
+        self
+    }
+}
+
+// The answers to a query, see above
+#[derive(Debug, Clone)]
+pub struct Solutions {
+    solutions: std::vec::IntoIter<Bindings>,
+}
+
+impl Iterator for Solutions {
+    type Item = Bindings;
+
+    fn next(&mut self) -> Option<Bindings> {
+        self.solutions.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.solutions.size_hint()
+    }
+}
+
+impl ExactSizeIterator for Solutions {}
+
+impl Solutions {
+    pub(crate) fn new(solutions: Vec<Bindings>) -> Self {
+        Self {
+            solutions: solutions.into_iter(),
+        }
+    }
+
+    // Each answer's value for one variable
+    pub fn values(self, variable: &str) -> impl Iterator<Item = Value> + '_ {
+        self.filter_map(move |mut bindings| bindings.remove(variable))
+    }
+}
+
+impl RuleEngine {
+    // Every binding set satisfying the query, checked like a rule's conditions
+    pub fn solve(&self, query: &Query, store: &EntityStore) -> Result<Solutions, Error> {
+        let mut rule = Rule::new("query");
+        rule.conditions = query.conditions.clone();
+        let bound = query.bindings.keys().map(String::as_str).collect();
+        self.check_from(&rule, bound)?;
+        let start = (query.bindings.clone(), Vec::new());
+        let solutions: Vec<Bindings> = self
+            .match_from(None, &rule.conditions, store, start)
+            .into_iter()
+            .map(|(bindings, _)| bindings)
+            .collect();
+        Ok(Solutions::new(solutions))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::Fact;
+    use crate::store::{Component, EntityId};
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Parent(EntityId);
+    impl Component for Parent {}
+
+    impl Fact for Parent {
+        const FIELDS: &'static [&'static str] = &["parent"];
+
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Entity(self.0)]
+        }
+
+        fn from_values(values: &[Value]) -> Option<Self> {
+            Some(Parent(values.first()?.as_entity()?))
+        }
+    }
+
+    #[test]
+    fn goals_answer_with_binding_sets() {
+        let mut engine = RuleEngine::new();
+        engine.register::<Parent>("Parent");
+        let mut store = EntityStore::new();
+        store.new_component::<Parent>();
+        // 1 and 2 are children of 3, which is a child of 4
+        for (child, parent) in [(1, 3), (2, 3), (3, 4)] {
+            store.add_component(child, Parent(parent));
+        }
+
+        let grandparents = Query::parse("Parent(X, Y), Parent(Y, Z)").unwrap();
+        let solutions = engine.solve(&grandparents, &store).unwrap();
+        assert_eq!(solutions.len(), 2);
+        let found: Vec<Value> = solutions.values("Z").collect();
+        assert_eq!(found, [Value::Entity(4), Value::Entity(4)]);
+
+        let siblings = Query::new()
+            .pattern("Parent", [Term::var("x"), Term::var("p")])
+            .pattern("Parent", [Term::var("y"), Term::var("p")])
+            .and("x != y")
+            .unwrap()
+            .bind("x", Value::Entity(1));
+        let found: Vec<Bindings> = engine.solve(&siblings, &store).unwrap().collect();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0]["y"], Value::Entity(2));
+
+        let orphans = Query::new()
+            .pattern("Parent", [Term::Wildcard, Term::var("p")])
+            .not("Parent", [Term::var("p"), Term::Wildcard]);
+        let found: Vec<Value> = engine
+            .solve(&orphans, &store)
+            .unwrap()
+            .values("p")
+            .collect();
+        assert_eq!(found, [Value::Entity(4)]);
+
+        let unbound = Query::parse("Parent(x, y), z > 1").unwrap();
+        assert!(engine.solve(&unbound, &store).is_err());
+        assert!(engine.solve(&unbound.bind("z", 2), &store).is_ok());
+    }
+}
@@ -0,0 +1,489 @@
+// Qualitative spatial reasoning with the region connection calculus (RCC8)
+//
+// Any two regions stand in exactly one of eight relations: disconnected, externally
+// connected, partially overlapping, equal, or one is a tangential or non tangential
+// proper part of the other. Entities with a Region component are related by their
+// extents. Relations between entities whose extents aren't known can be asserted on
+// the engine's Rcc8Network instead, which narrows every other relation it can through
+// the composition table, so a room NTPP its floor and the floor TPP its building give
+// the room NTPP the building without any of them being placed.
+//
+// Rules ask with `rcc8(a, b, "tpp|ntpp")`, true when a's relation to b is known to
+// be one of those named.
+use crate::engine::RuleEngine;
+use crate::error::Error;
+use crate::registry::{Fact, TypedFact};
+use crate::rule::Term;
+use crate::spatial::{bind, entity_of, Position, Rect};
+use crate::store::{Component, EntityId, EntityStore};
+use crate::value::{Bindings, Value};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Rcc8 {
+    Dc,
+    Ec,
+    Po,
+    Tpp,
+    Ntpp,
+    // Inverses, b is a proper part of a
+    Tppi,
+    Ntppi,
+    Eq,
+}
+
+const ALL: [Rcc8; 8] = [
+    Rcc8::Dc,
+    Rcc8::Ec,
+    Rcc8::Po,
+    Rcc8::Tpp,
+    Rcc8::Ntpp,
+    Rcc8::Tppi,
+    Rcc8::Ntppi,
+    Rcc8::Eq,
+];
+
+impl Rcc8 {
+    // How b relates to a, given how a relates to b
+    pub fn converse(self) -> Self {
+        match self {
+            Rcc8::Tpp => Rcc8::Tppi,
+            Rcc8::Ntpp => Rcc8::Ntppi,
+            Rcc8::Tppi => Rcc8::Tpp,
+            Rcc8::Ntppi => Rcc8::Ntpp,
+            other => other,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Rcc8::Dc => "dc",
+            Rcc8::Ec => "ec",
+            Rcc8::Po => "po",
+            Rcc8::Tpp => "tpp",
+            Rcc8::Ntpp => "ntpp",
+            Rcc8::Tppi => "tppi",
+            Rcc8::Ntppi => "ntppi",
+            Rcc8::Eq => "eq",
+        }
+    }
+
+    // Relation of two extents, each covering whole cells
+    // Cells meeting along an edge or at a corner are externally connected
+    pub fn between(a: &Rect, b: &Rect) -> Self {
+        let touches = |inner: &Rect, outer: &Rect| {
+            inner.min.x == outer.min.x
+                || inner.min.y == outer.min.y
+                || inner.max.x == outer.max.x
+                || inner.max.y == outer.max.y
+        };
+        if a == b {
+            Rcc8::Eq
+        } else if !a.intersects(b) {
+            let (x, y) = a.gap(b);
+            if x <= 1 && y <= 1 {
+                Rcc8::Ec
+            } else {
+                Rcc8::Dc
+            }
+        } else if b.contains_rect(a) {
+            if touches(a, b) {
+                Rcc8::Tpp
+            } else {
+                Rcc8::Ntpp
+            }
+        } else if a.contains_rect(b) {
+            if touches(b, a) {
+                Rcc8::Tppi
+            } else {
+                Rcc8::Ntppi
+            }
+        } else {
+            Rcc8::Po
+        }
+    }
+
+    // What a can be to c, given a is self to b and b is other to c
+    pub fn compose(self, other: Rcc8) -> Rcc8Set {
+        use Rcc8::*;
+        let all = Rcc8Set::ALL;
+        let set = Rcc8Set::of;
+        // Rows are self, columns other, in declaration order
+        match (self, other) {
+            (Eq, r) => set(&[r]),
+            (r, Eq) => set(&[r]),
+            (Dc, Dc) => all,
+            (Dc, Ec | Po | Tpp | Ntpp) => set(&[Dc, Ec, Po, Tpp, Ntpp]),
+            (Dc, Tppi | Ntppi) => set(&[Dc]),
+            (Ec, Dc) => set(&[Dc, Ec, Po, Tppi, Ntppi]),
+            (Ec, Ec) => set(&[Dc, Ec, Po, Tpp, Tppi, Eq]),
+            (Ec, Po) => set(&[Dc, Ec, Po, Tpp, Ntpp]),
+            (Ec, Tpp) => set(&[Ec, Po, Tpp, Ntpp]),
+            (Ec, Ntpp) => set(&[Po, Tpp, Ntpp]),
+            (Ec, Tppi) => set(&[Dc, Ec]),
+            (Ec, Ntppi) => set(&[Dc]),
+            (Po, Dc | Ec | Tppi | Ntppi) => set(&[Dc, Ec, Po, Tppi, Ntppi]),
+            (Po, Po) => all,
+            (Po, Tpp | Ntpp) => set(&[Po, Tpp, Ntpp]),
+            (Tpp, Dc) => set(&[Dc]),
+            (Tpp, Ec) => set(&[Dc, Ec]),
+            (Tpp, Po) => set(&[Dc, Ec, Po, Tpp, Ntpp]),
+            (Tpp, Tpp) => set(&[Tpp, Ntpp]),
+            (Tpp, Ntpp) => set(&[Ntpp]),
+            (Tpp, Tppi) => set(&[Dc, Ec, Po, Tpp, Tppi, Eq]),
+            (Tpp, Ntppi) => set(&[Dc, Ec, Po, Tppi, Ntppi]),
+            (Ntpp, Dc | Ec) => set(&[Dc]),
+            (Ntpp, Po | Tppi) => set(&[Dc, Ec, Po, Tpp, Ntpp]),
+            (Ntpp, Tpp | Ntpp) => set(&[Ntpp]),
+            (Ntpp, Ntppi) => all,
+            (Tppi, Dc) => set(&[Dc, Ec, Po, Tppi, Ntppi]),
+            (Tppi, Ec) => set(&[Ec, Po, Tppi, Ntppi]),
+            (Tppi, Po) => set(&[Po, Tppi, Ntppi]),
+            (Tppi, Tpp) => set(&[Po, Tpp, Tppi, Eq]),
+            (Tppi, Ntpp) => set(&[Po, Tpp, Ntpp]),
+            (Tppi, Tppi) => set(&[Tppi, Ntppi]),
+            (Tppi, Ntppi) => set(&[Ntppi]),
+            (Ntppi, Dc) => set(&[Dc, Ec, Po, Tppi, Ntppi]),
+            (Ntppi, Ec | Po | Tpp) => set(&[Po, Tppi, Ntppi]),
+            (Ntppi, Ntpp) => set(&[Po, Tpp, Ntpp, Tppi, Ntppi, Eq]),
+            (Ntppi, Tppi | Ntppi) => set(&[Ntppi]),
+        }
+    }
+}
+
+// A disjunction of relations, what is known about how two regions relate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Rcc8Set(u8);
+
+impl Rcc8Set {
+    pub const EMPTY: Rcc8Set = Rcc8Set(0);
+    // Nothing known, any relation is possible
+    pub const ALL: Rcc8Set = Rcc8Set(0xff);
+
+    pub fn of(relations: &[Rcc8]) -> Self {
+        Rcc8Set(relations.iter().fold(0, |bits, &r| bits | 1 << r as u8))
+    }
+
+    // Relation names separated by `|`, e.g. "tpp|ntpp"
+    pub fn parse(names: &str) -> Option<Self> {
+        names
+            .split('|')
+            .map(|name| ALL.into_iter().find(|r| r.name() == name.trim()))
+            .collect::<Option<Vec<_>>>()
+            .map(|relations| Rcc8Set::of(&relations))
+    }
+
+    pub fn contains(self, relation: Rcc8) -> bool {
+        self.0 & 1 << relation as u8 != 0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn is_subset(self, other: Rcc8Set) -> bool {
+        self.0 & !other.0 == 0
+    }
+
+    pub fn intersection(self, other: Rcc8Set) -> Self {
+        Rcc8Set(self.0 & other.0)
+    }
+
+    // The single relation, once only one is possible
+    pub fn single(self) -> Option<Rcc8> {
+        let mut relations = self.iter();
+        let first = relations.next()?;
+        relations.next().is_none().then_some(first)
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = Rcc8> {
+        ALL.into_iter().filter(move |&r| self.contains(r))
+    }
+
+    pub fn converse(self) -> Self {
+        Rcc8Set::of(&self.iter().map(Rcc8::converse).collect::<Vec<_>>())
+    }
+
+    // Every relation a can have to c, given a is in self to b and b in other to c
+    pub fn compose(self, other: Rcc8Set) -> Self {
+        let mut bits = 0;
+        for a in self.iter() {
+            for b in other.iter() {
+                bits |= a.compose(b).0;
+            }
+        }
+        Rcc8Set(bits)
+    }
+}
+
+impl fmt::Display for Rcc8Set {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<&str> = self.iter().map(Rcc8::name).collect();
+        write!(f, "{}", names.join("|"))
+    }
+}
+
+// The extent of a region, in whole cells
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Region(pub Rect);
+
+impl Component for Region {}
+
+impl Fact for Region {
+    const FIELDS: &'static [&'static str] = &["min_x", "min_y", "max_x", "max_y"];
+
+    fn to_values(&self) -> Vec<Value> {
+        let Rect { min, max } = self.0;
+        [min.x, min.y, max.x, max.y].map(Value::Int).to_vec()
+    }
+
+    fn from_values(values: &[Value]) -> Option<Self> {
+        match values {
+            [min_x, min_y, max_x, max_y] => Some(Region(Rect::new(
+                Position::new(min_x.as_int()?, min_y.as_int()?),
+                Position::new(max_x.as_int()?, max_y.as_int()?),
+            ))),
+            _ => None,
+        }
+    }
+}
+
+impl TypedFact for Region {
+    type Fields = (i64, i64, i64, i64);
+
+    fn fields(&self) -> Self::Fields {
+        let Rect { min, max } = self.0;
+        (min.x, min.y, max.x, max.y)
+    }
+
+    fn from_fields((min_x, min_y, max_x, max_y): Self::Fields) -> Self {
+        Region(Rect::new(
+            Position::new(min_x, min_y),
+            Position::new(max_x, max_y),
+        ))
+    }
+}
+
+// Asserted relations between regions, kept closed under composition
+#[derive(Debug, Clone, Default)]
+pub struct Rcc8Network {
+    // Both directions are stored, a missing pair is unconstrained
+    relations: HashMap<(EntityId, EntityId), Rcc8Set>,
+}
+
+impl Rcc8Network {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Entities with any relation asserted
+    pub fn regions(&self) -> BTreeSet<EntityId> {
+        self.relations.keys().map(|&(a, _)| a).collect()
+    }
+
+    // What is known of how a relates to b
+    pub fn relation(&self, a: EntityId, b: EntityId) -> Rcc8Set {
+        if a == b {
+            return Rcc8Set::of(&[Rcc8::Eq]);
+        }
+        self.relations.get(&(a, b)).copied().unwrap_or(Rcc8Set::ALL)
+    }
+
+    // Narrows a's relation to b to those in the set, then everything that follows
+    // Fails, leaving the network as it was, if that leaves two regions no relation
+    pub fn constrain(&mut self, a: EntityId, b: EntityId, set: Rcc8Set) -> Result<(), Error> {
+        let mut closed = self.clone();
+        closed.narrow(a, b, set)?;
+        closed.close()?;
+        *self = closed;
+        Ok(())
+    }
+
+    // Constrains each pair of the entities that have a Region to how their extents relate
+    pub fn constrain_regions(
+        &mut self,
+        store: &EntityStore,
+        entities: &[EntityId],
+    ) -> Result<(), Error> {
+        let Some(pool) = store.get::<Region>() else {
+            return Ok(());
+        };
+        let pool = pool.borrow();
+        let mut closed = self.clone();
+        for (i, &a) in entities.iter().enumerate() {
+            for &b in &entities[i + 1..] {
+                if let (Some(ra), Some(rb)) = (pool.get(a), pool.get(b)) {
+                    closed.narrow(a, b, Rcc8Set::of(&[Rcc8::between(&ra.0, &rb.0)]))?;
+                }
+            }
+        }
+        closed.close()?;
+        *self = closed;
+        Ok(())
+    }
+
+    fn narrow(&mut self, a: EntityId, b: EntityId, set: Rcc8Set) -> Result<bool, Error> {
+        let before = self.relation(a, b);
+        let after = before.intersection(set);
+        if after.is_empty() {
+            return Err(Error::Inconsistent { a, b });
+        }
+        if a != b {
+            self.relations.insert((a, b), after);
+            self.relations.insert((b, a), after.converse());
+        }
+        Ok(after != before)
+    }
+
+    // Path consistency, relation(a, c) ⊆ relation(a, b) ∘ relation(b, c) for every b
+    fn close(&mut self) -> Result<(), Error> {
+        let regions: Vec<EntityId> = self.regions().into_iter().collect();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &a in &regions {
+                for &b in &regions {
+                    for &c in &regions {
+                        if a == b || b == c || a == c {
+                            continue;
+                        }
+                        let implied = self.relation(a, b).compose(self.relation(b, c));
+                        changed |= self.narrow(a, c, implied)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RuleEngine {
+    pub fn rcc8(&self) -> &Rcc8Network {
+        &self.rcc8
+    }
+
+    pub fn rcc8_mut(&mut self) -> &mut Rcc8Network {
+        &mut self.rcc8
+    }
+
+    // What is known of how a relates to b, from their extents if both have one
+    pub fn region_relation(&self, store: &EntityStore, a: EntityId, b: EntityId) -> Rcc8Set {
+        let extent = |entity_id| {
+            let pool = store.get::<Region>()?;
+            let region = pool.borrow().get(entity_id).copied();
+            region
+        };
+        match (extent(a), extent(b)) {
+            (Some(ra), Some(rb)) => Rcc8Set::of(&[Rcc8::between(&ra.0, &rb.0)]),
+            _ => self.rcc8.relation(a, b),
+        }
+    }
+
+    // Bindings extended so a is known to relate to b by one of the named relations
+    // An unbound side is tried against every region the store or network knows
+    pub(crate) fn rcc8_matches(
+        &self,
+        a: &Term,
+        b: &Term,
+        wanted: &Value,
+        bindings: &Bindings,
+        store: &EntityStore,
+    ) -> Vec<Bindings> {
+        let Some(wanted) = wanted.as_str().and_then(Rcc8Set::parse) else {
+            return Vec::new();
+        };
+        let known = |a, b| self.region_relation(store, a, b).is_subset(wanted);
+        match (entity_of(a, bindings), entity_of(b, bindings)) {
+            (Some(ea), Some(eb)) if known(ea, eb) => vec![bindings.clone()],
+            (Some(_), Some(_)) | (None, None) => Vec::new(),
+            (from, to) => {
+                let mut regions = self.rcc8.regions();
+                if let Some(pool) = store.get::<Region>() {
+                    regions.extend(pool.borrow().entities().into_iter().copied());
+                }
+                regions
+                    .into_iter()
+                    .filter(|&other| match (from, to) {
+                        (Some(ea), _) => other != ea && known(ea, other),
+                        (_, Some(eb)) => other != eb && known(other, eb),
+                        _ => false,
+                    })
+                    .filter_map(|other| bind(if from.is_some() { b } else { a }, other, bindings))
+                    .collect()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn composition_infers_containment() {
+        // The table is its own converse, (r ∘ s)˘ = s˘ ∘ r˘
+        for r in ALL {
+            for s in ALL {
+                assert_eq!(
+                    r.compose(s).converse(),
+                    s.converse().compose(r.converse()),
+                    "{:?} ∘ {:?}",
+                    r,
+                    s
+                );
+            }
+        }
+
+        let (room, floor, building, yard) = (1, 2, 3, 4);
+        let mut network = Rcc8Network::new();
+        network
+            .constrain(room, floor, Rcc8Set::of(&[Rcc8::Ntpp]))
+            .unwrap();
+        network
+            .constrain(floor, building, Rcc8Set::of(&[Rcc8::Tpp]))
+            .unwrap();
+        network
+            .constrain(building, yard, Rcc8Set::of(&[Rcc8::Ec]))
+            .unwrap();
+        assert_eq!(network.relation(room, building).single(), Some(Rcc8::Ntpp));
+        assert_eq!(network.relation(yard, room).single(), Some(Rcc8::Dc));
+        assert_eq!(
+            network.constrain(room, yard, Rcc8Set::of(&[Rcc8::Po])),
+            Err(Error::Inconsistent { a: room, b: yard })
+        );
+        assert_eq!(network.relation(room, yard).single(), Some(Rcc8::Dc));
+
+        let mut store = EntityStore::new();
+        store.new_component::<Region>();
+        let at = |x0, y0, x1, y1| Region(Rect::new(Position::new(x0, y0), Position::new(x1, y1)));
+        store.add_component(5, at(0, 0, 9, 9));
+        store.add_component(6, at(0, 0, 4, 4));
+        store.add_component(7, at(10, 10, 12, 12));
+        let mut engine = RuleEngine::new();
+        engine.register::<Region>("Region");
+        engine
+            .rcc8_mut()
+            .constrain(room, 5, Rcc8Set::of(&[Rcc8::Ntpp]))
+            .unwrap();
+
+        let inside = |source: &str| {
+            let mut found: Vec<EntityId> = engine
+                .query(source, &store)
+                .unwrap()
+                .iter()
+                .map(|bindings| bindings["a"].as_entity().unwrap())
+                .collect();
+            found.sort();
+            found
+        };
+        assert_eq!(
+            inside(r#"Region(b, 0, 0, 9, 9), rcc8(a, b, "tpp|ntpp")"#),
+            vec![1, 6]
+        );
+        assert_eq!(
+            inside(r#"Region(b, 0, 0, 9, 9), rcc8(a, b, "ec")"#),
+            vec![7]
+        );
+    }
+}
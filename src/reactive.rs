@@ -0,0 +1,328 @@
+// Reactive rules, fired once per change rather than whenever their conditions hold
+//
+// Once change tracking is on, the store sends a Change event for every component
+// added, replaced or removed through it. Rules with an `on insert` or `on remove`
+// trigger are matched against each change as the engine reads it, and each match
+// fires exactly once. The rest of the rule's conditions are matched only once the
+// match comes up to fire, so a reaction sees what the ones before it did.
+//
+// An inserted change carries the fields it was inserted with, so two inserts before a
+// run are seen as two values rather than the last one twice. Fields are read through
+// the registry, which the engine lends the store each run; changes made before an
+// engine has run over the store are read back from the store unless
+// read_fields_with has been called. Likewise each change is stamped with the engine's
+// time when it's made, see time.rs, which is what windows count from, see cep.rs.
+use crate::engine::{Activation, RuleEngine};
+use crate::provenance::Premise;
+use crate::registry::Registry;
+use crate::rule::TriggerKind;
+use crate::store::{Component, EntityId, EntityStore};
+use crate::time::Stamp;
+use crate::value::{Bindings, Value};
+use std::any::{Any, TypeId};
+use std::fmt;
+use std::sync::{Arc, Weak};
+
+// Reads a registered component's fields from it held type erased
+pub(crate) type FieldReader = fn(&dyn Any) -> Option<Vec<Value>>;
+
+#[derive(Clone)]
+pub enum ChangeKind {
+    // Added or replaced, with the new fields if the store knew how to read them,
+    // otherwise they're read from the store when the change is
+    Inserted(Option<Vec<Value>>),
+    // The component that was removed
+    Removed(Arc<dyn Any + Send + Sync>),
+}
+
+#[derive(Clone)]
+pub struct Change {
+    pub component: TypeId,
+    pub entity: EntityId,
+    pub kind: ChangeKind,
+    // When it was made, if an engine had run over the store by then
+    pub at: Option<Stamp>,
+}
+
+impl fmt::Debug for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            ChangeKind::Inserted(_) => "Inserted",
+            ChangeKind::Removed(_) => "Removed",
+        };
+        write!(
+            f,
+            "Change({:?}, #{}, {})",
+            self.component, self.entity, kind
+        )
+    }
+}
+
+impl EntityStore {
+    // Start sending Change events, RuleEngine::run does this when it has reactive rules
+    // Changes are kept in an Events queue, so update_events must be called each tick
+    pub fn track_changes(&mut self) {
+        self.new_events::<Change>();
+    }
+
+    pub fn tracking_changes(&self) -> bool {
+        self.events::<Change>().is_some()
+    }
+
+    // See above
+    pub fn read_fields_with(&mut self, registry: &Registry) {
+        for info in registry.iter() {
+            if let Some(reader) = info.reader() {
+                self.readers.insert(info.type_id, reader);
+            }
+        }
+    }
+
+    // Records the entity's T as inserted, with its fields as they are now
+    pub(crate) fn record_inserted<T: Component + Eq + 'static>(&self, entity: EntityId) {
+        if !self.tracking_changes() {
+            return;
+        }
+        let values = self.readers.get(&TypeId::of::<T>()).and_then(|reader| {
+            let pool = self.get::<T>()?.borrow();
+            reader(pool.get(entity)?)
+        });
+        self.record(TypeId::of::<T>(), entity, ChangeKind::Inserted(values));
+    }
+
+    pub(crate) fn record(&self, component: TypeId, entity: EntityId, kind: ChangeKind) {
+        let Some(queue) = self.events::<Change>() else {
+            return;
+        };
+        let change = Change {
+            component,
+            entity,
+            kind,
+            at: self.time.as_ref().map(|time| time.now()),
+        };
+        for log in self.change_logs.iter().filter_map(Weak::upgrade) {
+            log.lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .log(&change);
+        }
+        queue.borrow_mut().send(change);
+    }
+}
+
+impl RuleEngine {
+    pub(crate) fn has_reactive_rules(&self) -> bool {
+        self.rules().iter().any(|rule| rule.trigger.is_some())
+    }
+
+    // Matches reactive triggers against changes since the last call, queueing the matches
+    pub(crate) fn react(&mut self, store: &EntityStore) {
+        let (Some(reader), Some(queue)) = (&mut self.changes, store.events::<Change>()) else {
+            return;
+        };
+        let changes: Vec<Change> = reader.read(&queue.borrow()).cloned().collect();
+        let now = self.now();
+        // Triggers matched, with their windows filled in afterwards as that needs &mut self
+        let mut hits = Vec::new();
+
+        for change in changes {
+            let Some(info) = self.registry().get_type_id(change.component) else {
+                continue;
+            };
+            let (kind, values) = match &change.kind {
+                ChangeKind::Inserted(Some(values)) => (TriggerKind::Insert, Some(values.clone())),
+                ChangeKind::Inserted(None) => (TriggerKind::Insert, info.get(store, change.entity)),
+                ChangeKind::Removed(component) => {
+                    (TriggerKind::Remove, info.values_of(component.as_ref()))
+                }
+            };
+            // Inserted and then removed again before we got to it
+            let Some(values) = values else {
+                continue;
+            };
+            let mut args = vec![Value::Entity(change.entity)];
+            args.extend(values.iter().cloned());
+
+            for &index in self.agenda() {
+                let Some(trigger) = &self.rules()[index].trigger else {
+                    continue;
+                };
+                if trigger.kind != kind || trigger.pattern.component != info.name {
+                    continue;
+                }
+                let Some(bindings) = trigger.pattern.unify(&args, &Bindings::new()) else {
+                    continue;
+                };
+                if !trigger.pattern.guard_holds(&bindings, info.fields, &values) {
+                    continue;
+                }
+                let premise = Premise {
+                    component: info.name.clone(),
+                    entity: change.entity,
+                    values: values.clone(),
+                };
+                let at = change.at.unwrap_or(now);
+                hits.push((index, trigger.window, bindings, premise, at));
+            }
+        }
+
+        for (index, window, bindings, premise, at) in hits {
+            if let Some(window) = window {
+                if !self.fill_window(index, &bindings, window, at) {
+                    continue;
+                }
+            }
+            self.triggered.push_back(Activation {
+                rule: index,
+                bindings,
+                premises: vec![premise],
+            });
+        }
+    }
+
+    // Matches the conditions of queued trigger matches against the store as it is now,
+    // until one of them holds or none are left
+    pub(crate) fn match_triggered(&mut self, store: &EntityStore) {
+        while self.reactions.is_empty() {
+            let Some(hit) = self.triggered.pop_front() else {
+                return;
+            };
+            let conditions = &self.rules()[hit.rule].conditions;
+            let matches = self.match_from(
+                Some(hit.rule),
+                conditions,
+                store,
+                (hit.bindings, hit.premises),
+            );
+            let reactions = matches.into_iter().map(|(bindings, premises)| Activation {
+                rule: hit.rule,
+                bindings,
+                premises,
+            });
+            self.reactions.extend(reactions.collect::<Vec<_>>());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::RuleEngine;
+    use crate::registry::{Fact, TypedFact};
+    use crate::store::{Component, EntityStore};
+    use crate::value::Value;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Health(i64);
+    impl Component for Health {}
+    impl Fact for Health {
+        const FIELDS: &'static [&'static str] = &["value"];
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.0)]
+        }
+        fn from_values(values: &[Value]) -> Option<Self> {
+            match values {
+                [value] => Some(Health(value.as_int()?)),
+                _ => None,
+            }
+        }
+    }
+    impl TypedFact for Health {
+        type Fields = (i64,);
+        fn fields(&self) -> Self::Fields {
+            (self.0,)
+        }
+        fn from_fields((value,): Self::Fields) -> Self {
+            Health(value)
+        }
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Damage(i64);
+    impl Component for Damage {}
+    impl Fact for Damage {
+        const FIELDS: &'static [&'static str] = &["amount"];
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.0)]
+        }
+        fn from_values(values: &[Value]) -> Option<Self> {
+            match values {
+                [value] => Some(Damage(value.as_int()?)),
+                _ => None,
+            }
+        }
+    }
+    impl TypedFact for Damage {
+        type Fields = (i64,);
+        fn fields(&self) -> Self::Fields {
+            (self.0,)
+        }
+        fn from_fields((amount,): Self::Fields) -> Self {
+            Damage(amount)
+        }
+    }
+
+    fn health(store: &EntityStore) -> i64 {
+        store.get::<Health>().unwrap().borrow().get(1).unwrap().0
+    }
+
+    #[test]
+    fn fires_once_per_change() {
+        let mut engine = RuleEngine::new();
+        engine.register::<Health>("Health");
+        engine.register::<Damage>("Damage");
+        engine
+            .add_rule(crate::rule!(
+                "hurt" on insert Damage(e, amt) when Health(e, h) then insert Health(e, h - amt)
+            ))
+            .unwrap();
+        engine
+            .load_str(
+                r#"rule "heal" on remove Damage(e, amt) where amt > 5 then insert Damage(e, 0)"#,
+            )
+            .unwrap();
+
+        let mut store = EntityStore::new();
+        store.new_component::<Health>();
+        store.new_component::<Damage>();
+        store.track_changes();
+        store.add_component(1, Health(100));
+
+        store.add_component(1, Damage(10));
+        assert_eq!(engine.run(&mut store), 1);
+        assert_eq!(health(&store), 90);
+        assert_eq!(engine.run(&mut store), 0);
+
+        // The same fact again is still a new change
+        store.add_component(1, Damage(10));
+        engine.run(&mut store);
+        assert_eq!(health(&store), 80);
+
+        // Removing it inserts Damage(0), which hurts for nothing
+        store.remove_component::<Damage>(1);
+        assert_eq!(engine.run(&mut store), 2);
+        assert_eq!(health(&store), 80);
+        assert!(store.has_component::<Damage>(1));
+    }
+
+    #[test]
+    fn each_insert_is_seen_with_its_own_value() {
+        let mut engine = RuleEngine::new();
+        engine.register::<Health>("Health");
+        engine.register::<Damage>("Damage");
+        engine
+            .add_rule(crate::rule!(
+                "hurt" on insert Damage(e, amt) when Health(e, h) then insert Health(e, h - amt)
+            ))
+            .unwrap();
+
+        let mut store = EntityStore::new();
+        store.new_component::<Health>();
+        store.new_component::<Damage>();
+        store.track_changes();
+        store.read_fields_with(engine.registry());
+        store.add_component(1, Health(100));
+        store.add_component(1, Damage(5));
+        store.add_component(1, Damage(7));
+        assert_eq!(engine.run(&mut store), 2);
+        assert_eq!(health(&store), 88);
+    }
+}
@@ -0,0 +1,234 @@
+// Reading and writing component fields without knowing their types
+//
+// The registry sees components as rows of Values, which is all rules need, but an
+// inspector or an editor wants the fields themselves, with their real types. A type
+// implementing Reflect lists its fields, usually through the reflect! macro, each with
+// its name, the name and TypeId of its type and accessors over dyn Any. Registering it
+// with Registry::reflect lets field and set_field reach into any entity's component by
+// TypeId and field name. As with pool_mut, see access.rs, writes go through the pool
+// rather than add_component, so hooks don't hear of them but indexes catch up.
+use crate::cell::{AtomicRef, AtomicRefMut};
+use crate::error::Error;
+use crate::registry::Registry;
+use crate::store::{Component, EntityId, EntityStore};
+use std::any::{Any, TypeId};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Field {
+    pub name: &'static str,
+    pub type_name: &'static str,
+    pub type_id: TypeId,
+    get: fn(&dyn Any) -> Option<&dyn Any>,
+    get_mut: fn(&mut dyn Any) -> Option<&mut dyn Any>,
+    set: Setter,
+}
+
+// Moves a value into the field, handing it back if either type is wrong
+type Setter = fn(&mut dyn Any, Box<dyn Any>) -> Result<(), Box<dyn Any>>;
+
+impl Field {
+    // Use reflect! rather than building these by hand
+    pub fn new<F: Any>(
+        name: &'static str,
+        get: fn(&dyn Any) -> Option<&dyn Any>,
+        get_mut: fn(&mut dyn Any) -> Option<&mut dyn Any>,
+        set: Setter,
+    ) -> Self {
+        Field {
+            name,
+            type_name: std::any::type_name::<F>(),
+            type_id: TypeId::of::<F>(),
+            get,
+            get_mut,
+            set,
+        }
+    }
+
+    // The field of the component, None if the component isn't the type it's from
+    pub fn get<'a>(&self, component: &'a dyn Any) -> Option<&'a dyn Any> {
+        (self.get)(component)
+    }
+
+    pub fn get_mut<'a>(&self, component: &'a mut dyn Any) -> Option<&'a mut dyn Any> {
+        (self.get_mut)(component)
+    }
+
+    pub fn set(&self, component: &mut dyn Any, value: Box<dyn Any>) -> Result<(), Box<dyn Any>> {
+        (self.set)(component, value)
+    }
+}
+
+pub trait Reflect: Component + Eq + 'static {
+    fn fields() -> Vec<Field>;
+}
+
+// Implements Reflect for a struct, naming the fields to expose and their types
+// reflect!(Health { hp: i64, max: i64 }), or reflect!(Score { 0: u32 }) for tuple structs
+#[macro_export]
+macro_rules! reflect {
+    ($component:ty { $($field:tt: $type:ty),* $(,)? }) => {
+        impl $crate::reflect::Reflect for $component {
+            fn fields() -> Vec<$crate::reflect::Field> {
+                vec![$($crate::reflect::Field::new::<$type>(
+                    stringify!($field),
+                    |component| Some(&component.downcast_ref::<$component>()?.$field),
+                    |component| Some(&mut component.downcast_mut::<$component>()?.$field),
+                    |component, value| {
+                        let value = value.downcast::<$type>()?;
+                        match component.downcast_mut::<$component>() {
+                            Some(component) => {
+                                component.$field = *value;
+                                Ok(())
+                            }
+                            None => Err(value),
+                        }
+                    },
+                )),*]
+            }
+        }
+    };
+}
+
+impl Registry {
+    // Makes T's fields reachable through field and set_field
+    pub fn reflect<T: Reflect>(&mut self) {
+        self.reflected.insert(TypeId::of::<T>(), T::fields());
+    }
+
+    // The reflected fields of a component type, by TypeId
+    pub fn fields_of(&self, type_id: TypeId) -> Option<&[Field]> {
+        self.reflected.get(&type_id).map(Vec::as_slice)
+    }
+
+    // The reflected fields of a registered component, by its name
+    pub fn fields_named(&self, component: &str) -> Option<&[Field]> {
+        self.fields_of(self.get(component)?.type_id)
+    }
+
+    fn reflected_field(&self, type_id: TypeId, field: &str) -> Result<Field, Error> {
+        let fields = self
+            .fields_of(type_id)
+            .ok_or_else(|| Error::Reflect(format!("{:?} isn't reflected", type_id)))?;
+        fields
+            .iter()
+            .find(|other| other.name == field)
+            .copied()
+            .ok_or_else(|| Error::Reflect(format!("no field `{}`", field)))
+    }
+}
+
+impl EntityStore {
+    // One field of the entity's component, borrowing its pool
+    pub fn field(
+        &self,
+        registry: &Registry,
+        type_id: TypeId,
+        entity_id: EntityId,
+        field: &str,
+    ) -> Result<AtomicRef<'_, dyn Any>, Error> {
+        let field = registry.reflected_field(type_id, field)?;
+        let pool = self.pool_ref(type_id)?;
+        AtomicRef::filter_map(pool, |pool| field.get(pool.get_any(entity_id)?))
+            .map_err(|_| Error::Reflect(format!("entity {} has no such component", entity_id)))
+    }
+
+    // One field of the entity's component, borrowed for writing
+    pub fn field_mut(
+        &self,
+        registry: &Registry,
+        type_id: TypeId,
+        entity_id: EntityId,
+        field: &str,
+    ) -> Result<AtomicRefMut<'_, dyn Any>, Error> {
+        let field = registry.reflected_field(type_id, field)?;
+        let pool = self.pool_ref_mut(type_id)?;
+        AtomicRefMut::filter_map(pool, |pool| field.get_mut(pool.get_any_mut(entity_id)?))
+            .map_err(|_| Error::Reflect(format!("entity {} has no such component", entity_id)))
+    }
+
+    // Overwrites one field, failing if the value isn't of the field's type
+    pub fn set_field(
+        &self,
+        registry: &Registry,
+        type_id: TypeId,
+        entity_id: EntityId,
+        field: &str,
+        value: Box<dyn Any>,
+    ) -> Result<(), Error> {
+        let field = registry.reflected_field(type_id, field)?;
+        let mut pool = self.pool_ref_mut(type_id)?;
+        let component = pool
+            .get_any_mut(entity_id)
+            .ok_or_else(|| Error::Reflect(format!("entity {} has no such component", entity_id)))?;
+        field
+            .set(component, value)
+            .map_err(|_| Error::Reflect(format!("`{}` holds a {}", field.name, field.type_name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Health {
+        hp: i64,
+        label: String,
+    }
+    impl Component for Health {}
+    reflect!(Health {
+        hp: i64,
+        label: String
+    });
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Score(u32);
+    impl Component for Score {}
+    reflect!(Score { 0: u32 });
+
+    #[test]
+    fn fields_read_and_write_without_types() {
+        let mut registry = Registry::new();
+        registry.reflect::<Health>();
+        registry.reflect::<Score>();
+        let mut store = EntityStore::new();
+        store.new_component::<Health>();
+        store.new_component::<Score>();
+        let label = "goblin".to_string();
+        store.add_component(2, Health { hp: 30, label });
+        store.add_component(2, Score(7));
+
+        let health = TypeId::of::<Health>();
+        let names: Vec<_> = registry
+            .fields_of(health)
+            .unwrap()
+            .iter()
+            .map(|f| f.name)
+            .collect();
+        assert_eq!(names, ["hp", "label"]);
+        let hp = store.field(&registry, health, 2, "hp").unwrap();
+        assert_eq!(hp.downcast_ref::<i64>(), Some(&30));
+        drop(hp);
+
+        store
+            .set_field(&registry, health, 2, "hp", Box::new(12_i64))
+            .unwrap();
+        *store
+            .field_mut(&registry, TypeId::of::<Score>(), 2, "0")
+            .unwrap()
+            .downcast_mut::<u32>()
+            .unwrap() += 1;
+        assert!(store
+            .set_field(&registry, health, 2, "hp", Box::new("x"))
+            .is_err());
+        assert!(store.field(&registry, health, 3, "hp").is_err());
+        assert!(store.field(&registry, health, 2, "mana").is_err());
+
+        let pool = store.get::<Health>().unwrap().borrow();
+        assert_eq!(pool.get(2).unwrap().hp, 12);
+        assert_eq!(
+            store.get::<Score>().unwrap().borrow().get(2),
+            Some(&Score(8))
+        );
+    }
+}
@@ -0,0 +1,387 @@
+// Registry of component types that rules can talk about by name
+use crate::closure::{self, Closure};
+use crate::hierarchy::{self, Parent};
+use crate::migrate::Migration;
+use crate::path::{self, Graph};
+use crate::reactive::FieldReader;
+use crate::reflect::Field;
+use crate::relation::{insert_relation, sources_of, Relation};
+use crate::store::{Component, EntityId, EntityStore};
+use crate::tiles::{self, Neighbours, Tile};
+use crate::value::Value;
+use crate::view::{self, ViewRows};
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+
+// A component that can be read and written by rules
+// Fields are exposed positionally, in the order given by FIELDS,
+// so `Health(e, h)` binds e to the entity and h to the first field
+pub trait Fact: Component + Eq + Sized + 'static {
+    const FIELDS: &'static [&'static str];
+
+    fn to_values(&self) -> Vec<Value>;
+
+    // Returns None if the values don't fit the component (wrong count or types)
+    fn from_values(values: &[Value]) -> Option<Self>;
+}
+
+// A fact whose fields are also available as a typed tuple
+// rule! uses this to type check rules at compile time
+pub trait TypedFact: Fact {
+    type Fields;
+
+    fn fields(&self) -> Self::Fields;
+    fn from_fields(fields: Self::Fields) -> Self;
+}
+
+// An entity and the field values of one of its components
+pub type FactRow = (EntityId, Vec<Value>);
+
+// Type erased operations on a registered component
+// These are plain fn pointers monomorphised for each registered type
+#[derive(Debug, Clone)]
+pub struct ComponentInfo {
+    pub name: String,
+    pub type_id: TypeId,
+    pub fields: &'static [&'static str],
+    facts: fn(&EntityStore) -> Vec<FactRow>,
+    get: fn(&EntityStore, EntityId) -> Option<Vec<Value>>,
+    rows: fn(&EntityStore, EntityId) -> Vec<Vec<Value>>,
+    insert: fn(&mut EntityStore, EntityId, &[Value]) -> bool,
+    remove: fn(&mut EntityStore, EntityId),
+    values: FieldReader,
+    version: fn(&EntityStore) -> Option<(u64, usize)>,
+    changed: fn(&EntityStore, u64) -> Vec<FactRow>,
+    // Relations only, the sources pointing at a target
+    sources: Option<fn(&EntityStore, EntityId) -> Vec<EntityId>>,
+    // Worked out from other components rather than stored, like AdjacentTo
+    derived: bool,
+}
+
+impl ComponentInfo {
+    pub(crate) fn new<T: Fact>(name: &str) -> Self {
+        ComponentInfo {
+            name: name.to_string(),
+            type_id: TypeId::of::<T>(),
+            fields: T::FIELDS,
+            facts: facts::<T>,
+            get: get::<T>,
+            rows: rows::<T>,
+            insert: insert::<T>,
+            remove: remove::<T>,
+            values: values::<T>,
+            version: version::<T>,
+            changed: changed::<T>,
+            sources: None,
+            derived: false,
+        }
+    }
+
+    // Number of pattern arguments, the entity plus one per field
+    pub fn arity(&self) -> usize {
+        self.fields.len() + 1
+    }
+
+    // Every (entity, fields) pair currently in the store
+    pub fn facts(&self, store: &EntityStore) -> Vec<FactRow> {
+        (self.facts)(store)
+    }
+
+    pub fn get(&self, store: &EntityStore, entity_id: EntityId) -> Option<Vec<Value>> {
+        (self.get)(store, entity_id)
+    }
+
+    // Every fact the entity has, more than one only for relations like AdjacentTo
+    pub fn rows(&self, store: &EntityStore, entity_id: EntityId) -> Vec<Vec<Value>> {
+        (self.rows)(store, entity_id)
+    }
+
+    // Builds the component from values and adds it, creating the pool if needed
+    // Returns false if the values couldn't be turned into the component, or adding it
+    // was refused, see require.rs and singleton.rs
+    pub fn insert(&self, store: &mut EntityStore, entity_id: EntityId, values: &[Value]) -> bool {
+        (self.insert)(store, entity_id, values)
+    }
+
+    pub fn remove(&self, store: &mut EntityStore, entity_id: EntityId) {
+        (self.remove)(store, entity_id)
+    }
+
+    // For relations, the entities pointing at target without a pool scan
+    pub fn sources(&self, store: &EntityStore, target: EntityId) -> Option<Vec<EntityId>> {
+        Some((self.sources?)(store, target))
+    }
+
+    pub fn is_derived(&self) -> bool {
+        self.derived
+    }
+
+    pub fn is_relation(&self) -> bool {
+        self.sources.is_some()
+    }
+
+    // The pool's change tick and size, one of which moves whenever its facts change, see
+    // cache.rs. None for derived components, which have no pool of their own
+    pub fn version(&self, store: &EntityStore) -> Option<(u64, usize)> {
+        (self.version)(store)
+    }
+
+    // Facts added, replaced or borrowed mutably since the pool's change tick was at tick,
+    // see version and wal.rs. Always empty for derived components
+    pub fn changed_since(&self, store: &EntityStore, tick: u64) -> Vec<FactRow> {
+        (self.changed)(store, tick)
+    }
+
+    // Field values of a component held type erased, None if it isn't this component
+    pub fn values_of(&self, component: &dyn Any) -> Option<Vec<Value>> {
+        (self.values)(component)
+    }
+
+    // values_of as a plain fn, None for components with no Rust type of their own
+    pub(crate) fn reader(&self) -> Option<FieldReader> {
+        (!self.derived).then_some(self.values)
+    }
+}
+
+fn facts<T: Fact>(store: &EntityStore) -> Vec<FactRow> {
+    match store.get::<T>() {
+        Some(pool) => pool
+            .borrow()
+            .components_iter()
+            .map(|(entity_id, component)| (*entity_id, component.to_values()))
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+fn get<T: Fact>(store: &EntityStore, entity_id: EntityId) -> Option<Vec<Value>> {
+    let pool = store.get::<T>()?;
+    let pool = pool.borrow();
+    Some(pool.get(entity_id)?.to_values())
+}
+
+fn rows<T: Fact>(store: &EntityStore, entity_id: EntityId) -> Vec<Vec<Value>> {
+    get::<T>(store, entity_id).into_iter().collect()
+}
+
+fn insert<T: Fact>(store: &mut EntityStore, entity_id: EntityId, values: &[Value]) -> bool {
+    let Some(component) = T::from_values(values) else {
+        return false;
+    };
+    if store.get::<T>().is_none() {
+        store.new_component::<T>();
+    }
+    store.reserve_up_to(entity_id);
+    store.try_add_component(entity_id, component).is_ok()
+}
+
+fn remove<T: Fact>(store: &mut EntityStore, entity_id: EntityId) {
+    store.remove_component::<T>(entity_id);
+}
+
+fn values<T: Fact>(component: &dyn Any) -> Option<Vec<Value>> {
+    Some(component.downcast_ref::<T>()?.to_values())
+}
+
+fn version<T: Component + Eq + 'static>(store: &EntityStore) -> Option<(u64, usize)> {
+    let version = store.get::<T>().map_or((0, 0), |pool| {
+        let pool = pool.borrow();
+        (pool.tick(), pool.len())
+    });
+    Some(version)
+}
+
+fn changed<T: Fact>(store: &EntityStore, tick: u64) -> Vec<FactRow> {
+    match store.get::<T>() {
+        Some(pool) => pool
+            .borrow()
+            .changed_since(tick)
+            .map(|(entity_id, component)| (*entity_id, component.to_values()))
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Registry {
+    components: Vec<ComponentInfo>,
+    by_name: HashMap<String, usize>,
+    // Schema migrations by component name, see migrate.rs
+    pub(crate) migrations: HashMap<String, Vec<Migration>>,
+    // Fields of reflected types, see reflect.rs
+    pub(crate) reflected: HashMap<TypeId, Vec<Field>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Registers T under the given name, replacing any previous registration of that name
+    pub fn register<T: Fact>(&mut self, name: &str) {
+        self.insert_info(ComponentInfo::new::<T>(name));
+    }
+
+    pub(crate) fn insert_info(&mut self, info: ComponentInfo) {
+        if let Some(&index) = self.by_name.get(&info.name) {
+            self.components[index] = info;
+        } else {
+            self.by_name
+                .insert(info.name.clone(), self.components.len());
+            self.components.push(info);
+        }
+    }
+
+    // Registers a relation, inserts made through the registry check its endpoints
+    pub fn register_relation<R: Relation + Fact>(&mut self, name: &str) {
+        self.insert_info(ComponentInfo {
+            insert: insert_relation::<R>,
+            sources: Some(sources_of::<R>),
+            ..ComponentInfo::new::<R>(name)
+        });
+    }
+
+    // Registers the transitive closure of R, see closure.rs
+    // It can't be inserted or removed, and has no single value for get
+    pub fn register_closure<R: Relation>(&mut self, name: &str) {
+        self.insert_info(ComponentInfo {
+            name: name.to_string(),
+            type_id: TypeId::of::<Closure<R>>(),
+            fields: &["target"],
+            facts: closure::closure_facts::<R>,
+            get: |_, _| None,
+            rows: closure::closure_rows::<R>,
+            insert: |_, _, _| false,
+            remove: |_, _| {},
+            values: |_| None,
+            version: |_| None,
+            changed: |_, _| Vec::new(),
+            sources: Some(closure::closure_sources::<R>),
+            derived: true,
+        });
+    }
+
+    // Registers the routes through G, see path.rs
+    pub fn register_paths<G: Graph>(&mut self, name: &str) {
+        self.insert_info(ComponentInfo {
+            name: name.to_string(),
+            type_id: TypeId::of::<G>(),
+            fields: &["goal", "next", "cost"],
+            facts: path::route_facts::<G>,
+            get: |_, _| None,
+            rows: path::route_rows::<G>,
+            insert: |_, _, _| false,
+            remove: |_, _| {},
+            values: |_| None,
+            version: |_| None,
+            changed: |_, _| Vec::new(),
+            sources: None,
+            derived: true,
+        });
+    }
+
+    // Registers Parent, with inserts and removes keeping Children in step
+    pub fn register_hierarchy(&mut self, parent: &str) {
+        self.insert_info(ComponentInfo {
+            insert: hierarchy::insert_parent,
+            remove: hierarchy::remove_parent,
+            ..ComponentInfo::new::<Parent>(parent)
+        });
+    }
+
+    // Registers Tile, with inserts and removes kept on the map, and the adjacency relation
+    // AdjacentTo can't be inserted or removed, and has no single value for get
+    pub fn register_tiles(&mut self, tile: &str, adjacency: &str) {
+        self.insert_info(ComponentInfo {
+            insert: tiles::insert_tile,
+            remove: tiles::remove_tile,
+            ..ComponentInfo::new::<Tile>(tile)
+        });
+        self.insert_info(ComponentInfo {
+            name: adjacency.to_string(),
+            type_id: TypeId::of::<Neighbours>(),
+            fields: &["target"],
+            facts: tiles::adjacency_facts,
+            get: |_, _| None,
+            rows: tiles::adjacency_rows,
+            insert: |_, _, _| false,
+            remove: |_, _| {},
+            values: |_| None,
+            version: |_| None,
+            changed: |_, _| Vec::new(),
+            sources: None,
+            derived: true,
+        });
+    }
+
+    // Registers a materialized view's pool, see view.rs
+    // Rules read it like any component, but only the engine writes it
+    pub(crate) fn register_view<const SLOT: usize>(
+        &mut self,
+        name: &str,
+        fields: &'static [&'static str],
+    ) {
+        self.insert_info(ComponentInfo {
+            name: name.to_string(),
+            type_id: TypeId::of::<ViewRows<SLOT>>(),
+            fields,
+            facts: view::view_facts::<SLOT>,
+            get: view::view_get::<SLOT>,
+            rows: view::view_rows::<SLOT>,
+            insert: |_, _, _| false,
+            remove: |_, _| {},
+            values: |_| None,
+            version: version::<ViewRows<SLOT>>,
+            changed: |_, _| Vec::new(),
+            sources: None,
+            derived: true,
+        });
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ComponentInfo> {
+        self.by_name.get(name).map(|&index| &self.components[index])
+    }
+
+    // The registration for a Rust type, if it has one
+    pub fn get_type<T: 'static>(&self) -> Option<&ComponentInfo> {
+        self.get_type_id(TypeId::of::<T>())
+    }
+
+    pub fn get_type_id(&self, type_id: TypeId) -> Option<&ComponentInfo> {
+        self.components.iter().find(|info| info.type_id == type_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ComponentInfo> {
+        self.components.iter()
+    }
+
+    // Inserts saved rows through their registrations, relations last so their endpoints
+    // are there first. Fails with a description of the first row that wouldn't go in
+    pub(crate) fn insert_rows(
+        store: &mut EntityStore,
+        mut sections: Vec<(&ComponentInfo, Vec<FactRow>)>,
+    ) -> Result<(), String> {
+        sections.sort_by_key(|(info, _)| info.is_relation());
+        for (info, rows) in sections {
+            for (entity_id, values) in rows {
+                if !info.insert(store, entity_id, &values) {
+                    return Err(format!(
+                        "entity {} can't have `{}` with fields {:?}",
+                        entity_id, info.name, values
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Copies every registered component from one store into another
+    // Unregistered components are left behind, rules can't see them anyway
+    pub fn copy_facts(&self, from: &EntityStore, to: &mut EntityStore) {
+        for info in &self.components {
+            for (entity_id, values) in info.facts(from) {
+                info.insert(to, entity_id, &values);
+            }
+        }
+    }
+}
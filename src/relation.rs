@@ -0,0 +1,320 @@
+// Typed relations, components that point from one entity to another
+//
+// relation!(Owns: Player -> Item);
+//
+// declares an `Owns(target)` component that may only be placed on entities with
+// a Player, pointing at entities with an Item.
+//
+// relate checks both endpoints, and rules insert relations through it. That's the only
+// check: add_component puts a relation on any entity, pointing anywhere, and taking the
+// Player or Item off an endpoint later leaves its relations in place, as hooks can't
+// reach the store to refuse or undo either. Code adding relations itself or removing
+// endpoint components keeps them valid itself, check_endpoints says if one still is.
+//
+// Each relation type related through the store also gets a reverse index, kept up to
+// date by hooks, so sources answers "who owns this item" without scanning the pool.
+// Rules use it too, for patterns whose target is bound but whose source isn't.
+use crate::cell::AtomicRefCell;
+use crate::error::Error;
+use crate::registry::Fact;
+use crate::store::{Component, EntityId, EntityStore};
+use crate::value::Value;
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub trait Relation: Component + Eq + Sized + 'static {
+    // Component the entity holding the relation must have
+    type Source: Component + Eq + 'static;
+    // Component the target entity must have
+    type Target: Component + Eq + 'static;
+
+    fn new(target: EntityId) -> Self;
+    fn target(&self) -> EntityId;
+}
+
+// Strips the module path, `game::Player` reads better than the full path in errors
+pub(crate) fn short_type_name<T: ?Sized>() -> &'static str {
+    let name = std::any::type_name::<T>();
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+// Checks both endpoints have the components the relation requires, see above
+pub fn check_endpoints<R: Relation>(
+    store: &EntityStore,
+    source: EntityId,
+    target: EntityId,
+) -> Result<(), Error> {
+    let missing = |entity: EntityId, component: &str| Error::Endpoint {
+        relation: short_type_name::<R>().to_string(),
+        entity,
+        component: component.to_string(),
+    };
+    if !store.has_component::<R::Source>(source) {
+        return Err(missing(source, short_type_name::<R::Source>()));
+    }
+    if !store.has_component::<R::Target>(target) {
+        return Err(missing(target, short_type_name::<R::Target>()));
+    }
+    Ok(())
+}
+
+// The sources pointing at each target, for one relation type
+pub(crate) type ReverseIndex = Arc<AtomicRefCell<HashMap<EntityId, Vec<EntityId>>>>;
+
+fn unlink(index: &ReverseIndex, source: EntityId, target: EntityId) {
+    let mut index = index.borrow_mut();
+    if let Some(sources) = index.get_mut(&target) {
+        sources.retain(|&other| other != source);
+        if sources.is_empty() {
+            index.remove(&target);
+        }
+    }
+}
+
+impl EntityStore {
+    // Adds the relation from source to target, replacing any existing one of the same type
+    // Fails without changing anything if an endpoint is missing its required component
+    pub fn relate<R: Relation>(&mut self, source: EntityId, target: EntityId) -> Result<(), Error> {
+        check_endpoints::<R>(self, source, target)?;
+        if self.get::<R>().is_none() {
+            self.new_component::<R>();
+        }
+        self.index_relation::<R>();
+        self.add_component(source, R::new(target));
+        Ok(())
+    }
+
+    // Starts keeping a reverse index for R, relate does this itself
+    pub fn index_relation<R: Relation>(&mut self) {
+        if self.reverse.contains_key(&TypeId::of::<R>()) {
+            return;
+        }
+        let index = ReverseIndex::default();
+        if let Some(pool) = self.get::<R>() {
+            let mut index = index.borrow_mut();
+            for (&source, relation) in pool.borrow().components_iter() {
+                index.entry(relation.target()).or_default().push(source);
+            }
+        }
+
+        let added = index.clone();
+        self.on_add(move |source, relation: &R| {
+            added
+                .borrow_mut()
+                .entry(relation.target())
+                .or_default()
+                .push(source);
+        });
+        let replaced = index.clone();
+        self.on_replace(move |source, old: &R, new: &R| {
+            unlink(&replaced, source, old.target());
+            replaced
+                .borrow_mut()
+                .entry(new.target())
+                .or_default()
+                .push(source);
+        });
+        let removed = index.clone();
+        self.on_remove(move |source, relation: &R| unlink(&removed, source, relation.target()));
+        self.reverse.insert(TypeId::of::<R>(), index);
+    }
+
+    // Every entity whose relation of type R points at target, in the order they related
+    // Without a reverse index this scans the pool
+    pub fn sources<R: Relation>(&self, target: EntityId) -> Vec<EntityId> {
+        if let Some(index) = self.reverse.get(&TypeId::of::<R>()) {
+            return index.borrow().get(&target).cloned().unwrap_or_default();
+        }
+        let Some(pool) = self.get::<R>() else {
+            return Vec::new();
+        };
+        let pool = pool.borrow();
+        pool.components_iter()
+            .filter(|(_, relation)| relation.target() == target)
+            .map(|(&source, _)| source)
+            .collect()
+    }
+
+    // Where the source's relation of type R points, if it has one
+    pub fn related<R: Relation>(&self, source: EntityId) -> Option<EntityId> {
+        let pool = self.get::<R>()?;
+        let target = pool.borrow().get(source)?.target();
+        Some(target)
+    }
+}
+
+// Lets rules look sources up through the reverse index
+pub(crate) fn sources_of<R: Relation>(store: &EntityStore, target: EntityId) -> Vec<EntityId> {
+    store.sources::<R>(target)
+}
+
+// Lets rules insert relations, the registry routes their inserts through check_endpoints
+pub(crate) fn insert_relation<R: Relation + Fact>(
+    store: &mut EntityStore,
+    entity_id: EntityId,
+    values: &[Value],
+) -> bool {
+    match R::from_values(values) {
+        Some(relation) => store.relate::<R>(entity_id, relation.target()).is_ok(),
+        None => false,
+    }
+}
+
+#[macro_export]
+macro_rules! relation {
+    ($vis:vis $name:ident : $source:ident -> $target:ident) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        $vis struct $name(pub $crate::EntityId);
+
+        impl $crate::Component for $name {}
+
+        impl $crate::relation::Relation for $name {
+            type Source = $source;
+            type Target = $target;
+
+            fn new(target: $crate::EntityId) -> Self {
+                $name(target)
+            }
+
+            fn target(&self) -> $crate::EntityId {
+                self.0
+            }
+        }
+
+        impl $crate::Fact for $name {
+            const FIELDS: &'static [&'static str] = &["target"];
+
+            fn to_values(&self) -> Vec<$crate::Value> {
+                vec![$crate::Value::Entity(self.0)]
+            }
+
+            fn from_values(values: &[$crate::Value]) -> Option<Self> {
+                match values {
+                    [target] => Some($name(target.as_entity()?)),
+                    _ => None,
+                }
+            }
+        }
+
+        impl $crate::registry::TypedFact for $name {
+            type Fields = ($crate::EntityId,);
+
+            fn fields(&self) -> Self::Fields {
+                (self.0,)
+            }
+
+            fn from_fields((target,): Self::Fields) -> Self {
+                $name(target)
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::RuleEngine;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Player;
+    impl Component for Player {}
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Item;
+    impl Component for Item {}
+
+    relation!(Owns: Player -> Item);
+
+    fn store() -> EntityStore {
+        let mut store = EntityStore::new();
+        store.new_component::<Player>();
+        store.new_component::<Item>();
+        store.add_component(1, Player);
+        store.add_component(2, Item);
+        store.add_component(3, Item);
+        store
+    }
+
+    #[test]
+    fn relate_checks_endpoints() {
+        let mut store = store();
+        assert!(store.relate::<Owns>(1, 2).is_ok());
+        assert_eq!(store.related::<Owns>(1), Some(2));
+
+        assert_eq!(
+            store.relate::<Owns>(2, 3),
+            Err(Error::Endpoint {
+                relation: "Owns".into(),
+                entity: 2,
+                component: "Player".into()
+            })
+        );
+        assert_eq!(
+            store.relate::<Owns>(1, 1),
+            Err(Error::Endpoint {
+                relation: "Owns".into(),
+                entity: 1,
+                component: "Item".into()
+            })
+        );
+        assert_eq!(store.related::<Owns>(1), Some(2));
+    }
+
+    #[test]
+    fn reverse_index_finds_sources() {
+        let mut store = store();
+        store.add_component(4, Player);
+        store.add_component(5, Player);
+        store.relate::<Owns>(1, 2).unwrap();
+        store.relate::<Owns>(4, 2).unwrap();
+        store.relate::<Owns>(5, 3).unwrap();
+        assert_eq!(store.sources::<Owns>(2), vec![1, 4]);
+
+        store.relate::<Owns>(4, 3).unwrap();
+        store.remove_entity(1);
+        assert!(store.sources::<Owns>(2).is_empty());
+        assert_eq!(store.sources::<Owns>(3), vec![5, 4]);
+
+        let mut engine = RuleEngine::new();
+        engine.register_relation::<Owns>("Owns");
+        let owners = engine
+            .query("Owns(q, i), Owns(p, i), p != q", &store)
+            .unwrap();
+        assert_eq!(owners.len(), 2);
+    }
+
+    #[test]
+    fn rules_cannot_bypass_endpoints() {
+        let mut engine = RuleEngine::new();
+        engine.register_relation::<Owns>("Owns");
+        engine
+            .load_str(r#"rule "flip" when Owns(p, i) then insert Owns(i, p)"#)
+            .unwrap();
+
+        let mut store = store();
+        store.relate::<Owns>(1, 2).unwrap();
+        engine.run(&mut store);
+        assert_eq!(store.related::<Owns>(2), None);
+    }
+
+    #[test]
+    fn only_relate_checks_endpoints() {
+        let mut store = store();
+        store.relate::<Owns>(1, 2).unwrap();
+        store.add_component(2, Owns(3));
+        assert_eq!(store.related::<Owns>(2), Some(3));
+        assert!(check_endpoints::<Owns>(&store, 2, 3).is_err());
+
+        store.remove_component::<Item>(2);
+        assert_eq!(store.related::<Owns>(1), Some(2));
+        assert_eq!(
+            check_endpoints::<Owns>(&store, 1, 2),
+            Err(Error::Endpoint {
+                relation: "Owns".into(),
+                entity: 2,
+                component: "Item".into()
+            })
+        );
+    }
+}
@@ -0,0 +1,178 @@
+// Hot reloading of rule files
+//
+// A RuleWatcher polls a set of rule files, and when any of them changes the engine's
+// rules are replaced with everything the files contain. Polling is done by the caller
+// between runs, so a swap never happens part way through firing.
+use crate::engine::RuleEngine;
+use crate::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+fn read(path: &Path) -> Result<String, Error> {
+    fs::read_to_string(path).map_err(|error| Error::Io {
+        path: path.display().to_string(),
+        message: error.to_string(),
+    })
+}
+
+impl RuleEngine {
+    // Add every rule in the file, like load_str
+    pub fn load_file(&mut self, path: impl AsRef<Path>) -> Result<(), Error> {
+        self.load_str(&read(path.as_ref())?)
+    }
+}
+
+// What we remember about a file to notice it changing
+// Length is included as modification times can be coarse
+type Fingerprint = Option<(SystemTime, u64)>;
+
+fn fingerprint(path: &Path) -> Fingerprint {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+#[derive(Debug)]
+pub struct RuleWatcher {
+    paths: Vec<PathBuf>,
+    // None until the first poll, so the first poll always loads
+    seen: Option<Vec<Fingerprint>>,
+}
+
+impl RuleWatcher {
+    pub fn new<P: Into<PathBuf>>(paths: impl IntoIterator<Item = P>) -> Self {
+        RuleWatcher {
+            paths: paths.into_iter().map(Into::into).collect(),
+            seen: None,
+        }
+    }
+
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    // True if any file has changed since the last call
+    pub fn changed(&mut self) -> bool {
+        let current: Vec<Fingerprint> = self.paths.iter().map(|path| fingerprint(path)).collect();
+        if self.seen.as_ref() == Some(&current) {
+            return false;
+        }
+        self.seen = Some(current);
+        true
+    }
+
+    // Replaces the engine's rules with the contents of the files if any changed
+    // Returns None when nothing changed. On error the old rules are kept, and the
+    // watcher waits for the files to change again before retrying
+    pub fn reload(&mut self, engine: &mut RuleEngine) -> Option<Result<(), Error>> {
+        if !self.changed() {
+            return None;
+        }
+        let mut source = String::new();
+        for path in &self.paths {
+            match read(path) {
+                Ok(text) => {
+                    source.push_str(&text);
+                    source.push('\n');
+                }
+                Err(error) => return Some(Err(error)),
+            }
+        }
+        Some(engine.replace_str(&source))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::Fact;
+    use crate::store::{Component, EntityStore};
+    use crate::value::Value;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Heat(i64);
+    impl Component for Heat {}
+    impl Fact for Heat {
+        const FIELDS: &'static [&'static str] = &["value"];
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.0)]
+        }
+        fn from_values(values: &[Value]) -> Option<Self> {
+            match values {
+                [value] => Some(Heat(value.as_int()?)),
+                _ => None,
+            }
+        }
+    }
+
+    fn temp_file(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rete-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("rules.rete")
+    }
+
+    #[test]
+    fn reload_swaps_rules_and_keeps_store() {
+        let path = temp_file("reload");
+        fs::write(
+            &path,
+            r#"rule "warm" when Heat(e, h), h < 10 then insert Heat(e, 10)"#,
+        )
+        .unwrap();
+
+        let mut engine = RuleEngine::new();
+        engine.register::<Heat>("Heat");
+        let mut watcher = RuleWatcher::new([&path]);
+        assert_eq!(watcher.reload(&mut engine), Some(Ok(())));
+        assert_eq!(watcher.reload(&mut engine), None);
+
+        let mut store = EntityStore::new();
+        store.new_component::<Heat>();
+        store.add_component(1, Heat(0));
+        engine.run(&mut store);
+        assert_eq!(
+            store.get::<Heat>().unwrap().borrow().get(1),
+            Some(&Heat(10))
+        );
+
+        fs::write(
+            &path,
+            r#"rule "hotter" when Heat(e, h), h < 100 then insert Heat(e, 100)"#,
+        )
+        .unwrap();
+        assert_eq!(watcher.reload(&mut engine), Some(Ok(())));
+        assert_eq!(engine.rules().len(), 1);
+        assert_eq!(engine.rules()[0].name, "hotter");
+
+        engine.run(&mut store);
+        assert_eq!(
+            store.get::<Heat>().unwrap().borrow().get(1),
+            Some(&Heat(100))
+        );
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn broken_reload_keeps_old_rules() {
+        let path = temp_file("broken");
+        fs::write(
+            &path,
+            r#"rule "warm" when Heat(e, h) then insert Heat(e, 1)"#,
+        )
+        .unwrap();
+
+        let mut engine = RuleEngine::new();
+        engine.register::<Heat>("Heat");
+        let mut watcher = RuleWatcher::new([&path]);
+        watcher.reload(&mut engine).unwrap().unwrap();
+
+        fs::write(&path, r#"rule "warm" when Heat(e, h then"#).unwrap();
+        assert!(matches!(
+            watcher.reload(&mut engine),
+            Some(Err(Error::Parse { .. }))
+        ));
+        assert_eq!(engine.rules()[0].name, "warm");
+        assert_eq!(watcher.reload(&mut engine), None);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,352 @@
+// An interactive prompt over a world
+//
+// Repl takes one line at a time and answers with text, for trying rules out against a
+// world by hand. `rete repl` runs one over stdin. The commands are:
+//
+// spawn [name]           a new entity, bound to name, or e and its id
+// insert Health(e, 50)   an action as written after `then`, see dsl.rs
+// remove Health(e)
+// query Health(e, h), h < 10
+// show e                 every registered fact on the entity
+// facts Health           every Health fact
+// agenda                 the activations that would fire, in order
+// step                   fires the next activation, showing what it changed
+// run                    fires until nothing new is ready
+// break "flee"           pauses step and run before the rule fires, see breakpoint.rs
+// break Health(e)        pauses them after a firing changes Health on e, or on any
+//                        entity without one, and on its own lists the breakpoints
+// rule ... / fact ...    rules and declarations, as in a rule file
+//
+// Names bound by spawn, and by step, stay bound for later commands, so an entity can be
+// written as its name. An entity can also be written as its id.
+use crate::breakpoint::Breakpoint;
+use crate::dsl;
+use crate::engine::{Activation, RuleEngine};
+use crate::error::Error;
+use crate::rule::Action;
+use crate::store::{EntityId, EntityStore};
+use crate::trace::Mutation;
+use crate::value::{Bindings, Value};
+use std::io::{self, BufRead, Write};
+
+#[derive(Debug, Default)]
+pub struct Repl {
+    pub engine: RuleEngine,
+    pub store: EntityStore,
+    bindings: Bindings,
+}
+
+fn format_bindings(bindings: &Bindings) -> String {
+    let pairs: Vec<String> = bindings
+        .iter()
+        .map(|(name, value)| format!("{} = {}", name, value))
+        .collect();
+    pairs.join(", ")
+}
+
+fn format_fact(component: &str, entity_id: EntityId, values: &[Value]) -> String {
+    let values: Vec<String> = std::iter::once(Value::Entity(entity_id))
+        .chain(values.iter().cloned())
+        .map(|value| value.to_string())
+        .collect();
+    format!("{}({})", component, values.join(", "))
+}
+
+impl Repl {
+    pub fn new(engine: RuleEngine, store: EntityStore) -> Self {
+        Self {
+            engine,
+            store,
+            bindings: Bindings::new(),
+        }
+    }
+
+    // Runs one command, giving what to print
+    pub fn eval(&mut self, line: &str) -> Result<String, Error> {
+        let line = line.trim();
+        let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        match command {
+            "" => Ok(String::new()),
+            "spawn" => Ok(self.spawn(rest)),
+            "insert" | "remove" => self.act(line),
+            "query" => self.query(rest),
+            "show" => self.show(rest),
+            "facts" => self.facts(rest),
+            "agenda" => Ok(self.agenda()),
+            "break" => self.breakpoint(rest),
+            "step" => {
+                let answer = match self.engine.step(&mut self.store) {
+                    Some(step) => {
+                        let fired = self.describe(&step.activation, &step.mutations);
+                        self.bindings.extend(step.activation.bindings);
+                        fired
+                    }
+                    None if self.engine.paused().is_some() => String::new(),
+                    None => "nothing to fire".to_string(),
+                };
+                Ok(self.with_pause(answer))
+            }
+            "run" => {
+                let answer = format!("fired {}", self.engine.run(&mut self.store));
+                Ok(self.with_pause(answer))
+            }
+            "rule" | "fact" | "module" => {
+                self.engine.load_str(line)?;
+                Ok(String::new())
+            }
+            _ => Err(Error::Command(format!("unknown command `{}`", command))),
+        }
+    }
+
+    // Reads commands until the input ends, printing each answer or error
+    pub fn run(&mut self, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        write!(output, "> ")?;
+        output.flush()?;
+        for line in input.lines() {
+            match self.eval(&line?) {
+                Ok(answer) if answer.is_empty() => {}
+                Ok(answer) => writeln!(output, "{}", answer)?,
+                Err(error) => writeln!(output, "error: {}", error)?,
+            }
+            write!(output, "> ")?;
+            output.flush()?;
+        }
+        writeln!(output)
+    }
+
+    fn spawn(&mut self, name: &str) -> String {
+        let entity_id = self.store.spawn();
+        let name = match name {
+            "" => format!("e{}", entity_id),
+            name => name.to_string(),
+        };
+        let answer = format!("{} = #{}", name, entity_id);
+        self.bindings.insert(name, Value::Entity(entity_id));
+        answer
+    }
+
+    // The entity a value stands for, ids written as numbers included
+    fn entity(value: Option<Value>) -> Option<EntityId> {
+        match value? {
+            Value::Entity(entity_id) => Some(entity_id),
+            Value::Int(id) => usize::try_from(id).ok(),
+            _ => None,
+        }
+    }
+
+    fn unbound(&self, what: &str) -> Error {
+        Error::Command(format!("`{}` isn't an entity", what))
+    }
+
+    fn act(&mut self, line: &str) -> Result<String, Error> {
+        let registry = self.engine.registry();
+        match dsl::parse_action(line)? {
+            Action::Insert {
+                component, args, ..
+            } => {
+                let mut values = args
+                    .iter()
+                    .map(|arg| arg.eval(&self.bindings))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| Error::Command("unbound variable".to_string()))?;
+                if values.is_empty() {
+                    return Err(self.unbound(&component));
+                }
+                let entity_id =
+                    Self::entity(Some(values.remove(0))).ok_or_else(|| self.unbound(&component))?;
+                self.store
+                    .insert_fact(registry, &component, entity_id, &values)?;
+                Ok(String::new())
+            }
+            Action::Remove { component, entity } => {
+                let info = registry
+                    .get(&component)
+                    .ok_or_else(|| Error::UnknownComponent(component.clone()))?;
+                let entity_id = Self::entity(entity.eval(&self.bindings))
+                    .ok_or_else(|| self.unbound(&component))?;
+                info.remove(&mut self.store, entity_id);
+                Ok(String::new())
+            }
+            Action::Run { .. } | Action::Post { .. } | Action::Minimize { .. } => Err(
+                Error::Command("only insert and remove run here".to_string()),
+            ),
+        }
+    }
+
+    fn query(&self, conditions: &str) -> Result<String, Error> {
+        let found = self.engine.query(conditions, &self.store)?;
+        let mut lines: Vec<String> = found.iter().map(format_bindings).collect();
+        lines.push(format!("{} found", found.len()));
+        Ok(lines.join("\n"))
+    }
+
+    // An entity written as its name or id
+    fn resolve(&self, entity: &str) -> Option<EntityId> {
+        let value = match entity.parse::<i64>() {
+            Ok(id) => Some(Value::Int(id)),
+            Err(_) => self.bindings.get(entity).cloned(),
+        };
+        Self::entity(value)
+    }
+
+    fn show(&self, entity: &str) -> Result<String, Error> {
+        let entity_id = self.resolve(entity).ok_or_else(|| self.unbound(entity))?;
+        let lines: Vec<String> = self
+            .engine
+            .registry()
+            .iter()
+            .filter(|info| !info.is_derived())
+            .flat_map(|info| {
+                info.rows(&self.store, entity_id)
+                    .into_iter()
+                    .map(|values| format_fact(&info.name, entity_id, &values))
+            })
+            .collect();
+        Ok(lines.join("\n"))
+    }
+
+    fn facts(&self, component: &str) -> Result<String, Error> {
+        let info = self
+            .engine
+            .registry()
+            .get(component)
+            .ok_or_else(|| Error::UnknownComponent(component.to_string()))?;
+        let lines: Vec<String> = info
+            .facts(&self.store)
+            .iter()
+            .map(|(entity_id, values)| format_fact(&info.name, *entity_id, values))
+            .collect();
+        Ok(lines.join("\n"))
+    }
+
+    fn agenda(&self) -> String {
+        let activations = self.engine.activations(&self.store);
+        let lines: Vec<String> = activations
+            .iter()
+            .map(|activation| self.describe(activation, &[]))
+            .collect();
+        match lines.is_empty() {
+            true => "nothing to fire".to_string(),
+            false => lines.join("\n"),
+        }
+    }
+
+    // The activation, with what it changed if it has fired
+    fn describe(&self, activation: &Activation, mutations: &[Mutation]) -> String {
+        let rule = &self.engine.rules()[activation.rule].name;
+        let described = format!("\"{}\" {}", rule, format_bindings(&activation.bindings));
+        let changed: Vec<String> = mutations.iter().map(Mutation::to_string).collect();
+        match changed.is_empty() {
+            true => described,
+            false => format!("{}: {}", described, changed.join(", ")),
+        }
+    }
+
+    // `break "flee"` pauses before the rule fires, `break Health(hero)` after a firing
+    // changes the component on the entity, and `break Health` on any, see breakpoint.rs
+    // On its own it lists the breakpoints
+    fn breakpoint(&mut self, what: &str) -> Result<String, Error> {
+        if what.is_empty() {
+            let lines: Vec<String> = self
+                .engine
+                .breakpoints()
+                .iter()
+                .map(|breakpoint| match breakpoint {
+                    Breakpoint::Rule(rule) => format!("\"{}\"", rule),
+                    Breakpoint::Change {
+                        component,
+                        entity: Some(entity_id),
+                    } => format!("{}(#{})", component, entity_id),
+                    Breakpoint::Change { component, .. } => component.clone(),
+                })
+                .collect();
+            return Ok(lines.join("\n"));
+        }
+        let breakpoint = match what
+            .strip_prefix('"')
+            .and_then(|rule| rule.strip_suffix('"'))
+        {
+            Some(rule) => Breakpoint::Rule(rule.to_string()),
+            None => {
+                let (component, entity) = match what.split_once('(') {
+                    Some((component, entity)) => {
+                        let entity = entity.trim_end_matches(')').trim();
+                        let entity_id = self.resolve(entity).ok_or_else(|| self.unbound(entity))?;
+                        (component.trim(), Some(entity_id))
+                    }
+                    None => (what, None),
+                };
+                if self.engine.registry().get(component).is_none() {
+                    return Err(Error::UnknownComponent(component.to_string()));
+                }
+                Breakpoint::Change {
+                    component: component.to_string(),
+                    entity,
+                }
+            }
+        };
+        self.engine.add_breakpoint(breakpoint);
+        Ok(String::new())
+    }
+
+    // Says where the engine paused, if it did
+    fn with_pause(&self, answer: String) -> String {
+        let Some(hit) = self.engine.paused() else {
+            return answer;
+        };
+        let pause = match hit.fired {
+            true => format!(
+                "paused after {}",
+                self.describe(&hit.activation, &hit.mutations)
+            ),
+            false => format!("paused before {}", self.describe(&hit.activation, &[])),
+        };
+        match answer.is_empty() {
+            true => pause,
+            false => format!("{}\n{}", answer, pause),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_build_and_inspect_a_world() {
+        let mut repl = Repl::default();
+        let mut eval = |line: &str| repl.eval(line).unwrap();
+        eval("fact Health(value)");
+        eval("fact Fleeing()");
+        eval(r#"rule "flee" when Health(e, h), h < 10 then insert Fleeing(e)"#);
+        assert_eq!(eval("spawn hero"), "hero = #0");
+        assert_eq!(eval("spawn"), "e1 = #1");
+        eval("insert Health(hero, 5)");
+        eval("insert Health(1, 50)");
+        assert_eq!(eval("show hero"), "Health(#0, 5)");
+        assert_eq!(eval("agenda"), "\"flee\" e = #0, h = 5");
+        assert_eq!(eval("step"), "\"flee\" e = #0, h = 5: insert Fleeing(#0)");
+        assert_eq!(eval("step"), "nothing to fire");
+        assert_eq!(eval("query Fleeing(x)"), "x = #0\n1 found");
+        eval("remove Fleeing(e)");
+        assert_eq!(eval("facts Fleeing"), "");
+        eval(r#"break "flee""#);
+        assert_eq!(eval("spawn"), "e2 = #2");
+        eval("insert Health(e2, 2)");
+        assert_eq!(eval("run"), "fired 0\npaused before \"flee\" e = #2, h = 2");
+        assert_eq!(eval("run"), "fired 1");
+        assert!(repl.eval("jump").is_err());
+
+        let mut output = Vec::new();
+        repl.run("facts Health\nshow nobody\n".as_bytes(), &mut output)
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("Health(#1, 50)"), "{}", output);
+        assert!(
+            output.contains("error: `nobody` isn't an entity"),
+            "{}",
+            output
+        );
+    }
+}
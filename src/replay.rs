@@ -0,0 +1,298 @@
+// Recording a session's inputs and replaying them exactly
+//
+// A Recorder takes a snapshot of the starting store and logs every input fed to it:
+// facts asserted and retracted from outside, the clock being set, and calls to run.
+// Replaying the recording against the same rules gives the same firings in the same
+// order and the same store after every input. Two things make that hold. The engine is
+// put on a manual clock, so time only moves when the log says so. And its order is
+// pinned: a rule's activations are sorted by the facts they matched instead of coming
+// in pool order, since a store rebuilt from a snapshot has its pools in a different
+// order from the one that was saved. Salience and rule order still come first.
+use crate::engine::RuleEngine;
+use crate::error::Error;
+use crate::provenance::Premise;
+use crate::registry::Registry;
+use crate::store::{EntityId, EntityStore};
+use crate::time::Clock;
+use crate::value::{Bindings, Value};
+use std::cmp::Ordering;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Input {
+    Assert {
+        component: String,
+        entity: EntityId,
+        values: Vec<Value>,
+    },
+    Retract {
+        component: String,
+        entity: EntityId,
+    },
+    SetTime(Duration),
+    Run,
+}
+
+impl Input {
+    // Returns the number of rules fired, always 0 but for Run
+    fn apply(&self, engine: &mut RuleEngine, store: &mut EntityStore) -> Result<usize, Error> {
+        match self {
+            Input::Assert {
+                component,
+                entity,
+                values,
+            } => {
+                let info = engine
+                    .registry()
+                    .get(component)
+                    .ok_or_else(|| Error::UnknownComponent(component.clone()))?;
+                if !info.insert(store, *entity, values) {
+                    return Err(Error::Arity {
+                        component: component.clone(),
+                        expected: info.arity(),
+                        found: values.len() + 1,
+                    });
+                }
+            }
+            Input::Retract { component, entity } => {
+                let info = engine
+                    .registry()
+                    .get(component)
+                    .ok_or_else(|| Error::UnknownComponent(component.clone()))?;
+                info.remove(store, *entity);
+            }
+            Input::SetTime(now) => engine.set_time(*now),
+            Input::Run => return Ok(engine.run(store)),
+        }
+        Ok(0)
+    }
+}
+
+// A starting store, encoded as in binary.rs, and what was fed to it
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Recording {
+    pub start: Vec<u8>,
+    pub inputs: Vec<Input>,
+}
+
+#[derive(Debug)]
+pub struct Recorder {
+    engine: RuleEngine,
+    store: EntityStore,
+    recording: Recording,
+}
+
+impl Recorder {
+    // The engine should not have run yet, and is pinned and put on a manual clock at 0
+    pub fn new(mut engine: RuleEngine, store: EntityStore) -> Self {
+        engine.pin_order(true);
+        engine.set_clock(Clock::Manual(Duration::ZERO));
+        let start = engine.registry().encode_store(&store);
+        Recorder {
+            engine,
+            store,
+            recording: Recording {
+                start,
+                inputs: Vec::new(),
+            },
+        }
+    }
+
+    // Applies the input and logs it, unless it failed
+    pub fn apply(&mut self, input: Input) -> Result<usize, Error> {
+        let fired = input.apply(&mut self.engine, &mut self.store)?;
+        self.recording.inputs.push(input);
+        Ok(fired)
+    }
+
+    pub fn engine(&self) -> &RuleEngine {
+        &self.engine
+    }
+
+    pub fn store(&self) -> &EntityStore {
+        &self.store
+    }
+
+    pub fn recording(&self) -> &Recording {
+        &self.recording
+    }
+
+    pub fn finish(self) -> (Recording, RuleEngine, EntityStore) {
+        (self.recording, self.engine, self.store)
+    }
+}
+
+// Replays a recording on an engine with the same rules that hasn't run yet
+// each is called after every input with the engine and store as they are then
+pub fn replay(
+    mut engine: RuleEngine,
+    recording: &Recording,
+    mut each: impl FnMut(&Input, &RuleEngine, &EntityStore),
+) -> Result<(RuleEngine, EntityStore), Error> {
+    engine.pin_order(true);
+    engine.set_clock(Clock::Manual(Duration::ZERO));
+    let mut store = engine.registry().decode_store(&recording.start)?.store;
+    for input in &recording.inputs {
+        input.apply(&mut engine, &mut store)?;
+        each(input, &engine, &store);
+    }
+    Ok((engine, store))
+}
+
+// The order pinned activations fire in, by the facts they matched
+pub(crate) fn pinned_order(a: &[Premise], b: &[Premise]) -> Ordering {
+    let key = |premise: &Premise| (premise.entity, premise.component.clone());
+    for (a, b) in a.iter().zip(b) {
+        let order = key(a)
+            .cmp(&key(b))
+            .then_with(|| values_order(&a.values, &b.values));
+        if order.is_ne() {
+            return order;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+// Breaks ties between activations matching the same facts, as rules without patterns do
+pub(crate) fn bindings_order(a: &Bindings, b: &Bindings) -> Ordering {
+    for ((a_name, a), (b_name, b)) in a.iter().zip(b) {
+        let order = a_name.cmp(b_name).then_with(|| a.total_cmp(b));
+        if order.is_ne() {
+            return order;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+pub(crate) fn values_order(a: &[Value], b: &[Value]) -> Ordering {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| a.total_cmp(b))
+        .find(|order| order.is_ne())
+        .unwrap_or_else(|| a.len().cmp(&b.len()))
+}
+
+impl RuleEngine {
+    // Fire each rule's activations in a fixed order rather than the store's, see above
+    pub fn pin_order(&mut self, pinned: bool) {
+        self.pinned = pinned;
+    }
+}
+
+impl Registry {
+    // An assert input for a fact, checking the component is registered
+    pub fn assert_input(
+        &self,
+        component: &str,
+        entity: EntityId,
+        values: Vec<Value>,
+    ) -> Result<Input, Error> {
+        self.get(component)
+            .ok_or_else(|| Error::UnknownComponent(component.to_string()))?;
+        Ok(Input::Assert {
+            component: component.to_string(),
+            entity,
+            values,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::Firing;
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Hunger(i64);
+
+    impl crate::store::Component for Hunger {}
+
+    impl crate::registry::Fact for Hunger {
+        const FIELDS: &'static [&'static str] = &["level"];
+
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.0)]
+        }
+
+        fn from_values(values: &[Value]) -> Option<Self> {
+            match values {
+                [level] => Some(Hunger(level.as_int()?)),
+                _ => None,
+            }
+        }
+    }
+
+    fn engine() -> RuleEngine {
+        let mut engine = RuleEngine::new();
+        engine.register::<Hunger>("Hunger");
+        engine.enable_trace();
+        engine
+            .load_str(
+                r#"
+                rule "starve" when Hunger(e, h), h < 10 then insert Hunger(e, h + 1) for 2s
+                rule "feed" salience 1 when Hunger(e, h), h > 10 then insert Hunger(e, 10)
+                "#,
+            )
+            .unwrap();
+        engine
+    }
+
+    fn firings(engine: &RuleEngine) -> Vec<(String, Bindings)> {
+        engine
+            .trace()
+            .unwrap()
+            .log()
+            .iter()
+            .map(|Firing { rule, bindings, .. }| (rule.clone(), bindings.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn replay_reproduces_firings_and_states() {
+        let mut store = EntityStore::new();
+        store.new_component::<Hunger>();
+        // Pool order differs from entity order, as it would after churn
+        for entity_id in [3, 1, 2] {
+            store.add_component(entity_id, Hunger(entity_id as i64 * 4));
+        }
+        let mut recorder = Recorder::new(engine(), store);
+        let mut states = Vec::new();
+        for input in [
+            Input::Run,
+            Input::SetTime(Duration::from_secs(5)),
+            Input::Run,
+            Input::Retract {
+                component: "Hunger".to_string(),
+                entity: 2,
+            },
+            Input::Assert {
+                component: "Hunger".to_string(),
+                entity: 4,
+                values: vec![Value::Int(9)],
+            },
+            Input::Run,
+        ] {
+            recorder.apply(input).unwrap();
+            states.push(recorder.engine().registry().encode_store(recorder.store()));
+        }
+        assert!(recorder
+            .apply(Input::Retract {
+                component: "Thirst".to_string(),
+                entity: 1
+            })
+            .is_err());
+        let (recording, recorded, _) = recorder.finish();
+        assert_eq!(recording.inputs.len(), 6);
+
+        let mut replayed_states = Vec::new();
+        let (replayed, _) = replay(engine(), &recording, |_, engine, store| {
+            replayed_states.push(engine.registry().encode_store(store));
+        })
+        .unwrap();
+        assert_eq!(replayed_states, states);
+        assert!(!firings(&recorded).is_empty());
+        assert_eq!(firings(&replayed), firings(&recorded));
+    }
+}
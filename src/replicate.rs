@@ -0,0 +1,364 @@
+// A stream of component changes for mirroring a world on another machine
+//
+// A Replicator follows the store's Change events, like Wal, and each call to frame
+// gathers the changes since the last into a Frame for that tick: entities spawned and
+// despawned, and registered components inserted, changed or removed, with their fields.
+// An entity spawns with its first registered component and despawns with its last.
+// Frames encode to bytes with the value encoding of binary.rs, to send however the
+// host likes, and Registry::apply_frame plays one onto a peer's store. A peer joining
+// late starts from snapshot, which spawns and inserts everything there is now, then
+// applies the frames after it. frame has to be called at least once between two
+// update_events calls, or changes will be missed.
+use crate::binary::{write_bytes, write_value, write_varint, Reader};
+use crate::error::Error;
+use crate::events::EventReader;
+use crate::reactive::{Change, ChangeKind};
+use crate::registry::Registry;
+use crate::store::{EntityId, EntityStore};
+use crate::value::Value;
+use std::any::TypeId;
+use std::collections::{HashMap, HashSet};
+
+const SPAWN: u8 = 0;
+const DESPAWN: u8 = 1;
+const INSERT: u8 = 2;
+const CHANGE: u8 = 3;
+const REMOVE: u8 = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Delta {
+    Spawn(EntityId),
+    Despawn(EntityId),
+    // The entity didn't have the component before
+    Insert {
+        component: String,
+        entity: EntityId,
+        values: Vec<Value>,
+    },
+    // The entity's component was replaced or changed
+    Change {
+        component: String,
+        entity: EntityId,
+        values: Vec<Value>,
+    },
+    Remove {
+        component: String,
+        entity: EntityId,
+    },
+}
+
+// The changes made during one tick, in the order they happened
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Frame {
+    pub tick: u64,
+    pub deltas: Vec<Delta>,
+}
+
+impl Frame {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_varint(&mut out, self.tick);
+        write_varint(&mut out, self.deltas.len() as u64);
+        for delta in &self.deltas {
+            match delta {
+                Delta::Spawn(entity_id) => {
+                    out.push(SPAWN);
+                    write_varint(&mut out, *entity_id as u64);
+                }
+                Delta::Despawn(entity_id) => {
+                    out.push(DESPAWN);
+                    write_varint(&mut out, *entity_id as u64);
+                }
+                Delta::Insert {
+                    component,
+                    entity,
+                    values,
+                }
+                | Delta::Change {
+                    component,
+                    entity,
+                    values,
+                } => {
+                    out.push(match delta {
+                        Delta::Insert { .. } => INSERT,
+                        _ => CHANGE,
+                    });
+                    write_bytes(&mut out, component.as_bytes());
+                    write_varint(&mut out, *entity as u64);
+                    write_varint(&mut out, values.len() as u64);
+                    for value in values {
+                        write_value(&mut out, value);
+                    }
+                }
+                Delta::Remove { component, entity } => {
+                    out.push(REMOVE);
+                    write_bytes(&mut out, component.as_bytes());
+                    write_varint(&mut out, *entity as u64);
+                }
+            }
+        }
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Frame, Error> {
+        let mut reader = Reader { bytes };
+        let tick = reader.varint()?;
+        let deltas = (0..reader.len()?)
+            .map(|_| {
+                Ok(match reader.byte()? {
+                    SPAWN => Delta::Spawn(reader.len()?),
+                    DESPAWN => Delta::Despawn(reader.len()?),
+                    op @ (INSERT | CHANGE) => {
+                        let component = reader.string()?;
+                        let entity = reader.len()?;
+                        let values = (0..reader.len()?)
+                            .map(|_| reader.value())
+                            .collect::<Result<Vec<_>, _>>()?;
+                        match op {
+                            INSERT => Delta::Insert {
+                                component,
+                                entity,
+                                values,
+                            },
+                            _ => Delta::Change {
+                                component,
+                                entity,
+                                values,
+                            },
+                        }
+                    }
+                    REMOVE => Delta::Remove {
+                        component: reader.string()?,
+                        entity: reader.len()?,
+                    },
+                    op => return Err(Error::Decode(format!("unknown delta {}", op))),
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        if !reader.is_empty() {
+            return Err(Error::Decode("trailing bytes after frame".to_string()));
+        }
+        Ok(Frame { tick, deltas })
+    }
+}
+
+pub struct Replicator {
+    changes: EventReader<Change>,
+    tick: u64,
+    // The registered components each entity has, as peers last heard
+    held: HashMap<EntityId, HashSet<TypeId>>,
+}
+
+impl Replicator {
+    // Turns on change tracking, frames hold the changes from now on
+    pub fn new(registry: &Registry, store: &mut EntityStore) -> Self {
+        store.track_changes();
+        let changes = store.events::<Change>().unwrap().borrow().reader();
+        let mut held: HashMap<EntityId, HashSet<TypeId>> = HashMap::new();
+        for info in registry.iter().filter(|info| !info.is_derived()) {
+            for (entity_id, _) in info.facts(store) {
+                held.entry(entity_id).or_default().insert(info.type_id);
+            }
+        }
+        Replicator {
+            changes,
+            tick: 0,
+            held,
+        }
+    }
+
+    // The tick of the last frame
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    // Everything in the store as of the last frame, for a peer starting out
+    pub fn snapshot(&self, registry: &Registry, store: &EntityStore) -> Frame {
+        let mut entities: Vec<&EntityId> = self.held.keys().collect();
+        entities.sort();
+        let mut deltas: Vec<Delta> = entities.iter().map(|&&e| Delta::Spawn(e)).collect();
+        for info in registry.iter().filter(|info| !info.is_derived()) {
+            for (entity, values) in info.facts(store) {
+                deltas.push(Delta::Insert {
+                    component: info.name.to_string(),
+                    entity,
+                    values,
+                });
+            }
+        }
+        Frame {
+            tick: self.tick,
+            deltas,
+        }
+    }
+
+    // The changes since the last frame, as the next tick
+    pub fn frame(&mut self, registry: &Registry, store: &EntityStore) -> Frame {
+        self.tick += 1;
+        let mut deltas = Vec::new();
+        let Some(queue) = store.events::<Change>() else {
+            return Frame {
+                tick: self.tick,
+                deltas,
+            };
+        };
+        for change in self.changes.read(&queue.borrow()) {
+            let Some(info) = registry
+                .get_type_id(change.component)
+                .filter(|info| !info.is_derived())
+            else {
+                continue;
+            };
+            let component = info.name.to_string();
+            let entity = change.entity;
+            let held = self.held.entry(entity).or_default();
+            match change.kind {
+                ChangeKind::Inserted(_) => {
+                    // Removed again since, the removal follows
+                    let Some(values) = info.get(store, entity) else {
+                        continue;
+                    };
+                    if held.is_empty() {
+                        deltas.push(Delta::Spawn(entity));
+                    }
+                    match held.insert(info.type_id) {
+                        true => deltas.push(Delta::Insert {
+                            component,
+                            entity,
+                            values,
+                        }),
+                        false => deltas.push(Delta::Change {
+                            component,
+                            entity,
+                            values,
+                        }),
+                    }
+                }
+                ChangeKind::Removed(_) => {
+                    if !held.remove(&info.type_id) {
+                        continue;
+                    }
+                    deltas.push(Delta::Remove { component, entity });
+                    if held.is_empty() {
+                        self.held.remove(&entity);
+                        deltas.push(Delta::Despawn(entity));
+                    }
+                }
+            }
+        }
+        Frame {
+            tick: self.tick,
+            deltas,
+        }
+    }
+}
+
+impl Registry {
+    // Plays a frame from a Replicator onto a peer's store
+    pub fn apply_frame(&self, store: &mut EntityStore, frame: &Frame) -> Result<(), Error> {
+        for delta in &frame.deltas {
+            match delta {
+                Delta::Spawn(entity_id) => store.reserve_up_to(*entity_id),
+                Delta::Despawn(entity_id) => store.remove_entity(*entity_id),
+                Delta::Insert {
+                    component,
+                    entity,
+                    values,
+                }
+                | Delta::Change {
+                    component,
+                    entity,
+                    values,
+                } => store.insert_fact(self, component, *entity, values)?,
+                Delta::Remove { component, entity } => {
+                    let info = self
+                        .get(component)
+                        .ok_or_else(|| Error::UnknownComponent(component.clone()))?;
+                    info.remove(store, *entity);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::WorkingMemory;
+    use crate::registry::Fact;
+    use crate::store::Component;
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Health(i64);
+    impl Component for Health {}
+
+    impl Fact for Health {
+        const FIELDS: &'static [&'static str] = &["hp"];
+
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.0)]
+        }
+
+        fn from_values(values: &[Value]) -> Option<Self> {
+            Some(Health(values.first()?.as_int()?))
+        }
+    }
+
+    #[test]
+    fn peers_mirror_the_world_frame_by_frame() {
+        let mut engine = crate::engine::RuleEngine::new();
+        engine.register::<Health>("Health");
+        let registry = engine.registry().clone();
+        let mut memory = WorkingMemory::new(engine);
+        memory.assert_fact(1, Health(10));
+        memory.assert_fact(4, Health(1));
+        let mut replicator = Replicator::new(&registry, memory.store_mut());
+
+        let mut peer = EntityStore::new();
+        peer.new_component::<Health>();
+        let snapshot = replicator.snapshot(&registry, memory.store());
+        assert_eq!(snapshot.deltas.len(), 4);
+        registry.apply_frame(&mut peer, &snapshot).unwrap();
+
+        memory.assert_fact(1, Health(7));
+        memory.assert_fact(2, Health(3));
+        memory.store_mut().remove_entity(4);
+        let frame = replicator.frame(&registry, memory.store());
+        let component = String::from("Health");
+        assert_eq!(frame.tick, 1);
+        assert_eq!(
+            frame.deltas,
+            vec![
+                Delta::Change {
+                    component: component.clone(),
+                    entity: 1,
+                    values: vec![Value::Int(7)],
+                },
+                Delta::Spawn(2),
+                Delta::Insert {
+                    component: component.clone(),
+                    entity: 2,
+                    values: vec![Value::Int(3)],
+                },
+                Delta::Remove {
+                    component,
+                    entity: 4,
+                },
+                Delta::Despawn(4),
+            ]
+        );
+
+        let sent = Frame::decode(&frame.encode()).unwrap();
+        assert_eq!(sent, frame);
+        registry.apply_frame(&mut peer, &sent).unwrap();
+        assert_eq!(
+            registry.encode_store(&peer),
+            registry.encode_store(memory.store())
+        );
+        assert!(replicator
+            .frame(&registry, memory.store())
+            .deltas
+            .is_empty());
+    }
+}
@@ -0,0 +1,188 @@
+// Components that need others
+//
+// require::<Velocity, Position>() says every entity with a Velocity must have a
+// Position too, and the store keeps it so, so rules and systems never see a Velocity
+// without one. Adding a Velocity to an entity with no Position is refused, and so is
+// removing the Position while the Velocity is there. require_default instead adds a
+// default Position along with the Velocity, and takes the Velocity off with the
+// Position. add_component and remove_component can't say they refused, the try_ forms
+// return Error::Required. Entities already breaking a new requirement are left as they
+// are, as are whole entities being removed. Requirements shouldn't be declared on
+// components added in bulk through pools rather than the store, which can't check them.
+use crate::error::Error;
+use crate::relation::short_type_name;
+use crate::store::{Component, EntityId, EntityStore};
+use std::any::TypeId;
+
+#[derive(Debug, Clone)]
+pub(crate) struct Required {
+    component: TypeId,
+    requires: TypeId,
+    names: (&'static str, &'static str),
+    // Adds a default of the required component, None to refuse instead
+    insert: Option<fn(&mut EntityStore, EntityId)>,
+    // Takes the requiring component off, when its requirement is taken away
+    remove: fn(&mut EntityStore, EntityId),
+}
+
+fn remove<T: Component + Eq + 'static>(store: &mut EntityStore, entity_id: EntityId) {
+    store.remove_component::<T>(entity_id);
+}
+
+impl EntityStore {
+    // Entities with T must have R, refusing changes that would break it, see above
+    pub fn require<T: Component + Eq + 'static, R: Component + Eq + 'static>(&mut self) {
+        self.add_requirement::<T, R>(None);
+    }
+
+    // Entities with T must have R, adding or removing to keep it so, see above
+    pub fn require_default<T, R>(&mut self)
+    where
+        T: Component + Eq + 'static,
+        R: Component + Eq + Default + 'static,
+    {
+        self.add_requirement::<T, R>(Some(EntityStore::insert_default::<R>));
+    }
+
+    fn add_requirement<T: Component + Eq + 'static, R: Component + Eq + 'static>(
+        &mut self,
+        insert: Option<fn(&mut EntityStore, EntityId)>,
+    ) {
+        let (component, requires) = (TypeId::of::<T>(), TypeId::of::<R>());
+        self.requirements
+            .retain(|required| (required.component, required.requires) != (component, requires));
+        self.requirements.push(Required {
+            component,
+            requires,
+            names: (short_type_name::<T>(), short_type_name::<R>()),
+            insert,
+            remove: remove::<T>,
+        });
+    }
+
+    // remove_component, but saying when a requirement refused it
+    pub fn try_remove_component<T: Component + Eq + 'static>(
+        &mut self,
+        entity_id: EntityId,
+    ) -> Result<(), Error> {
+        self.needed(TypeId::of::<T>(), entity_id)?;
+        self.remove_component::<T>(entity_id);
+        Ok(())
+    }
+
+    // Whether a type is on either side of a requirement
+    pub(crate) fn constrained(&self, type_id: TypeId) -> bool {
+        self.requirements
+            .iter()
+            .any(|required| required.component == type_id || required.requires == type_id)
+    }
+
+    fn holds(&self, type_id: TypeId, entity_id: EntityId) -> bool {
+        self.presence(type_id)
+            .is_some_and(|presence| presence.contains(entity_id))
+    }
+
+    // An error if the component can't be added to the entity for lack of another
+    pub(crate) fn missing(&self, type_id: TypeId, entity_id: EntityId) -> Result<(), Error> {
+        let missing = self.requirements.iter().find(|required| {
+            required.component == type_id
+                && required.insert.is_none()
+                && !self.holds(required.requires, entity_id)
+        });
+        match missing {
+            Some(required) => Err(Error::Required {
+                component: required.names.0.to_string(),
+                requires: required.names.1.to_string(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    // An error if the component can't be taken off the entity while another needs it
+    pub(crate) fn needed(&self, type_id: TypeId, entity_id: EntityId) -> Result<(), Error> {
+        let needed = self.requirements.iter().find(|required| {
+            required.requires == type_id
+                && required.insert.is_none()
+                && self.holds(required.component, entity_id)
+        });
+        match needed {
+            Some(required) => Err(Error::Required {
+                component: required.names.0.to_string(),
+                requires: required.names.1.to_string(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    // Adds the defaults a newly added component needs
+    pub(crate) fn insert_required(&mut self, type_id: TypeId, entity_id: EntityId) {
+        let inserts: Vec<_> = self
+            .requirements
+            .iter()
+            .filter(|required| required.component == type_id)
+            .filter_map(|required| Some((required.requires, required.insert?)))
+            .collect();
+        for (requires, insert) in inserts {
+            if !self.holds(requires, entity_id) {
+                insert(self, entity_id);
+            }
+        }
+    }
+
+    // Takes off the components that needed one just removed
+    pub(crate) fn remove_requiring(&mut self, type_id: TypeId, entity_id: EntityId) {
+        let removes: Vec<_> = self
+            .requirements
+            .iter()
+            .filter(|required| required.requires == type_id && required.insert.is_some())
+            .map(|required| required.remove)
+            .collect();
+        for remove in removes {
+            remove(self, entity_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Default, PartialEq, Eq)]
+    struct Position(i64, i64);
+    impl Component for Position {}
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Velocity(i64, i64);
+    impl Component for Velocity {}
+
+    #[derive(Debug, Default, PartialEq, Eq)]
+    struct Mass(i64);
+    impl Component for Mass {}
+
+    #[test]
+    fn requirements_hold_on_insert_and_remove() {
+        let mut store = EntityStore::new();
+        store.new_component::<Position>();
+        store.new_component::<Velocity>();
+        store.require::<Velocity, Position>();
+        store.require_default::<Velocity, Mass>();
+
+        store.add_component(1, Velocity(1, 0));
+        assert!(!store.has_component::<Velocity>(1));
+        assert!(matches!(
+            store.try_add_component(1, Velocity(1, 0)),
+            Err(Error::Required { .. })
+        ));
+
+        store.add_component(2, Position(0, 0));
+        store.add_component(2, Velocity(1, 0));
+        assert!(store.has_component::<Velocity>(2) && store.has_component::<Mass>(2));
+        assert!(store.try_remove_component::<Position>(2).is_err());
+        assert!(store.has_component::<Position>(2));
+
+        store.remove_component::<Mass>(2);
+        assert!(!store.has_component::<Velocity>(2));
+        store.remove_component::<Position>(2);
+        assert!(!store.has_component::<Position>(2));
+    }
+}
@@ -0,0 +1,438 @@
+// Rule representation, shared by the DSL parser and rules built in Rust
+use crate::time::Span;
+use crate::value::{Bindings, Value};
+
+// An argument in a condition pattern
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Term {
+    Var(String),
+    Const(Value),
+    // `_`, matches anything and binds nothing
+    Wildcard,
+}
+
+// `Health(e, h)`, the first argument is always the entity
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Pattern {
+    pub component: String,
+    pub args: Vec<Term>,
+    // `where` clause, tested against each fact as the pool is scanned
+    // Besides bound variables it can use the component's field names
+    pub guard: Option<Expr>,
+    // How long the pattern must have held, see temporal.rs
+    pub temporal: Option<Temporal>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Temporal {
+    // `for at least 5 ticks`, has held for at least the span
+    AtLeast(Span),
+    // `within 10s`, began to hold no more than the span ago
+    Within(Span),
+    // `since t`, binds t to the tick the pattern began to hold
+    Since(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Expr {
+    Const(Value),
+    Var(String),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(Box<Expr>, BinaryOp, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Condition {
+    // The component must be present and unify with the bindings so far
+    Pattern(Pattern),
+    // No component may unify, unbound variables inside are local to the check
+    Not(Pattern),
+    // A boolean guard over already bound variables
+    Test(Expr),
+    // A spatial predicate, answered by the engine's spatial index
+    Spatial(Spatial),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Spatial {
+    // `near(a, b, 5)`, b is another entity within the radius of a
+    Near(Term, Term, Expr),
+    // `within(a, "town")`, a lies wholly inside a region defined on the engine
+    Within(Term, Expr),
+    // `adjacent(a, b)`, a and b touch without overlapping, diagonally included
+    Adjacent(Term, Term),
+    // `rcc8(a, b, "tpp|ntpp")`, a is known to relate to b by one of those, see rcc8.rs
+    Rcc8(Term, Term, Expr),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Action {
+    // Adds the component, replacing any existing one on that entity
+    // With a time to live the engine removes it again once that has passed
+    Insert {
+        component: String,
+        args: Vec<Expr>,
+        ttl: Option<Span>,
+    },
+    Remove {
+        component: String,
+        entity: Expr,
+    },
+    // Hands the activation to a script, see script.rs
+    Run {
+        script: String,
+    },
+    // Adds a constraint over the domains of the entities bound, see constraint.rs
+    Post {
+        constraint: Expr,
+    },
+    // Adds a cost for the solver to minimize, see solver.rs
+    Minimize {
+        cost: Expr,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TriggerKind {
+    Insert,
+    Remove,
+}
+
+// `count 3 within 60s`, the trigger must match this many changes in the span
+// Changes are counted separately for each set of values the trigger's variables take
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Window {
+    pub count: usize,
+    // None counts changes however far apart they are
+    pub within: Option<Span>,
+}
+
+// `on insert Damage(e, amt)`, the change that makes a reactive rule fire
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Trigger {
+    pub kind: TriggerKind,
+    pub pattern: Pattern,
+    pub window: Option<Window>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Rule {
+    pub name: String,
+    // Higher salience fires first when several rules are ready
+    pub salience: i32,
+    // Log odds its conclusions hold when its conditions do, None if certain, see
+    // probability.rs
+    pub weight: Option<f64>,
+    // Certainty factor of its conclusions given its conditions, None if certain, see
+    // certainty.rs
+    pub certainty: Option<f64>,
+    // Rules it's an exception to, by name, see defeasible.rs
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub overrides: Vec<String>,
+    // Never fires on what it inserted itself, see looping.rs
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub no_loop: bool,
+    // Only fires on what was ready when the run began, see looping.rs
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub lock_on_active: bool,
+    // Module the rule belongs to, modules can be enabled and disabled as a whole
+    pub module: Option<String>,
+    // Reactive rules fire once per matching change instead of whenever their conditions hold
+    pub trigger: Option<Trigger>,
+    pub conditions: Vec<Condition>,
+    pub actions: Vec<Action>,
+}
+
+impl Rule {
+    pub fn new(name: &str) -> Self {
+        Rule {
+            name: name.to_string(),
+            salience: 0,
+            weight: None,
+            certainty: None,
+            overrides: Vec::new(),
+            no_loop: false,
+            lock_on_active: false,
+            module: None,
+            trigger: None,
+            conditions: Vec::new(),
+            actions: Vec::new(),
+        }
+    }
+}
+
+impl Term {
+    pub fn var(name: &str) -> Self {
+        Term::Var(name.to_string())
+    }
+
+    pub fn value(value: impl Into<Value>) -> Self {
+        Term::Const(value.into())
+    }
+}
+
+impl Pattern {
+    pub fn new(component: &str, args: Vec<Term>) -> Self {
+        Pattern {
+            component: component.to_string(),
+            args,
+            guard: None,
+            temporal: None,
+        }
+    }
+
+    pub fn guarded(mut self, guard: Expr) -> Self {
+        self.guard = Some(guard);
+        self
+    }
+
+    // Extends bindings so the pattern matches the given entity and fields
+    // Returns None if a constant or already bound variable disagrees
+    pub fn unify(&self, values: &[Value], bindings: &Bindings) -> Option<Bindings> {
+        if values.len() != self.args.len() {
+            return None;
+        }
+        let mut bindings = bindings.clone();
+        for (term, value) in self.args.iter().zip(values) {
+            match term {
+                Term::Wildcard => {}
+                Term::Const(constant) => {
+                    if constant != value {
+                        return None;
+                    }
+                }
+                Term::Var(name) => match bindings.get(name) {
+                    Some(bound) if bound != value => return None,
+                    Some(_) => {}
+                    None => {
+                        bindings.insert(name.clone(), value.clone());
+                    }
+                },
+            }
+        }
+        Some(bindings)
+    }
+
+    // Whether the where clause holds for a fact the pattern unified with
+    // A variable shadows a field of the same name
+    pub fn guard_holds(&self, bindings: &Bindings, fields: &[&str], values: &[Value]) -> bool {
+        let Some(guard) = &self.guard else {
+            return true;
+        };
+        let mut scope = bindings.clone();
+        for (field, value) in fields.iter().zip(values) {
+            scope
+                .entry(field.to_string())
+                .or_insert_with(|| value.clone());
+        }
+        guard.eval(&scope) == Some(Value::Bool(true))
+    }
+
+    // Variables appearing in this pattern, in argument order, then any `since` variable
+    pub fn vars(&self) -> impl Iterator<Item = &str> {
+        let since = match &self.temporal {
+            Some(Temporal::Since(name)) => Some(name.as_str()),
+            _ => None,
+        };
+        self.args
+            .iter()
+            .filter_map(|term| match term {
+                Term::Var(name) => Some(name.as_str()),
+                _ => None,
+            })
+            .chain(since)
+    }
+}
+
+impl Spatial {
+    // The entity arguments, in order
+    pub fn entities(&self) -> Vec<&Term> {
+        match self {
+            Spatial::Near(a, b, _) | Spatial::Adjacent(a, b) | Spatial::Rcc8(a, b, _) => {
+                vec![a, b]
+            }
+            Spatial::Within(a, _) => vec![a],
+        }
+    }
+
+    // The radius, region or relations, which must only use bound variables
+    pub fn exprs(&self) -> Vec<&Expr> {
+        match self {
+            Spatial::Near(_, _, radius) => vec![radius],
+            Spatial::Rcc8(_, _, relations) => vec![relations],
+            Spatial::Within(_, region) => vec![region],
+            Spatial::Adjacent(..) => Vec::new(),
+        }
+    }
+}
+
+impl Expr {
+    pub fn var(name: &str) -> Self {
+        Expr::Var(name.to_string())
+    }
+
+    pub fn binary(lhs: Expr, op: BinaryOp, rhs: Expr) -> Self {
+        Expr::Binary(Box::new(lhs), op, Box::new(rhs))
+    }
+
+    // Evaluates against the bindings
+    // Returns None on unbound variables or type errors (e.g. "a" + 1)
+    pub fn eval(&self, bindings: &Bindings) -> Option<Value> {
+        match self {
+            Expr::Const(value) => Some(value.clone()),
+            Expr::Var(name) => bindings.get(name).cloned(),
+            Expr::Unary(op, expr) => {
+                let value = expr.eval(bindings)?;
+                match op {
+                    UnaryOp::Neg => match value {
+                        Value::Int(i) => Some(Value::Int(i.checked_neg()?)),
+                        Value::Float(f) => Some(Value::Float(-f)),
+                        _ => None,
+                    },
+                    UnaryOp::Not => Some(Value::Bool(!value.as_bool()?)),
+                }
+            }
+            Expr::Binary(lhs, BinaryOp::And, rhs) => {
+                if !lhs.eval(bindings)?.as_bool()? {
+                    return Some(Value::Bool(false));
+                }
+                Some(Value::Bool(rhs.eval(bindings)?.as_bool()?))
+            }
+            Expr::Binary(lhs, BinaryOp::Or, rhs) => {
+                if lhs.eval(bindings)?.as_bool()? {
+                    return Some(Value::Bool(true));
+                }
+                Some(Value::Bool(rhs.eval(bindings)?.as_bool()?))
+            }
+            Expr::Binary(lhs, op, rhs) => binary(&lhs.eval(bindings)?, *op, &rhs.eval(bindings)?),
+        }
+    }
+
+    // Every variable referenced by the expression
+    pub fn vars(&self) -> Vec<&str> {
+        let mut vars = Vec::new();
+        self.collect_vars(&mut vars);
+        vars
+    }
+
+    fn collect_vars<'a>(&'a self, vars: &mut Vec<&'a str>) {
+        match self {
+            Expr::Const(_) => {}
+            Expr::Var(name) => vars.push(name),
+            Expr::Unary(_, expr) => expr.collect_vars(vars),
+            Expr::Binary(lhs, _, rhs) => {
+                lhs.collect_vars(vars);
+                rhs.collect_vars(vars);
+            }
+        }
+    }
+}
+
+fn binary(lhs: &Value, op: BinaryOp, rhs: &Value) -> Option<Value> {
+    use std::cmp::Ordering;
+    match op {
+        BinaryOp::Eq => Some(Value::Bool(lhs.compare(rhs)? == Ordering::Equal)),
+        BinaryOp::Ne => Some(Value::Bool(lhs.compare(rhs)? != Ordering::Equal)),
+        BinaryOp::Lt => Some(Value::Bool(lhs.compare(rhs)? == Ordering::Less)),
+        BinaryOp::Le => Some(Value::Bool(lhs.compare(rhs)? != Ordering::Greater)),
+        BinaryOp::Gt => Some(Value::Bool(lhs.compare(rhs)? == Ordering::Greater)),
+        BinaryOp::Ge => Some(Value::Bool(lhs.compare(rhs)? != Ordering::Less)),
+        BinaryOp::And | BinaryOp::Or => None,
+        _ => match (lhs, rhs) {
+            (Value::Int(a), Value::Int(b)) => Some(Value::Int(match op {
+                BinaryOp::Add => a.checked_add(*b)?,
+                BinaryOp::Sub => a.checked_sub(*b)?,
+                BinaryOp::Mul => a.checked_mul(*b)?,
+                BinaryOp::Div => a.checked_div(*b)?,
+                BinaryOp::Rem => a.checked_rem(*b)?,
+                _ => unreachable!(),
+            })),
+            (Value::Str(a), Value::Str(b)) if op == BinaryOp::Add => {
+                Some(Value::Str(format!("{}{}", a, b)))
+            }
+            (a, b) => {
+                let (a, b) = (a.as_float()?, b.as_float()?);
+                Some(Value::Float(match op {
+                    BinaryOp::Add => a + b,
+                    BinaryOp::Sub => a - b,
+                    BinaryOp::Mul => a * b,
+                    BinaryOp::Div => a / b,
+                    BinaryOp::Rem => a % b,
+                    _ => unreachable!(),
+                }))
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unify_respects_existing_bindings() {
+        let pattern = Pattern::new("Parent", vec![Term::var("x"), Term::var("y")]);
+        let mut bindings = Bindings::new();
+        bindings.insert("x".into(), Value::Entity(1));
+
+        let matched = pattern
+            .unify(&[Value::Entity(1), Value::Entity(2)], &bindings)
+            .unwrap();
+        assert_eq!(matched.get("y"), Some(&Value::Entity(2)));
+        assert!(pattern
+            .unify(&[Value::Entity(3), Value::Entity(2)], &bindings)
+            .is_none());
+    }
+
+    #[test]
+    fn eval_arithmetic_and_comparison() {
+        let mut bindings = Bindings::new();
+        bindings.insert("h".into(), Value::Int(7));
+
+        let expr = Expr::binary(
+            Expr::binary(Expr::var("h"), BinaryOp::Add, Expr::Const(Value::Int(2))),
+            BinaryOp::Lt,
+            Expr::Const(Value::Float(10.0)),
+        );
+        assert_eq!(expr.eval(&bindings), Some(Value::Bool(true)));
+
+        let bad = Expr::binary(Expr::var("h"), BinaryOp::Add, Expr::Const("x".into()));
+        assert_eq!(bad.eval(&bindings), None);
+    }
+}
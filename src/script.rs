@@ -0,0 +1,284 @@
+// Rule actions written in Rhai
+//
+// With the scripting feature, `then run "reward"` hands an activation to a script
+// defined on the engine with define_script, for actions that don't fit insert and
+// remove. The rule's bindings are the script's variables, entities as Entity values,
+// and the script works on the store through a few functions:
+//
+//   get("Health", e)        the fields as an array, or () if e has no Health
+//   has("Health", e)
+//   set("Health", e, [30])  adds or replaces, false if the fields don't fit
+//   remove("Health", e)
+//   new_entity()            a new entity, with nothing on it yet
+//   despawn(e)
+//   entity(3), e.id         between entities and integers
+//
+// Components are named as the registry names them, as in rules. The store is lent to
+// the script for the run and handed back after, so a script can't keep hold of it.
+// Changes made by scripts aren't traced or given provenance, and a script that fails
+// leaves whatever it had done and is reported as StoreEvent::ActionFailed.
+use crate::error::Error;
+use crate::registry::Registry;
+use crate::stats::StoreEvent;
+use crate::store::{EntityId, EntityStore};
+use crate::value::{Bindings, Value};
+use rhai::{Array, Dynamic, Engine, Scope, AST, INT};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// An entity as scripts see it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ScriptEntity(pub EntityId);
+
+// What scripts work on while one runs
+#[derive(Default)]
+struct World {
+    store: EntityStore,
+    registry: Registry,
+}
+
+type Shared = Arc<Mutex<World>>;
+
+pub(crate) struct Scripts {
+    engine: Engine,
+    scripts: HashMap<String, AST>,
+    world: Shared,
+}
+
+impl fmt::Debug for Scripts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Scripts")
+            .field("scripts", &self.scripts.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Default for Scripts {
+    fn default() -> Self {
+        let world = Shared::default();
+        let mut engine = Engine::new();
+        engine
+            .register_type_with_name::<ScriptEntity>("Entity")
+            .register_get("id", |entity: &mut ScriptEntity| entity.0 as INT)
+            .register_fn("entity", |id: INT| ScriptEntity(id.max(0) as EntityId));
+
+        let shared = world.clone();
+        engine.register_fn("get", move |component: &str, entity: ScriptEntity| {
+            let world = lock(&shared);
+            match world.registry.get(component) {
+                Some(info) => info
+                    .get(&world.store, entity.0)
+                    .map_or(Dynamic::UNIT, |values| {
+                        values.into_iter().map(to_dynamic).collect::<Array>().into()
+                    }),
+                None => Dynamic::UNIT,
+            }
+        });
+        let shared = world.clone();
+        engine.register_fn("has", move |component: &str, entity: ScriptEntity| {
+            let world = lock(&shared);
+            let info = world.registry.get(component);
+            info.is_some_and(|info| info.get(&world.store, entity.0).is_some())
+        });
+        let shared = world.clone();
+        engine.register_fn(
+            "set",
+            move |component: &str, entity: ScriptEntity, fields: Array| {
+                let world = &mut *lock(&shared);
+                let Some(info) = world.registry.get(component) else {
+                    return false;
+                };
+                let Some(values) = fields
+                    .into_iter()
+                    .map(from_dynamic)
+                    .collect::<Option<Vec<_>>>()
+                else {
+                    return false;
+                };
+                values.len() == info.fields.len()
+                    && info.insert(&mut world.store, entity.0, &values)
+            },
+        );
+        let shared = world.clone();
+        engine.register_fn("remove", move |component: &str, entity: ScriptEntity| {
+            let world = &mut *lock(&shared);
+            if let Some(info) = world.registry.get(component) {
+                info.remove(&mut world.store, entity.0);
+            }
+        });
+        let shared = world.clone();
+        engine.register_fn("new_entity", move || {
+            ScriptEntity(lock(&shared).store.spawn())
+        });
+        let shared = world.clone();
+        engine.register_fn("despawn", move |entity: ScriptEntity| {
+            lock(&shared).store.remove_entity(entity.0);
+        });
+
+        Scripts {
+            engine,
+            scripts: HashMap::new(),
+            world,
+        }
+    }
+}
+
+fn lock(world: &Shared) -> MutexGuard<'_, World> {
+    world
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn to_dynamic(value: Value) -> Dynamic {
+    match value {
+        Value::Int(i) => Dynamic::from(i),
+        Value::Float(f) => Dynamic::from(f),
+        Value::Bool(b) => Dynamic::from(b),
+        Value::Str(s) => Dynamic::from(s),
+        Value::Entity(entity_id) => Dynamic::from(ScriptEntity(entity_id)),
+    }
+}
+
+fn from_dynamic(value: Dynamic) -> Option<Value> {
+    if let Some(entity) = value.clone().try_cast::<ScriptEntity>() {
+        return Some(Value::Entity(entity.0));
+    }
+    if let Ok(i) = value.as_int() {
+        return Some(Value::Int(i));
+    }
+    if let Ok(f) = value.as_float() {
+        return Some(Value::Float(f));
+    }
+    if let Ok(b) = value.as_bool() {
+        return Some(Value::Bool(b));
+    }
+    value.into_string().ok().map(Value::Str)
+}
+
+impl Scripts {
+    pub(crate) fn define(&mut self, name: &str, source: &str) -> Result<(), Error> {
+        let ast = self
+            .engine
+            .compile(source)
+            .map_err(|error| Error::Script(format!("`{}`: {}", name, error)))?;
+        self.scripts.insert(name.to_string(), ast);
+        Ok(())
+    }
+
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.scripts.contains_key(name)
+    }
+
+    // Runs the named script over the store, see above
+    pub(crate) fn run(
+        &self,
+        rule: &str,
+        name: &str,
+        bindings: &Bindings,
+        registry: &Registry,
+        store: &mut EntityStore,
+    ) {
+        let Some(ast) = self.scripts.get(name) else {
+            return;
+        };
+        let mut scope = Scope::new();
+        for (variable, value) in bindings {
+            scope.push_dynamic(variable.as_str(), to_dynamic(value.clone()));
+        }
+        {
+            let mut world = lock(&self.world);
+            world.store = std::mem::take(store);
+            world.registry = registry.clone();
+        }
+        let result = self.engine.run_ast_with_scope(&mut scope, ast);
+        *store = std::mem::take(&mut lock(&self.world).store);
+        if let Err(error) = result {
+            store.emit(StoreEvent::ActionFailed {
+                rule: rule.to_string(),
+                message: error.to_string(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::RuleEngine;
+    use crate::registry::Fact;
+    use crate::store::Component;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Gold(i64);
+    impl Component for Gold {}
+
+    impl Fact for Gold {
+        const FIELDS: &'static [&'static str] = &["amount"];
+
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.0)]
+        }
+
+        fn from_values(values: &[Value]) -> Option<Self> {
+            Some(Gold(values.first()?.as_int()?))
+        }
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Chest(EntityId);
+    impl Component for Chest {}
+
+    impl Fact for Chest {
+        const FIELDS: &'static [&'static str] = &["owner"];
+
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Entity(self.0)]
+        }
+
+        fn from_values(values: &[Value]) -> Option<Self> {
+            Some(Chest(values.first()?.as_entity()?))
+        }
+    }
+
+    #[test]
+    fn scripts_act_for_rules() {
+        let mut engine = RuleEngine::new();
+        engine.register::<Gold>("Gold");
+        engine.register::<Chest>("Chest");
+        engine
+            .define_script(
+                "open",
+                r#"
+                let gold = get("Gold", owner);
+                set("Gold", owner, [gold[0] + 50]);
+                despawn(c);
+                let pile = new_entity();
+                set("Gold", pile, [pile.id]);
+                "#,
+            )
+            .unwrap();
+        assert!(engine.define_script("broken", "let = ;").is_err());
+        engine
+            .load_str(r#"rule "open" when Chest(c, owner), Gold(owner, _) then run "open""#)
+            .unwrap();
+        assert!(matches!(
+            engine.load_str(r#"rule "x" when Gold(e, _) then run "missing""#),
+            Err(Error::Script(_))
+        ));
+
+        let mut store = EntityStore::new();
+        store.new_component::<Gold>();
+        store.new_component::<Chest>();
+        store.add_component(1, Gold(10));
+        store.add_component(2, Chest(1));
+        store.reserve_up_to(2);
+        assert_eq!(engine.run(&mut store), 1);
+        let gold = store.get::<Gold>().unwrap().borrow();
+        assert_eq!(
+            (gold.get(1), gold.get(3)),
+            (Some(&Gold(60)), Some(&Gold(3)))
+        );
+        assert!(!store.has_component::<Chest>(2));
+    }
+}
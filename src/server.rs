@@ -0,0 +1,341 @@
+// Serving a world over HTTP
+//
+// With the server feature the engine and a store can be run as a service, for
+// deployments that want rules as a microservice rather than a library. router builds
+// the axum Router, to mount in a larger app, and serve listens on an address with
+// nothing else. `rete serve [address] [rule files...]` does the same from the
+// command line. Values are written as in json.rs, entities as { "entity": id }.
+//
+//   POST   /rules                    rule file text, loaded as with load_str
+//   POST   /entities                 a new entity, { "entity": id }
+//   POST   /facts                    { "component": "Health", "entity": 3, "fields": { "hp": 10 } }
+//   GET    /facts/:component         [{ "entity": 3, "fields": { "hp": 10 } }]
+//   DELETE /facts/:component/:entity
+//   POST   /run                      { "fired": 2 }
+//   POST   /query                    { "conditions": "Health(e, h), h < 10" }, one object per match
+//   GET    /metrics                  Prometheus text, with the metrics feature, see metrics.rs
+//
+// Facts only go on entities made by POST /entities, or already in the store it was
+// given, so a client can't have the store allocate for an id far past any entity.
+//
+// Requests take turns on one lock, so each sees the world as the last one left it. A
+// run can take a while, so the lock is waited on and held on tokio's blocking threads,
+// leaving the executor's to serve everything else meanwhile. A failed request answers
+// { "error": message }, with 404 for unknown components and 400 for the rest.
+use crate::engine::RuleEngine;
+use crate::error::Error;
+use crate::json::{from_json, to_json};
+use crate::store::{EntityId, EntityStore};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+use serde_json::{json, Map, Value};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+struct Served {
+    engine: RuleEngine,
+    store: EntityStore,
+}
+
+type Shared = Arc<Mutex<Served>>;
+
+struct Failure(Error);
+
+impl From<Error> for Failure {
+    fn from(error: Error) -> Self {
+        Failure(error)
+    }
+}
+
+impl IntoResponse for Failure {
+    fn into_response(self) -> Response {
+        let status = match self.0 {
+            Error::UnknownComponent(_) => StatusCode::NOT_FOUND,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        (status, Json(json!({ "error": self.0.to_string() }))).into_response()
+    }
+}
+
+type Answer = Result<Json<Value>, Failure>;
+
+fn bad(message: &str) -> Failure {
+    Failure(Error::Request(message.to_string()))
+}
+
+// A panicked request leaves the world as it was when it panicked, which is still usable
+fn lock(shared: &Shared) -> MutexGuard<'_, Served> {
+    shared
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// Works on the world on a blocking thread, see above
+async fn with<T: Send + 'static>(
+    shared: Shared,
+    work: impl FnOnce(&mut Served) -> T + Send + 'static,
+) -> T {
+    tokio::task::spawn_blocking(move || work(&mut lock(&shared)))
+        .await
+        .unwrap_or_else(|failed| std::panic::resume_unwind(failed.into_panic()))
+}
+
+async fn load_rules(State(shared): State<Shared>, source: String) -> Result<StatusCode, Failure> {
+    with(shared, move |served| served.engine.load_str(&source)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn spawn(State(shared): State<Shared>) -> Answer {
+    let entity_id = with(shared, |served| served.store.spawn()).await;
+    Ok(Json(json!({ "entity": entity_id })))
+}
+
+async fn assert_fact(
+    State(shared): State<Shared>,
+    Json(mut body): Json<Value>,
+) -> Result<StatusCode, Failure> {
+    let component = body["component"]
+        .as_str()
+        .ok_or_else(|| bad("`component` should be a string"))?
+        .to_string();
+    let entity_id = body["entity"]
+        .as_u64()
+        .ok_or_else(|| bad("`entity` should be an entity id"))?;
+    let entity_id = EntityId::try_from(entity_id).unwrap_or(EntityId::MAX);
+    let fields = match body.get_mut("fields").map(Value::take).unwrap_or_default() {
+        Value::Null => Map::new(),
+        Value::Object(fields) => fields,
+        _ => return Err(bad("`fields` should be an object")),
+    };
+    with(shared, move |served| {
+        if entity_id >= served.store.next_entity() {
+            return Err(bad(&format!("there's no entity {}", entity_id)));
+        }
+        let info = served
+            .engine
+            .registry()
+            .get(&component)
+            .ok_or_else(|| Error::UnknownComponent(component.clone()))?;
+        if let Some(extra) = fields
+            .keys()
+            .find(|key| !info.fields.contains(&key.as_str()))
+        {
+            return Err(bad(&format!("`{}` has no field `{}`", component, extra)));
+        }
+        let values = info
+            .fields
+            .iter()
+            .map(|field| {
+                let value = fields
+                    .get(*field)
+                    .ok_or_else(|| bad(&format!("missing field `{}`", field)))?;
+                from_json(value).ok_or_else(|| bad(&format!("`{}` isn't a value", field)))
+            })
+            .collect::<Result<Vec<_>, Failure>>()?;
+        let registry = served.engine.registry();
+        served
+            .store
+            .insert_fact(registry, &component, entity_id, &values)?;
+        Ok(StatusCode::NO_CONTENT)
+    })
+    .await
+}
+
+async fn facts(State(shared): State<Shared>, Path(component): Path<String>) -> Answer {
+    with(shared, move |served| {
+        let info = served
+            .engine
+            .registry()
+            .get(&component)
+            .ok_or_else(|| Error::UnknownComponent(component.clone()))?;
+        let facts: Vec<Value> = info
+            .facts(&served.store)
+            .iter()
+            .map(|(entity_id, values)| {
+                let fields: Map<String, Value> = info
+                    .fields
+                    .iter()
+                    .zip(values)
+                    .map(|(field, value)| (field.to_string(), to_json(value)))
+                    .collect();
+                json!({ "entity": entity_id, "fields": fields })
+            })
+            .collect();
+        Ok(Json(Value::Array(facts)))
+    })
+    .await
+}
+
+async fn retract(
+    State(shared): State<Shared>,
+    Path((component, entity_id)): Path<(String, EntityId)>,
+) -> Result<StatusCode, Failure> {
+    with(shared, move |served| {
+        let info = served
+            .engine
+            .registry()
+            .get(&component)
+            .ok_or_else(|| Error::UnknownComponent(component.clone()))?;
+        info.remove(&mut served.store, entity_id);
+        Ok(StatusCode::NO_CONTENT)
+    })
+    .await
+}
+
+async fn run(State(shared): State<Shared>) -> Answer {
+    let fired = with(shared, |served| served.engine.run(&mut served.store)).await;
+    Ok(Json(json!({ "fired": fired })))
+}
+
+async fn query(State(shared): State<Shared>, Json(body): Json<Value>) -> Answer {
+    let conditions = body["conditions"]
+        .as_str()
+        .ok_or_else(|| bad("`conditions` should be a string"))?
+        .to_string();
+    with(shared, move |served| {
+        let found = served.engine.query(&conditions, &served.store)?;
+        let rows = found
+            .iter()
+            .map(|bindings| {
+                let row: Map<String, Value> = bindings
+                    .iter()
+                    .map(|(name, value)| (name.clone(), to_json(value)))
+                    .collect();
+                Value::Object(row)
+            })
+            .collect();
+        Ok(Json(Value::Array(rows)))
+    })
+    .await
+}
+
+#[cfg(feature = "metrics")]
+async fn metrics(State(shared): State<Shared>) -> String {
+    with(shared, |served| {
+        served
+            .engine
+            .metrics_text(&served.store)
+            .unwrap_or_default()
+    })
+    .await
+}
+
+// The service's routes over the engine and store, see above
+// With the metrics feature, the engine counts from here on for GET /metrics
+pub fn router(engine: RuleEngine, store: EntityStore) -> Router {
+    let shared = Arc::new(Mutex::new(Served { engine, store }));
+    #[cfg(feature = "metrics")]
+    lock(&shared).engine.enable_metrics();
+    let routes = Router::new()
+        .route("/rules", post(load_rules))
+        .route("/entities", post(spawn))
+        .route("/facts", post(assert_fact))
+        .route("/facts/:component", get(facts))
+        .route("/facts/:component/:entity", delete(retract))
+        .route("/run", post(run))
+        .route("/query", post(query));
+    #[cfg(feature = "metrics")]
+    let routes = routes.route("/metrics", get(metrics));
+    routes.with_state(shared)
+}
+
+// Serves the router on the address until the listener fails
+pub async fn serve(address: &str, engine: RuleEngine, store: EntityStore) -> std::io::Result<()> {
+    let listener = tokio::net::TcpListener::bind(address).await?;
+    axum::serve(listener, router(engine, store)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+
+    fn request(address: SocketAddr, method: &str, path: &str, body: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: rete\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response[9..12].parse().unwrap();
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        (status, body.to_string())
+    }
+
+    #[test]
+    fn facts_and_rules_over_http() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let listener = runtime
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = router(RuleEngine::new(), EntityStore::new());
+        runtime.spawn(async move { axum::serve(listener, app).await });
+
+        let rules = r#"fact Order(total)
+            fact Discount(percent)
+            rule "big" when Order(o, t), t >= 100 then insert Discount(o, 10)"#;
+        assert_eq!(request(address, "POST", "/rules", rules).0, 204);
+        assert_eq!(
+            request(address, "POST", "/entities", ""),
+            (200, r#"{"entity":0}"#.to_string())
+        );
+        assert_eq!(
+            request(address, "POST", "/entities", ""),
+            (200, r#"{"entity":1}"#.to_string())
+        );
+        for (entity, total) in [(0, 150), (1, 20)] {
+            let order =
+                json!({ "component": "Order", "entity": entity, "fields": { "total": total } });
+            let status = request(address, "POST", "/facts", &order.to_string()).0;
+            assert_eq!(status, 204);
+        }
+        assert_eq!(
+            request(address, "POST", "/run", ""),
+            (200, r#"{"fired":1}"#.to_string())
+        );
+        let (_, found) = request(
+            address,
+            "POST",
+            "/query",
+            r#"{"conditions": "Discount(o, p)"}"#,
+        );
+        assert_eq!(found, r#"[{"o":{"entity":0},"p":10}]"#);
+
+        assert_eq!(request(address, "DELETE", "/facts/Discount/0", "").0, 204);
+        assert_eq!(
+            request(address, "GET", "/facts/Discount", ""),
+            (200, "[]".to_string())
+        );
+        let (status, error) = request(address, "GET", "/facts/Invoice", "");
+        assert_eq!(
+            (status, error.as_str()),
+            (404, r#"{"error":"unknown component `Invoice`"}"#)
+        );
+        let missing = r#"{"component": "Order", "entity": 1, "fields": {}}"#;
+        assert_eq!(request(address, "POST", "/facts", missing).0, 400);
+        for entity in [2, 1u64 << 50] {
+            let order = json!({ "component": "Order", "entity": entity, "fields": { "total": 1 } });
+            let (status, error) = request(address, "POST", "/facts", &order.to_string());
+            assert_eq!(status, 400);
+            assert!(error.contains(&format!("there's no entity {}", entity)));
+        }
+
+        #[cfg(feature = "metrics")]
+        {
+            let (status, text) = request(address, "GET", "/metrics", "");
+            assert_eq!(status, 200);
+            assert!(text.contains("rete_rule_firings_total{rule=\"big\"} 1"));
+        }
+    }
+}
@@ -0,0 +1,211 @@
+// Shadow execution of two versions of a rule set
+//
+// The primary version runs against the real store as usual. Before it does, the
+// registered facts are copied into a scratch store and the candidate version runs
+// there. Afterwards the two stores are compared and every fact they disagree on is
+// recorded, so a new rule set can be checked against live input before switching.
+use crate::engine::RuleEngine;
+use crate::store::{EntityId, EntityStore};
+use crate::value::Value;
+use std::collections::BTreeMap;
+
+// A fact the two versions ended a run disagreeing on
+// None means that version has no such component on the entity
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub run: usize,
+    pub component: String,
+    pub entity: EntityId,
+    pub primary: Option<Vec<Value>>,
+    pub candidate: Option<Vec<Value>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShadowReport {
+    pub primary_fired: usize,
+    pub candidate_fired: usize,
+    pub divergences: usize,
+}
+
+#[derive(Debug)]
+pub struct Shadow {
+    pub primary_version: String,
+    pub candidate_version: String,
+    primary: RuleEngine,
+    candidate: RuleEngine,
+    runs: usize,
+    divergences: Vec<Divergence>,
+}
+
+type FactTable = BTreeMap<(String, EntityId), Vec<Value>>;
+
+impl Shadow {
+    pub fn new(
+        primary_version: &str,
+        primary: RuleEngine,
+        candidate_version: &str,
+        candidate: RuleEngine,
+    ) -> Self {
+        Shadow {
+            primary_version: primary_version.to_string(),
+            candidate_version: candidate_version.to_string(),
+            primary,
+            candidate,
+            runs: 0,
+            divergences: Vec::new(),
+        }
+    }
+
+    pub fn primary(&self) -> &RuleEngine {
+        &self.primary
+    }
+
+    pub fn candidate(&self) -> &RuleEngine {
+        &self.candidate
+    }
+
+    // Every divergence recorded so far, oldest first
+    pub fn divergences(&self) -> &[Divergence] {
+        &self.divergences
+    }
+
+    // Facts registered with the primary, keyed so the two stores line up
+    fn table(&self, store: &EntityStore) -> FactTable {
+        let mut table = FactTable::new();
+        for info in self.primary.registry().iter() {
+            for (entity_id, values) in info.facts(store) {
+                table.insert((info.name.clone(), entity_id), values);
+            }
+        }
+        table
+    }
+
+    // Runs both versions from the store's current state
+    // Only the primary's changes are kept in the store
+    pub fn run(&mut self, store: &mut EntityStore) -> ShadowReport {
+        let mut scratch = EntityStore::new();
+        self.primary.registry().copy_facts(store, &mut scratch);
+
+        let primary_fired = self.primary.run(store);
+        let candidate_fired = self.candidate.run(&mut scratch);
+
+        let primary = self.table(store);
+        let mut candidate = self.table(&scratch);
+        let before = self.divergences.len();
+
+        for ((component, entity), values) in primary {
+            let other = candidate.remove(&(component.clone(), entity));
+            if other.as_ref() != Some(&values) {
+                self.divergences.push(Divergence {
+                    run: self.runs,
+                    component,
+                    entity,
+                    primary: Some(values),
+                    candidate: other,
+                });
+            }
+        }
+        for ((component, entity), values) in candidate {
+            self.divergences.push(Divergence {
+                run: self.runs,
+                component,
+                entity,
+                primary: None,
+                candidate: Some(values),
+            });
+        }
+
+        self.runs += 1;
+        ShadowReport {
+            primary_fired,
+            candidate_fired,
+            divergences: self.divergences.len() - before,
+        }
+    }
+
+    // Switch over, the candidate becomes the engine to use from now on
+    // Its refraction memory came from scratch stores, so it starts afresh on the real one
+    pub fn promote(mut self) -> RuleEngine {
+        self.candidate.forget_fired();
+        self.candidate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::{Fact, Registry};
+    use crate::store::Component;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Health(i64);
+    impl Component for Health {}
+    impl Fact for Health {
+        const FIELDS: &'static [&'static str] = &["value"];
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.0)]
+        }
+        fn from_values(values: &[Value]) -> Option<Self> {
+            match values {
+                [value] => Some(Health(value.as_int()?)),
+                _ => None,
+            }
+        }
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Fleeing;
+    impl Component for Fleeing {}
+    impl Fact for Fleeing {
+        const FIELDS: &'static [&'static str] = &[];
+        fn to_values(&self) -> Vec<Value> {
+            Vec::new()
+        }
+        fn from_values(values: &[Value]) -> Option<Self> {
+            values.is_empty().then_some(Fleeing)
+        }
+    }
+
+    fn version(registry: &Registry, threshold: i64) -> RuleEngine {
+        let mut engine = RuleEngine::with_registry(registry.clone());
+        engine
+            .load_str(&format!(
+                r#"rule "flee" when Health(e, h), h < {} then insert Fleeing(e)"#,
+                threshold
+            ))
+            .unwrap();
+        engine
+    }
+
+    #[test]
+    fn records_divergent_derivations() {
+        let mut registry = Registry::new();
+        registry.register::<Health>("Health");
+        registry.register::<Fleeing>("Fleeing");
+        let mut shadow = Shadow::new("v1", version(&registry, 10), "v2", version(&registry, 20));
+
+        let mut store = EntityStore::new();
+        store.new_component::<Health>();
+        store.add_component(1, Health(5));
+        store.add_component(2, Health(15));
+
+        let report = shadow.run(&mut store);
+        assert_eq!(report.primary_fired, 1);
+        assert_eq!(report.candidate_fired, 2);
+        assert_eq!(
+            shadow.divergences(),
+            &[Divergence {
+                run: 0,
+                component: "Fleeing".into(),
+                entity: 2,
+                primary: None,
+                candidate: Some(Vec::new()),
+            }]
+        );
+        assert!(!store.has_component::<Fleeing>(2));
+
+        let mut engine = shadow.promote();
+        engine.run(&mut store);
+        assert!(store.has_component::<Fleeing>(2));
+    }
+}
@@ -0,0 +1,137 @@
+// Components only one entity may have
+//
+// Some components describe the world rather than anything in it, a clock or the
+// weather or the current level, and a second copy is always a mistake. Marking the
+// type with singleton says what to do when one is added to an entity while another
+// holds it: Reject leaves the holder as it was and drops the new component, Move takes
+// it off the holder first, in the same undo step. add_component can't say which
+// happened, try_add_component returns Error::Singleton for a rejected one. single
+// returns the holder and its component without going through the pool.
+use crate::cell::AtomicRef;
+use crate::error::Error;
+use crate::relation::short_type_name;
+use crate::store::{check_entity, Component, EntityId, EntityStore};
+use std::any::TypeId;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Uniqueness {
+    Reject,
+    Move,
+}
+
+impl EntityStore {
+    // Makes T unique in this store, making its pool if needed
+    // Entities holding it already are left alone, even if there are several
+    pub fn singleton<T: Component + Eq + 'static>(&mut self, uniqueness: Uniqueness) {
+        if self.get::<T>().is_none() {
+            self.new_component::<T>();
+        }
+        self.singletons.insert(TypeId::of::<T>(), uniqueness);
+    }
+
+    pub fn is_singleton<T: Component + Eq + 'static>(&self) -> bool {
+        self.singletons.contains_key(&TypeId::of::<T>())
+    }
+
+    // The entity holding T and its component, the lowest if several do
+    pub fn single<T: Component + Eq + 'static>(&self) -> Option<(EntityId, AtomicRef<'_, T>)> {
+        let entity_id = self
+            .presence(TypeId::of::<T>())?
+            .entities()
+            .into_iter()
+            .next()?;
+        let pool = self.get::<T>()?.borrow();
+        let component = AtomicRef::filter_map(pool, |pool| pool.get(entity_id)).ok()?;
+        Some((entity_id, component))
+    }
+
+    // add_component, but saying when the pool is missing, the id is past MAX_ENTITY, a
+    // singleton was rejected or a requirement refused it, see require.rs
+    pub fn try_add_component<T: Component + Eq + 'static>(
+        &mut self,
+        entity_id: EntityId,
+        component: T,
+    ) -> Result<(), Error> {
+        if self.get::<T>().is_none() {
+            return Err(Error::UnknownComponent(short_type_name::<T>().to_string()));
+        }
+        check_entity(entity_id)?;
+        self.make_room::<T>(entity_id)?;
+        if !self.has_component::<T>(entity_id) {
+            self.missing(TypeId::of::<T>(), entity_id)?;
+        }
+        self.add_component(entity_id, component);
+        Ok(())
+    }
+
+    // Some other entity to take T off before adding it to this one, or an error if it
+    // can't be added, see above
+    pub(crate) fn make_room<T: Component + Eq + 'static>(
+        &self,
+        entity_id: EntityId,
+    ) -> Result<Option<EntityId>, Error> {
+        let Some(&uniqueness) = self.singletons.get(&TypeId::of::<T>()) else {
+            return Ok(None);
+        };
+        let Some(presence) = self.presence(TypeId::of::<T>()) else {
+            return Ok(None);
+        };
+        let holder = presence
+            .entities()
+            .into_iter()
+            .find(|&other| other != entity_id);
+        match (holder, uniqueness) {
+            (None, _) => Ok(None),
+            (Some(_), Uniqueness::Reject) => {
+                Err(Error::Singleton(short_type_name::<T>().to_string()))
+            }
+            (Some(holder), Uniqueness::Move) => Ok(Some(holder)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Clock(u64);
+    impl Component for Clock {}
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Camera(i64);
+    impl Component for Camera {}
+
+    #[test]
+    fn singletons_reject_or_move() {
+        let mut store = EntityStore::new();
+        store.singleton::<Clock>(Uniqueness::Reject);
+        store.singleton::<Camera>(Uniqueness::Move);
+        store.add_component(1, Clock(10));
+        store.add_component(1, Clock(11));
+        assert!(matches!(
+            store.try_add_component(2, Clock(0)),
+            Err(Error::Singleton(_))
+        ));
+        store.add_component(3, Clock(0));
+        assert_eq!(
+            store.single::<Clock>().map(|(e, c)| (e, c.0)),
+            Some((1, 11))
+        );
+
+        store.snapshot_component::<Camera>();
+        store.start_journal();
+        store.add_component(4, Camera(1));
+        store.add_component(6, Camera(2));
+        assert!(!store.has_component::<Camera>(4));
+        assert_eq!(
+            store.single::<Camera>().map(|(e, c)| (e, c.0)),
+            Some((6, 2))
+        );
+        store.undo(1);
+        assert_eq!(
+            store.single::<Camera>().map(|(e, c)| (e, c.0)),
+            Some((4, 1))
+        );
+    }
+}
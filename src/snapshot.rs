@@ -0,0 +1,239 @@
+// Checkpointing a store and rolling it back
+//
+// Pools are type erased, so a snapshot can only copy the ones whose component type has
+// been marked with snapshot_component, which needs the type to be Clone. Tiles and
+// parents mark their own types. A snapshot holds copies of those pools, the highest
+// entity id and which tile is in each cell. restore puts them back through
+// add_component and remove_component, touching only what differs, so hooks, reverse
+// indexes and change tracking see the rollback like any other change. Requirements,
+// see require.rs, are set aside while pools go back one by one, as the state being
+// restored kept them already. Pools of unmarked types are left as they are by restore.
+use crate::diff;
+use crate::journal;
+use crate::store::{Component, EntityId, EntityStore, Pool};
+use crate::tiles::Tile;
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+
+// Compares two saved pools of one type, either possibly missing, giving a PoolDelta
+type PoolDiff = fn(Option<&dyn Any>, Option<&dyn Any>) -> Option<Box<dyn Any>>;
+
+// Sets or clears one entity's component, giving back what it replaced
+type PutOne = fn(
+    &mut EntityStore,
+    EntityId,
+    Option<Box<dyn Any + Send + Sync>>,
+) -> Option<Box<dyn Any + Send + Sync>>;
+
+// Copies a pool out and puts one back, monomorphised for each marked type
+// The rest compare saved pools and patch stores with the result, see diff.rs, and
+// copy and set single components, see journal.rs
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PoolCopier {
+    copy: fn(&EntityStore) -> Option<Box<dyn Any>>,
+    restore: fn(&mut EntityStore, &dyn Any),
+    pub(crate) entities: fn(&dyn Any) -> Vec<EntityId>,
+    pub(crate) diff: PoolDiff,
+    pub(crate) apply: fn(&mut EntityStore, &dyn Any),
+    // Single components, for the journal
+    pub(crate) clone_one: fn(&dyn Any) -> Option<Box<dyn Any + Send + Sync>>,
+    pub(crate) put: PutOne,
+}
+
+fn copy<T: Component + Eq + Clone + 'static>(store: &EntityStore) -> Option<Box<dyn Any>> {
+    let pool = store.get::<T>()?;
+    let pool: Pool<T> = pool.borrow().clone();
+    Some(Box::new(pool))
+}
+
+fn restore<T: Component + Eq + Clone + 'static>(store: &mut EntityStore, saved: &dyn Any) {
+    let Some(saved) = saved.downcast_ref::<Pool<T>>() else {
+        return;
+    };
+    if store.get::<T>().is_none() {
+        store.new_component::<T>();
+    }
+    let stale: Vec<EntityId> = store
+        .get::<T>()
+        .unwrap()
+        .borrow()
+        .entities()
+        .into_iter()
+        .copied()
+        .filter(|&entity_id| !saved.has_component(entity_id))
+        .collect();
+    for entity_id in stale {
+        store.remove_component::<T>(entity_id);
+    }
+    for (&entity_id, component) in saved.components_iter() {
+        let current = store.get::<T>().unwrap().borrow().get(entity_id).cloned();
+        if current.as_ref() != Some(component) {
+            store.add_component(entity_id, component.clone());
+        }
+    }
+}
+
+pub struct WorldSnapshot {
+    pub(crate) max_entity: EntityId,
+    // The store's next free id, see EntityStore::next_entity
+    pub(crate) next_entity: EntityId,
+    pub(crate) pools: HashMap<TypeId, Box<dyn Any>>,
+    tiles: HashMap<Tile, EntityId>,
+    // How to handle each saved pool without knowing its type
+    pub(crate) copiers: HashMap<TypeId, PoolCopier>,
+}
+
+impl WorldSnapshot {
+    pub fn max_entity(&self) -> EntityId {
+        self.max_entity
+    }
+
+    // The saved pool of T, None if T wasn't marked or had no pool when the snapshot was taken
+    pub fn pool<T: Component + Eq + 'static>(&self) -> Option<&Pool<T>> {
+        self.pools.get(&TypeId::of::<T>())?.downcast_ref()
+    }
+}
+
+impl fmt::Debug for WorldSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WorldSnapshot")
+            .field("max_entity", &self.max_entity)
+            .field("pools", &self.pools.len())
+            .finish()
+    }
+}
+
+impl EntityStore {
+    // Includes T's pool in snapshots from now on
+    pub fn snapshot_component<T: Component + Eq + Clone + 'static>(&mut self) {
+        self.copiers.insert(
+            TypeId::of::<T>(),
+            PoolCopier {
+                copy: copy::<T>,
+                restore: restore::<T>,
+                entities: diff::entities::<T>,
+                diff: diff::diff::<T>,
+                apply: diff::apply::<T>,
+                clone_one: journal::clone_one::<T>,
+                put: journal::put::<T>,
+            },
+        );
+    }
+
+    pub fn snapshot(&self) -> WorldSnapshot {
+        WorldSnapshot {
+            max_entity: self.max_entity(),
+            next_entity: self.allocated(),
+            pools: self
+                .copiers
+                .iter()
+                .filter_map(|(&type_id, copier)| Some((type_id, (copier.copy)(self)?)))
+                .collect(),
+            tiles: self.tiles.clone(),
+            copiers: self.copiers.clone(),
+        }
+    }
+
+    // Puts every marked pool back as it was in the snapshot, marking the snapshot's types
+    // A marked pool the snapshot has no copy of is emptied, it didn't exist back then
+    pub fn restore(&mut self, snapshot: &WorldSnapshot) {
+        self.copiers
+            .extend(snapshot.copiers.iter().map(|(&k, &v)| (k, v)));
+        let copiers: Vec<(TypeId, PoolCopier)> =
+            self.copiers.iter().map(|(&k, &v)| (k, v)).collect();
+        let requirements = std::mem::take(&mut self.requirements);
+        for (type_id, copier) in copiers {
+            match snapshot.pools.get(&type_id) {
+                Some(saved) => (copier.restore)(self, saved.as_ref()),
+                None => {
+                    if let Some(empty) = self.empty_pool(type_id) {
+                        (copier.restore)(self, empty.as_ref());
+                    }
+                }
+            }
+        }
+        self.requirements = requirements;
+        self.tiles = snapshot.tiles.clone();
+        self.set_next_entity(snapshot.next_entity);
+    }
+
+    // An empty pool of the given marked type, so restore can clear a pool it has no copy of
+    fn empty_pool(&self, type_id: TypeId) -> Option<Box<dyn Any>> {
+        let pool_ref = self
+            .pool_refs
+            .0
+            .iter()
+            .find(|pool| pool.borrow().component_type() == type_id)?;
+        Some(pool_ref.borrow().empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hierarchy::Parent;
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Health(i64);
+
+    impl Component for Health {}
+
+    #[test]
+    fn restore_rolls_back_changes() {
+        let mut store = EntityStore::new();
+        store.new_component::<Health>();
+        store.snapshot_component::<Health>();
+        store.add_component(1, Health(10));
+        store.add_component(2, Health(20));
+        store.place_tile(1, Tile::new(0, 0));
+        store.set_parent(2, 1).unwrap();
+        let snapshot = store.snapshot();
+
+        let removed = std::sync::Arc::new(crate::cell::AtomicRefCell::new(Vec::new()));
+        let seen = removed.clone();
+        store.on_remove::<Health>(move |entity_id, _| seen.borrow_mut().push(entity_id));
+        store.add_component(1, Health(5));
+        store.add_component(3, Health(30));
+        store.reserve_up_to(3);
+        store.place_tile(3, Tile::new(1, 0));
+        store.remove_parent(2);
+
+        store.restore(&snapshot);
+        let health = store.get::<Health>().unwrap();
+        assert_eq!(health.borrow().get(1), Some(&Health(10)));
+        assert!(!health.borrow().has_component(3));
+        assert_eq!(*removed.borrow(), vec![3]);
+        assert_eq!(store.max_entity(), 2);
+        assert_eq!(store.tile_at(Tile::new(1, 0)), None);
+        assert!(store.neighbours(1).is_empty());
+        assert_eq!(store.children(1), vec![2]);
+        assert_eq!(store.parent(2), Some(1));
+        assert_eq!(snapshot.pool::<Parent>().unwrap().len(), 1);
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Armour(i64);
+    impl Component for Armour {}
+
+    #[test]
+    fn restore_puts_back_components_with_requirements() {
+        let mut store = EntityStore::new();
+        store.new_component::<Health>();
+        store.new_component::<Armour>();
+        store.snapshot_component::<Health>();
+        store.snapshot_component::<Armour>();
+        store.require::<Armour, Health>();
+        store.add_component(1, Health(10));
+        store.add_component(1, Armour(3));
+        let snapshot = store.snapshot();
+
+        store.remove_entity(1);
+        store.restore(&snapshot);
+        assert!(store.has_component::<Health>(1));
+        assert_eq!(
+            store.get::<Armour>().unwrap().borrow().get(1),
+            Some(&Armour(3))
+        );
+    }
+}
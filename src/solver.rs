@@ -0,0 +1,371 @@
+// Backtracking search over posted constraints
+//
+// Propagation alone only narrows domains, see constraint.rs. RuleEngine::search goes on
+// to pick one value for every entity with a Domain so every posted constraint holds, by
+// choosing a variable, trying its values in turn and propagating after each choice,
+// backing up when a domain empties. Which variable is chosen next and in what order its
+// values are tried are set by Search, and change how fast a solution is found and which
+// one, not whether one is.
+//
+// Rules can also post costs, `then minimize hours * x`, read like constraints. Once any
+// are posted the search looks for the solution with the lowest total cost instead of
+// the first, by branch and bound: it keeps the cheapest solution so far, and backs up
+// from any choice where the cheapest each cost could still come to adds up to no less.
+// Costs are integers, and a cost that isn't one for some choice of values rules that
+// choice out. With a limit, the cheapest solution found within it is returned.
+//
+// assign writes the solution found back as single valued domains, so rules matching on
+// fixed domains can act on it, for scheduling or placement say.
+use crate::constraint::{self, Constraint, Domain, Domains, Scope};
+use crate::engine::RuleEngine;
+use crate::rule::Expr;
+use crate::store::{EntityId, EntityStore};
+use crate::value::{Bindings, Value};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, VecDeque};
+
+// Which variable to choose a value for next
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VariableOrder {
+    // Lowest entity first
+    InOrder,
+    // Fewest values left first, failing early on the hardest variables
+    #[default]
+    SmallestDomain,
+    // In the most constraints with other unfixed variables first, then fewest values
+    MostConstrained,
+}
+
+// Which of a variable's values to try first
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ValueOrder {
+    #[default]
+    Ascending,
+    Descending,
+    // The value leaving the other variables the most values after propagating first
+    LeastConstraining,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Search {
+    pub variables: VariableOrder,
+    pub values: ValueOrder,
+    // Gives up after this many choices, None to search until done
+    pub limit: Option<usize>,
+}
+
+// A value for every entity with a Domain
+pub type Assignment = BTreeMap<EntityId, i64>;
+
+struct Backtrack<'a> {
+    engine: &'a RuleEngine,
+    scopes: &'a [Scope<'a>],
+    costs: Vec<(&'a Constraint, Scope<'a>)>,
+    search: Search,
+    choices: usize,
+    // The cheapest solution so far and its cost
+    best: Option<(i64, Domains)>,
+}
+
+impl Backtrack<'_> {
+    // The constraints over the entity
+    fn over(&self, entity_id: EntityId) -> VecDeque<usize> {
+        (0..self.scopes.len())
+            .filter(|&index| {
+                self.scopes[index]
+                    .iter()
+                    .any(|(over, _)| *over == entity_id)
+            })
+            .collect()
+    }
+
+    fn choose(&self, domains: &Domains) -> Option<EntityId> {
+        let mut unfixed: Vec<EntityId> = domains
+            .iter()
+            .filter(|(_, values)| values.len() > 1)
+            .map(|(&entity_id, _)| entity_id)
+            .collect();
+        unfixed.sort();
+        let size = |entity_id: &EntityId| domains[entity_id].len();
+        match self.search.variables {
+            VariableOrder::InOrder => unfixed.first().copied(),
+            VariableOrder::SmallestDomain => unfixed.into_iter().min_by_key(size),
+            VariableOrder::MostConstrained => unfixed.iter().copied().min_by_key(|entity_id| {
+                let degree = self
+                    .scopes
+                    .iter()
+                    .filter(|scope| {
+                        scope.iter().any(|(over, _)| over == entity_id)
+                            && scope
+                                .iter()
+                                .any(|(other, _)| other != entity_id && domains[other].len() > 1)
+                    })
+                    .count();
+                (Reverse(degree), size(entity_id))
+            }),
+        }
+    }
+
+    // The domains with the entity fixed to the value and propagated, None if one empties
+    fn fix(&self, domains: &Domains, entity_id: EntityId, value: i64) -> Option<Domains> {
+        let mut domains = domains.clone();
+        domains.insert(entity_id, [value].into());
+        let (_, consistent) = self
+            .engine
+            .prune(&mut domains, self.scopes, self.over(entity_id));
+        consistent.then_some(domains)
+    }
+
+    // The least the costs can add up to with values from the domains, None if some
+    // cost can't be worked out for any of them
+    fn bound(&self, domains: &Domains) -> Option<i64> {
+        self.costs
+            .iter()
+            .map(|(cost, scope)| lowest(cost, scope, domains, &mut cost.bindings.clone(), 0))
+            .sum()
+    }
+
+    // Searches below the choices made so far, true once the search should stop
+    fn solve(&mut self, domains: Domains) -> bool {
+        let optimizing = !self.costs.is_empty();
+        let bound = match optimizing {
+            true => match self.bound(&domains) {
+                Some(bound) => bound,
+                None => return false,
+            },
+            false => 0,
+        };
+        if self.best.as_ref().is_some_and(|(best, _)| bound >= *best) {
+            return false;
+        }
+        let Some(entity_id) = self.choose(&domains) else {
+            // Every domain is down to one value, so the bound is the cost
+            self.best = Some((bound, domains));
+            return !optimizing;
+        };
+        let mut tries: Vec<(i64, Option<Domains>)> = domains[&entity_id]
+            .iter()
+            .map(|&value| (value, None))
+            .collect();
+        match self.search.values {
+            ValueOrder::Ascending => {}
+            ValueOrder::Descending => tries.reverse(),
+            ValueOrder::LeastConstraining => {
+                for (value, fixed) in &mut tries {
+                    *fixed = self.fix(&domains, entity_id, *value);
+                }
+                // Values that empty a domain go last, and are skipped below
+                tries.sort_by_key(|(_, fixed)| {
+                    let left = fixed
+                        .as_ref()
+                        .map(|fixed| fixed.values().map(|values| values.len()).sum::<usize>());
+                    Reverse(left)
+                });
+            }
+        }
+        for (value, fixed) in tries {
+            if self.search.limit.is_some_and(|limit| self.choices >= limit) {
+                return true;
+            }
+            self.choices += 1;
+            let fixed = match (fixed, self.search.values) {
+                (Some(fixed), _) => Some(fixed),
+                (None, ValueOrder::LeastConstraining) => None,
+                (None, _) => self.fix(&domains, entity_id, value),
+            };
+            if fixed.is_some_and(|fixed| self.solve(fixed)) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+// The lowest the cost comes to over the values left for the variables from the next on
+fn lowest(
+    cost: &Constraint,
+    scope: &Scope,
+    domains: &Domains,
+    bindings: &mut Bindings,
+    next: usize,
+) -> Option<i64> {
+    let Some((entity_id, names)) = scope.get(next) else {
+        return cost.expr.eval(bindings)?.as_int();
+    };
+    domains[entity_id]
+        .iter()
+        .filter_map(|&value| {
+            for name in names {
+                bindings.insert(name.to_string(), Value::Int(value));
+            }
+            lowest(cost, scope, domains, bindings, next + 1)
+        })
+        .min()
+}
+
+impl RuleEngine {
+    // Adds a cost over the domains of the entities in bindings to minimize, see above
+    pub fn post_cost(&mut self, expr: Expr, bindings: Bindings) {
+        let cost = Constraint { expr, bindings };
+        if !self.costs.contains(&cost) {
+            self.costs.push(cost);
+        }
+    }
+
+    pub fn costs(&self) -> &[Constraint] {
+        &self.costs
+    }
+
+    // A value for every domain satisfying every posted constraint, see above
+    // None if there's none, or none was found within the search's limit
+    pub fn search(&self, store: &EntityStore, search: &Search) -> Option<Assignment> {
+        Some(self.optimize(store, search)?.0)
+    }
+
+    // As search, with the total of the posted costs, the lowest possible unless limited
+    pub fn optimize(&self, store: &EntityStore, search: &Search) -> Option<(Assignment, i64)> {
+        let mut domains = constraint::domains(store).unwrap_or_default();
+        let scopes = self.scopes(&domains);
+        let all = (0..self.constraints.len()).collect();
+        let (_, consistent) = self.prune(&mut domains, &scopes, all);
+        if !consistent {
+            return None;
+        }
+        let costs = self
+            .costs
+            .iter()
+            .map(|cost| (cost, cost.variables(&domains)))
+            .collect();
+        let mut backtrack = Backtrack {
+            engine: self,
+            scopes: &scopes,
+            costs,
+            search: *search,
+            choices: 0,
+            best: None,
+        };
+        backtrack.solve(domains);
+        let (cost, solved) = backtrack.best?;
+        let assignment = solved
+            .into_iter()
+            .filter_map(|(entity_id, values)| Some((entity_id, *values.first()?)))
+            .collect();
+        Some((assignment, cost))
+    }
+
+    // Searches and fixes every domain to the value found, false if nothing was found
+    // With costs posted that's the cheapest solution
+    pub fn assign(&self, store: &mut EntityStore, search: &Search) -> bool {
+        let Some(assignment) = self.search(store, search) else {
+            return false;
+        };
+        for (entity_id, value) in assignment {
+            store.add_component(entity_id, Domain::range(value, value));
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rule::BinaryOp;
+
+    #[test]
+    fn search_finds_complete_assignments() {
+        // Four queens, one entity per column holding the row of its queen
+        let mut engine = RuleEngine::new();
+        let mut store = EntityStore::new();
+        store.new_component::<Domain>();
+        for column in 0..4 {
+            store.add_component(column, Domain::range(0, 3));
+        }
+        let var = |name: &str| Expr::Var(name.to_string());
+        let distance = |a, b| Expr::binary(var(a), BinaryOp::Sub, var(b));
+        for a in 0..4 {
+            for b in a + 1..4 {
+                let apart = Expr::Const(Value::Int((b - a) as i64));
+                let differ = Expr::binary(var("a"), BinaryOp::Ne, var("b"));
+                let up = Expr::binary(distance("a", "b"), BinaryOp::Ne, apart.clone());
+                let down = Expr::binary(distance("b", "a"), BinaryOp::Ne, apart);
+                let safe =
+                    Expr::binary(differ, BinaryOp::And, Expr::binary(up, BinaryOp::And, down));
+                let bindings = Bindings::from([
+                    ("a".to_string(), Value::Entity(a)),
+                    ("b".to_string(), Value::Entity(b)),
+                ]);
+                engine.post(safe, bindings);
+            }
+        }
+
+        let orders = [
+            (VariableOrder::InOrder, ValueOrder::Ascending),
+            (VariableOrder::SmallestDomain, ValueOrder::Descending),
+            (
+                VariableOrder::MostConstrained,
+                ValueOrder::LeastConstraining,
+            ),
+        ];
+        for (variables, values) in orders {
+            let search = Search {
+                variables,
+                values,
+                limit: None,
+            };
+            let rows: Vec<i64> = engine
+                .search(&store, &search)
+                .unwrap()
+                .into_values()
+                .collect();
+            assert!(rows == [1, 3, 0, 2] || rows == [2, 0, 3, 1]);
+        }
+
+        let first = Search::default();
+        assert!(engine.assign(&mut store, &first));
+        let placed = store.get::<Domain>().unwrap().borrow().get(0).cloned();
+        assert_eq!(placed.unwrap().value(), Some(1));
+
+        // Neither solution has the last queen in the top row
+        store.add_component(3, Domain::range(0, 0));
+        for column in 0..3 {
+            store.add_component(column, Domain::range(1, 3));
+        }
+        assert_eq!(engine.search(&store, &first), None);
+    }
+
+    #[test]
+    fn costs_pick_the_cheapest_solution() {
+        // Three tasks on three machines, one each, costing hours times the machine number
+        let mut engine = RuleEngine::new();
+        engine.register::<Domain>("Domain");
+        engine
+            .load_str(
+                r#"
+                fact Task(hours)
+                rule "apart" when Task(a, _), Task(b, _), a < b then post a != b
+                rule "cost" when Task(t, hours) then minimize hours * t
+                "#,
+            )
+            .unwrap();
+        let registry = engine.registry().clone();
+        let mut store = EntityStore::new();
+        store.new_component::<Domain>();
+        for (task, hours) in [(1, 5), (2, 1), (3, 3)] {
+            store.add_component(task, Domain::range(0, 2));
+            store
+                .insert_fact(&registry, "Task", task, &[Value::Int(hours)])
+                .unwrap();
+        }
+        engine.run(&mut store);
+        assert_eq!(engine.costs().len(), 3);
+
+        let (assignment, cost) = engine.optimize(&store, &Search::default()).unwrap();
+        assert_eq!(assignment, Assignment::from([(1, 0), (2, 2), (3, 1)]));
+        assert_eq!(cost, 5);
+        let descending = Search {
+            values: ValueOrder::Descending,
+            ..Search::default()
+        };
+        assert_eq!(engine.optimize(&store, &descending).unwrap().1, 5);
+    }
+}
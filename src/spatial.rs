@@ -0,0 +1,503 @@
+// Spatial indexes over the Position component
+//
+// An index is handed to EntityStore::index_positions, which fills it from the Position
+// pool and keeps it following every Position added, replaced or removed through the
+// store afterwards. Positions moved in place through the pool are caught up on by
+// refresh_indexes, see index.rs.
+//
+// Handing the same index to RuleEngine::use_spatial_index lets rules ask about it with
+// `near(a, b, radius)`, `within(a, "region")` and `adjacent(a, b)`. Each looks up the
+// entities around one it already has rather than comparing every pair. Regions are
+// named rectangles defined on the engine.
+//
+// SpatialGrid here suits evenly spread worlds, QuadTree in quadtree.rs clustered ones.
+use crate::cell::AtomicRefCell;
+use crate::engine::RuleEngine;
+use crate::registry::{Fact, TypedFact};
+use crate::rule::{Spatial, Term};
+use crate::store::{Component, EntityId, EntityStore};
+use crate::value::{Bindings, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+// Where an entity is, in whole world units
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Position {
+    pub x: i64,
+    pub y: i64,
+}
+
+impl Position {
+    pub fn new(x: i64, y: i64) -> Self {
+        Position { x, y }
+    }
+
+    // Squared euclidean distance, exact for integer positions
+    pub fn distance_squared(&self, other: &Position) -> i64 {
+        let (dx, dy) = (self.x - other.x, self.y - other.y);
+        dx * dx + dy * dy
+    }
+}
+
+impl Component for Position {}
+
+impl Fact for Position {
+    const FIELDS: &'static [&'static str] = &["x", "y"];
+
+    fn to_values(&self) -> Vec<Value> {
+        vec![Value::Int(self.x), Value::Int(self.y)]
+    }
+
+    fn from_values(values: &[Value]) -> Option<Self> {
+        match values {
+            [x, y] => Some(Position::new(x.as_int()?, y.as_int()?)),
+            _ => None,
+        }
+    }
+}
+
+impl TypedFact for Position {
+    type Fields = (i64, i64);
+
+    fn fields(&self) -> Self::Fields {
+        (self.x, self.y)
+    }
+
+    fn from_fields((x, y): Self::Fields) -> Self {
+        Position::new(x, y)
+    }
+}
+
+// An axis aligned rectangle, both corners included
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Rect {
+    pub min: Position,
+    pub max: Position,
+}
+
+impl Rect {
+    pub fn new(min: Position, max: Position) -> Self {
+        Rect { min, max }
+    }
+
+    // The square of positions no further than radius from center along either axis
+    pub fn around(center: Position, radius: i64) -> Self {
+        Rect::new(
+            Position::new(center.x - radius, center.y - radius),
+            Position::new(center.x + radius, center.y + radius),
+        )
+    }
+
+    // The rectangle covering just one position
+    pub fn point(position: Position) -> Self {
+        Rect::new(position, position)
+    }
+
+    pub fn contains(&self, position: &Position) -> bool {
+        (self.min.x..=self.max.x).contains(&position.x)
+            && (self.min.y..=self.max.y).contains(&position.y)
+    }
+
+    pub fn contains_rect(&self, other: &Rect) -> bool {
+        self.contains(&other.min) && self.contains(&other.max)
+    }
+
+    pub fn intersects(&self, other: &Rect) -> bool {
+        self.min.x <= other.max.x
+            && other.min.x <= self.max.x
+            && self.min.y <= other.max.y
+            && other.min.y <= self.max.y
+    }
+
+    // The rectangle grown by amount on every side
+    pub fn expand(&self, amount: i64) -> Self {
+        Rect::new(
+            Position::new(self.min.x - amount, self.min.y - amount),
+            Position::new(self.max.x + amount, self.max.y + amount),
+        )
+    }
+
+    // Steps between the two rectangles along each axis, zero where they overlap
+    pub fn gap(&self, other: &Rect) -> (i64, i64) {
+        (
+            (other.min.x - self.max.x)
+                .max(self.min.x - other.max.x)
+                .max(0),
+            (other.min.y - self.max.y)
+                .max(self.min.y - other.max.y)
+                .max(0),
+        )
+    }
+
+    // Squared distance from the position to the nearest point of the rectangle
+    pub fn distance_squared(&self, position: &Position) -> i64 {
+        let nearest = Position::new(
+            position.x.clamp(self.min.x, self.max.x),
+            position.y.clamp(self.min.y, self.max.y),
+        );
+        nearest.distance_squared(position)
+    }
+}
+
+// What every spatial index answers, whatever its layout
+pub trait SpatialIndex: fmt::Debug + Send + Sync {
+    // Adds the entity at a position, moving it if it is already indexed
+    fn insert(&mut self, entity_id: EntityId, position: Position);
+    fn remove(&mut self, entity_id: EntityId);
+    // The area the entity is indexed as covering, a single position for point indexes
+    fn bounds(&self, entity_id: EntityId) -> Option<Rect>;
+
+    // Entities overlapping the rectangle, in no particular order
+    fn in_rect(&self, rect: Rect) -> Vec<EntityId>;
+
+    // Entities no further than radius from center, in no particular order
+    fn near(&self, center: Position, radius: i64) -> Vec<EntityId> {
+        let mut found = self.in_rect(Rect::around(center, radius));
+        found.retain(|&entity_id| {
+            self.bounds(entity_id)
+                .is_some_and(|bounds| bounds.distance_squared(&center) <= radius * radius)
+        });
+        found
+    }
+}
+
+// Buckets entities into square cells of a fixed size
+// Cheap to update, and good when entities are spread fairly evenly
+#[derive(Debug, Clone)]
+pub struct SpatialGrid {
+    cell_size: i64,
+    cells: HashMap<(i64, i64), Vec<EntityId>>,
+    positions: HashMap<EntityId, Position>,
+}
+
+impl SpatialGrid {
+    pub fn new(cell_size: i64) -> Self {
+        assert!(cell_size > 0, "grid cells must have a positive size");
+        SpatialGrid {
+            cell_size,
+            cells: HashMap::new(),
+            positions: HashMap::new(),
+        }
+    }
+
+    pub fn position(&self, entity_id: EntityId) -> Option<Position> {
+        self.positions.get(&entity_id).copied()
+    }
+
+    pub fn cell_size(&self) -> i64 {
+        self.cell_size
+    }
+
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    // The cell a position falls in, cells below zero included
+    pub fn cell_of(&self, position: Position) -> (i64, i64) {
+        (
+            position.x.div_euclid(self.cell_size),
+            position.y.div_euclid(self.cell_size),
+        )
+    }
+
+    // Entities in one cell
+    pub fn cell(&self, cell: (i64, i64)) -> &[EntityId] {
+        self.cells.get(&cell).map_or(&[], Vec::as_slice)
+    }
+
+    // Entities in the cell around position and the `rings` of cells surrounding it
+    pub fn neighborhood(&self, position: Position, rings: i64) -> Vec<EntityId> {
+        let (cx, cy) = self.cell_of(position);
+        let mut found = Vec::new();
+        for x in cx - rings..=cx + rings {
+            for y in cy - rings..=cy + rings {
+                found.extend_from_slice(self.cell((x, y)));
+            }
+        }
+        found
+    }
+}
+
+impl SpatialIndex for SpatialGrid {
+    fn insert(&mut self, entity_id: EntityId, position: Position) {
+        self.remove(entity_id);
+        let cell = self.cell_of(position);
+        self.cells.entry(cell).or_default().push(entity_id);
+        self.positions.insert(entity_id, position);
+    }
+
+    fn remove(&mut self, entity_id: EntityId) {
+        let Some(position) = self.positions.remove(&entity_id) else {
+            return;
+        };
+        let cell = self.cell_of(position);
+        if let Some(entities) = self.cells.get_mut(&cell) {
+            entities.retain(|&other| other != entity_id);
+            if entities.is_empty() {
+                self.cells.remove(&cell);
+            }
+        }
+    }
+
+    fn bounds(&self, entity_id: EntityId) -> Option<Rect> {
+        self.positions.get(&entity_id).copied().map(Rect::point)
+    }
+
+    fn in_rect(&self, rect: Rect) -> Vec<EntityId> {
+        let (min, max) = (self.cell_of(rect.min), self.cell_of(rect.max));
+        let mut found = Vec::new();
+        for x in min.0..=max.0 {
+            for y in min.1..=max.1 {
+                found.extend(self.cell((x, y)).iter().filter(|&&entity_id| {
+                    self.positions
+                        .get(&entity_id)
+                        .is_some_and(|position| rect.contains(position))
+                }));
+            }
+        }
+        found
+    }
+}
+
+impl EntityStore {
+    // Fills the index from the Position pool and keeps it in sync from then on
+    // The returned handle is shared with the store's hooks
+    pub fn index_positions<I: SpatialIndex + 'static>(
+        &mut self,
+        index: I,
+    ) -> Arc<AtomicRefCell<I>> {
+        let index = Arc::new(AtomicRefCell::new(index));
+        self.follow(
+            &index,
+            |index: &mut I, entity_id, position: Option<&Position>| match position {
+                Some(&position) => index.insert(entity_id, position),
+                None => index.remove(entity_id),
+            },
+        );
+        index
+    }
+}
+
+// The entity a term stands for, if it is bound
+pub(crate) fn entity_of(term: &Term, bindings: &Bindings) -> Option<EntityId> {
+    match term {
+        Term::Var(name) => bindings.get(name)?.as_entity(),
+        Term::Const(value) => value.as_entity(),
+        Term::Wildcard => None,
+    }
+}
+
+// Binds term to the entity, or checks it already stands for it
+pub(crate) fn bind(term: &Term, entity_id: EntityId, bindings: &Bindings) -> Option<Bindings> {
+    match term {
+        Term::Var(name) if !bindings.contains_key(name) => {
+            let mut bindings = bindings.clone();
+            bindings.insert(name.clone(), Value::Entity(entity_id));
+            Some(bindings)
+        }
+        Term::Wildcard => Some(bindings.clone()),
+        _ => (entity_of(term, bindings)? == entity_id).then(|| bindings.clone()),
+    }
+}
+
+impl RuleEngine {
+    // Answer spatial predicates in rules from this index
+    // Usually the handle returned by EntityStore::index_positions
+    pub fn use_spatial_index(&mut self, index: Arc<AtomicRefCell<dyn SpatialIndex>>) {
+        self.spatial = Some(index);
+    }
+
+    // Names a rectangle for `within` to test against, replacing any of the same name
+    pub fn define_region(&mut self, name: &str, region: Rect) {
+        self.regions.insert(name.to_string(), region);
+    }
+
+    pub fn region(&self, name: &str) -> Option<Rect> {
+        self.regions.get(name).copied()
+    }
+
+    // Every extension of bindings satisfying the predicate
+    // Without a spatial index nothing but rcc8 satisfies one
+    pub(crate) fn spatial_matches(
+        &self,
+        predicate: &Spatial,
+        bindings: &Bindings,
+        store: &EntityStore,
+    ) -> Vec<Bindings> {
+        if let Spatial::Rcc8(a, b, relations) = predicate {
+            return match relations.eval(bindings) {
+                Some(relations) => self.rcc8_matches(a, b, &relations, bindings, store),
+                None => Vec::new(),
+            };
+        }
+        let Some(index) = &self.spatial else {
+            return Vec::new();
+        };
+        let index = index.borrow();
+        match predicate {
+            Spatial::Near(a, b, radius) => {
+                let Some(radius) = radius.eval(bindings).and_then(|radius| radius.as_int()) else {
+                    return Vec::new();
+                };
+                pairs(&*index, a, b, bindings, radius, |(x, y)| {
+                    x * x + y * y <= radius * radius
+                })
+            }
+            Spatial::Rcc8(..) => unreachable!("answered without the index"),
+            Spatial::Adjacent(a, b) => pairs(&*index, a, b, bindings, 1, |(x, y)| x.max(y) == 1),
+            Spatial::Within(a, region) => {
+                let region = region.eval(bindings);
+                let Some(region) = region
+                    .as_ref()
+                    .and_then(Value::as_str)
+                    .and_then(|name| self.region(name))
+                else {
+                    return Vec::new();
+                };
+                let inside = |entity_id: &EntityId| {
+                    index
+                        .bounds(*entity_id)
+                        .is_some_and(|bounds| region.contains_rect(&bounds))
+                };
+                match entity_of(a, bindings) {
+                    Some(entity_id) if inside(&entity_id) => vec![bindings.clone()],
+                    Some(_) => Vec::new(),
+                    None => index
+                        .in_rect(region)
+                        .into_iter()
+                        .filter(inside)
+                        .filter_map(|entity_id| bind(a, entity_id, bindings))
+                        .collect(),
+                }
+            }
+        }
+    }
+}
+
+// Pairs of distinct entities no more than reach apart whose gap passes the test
+// Searches around whichever of the two is bound, both predicates being symmetric
+fn pairs(
+    index: &dyn SpatialIndex,
+    a: &Term,
+    b: &Term,
+    bindings: &Bindings,
+    reach: i64,
+    test: impl Fn((i64, i64)) -> bool,
+) -> Vec<Bindings> {
+    let (from, other) = match (entity_of(a, bindings), entity_of(b, bindings)) {
+        (Some(from), _) => (from, b),
+        (None, Some(from)) => (from, a),
+        (None, None) => return Vec::new(),
+    };
+    let Some(bounds) = index.bounds(from) else {
+        return Vec::new();
+    };
+    index
+        .in_rect(bounds.expand(reach))
+        .into_iter()
+        .filter(|&entity_id| entity_id != from)
+        .filter(|&entity_id| {
+            index
+                .bounds(entity_id)
+                .is_some_and(|other| test(bounds.gap(&other)))
+        })
+        .filter_map(|entity_id| bind(other, entity_id, bindings))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::rule::Condition;
+
+    #[test]
+    fn grid_follows_the_position_pool() {
+        let mut store = EntityStore::new();
+        store.new_component::<Position>();
+        store.add_component(1, Position::new(0, 0));
+        let grid = store.index_positions(SpatialGrid::new(10));
+
+        store.add_component(2, Position::new(3, 4));
+        store.add_component(3, Position::new(-5, 0));
+        store.add_component(4, Position::new(25, 25));
+        assert_eq!(grid.borrow().cell_of(Position::new(-5, 0)), (-1, 0));
+        assert_eq!(grid.borrow().cell((0, 0)), &[1, 2]);
+
+        let mut near = grid.borrow().near(Position::new(0, 0), 5);
+        near.sort();
+        assert_eq!(near, vec![1, 2, 3]);
+
+        store.add_component(2, Position::new(21, 21));
+        store.remove_entity(3);
+        let mut around = grid.borrow().neighborhood(Position::new(25, 25), 0);
+        around.sort();
+        assert_eq!(around, vec![2, 4]);
+        assert_eq!(grid.borrow().near(Position::new(0, 0), 5), vec![1]);
+        assert_eq!(grid.borrow().len(), 3);
+    }
+
+    #[test]
+    fn spatial_predicates_use_the_index() {
+        let mut store = EntityStore::new();
+        store.new_component::<Position>();
+        let grid = store.index_positions(SpatialGrid::new(4));
+        store.add_component(1, Position::new(0, 0));
+        store.add_component(2, Position::new(1, 1));
+        store.add_component(3, Position::new(3, 4));
+        store.add_component(4, Position::new(20, 20));
+
+        let mut engine = RuleEngine::new();
+        engine.register::<Position>("Position");
+        engine.use_spatial_index(grid);
+        engine.define_region(
+            "camp",
+            Rect::new(Position::new(-1, -1), Position::new(2, 2)),
+        );
+
+        let pairs = |source: &str| {
+            let mut found: Vec<(EntityId, EntityId)> = engine
+                .query(source, &store)
+                .unwrap()
+                .iter()
+                .map(|bindings| {
+                    (
+                        bindings["a"].as_entity().unwrap(),
+                        bindings["b"].as_entity().unwrap(),
+                    )
+                })
+                .collect();
+            found.sort();
+            found
+        };
+        assert_eq!(
+            pairs("Position(a, 0, 0), near(a, b, 5)"),
+            vec![(1, 2), (1, 3)]
+        );
+        assert_eq!(pairs("Position(b, 1, 1), adjacent(a, b)"), vec![(1, 2)]);
+        assert_eq!(
+            pairs(r#"within(a, "camp"), within(b, "camp"), a != b"#),
+            vec![(1, 2), (2, 1)]
+        );
+        assert!(matches!(
+            engine.query("near(a, b, 5)", &store),
+            Err(Error::UnboundVariable { .. })
+        ));
+
+        let rule = crate::rule!(
+            "regroup" when Position(a, _, _), within(a, "camp"), adjacent(a, b) then remove Position(b)
+        );
+        assert!(matches!(
+            rule.conditions[2],
+            Condition::Spatial(Spatial::Adjacent(..))
+        ));
+        engine.add_rule(rule).unwrap();
+        engine.run(&mut store);
+        assert!(store.has_component::<Position>(1) && !store.has_component::<Position>(2));
+    }
+}
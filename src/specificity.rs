@@ -0,0 +1,100 @@
+// Ordering rules by specificity
+//
+// Instead of setting salience by hand, the engine can fire the more specific of two
+// ready rules first, so a rule written for a narrower case gets its say before the
+// general one. Salience still comes first, and rules of equal salience and specificity
+// keep the order they were added in.
+//
+// A rule's specificity counts its conditions and how far each narrows what matches:
+// every pattern, `not`, test and spatial predicate counts one, and so does every
+// constant argument, `where` clause, temporal qualifier, and variable a pattern joins on
+// that an earlier condition already bound. A reactive rule's trigger counts as a pattern.
+use crate::engine::RuleEngine;
+use crate::rule::{Condition, Pattern, Rule, Term};
+use std::collections::HashSet;
+
+impl Rule {
+    // See above
+    pub fn specificity(&self) -> usize {
+        let mut bound = HashSet::new();
+        let mut pattern = |pattern: &Pattern| {
+            let narrowing = pattern
+                .args
+                .iter()
+                .filter(|term| match term {
+                    Term::Const(_) => true,
+                    Term::Var(name) => !bound.insert(name.clone()),
+                    Term::Wildcard => false,
+                })
+                .count();
+            1 + narrowing + pattern.guard.is_some() as usize + pattern.temporal.is_some() as usize
+        };
+        let trigger = self
+            .trigger
+            .as_ref()
+            .map_or(0, |trigger| pattern(&trigger.pattern));
+        trigger
+            + self
+                .conditions
+                .iter()
+                .map(|condition| match condition {
+                    Condition::Pattern(inner) | Condition::Not(inner) => pattern(inner),
+                    Condition::Test(_) | Condition::Spatial(_) => 1,
+                })
+                .sum::<usize>()
+    }
+}
+
+impl RuleEngine {
+    // Fire rules of equal salience most specific first, see above
+    pub fn order_by_specificity(&mut self, specific: bool) {
+        if self.specific != specific {
+            self.specific = specific;
+            self.reorder();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::EntityStore;
+    use crate::value::Value;
+
+    #[test]
+    fn specific_rules_fire_first() {
+        let mut engine = RuleEngine::new();
+        engine
+            .load_str(
+                r#"
+                fact Temperature(degrees)
+                fact Smoke()
+                fact Status(what)
+                rule "hot" when Temperature(e, t), t > 50 then insert Status(e, "hot")
+                rule "fire" when Temperature(e, t), t > 50, Smoke(e) then insert Status(e, "fire")
+                rule "kitchen" salience 1 when Smoke(e) then insert Status(e, "cooking")
+                "#,
+            )
+            .unwrap();
+        let specificity: Vec<usize> = engine.rules().iter().map(Rule::specificity).collect();
+        // Smoke(e) joins on e in "fire"
+        assert_eq!(specificity, [2, 4, 1]);
+
+        let registry = engine.registry().clone();
+        let mut store = EntityStore::new();
+        store
+            .insert_fact(&registry, "Temperature", 1, &[Value::Int(80)])
+            .unwrap();
+        store.insert_fact(&registry, "Smoke", 1, &[]).unwrap();
+        let order = |engine: &RuleEngine| -> Vec<String> {
+            engine
+                .activations(&store)
+                .iter()
+                .map(|activation| engine.rules()[activation.rule].name.clone())
+                .collect()
+        };
+        assert_eq!(order(&engine), ["kitchen", "hot", "fire"]);
+        engine.order_by_specificity(true);
+        assert_eq!(order(&engine), ["kitchen", "fire", "hot"]);
+    }
+}
@@ -0,0 +1,282 @@
+// Store level statistics, emitted on the store's event bus
+//
+// Hosts that autoscale can subscribe to these to shed load, split shards or alert
+// when a world grows, instead of polling pool sizes themselves.
+//
+// stats describes what the store holds: how many components each pool has, how many
+// entities each indexed relation points at, and for indexed fields how many distinct
+// values they take and, for ordered ones, a histogram of them. It is worked out when
+// asked from counts the pools and indexes keep anyway, so there is nothing to maintain
+// and it is never stale. The join planner reads the same numbers, see plan.rs.
+use crate::store::EntityStore;
+use std::any::TypeId;
+
+// Buckets in the histograms of ordered fields
+const BUCKETS: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreEvent {
+    // A pool's packed arrays reallocated to hold more components
+    PoolGrew {
+        component: &'static str,
+        len: usize,
+        capacity: usize,
+    },
+    // A pool released memory it no longer needed, see EntityStore::shrink_to_fit
+    PoolShrank {
+        component: &'static str,
+        len: usize,
+        capacity: usize,
+    },
+    // Activations waiting to fire when RuleEngine::run starts
+    AgendaBacklog {
+        pending: usize,
+    },
+    // A rule's script or plugin action stopped with an error, see script.rs and plugin.rs
+    ActionFailed {
+        rule: String,
+        message: String,
+    },
+    // A cached query's answer was thrown away as pools it reads changed, see cache.rs
+    CacheInvalidated {
+        changed: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    pub pools: Vec<PoolStats>,
+}
+
+impl Stats {
+    pub fn pool(&self, component: &str) -> Option<&PoolStats> {
+        self.pools.iter().find(|pool| pool.component == component)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolStats {
+    pub component: &'static str,
+    pub type_id: TypeId,
+    pub len: usize,
+    // Relations with a reverse index only, how many distinct entities they point at
+    pub targets: Option<usize>,
+    // Indexed fields only, in field order
+    pub fields: Vec<FieldStats>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldStats {
+    pub position: usize,
+    // With a hash index
+    pub distinct: Option<usize>,
+    // With an ordered index
+    pub histogram: Option<Histogram>,
+}
+
+// How a numeric field's values spread between its least and greatest, in buckets of
+// equal width
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    pub min: f64,
+    pub max: f64,
+    pub buckets: Vec<usize>,
+}
+
+impl Histogram {
+    // From each value and how many entities have it, None if there are none
+    pub(crate) fn build(values: &[(f64, usize)]) -> Option<Self> {
+        let min = values.first()?.0;
+        let max = values.last()?.0;
+        let mut buckets = vec![0; BUCKETS];
+        for &(value, count) in values {
+            buckets[Self::bucket(min, max, value)] += count;
+        }
+        Some(Histogram { min, max, buckets })
+    }
+
+    fn bucket(min: f64, max: f64, value: f64) -> usize {
+        if max <= min {
+            return 0;
+        }
+        let bucket = ((value - min) / (max - min) * BUCKETS as f64) as usize;
+        bucket.min(BUCKETS - 1)
+    }
+
+    pub fn total(&self) -> usize {
+        self.buckets.iter().sum()
+    }
+
+    // Roughly what share of the values lie between the bounds, inclusive, counting
+    // every bucket the bounds touch in full
+    pub fn fraction(&self, low: Option<f64>, high: Option<f64>) -> f64 {
+        let total = self.total();
+        let (low, high) = (low.unwrap_or(self.min), high.unwrap_or(self.max));
+        if total == 0 || low > self.max || high < self.min || low > high {
+            return 0.0;
+        }
+        let first = Self::bucket(self.min, self.max, low.max(self.min));
+        let last = Self::bucket(self.min, self.max, high.min(self.max));
+        let within: usize = self.buckets[first..=last].iter().sum();
+        within as f64 / total as f64
+    }
+}
+
+impl EntityStore {
+    pub fn stats(&self) -> Stats {
+        let pools = self
+            .pool_refs
+            .0
+            .iter()
+            .map(|pool| {
+                let pool = pool.borrow();
+                let type_id = pool.component_type();
+                let mut positions: Vec<usize> = self
+                    .field_indexes
+                    .keys()
+                    .chain(self.ordered_indexes.keys())
+                    .filter(|(indexed, _)| *indexed == type_id)
+                    .map(|&(_, position)| position)
+                    .collect();
+                positions.sort();
+                positions.dedup();
+                let usage = pool.usage();
+                PoolStats {
+                    component: usage.component,
+                    type_id,
+                    len: usage.len,
+                    targets: self.reverse.get(&type_id).map(|index| index.borrow().len()),
+                    fields: positions
+                        .into_iter()
+                        .map(|position| FieldStats {
+                            position,
+                            distinct: self.distinct_values(type_id, position),
+                            histogram: self.histogram(type_id, position),
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+        Stats { pools }
+    }
+
+    pub fn subscribe(&mut self, subscriber: impl FnMut(&StoreEvent) + Send + Sync + 'static) {
+        self.events.subscribe(subscriber);
+    }
+
+    pub(crate) fn emit(&mut self, event: StoreEvent) {
+        self.events.emit(&event);
+    }
+
+    // Releases spare capacity in every pool, emitting PoolShrank for those that shrank
+    pub fn shrink_to_fit(&mut self) {
+        let shrunk: Vec<StoreEvent> = self
+            .pool_refs
+            .0
+            .iter()
+            .filter_map(|pool_ref| pool_ref.borrow_mut().shrink_to_fit())
+            .collect();
+        for event in shrunk {
+            self.emit(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cell::AtomicRefCell;
+    use crate::registry::Fact;
+    use crate::store::Component;
+    use crate::value::Value;
+    use std::sync::Arc;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Health(i64);
+    impl Component for Health {}
+
+    #[test]
+    fn pools_report_growth_and_shrinking() {
+        let mut store = EntityStore::new();
+        store.new_component::<Health>();
+        let events = Arc::new(AtomicRefCell::new(Vec::new()));
+        let sink = events.clone();
+        store.subscribe(move |event| sink.borrow_mut().push(event.clone()));
+
+        for entity in 0..5 {
+            store.add_component(entity, Health(10));
+        }
+        let grew: Vec<usize> = events
+            .borrow()
+            .iter()
+            .map(|event| match event {
+                StoreEvent::PoolGrew {
+                    component: "Health",
+                    capacity,
+                    ..
+                } => *capacity,
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        assert!(grew.len() >= 2);
+        assert!(grew.windows(2).all(|pair| pair[0] < pair[1]));
+
+        events.borrow_mut().clear();
+        for entity in 0..4 {
+            store.remove_component::<Health>(entity);
+        }
+        store.shrink_to_fit();
+        assert_eq!(
+            *events.borrow(),
+            [StoreEvent::PoolShrank {
+                component: "Health",
+                len: 1,
+                capacity: 1
+            }]
+        );
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Level(i64);
+    impl Component for Level {}
+
+    impl Fact for Level {
+        const FIELDS: &'static [&'static str] = &["level"];
+
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.0)]
+        }
+
+        fn from_values(values: &[Value]) -> Option<Self> {
+            Some(Level(values.first()?.as_int()?))
+        }
+    }
+
+    #[test]
+    fn stats_describe_pools_and_indexes() {
+        let mut store = EntityStore::new();
+        store.new_component::<Health>();
+        store.new_component::<Level>();
+        store.index_field::<Level>("level");
+        store.index_range::<Level>("level");
+        for entity in 0..100 {
+            store.add_component(entity, Level(entity as i64 % 16));
+        }
+        store.add_component(0, Health(1));
+
+        let stats = store.stats();
+        assert_eq!(stats.pool("Health").unwrap().len, 1);
+        let level = stats.pool("Level").unwrap();
+        assert_eq!((level.len, level.targets), (100, None));
+        assert_eq!(level.fields.len(), 1);
+        assert_eq!(level.fields[0].distinct, Some(16));
+        let histogram = level.fields[0].histogram.as_ref().unwrap();
+        assert_eq!(
+            (histogram.min, histogram.max, histogram.total()),
+            (0.0, 15.0, 100)
+        );
+        assert_eq!(histogram.fraction(None, None), 1.0);
+        assert!(histogram.fraction(Some(12.0), None) < 0.5);
+        assert_eq!(histogram.fraction(Some(20.0), None), 0.0);
+    }
+}
@@ -0,0 +1,1058 @@
+// Sparse Array Entity-Component Store:
+use crate::bitset::BitSet;
+use crate::bus::EventBus;
+use crate::cell::{AtomicRef, AtomicRefCell, AtomicRefMut};
+use crate::clone::Cloners;
+use crate::compact::{PoolUsage, Remap};
+use crate::error::Error;
+use crate::events::EventQueues;
+use crate::group::Group;
+use crate::hooks::HookStore;
+use crate::index::{FieldIndex, OrderedIndex, Refreshers};
+use crate::journal::Journal;
+use crate::prefab::Prefab;
+use crate::reactive::{ChangeKind, FieldReader};
+use crate::relation::{short_type_name, ReverseIndex};
+use crate::require::Required;
+use crate::singleton::Uniqueness;
+use crate::snapshot::PoolCopier;
+use crate::stats::StoreEvent;
+use crate::tiles::Tile;
+use crate::time::SharedTime;
+use crate::wal::ChangeLog;
+use anymap::Map;
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+
+pub type EntityId = usize;
+
+// The highest entity id a store takes. Pools size their sparse arrays and bitsets by id,
+// so one id from a file, a request or a C caller far past any real entity would have
+// them allocate for every id below it. Stores turn such ids away instead, reserving
+// nothing and refusing components for them, and decoders and services report them as
+// errors, see check_entity
+pub const MAX_ENTITY: EntityId = (1 << 28) - 1;
+
+// An error for ids past MAX_ENTITY, for anything taking ids from outside the program
+pub fn check_entity(entity_id: EntityId) -> Result<EntityId, Error> {
+    match entity_id <= MAX_ENTITY {
+        true => Ok(entity_id),
+        false => Err(Error::EntityRange(entity_id)),
+    }
+}
+
+// Send and Sync so the store can move to or be shared with other threads, see cell.rs
+pub trait Component: Send + Sync {}
+
+// https://gist.github.com/dakom/82551fff5d2b843cbe1601bbaff2acbf
+// http://reports-archive.adm.cs.cmu.edu/anon/1995/CMU-CS-95-113.pdf
+
+// Entries in each page of a pool's sparse array
+const PAGE_SIZE: usize = 4096;
+
+// A sparse array split into fixed size pages, each allocated the first time one of its
+// entities gets the component, so a rare component in a world of millions of entities
+// only pays for the pages its entities fall in
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Pages {
+    pages: Vec<Option<Box<[Option<EntityId>]>>>,
+    // One past the highest entity id reserved, what the length of a flat array would be
+    len: usize,
+}
+
+impl Pages {
+    fn get(&self, entity_id: EntityId) -> Option<EntityId> {
+        self.pages.get(entity_id / PAGE_SIZE)?.as_ref()?[entity_id % PAGE_SIZE]
+    }
+
+    // Allocates the entity's page if needed, unless the entry is being cleared
+    // Panics past MAX_ENTITY, which the store checks for before it gets here
+    fn set(&mut self, entity_id: EntityId, index: Option<EntityId>) {
+        if index.is_none() && self.get(entity_id).is_none() {
+            return;
+        }
+        assert!(
+            entity_id <= MAX_ENTITY,
+            "entity {} is past MAX_ENTITY",
+            entity_id
+        );
+        let page = entity_id / PAGE_SIZE;
+        if page >= self.pages.len() {
+            self.pages.resize(page + 1, None);
+        }
+        let entries =
+            self.pages[page].get_or_insert_with(|| vec![None; PAGE_SIZE].into_boxed_slice());
+        entries[entity_id % PAGE_SIZE] = index;
+        self.len = self.len.max(entity_id + 1);
+    }
+
+    fn allocated(&self) -> usize {
+        self.pages.iter().filter(|page| page.is_some()).count()
+    }
+
+    // Frees pages with no entries left
+    fn shrink_to_fit(&mut self) {
+        for page in &mut self.pages {
+            if page
+                .as_ref()
+                .is_some_and(|entries| entries.iter().all(Option::is_none))
+            {
+                *page = None;
+            }
+        }
+        while self.pages.last().is_some_and(Option::is_none) {
+            self.pages.pop();
+        }
+        self.pages.shrink_to_fit();
+    }
+
+    fn bytes(&self) -> usize {
+        self.pages.capacity() * std::mem::size_of::<Option<Box<[Option<EntityId>]>>>()
+            + self.allocated() * PAGE_SIZE * std::mem::size_of::<Option<EntityId>>()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pool<T: Component + Eq> {
+    // A paged sparse array, values are integers which index EntityList
+    // Index of elements is their EntityId
+    entity_indices: Pages,
+
+    // A packed array, contains integers which are EntityIds
+    // Index is meaningless other than that it is correct from entity_indices
+    entity_list: Vec<EntityId>,
+
+    // A packed array, contains the components
+    // Never allocates for zero-sized components, see tags.rs
+    component_list: Vec<T>,
+
+    // Which entities have the component, for joins, see bitset.rs
+    presence: BitSet,
+
+    // A packed array, the tick each component last changed at
+    // Ticks count the pool's own changes, so indexes can catch up on just the
+    // components changed since they last looked, see index.rs
+    ticks: Vec<u64>,
+
+    // The latest tick handed out
+    tick: u64,
+}
+
+pub trait PoolRef: Send + Sync {
+    fn remove(&mut self, entity_id: EntityId);
+    // Removes and returns the component, for callers that only know the pool type erased
+    fn take_any(&mut self, entity_id: EntityId) -> Option<Box<dyn Any + Send + Sync>>;
+    fn component_type(&self) -> TypeId;
+    // Releases spare capacity, describing the result if anything was released
+    fn shrink_to_fit(&mut self) -> Option<StoreEvent>;
+    // A new empty pool of the same type
+    fn empty(&self) -> Box<dyn Any>;
+    fn presence(&self) -> &BitSet;
+    fn usage(&self) -> PoolUsage;
+    // Renumbers the entities in place, keeping packed order, see compact.rs
+    fn remap(&mut self, remap: &Remap);
+    // Where the entity's component is in the packed arrays
+    fn index_of(&self, entity_id: EntityId) -> Option<usize>;
+    fn swap_packed(&mut self, a: usize, b: usize);
+    fn remove_batch(&mut self, entity_ids: &[EntityId]) -> usize;
+    fn get_any(&self, entity_id: EntityId) -> Option<&dyn Any>;
+    // Counts as a change, as get_mut does
+    fn get_any_mut(&mut self, entity_id: EntityId) -> Option<&mut dyn Any>;
+}
+
+impl<T: Component + Eq + 'static> PoolRef for Pool<T> {
+    // Remove the component from the given entity
+    fn remove(&mut self, entity_id: EntityId) {
+        self.take(entity_id);
+    }
+
+    fn take_any(&mut self, entity_id: EntityId) -> Option<Box<dyn Any + Send + Sync>> {
+        Some(Box::new(self.take(entity_id)?))
+    }
+
+    fn component_type(&self) -> TypeId {
+        TypeId::of::<T>()
+    }
+
+    fn shrink_to_fit(&mut self) -> Option<StoreEvent> {
+        let before = self.capacity();
+        Pool::shrink_to_fit(self);
+        (self.capacity() < before).then(|| StoreEvent::PoolShrank {
+            component: short_type_name::<T>(),
+            len: self.len(),
+            capacity: self.capacity(),
+        })
+    }
+
+    fn empty(&self) -> Box<dyn Any> {
+        Box::new(Pool::<T>::new())
+    }
+
+    fn presence(&self) -> &BitSet {
+        &self.presence
+    }
+
+    fn usage(&self) -> PoolUsage {
+        PoolUsage {
+            component: short_type_name::<T>(),
+            len: self.len(),
+            bytes: self.bytes(),
+        }
+    }
+
+    fn remap(&mut self, remap: &Remap) {
+        self.entity_indices = Pages::default();
+        self.presence = BitSet::new();
+        for index in 0..self.entity_list.len() {
+            let entity_id = self.entity_list[index];
+            let entity_id = remap.get(&entity_id).copied().unwrap_or(entity_id);
+            self.entity_list[index] = entity_id;
+            self.entity_indices.set(entity_id, Some(index));
+            self.presence.insert(entity_id);
+        }
+    }
+
+    fn index_of(&self, entity_id: EntityId) -> Option<usize> {
+        self.entity_indices.get(entity_id)
+    }
+
+    fn swap_packed(&mut self, a: usize, b: usize) {
+        self.swap(a, b);
+    }
+
+    fn remove_batch(&mut self, entity_ids: &[EntityId]) -> usize {
+        Pool::remove_batch(self, entity_ids)
+    }
+
+    fn get_any(&self, entity_id: EntityId) -> Option<&dyn Any> {
+        Some(self.get(entity_id)?)
+    }
+
+    fn get_any_mut(&mut self, entity_id: EntityId) -> Option<&mut dyn Any> {
+        Some(self.get_mut(entity_id)?)
+    }
+}
+
+// Adds many components at once, reserving room up front and moving every change tick to
+// one new tick. Entities that already have the component have it replaced, as with
+// add_component, but nothing outside the pool hears about any of it
+impl<T: Component + Eq> Extend<(EntityId, T)> for Pool<T> {
+    fn extend<I: IntoIterator<Item = (EntityId, T)>>(&mut self, components: I) {
+        let components = components.into_iter();
+        let additional = components.size_hint().0;
+        self.entity_list.reserve(additional);
+        self.component_list.reserve(additional);
+        self.ticks.reserve(additional);
+        self.tick += 1;
+        for (entity_id, component) in components {
+            match self.entity_indices.get(entity_id) {
+                Some(index) => {
+                    self.component_list[index] = component;
+                    self.ticks[index] = self.tick;
+                }
+                None => {
+                    self.entity_indices
+                        .set(entity_id, Some(self.entity_list.len()));
+                    self.entity_list.push(entity_id);
+                    self.component_list.push(component);
+                    self.ticks.push(self.tick);
+                    self.presence.insert(entity_id);
+                }
+            }
+        }
+    }
+}
+
+impl<T: Component + Eq> Default for Pool<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Component + Eq> Pool<T> {
+    pub fn new() -> Self {
+        Pool {
+            entity_indices: Pages::default(),
+            entity_list: Vec::new(),
+            component_list: Vec::new(),
+            presence: BitSet::new(),
+            ticks: Vec::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn new_entity(&mut self) -> EntityId {
+        self.entity_indices.len += 1;
+        self.entity_indices.len - 1
+    }
+
+    // Ensures that the entity list covers up to (and including) a given entity id
+    // Pages are only allocated once an entity in them gets the component
+    pub fn reserve_up_to(&mut self, entity_id: EntityId) {
+        self.entity_indices.len = self.entity_indices.len.max(entity_id.min(MAX_ENTITY) + 1);
+    }
+
+    // Adds a component, or overrides it if there already is one
+    // Returns the component that was overridden
+    pub fn add_component(&mut self, entity_id: EntityId, component: T) -> Option<T> {
+        if let Some(index) = self.entity_indices.get(entity_id) {
+            // Entity already exists, replace it
+            self.entity_list[index] = entity_id;
+            self.mark(index);
+            Some(std::mem::replace(
+                &mut self.component_list[index],
+                component,
+            ))
+        } else {
+            self.entity_indices
+                .set(entity_id, Some(self.entity_list.len()));
+            self.entity_list.push(entity_id);
+            self.component_list.push(component);
+            self.ticks.push(0);
+            self.mark(self.ticks.len() - 1);
+            self.presence.insert(entity_id);
+            None
+        }
+    }
+
+    // Removes the entity's component and returns it
+    pub fn take(&mut self, entity_id: EntityId) -> Option<T> {
+        // Remove the index of entity_indices equal to the entity_id
+        let entity_index = self.entity_indices.get(entity_id)?;
+        self.entity_indices.set(entity_id, None);
+        self.presence.remove(entity_id);
+
+        // First of all, remove the entity_list and component_list using a swap_pop
+        self.entity_list.swap_remove(entity_index);
+        let component = self.component_list.swap_remove(entity_index);
+        self.ticks.swap_remove(entity_index);
+
+        // Update the entity_indices value that previously pointed to the end
+        if let Some(&moved_entity_id) = self.entity_list.get(entity_index) {
+            self.entity_indices.set(moved_entity_id, Some(entity_index));
+        }
+        Some(component)
+    }
+
+    // Removes every listed entity's component in one pass over the packed arrays,
+    // returning how many there were. Unlike take, the components left keep their order
+    pub fn remove_batch(&mut self, entity_ids: &[EntityId]) -> usize {
+        let mut removed = 0;
+        for &entity_id in entity_ids {
+            if self.entity_indices.get(entity_id).is_some() {
+                self.entity_indices.set(entity_id, None);
+                self.presence.remove(entity_id);
+                removed += 1;
+            }
+        }
+        if removed == 0 {
+            return 0;
+        }
+        let mut kept = 0;
+        for index in 0..self.entity_list.len() {
+            let entity_id = self.entity_list[index];
+            if !self.presence.contains(entity_id) {
+                continue;
+            }
+            if kept != index {
+                self.entity_list.swap(kept, index);
+                self.component_list.swap(kept, index);
+                self.ticks.swap(kept, index);
+                self.entity_indices.set(entity_id, Some(kept));
+            }
+            kept += 1;
+        }
+        self.entity_list.truncate(kept);
+        self.component_list.truncate(kept);
+        self.ticks.truncate(kept);
+        removed
+    }
+
+    // Swaps two entries of the packed arrays, keeping the sparse array pointing at them
+    pub(crate) fn swap(&mut self, a: usize, b: usize) {
+        if a == b {
+            return;
+        }
+        self.entity_list.swap(a, b);
+        self.component_list.swap(a, b);
+        self.ticks.swap(a, b);
+        self.entity_indices.set(self.entity_list[a], Some(a));
+        self.entity_indices.set(self.entity_list[b], Some(b));
+    }
+
+    // Returns the length of entity_list/component_list (they should be the same)
+    pub fn len(&self) -> usize {
+        self.entity_list.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entity_list.is_empty()
+    }
+
+    // How many components fit before the packed arrays reallocate
+    // Counted on the entity list, as a list of zero-sized components has no end
+    pub fn capacity(&self) -> usize {
+        self.entity_list.capacity()
+    }
+
+    // Releases spare capacity in the packed arrays, and pages and bits no longer in use
+    pub fn shrink_to_fit(&mut self) {
+        self.entity_list.shrink_to_fit();
+        self.component_list.shrink_to_fit();
+        self.ticks.shrink_to_fit();
+        self.entity_indices.shrink_to_fit();
+        self.presence.shrink_to_fit();
+    }
+
+    // Bytes allocated by the pool, spare capacity included
+    pub fn bytes(&self) -> usize {
+        self.entity_list.capacity() * std::mem::size_of::<EntityId>()
+            + self.component_list.capacity() * std::mem::size_of::<T>()
+            + self.ticks.capacity() * std::mem::size_of::<u64>()
+            + self.entity_indices.bytes()
+            + self.presence.bytes()
+    }
+
+    // Pages of the sparse array that have been allocated
+    pub fn allocated_pages(&self) -> usize {
+        self.entity_indices.allocated()
+    }
+
+    pub fn entities(&self) -> Vec<&EntityId> {
+        self.entity_list.iter().collect()
+    }
+
+    pub fn components(&self) -> Vec<(&EntityId, &T)> {
+        self.entity_list
+            .iter()
+            .zip(self.component_list.iter())
+            .collect()
+    }
+
+    pub fn get(&self, entity_id: EntityId) -> Option<&T> {
+        Some(&self.component_list[self.entity_indices.get(entity_id)?])
+    }
+
+    // Counts as a change to the component, whether or not it is written to
+    pub fn get_mut(&mut self, entity_id: EntityId) -> Option<&mut T> {
+        let index = self.entity_indices.get(entity_id)?;
+        self.mark(index);
+        Some(&mut self.component_list[index])
+    }
+
+    // These count as changes to every component
+    pub fn components_mut(&mut self) -> Vec<(&EntityId, &mut T)> {
+        self.mark_all();
+        self.entity_list
+            .iter()
+            .zip(self.component_list.iter_mut())
+            .collect()
+    }
+
+    pub fn components_iter(&self) -> impl Iterator<Item = (&EntityId, &T)> {
+        self.entity_list.iter().zip(self.component_list.iter())
+    }
+
+    pub fn components_iter_mut(&mut self) -> impl Iterator<Item = (&EntityId, &mut T)> {
+        self.mark_all();
+        self.entity_list.iter().zip(self.component_list.iter_mut())
+    }
+
+    pub fn has_component(&self, entity_id: EntityId) -> bool {
+        self.entity_indices.get(entity_id).is_some()
+    }
+
+    // The packed arrays, entity and component side by side
+    #[cfg(feature = "parallel")]
+    pub(crate) fn packed(&self) -> (&[EntityId], &[T]) {
+        (&self.entity_list, &self.component_list)
+    }
+
+    // As packed, counting as a change to every component
+    #[cfg(feature = "parallel")]
+    pub(crate) fn packed_mut(&mut self) -> (&[EntityId], &mut [T]) {
+        self.mark_all();
+        (&self.entity_list, &mut self.component_list)
+    }
+
+    // The latest change tick, to pass to changed_since later
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    // Components added, replaced or borrowed mutably after the tick
+    pub fn changed_since(&self, tick: u64) -> impl Iterator<Item = (&EntityId, &T)> {
+        self.components_iter()
+            .zip(&self.ticks)
+            .filter(move |(_, &changed)| changed > tick)
+            .map(|(component, _)| component)
+    }
+
+    fn mark(&mut self, index: usize) {
+        self.tick += 1;
+        self.ticks[index] = self.tick;
+    }
+
+    fn mark_all(&mut self) {
+        self.tick += 1;
+        self.ticks.fill(self.tick);
+    }
+}
+
+pub(crate) struct PoolRefStore(pub(crate) Vec<Arc<AtomicRefCell<dyn PoolRef>>>);
+impl std::fmt::Debug for PoolRefStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "PoolRefStore")
+    }
+}
+
+#[derive(Debug)]
+pub struct EntityStore {
+    // Stores Arc<AtomicRefCell<Pool<T>>> in an anymap
+    // Lets us access the pool of a type, given its type
+    store: Map<dyn anymap::any::Any + Send + Sync>,
+
+    // Stores Arc<AtomicRefCell<dyn PoolRef>>> in a vec
+    // These are the same pools as in store, but type erased
+    // and iterable.
+    pub(crate) pool_refs: PoolRefStore,
+
+    // Id of the last entity
+    max_entity: EntityId,
+    // One past the highest id spawned or reserved, 0 if none has been, so an entity
+    // with no components yet still isn't handed out again, see batch.rs
+    allocated: EntityId,
+
+    // Subscribers to StoreEvents
+    pub(crate) events: EventBus<StoreEvent>,
+
+    // Typed event queues, see events.rs
+    pub(crate) queues: EventQueues,
+
+    // Lifecycle callbacks, see hooks.rs
+    pub(crate) hooks: HookStore,
+
+    // Reverse indexes of relations, by relation type, see relation.rs
+    pub(crate) reverse: HashMap<TypeId, ReverseIndex>,
+
+    // Hash indexes on component fields, by type and field position, see index.rs
+    pub(crate) field_indexes: HashMap<(TypeId, usize), Arc<AtomicRefCell<FieldIndex>>>,
+
+    // Ordered indexes on numeric fields, keyed the same way
+    pub(crate) ordered_indexes: HashMap<(TypeId, usize), Arc<AtomicRefCell<OrderedIndex>>>,
+
+    // Catch indexes up on components changed in place, see index.rs
+    pub(crate) refreshers: Refreshers,
+
+    // Which tile is in each cell, see tiles.rs
+    pub(crate) tiles: HashMap<Tile, EntityId>,
+
+    // Pools included in snapshots, see snapshot.rs
+    pub(crate) copiers: HashMap<TypeId, PoolCopier>,
+
+    // Entity templates by name, see prefab.rs
+    pub(crate) prefabs: HashMap<String, Prefab>,
+
+    // How to copy each component type onto a cloned entity, see clone.rs
+    pub(crate) cloners: Cloners,
+
+    // Component types only one entity may have, see singleton.rs
+    pub(crate) singletons: HashMap<TypeId, Uniqueness>,
+
+    // Components that need others, see require.rs
+    pub(crate) requirements: Vec<Required>,
+
+    // Undo history, if it is being kept, see journal.rs
+    pub(crate) journal: Option<Journal>,
+
+    // Owning groups, see group.rs
+    pub(crate) groups: Vec<Group>,
+
+    // How to read the fields of each registered type, so changes carry their values, see
+    // reactive.rs
+    pub(crate) readers: HashMap<TypeId, FieldReader>,
+    // The time of the engine running over the store, to stamp changes with
+    pub(crate) time: Option<SharedTime>,
+    // Where write-ahead logs take each change as it's made, see wal.rs
+    pub(crate) change_logs: Vec<Weak<Mutex<dyn ChangeLog>>>,
+}
+
+impl Default for EntityStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EntityStore {
+    pub fn new() -> Self {
+        EntityStore {
+            store: Map::new(),
+            max_entity: 0,
+            allocated: 0,
+            pool_refs: PoolRefStore(Vec::new()),
+            events: EventBus::new(),
+            queues: EventQueues::default(),
+            hooks: HookStore::default(),
+            reverse: HashMap::new(),
+            field_indexes: HashMap::new(),
+            ordered_indexes: HashMap::new(),
+            refreshers: Refreshers::default(),
+            tiles: HashMap::new(),
+            copiers: HashMap::new(),
+            prefabs: HashMap::new(),
+            cloners: Cloners::default(),
+            singletons: HashMap::new(),
+            requirements: Vec::new(),
+            journal: None,
+            groups: Vec::new(),
+            readers: HashMap::new(),
+            time: None,
+            change_logs: Vec::new(),
+        }
+    }
+
+    // Define a new component type for the store
+    // Ideally done when there are no entities, or very few
+    pub fn new_component<T: Component + Eq + 'static>(&mut self) {
+        let mut pool = Pool::<T>::new();
+        pool.reserve_up_to(self.max_entity);
+
+        let pool_rc: Arc<AtomicRefCell<Pool<T>>> = Arc::new(AtomicRefCell::new(pool));
+        self.store.insert(pool_rc.clone());
+        self.pool_refs.0.push(pool_rc.clone());
+    }
+
+    // Ids past MAX_ENTITY reserve nothing
+    pub fn reserve_up_to(&mut self, entity_id: EntityId) {
+        if entity_id > MAX_ENTITY {
+            return;
+        }
+        self.allocated = self.allocated.max(entity_id + 1);
+        if self.max_entity >= entity_id {
+            return;
+        }
+        self.max_entity = entity_id;
+    }
+
+    // Unlike reserve_up_to this can lower it, for restoring an earlier state
+    // next is the first id free to hand out, 0 if none has been
+    pub(crate) fn set_next_entity(&mut self, next: EntityId) {
+        self.allocated = next;
+        self.max_entity = next.saturating_sub(1);
+    }
+
+    pub(crate) fn allocated(&self) -> EntityId {
+        self.allocated
+    }
+
+    // The highest entity id the store has seen
+    pub fn max_entity(&self) -> EntityId {
+        self.max_entity
+    }
+
+    pub fn get<T: Component + Eq + 'static>(&self) -> Option<&Arc<AtomicRefCell<Pool<T>>>> {
+        self.store.get::<Arc<AtomicRefCell<Pool<T>>>>()
+    }
+
+    pub fn get_mut<T: Component + Eq + 'static>(
+        &mut self,
+    ) -> Option<&mut Arc<AtomicRefCell<Pool<T>>>> {
+        self.store.get_mut::<Arc<AtomicRefCell<Pool<T>>>>()
+    }
+
+    // Add a instance of a component to a entity
+    // Note this should not be called when queries are out, only between queries
+    // as it performs a borrow_mut on the pool the component is added to
+    // THIS IS CALLED COMMAND BUFFERING
+    pub fn add_component<T: Component + Eq + 'static>(
+        &mut self,
+        entity_id: EntityId,
+        component: T,
+    ) {
+        if self.get::<T>().is_none() || entity_id > MAX_ENTITY {
+            return;
+        }
+        let type_id = TypeId::of::<T>();
+        let new = !self.has_component::<T>(entity_id);
+        // A singleton held elsewhere is refused or taken off its holder, see singleton.rs,
+        // and a component needing one the entity lacks is refused, see require.rs
+        let Ok(holder) = self.make_room::<T>(entity_id) else {
+            return;
+        };
+        if new && self.missing(type_id, entity_id).is_err() {
+            return;
+        }
+        self.begin_step();
+        if let Some(holder) = holder {
+            self.remove_component::<T>(holder);
+        }
+        let pool = self.store.get::<Arc<AtomicRefCell<Pool<T>>>>().unwrap();
+        let (previous, grew) = {
+            let mut pool = pool.borrow_mut();
+            let before = pool.capacity();
+            let previous = pool.add_component(entity_id, component);
+            if let (Some(hooks), Some(current)) = (self.hooks.get_mut::<T>(), pool.get(entity_id)) {
+                match &previous {
+                    Some(old) => hooks.replaced(entity_id, old, current),
+                    None => hooks.added(entity_id, current),
+                }
+            }
+            let grew = (pool.capacity() > before).then(|| StoreEvent::PoolGrew {
+                component: short_type_name::<T>(),
+                len: pool.len(),
+                capacity: pool.capacity(),
+            });
+            (previous, grew)
+        };
+        if previous.is_none() {
+            self.join_groups(TypeId::of::<T>(), entity_id);
+        }
+        self.journal_insert(entity_id, previous);
+        self.record_inserted::<T>(entity_id);
+        if let Some(event) = grew {
+            self.emit(event);
+        }
+        if new {
+            self.insert_required(type_id, entity_id);
+        }
+        self.end_step();
+    }
+
+    // Adds T's default, making the pool if it's the first
+    pub fn insert_default<T: Component + Eq + Default + 'static>(&mut self, entity_id: EntityId) {
+        self.get_or_insert_with(entity_id, T::default);
+    }
+
+    // The entity's T, added first from make if it has none
+    // None only if adding it was refused, see singleton.rs and require.rs
+    pub fn get_or_insert_with<T: Component + Eq + 'static>(
+        &mut self,
+        entity_id: EntityId,
+        make: impl FnOnce() -> T,
+    ) -> Option<AtomicRefMut<'_, T>> {
+        if self.get::<T>().is_none() {
+            self.new_component::<T>();
+        }
+        if !self.has_component::<T>(entity_id) {
+            self.reserve_up_to(entity_id);
+            self.add_component(entity_id, make());
+        }
+        let pool = self.get::<T>()?.borrow_mut();
+        AtomicRefMut::filter_map(pool, |pool| pool.get_mut(entity_id)).ok()
+    }
+
+    pub fn remove_component<T: Component + Eq + 'static>(&mut self, entity_id: EntityId) {
+        if !self.has_component::<T>(entity_id) {
+            return;
+        }
+        if self.constrained(TypeId::of::<T>()) {
+            if self.needed(TypeId::of::<T>(), entity_id).is_err() {
+                return;
+            }
+            self.begin_step();
+            self.take_component::<T>(entity_id);
+            self.remove_requiring(TypeId::of::<T>(), entity_id);
+            self.end_step();
+        } else {
+            self.take_component::<T>(entity_id);
+        }
+    }
+
+    fn take_component<T: Component + Eq + 'static>(&mut self, entity_id: EntityId) {
+        self.leave_groups(Some(TypeId::of::<T>()), entity_id);
+        let pool = self.store.get::<Arc<AtomicRefCell<Pool<T>>>>().unwrap();
+        let Some(removed) = pool.borrow_mut().take(entity_id) else {
+            return;
+        };
+        if let Some(hooks) = self.hooks.get_mut::<T>() {
+            hooks.removed(entity_id, &removed);
+        }
+        if let Some(journal) = &mut self.journal {
+            journal.record_removed(&self.copiers, TypeId::of::<T>(), entity_id, &removed);
+        }
+        self.record(
+            TypeId::of::<T>(),
+            entity_id,
+            ChangeKind::Removed(Arc::new(removed)),
+        );
+    }
+
+    pub fn entities<T: Component + Eq + 'static>(&self) -> Option<AtomicRef<'_, Vec<EntityId>>> {
+        let pool = self.store.get::<Arc<AtomicRefCell<Pool<T>>>>()?;
+        Some(AtomicRef::map(pool.borrow(), |borrowed| {
+            &borrowed.entity_list
+        }))
+    }
+
+    pub fn components<T: Component + Eq + 'static>(&self) -> Option<AtomicRef<'_, Vec<T>>> {
+        let pool = self.store.get::<Arc<AtomicRefCell<Pool<T>>>>()?;
+        Some(AtomicRef::map(pool.borrow(), |borrowed| {
+            &borrowed.component_list
+        }))
+    }
+
+    pub fn components_mut<T: Component + Eq + 'static>(
+        &mut self,
+    ) -> Option<AtomicRefMut<'_, Vec<T>>> {
+        let pool = self.store.get::<Arc<AtomicRefCell<Pool<T>>>>()?;
+        Some(AtomicRefMut::map(pool.borrow_mut(), |borrowed| {
+            borrowed.mark_all();
+            &mut borrowed.component_list
+        }))
+    }
+
+    pub fn has_component<T: Component + Eq + 'static>(&self, entity_id: EntityId) -> bool {
+        if let Some(pool) = self.store.get::<Arc<AtomicRefCell<Pool<T>>>>() {
+            pool.borrow().has_component(entity_id)
+        } else {
+            false
+        }
+    }
+
+    pub fn remove_entity(&mut self, entity_id: EntityId) {
+        let tracking = self.tracking_changes();
+        self.leave_groups(None, entity_id);
+        self.begin_step();
+        for pool_ref in &self.pool_refs.0 {
+            let mut pool = pool_ref.borrow_mut();
+            let type_id = pool.component_type();
+            let hooks = self.hooks.get_erased(type_id);
+            let journaled = self.journal.is_some() && self.copiers.contains_key(&type_id);
+            if hooks.is_none() && !tracking && !journaled {
+                pool.remove(entity_id);
+                continue;
+            }
+            let Some(removed) = pool.take_any(entity_id) else {
+                continue;
+            };
+            if let Some(hooks) = hooks {
+                hooks.removed_any(entity_id, removed.as_ref());
+            }
+            if let Some(journal) = &mut self.journal {
+                journal.record_removed(&self.copiers, type_id, entity_id, removed.as_ref());
+            }
+            self.record(type_id, entity_id, ChangeKind::Removed(removed.into()));
+        }
+        self.end_step();
+    }
+}
+
+pub trait View {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct TestComponent {
+        data: i32,
+    }
+
+    impl Component for TestComponent {}
+
+    #[test]
+    fn entity_store_creation() {
+        let store = EntityStore::new();
+        assert_eq!(store.max_entity, 0);
+        assert_eq!(store.store.len(), 0);
+    }
+
+    #[test]
+    fn component_registration() {
+        let mut store = EntityStore::new();
+        store.new_component::<TestComponent>();
+        assert_eq!(store.store.len(), 1);
+    }
+
+    #[test]
+    fn component_addition_removal() {
+        let mut store = EntityStore::new();
+        store.new_component::<TestComponent>();
+
+        let entity_id = 1;
+        store.add_component(entity_id, TestComponent { data: 10 });
+
+        {
+            let pool = store.get::<TestComponent>().unwrap();
+            let borrowed = pool.borrow();
+            assert_eq!(borrowed.get(entity_id).unwrap().data, 10);
+        }
+
+        store.remove_component::<TestComponent>(entity_id);
+
+        let pool = store.get::<TestComponent>().unwrap();
+        assert!(pool.borrow().get(entity_id).is_none());
+    }
+
+    #[test]
+    fn entity_removal() {
+        let mut store = EntityStore::new();
+        store.new_component::<TestComponent>();
+
+        let entity_id = 1;
+        store.add_component(entity_id, TestComponent { data: 10 });
+
+        {
+            let pool = store.get::<TestComponent>().unwrap();
+            let borrowed = pool.borrow();
+            assert_eq!(borrowed.get(entity_id).unwrap().data, 10);
+        }
+
+        store.remove_entity(entity_id);
+
+        let pool = store.get::<TestComponent>().unwrap();
+        assert!(pool.borrow().get(entity_id).is_none());
+    }
+
+    #[test]
+    fn swap_remove_keeps_indices() {
+        let mut store = EntityStore::new();
+        store.new_component::<TestComponent>();
+
+        store.add_component(1, TestComponent { data: 10 });
+        store.add_component(2, TestComponent { data: 20 });
+        store.add_component(3, TestComponent { data: 30 });
+        store.remove_component::<TestComponent>(1);
+
+        let pool = store.get::<TestComponent>().unwrap();
+        let borrowed = pool.borrow();
+        assert!(!borrowed.has_component(1));
+        assert_eq!(borrowed.get(2).unwrap().data, 20);
+        assert_eq!(borrowed.get(3).unwrap().data, 30);
+    }
+
+    #[test]
+    fn component_iterators() {
+        let mut store = EntityStore::new();
+        store.new_component::<TestComponent>();
+
+        store.add_component(1, TestComponent { data: 10 });
+        store.add_component(2, TestComponent { data: 20 });
+        store.add_component(3, TestComponent { data: 30 });
+
+        let pool = store.get::<TestComponent>().unwrap();
+
+        {
+            let borrowed = pool.borrow();
+            let data: Vec<_> = borrowed.components_iter().map(|(_, c)| c.data).collect();
+            assert_eq!(data, vec![10, 20, 30]);
+        }
+
+        let mut borrowed_mut = pool.borrow_mut();
+        let data: Vec<_> = borrowed_mut
+            .components_iter_mut()
+            .map(|(_, c)| {
+                c.data += 1;
+                c.data
+            })
+            .collect();
+        assert_eq!(data, vec![11, 21, 31]);
+    }
+
+    #[test]
+    fn pages_are_allocated_on_demand() {
+        let mut pool = Pool::new();
+        pool.reserve_up_to(10_000_000);
+        assert_eq!(pool.allocated_pages(), 0);
+
+        pool.add_component(9_999_999, TestComponent { data: 1 });
+        pool.add_component(10, TestComponent { data: 2 });
+        pool.add_component(20, TestComponent { data: 3 });
+        assert_eq!(pool.allocated_pages(), 2);
+        assert_eq!(pool.get(9_999_999).unwrap().data, 1);
+        assert!(pool.get(9_999_998).is_none() && pool.get(50_000_000).is_none());
+
+        pool.take(10);
+        assert_eq!(pool.get(20).unwrap().data, 3);
+        assert!(pool.take(10_000_001).is_none());
+        assert_eq!(pool.allocated_pages(), 2);
+    }
+
+    #[test]
+    fn ids_past_the_maximum_are_turned_away() {
+        let mut store = EntityStore::new();
+        store.new_component::<TestComponent>();
+        store.reserve_up_to(usize::MAX);
+        store.reserve_up_to(MAX_ENTITY + 1);
+        assert_eq!(store.next_entity(), 0);
+
+        store.add_component(1 << 50, TestComponent { data: 1 });
+        assert_eq!(
+            store.try_add_component(MAX_ENTITY + 1, TestComponent { data: 2 }),
+            Err(Error::EntityRange(MAX_ENTITY + 1))
+        );
+        let pool = store.get::<TestComponent>().unwrap();
+        assert!(pool.borrow().is_empty() && pool.borrow().allocated_pages() == 0);
+
+        store.add_component(MAX_ENTITY, TestComponent { data: 3 });
+        assert_eq!(store.next_entity(), MAX_ENTITY + 1);
+        assert_eq!(
+            check_entity(MAX_ENTITY + 1),
+            Err(Error::EntityRange(MAX_ENTITY + 1))
+        );
+    }
+
+    #[test]
+    fn defaults_fill_in_missing_components() {
+        #[derive(Debug, Default, PartialEq, Eq)]
+        struct Score(u32);
+        impl Component for Score {}
+
+        let mut store = EntityStore::new();
+        store.insert_default::<Score>(3);
+        assert_eq!(
+            store.get::<Score>().unwrap().borrow().get(3),
+            Some(&Score(0))
+        );
+        for _ in 0..2 {
+            store.get_or_insert_with(5, || Score(10)).unwrap().0 += 1;
+        }
+        store.get_or_insert_with(3, || Score(10)).unwrap().0 += 1;
+        let scores = store.get::<Score>().unwrap().borrow();
+        assert_eq!(
+            (scores.get(3), scores.get(5)),
+            (Some(&Score(1)), Some(&Score(12)))
+        );
+    }
+
+    #[test]
+    fn stores_are_shared_across_threads() {
+        fn shared<T: Send + Sync>() {}
+        shared::<EntityStore>();
+
+        let mut store = EntityStore::new();
+        store.new_component::<TestComponent>();
+        store.on_add::<TestComponent>(|_, component| assert!(component.data >= 0));
+        for entity_id in 0..100 {
+            store.add_component(
+                entity_id,
+                TestComponent {
+                    data: entity_id as i32,
+                },
+            );
+        }
+        let store = std::thread::spawn(move || {
+            store.remove_component::<TestComponent>(0);
+            store
+        })
+        .join()
+        .unwrap();
+
+        let sums: Vec<i32> = std::thread::scope(|scope| {
+            let readers: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let components = store.components::<TestComponent>().unwrap();
+                        components.iter().map(|component| component.data).sum()
+                    })
+                })
+                .collect();
+            readers
+                .into_iter()
+                .map(|reader| reader.join().unwrap())
+                .collect()
+        });
+        assert_eq!(sums, vec![4950; 4]);
+    }
+}
@@ -0,0 +1,233 @@
+// Subscribing to query results
+//
+// store.subscribe_query::<(Health, Position)>(callback) calls back whenever an entity comes to
+// have all of the components, Entered, or stops having one of them, Left, so a UI or
+// network layer can follow a result set without checking it every tick. Entities
+// already in it when subscribing are reported as entering straight away, so the
+// callback alone can build the set up.
+//
+// Membership is followed with hooks, see hooks.rs, so it changes as the store does,
+// including through remove_entity, and components changed in place through a pool,
+// which don't move anything in or out, aren't seen. The callback runs in the middle of
+// the store change, so it should hand the entity on rather than reach for the store.
+// Hooks can't be taken out again, so cancelling leaves them in place doing nothing.
+// Store events have subscribe to themselves, see stats.rs.
+use crate::store::{Component, EntityId, EntityStore};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Membership {
+    Entered(EntityId),
+    Left(EntityId),
+}
+
+type Callback = Box<dyn FnMut(Membership) + Send + Sync>;
+
+// What each entity has of the set, one bit per component, shared by a subscription's hooks
+pub struct Watched {
+    held: HashMap<EntityId, u32>,
+    full: u32,
+    callback: Callback,
+    cancelled: Arc<AtomicBool>,
+}
+
+type Shared = Arc<Mutex<Watched>>;
+
+impl Watched {
+    fn gained(&mut self, entity_id: EntityId, bit: u32) {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return;
+        }
+        let held = self.held.entry(entity_id).or_default();
+        let was = *held;
+        *held |= bit;
+        if was != self.full && *held == self.full {
+            (self.callback)(Membership::Entered(entity_id));
+        }
+    }
+
+    fn lost(&mut self, entity_id: EntityId, bit: u32) {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return;
+        }
+        let Some(held) = self.held.get_mut(&entity_id) else {
+            return;
+        };
+        let was = *held;
+        *held &= !bit;
+        if *held == 0 {
+            self.held.remove(&entity_id);
+        }
+        if was == self.full {
+            (self.callback)(Membership::Left(entity_id));
+        }
+    }
+}
+
+fn lock(shared: &Shared) -> std::sync::MutexGuard<'_, Watched> {
+    shared
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// A tuple of component types, an entity is in its results while it has every one
+pub trait ComponentSet: 'static {
+    const LEN: usize;
+
+    // The bits of the components each entity has, in tuple order
+    fn held(store: &EntityStore) -> HashMap<EntityId, u32>;
+
+    // Every entity with the first component, to find those with all of them
+    fn candidates(store: &EntityStore) -> Vec<EntityId>;
+
+    // Hooks each component into the subscription
+    fn watch(store: &mut EntityStore, watched: &Shared);
+}
+
+macro_rules! component_set {
+    ($first:ident $(, $rest:ident)*) => {
+        impl<$first: Component + Eq + 'static $(, $rest: Component + Eq + 'static)*>
+            ComponentSet for ($first, $($rest,)*)
+        {
+            const LEN: usize = [stringify!($first) $(, stringify!($rest))*].len();
+
+            fn held(store: &EntityStore) -> HashMap<EntityId, u32> {
+                let mut held = HashMap::new();
+                let mut bit = 1;
+                component_set!(@held store, held, bit, $first $(, $rest)*);
+                held
+            }
+
+            fn candidates(store: &EntityStore) -> Vec<EntityId> {
+                store
+                    .entities::<$first>()
+                    .map(|entities| entities.clone())
+                    .unwrap_or_default()
+            }
+
+            fn watch(store: &mut EntityStore, watched: &Shared) {
+                let mut bit = 1;
+                component_set!(@watch store, watched, bit, $first $(, $rest)*);
+            }
+        }
+    };
+    (@held $store:ident, $held:ident, $bit:ident, $($component:ident),*) => {
+        $(
+            if let Some(entities) = $store.entities::<$component>() {
+                for entity_id in entities.iter() {
+                    *$held.entry(*entity_id).or_insert(0) |= $bit;
+                }
+            }
+            $bit <<= 1;
+        )*
+        let _ = $bit;
+    };
+    (@watch $store:ident, $watched:ident, $bit:ident, $($component:ident),*) => {
+        $(
+            let (added, removed, this) = ($watched.clone(), $watched.clone(), $bit);
+            $store.on_add::<$component>(move |entity_id, _| {
+                lock(&added).gained(entity_id, this)
+            });
+            $store.on_remove::<$component>(move |entity_id, _| {
+                lock(&removed).lost(entity_id, this)
+            });
+            $bit <<= 1;
+        )*
+        let _ = $bit;
+    };
+}
+
+component_set!(A);
+component_set!(A, B);
+component_set!(A, B, C);
+component_set!(A, B, C, D);
+
+// Stops a subscription's callback from being called again
+#[derive(Debug, Clone)]
+pub struct Subscription {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Subscription {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+impl EntityStore {
+    // Calls back as entities enter and leave Q's results, see above
+    pub fn subscribe_query<Q: ComponentSet>(
+        &mut self,
+        callback: impl FnMut(Membership) + Send + Sync + 'static,
+    ) -> Subscription {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let full = (1 << Q::LEN) - 1;
+        let mut watched = Watched {
+            held: Q::held(self),
+            full,
+            callback: Box::new(callback),
+            cancelled: cancelled.clone(),
+        };
+        for entity_id in Q::candidates(self) {
+            if watched.held.get(&entity_id) == Some(&full) {
+                (watched.callback)(Membership::Entered(entity_id));
+            }
+        }
+        let watched = Arc::new(Mutex::new(watched));
+        Q::watch(self, &watched);
+        Subscription { cancelled }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Health(i64);
+    impl Component for Health {}
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Position(i64, i64);
+    impl Component for Position {}
+
+    #[test]
+    fn subscribers_see_entities_enter_and_leave() {
+        let mut store = EntityStore::new();
+        store.new_component::<Health>();
+        store.new_component::<Position>();
+        store.add_component(1, Health(10));
+        store.add_component(1, Position(0, 0));
+        store.add_component(2, Health(10));
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = seen.clone();
+        let subscription = store.subscribe_query::<(Health, Position)>(move |membership| {
+            log.lock().unwrap().push(membership);
+        });
+        store.add_component(2, Position(1, 1));
+        // Replacing a component moves nothing in or out
+        store.add_component(2, Health(5));
+        store.remove_component::<Health>(1);
+        store.add_component(3, Position(2, 2));
+        store.remove_entity(2);
+        subscription.cancel();
+        store.add_component(3, Health(1));
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [
+                Membership::Entered(1),
+                Membership::Entered(2),
+                Membership::Left(1),
+                Membership::Left(2),
+            ]
+        );
+    }
+}
@@ -0,0 +1,346 @@
+// Backward chaining with tabled resolution
+//
+// RuleEngine::prove answers a query by reading the rules backwards, as Horn clauses
+// whose heads are their inserts, rather than running them forwards. A pattern on a
+// component some rule inserts is a sub-goal: its answers are the facts in the store plus
+// whatever the rules inserting it would, worked out by proving their conditions in turn.
+// Nothing is written to the store, so a sub-goal can have many answers per entity, like
+// every entity reachable from one, where running the rules would keep one.
+//
+// Each sub-goal is a call, its component and the arguments known when it's reached, and
+// its answers are kept in a table. A call reached again while it's still being worked out
+// reads the answers found so far instead of recursing, and the first call in a cycle
+// goes round until none of the tables in it grow, so recursion over cyclic data, like
+// reachability in a graph with loops, ends. Finished tables are kept and reused by later
+// calls and later proofs until the engine runs again, so facts asserted between runs
+// aren't seen by calls already proved unless forget_tables is called.
+//
+// Removes and runs have no reading backwards and are ignored, as are rules in disabled
+// modules. Rules depending on history, reactive ones or ones with temporal patterns,
+// are an error to prove through, as is `not` on a sub-goal that depends on itself.
+use crate::engine::RuleEngine;
+use crate::error::Error;
+use crate::query::{Query, Solutions};
+use crate::rule::{Action, Condition, Expr, Pattern, Rule, Term};
+use crate::store::EntityStore;
+use crate::value::{Bindings, Value};
+use std::collections::{HashMap, HashSet};
+
+// A sub-goal, its component and each argument if known
+type Call = (String, Vec<Option<Value>>);
+
+#[derive(Debug, Default)]
+struct Table {
+    // Entity then fields, in the order found
+    answers: Vec<Vec<Value>>,
+    seen: HashSet<Vec<Value>>,
+    complete: bool,
+}
+
+// Tables kept between proofs, see above
+#[derive(Debug, Default)]
+pub(crate) struct Tables {
+    // The engine tick they were proved in
+    tick: u64,
+    tables: HashMap<Call, Table>,
+}
+
+// No call on the stack was read
+const NONE: usize = usize::MAX;
+
+struct Resolution<'a> {
+    engine: &'a RuleEngine,
+    store: &'a EntityStore,
+    tables: &'a mut HashMap<Call, Table>,
+    // The rules inserting each component and the arguments they insert
+    heads: HashMap<&'a str, Vec<(&'a Rule, &'a [Expr])>>,
+    // Calls being worked out, outermost first
+    stack: Vec<Call>,
+    // Calls left unfinished until the first call of their cycle is
+    pending: Vec<Call>,
+    // Answers added so far, to tell when a cycle has stopped growing
+    added: usize,
+}
+
+impl<'a> Resolution<'a> {
+    // Every extension of bindings satisfying the conditions, and the position on the
+    // stack of the outermost call they read unfinished, NONE if they didn't
+    fn resolve(
+        &mut self,
+        conditions: &[Condition],
+        bindings: Bindings,
+    ) -> Result<(Vec<Bindings>, usize), Error> {
+        let mut partial = vec![bindings];
+        let mut low = NONE;
+        for condition in conditions {
+            let mut next = Vec::new();
+            for bindings in &partial {
+                match condition {
+                    Condition::Pattern(pattern) => {
+                        let (unified, read) = self.unify(pattern, bindings)?;
+                        low = low.min(read);
+                        next.extend(unified);
+                    }
+                    Condition::Not(pattern) => {
+                        let (unified, read) = self.unify(pattern, bindings)?;
+                        if read != NONE {
+                            return Err(Error::Tabling(format!(
+                                "not {} depends on itself",
+                                pattern.component
+                            )));
+                        }
+                        if unified.is_empty() {
+                            next.push(bindings.clone());
+                        }
+                    }
+                    Condition::Test(expr) => {
+                        if expr.eval(bindings) == Some(Value::Bool(true)) {
+                            next.push(bindings.clone());
+                        }
+                    }
+                    Condition::Spatial(predicate) => {
+                        next.extend(self.engine.spatial_matches(predicate, bindings, self.store))
+                    }
+                }
+            }
+            partial = next;
+            if partial.is_empty() {
+                break;
+            }
+        }
+        Ok((partial, low))
+    }
+
+    // The pattern against the store's facts and its call's answers
+    fn unify(
+        &mut self,
+        pattern: &Pattern,
+        bindings: &Bindings,
+    ) -> Result<(Vec<Bindings>, usize), Error> {
+        if pattern.temporal.is_some() {
+            return Err(Error::Tabling(format!(
+                "temporal pattern on {}",
+                pattern.component
+            )));
+        }
+        let info = self.engine.info(&pattern.component)?;
+        let mut rows: Vec<Vec<Value>> = RuleEngine::candidates(info, pattern, bindings, self.store)
+            .into_iter()
+            .map(|(entity_id, fields)| {
+                std::iter::once(Value::Entity(entity_id))
+                    .chain(fields)
+                    .collect()
+            })
+            .collect();
+        let mut low = NONE;
+        if self.heads.contains_key(pattern.component.as_str()) {
+            let known = pattern
+                .args
+                .iter()
+                .map(|term| match term {
+                    Term::Const(value) => Some(value.clone()),
+                    Term::Var(name) => bindings.get(name).cloned(),
+                    Term::Wildcard => None,
+                })
+                .collect();
+            let (answers, read) = self.call((pattern.component.clone(), known))?;
+            low = read;
+            let stored: HashSet<Vec<Value>> = rows.iter().cloned().collect();
+            rows.extend(answers.into_iter().filter(|row| !stored.contains(row)));
+        }
+        let unified = rows
+            .into_iter()
+            .filter_map(|row| {
+                let bindings = pattern.unify(&row, bindings)?;
+                pattern
+                    .guard_holds(&bindings, info.fields, &row[1..])
+                    .then_some(bindings)
+            })
+            .collect();
+        Ok((unified, low))
+    }
+
+    // The call's answers, and the position on the stack of the outermost call they read
+    // unfinished, NONE once they're complete
+    fn call(&mut self, call: Call) -> Result<(Vec<Vec<Value>>, usize), Error> {
+        if let Some(table) = self.tables.get(&call) {
+            if table.complete {
+                return Ok((table.answers.clone(), NONE));
+            }
+            if let Some(depth) = self.stack.iter().position(|on| *on == call) {
+                return Ok((table.answers.clone(), depth));
+            }
+        }
+        self.tables.entry(call.clone()).or_default();
+        let depth = self.stack.len();
+        let pending = self.pending.len();
+        self.stack.push(call.clone());
+        let heads = self.heads[call.0.as_str()].clone();
+        let low = loop {
+            let added = self.added;
+            let mut low = NONE;
+            for &(rule, args) in &heads {
+                if rule.trigger.is_some() {
+                    return Err(Error::Tabling(format!(
+                        "rule \"{}\" is reactive",
+                        rule.name
+                    )));
+                }
+                // Arguments the call knows bind the head's variables up front
+                let mut start = Bindings::new();
+                let fits = args
+                    .iter()
+                    .zip(&call.1)
+                    .all(|(arg, known)| match (arg, known) {
+                        (Expr::Var(name), Some(value)) => {
+                            start.entry(name.clone()).or_insert_with(|| value.clone()) == value
+                        }
+                        (Expr::Const(constant), Some(value)) => constant == value,
+                        _ => true,
+                    });
+                if !fits {
+                    continue;
+                }
+                let (solutions, read) = self.resolve(&rule.conditions, start)?;
+                low = low.min(read);
+                for bindings in solutions {
+                    let Some(row) = args
+                        .iter()
+                        .map(|arg| arg.eval(&bindings))
+                        .collect::<Option<Vec<Value>>>()
+                    else {
+                        continue;
+                    };
+                    let answers = row.len() == call.1.len()
+                        && row.iter().zip(&call.1).all(|(value, known)| {
+                            known.as_ref().is_none_or(|known| known == value)
+                        });
+                    let table = self.tables.get_mut(&call).expect("tabled above");
+                    if answers && table.seen.insert(row.clone()) {
+                        table.answers.push(row);
+                        self.added += 1;
+                    }
+                }
+            }
+            // Only reading itself or calls under it, it starts a cycle and goes round it
+            // until it stops growing, otherwise the call it read goes round for it
+            if low < depth || low == NONE || self.added == added {
+                break low;
+            }
+        };
+        self.stack.pop();
+        if low < depth {
+            self.pending.push(call.clone());
+        } else {
+            for finished in self
+                .pending
+                .drain(pending..)
+                .chain(std::iter::once(call.clone()))
+            {
+                self.tables.get_mut(&finished).expect("tabled").complete = true;
+            }
+        }
+        let answers = self.tables[&call].answers.clone();
+        Ok((answers, if low < depth { low } else { NONE }))
+    }
+}
+
+impl RuleEngine {
+    // Every binding set satisfying the query, proved backwards from the rules, see above
+    pub fn prove(&mut self, goal: &Query, store: &EntityStore) -> Result<Solutions, Error> {
+        let mut rule = Rule::new("query");
+        rule.conditions = goal.conditions.clone();
+        self.check_from(&rule, goal.bindings.keys().map(String::as_str).collect())?;
+        if self.tables.tick != self.tick {
+            self.forget_tables();
+        }
+
+        let mut heads: HashMap<&str, Vec<(&Rule, &[Expr])>> = HashMap::new();
+        for &index in self.agenda() {
+            let rule = &self.rules[index];
+            for action in &rule.actions {
+                if let Action::Insert {
+                    component, args, ..
+                } = action
+                {
+                    heads.entry(component).or_default().push((rule, args));
+                }
+            }
+        }
+        let mut tables = std::mem::take(&mut self.tables.tables);
+        let mut resolution = Resolution {
+            engine: self,
+            store,
+            tables: &mut tables,
+            heads,
+            stack: Vec::new(),
+            pending: Vec::new(),
+            added: 0,
+        };
+        let solved = resolution.resolve(&goal.conditions, goal.bindings.clone());
+        // A failed proof may leave tables half worked out
+        tables.retain(|_, table| table.complete);
+        self.tables.tables = tables;
+        Ok(Solutions::new(solved?.0))
+    }
+
+    // Drops the tables kept from earlier proofs, see above
+    pub fn forget_tables(&mut self) {
+        self.tables.tick = self.tick;
+        self.tables.tables.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recursion_over_cycles_ends() {
+        let mut engine = RuleEngine::new();
+        engine
+            .load_str(
+                r#"
+                fact Edge(to)
+                fact Reach(to)
+                rule "edge" when Edge(x, y) then insert Reach(x, y)
+                rule "path" when Reach(x, y), Edge(y, z) then insert Reach(x, z)
+                "#,
+            )
+            .unwrap();
+        let registry = engine.registry().clone();
+        let mut store = EntityStore::new();
+        // 1 -> 2 -> 3 -> 1, and 4 -> 1
+        for (from, to) in [(1, 2), (2, 3), (3, 1), (4, 1)] {
+            store
+                .insert_fact(&registry, "Edge", from, &[Value::Entity(to)])
+                .unwrap();
+        }
+
+        let goal = Query::parse("Reach(a, b)")
+            .unwrap()
+            .bind("a", Value::Entity(4));
+        let mut reached: Vec<Value> = engine.prove(&goal, &store).unwrap().values("b").collect();
+        reached.sort_by_key(|value| value.as_entity());
+        assert_eq!(
+            reached,
+            [Value::Entity(1), Value::Entity(2), Value::Entity(3)]
+        );
+        let tabled = engine.tables.tables.len();
+        assert!(engine.tables.tables.values().all(|table| table.complete));
+
+        // Reusing the tables, nothing new is worked out
+        assert_eq!(engine.prove(&goal, &store).unwrap().len(), 3);
+        assert_eq!(engine.tables.tables.len(), tabled);
+
+        let everything = Query::parse("Reach(a, b)").unwrap();
+        assert_eq!(engine.prove(&everything, &store).unwrap().len(), 12);
+        let unreached = Query::parse("Edge(a, _), not Reach(a, a)").unwrap();
+        let unreached: Vec<Value> = engine
+            .prove(&unreached, &store)
+            .unwrap()
+            .values("a")
+            .collect();
+        assert_eq!(unreached, [Value::Entity(4)]);
+    }
+}
@@ -0,0 +1,112 @@
+// Tags, components with no data
+//
+// Most components in a rule-driven world only mark an entity, Frozen or Burning or
+// Selected, and carry nothing. A zero-sized type's component list never allocates, so a
+// tag pool costs what its presence does: the sparse array, the entity list and the
+// bitset. tagged reads the bitset rather than the pool, and patterns over tags join
+// through it like any other pool, see bitset.rs, reading no component to find a match.
+use crate::store::{Component, EntityId, EntityStore, Pool};
+use std::any::TypeId;
+
+impl<T: Component + Eq> Pool<T> {
+    // Whether the pool's components are zero-sized, see above
+    pub fn is_tag(&self) -> bool {
+        std::mem::size_of::<T>() == 0
+    }
+}
+
+impl EntityStore {
+    // Marks the entity, making the pool if it's the first
+    pub fn tag<T: Component + Eq + Default + 'static>(&mut self, entity_id: EntityId) {
+        self.insert_default::<T>(entity_id);
+    }
+
+    pub fn untag<T: Component + Eq + 'static>(&mut self, entity_id: EntityId) {
+        self.remove_component::<T>(entity_id);
+    }
+
+    // Entities with the tag, in id order
+    pub fn tagged<T: Component + Eq + 'static>(&self) -> Vec<EntityId> {
+        self.presence(TypeId::of::<T>())
+            .map_or_else(Vec::new, |presence| presence.entities())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::RuleEngine;
+    use crate::registry::Fact;
+    use crate::value::Value;
+
+    #[derive(Debug, Default, PartialEq, Eq)]
+    struct Frozen;
+    impl Component for Frozen {}
+
+    impl Fact for Frozen {
+        const FIELDS: &'static [&'static str] = &[];
+
+        fn to_values(&self) -> Vec<Value> {
+            Vec::new()
+        }
+
+        fn from_values(_: &[Value]) -> Option<Self> {
+            Some(Frozen)
+        }
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Health(i64);
+    impl Component for Health {}
+
+    impl Fact for Health {
+        const FIELDS: &'static [&'static str] = &["hp"];
+
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.0)]
+        }
+
+        fn from_values(values: &[Value]) -> Option<Self> {
+            Some(Health(values.first()?.as_int()?))
+        }
+    }
+
+    #[test]
+    fn tags_store_only_presence() {
+        let mut store = EntityStore::new();
+        store.new_component::<Health>();
+        for entity_id in 0..1000 {
+            store.add_component(entity_id, Health(entity_id as i64));
+            if entity_id % 100 == 7 {
+                store.tag::<Frozen>(entity_id);
+            }
+        }
+        store.untag::<Frozen>(207);
+        assert_eq!(
+            store.tagged::<Frozen>(),
+            vec![7, 107, 307, 407, 507, 607, 707, 807, 907]
+        );
+
+        let pool = store.get::<Frozen>().unwrap().borrow();
+        assert!(pool.is_tag() && pool.capacity() < 1000);
+        drop(pool);
+        let bytes = |component: &str| {
+            let usage = store.memory_usage();
+            usage
+                .iter()
+                .find(|usage| usage.component == component)
+                .unwrap()
+                .bytes
+        };
+        // The same sparse page each, but only Health keeps a thousand of anything
+        assert!(bytes("Frozen") + 1000 * std::mem::size_of::<Health>() < bytes("Health"));
+
+        let mut engine = RuleEngine::new();
+        engine.register::<Frozen>("Frozen");
+        engine.register::<Health>("Health");
+        let found = engine
+            .query("Health(e, h), Frozen(e), h > 500", &store)
+            .unwrap();
+        assert_eq!(found.len(), 5);
+    }
+}
@@ -0,0 +1,257 @@
+// Async rule actions, behind the tokio feature
+//
+// An action that has to wait on something, a call to another service say, is defined
+// with define_async and run like a script, `then run "lookup"`. Firing spawns the
+// action's future on a tokio runtime with the rule's bindings and carries on without
+// waiting. The future answers with the commands to carry out, inserting and removing
+// facts, which queue up and are applied as the first stage of the next run or step,
+// when no rule is part way through firing.
+//
+// Futures run on the runtime given to set_runtime, or the one the engine is used from.
+// A future that fails, or a command the registry turns down, is reported as
+// StoreEvent::ActionFailed once applied, as for scripts, see script.rs, and as with
+// scripts the changes aren't traced or given provenance. settle_actions waits for every
+// action still running to answer, so a host can run again knowing its results are in.
+use crate::engine::RuleEngine;
+use crate::stats::StoreEvent;
+use crate::store::{EntityId, EntityStore};
+use crate::value::{Bindings, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+
+// A change for an async action to make once it's done
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Insert {
+        component: String,
+        entity: EntityId,
+        values: Vec<Value>,
+    },
+    Remove {
+        component: String,
+        entity: EntityId,
+    },
+}
+
+type Answer = Result<Vec<Command>, String>;
+type Action = Arc<dyn Fn(Bindings) -> Pin<Box<dyn Future<Output = Answer> + Send>> + Send + Sync>;
+
+// What a finished action sent back, with the rule that fired it
+type Done = (String, Answer);
+
+pub(crate) struct Tasks {
+    actions: HashMap<String, Action>,
+    runtime: Option<Handle>,
+    sender: mpsc::UnboundedSender<Done>,
+    receiver: mpsc::UnboundedReceiver<Done>,
+    // Spawned and not yet received
+    running: AtomicUsize,
+    // Received by settle_actions, waiting to be applied
+    arrived: Vec<Done>,
+}
+
+impl fmt::Debug for Tasks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Tasks")
+            .field("actions", &self.actions.keys().collect::<Vec<_>>())
+            .field("running", &self.running)
+            .finish()
+    }
+}
+
+impl Default for Tasks {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Tasks {
+            actions: HashMap::new(),
+            runtime: None,
+            sender,
+            receiver,
+            running: AtomicUsize::new(0),
+            arrived: Vec::new(),
+        }
+    }
+}
+
+impl Tasks {
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.actions.contains_key(name)
+    }
+
+    // Actions running or answered and not yet applied
+    pub(crate) fn pending(&self) -> bool {
+        self.running.load(Ordering::Relaxed) > 0 || !self.arrived.is_empty()
+    }
+
+    // Starts the named action on the runtime, reporting at once if there isn't one
+    pub(crate) fn spawn(
+        &self,
+        rule: &str,
+        name: &str,
+        bindings: &Bindings,
+        store: &mut EntityStore,
+    ) {
+        let Some(action) = self.actions.get(name) else {
+            return;
+        };
+        let Some(runtime) = self.runtime.clone().or_else(|| Handle::try_current().ok()) else {
+            store.emit(StoreEvent::ActionFailed {
+                rule: rule.to_string(),
+                message: format!("no tokio runtime to run `{}` on", name),
+            });
+            return;
+        };
+        let future = action(bindings.clone());
+        let sender = self.sender.clone();
+        let rule = rule.to_string();
+        self.running.fetch_add(1, Ordering::Relaxed);
+        runtime.spawn(async move {
+            let _ = sender.send((rule, future.await));
+        });
+    }
+}
+
+impl RuleEngine {
+    // Defines an action rules can run that answers later, see above
+    pub fn define_async<F, Fut>(&mut self, name: &str, action: F)
+    where
+        F: Fn(Bindings) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Vec<Command>, String>> + Send + 'static,
+    {
+        self.tasks.actions.insert(
+            name.to_string(),
+            Arc::new(move |bindings| Box::pin(action(bindings))),
+        );
+    }
+
+    pub fn set_runtime(&mut self, runtime: Handle) {
+        self.tasks.runtime = Some(runtime);
+    }
+
+    // Async actions started and not yet answered
+    pub fn running_actions(&self) -> usize {
+        self.tasks.running.load(Ordering::Relaxed)
+    }
+
+    // Waits until every running action has answered, to be applied by the next run or step
+    pub async fn settle_actions(&mut self) {
+        while self.running_actions() > 0 {
+            let Some(done) = self.tasks.receiver.recv().await else {
+                return;
+            };
+            self.tasks.running.fetch_sub(1, Ordering::Relaxed);
+            self.tasks.arrived.push(done);
+        }
+    }
+
+    // Carries out the commands of every action that has answered, in the order they did
+    pub(crate) fn apply_actions(&mut self, store: &mut EntityStore) {
+        let mut arrived = std::mem::take(&mut self.tasks.arrived);
+        while let Ok(done) = self.tasks.receiver.try_recv() {
+            self.tasks.running.fetch_sub(1, Ordering::Relaxed);
+            arrived.push(done);
+        }
+        for (rule, answer) in arrived {
+            let commands = match answer {
+                Ok(commands) => commands,
+                Err(message) => {
+                    store.emit(StoreEvent::ActionFailed { rule, message });
+                    continue;
+                }
+            };
+            for command in commands {
+                if let Err(message) = self.apply(&command, store) {
+                    store.emit(StoreEvent::ActionFailed {
+                        rule: rule.clone(),
+                        message,
+                    });
+                }
+            }
+        }
+    }
+
+    fn apply(&self, command: &Command, store: &mut EntityStore) -> Result<(), String> {
+        let (Command::Insert { component, .. } | Command::Remove { component, .. }) = command;
+        let info = self
+            .registry
+            .get(component)
+            .ok_or_else(|| format!("`{}` isn't a registered component", component))?;
+        match command {
+            Command::Insert { entity, values, .. } => {
+                store.reserve_up_to(*entity);
+                if !info.insert(store, *entity, values) {
+                    return Err(format!("{:?} don't fit `{}`", values, component));
+                }
+            }
+            Command::Remove { entity, .. } => info.remove(store, *entity),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn async_actions_apply_at_the_next_run() {
+        let mut engine = RuleEngine::new();
+        engine
+            .load_str(
+                r#"
+                fact Request(id)
+                fact Reply(id)
+                "#,
+            )
+            .unwrap();
+        engine.define_async("lookup", |bindings| async move {
+            tokio::task::yield_now().await;
+            let id = bindings["id"].clone();
+            match id {
+                Value::Int(0) => Err("no such id".to_string()),
+                _ => Ok(vec![Command::Insert {
+                    component: "Reply".to_string(),
+                    entity: bindings["e"].as_entity().unwrap(),
+                    values: vec![id],
+                }]),
+            }
+        });
+        engine
+            .load_str(r#"rule "ask" when Request(e, id), not Reply(e, _) then run "lookup""#)
+            .unwrap();
+        let registry = engine.registry().clone();
+        let mut store = EntityStore::new();
+        for (entity_id, id) in [(1, 7), (2, 0)] {
+            store
+                .insert_fact(&registry, "Request", entity_id, &[Value::Int(id)])
+                .unwrap();
+        }
+        let failed = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = failed.clone();
+        store.subscribe(move |event| {
+            if let StoreEvent::ActionFailed { message, .. } = event {
+                log.lock().unwrap().push(message.clone());
+            }
+        });
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        engine.set_runtime(runtime.handle().clone());
+        assert_eq!(engine.run(&mut store), 2);
+        runtime.block_on(engine.settle_actions());
+        assert_eq!(engine.running_actions(), 0);
+        assert!(registry.get("Reply").unwrap().get(&store, 1).is_none());
+
+        engine.run(&mut store);
+        assert_eq!(
+            registry.get("Reply").unwrap().get(&store, 1),
+            Some(vec![Value::Int(7)])
+        );
+        assert_eq!(*failed.lock().unwrap(), ["no such id"]);
+    }
+}
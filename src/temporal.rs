@@ -0,0 +1,163 @@
+// Temporal patterns, conditions on how long a pattern has held
+//
+// At the start of every run the engine looks at which entities each temporal pattern
+// matches on its own, remembering when each began to match and forgetting those that
+// stopped. A pattern holds continuously while it keeps matching the same entity, even
+// if field values change, so `Heat(e, t) for at least 5 ticks where t > 90` works.
+// Only the pattern's own constants and where clause count, so its where clause should
+// not depend on variables bound by earlier conditions.
+use crate::engine::RuleEngine;
+use crate::rule::{Condition, Temporal};
+use crate::store::EntityStore;
+use crate::time::Stamp;
+use crate::value::{Bindings, Value};
+use std::collections::HashSet;
+
+impl Temporal {
+    // Whether a pattern that began to hold at since satisfies this at now
+    // `since` binds its variable, failing if it is already bound to something else
+    pub fn holds(&self, since: Stamp, now: Stamp, bindings: &mut Bindings) -> bool {
+        match self {
+            Temporal::AtLeast(span) => span.passed(since, now),
+            Temporal::Within(span) => span.covers(since, now),
+            Temporal::Since(name) => {
+                let tick = Value::Int(since.tick as i64);
+                match bindings.get(name) {
+                    Some(bound) => *bound == tick,
+                    None => {
+                        bindings.insert(name.clone(), tick);
+                        true
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl RuleEngine {
+    pub(crate) fn observe(&mut self, store: &EntityStore) {
+        let mut holding = HashSet::new();
+        for (index, rule) in self.rules.iter().enumerate() {
+            for (position, condition) in rule.conditions.iter().enumerate() {
+                let (Condition::Pattern(pattern) | Condition::Not(pattern)) = condition else {
+                    continue;
+                };
+                if pattern.temporal.is_none() {
+                    continue;
+                }
+                let Some(info) = self.registry().get(&pattern.component) else {
+                    continue;
+                };
+                for (entity_id, fields) in info.facts(store) {
+                    let mut values = vec![Value::Entity(entity_id)];
+                    values.extend(fields.iter().cloned());
+                    let matched =
+                        pattern
+                            .unify(&values, &Bindings::new())
+                            .is_some_and(|bindings| {
+                                pattern.guard_holds(&bindings, info.fields, &fields)
+                            });
+                    if matched {
+                        holding.insert((index, position, entity_id));
+                    }
+                }
+            }
+        }
+
+        let now = self.now();
+        self.held.retain(|key, _| holding.contains(key));
+        for key in holding {
+            self.held.entry(key).or_insert(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::RuleEngine;
+    use crate::registry::{Fact, TypedFact};
+    use crate::store::{Component, EntityStore};
+    use crate::value::Value;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Heat(i64);
+    impl Component for Heat {}
+    impl Fact for Heat {
+        const FIELDS: &'static [&'static str] = &["degrees"];
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.0)]
+        }
+        fn from_values(values: &[Value]) -> Option<Self> {
+            match values {
+                [value] => Some(Heat(value.as_int()?)),
+                _ => None,
+            }
+        }
+    }
+    impl TypedFact for Heat {
+        type Fields = (i64,);
+        fn fields(&self) -> Self::Fields {
+            (self.0,)
+        }
+        fn from_fields((degrees,): Self::Fields) -> Self {
+            Heat(degrees)
+        }
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Shutdown(i64);
+    impl Component for Shutdown {}
+    impl Fact for Shutdown {
+        const FIELDS: &'static [&'static str] = &["overheated_at"];
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.0)]
+        }
+        fn from_values(values: &[Value]) -> Option<Self> {
+            match values {
+                [value] => Some(Shutdown(value.as_int()?)),
+                _ => None,
+            }
+        }
+    }
+    impl TypedFact for Shutdown {
+        type Fields = (i64,);
+        fn fields(&self) -> Self::Fields {
+            (self.0,)
+        }
+        fn from_fields((overheated_at,): Self::Fields) -> Self {
+            Shutdown(overheated_at)
+        }
+    }
+
+    #[test]
+    fn shutdown_after_five_hot_ticks() {
+        let mut engine = RuleEngine::new();
+        engine.register::<Heat>("Heat");
+        engine.register::<Shutdown>("Shutdown");
+        engine
+            .add_rule(crate::rule!(
+                "shutdown"
+                when Heat(e, _) for at least 5 ticks where degrees > 90,
+                    Heat(e, _) since t where degrees > 90
+                then insert Shutdown(e, t)
+            ))
+            .unwrap();
+
+        let mut store = EntityStore::new();
+        store.new_component::<Heat>();
+        let mut shutdown_after = |heats: &[i64]| {
+            for &heat in heats {
+                store.add_component(1, Heat(heat));
+                engine.run(&mut store);
+            }
+            store
+                .get::<Shutdown>()
+                .and_then(|pool| pool.borrow().get(1).map(|s| s.0))
+        };
+
+        // Cooling off on the fifth tick resets the count
+        assert_eq!(shutdown_after(&[95, 99, 93, 91, 50]), None);
+        assert_eq!(shutdown_after(&[91, 92, 93, 94, 95]), None);
+        assert_eq!(shutdown_after(&[96]), Some(5));
+    }
+}
@@ -0,0 +1,225 @@
+// Testing rule sets
+//
+// A Scenario gives a rule set its own tests, given/when/then style:
+//
+// Scenario::new(engine)
+//     .given("Health", 1, &[Value::Int(5)])
+//     .when_run()
+//     .then_fact("Fleeing", 1, &[])
+//     .then_fired("flee", 1);
+//
+// Given seeds the world with facts. When runs the whole engine, or with when_running
+// only the rules named, until nothing more is ready, and can be repeated to run it
+// again over what the last run left. Then asserts on what the runs derived and how often
+// each rule fired, panicking with what was found instead, so it reads as a failed test.
+//
+// A run firing more than the limit, 10000 by default, is taken to be stuck in a loop and
+// panics too.
+use crate::engine::RuleEngine;
+use crate::registry::ComponentInfo;
+use crate::rule::Rule;
+use crate::store::{EntityId, EntityStore};
+use crate::value::Value;
+use std::collections::HashMap;
+
+pub struct Scenario {
+    engine: RuleEngine,
+    store: EntityStore,
+    // How often each rule has fired, by name, over every run so far
+    fired: HashMap<String, usize>,
+    // Firings allowed in one run before it's taken to be looping
+    limit: usize,
+}
+
+impl Scenario {
+    pub fn new(engine: RuleEngine) -> Self {
+        Scenario {
+            engine,
+            store: EntityStore::new(),
+            fired: HashMap::new(),
+            limit: 10_000,
+        }
+    }
+
+    // A scenario over the rules in the source, panicking if they don't load
+    pub fn load(source: &str) -> Self {
+        let mut engine = RuleEngine::new();
+        if let Err(error) = engine.load_str(source) {
+            panic!("rules don't load: {}", error);
+        }
+        Self::new(engine)
+    }
+
+    pub fn limit(&mut self, limit: usize) -> &mut Self {
+        self.limit = limit;
+        self
+    }
+
+    pub fn engine(&mut self) -> &mut RuleEngine {
+        &mut self.engine
+    }
+
+    pub fn store(&mut self) -> &mut EntityStore {
+        &mut self.store
+    }
+
+    fn info(&self, component: &str) -> &ComponentInfo {
+        match self.engine.registry().get(component) {
+            Some(info) => info,
+            None => panic!("unknown component `{}`", component),
+        }
+    }
+
+    // Inserts a registered fact, the entity followed by the component's fields
+    pub fn given(&mut self, component: &str, entity_id: EntityId, values: &[Value]) -> &mut Self {
+        let registry = self.engine.registry().clone();
+        if let Err(error) = self
+            .store
+            .insert_fact(&registry, component, entity_id, values)
+        {
+            panic!("can't give {}({}): {}", component, entity_id, error);
+        }
+        self
+    }
+
+    pub fn when_run(&mut self) -> &mut Self {
+        self.fire()
+    }
+
+    // Runs only the rules named, the rest neither fire nor are matched
+    pub fn when_running(&mut self, rules: &[&str]) -> &mut Self {
+        if let Some(unknown) = rules
+            .iter()
+            .find(|name| !self.engine.rules().iter().any(|rule| rule.name == **name))
+        {
+            panic!("unknown rule \"{}\"", unknown);
+        }
+        let all = self.engine.rules().to_vec();
+        let only = all
+            .iter()
+            .filter(|rule| rules.contains(&rule.name.as_str()))
+            .cloned()
+            .collect();
+        self.replace(only);
+        self.fire();
+        self.replace(all);
+        self
+    }
+
+    fn replace(&mut self, rules: Vec<Rule>) {
+        if let Err(error) = self.engine.replace_rules(rules) {
+            panic!("rules don't check: {}", error);
+        }
+    }
+
+    fn fire(&mut self) -> &mut Self {
+        let mut fired = 0;
+        while let Some(step) = self.engine.step(&mut self.store) {
+            fired += 1;
+            if fired > self.limit {
+                panic!(
+                    "still firing after {} firings, rules may be looping",
+                    self.limit
+                );
+            }
+            *self.fired.entry(step.rule).or_default() += 1;
+        }
+        self
+    }
+
+    // Asserts the entity has the component with these field values
+    pub fn then_fact(
+        &mut self,
+        component: &str,
+        entity_id: EntityId,
+        values: &[Value],
+    ) -> &mut Self {
+        let rows = self.info(component).rows(&self.store, entity_id);
+        if !rows.iter().any(|row| row == values) {
+            panic!(
+                "expected {}({}) with {:?}, found {:?}",
+                component, entity_id, values, rows
+            );
+        }
+        self
+    }
+
+    // Asserts the entity doesn't have the component at all
+    pub fn then_absent(&mut self, component: &str, entity_id: EntityId) -> &mut Self {
+        let rows = self.info(component).rows(&self.store, entity_id);
+        if !rows.is_empty() {
+            panic!("expected no {}({}), found {:?}", component, entity_id, rows);
+        }
+        self
+    }
+
+    // Asserts how many facts of the component there are across every entity
+    pub fn then_count(&mut self, component: &str, count: usize) -> &mut Self {
+        let facts = self.info(component).facts(&self.store);
+        if facts.len() != count {
+            panic!(
+                "expected {} {} facts, found {}: {:?}",
+                count,
+                component,
+                facts.len(),
+                facts
+            );
+        }
+        self
+    }
+
+    // Asserts how often the rule has fired over every run so far
+    pub fn then_fired(&mut self, rule: &str, times: usize) -> &mut Self {
+        let fired = self.fired.get(rule).copied().unwrap_or(0);
+        if fired != times {
+            panic!(
+                "expected \"{}\" to fire {} times, it fired {}",
+                rule, times, fired
+            );
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scenarios_check_rule_sets() {
+        Scenario::load(
+            r#"
+            fact Health(points)
+            fact Fleeing()
+            fact Alarm()
+            rule "flee" when Health(e, h), h < 10, not Fleeing(e) then insert Fleeing(e)
+            rule "alarm" when Fleeing(e) then insert Alarm(e)
+            "#,
+        )
+        .given("Health", 1, &[Value::Int(5)])
+        .given("Health", 2, &[Value::Int(50)])
+        .when_running(&["flee"])
+        .then_fact("Fleeing", 1, &[])
+        .then_absent("Fleeing", 2)
+        .then_count("Alarm", 0)
+        .then_fired("flee", 1)
+        .when_run()
+        .then_count("Alarm", 1)
+        .then_fired("alarm", 1)
+        .then_fired("flee", 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "rules may be looping")]
+    fn loops_are_caught() {
+        Scenario::load(
+            r#"
+            fact Count(n)
+            rule "count" when Count(e, n) then insert Count(e, n + 1)
+            "#,
+        )
+        .given("Count", 1, &[Value::Int(0)])
+        .limit(50)
+        .when_run();
+    }
+}
@@ -0,0 +1,240 @@
+// Tile maps with adjacency kept up to date
+//
+// Tiles are entities with a Tile component, at most one to a cell. Placing and removing
+// them through place_tile and remove_tile also maintains a Neighbours component on
+// each tile listing the tiles in the eight cells around it, so finding neighbours never
+// scans the map. Registering the tiles with an engine lets rules place and remove them
+// and exposes the neighbour lists as a relation, `AdjacentTo(a, b)` holding once for
+// each neighbour b of a.
+//
+// Tiles added with add_component directly bypass all of this and get no neighbours,
+// and a tile should be taken off the map with remove_tile before remove_entity.
+use crate::registry::{Fact, TypedFact};
+use crate::store::{Component, EntityId, EntityStore};
+use crate::value::Value;
+
+// Which cell of the map a tile is in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Tile {
+    pub x: i64,
+    pub y: i64,
+}
+
+impl Tile {
+    pub fn new(x: i64, y: i64) -> Self {
+        Tile { x, y }
+    }
+
+    // The eight cells around this one
+    pub fn around(&self) -> impl Iterator<Item = Tile> + '_ {
+        (-1..=1)
+            .flat_map(|dx| (-1..=1).map(move |dy| (dx, dy)))
+            .filter(|&offset| offset != (0, 0))
+            .map(|(dx, dy)| Tile::new(self.x + dx, self.y + dy))
+    }
+}
+
+impl Component for Tile {}
+
+impl Fact for Tile {
+    const FIELDS: &'static [&'static str] = &["x", "y"];
+
+    fn to_values(&self) -> Vec<Value> {
+        vec![Value::Int(self.x), Value::Int(self.y)]
+    }
+
+    fn from_values(values: &[Value]) -> Option<Self> {
+        match values {
+            [x, y] => Some(Tile::new(x.as_int()?, y.as_int()?)),
+            _ => None,
+        }
+    }
+}
+
+impl TypedFact for Tile {
+    type Fields = (i64, i64);
+
+    fn fields(&self) -> Self::Fields {
+        (self.x, self.y)
+    }
+
+    fn from_fields((x, y): Self::Fields) -> Self {
+        Tile::new(x, y)
+    }
+}
+
+// The tiles adjacent to this one, in ascending order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Neighbours(pub Vec<EntityId>);
+
+impl Component for Neighbours {}
+
+impl EntityStore {
+    // Puts the entity's tile in a cell, moving it if it already has one
+    // A tile already in that cell is removed from the map first
+    pub fn place_tile(&mut self, entity_id: EntityId, tile: Tile) {
+        self.begin_step();
+        self.remove_tile(entity_id);
+        if let Some(&occupant) = self.tiles.get(&tile) {
+            self.remove_tile(occupant);
+        }
+        if self.get::<Tile>().is_none() {
+            self.new_component::<Tile>();
+            self.snapshot_component::<Tile>();
+        }
+        if self.get::<Neighbours>().is_none() {
+            self.new_component::<Neighbours>();
+            self.snapshot_component::<Neighbours>();
+        }
+
+        let neighbours: Vec<EntityId> = tile
+            .around()
+            .filter_map(|cell| self.tiles.get(&cell).copied())
+            .collect();
+        for &neighbour in &neighbours {
+            self.update_neighbours(neighbour, |list| list.push(entity_id));
+        }
+        self.reserve_up_to(entity_id);
+        self.tiles.insert(tile, entity_id);
+        self.add_component(entity_id, tile);
+        self.update_neighbours(entity_id, |list| *list = neighbours);
+        self.end_step();
+    }
+
+    // Takes the entity's tile off the map, along with its place in its neighbours' lists
+    pub fn remove_tile(&mut self, entity_id: EntityId) {
+        let Some(tile) = self
+            .get::<Tile>()
+            .and_then(|pool| pool.borrow().get(entity_id).copied())
+        else {
+            return;
+        };
+        self.begin_step();
+        if self.tiles.get(&tile) == Some(&entity_id) {
+            self.tiles.remove(&tile);
+        }
+        for neighbour in self.neighbours(entity_id) {
+            self.update_neighbours(neighbour, |list| list.retain(|&other| other != entity_id));
+        }
+        self.remove_component::<Neighbours>(entity_id);
+        self.remove_component::<Tile>(entity_id);
+        self.end_step();
+    }
+
+    // The tile in a cell, if there is one
+    pub fn tile_at(&self, tile: Tile) -> Option<EntityId> {
+        self.tiles.get(&tile).copied()
+    }
+
+    pub fn neighbours(&self, entity_id: EntityId) -> Vec<EntityId> {
+        self.get::<Neighbours>()
+            .and_then(|pool| pool.borrow().get(entity_id).map(|list| list.0.clone()))
+            .unwrap_or_default()
+    }
+
+    // Rebuilds the cell lookup from the Tile pool, after tiles were changed around it
+    pub(crate) fn reindex_tiles(&mut self) {
+        self.tiles = self
+            .get::<Tile>()
+            .map(|pool| {
+                pool.borrow()
+                    .components_iter()
+                    .map(|(&entity_id, &tile)| (tile, entity_id))
+                    .collect()
+            })
+            .unwrap_or_default();
+    }
+
+    // Replaces the list through add_component, so hooks and change tracking see it
+    fn update_neighbours(&mut self, entity_id: EntityId, update: impl FnOnce(&mut Vec<EntityId>)) {
+        let mut list = self.neighbours(entity_id);
+        update(&mut list);
+        list.sort_unstable();
+        self.add_component(entity_id, Neighbours(list));
+    }
+}
+
+// Registry hooks, so rules go through place_tile and remove_tile
+pub(crate) fn insert_tile(store: &mut EntityStore, entity_id: EntityId, values: &[Value]) -> bool {
+    match Tile::from_values(values) {
+        Some(tile) => {
+            store.place_tile(entity_id, tile);
+            true
+        }
+        None => false,
+    }
+}
+
+pub(crate) fn remove_tile(store: &mut EntityStore, entity_id: EntityId) {
+    store.remove_tile(entity_id);
+}
+
+// Each neighbour as its own AdjacentTo row
+pub(crate) fn adjacency_rows(store: &EntityStore, entity_id: EntityId) -> Vec<Vec<Value>> {
+    store
+        .neighbours(entity_id)
+        .into_iter()
+        .map(|neighbour| vec![Value::Entity(neighbour)])
+        .collect()
+}
+
+pub(crate) fn adjacency_facts(store: &EntityStore) -> Vec<(EntityId, Vec<Value>)> {
+    let Some(pool) = store.get::<Neighbours>() else {
+        return Vec::new();
+    };
+    let pool = pool.borrow();
+    pool.components_iter()
+        .flat_map(|(&entity_id, list)| {
+            list.0
+                .iter()
+                .map(move |&neighbour| (entity_id, vec![Value::Entity(neighbour)]))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::RuleEngine;
+
+    #[test]
+    fn adjacency_follows_the_map() {
+        let mut store = EntityStore::new();
+        for (entity_id, x) in [(1, 0), (2, 1), (3, 2)] {
+            store.place_tile(entity_id, Tile::new(x, 0));
+        }
+        store.place_tile(4, Tile::new(1, 1));
+        assert_eq!(store.neighbours(2), vec![1, 3, 4]);
+
+        // Moving a tile onto another's cell replaces it
+        store.place_tile(4, Tile::new(2, 0));
+        assert_eq!(store.tile_at(Tile::new(2, 0)), Some(4));
+        assert!(!store.has_component::<Tile>(3));
+        assert_eq!(store.neighbours(1), vec![2]);
+        assert_eq!(store.neighbours(2), vec![1, 4]);
+
+        let mut engine = RuleEngine::new();
+        engine.register_tiles("Tile", "AdjacentTo");
+        engine
+            .load_str(r#"rule "crumble" when Tile(b, 2, 0), AdjacentTo(a, b) then remove Tile(a)"#)
+            .unwrap();
+        let mut pairs: Vec<(EntityId, EntityId)> = engine
+            .query("AdjacentTo(a, b)", &store)
+            .unwrap()
+            .iter()
+            .map(|bindings| {
+                (
+                    bindings["a"].as_entity().unwrap(),
+                    bindings["b"].as_entity().unwrap(),
+                )
+            })
+            .collect();
+        pairs.sort();
+        assert_eq!(pairs, vec![(1, 2), (2, 1), (2, 4), (4, 2)]);
+
+        // Removing through a rule unlinks the tile too
+        engine.run(&mut store);
+        assert_eq!(store.tile_at(Tile::new(1, 0)), None);
+        assert!(store.neighbours(1).is_empty() && store.neighbours(4).is_empty());
+    }
+}
@@ -0,0 +1,141 @@
+// Time as rules see it
+//
+// The engine counts ticks, one per call to run, and keeps a clock. The clock is the
+// wall clock by default, but can be set by hand so simulations and tests get the same
+// results however fast they run. Rules measure time in either: `5 ticks`, `60s`, `250ms`.
+//
+// The engine shares its time with the stores it runs over, so the changes they record
+// are stamped when they're made rather than when the engine gets to them.
+use crate::engine::RuleEngine;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clock {
+    // Time since the engine was created
+    Wall(Instant),
+    // Time as last set by set_time
+    Manual(Duration),
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Clock::Wall(Instant::now())
+    }
+}
+
+impl Clock {
+    pub fn now(&self) -> Duration {
+        match self {
+            Clock::Wall(start) => start.elapsed(),
+            Clock::Manual(now) => *now,
+        }
+    }
+}
+
+// When something happened, in both measures of time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Stamp {
+    pub tick: u64,
+    pub time: Duration,
+}
+
+// The engine's tick and clock, as last shared with stores
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SharedTime(Arc<Mutex<(u64, Clock)>>);
+
+impl SharedTime {
+    fn set(&self, tick: u64, clock: Clock) {
+        *self
+            .0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = (tick, clock);
+    }
+
+    pub(crate) fn now(&self) -> Stamp {
+        let (tick, clock) = *self
+            .0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        Stamp {
+            tick,
+            time: clock.now(),
+        }
+    }
+}
+
+// A length of time in a rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Span {
+    Ticks(u64),
+    Time(Duration),
+}
+
+impl Span {
+    // How long ago since was, in this span's units
+    fn elapsed(&self, since: Stamp, now: Stamp) -> Span {
+        match self {
+            Span::Ticks(_) => Span::Ticks(now.tick.saturating_sub(since.tick)),
+            Span::Time(_) => Span::Time(now.time.saturating_sub(since.time)),
+        }
+    }
+
+    // True once this span has passed since since
+    pub fn passed(&self, since: Stamp, now: Stamp) -> bool {
+        match (self, self.elapsed(since, now)) {
+            (Span::Ticks(span), Span::Ticks(elapsed)) => elapsed >= *span,
+            (Span::Time(span), Span::Time(elapsed)) => elapsed >= *span,
+            _ => unreachable!("elapsed is measured in the span's own units"),
+        }
+    }
+
+    // True if since is no more than this span before now
+    pub fn covers(&self, since: Stamp, now: Stamp) -> bool {
+        match (self, self.elapsed(since, now)) {
+            (Span::Ticks(span), Span::Ticks(elapsed)) => elapsed <= *span,
+            (Span::Time(span), Span::Time(elapsed)) => elapsed <= *span,
+            _ => unreachable!("elapsed is measured in the span's own units"),
+        }
+    }
+}
+
+impl RuleEngine {
+    // Number of completed calls to run
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    pub fn clock(&self) -> Clock {
+        self.clock
+    }
+
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+        self.share_time();
+    }
+
+    // Switches to a manual clock reading now
+    pub fn set_time(&mut self, now: Duration) {
+        self.clock = Clock::Manual(now);
+        self.share_time();
+    }
+
+    // Moves a manual clock forward, a wall clock is switched to manual at its current time
+    pub fn advance(&mut self, by: Duration) {
+        self.clock = Clock::Manual(self.clock.now() + by);
+        self.share_time();
+    }
+
+    // Called whenever the tick or clock changes, see above
+    pub(crate) fn share_time(&self) {
+        self.time.set(self.tick, self.clock);
+    }
+
+    pub fn now(&self) -> Stamp {
+        Stamp {
+            tick: self.tick,
+            time: self.clock.now(),
+        }
+    }
+}
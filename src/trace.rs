@@ -0,0 +1,260 @@
+// Opt in audit log of rule firings
+//
+// Once enabled, every firing is recorded with the tick it happened in, the rule's
+// bindings and what its actions changed. Firings are kept in memory to be read back
+// later, streamed to subscribers as they happen, or both.
+use crate::bus::EventBus;
+use crate::engine::RuleEngine;
+use crate::store::EntityId;
+use crate::value::{Bindings, Value};
+use std::fmt;
+use std::time::SystemTime;
+
+// A change made to the store by a rule action
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mutation {
+    Inserted {
+        component: String,
+        entity: EntityId,
+        values: Vec<Value>,
+    },
+    // The values the component had when it was removed
+    Removed {
+        component: String,
+        entity: EntityId,
+        values: Vec<Value>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Firing {
+    // Which call to RuleEngine::run this happened in, see RuleEngine::tick
+    pub tick: u64,
+    // Position in the trace, across all ticks
+    pub sequence: u64,
+    pub time: SystemTime,
+    pub rule: String,
+    pub bindings: Bindings,
+    pub mutations: Vec<Mutation>,
+}
+
+#[derive(Debug)]
+pub struct Trace {
+    sequence: u64,
+    // When false firings are only streamed, so long runs don't grow memory
+    keep_log: bool,
+    log: Vec<Firing>,
+    sinks: EventBus<Firing>,
+}
+
+impl Default for Trace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Trace {
+    pub fn new() -> Self {
+        Trace {
+            sequence: 0,
+            keep_log: true,
+            log: Vec::new(),
+            sinks: EventBus::new(),
+        }
+    }
+
+    pub fn keep_log(&mut self, keep: bool) -> &mut Self {
+        self.keep_log = keep;
+        self
+    }
+
+    // Streams each firing to the sink as it is recorded
+    pub fn subscribe(&mut self, sink: impl FnMut(&Firing) + Send + Sync + 'static) -> &mut Self {
+        self.sinks.subscribe(sink);
+        self
+    }
+
+    // Firings kept so far, oldest first
+    pub fn log(&self) -> &[Firing] {
+        &self.log
+    }
+
+    // Takes the kept firings, leaving the log empty
+    pub fn drain(&mut self) -> Vec<Firing> {
+        std::mem::take(&mut self.log)
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        tick: u64,
+        rule: &str,
+        bindings: &Bindings,
+        mutations: Vec<Mutation>,
+    ) {
+        let firing = Firing {
+            tick,
+            sequence: self.sequence,
+            time: SystemTime::now(),
+            rule: rule.to_string(),
+            bindings: bindings.clone(),
+            mutations,
+        };
+        self.sequence += 1;
+        self.sinks.emit(&firing);
+        if self.keep_log {
+            self.log.push(firing);
+        }
+    }
+}
+
+impl RuleEngine {
+    // Starts recording firings, keeping any trace that is already enabled
+    pub fn enable_trace(&mut self) -> &mut Trace {
+        self.trace.get_or_insert_with(Trace::new)
+    }
+
+    // Stops recording, handing back what was recorded
+    pub fn disable_trace(&mut self) -> Option<Trace> {
+        self.trace.take()
+    }
+
+    pub fn trace(&self) -> Option<&Trace> {
+        self.trace.as_ref()
+    }
+
+    pub fn trace_mut(&mut self) -> Option<&mut Trace> {
+        self.trace.as_mut()
+    }
+}
+
+impl fmt::Display for Mutation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (verb, component, entity, values) = match self {
+            Mutation::Inserted {
+                component,
+                entity,
+                values,
+            } => ("insert", component, entity, values),
+            Mutation::Removed {
+                component,
+                entity,
+                values,
+            } => ("remove", component, entity, values),
+        };
+        write!(f, "{} {}(#{}", verb, component, entity)?;
+        for value in values {
+            write!(f, ", {}", value)?;
+        }
+        write!(f, ")")
+    }
+}
+
+// One line per firing, e.g. `3.0 "flee" e = #1, h = 5: insert Fleeing(#1)`
+impl fmt::Display for Firing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bindings: Vec<String> = self
+            .bindings
+            .iter()
+            .map(|(name, value)| format!("{} = {}", name, value))
+            .collect();
+        let mutations: Vec<String> = self.mutations.iter().map(Mutation::to_string).collect();
+        write!(
+            f,
+            "{}.{} {:?} {}: {}",
+            self.tick,
+            self.sequence,
+            self.rule,
+            bindings.join(", "),
+            mutations.join(", ")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cell::AtomicRefCell;
+    use crate::registry::Fact;
+    use crate::store::{Component, EntityStore};
+    use std::sync::Arc;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Health(i64);
+    impl Component for Health {}
+    impl Fact for Health {
+        const FIELDS: &'static [&'static str] = &["value"];
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.0)]
+        }
+        fn from_values(values: &[Value]) -> Option<Self> {
+            match values {
+                [value] => Some(Health(value.as_int()?)),
+                _ => None,
+            }
+        }
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Fleeing;
+    impl Component for Fleeing {}
+    impl Fact for Fleeing {
+        const FIELDS: &'static [&'static str] = &[];
+        fn to_values(&self) -> Vec<Value> {
+            Vec::new()
+        }
+        fn from_values(values: &[Value]) -> Option<Self> {
+            values.is_empty().then_some(Fleeing)
+        }
+    }
+
+    #[test]
+    fn records_and_streams_firings() {
+        let mut engine = RuleEngine::new();
+        engine.register::<Health>("Health");
+        engine.register::<Fleeing>("Fleeing");
+        engine
+            .load_str(
+                r#"
+                rule "flee" when Health(e, h), h < 10, not Fleeing(e) then insert Fleeing(e)
+                rule "calm" when Fleeing(e), Health(e, h), h >= 10 then remove Fleeing(e)
+                "#,
+            )
+            .unwrap();
+
+        let streamed = Arc::new(AtomicRefCell::new(Vec::new()));
+        let sink = streamed.clone();
+        engine
+            .enable_trace()
+            .subscribe(move |firing| sink.borrow_mut().push(firing.to_string()));
+
+        let mut store = EntityStore::new();
+        store.new_component::<Health>();
+        store.add_component(1, Health(5));
+        engine.run(&mut store);
+        store.add_component(1, Health(50));
+        engine.run(&mut store);
+
+        assert_eq!(
+            *streamed.borrow(),
+            [
+                "0.0 \"flee\" e = #1, h = 5: insert Fleeing(#1)",
+                "1.1 \"calm\" e = #1, h = 50: remove Fleeing(#1)",
+            ]
+        );
+        let log = engine.trace().unwrap().log();
+        assert_eq!(log.len(), 2);
+        assert_eq!(
+            log[1].mutations,
+            [Mutation::Removed {
+                component: "Fleeing".into(),
+                entity: 1,
+                values: Vec::new(),
+            }]
+        );
+
+        let trace = engine.disable_trace().unwrap();
+        assert_eq!(trace.log().len(), 2);
+        engine.run(&mut store);
+        assert!(engine.trace().is_none());
+    }
+}
@@ -0,0 +1,157 @@
+// Facts with a time to live
+//
+// A fact inserted with a time to live is removed by the engine at the start of the
+// first run after it has expired, and the rules are matched again without it. The fact
+// is only removed if it still has the value it was inserted with, so replacing it in
+// the meantime keeps the new value.
+use crate::engine::RuleEngine;
+use crate::registry::Fact;
+use crate::store::{EntityId, EntityStore};
+use crate::time::{Span, Stamp};
+use crate::value::Value;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expiry {
+    pub inserted: Stamp,
+    pub ttl: Span,
+    pub values: Vec<Value>,
+}
+
+impl RuleEngine {
+    // Adds the component now and removes it once ttl has passed
+    // T must be registered, otherwise this returns false and nothing is added
+    pub fn insert_for<T: Fact>(
+        &mut self,
+        store: &mut EntityStore,
+        entity_id: EntityId,
+        component: T,
+        ttl: Span,
+    ) -> bool {
+        let Some(info) = self.registry().get_type::<T>() else {
+            return false;
+        };
+        let values = component.to_values();
+        let key = (info.name.clone(), entity_id);
+        if store.get::<T>().is_none() {
+            store.new_component::<T>();
+        }
+        store.reserve_up_to(entity_id);
+        store.add_component(entity_id, component);
+        let expiry = Expiry {
+            inserted: self.now(),
+            ttl,
+            values,
+        };
+        self.expiries.insert(key, expiry);
+        true
+    }
+
+    // When the entity's component will be removed, None if it has no time to live
+    pub fn expiry(&self, component: &str, entity_id: EntityId) -> Option<&Expiry> {
+        self.expiries.get(&(component.to_string(), entity_id))
+    }
+
+    // Removes every expired fact, returning how many were removed
+    pub(crate) fn expire(&mut self, store: &mut EntityStore) -> usize {
+        let now = self.now();
+        let mut expired: Vec<((String, EntityId), Expiry)> = self
+            .expiries
+            .iter()
+            .filter(|(_, expiry)| expiry.ttl.passed(expiry.inserted, now))
+            .map(|(key, expiry)| (key.clone(), expiry.clone()))
+            .collect();
+        // Removed in a fixed order, not the map's, so runs can be replayed exactly
+        expired.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut removed = 0;
+        for ((component, entity_id), expiry) in expired {
+            self.expiries.remove(&(component.clone(), entity_id));
+            let Some(info) = self.registry().get(&component) else {
+                continue;
+            };
+            if info.get(store, entity_id) == Some(expiry.values.clone()) {
+                info.remove(store, entity_id);
+                self.provenance
+                    .remove(&(component, entity_id, expiry.values));
+                removed += 1;
+            }
+        }
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Component;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Stunned;
+    impl Component for Stunned {}
+    impl Fact for Stunned {
+        const FIELDS: &'static [&'static str] = &[];
+        fn to_values(&self) -> Vec<Value> {
+            Vec::new()
+        }
+        fn from_values(values: &[Value]) -> Option<Self> {
+            values.is_empty().then_some(Stunned)
+        }
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Hit(i64);
+    impl Component for Hit {}
+    impl Fact for Hit {
+        const FIELDS: &'static [&'static str] = &["damage"];
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.0)]
+        }
+        fn from_values(values: &[Value]) -> Option<Self> {
+            match values {
+                [value] => Some(Hit(value.as_int()?)),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn facts_expire_and_rules_rematch() {
+        let mut engine = RuleEngine::new();
+        engine.register::<Stunned>("Stunned");
+        engine.register::<Hit>("Hit");
+        engine
+            .load_str(
+                r#"rule "stun" when Hit(e, d), d > 10, not Stunned(e) then insert Stunned(e) for 2 ticks"#,
+            )
+            .unwrap();
+
+        let mut store = EntityStore::new();
+        engine.insert_for(&mut store, 1, Hit(20), Span::Ticks(3));
+        assert_eq!(engine.run(&mut store), 1);
+        assert_eq!(engine.expiry("Stunned", 1).unwrap().ttl, Span::Ticks(2));
+
+        // Stunned expires at the start of the third run and the still present hit restuns
+        assert_eq!(engine.run(&mut store), 0);
+        assert_eq!(engine.run(&mut store), 1);
+        assert!(store.has_component::<Stunned>(1));
+
+        // The hit itself goes next, and the stun with it after two more
+        assert_eq!(engine.run(&mut store), 0);
+        assert!(!store.has_component::<Hit>(1));
+        engine.run(&mut store);
+        assert!(!store.has_component::<Stunned>(1));
+        assert!(engine.expiry("Stunned", 1).is_none());
+    }
+
+    #[test]
+    fn replaced_facts_are_kept() {
+        let mut engine = RuleEngine::new();
+        engine.register::<Hit>("Hit");
+        let mut store = EntityStore::new();
+        engine.insert_for(&mut store, 1, Hit(1), Span::Ticks(1));
+        store.add_component(1, Hit(2));
+        engine.run(&mut store);
+        engine.run(&mut store);
+        assert!(store.has_component::<Hit>(1));
+    }
+}
@@ -0,0 +1,329 @@
+// Materialized views
+//
+// An expensive join many rules share can be worked out once and kept as a component of
+// its own:
+//
+// engine.define_view("Threat", &["target", "attacker"],
+//     "Attacks(attacker, target), Hostile(attacker)")
+//
+// The first variable is the entity each row goes on, the rest are its fields, named
+// after them. Rules then match Threat(t, a) like any other component, and an entity can
+// have any number of rows, as with relations. Answers whose first variable isn't an
+// entity are left out.
+//
+// The engine brings views up to date before each match, working through only what
+// changed in the pools a view reads since, see incremental.rs. Only the entities whose
+// rows came or went are written, so the view's own change ticks, indexes and hooks see
+// real changes only. Views are brought up to date in the order they were
+// defined, so one reading another is defined after it. Rules can't insert or remove
+// view facts, and like other derived components views aren't saved.
+//
+// Each view name is given one of VIEW_SLOTS pool types for the life of the process, as
+// dynamic components are, see dynamic.rs.
+use crate::engine::RuleEngine;
+use crate::error::Error;
+use crate::incremental::Maintained;
+use crate::query::Query;
+use crate::registry::{FactRow, Registry};
+use crate::rule::{Condition, Rule};
+use crate::store::{Component, EntityId, EntityStore};
+use crate::value::Value;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::sync::{Mutex, OnceLock};
+
+pub const VIEW_SLOTS: usize = 16;
+
+// Every row of the view on one entity
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ViewRows<const SLOT: usize>(pub Vec<Vec<Value>>);
+
+impl<const SLOT: usize> Component for ViewRows<SLOT> {}
+
+type Rows = BTreeMap<EntityId, Vec<Vec<Value>>>;
+
+struct Slot {
+    register: fn(&mut Registry, &str, &'static [&'static str]),
+    write: fn(&mut EntityStore, Rows),
+    put: fn(&mut EntityStore, EntityId, Vec<Vec<Value>>),
+}
+
+macro_rules! slots {
+    ($($slot:literal)*) => {
+        [$(Slot {
+            register: Registry::register_view::<$slot>,
+            write: write::<$slot>,
+            put: put::<$slot>,
+        },)*]
+    };
+}
+
+const SLOTS: [Slot; VIEW_SLOTS] = slots!(0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15);
+
+// Names given slots so far, by slot
+fn taken() -> &'static Mutex<Vec<String>> {
+    static TAKEN: OnceLock<Mutex<Vec<String>>> = OnceLock::new();
+    TAKEN.get_or_init(Default::default)
+}
+
+fn slot(name: &str) -> Option<usize> {
+    let mut taken = taken()
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(slot) = taken.iter().position(|other| other == name) {
+        return Some(slot);
+    }
+    (taken.len() < VIEW_SLOTS).then(|| {
+        taken.push(name.to_string());
+        taken.len() - 1
+    })
+}
+
+pub(crate) fn view_facts<const SLOT: usize>(store: &EntityStore) -> Vec<FactRow> {
+    let Some(pool) = store.get::<ViewRows<SLOT>>() else {
+        return Vec::new();
+    };
+    let pool = pool.borrow();
+    pool.components_iter()
+        .flat_map(|(&entity_id, rows)| rows.0.iter().map(move |row| (entity_id, row.clone())))
+        .collect()
+}
+
+pub(crate) fn view_rows<const SLOT: usize>(
+    store: &EntityStore,
+    entity_id: EntityId,
+) -> Vec<Vec<Value>> {
+    let Some(pool) = store.get::<ViewRows<SLOT>>() else {
+        return Vec::new();
+    };
+    let pool = pool.borrow();
+    pool.get(entity_id)
+        .map(|rows| rows.0.clone())
+        .unwrap_or_default()
+}
+
+pub(crate) fn view_get<const SLOT: usize>(
+    store: &EntityStore,
+    entity_id: EntityId,
+) -> Option<Vec<Value>> {
+    view_rows::<SLOT>(store, entity_id).into_iter().next()
+}
+
+// Writes the entities whose rows differ from the pool's, and removes those with none
+fn write<const SLOT: usize>(store: &mut EntityStore, mut rows: Rows) {
+    if store.get::<ViewRows<SLOT>>().is_none() {
+        store.new_component::<ViewRows<SLOT>>();
+    }
+    let mut gone = Vec::new();
+    if let Some(pool) = store.get::<ViewRows<SLOT>>() {
+        for (entity_id, had) in pool.borrow().components_iter() {
+            match rows.get(entity_id) {
+                Some(now) if *now == had.0 => {
+                    rows.remove(entity_id);
+                }
+                Some(_) => {}
+                None => gone.push(*entity_id),
+            }
+        }
+    }
+    for entity_id in gone {
+        store.remove_component::<ViewRows<SLOT>>(entity_id);
+    }
+    for (entity_id, now) in rows {
+        store.reserve_up_to(entity_id);
+        store.add_component(entity_id, ViewRows::<SLOT>(now));
+    }
+}
+
+// Writes one entity's rows, removing its component if it has none
+fn put<const SLOT: usize>(store: &mut EntityStore, entity_id: EntityId, rows: Vec<Vec<Value>>) {
+    if rows.is_empty() {
+        store.remove_component::<ViewRows<SLOT>>(entity_id);
+        return;
+    }
+    store.reserve_up_to(entity_id);
+    store.add_component(entity_id, ViewRows::<SLOT>(rows));
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct View {
+    name: String,
+    slot: usize,
+    maintained: Maintained,
+    rows: Rows,
+    // The pool's version once last written, to notice it was changed from outside or
+    // belongs to another store, when the whole view is written again
+    written: Option<(u64, usize)>,
+}
+
+impl RuleEngine {
+    // Keeps the answers to the conditions as a component, see above
+    // Defining a view again replaces its conditions and fields
+    pub fn define_view(
+        &mut self,
+        name: &str,
+        head: &[&str],
+        conditions: &str,
+    ) -> Result<(), Error> {
+        if head.is_empty() {
+            return Err(Error::View(format!("`{}` has no entity variable", name)));
+        }
+        let existing = self.views.iter().position(|view| view.name == name);
+        if existing.is_none() && self.registry.get(name).is_some() {
+            return Err(Error::View(format!(
+                "`{}` is already a registered component",
+                name
+            )));
+        }
+        let query = Query::parse(conditions)?;
+        let mut rule = Rule::new(name);
+        rule.conditions = query.conditions.clone();
+        self.check_from(&rule, HashSet::new())?;
+        let bound: Vec<&str> = rule
+            .conditions
+            .iter()
+            .filter_map(|condition| match condition {
+                Condition::Pattern(pattern) => Some(pattern.vars()),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        if let Some(variable) = head.iter().find(|variable| !bound.contains(variable)) {
+            return Err(Error::UnboundVariable {
+                rule: name.to_string(),
+                variable: variable.to_string(),
+            });
+        }
+
+        let slot = slot(name).ok_or_else(|| {
+            Error::View(format!(
+                "no slot left for `{}`, {} are taken",
+                name, VIEW_SLOTS
+            ))
+        })?;
+        // Kept for the life of the process, as the names of compiled fields are
+        let fields: Vec<&'static str> = head[1..]
+            .iter()
+            .map(|field| &*Box::leak(field.to_string().into_boxed_str()))
+            .collect();
+        (SLOTS[slot].register)(
+            &mut self.registry,
+            name,
+            Box::leak(fields.into_boxed_slice()),
+        );
+        let head = head.iter().map(|variable| variable.to_string()).collect();
+        let view = View {
+            name: name.to_string(),
+            slot,
+            maintained: Maintained::new(query.conditions, head),
+            rows: Rows::new(),
+            written: None,
+        };
+        match existing {
+            Some(index) => self.views[index] = view,
+            None => self.views.push(view),
+        }
+        Ok(())
+    }
+
+    // Brings every view up to date with the pools it reads, see above
+    // Run and step do this before each match, hosts reading views between runs can too
+    pub fn refresh_views(&mut self, store: &mut EntityStore) {
+        let mut views = std::mem::take(&mut self.views);
+        for view in &mut views {
+            let delta = view.maintained.refresh(self, store);
+            let mut touched = BTreeSet::new();
+            for (entity_id, row) in delta.removed {
+                if let Some(rows) = view.rows.get_mut(&entity_id) {
+                    rows.retain(|other| *other != row);
+                    if rows.is_empty() {
+                        view.rows.remove(&entity_id);
+                    }
+                }
+                touched.insert(entity_id);
+            }
+            for (entity_id, row) in delta.added {
+                view.rows.entry(entity_id).or_default().push(row);
+                touched.insert(entity_id);
+            }
+
+            let version = |store: &EntityStore| self.registry.get(&view.name)?.version(store);
+            let slot = &SLOTS[view.slot];
+            if view.written.is_some() && version(store) == view.written {
+                for entity_id in touched {
+                    let rows = view.rows.get(&entity_id).cloned().unwrap_or_default();
+                    (slot.put)(store, entity_id, rows);
+                }
+            } else {
+                (slot.write)(store, view.rows.clone());
+            }
+            view.written = version(store);
+        }
+        self.views = views;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn views_follow_the_pools_they_join() {
+        let mut engine = RuleEngine::new();
+        engine
+            .load_str(
+                r#"
+                fact Attacks(target)
+                fact Hostile()
+                fact Alarm(count)
+                "#,
+            )
+            .unwrap();
+        engine
+            .define_view(
+                "Threat",
+                &["target", "attacker"],
+                "Attacks(attacker, target), Hostile(attacker)",
+            )
+            .unwrap();
+        engine
+            .load_str(r#"rule "alarm" when Threat(t, a) then insert Alarm(t, a)"#)
+            .unwrap();
+        let registry = engine.registry().clone();
+        let mut store = EntityStore::new();
+        for (attacker, target) in [(1, 10), (2, 10), (3, 11)] {
+            store
+                .insert_fact(&registry, "Attacks", attacker, &[Value::Entity(target)])
+                .unwrap();
+        }
+        for attacker in [1, 2] {
+            store
+                .insert_fact(&registry, "Hostile", attacker, &[])
+                .unwrap();
+        }
+        engine.run(&mut store);
+
+        let threat = registry.get("Threat").unwrap();
+        assert_eq!(
+            threat.rows(&store, 10),
+            [vec![Value::Entity(1)], vec![Value::Entity(2)]]
+        );
+        assert!(threat.rows(&store, 11).is_empty());
+        assert_eq!(engine.query("Alarm(t, a)", &store).unwrap().len(), 1);
+
+        store.insert_fact(&registry, "Hostile", 3, &[]).unwrap();
+        engine.refresh_views(&mut store);
+        assert_eq!(threat.rows(&store, 11), [vec![Value::Entity(3)]]);
+        registry.get("Attacks").unwrap().remove(&mut store, 1);
+        engine.refresh_views(&mut store);
+        assert_eq!(threat.rows(&store, 10), [vec![Value::Entity(2)]]);
+
+        assert!(matches!(
+            engine.define_view("Nobody", &["x", "y"], "Hostile(x)"),
+            Err(Error::UnboundVariable { .. })
+        ));
+        assert!(matches!(
+            engine.define_view("Hostile", &["x"], "Hostile(x)"),
+            Err(Error::View(_))
+        ));
+    }
+}
@@ -0,0 +1,400 @@
+// Write-ahead log of store changes, for recovering after a crash
+//
+// A Wal takes each Change to a registered component as the store makes it, carrying the
+// fields it was made with, and writes and flushes a record for it there and then: the
+// new fields for an insert or replace, just the entity for a removal. A crash straight
+// after a change loses nothing the writer had flushed. A write that fails leaves the log
+// broken, so nothing more is written and the next sync returns the error.
+//
+// A component changed in place, through a pool's get_mut or components_mut, makes no
+// Change, so those are only logged when sync is called, which logs every fact its pool
+// has marked changed since the last sync, with the fields it has then. The same goes
+// for components registered after the Wal was made, whose fields it can't read as they
+// change. Calling sync after each engine run keeps those at most one step behind.
+//
+// To recover, load the last snapshot and replay the log written since it was taken.
+// Records are framed with their length, so a record cut short by a crash mid-write is
+// dropped rather than failing the replay. Records use the value encoding of binary.rs.
+use crate::binary::{write_bytes, write_value, write_varint, Reader};
+use crate::error::Error;
+use crate::reactive::{Change, ChangeKind};
+use crate::registry::Registry;
+use crate::store::{EntityId, EntityStore};
+use crate::value::Value;
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+const INSERT: u8 = 0;
+const REMOVE: u8 = 1;
+
+// Where the store hands each change as it's made, see store.rs
+pub(crate) trait ChangeLog: Send {
+    fn log(&mut self, change: &Change);
+}
+
+struct Log<W> {
+    // Only taken by into_inner
+    out: Option<W>,
+    // The registered components the Wal was made with
+    names: HashMap<TypeId, String>,
+    // What was last logged for each fact since the last sync, so it isn't logged again
+    // as changed in place
+    logged: HashMap<(TypeId, EntityId), Option<Vec<Value>>>,
+    // Changes to components the Wal can't read, to log as they are at the next sync
+    unread: Vec<(TypeId, EntityId)>,
+    // Records written since the last sync
+    count: usize,
+    failed: Option<io::Error>,
+}
+
+impl<W: Write> Log<W> {
+    fn write(
+        &mut self,
+        name: &str,
+        type_id: TypeId,
+        entity_id: EntityId,
+        values: Option<Vec<Value>>,
+    ) {
+        let Some(out) = self.out.as_mut().filter(|_| self.failed.is_none()) else {
+            return;
+        };
+        let mut frame = Vec::new();
+        write_record(&mut frame, name, entity_id, values.as_deref());
+        match out.write_all(&frame).and_then(|()| out.flush()) {
+            Ok(()) => {
+                self.logged.insert((type_id, entity_id), values);
+                self.count += 1;
+            }
+            Err(error) => self.failed = Some(error),
+        }
+    }
+}
+
+impl<W: Write + Send> ChangeLog for Log<W> {
+    fn log(&mut self, change: &Change) {
+        let (Some(name), ChangeKind::Inserted(Some(_)) | ChangeKind::Removed(_)) =
+            (self.names.get(&change.component).cloned(), &change.kind)
+        else {
+            self.unread.push((change.component, change.entity));
+            return;
+        };
+        let values = match &change.kind {
+            ChangeKind::Inserted(values) => values.clone(),
+            ChangeKind::Removed(_) => None,
+        };
+        self.write(&name, change.component, change.entity, values);
+    }
+}
+
+fn lock<W>(log: &Mutex<Log<W>>) -> MutexGuard<'_, Log<W>> {
+    log.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+pub struct Wal<W: Write> {
+    log: Arc<Mutex<Log<W>>>,
+    // Each component's pool change tick as of the last sync, to find changes made in place
+    ticks: HashMap<TypeId, u64>,
+}
+
+impl<W: Write + Send + 'static> Wal<W> {
+    // Turns on change tracking, only changes from now on are logged
+    pub fn new(out: W, registry: &Registry, store: &mut EntityStore) -> Self {
+        store.track_changes();
+        store.read_fields_with(registry);
+        let log = Arc::new(Mutex::new(Log {
+            out: Some(out),
+            names: registry
+                .iter()
+                .filter(|info| !info.is_derived())
+                .map(|info| (info.type_id, info.name.clone()))
+                .collect(),
+            logged: HashMap::new(),
+            unread: Vec::new(),
+            count: 0,
+            failed: None,
+        }));
+        let shared: Arc<Mutex<dyn ChangeLog>> = log.clone();
+        store.change_logs.retain(|log| log.strong_count() > 0);
+        store.change_logs.push(Arc::downgrade(&shared));
+        let ticks = registry
+            .iter()
+            .filter_map(|info| Some((info.type_id, info.version(store)?.0)))
+            .collect();
+        Wal { log, ticks }
+    }
+}
+
+impl<W: Write> Wal<W> {
+    // Logs what couldn't be as it happened, see above, returning how many records were
+    // written since the last sync, or the error that broke the log
+    pub fn sync(&mut self, registry: &Registry, store: &EntityStore) -> io::Result<usize> {
+        let mut log = lock(&self.log);
+        for (type_id, entity_id) in std::mem::take(&mut log.unread) {
+            let Some(info) = registry
+                .get_type_id(type_id)
+                .filter(|info| !info.is_derived())
+            else {
+                continue;
+            };
+            let values = info.get(store, entity_id);
+            log.write(&info.name, type_id, entity_id, values);
+        }
+
+        for info in registry.iter().filter(|info| !info.is_derived()) {
+            let Some((tick, _)) = info.version(store) else {
+                continue;
+            };
+            // A pool made again since starts its ticks over
+            let since = match self.ticks.insert(info.type_id, tick) {
+                Some(since) if since <= tick => since,
+                _ => 0,
+            };
+            for (entity_id, values) in info.changed_since(store, since) {
+                if log.logged.get(&(info.type_id, entity_id)) == Some(&Some(values.clone())) {
+                    continue;
+                }
+                log.write(&info.name, info.type_id, entity_id, Some(values));
+            }
+        }
+        log.logged.clear();
+        if let Some(error) = log.failed.take() {
+            return Err(error);
+        }
+        Ok(std::mem::take(&mut log.count))
+    }
+
+    pub fn get_ref(&self) -> impl Deref<Target = W> + '_ {
+        Out(lock(&self.log))
+    }
+
+    pub fn into_inner(self) -> W {
+        lock(&self.log)
+            .out
+            .take()
+            .expect("only into_inner takes the writer")
+    }
+}
+
+struct Out<'a, W>(MutexGuard<'a, Log<W>>);
+
+impl<W> Deref for Out<'_, W> {
+    type Target = W;
+
+    fn deref(&self) -> &W {
+        self.0
+            .out
+            .as_ref()
+            .expect("only into_inner takes the writer")
+    }
+}
+
+// An insert with the new fields, or a removal without any
+fn write_record(frames: &mut Vec<u8>, name: &str, entity_id: EntityId, values: Option<&[Value]>) {
+    let mut record = Vec::new();
+    record.push(if values.is_some() { INSERT } else { REMOVE });
+    write_bytes(&mut record, name.as_bytes());
+    write_varint(&mut record, entity_id as u64);
+    if let Some(values) = values {
+        write_varint(&mut record, values.len() as u64);
+        for value in values {
+            write_value(&mut record, value);
+        }
+    }
+    write_bytes(frames, &record);
+}
+
+impl Registry {
+    // Applies a log written by a Wal, returning how many records were replayed
+    // A record cut off at the end of the log is ignored
+    pub fn replay_wal(&self, store: &mut EntityStore, log: &[u8]) -> Result<usize, Error> {
+        let mut reader = Reader { bytes: log };
+        let mut replayed = 0;
+        while !reader.is_empty() {
+            let Ok(record) = reader.bytes() else {
+                break;
+            };
+            let mut record = Reader { bytes: record };
+            let op = record.byte()?;
+            let name = record.string()?;
+            let entity_id = record.entity()?;
+            let info = self
+                .get(&name)
+                .filter(|info| !info.is_derived())
+                .ok_or(Error::UnknownComponent(name))?;
+            match op {
+                INSERT => {
+                    let values = (0..record.len()?)
+                        .map(|_| record.value())
+                        .collect::<Result<Vec<_>, _>>()?;
+                    store.reserve_up_to(entity_id);
+                    if !info.insert(store, entity_id, &values) {
+                        return Err(Error::Decode(format!(
+                            "entity {} can't have `{}` with fields {:?}",
+                            entity_id, info.name, values
+                        )));
+                    }
+                }
+                REMOVE => info.remove(store, entity_id),
+                op => return Err(Error::Decode(format!("unknown log record {}", op))),
+            }
+            replayed += 1;
+        }
+        Ok(replayed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::RuleEngine;
+    use crate::memory::WorkingMemory;
+    use crate::registry::{Fact, TypedFact};
+    use crate::store::{Component, MAX_ENTITY};
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Health(i64);
+
+    impl Component for Health {}
+
+    impl Fact for Health {
+        const FIELDS: &'static [&'static str] = &["hp"];
+
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.0)]
+        }
+
+        fn from_values(values: &[Value]) -> Option<Self> {
+            match values {
+                [hp] => Some(Health(hp.as_int()?)),
+                _ => None,
+            }
+        }
+    }
+
+    impl TypedFact for Health {
+        type Fields = (i64,);
+
+        fn fields(&self) -> Self::Fields {
+            (self.0,)
+        }
+
+        fn from_fields((hp,): Self::Fields) -> Self {
+            Health(hp)
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Dead;
+
+    impl Component for Dead {}
+
+    impl Fact for Dead {
+        const FIELDS: &'static [&'static str] = &[];
+
+        fn to_values(&self) -> Vec<Value> {
+            Vec::new()
+        }
+
+        fn from_values(values: &[Value]) -> Option<Self> {
+            values.is_empty().then_some(Dead)
+        }
+    }
+
+    #[test]
+    fn replaying_the_log_recovers_the_store() {
+        let mut engine = RuleEngine::new();
+        engine.register::<Health>("Health");
+        engine.register::<Dead>("Dead");
+        engine
+            .load_str(r#"rule "die" when Health(e, hp) where hp <= 0 then remove Health(e), insert Dead(e)"#)
+            .unwrap();
+        let registry = engine.registry().clone();
+        let mut memory = WorkingMemory::new(engine);
+        memory.assert_fact(1, Health(10));
+        let snapshot = registry.encode_store(memory.store());
+
+        let mut wal = Wal::new(Vec::new(), &registry, memory.store_mut());
+        memory.assert_fact(2, Health(5));
+        memory.modify_fact::<Health>(1, |health| health.0 = 0);
+        wal.sync(&registry, memory.store()).unwrap();
+        memory.run();
+        assert_eq!(wal.sync(&registry, memory.store()).unwrap(), 2);
+        memory.retract_fact::<Health>(2);
+        wal.sync(&registry, memory.store()).unwrap();
+
+        // Crash, then recover from the snapshot and the log, with a torn record at the end
+        let mut log = wal.into_inner();
+        log.extend_from_slice(&[9, INSERT]);
+        let mut recovered = registry.decode_store(&snapshot).unwrap().store;
+        assert_eq!(registry.replay_wal(&mut recovered, &log).unwrap(), 5);
+        assert_eq!(
+            registry.encode_store(&recovered),
+            registry.encode_store(memory.store())
+        );
+        assert!(recovered.has_component::<Dead>(1));
+        assert!(!recovered.has_component::<Health>(2));
+    }
+
+    #[test]
+    fn changes_are_in_the_log_before_any_sync() {
+        let mut registry = Registry::new();
+        registry.register::<Health>("Health");
+        let mut store = EntityStore::new();
+        store.new_component::<Health>();
+        let mut wal = Wal::new(Vec::new(), &registry, &mut store);
+        store.add_component(1, Health(10));
+        store.add_component(2, Health(4));
+        store.remove_component::<Health>(2);
+
+        // Crash before syncing, the log already has every change
+        let log = wal.get_ref().clone();
+        let mut recovered = EntityStore::new();
+        assert_eq!(registry.replay_wal(&mut recovered, &log).unwrap(), 3);
+        let health = recovered.get::<Health>().unwrap();
+        assert_eq!(health.borrow().get(1), Some(&Health(10)));
+        assert_eq!(health.borrow().get(2), None);
+        assert_eq!(wal.sync(&registry, &store).unwrap(), 3);
+        assert_eq!(*wal.get_ref(), log);
+
+        // A record for an entity past the maximum fails rather than reserve up to it
+        let mut log = Vec::new();
+        write_record(&mut log, "Health", MAX_ENTITY + 1, Some(&[Value::Int(1)]));
+        assert!(matches!(
+            registry.replay_wal(&mut recovered, &log),
+            Err(Error::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn changes_made_in_place_are_logged() {
+        let mut registry = Registry::new();
+        registry.register::<Health>("Health");
+        let mut store = EntityStore::new();
+        store.new_component::<Health>();
+        let mut wal = Wal::new(Vec::new(), &registry, &mut store);
+        store.add_component(1, Health(10));
+        assert_eq!(wal.sync(&registry, &store).unwrap(), 1);
+
+        store
+            .get::<Health>()
+            .unwrap()
+            .borrow_mut()
+            .get_mut(1)
+            .unwrap()
+            .0 = 3;
+        store.add_component(2, Health(4));
+        // More than the change queue's double buffering holds
+        store.update_events();
+        store.update_events();
+        assert_eq!(wal.sync(&registry, &store).unwrap(), 2);
+
+        let mut recovered = EntityStore::new();
+        registry.replay_wal(&mut recovered, &wal.get_ref()).unwrap();
+        let health = recovered.get::<Health>().unwrap();
+        assert_eq!(health.borrow().get(1), Some(&Health(3)));
+        assert_eq!(health.borrow().get(2), Some(&Health(4)));
+    }
+}
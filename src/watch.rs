@@ -0,0 +1,92 @@
+// Async streams of query results, behind the tokio feature
+//
+// store.watch::<(Health, Position)>() is subscribe_query, see subscribe.rs, handing each
+// Entered and Left to a Stream instead of a callback, so a service embedding the engine
+// can await changes to the world rather than check it in a loop. The events queue on an
+// unbounded channel until read, starting with the entities already in the results.
+//
+// Dropping the stream cancels its subscription. The stream never ends by itself, as
+// the store can always change again.
+use crate::store::EntityStore;
+use crate::subscribe::{ComponentSet, Membership, Subscription};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::Stream;
+
+#[derive(Debug)]
+pub struct Watch {
+    events: UnboundedReceiverStream<Membership>,
+    subscription: Subscription,
+}
+
+impl Stream for Watch {
+    type Item = Membership;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Membership>> {
+        Pin::new(&mut self.events).poll_next(cx)
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        self.subscription.cancel();
+    }
+}
+
+impl EntityStore {
+    // A stream of entities entering and leaving Q's results, see above
+    pub fn watch<Q: ComponentSet>(&mut self) -> Watch {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let subscription = self.subscribe_query::<Q>(move |membership| {
+            let _ = sender.send(membership);
+        });
+        Watch {
+            events: UnboundedReceiverStream::new(receiver),
+            subscription,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Component;
+    use tokio_stream::StreamExt;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Health(i64);
+    impl Component for Health {}
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Position(i64, i64);
+    impl Component for Position {}
+
+    #[test]
+    fn watchers_await_entities_entering_and_leaving() {
+        let mut store = EntityStore::new();
+        store.new_component::<Health>();
+        store.new_component::<Position>();
+        store.add_component(1, Health(10));
+        store.add_component(1, Position(0, 0));
+        let mut watch = store.watch::<(Health, Position)>();
+        store.add_component(2, Position(1, 1));
+        store.add_component(2, Health(3));
+        store.remove_entity(1);
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let events: Vec<Membership> =
+            runtime.block_on(async { (&mut watch).take(3).collect().await });
+        assert_eq!(
+            events,
+            [
+                Membership::Entered(1),
+                Membership::Entered(2),
+                Membership::Left(1),
+            ]
+        );
+        drop(watch);
+        store.remove_entity(2);
+    }
+}
@@ -0,0 +1,163 @@
+// Several stores side by side
+//
+// Worlds keeps independent EntityStores by name, say the main world, a UI world and a
+// world for client side prediction, each with its own entities, pools, hooks and undo
+// history. move_entity takes an entity out of one and gives it a new id in another,
+// carrying its registered components over through the registry as merge does. Entity
+// fields keep their ids, since the entities they point at stay behind, and components
+// of unregistered types are dropped with the rest of the old entity.
+use crate::error::Error;
+use crate::registry::{ComponentInfo, FactRow, Registry};
+use crate::store::{EntityId, EntityStore};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Default)]
+pub struct Worlds {
+    worlds: BTreeMap<String, EntityStore>,
+}
+
+impl Worlds {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Adds a world, giving back one it replaced
+    pub fn insert(&mut self, name: &str, store: EntityStore) -> Option<EntityStore> {
+        self.worlds.insert(name.to_string(), store)
+    }
+
+    // The named world, created empty if there isn't one
+    pub fn world(&mut self, name: &str) -> &mut EntityStore {
+        self.worlds.entry(name.to_string()).or_default()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&EntityStore> {
+        self.worlds.get(name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut EntityStore> {
+        self.worlds.get_mut(name)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<EntityStore> {
+        self.worlds.remove(name)
+    }
+
+    // World names in order
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.worlds.keys().map(String::as_str)
+    }
+
+    // Two different worlds at once, None if either is missing or they're the same
+    pub fn pair_mut(&mut self, a: &str, b: &str) -> Option<(&mut EntityStore, &mut EntityStore)> {
+        if a == b || !self.worlds.contains_key(a) || !self.worlds.contains_key(b) {
+            return None;
+        }
+        let mut pair = self
+            .worlds
+            .iter_mut()
+            .filter(|(name, _)| name.as_str() == a || name.as_str() == b);
+        let (first_name, first) = pair.next()?;
+        let (_, second) = pair.next()?;
+        Some(if first_name == a {
+            (first, second)
+        } else {
+            (second, first)
+        })
+    }
+
+    // Moves an entity between worlds, returning its id in the new one, see above
+    // Fails without changing either world if a world is missing or a component won't go
+    // in, like a relation whose endpoint isn't in the new world
+    pub fn move_entity(
+        &mut self,
+        entity_id: EntityId,
+        from: &str,
+        to: &str,
+        registry: &Registry,
+    ) -> Result<EntityId, Error> {
+        for name in [from, to] {
+            if !self.worlds.contains_key(name) {
+                return Err(Error::UnknownWorld(name.to_string()));
+            }
+        }
+        // Moving within a world leaves the entity where it is
+        let Some((source, target)) = self.pair_mut(from, to) else {
+            return Ok(entity_id);
+        };
+        let moved = target.next_entity();
+        let sections: Vec<(&ComponentInfo, Vec<FactRow>)> = registry
+            .iter()
+            .filter(|info| !info.is_derived())
+            .map(|info| {
+                let rows = info.rows(source, entity_id);
+                (
+                    info,
+                    rows.into_iter().map(|values| (moved, values)).collect(),
+                )
+            })
+            .collect();
+        target.begin_step();
+        let inserted = Registry::insert_rows(target, sections);
+        if let Err(message) = inserted {
+            target.remove_entity(moved);
+            target.end_step();
+            return Err(Error::Merge(message));
+        }
+        target.reserve_up_to(moved);
+        target.end_step();
+        source.remove_entity(entity_id);
+        Ok(moved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::Fact;
+    use crate::store::Component;
+    use crate::value::Value;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Sprite(i64);
+    impl Component for Sprite {}
+
+    impl Fact for Sprite {
+        const FIELDS: &'static [&'static str] = &["frame"];
+
+        fn to_values(&self) -> Vec<Value> {
+            vec![Value::Int(self.0)]
+        }
+
+        fn from_values(values: &[Value]) -> Option<Self> {
+            Some(Sprite(values.first()?.as_int()?))
+        }
+    }
+
+    #[test]
+    fn entities_move_between_worlds() {
+        let mut registry = Registry::new();
+        registry.register::<Sprite>("Sprite");
+        let mut worlds = Worlds::new();
+        worlds.world("main").new_component::<Sprite>();
+        worlds.world("main").add_component(3, Sprite(7));
+        worlds.world("ui").new_component::<Sprite>();
+        worlds.world("ui").add_component(0, Sprite(1));
+        assert_eq!(worlds.names().collect::<Vec<_>>(), ["main", "ui"]);
+
+        let moved = worlds.move_entity(3, "main", "ui", &registry).unwrap();
+        assert_eq!(moved, 1);
+        assert!(!worlds.get("main").unwrap().has_component::<Sprite>(3));
+        let ui = worlds.get("ui").unwrap().get::<Sprite>().unwrap();
+        assert_eq!(ui.borrow().get(1), Some(&Sprite(7)));
+        assert_eq!(
+            worlds.move_entity(1, "ui", "prediction", &registry),
+            Err(Error::UnknownWorld("prediction".to_string()))
+        );
+
+        let (main, ui) = worlds.pair_mut("main", "ui").unwrap();
+        main.add_component(0, Sprite(2));
+        ui.remove_entity(0);
+        assert!(worlds.pair_mut("ui", "ui").is_none());
+    }
+}